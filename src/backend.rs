@@ -0,0 +1,92 @@
+use crate::ffi::CType;
+use crate::ir::{symbol, Function};
+use crate::seman::Type;
+
+// What the native backends (x86.rs, riscv.rs, thumb.rs) have in common that isn't about
+// instructions: label names, the runtime's helper names, how strings go into .rodata and
+// which C integers need narrowing. Also the encoded Image the RISC-V and Thumb encoders make
+// for their simulators.
+
+//
+// STRUCTS
+//
+
+/// A program encoded at a fixed address, ready to be copied into memory
+#[derive(Debug)]
+pub struct Image {
+    pub base: u32,
+    pub bytes: Vec<u8>,
+    pub entry: u32,
+    /// Every non-local label and its address, sorted by address
+    pub symbols: Vec<(String, u32)>,
+}
+impl Image {
+    /// The symbol an address belongs to, for error messages
+    pub fn symbol_at(&self, address: u32) -> Option<&str> {
+        self.symbols.iter().rev().find(|(_, start)| *start <= address).map(|(name, _)| name.as_str())
+    }
+}
+
+//
+// CODE GENERATION
+//
+
+pub const PRINT_INT: &str = "__gv_print_int";
+pub const PRINT_STR: &str = "__gv_print_str";
+pub const PRINT_CHAR: &str = "__gv_print_char";
+
+pub fn string_label(index: usize) -> String {
+    format!(".Lstr{}", index)
+}
+
+pub fn block_label(function: &Function, block: usize) -> String {
+    format!(".L{}_bb{}", symbol(&function.name), block)
+}
+
+/// How many 32-bit registers a value takes, i64s are pairs
+pub fn words_32(ty: Type) -> usize {
+    match ty {
+        Type::Int => 2,
+        Type::Str => 1,
+        Type::Void => 0,
+    }
+}
+
+/// Size and signedness of a C integer narrower than a `word_bits` register, which has to be
+/// sign or zero extended after coming back from C and cut down before going to it
+pub fn narrow_int(ty: Option<&CType>, word_bits: u8) -> Option<(u8, bool)> {
+    match ty {
+        Some(CType::Int { bits, signed }) if *bits < word_bits => Some((*bits, *signed)),
+        _ => None,
+    }
+}
+
+//
+// OUTPUT
+//
+
+/// The `.rodata` section, one label per string
+pub fn rodata_assembly(out: &mut String, rodata: &[(String, Vec<u8>)]) {
+    out.push_str("\n.section .rodata\n");
+    for (label, bytes) in rodata {
+        // Strings have their NUL included already, .asciz adds another one
+        match bytes.split_last() {
+            Some((0, string)) => out.push_str(&format!("{}:\n    .asciz \"{}\"\n", label, escape_bytes(string))),
+            _ => out.push_str(&format!("{}:\n    .ascii \"{}\"\n", label, escape_bytes(bytes))),
+        }
+    }
+}
+
+/// Escapes bytes for a `.asciz` directive
+fn escape_bytes(bytes: &[u8]) -> String {
+    let mut out = String::new();
+    for byte in bytes {
+        match byte {
+            b'"' => out.push_str("\\\""),
+            b'\\' => out.push_str("\\\\"),
+            0x20..=0x7e => out.push(*byte as char),
+            _ => out.push_str(&format!("\\{:03o}", byte)),
+        }
+    }
+    out
+}
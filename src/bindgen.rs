@@ -0,0 +1,1247 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use crate::compiler_settings::{KEYWORDS, MODULE_SEPARATOR};
+use crate::ffi::{CType, Extern};
+
+// C headers to Galvan, for `galvan bindgen hal.h` and `import c "hal.h";`. No libclang, just
+// enough of a C parser for what headers are usually made of: function prototypes become
+// `extern "C"` functions, integer `#define`s and enum values become constants, and structs get
+// their size and field offsets as constants (Galvan has no structs, but it can do math on a
+// pointer). Object-like macros get expanded, `#if`/`#ifdef` evaluated and quoted `#include`s
+// followed, `<...>` ones are skipped (the usual stdint.h/stddef.h types are built in).
+// Anything that can't be translated ends up as a `// skipped` comment saying why.
+
+//
+// STRUCTS
+//
+
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+enum Token {
+    Ident(String),
+    Number(i64),
+    /// Floating point literal, only there so expressions with one don't work
+    Float,
+    Str(String),
+    Punct(String),
+}
+
+/// C types, as far as bindgen cares
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+enum Ty {
+    Void,
+    Int {bits: u8, signed: bool},
+    /// `const char`, so a `const char *` can be a Galvan str
+    ConstChar,
+    /// Size in bytes
+    Float(usize),
+    Pointer(Box<Ty>),
+    Array(Box<Ty>, usize),
+    /// Key into `Bindgen::structs`, the tag or a made-up name for anonymous ones
+    Struct(String),
+    Function {ret: Box<Ty>, params: Parameters, variadic: bool},
+}
+
+/// Parameters of a function type, names are optional in C
+type Parameters = Vec<(Option<String>, Ty)>;
+
+/// Size, alignment and field offsets of a struct (or union), System V rules
+#[derive(Debug)]
+#[derive(Clone)]
+struct Layout {
+    size: usize,
+    align: usize,
+    fields: Vec<(String, usize)>,
+}
+
+/// The start of a declaration, everything before the names
+struct Specifiers {
+    ty: Ty,
+    typedef: bool,
+    /// `static` (or `static inline`), there's no symbol to link against
+    local: bool,
+    /// Struct whose body is in here, its constants get named after the declaration
+    defined: Option<String>,
+}
+
+/// `#define NAME(a, b) ...`
+struct Macro {
+    parameters: Vec<String>,
+    /// Ends in `...`, the rest of the arguments are `__VA_ARGS__`
+    variadic: bool,
+    body: Vec<Token>,
+}
+
+/// One `#if` level
+struct Condition {
+    /// Lines are read right now
+    active: bool,
+    /// Some branch of it was taken already, so `#else`/`#elif` stay off
+    taken: bool,
+    /// The `#if` is inside an inactive region itself
+    outer: bool,
+}
+
+struct Bindgen {
+    /// Object-like macros, name -> replacement
+    macros: HashMap<String, Vec<Token>>,
+    /// `#define`s in order, they become constants at the end (when every enum value is known)
+    defines: Vec<String>,
+    function_macros: HashMap<String, Macro>,
+    /// Values of enum constants
+    constants: HashMap<String, i64>,
+    typedefs: HashMap<String, Ty>,
+    structs: HashMap<String, Result<Layout, String>>,
+    anonymous: usize,
+    /// Headers read so far, each gets read once
+    included: Vec<PathBuf>,
+    /// Where else to look for `#include "..."`
+    include: Vec<PathBuf>,
+    /// Declarations, macros already expanded
+    tokens: Vec<Token>,
+    position: usize,
+    /// `extern "C" {` blocks around the declarations
+    extern_blocks: usize,
+    /// Galvan names already used in the output
+    names: HashSet<String>,
+    /// `names` in the order they were claimed, to give them back when a declaration fails
+    claimed: Vec<String>,
+    out: Vec<String>,
+}
+
+//
+// FUNCTIONS
+//
+
+const PUNCTUATION: [&str; 15] = ["...", "<<=", ">>=", "->", "++", "--", "<<", ">>", "<=", ">=", "==", "!=", "&&", "||", "##"];
+
+/// Qualifiers and such that don't change what a type is, as far as Galvan cares
+const IGNORED_WORDS: [&str; 16] = [
+    "const", "volatile", "restrict", "__restrict", "__restrict__", "__volatile__", "__const", "register",
+    "auto", "_Noreturn", "__extension__", "inline", "__inline", "__inline__", "_Thread_local", "extern",
+];
+
+/// Words that make up a builtin type, `unsigned long int` and friends
+const TYPE_WORDS: [&str; 11] = ["void", "char", "short", "int", "long", "signed", "unsigned", "float", "double", "_Bool", "bool"];
+
+/// Compiler extensions that take a parenthesized argument and mean nothing here
+const ATTRIBUTES: [&str; 6] = ["__attribute__", "__attribute", "__declspec", "__asm__", "__asm", "asm"];
+
+/// Macros every header gets to see, as if this was GCC on x86-64 Linux
+const PREDEFINED: [(&str, i64); 5] = [("__STDC__", 1), ("__GNUC__", 4), ("__x86_64__", 1), ("__LP64__", 1), ("__linux__", 1)];
+
+/// stdint.h, stddef.h and friends, since `<...>` includes aren't read
+fn builtin_typedef(name: &str) -> Option<Ty> {
+    let int = |bits, signed| Some(Ty::Int { bits, signed });
+    match name {
+        "int8_t" => int(8, true),
+        "int16_t" => int(16, true),
+        "int32_t" => int(32, true),
+        "int64_t" | "intptr_t" | "ssize_t" | "ptrdiff_t" | "off_t" | "intmax_t" => int(64, true),
+        "uint8_t" => int(8, false),
+        "uint16_t" => int(16, false),
+        "uint32_t" => int(32, false),
+        "uint64_t" | "uintptr_t" | "size_t" | "uintmax_t" => int(64, false),
+        "wchar_t" => int(32, true),
+        _ => None,
+    }
+}
+
+fn is_identifier(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Usable as a Galvan name
+fn is_galvan_name(name: &str) -> bool {
+    is_identifier(name) && !KEYWORDS.contains(&name)
+}
+
+/// Joins `\`-continued lines and blanks out comments, keeping the line numbers intact
+fn clean(text: &str) -> String {
+    let text = text.replace("\\\r\n", "").replace("\\\n", "");
+    let mut out = String::new();
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '/' if chars.peek() == Some(&'/') => {
+                while chars.next_if(|next| *next != '\n').is_some() {}
+            }
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+                let mut last = ' ';
+                for next in chars.by_ref() {
+                    if next == '\n' {out.push('\n')}
+                    if last == '*' && next == '/' {break}
+                    last = next;
+                }
+                out.push(' ');
+            }
+            '"' | '\'' => {
+                out.push(c);
+                while let Some(next) = chars.next() {
+                    out.push(next);
+                    if next == '\\' {
+                        if let Some(escaped) = chars.next() {out.push(escaped)}
+                    } else if next == c || next == '\n' {
+                        break;
+                    }
+                }
+            }
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Value of a C integer literal, suffixes and all
+fn parse_number(text: &str) -> Option<i64> {
+    let digits = text.trim_end_matches(['u', 'U', 'l', 'L']);
+    let value = if let Some(hex) = digits.strip_prefix("0x").or(digits.strip_prefix("0X")) {
+        u64::from_str_radix(hex, 16)
+    } else if let Some(binary) = digits.strip_prefix("0b").or(digits.strip_prefix("0B")) {
+        u64::from_str_radix(binary, 2)
+    } else if digits.len() > 1 && digits.starts_with('0') {
+        u64::from_str_radix(&digits[1..], 8)
+    } else {
+        digits.parse::<u64>()
+    };
+    value.ok().map(|value| value as i64)
+}
+
+/// Escape after a backslash in a char literal, `chars` is right after the backslash
+fn escape(chars: &mut std::iter::Peekable<std::str::Chars>) -> i64 {
+    match chars.next() {
+        Some('n') => 10,
+        Some('t') => 9,
+        Some('r') => 13,
+        Some('0') => 0,
+        Some('x') => {
+            let mut value = 0;
+            while let Some(digit) = chars.peek().and_then(|c| c.to_digit(16)) {
+                value = value * 16 + digit as i64;
+                chars.next();
+            }
+            value
+        }
+        Some(other) => other as i64,
+        None => 0,
+    }
+}
+
+/// Tokens of one (already cleaned) line
+fn tokenize(line: &str) -> Vec<Token> {
+    let mut tokens = vec![];
+    let mut chars = line.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let mut ident = String::new();
+            while let Some(next) = chars.next_if(|next| next.is_ascii_alphanumeric() || *next == '_') {ident.push(next)}
+            tokens.push(Token::Ident(ident));
+        } else if c.is_ascii_digit() {
+            let mut number = String::new();
+            while let Some(next) = chars.next_if(|next| next.is_ascii_alphanumeric() || *next == '.' || *next == '_') {
+                number.push(next);
+                // Exponents can have a sign
+                if "eEpP".contains(next) && !number.starts_with("0x") && let Some(sign) = chars.next_if(|sign| *sign == '+' || *sign == '-') {
+                    number.push(sign);
+                }
+            }
+            let float = number.contains('.') || (!number.starts_with("0x") && number.contains(['e', 'E']));
+            tokens.push(match parse_number(&number) {
+                Some(value) if !float => Token::Number(value),
+                _ => Token::Float,
+            });
+        } else if c == '"' {
+            chars.next();
+            let mut string = String::new();
+            while let Some(next) = chars.next() {
+                if next == '"' {break}
+                if next == '\\' && let Some(escaped) = chars.next() {string.push('\\'); string.push(escaped); continue}
+                string.push(next);
+            }
+            tokens.push(Token::Str(string));
+        } else if c == '\'' {
+            chars.next();
+            let value = match chars.next() {
+                Some('\\') => escape(&mut chars),
+                Some(other) => other as i64,
+                None => 0,
+            };
+            while chars.next().is_some_and(|next| next != '\'') {}
+            tokens.push(Token::Number(value));
+        } else {
+            let rest: String = chars.clone().take(3).collect();
+            let punct = PUNCTUATION.iter().find(|punct| rest.starts_with(**punct)).map(|punct| punct.to_string()).unwrap_or(c.to_string());
+            for _ in 0..punct.chars().count() {chars.next();}
+            tokens.push(Token::Punct(punct));
+        }
+    }
+    tokens
+}
+
+/// Tokens back into something readable, for the comments
+fn render(tokens: &[Token]) -> String {
+    let mut out = String::new();
+    for (index, token) in tokens.iter().enumerate() {
+        let text = match token {
+            Token::Ident(ident) => ident.clone(),
+            Token::Number(number) => number.to_string(),
+            Token::Float => "<float>".to_string(),
+            Token::Str(string) => format!("\"{}\"", string),
+            Token::Punct(punct) => punct.clone(),
+        };
+        let word = |token: &Token| matches!(token, Token::Ident(_) | Token::Number(_) | Token::Float | Token::Str(_));
+        let space = index > 0 && ((word(&tokens[index - 1]) && (word(token) || text == "*")) || tokens[index - 1] == Token::Punct(",".to_string()));
+        if space {out.push(' ')}
+        out.push_str(&text);
+    }
+    if out.chars().count() > 100 {out = format!("{}...", out.chars().take(100).collect::<String>())}
+    out
+}
+
+fn punct(token: Option<&Token>, expected: &str) -> bool {
+    matches!(token, Some(Token::Punct(punct)) if punct == expected)
+}
+
+fn ident(token: Option<&Token>) -> Option<&str> {
+    match token {
+        Some(Token::Ident(ident)) => Some(ident),
+        _ => None,
+    }
+}
+
+/// `galvan` constant syntax, there's no unary minus
+fn galvan_number(value: i64) -> String {
+    if value == i64::MIN {
+        format!("0 - {} - 1", i64::MAX)
+    } else if value < 0 {
+        format!("0 - {}", -value)
+    } else {
+        value.to_string()
+    }
+}
+
+/// Builtin type from its words, `unsigned long` -> u64
+fn word_type(words: &[String], constant: bool) -> Result<Ty, String> {
+    let has = |word: &str| words.iter().any(|other| other == word);
+    let longs = words.iter().filter(|word| *word == "long").count();
+    let signed = !has("unsigned");
+    Ok(if has("void") {
+        Ty::Void
+    } else if has("float") {
+        Ty::Float(4)
+    } else if has("double") {
+        Ty::Float(if longs > 0 {16} else {8})
+    } else if has("_Bool") || has("bool") {
+        Ty::Int { bits: 8, signed: false }
+    } else if has("char") {
+        if constant && !has("signed") && !has("unsigned") {Ty::ConstChar} else {Ty::Int { bits: 8, signed }}
+    } else if has("short") {
+        Ty::Int { bits: 16, signed }
+    } else if longs > 0 {
+        Ty::Int { bits: 64, signed }
+    } else if !words.is_empty() {
+        Ty::Int { bits: 32, signed }
+    } else {
+        return Err("no type".to_string());
+    })
+}
+
+impl Bindgen {
+    fn new(include: &[PathBuf]) -> Bindgen {
+        let mut bindgen = Bindgen {
+            macros: HashMap::new(), defines: vec![], function_macros: HashMap::new(), constants: HashMap::new(),
+            typedefs: HashMap::new(), structs: HashMap::new(), anonymous: 0, included: vec![], include: include.to_vec(),
+            tokens: vec![], position: 0, extern_blocks: 0, names: HashSet::new(), claimed: vec![], out: vec![],
+        };
+        for (name, value) in PREDEFINED {
+            bindgen.macros.insert(name.to_string(), vec![Token::Number(value)]);
+        }
+        bindgen
+    }
+
+    /// Replaces macros, `expanding` keeps a macro from expanding inside itself
+    fn expand(&self, tokens: &[Token], expanding: &mut Vec<String>) -> Vec<Token> {
+        let mut out = vec![];
+        let mut index = 0;
+        while index < tokens.len() {
+            let token = &tokens[index];
+            index += 1;
+            let Token::Ident(name) = token else {out.push(token.clone()); continue};
+            let replacement = if expanding.contains(name) {
+                None
+            } else if let Some(body) = self.macros.get(name) {
+                Some(body.clone())
+            } else if let Some(function) = self.function_macros.get(name) && punct(tokens.get(index), "(")
+                && let Some((arguments, end)) = arguments(tokens, index) {
+                index = end;
+                Some(self.substitute(function, &arguments, expanding))
+            } else {
+                None
+            };
+            match replacement {
+                Some(replacement) => {
+                    expanding.push(name.clone());
+                    out.extend(self.expand(&replacement, expanding));
+                    expanding.pop();
+                }
+                None => out.push(token.clone()),
+            }
+        }
+        out
+    }
+
+    /// Body of a function-like macro with the arguments in, `#` and `##` included
+    fn substitute(&self, function: &Macro, arguments: &[Vec<Token>], expanding: &mut Vec<String>) -> Vec<Token> {
+        let argument = |name: &str| -> Option<Vec<Token>> {
+            if name == "__VA_ARGS__" && function.variadic {
+                let rest = arguments.get(function.parameters.len()..).unwrap_or_default();
+                return Some(rest.join(&Token::Punct(",".to_string())));
+            }
+            let index = function.parameters.iter().position(|parameter| parameter == name)?;
+            Some(arguments.get(index).cloned().unwrap_or_default())
+        };
+        let body = &function.body;
+        let mut out: Vec<Token> = vec![];
+        let mut index = 0;
+        while index < body.len() {
+            let token = &body[index];
+            let pasted = punct(body.get(index + 1), "##") || (index > 0 && punct(body.get(index - 1), "##"));
+            if punct(Some(token), "#") && let Some(raw) = ident(body.get(index + 1)).and_then(argument) {
+                out.push(Token::Str(render(&raw)));
+                index += 2;
+                continue;
+            }
+            match ident(Some(token)).and_then(argument) {
+                // Arguments next to `##` don't get expanded first
+                Some(raw) if pasted => out.extend(raw),
+                Some(raw) => out.extend(self.expand(&raw, expanding)),
+                None => out.push(token.clone()),
+            }
+            index += 1;
+        }
+        // `a ## b` glues two tokens into one
+        let mut glued: Vec<Token> = vec![];
+        let mut tokens = out.into_iter();
+        while let Some(token) = tokens.next() {
+            if !punct(Some(&token), "##") {glued.push(token); continue}
+            let left = glued.pop().map(|left| render(&[left])).unwrap_or_default();
+            let right = tokens.next().map(|right| render(&[right])).unwrap_or_default();
+            glued.extend(tokenize(&format!("{}{}", left, right)));
+        }
+        glued
+    }
+
+    /// `#if` condition, `defined(X)` gets replaced before expanding and unknown names are 0
+    fn condition(&self, tokens: &[Token]) -> bool {
+        let mut replaced = vec![];
+        let mut tokens = tokens.iter().peekable();
+        while let Some(token) = tokens.next() {
+            if ident(Some(token)) != Some("defined") {replaced.push(token.clone()); continue}
+            let parens = tokens.next_if(|token| punct(Some(token), "(")).is_some();
+            let name = ident(tokens.next()).unwrap_or_default().to_string();
+            if parens {tokens.next();}
+            let defined = self.macros.contains_key(&name) || self.function_macros.contains_key(&name);
+            replaced.push(Token::Number(defined as i64));
+        }
+        let expanded = self.expand(&replaced, &mut vec![]);
+        let mut evaluator = Evaluator { tokens: &expanded, position: 0, bindgen: self, unknown_zero: true };
+        evaluator.evaluate().is_ok_and(|value| value != 0)
+    }
+
+    /// Reads a header, directives get handled right away and the rest ends up in `tokens`
+    fn preprocess(&mut self, path: &Path) -> Result<(), String> {
+        let canonical = path.canonicalize().unwrap_or(path.to_path_buf());
+        if self.included.contains(&canonical) {return Ok(())}
+        self.included.push(canonical);
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(error) => return Err(format!("Can't read '{}': {}", path.display(), error)),
+        };
+
+        let mut conditions: Vec<Condition> = vec![];
+        // Lines since the last directive, macro calls can go over more than one line
+        let mut pending = vec![];
+        for (number, line) in clean(&text).lines().enumerate() {
+            let active = conditions.last().is_none_or(|condition| condition.active);
+            let at = |error: &str| format!("{} at position {}:1 in {}", error, number + 1, path.display());
+            let Some(directive) = line.trim_start().strip_prefix('#') else {
+                if active {pending.extend(tokenize(line))}
+                continue;
+            };
+            let expanded = self.expand(&std::mem::take(&mut pending), &mut vec![]);
+            self.tokens.extend(expanded);
+            let tokens = tokenize(directive);
+            let name = ident(tokens.first()).unwrap_or_default();
+            let rest = tokens.get(1..).unwrap_or_default();
+            match name {
+                "if" | "ifdef" | "ifndef" => {
+                    let value = active && match name {
+                        "if" => self.condition(rest),
+                        "ifdef" => self.condition(&[Token::Ident("defined".to_string()), rest.first().cloned().unwrap_or(Token::Number(0))]),
+                        _ => !self.condition(&[Token::Ident("defined".to_string()), rest.first().cloned().unwrap_or(Token::Number(0))]),
+                    };
+                    conditions.push(Condition { active: value, taken: value, outer: !active });
+                }
+                "elif" | "else" => {
+                    let taken = self.condition(rest);
+                    let Some(condition) = conditions.last_mut() else {return Err(at(&format!("#{} without an #if", name)))};
+                    condition.active = !condition.outer && !condition.taken && (name == "else" || taken);
+                    condition.taken |= condition.active;
+                }
+                "endif" => {
+                    conditions.pop().ok_or_else(|| at("#endif without an #if"))?;
+                }
+                _ if !active => {}
+                "define" => {
+                    let Some(macro_name) = ident(rest.first()).map(str::to_string) else {continue};
+                    // `NAME(` right after the name is a function-like macro, `NAME (` isn't
+                    let after = directive.trim_start().trim_start_matches("define").trim_start().trim_start_matches(macro_name.as_str());
+                    if after.starts_with('(') {
+                        let Some(close) = rest.iter().position(|token| punct(Some(token), ")")) else {continue};
+                        let parameters = rest[2..close].iter().filter_map(|token| ident(Some(token)).map(str::to_string)).collect();
+                        let variadic = rest[2..close].iter().any(|token| punct(Some(token), "..."));
+                        let function = Macro { parameters, variadic, body: rest[close + 1..].to_vec() };
+                        self.function_macros.insert(macro_name.clone(), function);
+                        if !macro_name.starts_with('_') {
+                            self.out.push(format!("// skipped macro {}(): function-like macros can't be translated", macro_name));
+                        }
+                        continue;
+                    }
+                    self.macros.insert(macro_name.clone(), rest[1..].to_vec());
+                    self.defines.retain(|define| *define != macro_name);
+                    self.defines.push(macro_name);
+                }
+                "undef" => {
+                    if let Some(macro_name) = ident(rest.first()) {
+                        self.macros.remove(macro_name);
+                        self.function_macros.remove(macro_name);
+                        self.defines.retain(|define| define != macro_name);
+                    }
+                }
+                // Only quoted ones, system headers' types are built in
+                "include" => if let Some(Token::Str(file)) = rest.first() {
+                    let directory = path.parent().map(Path::to_path_buf).unwrap_or_default();
+                    let found = std::iter::once(&directory).chain(&self.include).map(|dir| dir.join(file)).find(|path| path.is_file());
+                    match found {
+                        Some(found) => self.preprocess(&found)?,
+                        None => self.out.push(format!("// skipped #include \"{}\": not found", file)),
+                    }
+                },
+                "error" => self.out.push(format!("// skipped #error{}", directive.trim_start().trim_start_matches("error"))),
+                // #pragma, #line, #warning...
+                _ => {}
+            }
+        }
+        if !conditions.is_empty() {return Err(format!("Unterminated #if in {}", path.display()))}
+        let expanded = self.expand(&pending, &mut vec![]);
+        self.tokens.extend(expanded);
+        Ok(())
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn peek_at(&self, offset: usize) -> Option<&Token> {
+        self.tokens.get(self.position + offset)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn at_punct(&self, expected: &str) -> bool {
+        punct(self.peek(), expected)
+    }
+
+    fn expect(&mut self, expected: &str) -> Result<(), String> {
+        match self.next() {
+            Some(Token::Punct(punct)) if punct == expected => Ok(()),
+            Some(other) => Err(format!("expected '{}', not {}", expected, render(&[other]))),
+            None => Err(format!("expected '{}' at the end of the header", expected)),
+        }
+    }
+
+    /// Moves past a balanced (), [] or {} group, starting at the opening one
+    fn skip_group(&mut self) {
+        let mut depth = 0;
+        while let Some(token) = self.next() {
+            match &token {
+                Token::Punct(punct) if ["(", "[", "{"].contains(&punct.as_str()) => depth += 1,
+                Token::Punct(punct) if [")", "]", "}"].contains(&punct.as_str()) => depth -= 1,
+                _ => {}
+            }
+            if depth == 0 {return}
+        }
+    }
+
+    /// `__attribute__((...))` and the like
+    fn skip_attributes(&mut self) {
+        while ident(self.peek()).is_some_and(|word| ATTRIBUTES.contains(&word)) {
+            self.position += 1;
+            if self.at_punct("(") {self.skip_group()}
+        }
+    }
+
+    /// Tokens up to (not including) a `,` or one of `end` at depth 0, for expressions
+    fn until(&mut self, end: &[&str]) -> Vec<Token> {
+        let start = self.position;
+        let mut depth = 0;
+        while let Some(token) = self.peek() {
+            if let Token::Punct(punct) = token {
+                if depth == 0 && (punct == "," || end.contains(&punct.as_str())) {break}
+                if ["(", "[", "{"].contains(&punct.as_str()) {depth += 1}
+                if [")", "]", "}"].contains(&punct.as_str()) {depth -= 1}
+            }
+            self.position += 1;
+        }
+        self.tokens[start..self.position].to_vec()
+    }
+
+    fn constant(&self, tokens: &[Token]) -> Result<i64, String> {
+        let mut evaluator = Evaluator { tokens, position: 0, bindgen: self, unknown_zero: false };
+        let value = evaluator.evaluate()?;
+        if evaluator.position < tokens.len() {return Err(format!("'{}' isn't a constant", render(tokens)))}
+        Ok(value)
+    }
+
+    fn size_align(&self, ty: &Ty) -> Result<(usize, usize), String> {
+        Ok(match ty {
+            Ty::Int { bits, .. } => (*bits as usize / 8, *bits as usize / 8),
+            Ty::ConstChar => (1, 1),
+            Ty::Float(size) => (*size, *size),
+            Ty::Pointer(_) => (8, 8),
+            Ty::Array(inner, count) => {
+                let (size, align) = self.size_align(inner)?;
+                (size * count, align)
+            }
+            Ty::Struct(key) => match self.structs.get(key) {
+                Some(Ok(layout)) => (layout.size, layout.align),
+                Some(Err(error)) => return Err(error.clone()),
+                None => return Err(format!("struct {} isn't defined", key)),
+            },
+            Ty::Void | Ty::Function { .. } => return Err("a field can't be void or a function".to_string()),
+        })
+    }
+
+    /// `struct tag { ... }`, `union ...` or `enum ...`, right after the keyword
+    fn tagged(&mut self, kind: &str, specifiers: &mut Specifiers) -> Result<Ty, String> {
+        self.skip_attributes();
+        let tag = ident(self.peek()).map(str::to_string);
+        if tag.is_some() {self.position += 1}
+        self.skip_attributes();
+        if !self.at_punct("{") {
+            return Ok(match (kind, tag) {
+                ("enum", _) => Ty::Int { bits: 32, signed: true },
+                (_, Some(tag)) => Ty::Struct(tag),
+                (_, None) => return Err(format!("{} without a name or a body", kind)),
+            });
+        }
+        self.position += 1;
+
+        if kind == "enum" {
+            let mut value = 0;
+            while !self.at_punct("}") {
+                let Some(Token::Ident(name)) = self.next() else {return Err("expected an enum constant".to_string())};
+                self.skip_attributes();
+                if self.at_punct("=") {
+                    self.position += 1;
+                    let expression = self.until(&["}"]);
+                    value = self.constant(&expression)?;
+                }
+                self.constants.insert(name.clone(), value);
+                self.constant_item(&name, value);
+                value = value.wrapping_add(1);
+                if self.at_punct(",") {self.position += 1}
+            }
+            self.position += 1;
+            return Ok(Ty::Int { bits: 32, signed: true });
+        }
+
+        let key = match tag {
+            Some(tag) => tag,
+            None => {
+                self.anonymous += 1;
+                format!("<anonymous {}>", self.anonymous)
+            }
+        };
+        let mut layout: Result<Layout, String> = Ok(Layout { size: 0, align: 1, fields: vec![] });
+        while !self.at_punct("}") {
+            if self.peek().is_none() {return Err(format!("{} {} never ends", kind, key))}
+            let field = self.specifiers()?;
+            let mut members = vec![];
+            // An anonymous struct or union inside, its fields belong to this one
+            if self.at_punct(";") && let Ty::Struct(inner) = &field.ty && field.defined.is_some() {
+                members.push((None, field.ty.clone(), self.structs.get(inner).cloned()));
+            }
+            while !self.at_punct(";") {
+                let (name, ty) = self.declarator(field.ty.clone())?;
+                if self.at_punct(":") {
+                    self.position += 1;
+                    self.until(&[";"]);
+                    layout = Err(format!("{} {} has bitfields", kind, key));
+                }
+                members.push((name, ty, None));
+                if self.at_punct(",") {self.position += 1} else {break}
+            }
+            self.expect(";")?;
+            let Ok(current) = &mut layout else {continue};
+            for (name, ty, inner) in members {
+                let (size, align) = match self.size_align(&ty) {
+                    Ok(size_align) => size_align,
+                    Err(error) => {layout = Err(error); break}
+                };
+                let offset = if kind == "union" {0} else {current.size.div_ceil(align) * align};
+                match (name, inner) {
+                    (Some(name), _) => current.fields.push((name, offset)),
+                    (None, Some(Ok(inner))) => current.fields.extend(inner.fields.iter().map(|(name, inner_offset)| (name.clone(), offset + inner_offset))),
+                    _ => {}
+                }
+                current.size = if kind == "union" {current.size.max(size)} else {offset + size};
+                current.align = current.align.max(align);
+            }
+        }
+        self.position += 1;
+        if let Ok(layout) = &mut layout {layout.size = layout.size.div_ceil(layout.align) * layout.align}
+        self.structs.insert(key.clone(), layout);
+        specifiers.defined = Some(key.clone());
+        Ok(Ty::Struct(key))
+    }
+
+    /// Whether a declaration can go on with `word`, a type or something in front of one
+    fn starts_type(&self, word: &str) -> bool {
+        TYPE_WORDS.contains(&word) || IGNORED_WORDS.contains(&word) || ATTRIBUTES.contains(&word)
+            || ["typedef", "static", "struct", "union", "enum"].contains(&word)
+            || self.typedefs.contains_key(word) || builtin_typedef(word).is_some()
+    }
+
+    /// Type and storage class at the start of a declaration
+    fn specifiers(&mut self) -> Result<Specifiers, String> {
+        let mut specifiers = Specifiers { ty: Ty::Void, typedef: false, local: false, defined: None };
+        let mut words: Vec<String> = vec![];
+        let mut base: Option<Ty> = None;
+        let mut constant = false;
+        while let Some(word) = ident(self.peek()).map(str::to_string) {
+            if ATTRIBUTES.contains(&word.as_str()) {
+                self.skip_attributes();
+                continue;
+            }
+            match word.as_str() {
+                "const" => constant = true,
+                "typedef" => specifiers.typedef = true,
+                "static" => specifiers.local = true,
+                word if IGNORED_WORDS.contains(&word) => {}
+                word if TYPE_WORDS.contains(&word) => words.push(word.to_string()),
+                "struct" | "union" | "enum" => {
+                    self.position += 1;
+                    base = Some(self.tagged(&word, &mut specifiers)?);
+                    continue;
+                }
+                _ if base.is_some() || !words.is_empty() => break,
+                name => match self.typedefs.get(name).cloned().or_else(|| builtin_typedef(name)) {
+                    Some(ty) => base = Some(ty),
+                    // An unknown name in front of a type is a macro from a header that wasn't
+                    // read, like `API int f(void);`
+                    None if ident(self.peek_at(1)).is_some_and(|next| self.starts_type(next)) => {}
+                    // An unknown name followed by another name or a `*` is some type we've never
+                    // seen the definition of, fine as long as it's only used through pointers
+                    None if ident(self.peek_at(1)).is_some() || punct(self.peek_at(1), "*") => base = Some(Ty::Struct(name.to_string())),
+                    None => break,
+                },
+            }
+            self.position += 1;
+        }
+        specifiers.ty = match base {
+            Some(Ty::Int { bits: 8, signed: true }) if constant && words.is_empty() => Ty::ConstChar,
+            Some(ty) => ty,
+            None => word_type(&words, constant)?,
+        };
+        Ok(specifiers)
+    }
+
+    /// `*name[4]`, `(*callback)(int)`, ... wrapped around `base`. The name is optional (parameters).
+    fn declarator(&mut self, base: Ty) -> Result<(Option<String>, Ty), String> {
+        let mut ty = base;
+        self.skip_attributes();
+        while self.at_punct("*") {
+            self.position += 1;
+            ty = Ty::Pointer(Box::new(ty));
+            while ident(self.peek()).is_some_and(|word| IGNORED_WORDS.contains(&word) || ATTRIBUTES.contains(&word)) {
+                if ATTRIBUTES.contains(&ident(self.peek()).unwrap()) {self.skip_attributes()} else {self.position += 1}
+            }
+        }
+        // `(*name)(...)`, the part outside the parentheses applies first
+        if self.at_punct("(") && (punct(self.peek_at(1), "*") || punct(self.peek_at(1), "(")) {
+            let inner = self.position + 1;
+            self.skip_group();
+            let ty = self.suffixes(ty)?;
+            let end = self.position;
+            self.position = inner;
+            let (name, ty) = self.declarator(ty)?;
+            self.expect(")")?;
+            self.position = end;
+            return Ok((name, ty));
+        }
+        let name = match ident(self.peek()) {
+            Some(name) if !ATTRIBUTES.contains(&name) => {
+                let name = name.to_string();
+                self.position += 1;
+                Some(name)
+            }
+            _ => None,
+        };
+        let ty = self.suffixes(ty)?;
+        self.skip_attributes();
+        // Same for the end of a prototype, `int f(void) __THROW __nonnull((1));`
+        if let Ty::Function { .. } = ty {
+            while let Some(word) = ident(self.peek()) && !self.starts_type(word) {
+                self.position += 1;
+                if self.at_punct("(") {self.skip_group()}
+                self.skip_attributes();
+            }
+        }
+        Ok((name, ty))
+    }
+
+    /// `[N]` and `(parameters)` after a name, the rightmost one is the innermost
+    fn suffixes(&mut self, ty: Ty) -> Result<Ty, String> {
+        let mut suffixes: Vec<Box<dyn FnOnce(Ty) -> Ty>> = vec![];
+        loop {
+            if self.at_punct("[") {
+                self.position += 1;
+                let size = self.until(&["]"]);
+                self.expect("]")?;
+                let count = if size.is_empty() {0} else {self.constant(&size)? as usize};
+                suffixes.push(Box::new(move |ty| Ty::Array(Box::new(ty), count)));
+            } else if self.at_punct("(") {
+                self.position += 1;
+                let (params, variadic) = self.parameters()?;
+                suffixes.push(Box::new(move |ty| Ty::Function { ret: Box::new(ty), params, variadic }));
+            } else {
+                break;
+            }
+        }
+        Ok(suffixes.into_iter().rev().fold(ty, |ty, suffix| suffix(ty)))
+    }
+
+    /// Function parameters, right after the `(`
+    fn parameters(&mut self) -> Result<(Parameters, bool), String> {
+        let mut params = vec![];
+        let mut variadic = false;
+        if ident(self.peek()) == Some("void") && punct(self.peek_at(1), ")") {self.position += 1}
+        while !self.at_punct(")") {
+            if self.at_punct("...") {
+                self.position += 1;
+                variadic = true;
+                continue;
+            }
+            let specifiers = self.specifiers()?;
+            let (name, ty) = self.declarator(specifiers.ty)?;
+            // Arrays and functions are pointers when they're parameters
+            let ty = match ty {
+                Ty::Array(inner, _) => Ty::Pointer(inner),
+                function @ Ty::Function { .. } => Ty::Pointer(Box::new(function)),
+                ty => ty,
+            };
+            params.push((name, ty));
+            if self.at_punct(",") {self.position += 1} else if !self.at_punct(")") {return Err("expected ',' or ')' in the parameters".to_string())}
+        }
+        self.position += 1;
+        Ok((params, variadic))
+    }
+
+    /// Galvan version of a parameter or return type
+    fn ffi_type(&self, ty: &Ty) -> Result<CType, String> {
+        Ok(match ty {
+            Ty::Void => CType::Void,
+            Ty::Int { bits, signed } => CType::Int { bits: *bits, signed: *signed },
+            Ty::ConstChar => CType::Int { bits: 8, signed: true },
+            Ty::Pointer(inner) if **inner == Ty::ConstChar => CType::Str,
+            Ty::Pointer(inner) => CType::Pointer(Box::new(match self.ffi_type(inner) {
+                Ok(CType::Str) => CType::Pointer(Box::new(CType::Int { bits: 8, signed: true })),
+                Ok(inner) => inner,
+                // Pointers to structs, functions and floats are just addresses
+                Err(_) => CType::Void,
+            })),
+            Ty::Float(_) => return Err("floating point isn't supported".to_string()),
+            Ty::Struct(key) => return Err(format!("struct {} is passed by value", key)),
+            Ty::Array(..) | Ty::Function { .. } => return Err("arrays and functions can't be passed by value".to_string()),
+        })
+    }
+
+    /// Claims a Galvan name in the output, false (with a comment why) if it can't have it
+    fn claim(&mut self, name: &str, what: &str) -> bool {
+        if !is_galvan_name(name) {
+            self.out.push(format!("// skipped {} {}: not a valid Galvan name", what, name));
+            return false;
+        }
+        if !self.names.insert(name.to_string()) {
+            self.out.push(format!("// skipped {} {}: the name is taken already", what, name));
+            return false;
+        }
+        self.claimed.push(name.to_string());
+        true
+    }
+
+    fn constant_item(&mut self, name: &str, value: i64) {
+        if self.claim(name, "constant") {
+            self.out.push(format!("pub const {} = {};", name, galvan_number(value)));
+        }
+    }
+
+    /// `sizeof_<name>` and `offsetof_<name>_<field>` of a struct
+    fn struct_items(&mut self, name: &str, key: &str) {
+        match self.structs.get(key).cloned() {
+            Some(Ok(layout)) => {
+                self.constant_item(&format!("sizeof_{}", name), layout.size as i64);
+                for (field, offset) in layout.fields {
+                    self.constant_item(&format!("offsetof_{}_{}", name, field), offset as i64);
+                }
+            }
+            Some(Err(error)) => self.out.push(format!("// skipped the layout of {}: {}", name, error)),
+            None => {}
+        }
+    }
+
+    fn function_item(&mut self, name: &str, ty: &Ty) -> Result<(), String> {
+        let Ty::Function { ret, params, variadic } = ty else {unreachable!()};
+        let mut parameters = vec![];
+        for (index, (parameter, ty)) in params.iter().enumerate() {
+            let c_type = self.ffi_type(ty).map_err(|error| format!("parameter {}: {}", index + 1, error))?;
+            // Galvan needs a name for every parameter
+            let parameter = match parameter {
+                Some(parameter) if is_galvan_name(parameter) && !parameters.iter().any(|(other, _)| other == parameter) => parameter.clone(),
+                _ => format!("arg{}", index),
+            };
+            parameters.push((parameter, c_type));
+        }
+        let return_type = self.ffi_type(ret)?;
+        if name.contains(MODULE_SEPARATOR) {
+            self.out.push(format!("// skipped function {}: Galvan function names can't have '{}' in them", name, MODULE_SEPARATOR));
+            return Ok(());
+        }
+        if self.claim(name, "function") {
+            let external = Extern { name: name.to_string(), symbol: name.to_string(), parameters, variadic: *variadic, return_type };
+            self.out.push(format!("pub extern \"C\" function {};", external.signature()));
+        }
+        Ok(())
+    }
+
+    /// One declaration (or a bit of `extern "C" {` around them)
+    fn declaration(&mut self) -> Result<(), String> {
+        if ident(self.peek()) == Some("extern") && matches!(self.peek_at(1), Some(Token::Str(abi)) if abi == "C") && punct(self.peek_at(2), "{") {
+            self.position += 3;
+            self.extern_blocks += 1;
+            return Ok(());
+        }
+        if self.at_punct("}") && self.extern_blocks > 0 {
+            self.position += 1;
+            self.extern_blocks -= 1;
+            return Ok(());
+        }
+        if self.at_punct(";") {
+            self.position += 1;
+            return Ok(());
+        }
+
+        let mut specifiers = self.specifiers()?;
+        let mut named = None;
+        while !self.at_punct(";") {
+            let (name, ty) = self.declarator(specifiers.ty.clone())?;
+            let Some(name) = name else {return Err("expected a name".to_string())};
+            if specifiers.typedef {
+                if specifiers.defined.as_ref().is_some_and(|key| ty == Ty::Struct(key.clone())) && named.is_none() {
+                    named = Some(name.clone());
+                }
+                self.typedefs.insert(name, ty);
+            } else if let Ty::Function { .. } = ty {
+                if specifiers.local {
+                    self.out.push(format!("// skipped function {}: it's static, so there's nothing to link against", name));
+                } else {
+                    self.function_item(&name, &ty)?;
+                }
+                // A definition right in the header
+                if self.at_punct("{") {
+                    self.skip_group();
+                    return Ok(());
+                }
+            } else {
+                self.out.push(format!("// skipped variable {}: C globals can't be used from Galvan", name));
+            }
+            if self.at_punct("=") {
+                self.position += 1;
+                self.until(&[";"]);
+            }
+            if self.at_punct(",") {self.position += 1} else {break}
+        }
+        self.expect(";")?;
+        // Struct constants are named after the typedef if there is one, the tag otherwise
+        if let Some(key) = specifiers.defined.take() {
+            match named {
+                Some(name) => self.struct_items(&name, &key),
+                None if !key.starts_with('<') => self.struct_items(&key.clone(), &key),
+                None => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Goes past the rest of a declaration that didn't work out, for the comment
+    fn skip_declaration(&mut self, start: usize) {
+        self.position = start;
+        let mut depth = 0;
+        while let Some(token) = self.next() {
+            match &token {
+                Token::Punct(punct) if ["(", "[", "{"].contains(&punct.as_str()) => depth += 1,
+                Token::Punct(punct) if [")", "]"].contains(&punct.as_str()) => depth -= 1,
+                // A function body ends the declaration, a struct body doesn't
+                Token::Punct(punct) if punct == "}" => {
+                    depth -= 1;
+                    if depth == 0 && self.tokens[start..self.position].iter().rev().skip_while(|token| !punct_token(token, "{")).nth(1).is_some_and(|token| punct_token(token, ")")) {
+                        return;
+                    }
+                }
+                Token::Punct(punct) if punct == ";" && depth <= 0 => return,
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Arguments of a macro call, `open` is the `(`. Also gives back where the call ends.
+fn arguments(tokens: &[Token], open: usize) -> Option<(Vec<Vec<Token>>, usize)> {
+    let mut arguments = vec![vec![]];
+    let mut depth = 0;
+    for (index, token) in tokens.iter().enumerate().skip(open + 1) {
+        match token {
+            Token::Punct(punct) if punct == ")" && depth == 0 => return Some((arguments, index + 1)),
+            Token::Punct(punct) if punct == "," && depth == 0 => arguments.push(vec![]),
+            token => {
+                if punct_token(token, "(") {depth += 1}
+                if punct_token(token, ")") {depth -= 1}
+                arguments.last_mut().unwrap().push(token.clone());
+            }
+        }
+    }
+    None
+}
+
+fn punct_token(token: &Token, expected: &str) -> bool {
+    punct(Some(token), expected)
+}
+
+/// C integer constant expressions, for `#define`s, `#if`s, enum values and array sizes
+struct Evaluator<'a> {
+    tokens: &'a [Token],
+    position: usize,
+    bindgen: &'a Bindgen,
+    /// In `#if`, names nobody defined are 0
+    unknown_zero: bool,
+}
+impl Evaluator<'_> {
+    const LEVELS: [&'static [&'static str]; 10] = [
+        &["||"], &["&&"], &["|"], &["^"], &["&"], &["==", "!="], &["<", ">", "<=", ">="], &["<<", ">>"], &["+", "-"], &["*", "/", "%"],
+    ];
+
+    fn evaluate(&mut self) -> Result<i64, String> {
+        let condition = self.binary(0)?;
+        if !punct(self.tokens.get(self.position), "?") {return Ok(condition)}
+        self.position += 1;
+        let then = self.evaluate()?;
+        if !punct(self.tokens.get(self.position), ":") {return Err("expected ':'".to_string())}
+        self.position += 1;
+        let otherwise = self.evaluate()?;
+        Ok(if condition != 0 {then} else {otherwise})
+    }
+
+    fn binary(&mut self, level: usize) -> Result<i64, String> {
+        if level == Evaluator::LEVELS.len() {return self.unary()}
+        let mut left = self.binary(level + 1)?;
+        while let Some(Token::Punct(operator)) = self.tokens.get(self.position) {
+            if !Evaluator::LEVELS[level].contains(&operator.as_str()) {break}
+            let operator = operator.clone();
+            self.position += 1;
+            let right = self.binary(level + 1)?;
+            left = match operator.as_str() {
+                "||" => (left != 0 || right != 0) as i64,
+                "&&" => (left != 0 && right != 0) as i64,
+                "|" => left | right,
+                "^" => left ^ right,
+                "&" => left & right,
+                "==" => (left == right) as i64,
+                "!=" => (left != right) as i64,
+                "<" => (left < right) as i64,
+                ">" => (left > right) as i64,
+                "<=" => (left <= right) as i64,
+                ">=" => (left >= right) as i64,
+                "<<" => left.wrapping_shl(right as u32),
+                ">>" => left.wrapping_shr(right as u32),
+                "+" => left.wrapping_add(right),
+                "-" => left.wrapping_sub(right),
+                "*" => left.wrapping_mul(right),
+                _ if right == 0 => return Err("division by zero".to_string()),
+                "/" => left.wrapping_div(right),
+                _ => left.wrapping_rem(right),
+            };
+        }
+        Ok(left)
+    }
+
+    /// `(type)` or `sizeof(type)` contents, just builtin types, typedefs, tags and pointers
+    fn type_name(&self, tokens: &[Token]) -> Option<Ty> {
+        let mut words = vec![];
+        let mut ty = None;
+        let mut tokens = tokens.iter().peekable();
+        while let Some(Token::Ident(word)) = tokens.peek() {
+            tokens.next();
+            match word.as_str() {
+                word if IGNORED_WORDS.contains(&word) => {}
+                word if TYPE_WORDS.contains(&word) => words.push(word.to_string()),
+                "enum" => {tokens.next(); ty = Some(Ty::Int { bits: 32, signed: true })}
+                "struct" | "union" => ty = Some(Ty::Struct(ident(tokens.next())?.to_string())),
+                name => ty = Some(self.bindgen.typedefs.get(name).cloned().or_else(|| builtin_typedef(name))?),
+            }
+        }
+        let mut ty = match ty {
+            Some(ty) => ty,
+            None => word_type(&words, false).ok()?,
+        };
+        for token in tokens {
+            if !punct_token(token, "*") {return None}
+            ty = Ty::Pointer(Box::new(ty));
+        }
+        Some(ty)
+    }
+
+    /// The tokens of a `( ... )` group starting at the current position, and where it ends
+    fn group(&self) -> Option<(&[Token], usize)> {
+        let mut depth = 0;
+        for (index, token) in self.tokens.iter().enumerate().skip(self.position) {
+            if punct_token(token, "(") {depth += 1}
+            if punct_token(token, ")") {
+                depth -= 1;
+                if depth == 0 {return Some((&self.tokens[self.position + 1..index], index + 1))}
+            }
+        }
+        None
+    }
+
+    fn unary(&mut self) -> Result<i64, String> {
+        let token = self.tokens.get(self.position).cloned().ok_or("expected a value")?;
+        match token {
+            Token::Punct(operator) if ["-", "+", "~", "!"].contains(&operator.as_str()) => {
+                self.position += 1;
+                let value = self.unary()?;
+                Ok(match operator.as_str() {
+                    "-" => value.wrapping_neg(),
+                    "~" => !value,
+                    "!" => (value == 0) as i64,
+                    _ => value,
+                })
+            }
+            Token::Punct(open) if open == "(" => {
+                let (inside, end) = self.group().ok_or("unbalanced parentheses")?;
+                // A cast, integers get cut down to the type
+                if let Some(ty) = self.type_name(inside) {
+                    self.position = end;
+                    let value = self.unary()?;
+                    return Ok(match ty {
+                        Ty::Int { bits: 64, .. } | Ty::Pointer(_) => value,
+                        Ty::Int { bits, signed: true } => (value << (64 - bits)) >> (64 - bits),
+                        Ty::Int { bits, signed: false } => value & ((1i64 << bits) - 1),
+                        _ => return Err("only casts to integers and pointers work in constants".to_string()),
+                    });
+                }
+                self.position += 1;
+                let value = self.evaluate()?;
+                if !punct(self.tokens.get(self.position), ")") {return Err("expected ')'".to_string())}
+                self.position += 1;
+                Ok(value)
+            }
+            Token::Ident(word) if word == "sizeof" => {
+                self.position += 1;
+                let (inside, end) = self.group().ok_or("sizeof needs a type in parentheses")?;
+                let ty = self.type_name(inside).ok_or("sizeof only works on types")?;
+                self.position = end;
+                Ok(self.bindgen.size_align(&ty)?.0 as i64)
+            }
+            Token::Number(value) => {
+                self.position += 1;
+                Ok(value)
+            }
+            Token::Ident(name) => {
+                self.position += 1;
+                match self.bindgen.constants.get(&name) {
+                    Some(value) => Ok(*value),
+                    None if self.unknown_zero => Ok(0),
+                    None => Err(format!("'{}' isn't a constant", name)),
+                }
+            }
+            Token::Float => Err("floating point isn't supported".to_string()),
+            Token::Str(_) => Err("only integers become constants, not strings".to_string()),
+            other => Err(format!("unexpected {} in a constant", render(&[other]))),
+        }
+    }
+}
+
+/// Galvan source for the header at `path`, `include` are the directories to look in for its
+/// `#include "..."`s after its own
+pub fn bindgen(path: &Path, include: &[PathBuf]) -> Result<String, String> {
+    let mut bindgen = Bindgen::new(include);
+    bindgen.preprocess(path)?;
+
+    while bindgen.position < bindgen.tokens.len() {
+        let start = bindgen.position;
+        let (out, claimed) = (bindgen.out.len(), bindgen.claimed.len());
+        if let Err(error) = bindgen.declaration() {
+            // Whatever it put out before failing goes too
+            bindgen.out.truncate(out);
+            for name in bindgen.claimed.split_off(claimed) {
+                bindgen.names.remove(&name);
+            }
+            bindgen.skip_declaration(start);
+            let end = bindgen.position.min(bindgen.tokens.len());
+            let declaration = render(&bindgen.tokens[start..end]);
+            let declaration = declaration.trim_end_matches(';');
+            bindgen.out.push(format!("// skipped {}: {}", declaration, error));
+        }
+    }
+
+    // Defines last, they can use enum values. Only the ones that are integers turn into
+    // anything, the rest (types, attributes, strings, include guards) get a comment saying why
+    let mut defines = vec![];
+    let out = std::mem::take(&mut bindgen.out);
+    for name in bindgen.defines.clone() {
+        let expanded = bindgen.expand(&[Token::Ident(name.clone())], &mut vec![]);
+        if name.starts_with("__") {
+            bindgen.out.push(format!("// skipped define {}: names starting with __ are the compiler's", name));
+            continue;
+        }
+        if expanded.is_empty() {
+            bindgen.out.push(format!("// skipped define {}: it's empty, there's no value", name));
+            continue;
+        }
+        match bindgen.constant(&expanded) {
+            Ok(value) => bindgen.constant_item(&name, value),
+            Err(error) => bindgen.out.push(format!("// skipped define {}: {}", name, error)),
+        }
+    }
+    defines.append(&mut bindgen.out);
+
+    let mut source = format!("// Generated by galvan bindgen from {}\n", path.display());
+    for line in defines.iter().chain(&out) {
+        source.push_str(line);
+        source.push('\n');
+    }
+    Ok(source)
+}
@@ -0,0 +1,533 @@
+use std::collections::HashMap;
+
+use crate::compiler_settings::*;
+use crate::interpreter::Value;
+use crate::intrinsics::{self, INTRINSICS};
+use crate::ir::{BinaryOp, Instruction, Module, Operand, Terminator};
+
+// Bytecode for the stack VM (vm.rs), compiled from the IR.
+// Every IR local and virtual register gets a slot in the call frame, instructions push and
+// pop values on the VM stack. Jump targets are instruction indices inside the function.
+//
+// .gvc file layout, all integers little endian:
+//   magic "GVC\0", u32 version
+//   u32 constant count, then per constant: u8 tag (0 = int, 1 = str), i64 or u32 length + bytes
+//   u32 function count, then per function: u32 name length + name, u32 parameters,
+//       u32 slots, u32 instruction count + instructions (u8 opcode + u32 operands)
+//       (intrinsics are numbered in intrinsics::INTRINSICS order)
+//   u32 entry function index
+
+//
+// STRUCTS
+//
+
+const BINARY_OPS: [BinaryOp; 10] = [BinaryOp::Add, BinaryOp::Sub, BinaryOp::Mul, BinaryOp::Div, BinaryOp::Lt,
+    BinaryOp::Gt, BinaryOp::Le, BinaryOp::Ge, BinaryOp::Eq, BinaryOp::Ne];
+
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+#[derive(PartialEq)]
+pub enum Op {
+    /// Push constant pool entry
+    Const(u32),
+    /// Push a frame slot
+    Load(u32),
+    /// Pop into a frame slot
+    Store(u32),
+    /// Throw away the top of the stack
+    Pop,
+    /// Pop right, pop left, push the result
+    Binary(BinaryOp),
+    Jump(u32),
+    /// Pop, jump if it's zero
+    JumpIfZero(u32),
+    /// `call function, argument count`, the arguments are on the stack, the return value replaces them
+    Call(u32, u32),
+    /// The builtin print, pops its arguments, pushes nothing
+    Print(u32),
+    /// Calls an intrinsic by index, pops its arguments, pushes the result unless it's void
+    Intrinsic(u32),
+    /// Pop the return value and go back to the caller
+    Return,
+}
+
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+pub struct BytecodeFunction {
+    pub name: String,
+    /// The first `parameters` slots are the arguments
+    pub parameters: u32,
+    pub slots: u32,
+    pub code: Vec<Op>,
+}
+
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+pub struct Program {
+    pub constants: Vec<Value>,
+    pub functions: Vec<BytecodeFunction>,
+    /// Index of ENTRY_FUNCTION
+    pub entry: u32,
+}
+
+//
+// COMPILING
+//
+
+struct Compiler {
+    constants: Vec<Value>,
+    constant_indices: HashMap<String, u32>,
+    function_indices: HashMap<String, u32>,
+    /// How many times each register of the current function gets read
+    uses: Vec<usize>,
+    /// Where the current block's code starts
+    block_start: usize,
+}
+impl Compiler {
+    /// Constant pool index of a value, same values share an entry
+    fn constant(&mut self, value: Value) -> u32 {
+        let key = format!("{:?}", value);
+        if let Some(index) = self.constant_indices.get(&key) {return *index}
+        self.constants.push(value);
+        let index = self.constants.len() as u32 - 1;
+        self.constant_indices.insert(key, index);
+        index
+    }
+
+    fn push(&mut self, code: &mut Vec<Op>, operand: &Operand, locals: usize) {
+        match operand {
+            Operand::Register(register) => {
+                let slot = (locals + register) as u32;
+                // `store %r` right before the only `load %r` cancel out, the value just stays on the stack
+                if self.uses[*register] == 1 && code.len() > self.block_start && code.last() == Some(&Op::Store(slot)) {
+                    code.pop();
+                } else {
+                    code.push(Op::Load(slot));
+                }
+            }
+            Operand::Constant(value) => {
+                let index = self.constant(Value::Int(*value));
+                code.push(Op::Const(index));
+            }
+        }
+    }
+
+    fn function(&mut self, module: &Module, function: &crate::ir::Function) -> Result<BytecodeFunction, String> {
+        // Locals first, then one slot per virtual register
+        let locals = function.locals.len();
+        let register = |register: usize| Op::Store((locals + register) as u32);
+        let mut code = vec![];
+        let mut block_starts = vec![];
+        // (instruction index, block) of jumps waiting for the block's start
+        let mut fixups = vec![];
+
+        self.uses = vec![0; function.registers.len()];
+        let mut count = |operand: &Operand| if let Operand::Register(register) = operand {self.uses[*register] += 1};
+        for block in &function.blocks {
+            for instruction in &block.instructions {
+                match instruction {
+                    Instruction::Copy { value, .. } | Instruction::Store { value, .. } => count(value),
+                    Instruction::Binary { left, right, .. } => {count(left); count(right)}
+                    Instruction::Call { args, .. } => args.iter().for_each(&mut count),
+                    Instruction::StringAddress { .. } | Instruction::Load { .. } | Instruction::Asm { .. } | Instruction::Line { .. } => {}
+                }
+            }
+            match &block.terminator {
+                Terminator::Branch { condition, .. } => count(condition),
+                Terminator::Return(Some(value)) => count(value),
+                _ => {}
+            }
+        }
+
+        for (index, block) in function.blocks.iter().enumerate() {
+            self.block_start = code.len();
+            block_starts.push(code.len() as u32);
+            for instruction in &block.instructions {
+                match instruction {
+                    Instruction::Copy { dest, value } => {
+                        self.push(&mut code, value, locals);
+                        code.push(register(*dest));
+                    }
+                    Instruction::StringAddress { dest, index } => {
+                        let constant = self.constant(Value::Str(module.strings[*index].clone()));
+                        code.push(Op::Const(constant));
+                        code.push(register(*dest));
+                    }
+                    Instruction::Binary { dest, op, left, right } => {
+                        self.push(&mut code, left, locals);
+                        self.push(&mut code, right, locals);
+                        code.push(Op::Binary(*op));
+                        code.push(register(*dest));
+                    }
+                    Instruction::Load { dest, local } => {
+                        code.push(Op::Load(*local as u32));
+                        code.push(register(*dest));
+                    }
+                    Instruction::Store { local, value } => {
+                        self.push(&mut code, value, locals);
+                        code.push(Op::Store(*local as u32));
+                    }
+                    Instruction::Call { dest, function: target, args } => {
+                        for arg in args {
+                            self.push(&mut code, arg, locals);
+                        }
+                        if target == "print" {
+                            code.push(Op::Print(args.len() as u32));
+                            continue;
+                        }
+                        if let Some(index) = intrinsics::index(target) {
+                            code.push(Op::Intrinsic(index as u32));
+                            if let Some(dest) = dest {code.push(register(*dest))}
+                            continue;
+                        }
+                        if let Some(external) = module.external(target) {
+                            return Err(format!("'{}' calls C function '{}', which can't run on the VM, compile the program and link it instead", function.name, external.symbol));
+                        }
+                        let Some(callee) = self.function_indices.get(target) else {
+                            return Err(format!("Call to unknown function '{}' in '{}'", target, function.name));
+                        };
+                        code.push(Op::Call(*callee, args.len() as u32));
+                        match dest {
+                            Some(dest) => code.push(register(*dest)),
+                            None => code.push(Op::Pop),
+                        }
+                    }
+                    Instruction::Asm { .. } => {
+                        return Err(format!("Inline assembly in '{}' can't run on the VM, compile the program to assembly or C instead", function.name));
+                    }
+                    Instruction::Line { .. } => {}
+                }
+            }
+
+            // Jumps to the very next block are left out
+            let next = index + 1;
+            match &block.terminator {
+                Terminator::Jump(target) => {
+                    if *target != next {
+                        fixups.push((code.len(), *target));
+                        code.push(Op::Jump(0));
+                    }
+                }
+                Terminator::Branch { condition, then_block, else_block } => {
+                    self.push(&mut code, condition, locals);
+                    fixups.push((code.len(), *else_block));
+                    code.push(Op::JumpIfZero(0));
+                    if *then_block != next {
+                        fixups.push((code.len(), *then_block));
+                        code.push(Op::Jump(0));
+                    }
+                }
+                Terminator::Return(value) => {
+                    match value {
+                        Some(value) => self.push(&mut code, value, locals),
+                        // Every call leaves exactly one value behind, even void ones
+                        None => {
+                            let index = self.constant(Value::Int(0));
+                            code.push(Op::Const(index));
+                        }
+                    }
+                    code.push(Op::Return);
+                }
+            }
+        }
+
+        for (at, block) in fixups {
+            let target = block_starts[block];
+            code[at] = match code[at] {
+                Op::Jump(_) => Op::Jump(target),
+                Op::JumpIfZero(_) => Op::JumpIfZero(target),
+                other => other,
+            };
+        }
+
+        // Registers that never needed a slot at the end don't get one
+        let parameters = function.parameters as u32;
+        Ok(BytecodeFunction { name: function.name.clone(), parameters, slots: used_slots(parameters, &code), code })
+    }
+}
+
+/// Compiles an IR module into bytecode
+pub fn compile(module: &Module) -> Result<Program, String> {
+    if debug_prints(BYTECODE_DEBUG_PRINTS) {eprintln!("- - - BYTECODE")}
+
+    let mut compiler = Compiler {
+        constants: vec![], constant_indices: HashMap::new(), function_indices: HashMap::new(), uses: vec![], block_start: 0,
+    };
+    for (index, function) in module.functions.iter().enumerate() {
+        compiler.function_indices.insert(function.name.clone(), index as u32);
+    }
+    let Some(entry) = compiler.function_indices.get(ENTRY_FUNCTION).copied() else {
+        return Err(format!("No '{}' function to start from, a library can't run on its own", ENTRY_FUNCTION));
+    };
+    let mut functions = vec![];
+    for function in &module.functions {
+        functions.push(compiler.function(module, function)?);
+    }
+
+    let program = Program { constants: compiler.constants, functions, entry };
+    verify(&program)?;
+    if debug_prints(BYTECODE_DEBUG_PRINTS) {eprintln!("{}", program)}
+    if debug_prints(BYTECODE_DEBUG_PRINTS) {eprintln!("- - - Bytecode done!")}
+    Ok(program)
+}
+
+/// Checks every index in the program, so the VM doesn't have to trust .gvc files
+/// The parameters and every slot the code loads or stores, the most a frame needs
+fn used_slots(parameters: u32, code: &[Op]) -> u32 {
+    code.iter().filter_map(|op| match op {
+        Op::Load(slot) | Op::Store(slot) => Some(slot.saturating_add(1)),
+        _ => None,
+    }).fold(parameters, u32::max)
+}
+
+pub fn verify(program: &Program) -> Result<(), String> {
+    if program.entry as usize >= program.functions.len() {
+        return Err("Entry function out of range".to_string());
+    }
+    for function in &program.functions {
+        if function.parameters > function.slots {
+            return Err(format!("'{}' has more parameters than slots", function.name));
+        }
+        // The VM makes room for all of them on every call, a made up count would be gigabytes
+        if function.slots > used_slots(function.parameters, &function.code) {
+            return Err(format!("'{}' has more slots than it uses", function.name));
+        }
+        let error = |at: usize, what: &str| Err(format!("{} out of range at {}:{:04}", what, function.name, at));
+        for (at, op) in function.code.iter().enumerate() {
+            match op {
+                Op::Const(index) if *index as usize >= program.constants.len() => return error(at, "Constant"),
+                Op::Load(slot) | Op::Store(slot) if *slot >= function.slots => return error(at, "Slot"),
+                Op::Jump(target) | Op::JumpIfZero(target) if *target as usize >= function.code.len() => return error(at, "Jump target"),
+                Op::Intrinsic(index) if *index as usize >= INTRINSICS.len() => return error(at, "Intrinsic"),
+                Op::Call(callee, args) => {
+                    let Some(callee) = program.functions.get(*callee as usize) else {return error(at, "Function")};
+                    if callee.parameters != *args {
+                        return Err(format!("'{}' takes {} arguments, {} given at {}:{:04}", callee.name, callee.parameters, args, function.name, at));
+                    }
+                }
+                _ => {}
+            }
+        }
+        // Running off the end of the code is never fine
+        if !matches!(function.code.last(), Some(Op::Return | Op::Jump(_))) {
+            return Err(format!("'{}' doesn't end in a return or jump", function.name));
+        }
+    }
+    Ok(())
+}
+
+//
+// DISASSEMBLER
+//
+
+impl Op {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Op::Const(_) => "const",
+            Op::Load(_) => "load",
+            Op::Store(_) => "store",
+            Op::Pop => "pop",
+            Op::Binary(op) => op.name(),
+            Op::Jump(_) => "jump",
+            Op::JumpIfZero(_) => "jz",
+            Op::Call(..) => "call",
+            Op::Print(_) => "print",
+            Op::Intrinsic(_) => "intrinsic",
+            Op::Return => "ret",
+        }
+    }
+}
+
+impl std::fmt::Display for Program {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (index, constant) in self.constants.iter().enumerate() {
+            match constant {
+                Value::Str(string) => writeln!(f, "const c{} = {:?}", index, string)?,
+                other => writeln!(f, "const c{} = {}", index, other)?,
+            }
+        }
+        for (index, function) in self.functions.iter().enumerate() {
+            writeln!(f)?;
+            let entry = if index as u32 == self.entry {" (entry)"} else {""};
+            writeln!(f, "function {}{}: {} parameters, {} slots", function.name, entry, function.parameters, function.slots)?;
+            for (at, op) in function.code.iter().enumerate() {
+                write!(f, "    {:04}  {}", at, op.name())?;
+                match op {
+                    Op::Const(index) => match &self.constants[*index as usize] {
+                        Value::Str(string) => write!(f, " c{}  ; {:?}", index, string)?,
+                        other => write!(f, " c{}  ; {}", index, other)?,
+                    },
+                    Op::Load(slot) | Op::Store(slot) => write!(f, " {}", slot)?,
+                    Op::Jump(target) | Op::JumpIfZero(target) => write!(f, " {:04}", target)?,
+                    Op::Call(callee, args) => write!(f, " {}, {}", self.functions[*callee as usize].name, args)?,
+                    Op::Print(args) => write!(f, " {}", args)?,
+                    Op::Intrinsic(index) => write!(f, " {}", INTRINSICS[*index as usize].name)?,
+                    _ => {}
+                }
+                writeln!(f)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+//
+// .gvc FILES
+//
+
+fn opcode(op: &Op) -> u8 {
+    match op {
+        Op::Const(_) => 0,
+        Op::Load(_) => 1,
+        Op::Store(_) => 2,
+        Op::Pop => 3,
+        Op::Jump(_) => 4,
+        Op::JumpIfZero(_) => 5,
+        Op::Call(..) => 6,
+        Op::Print(_) => 7,
+        Op::Return => 8,
+        Op::Intrinsic(_) => 9,
+        Op::Binary(op) => 16 + BINARY_OPS.iter().position(|other| other == op).unwrap() as u8,
+    }
+}
+
+fn write_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn write_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    write_u32(out, bytes.len() as u32);
+    out.extend_from_slice(bytes);
+}
+
+/// Serializes the program into the .gvc format
+pub fn write_gvc(program: &Program) -> Vec<u8> {
+    let mut out = GVC_MAGIC.to_vec();
+    write_u32(&mut out, GVC_VERSION);
+
+    write_u32(&mut out, program.constants.len() as u32);
+    for constant in &program.constants {
+        match constant {
+            Value::Str(string) => {
+                out.push(1);
+                write_bytes(&mut out, string.as_bytes());
+            }
+            Value::Int(value) => {
+                out.push(0);
+                out.extend_from_slice(&value.to_le_bytes());
+            }
+            // Never ends up in the pool
+            Value::Void => out.push(0xFF),
+        }
+    }
+
+    write_u32(&mut out, program.functions.len() as u32);
+    for function in &program.functions {
+        write_bytes(&mut out, function.name.as_bytes());
+        write_u32(&mut out, function.parameters);
+        write_u32(&mut out, function.slots);
+        write_u32(&mut out, function.code.len() as u32);
+        for op in &function.code {
+            out.push(opcode(op));
+            match op {
+                Op::Const(value) | Op::Load(value) | Op::Store(value) | Op::Jump(value)
+                    | Op::JumpIfZero(value) | Op::Print(value) | Op::Intrinsic(value) => write_u32(&mut out, *value),
+                Op::Call(callee, args) => {
+                    write_u32(&mut out, *callee);
+                    write_u32(&mut out, *args);
+                }
+                Op::Pop | Op::Return | Op::Binary(_) => {}
+            }
+        }
+    }
+
+    write_u32(&mut out, program.entry);
+    out
+}
+
+/// Cursor over the bytes of a .gvc file
+struct Reader<'a> {
+    bytes: &'a [u8],
+    at: usize,
+}
+impl Reader<'_> {
+    fn take(&mut self, count: usize) -> Result<&[u8], String> {
+        if self.bytes.len() - self.at < count {return Err("Unexpected end of file".to_string())}
+        self.at += count;
+        Ok(&self.bytes[self.at - count..self.at])
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn i64(&mut self) -> Result<i64, String> {
+        Ok(i64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let length = self.u32()? as usize;
+        match String::from_utf8(self.take(length)?.to_vec()) {
+            Ok(string) => Ok(string),
+            Err(_) => Err("String isn't valid UTF-8".to_string()),
+        }
+    }
+}
+
+/// Reads (and verifies) a .gvc file
+pub fn read_gvc(bytes: &[u8]) -> Result<Program, String> {
+    let mut reader = Reader { bytes, at: 0 };
+    if reader.take(GVC_MAGIC.len()).ok() != Some(GVC_MAGIC.as_slice()) {
+        return Err("Not a .gvc file".to_string());
+    }
+    let version = reader.u32()?;
+    if version != GVC_VERSION {
+        return Err(format!(".gvc version {} isn't supported, only {}", version, GVC_VERSION));
+    }
+
+    let mut constants = vec![];
+    for _ in 0..reader.u32()? {
+        constants.push(match reader.u8()? {
+            0 => Value::Int(reader.i64()?),
+            1 => Value::Str(reader.string()?),
+            tag => return Err(format!("Unknown constant tag {}", tag)),
+        });
+    }
+
+    let mut functions = vec![];
+    for _ in 0..reader.u32()? {
+        let name = reader.string()?;
+        let parameters = reader.u32()?;
+        let slots = reader.u32()?;
+        let mut code = vec![];
+        for _ in 0..reader.u32()? {
+            code.push(match reader.u8()? {
+                0 => Op::Const(reader.u32()?),
+                1 => Op::Load(reader.u32()?),
+                2 => Op::Store(reader.u32()?),
+                3 => Op::Pop,
+                4 => Op::Jump(reader.u32()?),
+                5 => Op::JumpIfZero(reader.u32()?),
+                6 => Op::Call(reader.u32()?, reader.u32()?),
+                7 => Op::Print(reader.u32()?),
+                8 => Op::Return,
+                9 => Op::Intrinsic(reader.u32()?),
+                opcode if (16..16 + BINARY_OPS.len() as u8).contains(&opcode) => Op::Binary(BINARY_OPS[(opcode - 16) as usize]),
+                opcode => return Err(format!("Unknown opcode {} in '{}'", opcode, name)),
+            });
+        }
+        functions.push(BytecodeFunction { name, parameters, slots, code });
+    }
+    let entry = reader.u32()?;
+    if reader.at != bytes.len() {return Err("Trailing bytes after the program".to_string())}
+
+    let program = Program { constants, functions, entry };
+    verify(&program)?;
+    Ok(program)
+}
@@ -0,0 +1,534 @@
+use crate::compiler_settings::*;
+use crate::ffi::{CType, Extern};
+use crate::ir::symbol;
+use crate::parser::{self, expand_asm, Attributes, Expression, InlineAsm, Operator, Statement};
+use crate::intrinsics;
+use crate::seman::{check_expression, Analysis, FunctionInfo, Scope, Type};
+use crate::source_map::file_path;
+
+// C99 backend. Works straight from the checked AST instead of the IR so the output keeps
+// the original while/if structure and stays readable. Every statement gets a `#line`
+// directive, so C compiler warnings and debuggers point at the .gv file.
+// Extern and exported functions keep their C symbol through GCC's `__asm__("symbol")` labels,
+// so they can't clash with anything the generated code declares itself.
+// Freestanding programs only get <stdint.h>, and start at `_start` (or --entry) instead of main.
+// Arithmetic goes through small inline helpers so it wraps around instead of being undefined.
+
+const C_KEYWORDS: [&str; 37] = [
+    "auto", "break", "case", "char", "const", "continue", "default", "do", "double", "else",
+    "enum", "extern", "float", "for", "goto", "if", "inline", "int", "long", "register",
+    "restrict", "return", "short", "signed", "sizeof", "static", "struct", "switch", "typedef",
+    "union", "unsigned", "void", "volatile", "while", "_Bool", "_Complex", "_Imaginary",
+];
+
+fn c_type(ty: Type) -> &'static str {
+    match ty {
+        Type::Int => "int64_t",
+        Type::Str => "const char *",
+        Type::Void => "void",
+    }
+}
+
+/// Galvan variable names are valid C names, unless they happen to be a keyword
+fn variable_name(name: &str) -> String {
+    if C_KEYWORDS.contains(&name) {format!("{}_", name)} else {name.to_string()}
+}
+
+/// `type name` with the pointer star attached to the name
+fn declaration(ty: Type, name: &str) -> String {
+    let ty = c_type(ty);
+    if ty.ends_with('*') {format!("{}{}", ty, name)} else {format!("{} {}", ty, name)}
+}
+
+fn parameters(info: &FunctionInfo) -> Vec<(String, Type)> {
+    info.parameters.iter().cloned().zip(info.parameter_types.iter().copied()).collect()
+}
+
+/// Body of an intrinsic's helper function, see intrinsics.rs. Parameters are a, b and c.
+fn intrinsic_body(name: &str) -> &'static str {
+    match name {
+        "__alloc" => "void *p = calloc(1, a ? (size_t)a : 1); if (!p) {fputs(\"out of memory\\n\", stderr); exit(1);} return (int64_t)(intptr_t)p;",
+        "__load8" => "return *(uint8_t *)(intptr_t)a;",
+        "__store8" => "*(uint8_t *)(intptr_t)a = (uint8_t)b;",
+        "__str_byte" => "return (uint8_t)a[b];",
+        "__str_at" => "return (const char *)(intptr_t)a;",
+        "__sys_read" => "ssize_t r = read((int)a, (void *)(intptr_t)b, (size_t)c); return r < 0 ? -errno : r;",
+        // printf() output is buffered, write() isn't, keep them in order
+        "__sys_write" => "fflush(stdout); ssize_t r = write((int)a, (const void *)(intptr_t)b, (size_t)c); return r < 0 ? -errno : r;",
+        "__sys_write_str" => "fflush(stdout); ssize_t r = write((int)a, b, strlen(b)); return r < 0 ? -errno : r;",
+        "__sys_open" => "int r = open(a, (int)b, (mode_t)c); return r < 0 ? -errno : r;",
+        "__sys_close" => "return close((int)a) < 0 ? -errno : 0;",
+        "__sys_exit" => "fflush(stdout); exit((int)a);",
+        _ => unreachable!("every intrinsic has a C version"),
+    }
+}
+
+/// Whether `statements` call `function` anywhere
+pub fn calls(statements: &[Statement], function: &str) -> bool {
+    fn in_expression(expression: &Expression, function: &str) -> bool {
+        match expression {
+            Expression::FunctionCall { target, args, .. } => target == function || args.iter().any(|arg| in_expression(arg, function)),
+            Expression::Operation(operation) => in_expression(&operation.left, function) || in_expression(&operation.right, function),
+            Expression::ReturnValue { value } => in_expression(value, function),
+            _ => false,
+        }
+    }
+    statements.iter().any(|statement| match statement {
+        Statement::ExpressionStatement(expression, _) => in_expression(expression, function),
+        Statement::VariableAssignment { value, .. } => in_expression(value, function),
+        Statement::FunctionAssignment { body, .. } => calls(body, function),
+        Statement::While { condition, body, .. } => in_expression(condition, function) || calls(body, function),
+        Statement::ConditionalStatement { condition, body, else_body, .. } => {
+            in_expression(condition, function) || calls(body, function) || else_body.as_ref().is_some_and(|body| calls(body, function))
+        }
+        Statement::Asm { asm, .. } => asm.inputs.iter().any(|(_, value)| in_expression(value, function)),
+        _ => false,
+    })
+}
+
+/// `#[interrupt]` wrappers, with whatever each compiler wants for an interrupt handler. GCC's
+/// x86 one needs -mgeneral-regs-only.
+const INTERRUPT_MACRO: [&str; 11] = [
+    "#if defined(__x86_64__) || defined(__i386__)",
+    "struct gv_interrupt_frame;",
+    "#define GV_INTERRUPT(attributes, name, function) attributes __attribute__((interrupt)) void name(struct gv_interrupt_frame *frame) { (void)frame; function(); }",
+    "#elif defined(__riscv)",
+    "#define GV_INTERRUPT(attributes, name, function) attributes __attribute__((interrupt)) void name(void) { function(); }",
+    "#elif defined(__arm__)",
+    "#define GV_INTERRUPT(attributes, name, function) attributes __attribute__((interrupt(\"IRQ\"))) void name(void) { function(); }",
+    "#else",
+    "#define GV_INTERRUPT(attributes, name, function) attributes void name(void) { function(); }",
+    "#endif",
+    "",
+];
+
+/// `__attribute__((section(".x"))) ` for `#[section(".x")]`, nothing otherwise
+fn section_attribute(attributes: &Attributes) -> String {
+    match &attributes.section {
+        Some(section) => format!("__attribute__((section({}))) ", c_string(section)),
+        None => String::new(),
+    }
+}
+
+fn c_string(value: &str) -> String {
+    let mut out = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            '\r' => out.push_str("\\r"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\{:03o}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Signed overflow is undefined in C, so arithmetic goes through these and wraps around like
+/// it does everywhere else. Dividing INT64_MIN by -1 would trap, it's a negation instead.
+const ARITHMETIC: [&str; 4] = [
+    "static inline int64_t gv_add(int64_t a, int64_t b) { return (int64_t)((uint64_t)a + (uint64_t)b); }",
+    "static inline int64_t gv_sub(int64_t a, int64_t b) { return (int64_t)((uint64_t)a - (uint64_t)b); }",
+    "static inline int64_t gv_mul(int64_t a, int64_t b) { return (int64_t)((uint64_t)a * (uint64_t)b); }",
+    "static inline int64_t gv_div(int64_t a, int64_t b) { return b == -1 ? (int64_t)(0 - (uint64_t)a) : a / b; }",
+];
+
+/// The ARITHMETIC helper for an operator, None for comparisons
+fn helper(operator: Operator) -> Option<&'static str> {
+    match operator {
+        Operator::Multiplication => Some("gv_mul"),
+        Operator::Division => Some("gv_div"),
+        Operator::Addition => Some("gv_add"),
+        Operator::Subtraction => Some("gv_sub"),
+        _ => None,
+    }
+}
+
+fn comparison(operator: Operator) -> &'static str {
+    match operator {
+        Operator::LesserThan => "<",
+        Operator::GreaterThan => ">",
+        Operator::EqualLesserThan => "<=",
+        Operator::EqualGreaterThan => ">=",
+        Operator::EqualTo => "==",
+        Operator::Inequal => "!=",
+        _ => unreachable!("arithmetic goes through a helper"),
+    }
+}
+
+/// Whether the expression is a comparison, the only operators left in the generated C
+fn is_comparison(expression: &Expression) -> bool {
+    matches!(expression, Expression::Operation(operation) if helper(operation.operator).is_none())
+}
+
+/// Whether the generated C expression is already an int64_t. Literals and comparisons
+/// are plain ints in C, which matters for printf.
+fn is_wide(expression: &Expression) -> bool {
+    match expression {
+        Expression::Number(number) => i32::try_from(*number).is_err(),
+        Expression::Operation(_) => !is_comparison(expression),
+        _ => true,
+    }
+}
+
+/// Casts an int expression to int64_t
+fn widen(value: String, expression: &Expression) -> String {
+    if is_comparison(expression) {format!("(int64_t)({})", value)} else {format!("(int64_t){}", value)}
+}
+
+struct CWriter<'a> {
+    out: String,
+    analysis: &'a Analysis,
+    /// Name of the root .gv file, for #line when the source map doesn't know better
+    source: String,
+    indent: usize,
+}
+impl CWriter<'_> {
+    fn line(&mut self, text: &str) {
+        for _ in 0..self.indent {self.out.push_str("    ")}
+        self.out.push_str(text);
+        self.out.push('\n');
+    }
+
+    fn line_directive(&mut self, statement: &Statement) {
+        let location = statement.location();
+        let file = file_path(location.2).unwrap_or(self.source.clone());
+        let line = format!("#line {} {}", location.0, c_string(&file));
+        self.line(&line);
+    }
+
+    fn expression(&self, expression: &Expression, scope: &Scope) -> Result<String, String> {
+        Ok(match expression {
+            Expression::Number(number) => {
+                if *number == i64::MIN {"INT64_MIN".to_string()}
+                else if i32::try_from(*number).is_ok() {number.to_string()}
+                else {format!("INT64_C({})", number)}
+            }
+            Expression::String(string) => c_string(string),
+            Expression::Variable(name, _) => variable_name(name),
+            Expression::Operation(operation) => {
+                let left = self.expression(&operation.left, scope)?;
+                let mut right = self.expression(&operation.right, scope)?;
+                // The helpers take int64_t, so `2 * 3` happens in 64 bits too
+                if let Some(helper) = helper(operation.operator) {
+                    format!("{}({}, {})", helper, left, right)
+                } else {
+                    // Comparisons group left to right, same as in Galvan
+                    if is_comparison(&operation.right) {right = format!("({})", right)}
+                    format!("{} {} {}", left, comparison(operation.operator), right)
+                }
+            }
+            Expression::FunctionCall { target, args, .. } if target == "print" => self.print(args, scope)?,
+            Expression::FunctionCall { target, args, .. } if let Some(external) = &self.analysis.functions[target].external => {
+                self.extern_call(external, args, scope)?
+            }
+            Expression::FunctionCall { target, args, .. } => {
+                let mut c_args = vec![];
+                for arg in args {
+                    c_args.push(self.expression(arg, scope)?);
+                }
+                format!("{}({})", symbol(target), c_args.join(", "))
+            }
+            Expression::ReturnValue { .. } => return Err("Return used as a value".to_string()),
+        })
+    }
+
+    /// Casts between Galvan's values and the C types, addresses are i64 on the Galvan side
+    fn extern_call(&self, external: &Extern, args: &[Expression], scope: &Scope) -> Result<String, String> {
+        let mut c_args = vec![];
+        for (index, arg) in args.iter().enumerate() {
+            let mut value = self.expression(arg, scope)?;
+            let ty = check_expression(arg, scope, &self.analysis.functions)?;
+            match (external.parameters.get(index).map(|(_, c_type)| c_type), ty) {
+                (Some(pointer @ CType::Pointer(_)), Type::Int) => value = format!("({})(intptr_t){}", pointer.c_name(), value),
+                (Some(pointer @ CType::Pointer(_)), _) => value = format!("({}){}", pointer.c_name(), value),
+                // Varargs don't get converted, same as in print()
+                (None, Type::Int) if !is_wide(arg) => value = widen(value, arg),
+                _ => {}
+            }
+            c_args.push(value);
+        }
+        let call = format!("{}({})", symbol(&external.name), c_args.join(", "));
+        Ok(match &external.return_type {
+            CType::Pointer(_) => format!("(int64_t)(intptr_t){}", call),
+            CType::Int { .. } => format!("(int64_t){}", call),
+            CType::Str | CType::Void => call,
+        })
+    }
+
+    /// `print(a, b)` -> `printf("%" PRId64 " %s\n", a, b)`
+    fn print(&self, args: &[Expression], scope: &Scope) -> Result<String, String> {
+        let mut format = String::from("\"");
+        let mut c_args = vec![];
+        for (index, arg) in args.iter().enumerate() {
+            if index > 0 {format.push(' ')}
+            let mut value = self.expression(arg, scope)?;
+            match check_expression(arg, scope, &self.analysis.functions)? {
+                Type::Str => format.push_str("%s"),
+                _ => {
+                    format.push_str("%\" PRId64 \"");
+                    // Varargs don't get converted, so it has to be an int64_t already
+                    if !is_wide(arg) {value = widen(value, arg)}
+                }
+            }
+            c_args.push(value);
+        }
+        format.push_str("\\n\"");
+        if c_args.is_empty() {return Ok(format!("printf({})", format))}
+        Ok(format!("printf({}, {})", format, c_args.join(", ")))
+    }
+
+    /// GCC extended asm, `{name}` becomes `%[name]`. Outputs are early clobbers (`=&r`) so
+    /// they don't share a register with an input, same as in the x86 backend.
+    fn asm(&self, asm: &InlineAsm, scope: &Scope) -> Result<String, String> {
+        let template = expand_asm(&asm.template.replace('%', "%%"), &mut |name| Ok(format!("%[{}]", name)))?;
+        let outputs: Vec<String> = asm.outputs.iter()
+            .map(|(name, variable)| format!("[{}] \"=&r\" ({})", name, variable_name(variable))).collect();
+        let mut inputs = vec![];
+        for (name, value) in &asm.inputs {
+            // An int would get a 32-bit register
+            let mut c_value = self.expression(value, scope)?;
+            if !is_wide(value) {c_value = widen(c_value, value)}
+            inputs.push(format!("[{}] \"r\" ({})", name, c_value));
+        }
+        let clobbers: Vec<String> = asm.clobbers.iter().map(|clobber| c_string(clobber)).collect();
+        // GCC treats asm without outputs as volatile anyway
+        let volatile = if asm.volatile || asm.outputs.is_empty() {" volatile"} else {""};
+        Ok(format!("__asm__{} ({} : {} : {} : {});", volatile, c_string(&template), outputs.join(", "), inputs.join(", "), clobbers.join(", ")))
+    }
+
+    fn statements(&mut self, statements: &[Statement], scope: &mut Scope) -> Result<(), String> {
+        for statement in statements {
+            self.line_directive(statement);
+            match statement {
+                Statement::ExpressionStatement(Expression::ReturnValue { value }, _) => {
+                    let line = format!("return {};", self.expression(value, scope)?);
+                    self.line(&line);
+                }
+                Statement::ExpressionStatement(expression, _) => {
+                    let line = format!("{};", self.expression(expression, scope)?);
+                    self.line(&line);
+                }
+                Statement::VariableAssignment { name, value, .. } => {
+                    let line = format!("{} = {};", variable_name(name), self.expression(value, scope)?);
+                    self.line(&line);
+                    let ty = check_expression(value, scope, &self.analysis.functions)?;
+                    scope.insert(name.clone(), ty);
+                }
+                Statement::Asm { asm, .. } => {
+                    let line = self.asm(asm, scope)?;
+                    self.line(&line);
+                    for (_, variable) in &asm.outputs {
+                        scope.insert(variable.clone(), Type::Int);
+                    }
+                }
+                Statement::FunctionAssignment { name, .. } => {
+                    return Err(format!("Nested function '{}' in the C backend", name));
+                }
+                Statement::Import { .. } | Statement::ConstAssignment { .. } => {
+                    return Err("Imports and constants should be resolved before the C backend".to_string());
+                }
+                Statement::Extern { function, .. } => {
+                    return Err(format!("Nested extern '{}' in the C backend", function.name));
+                }
+                Statement::Attribute { name, .. } => {
+                    return Err(format!("Nested #![{}] in the C backend", name));
+                }
+                Statement::While { condition, body, .. } => {
+                    let line = format!("while ({}) {{", self.expression(condition, scope)?);
+                    self.line(&line);
+                    self.indent += 1;
+                    self.statements(body, scope)?;
+                    self.indent -= 1;
+                    self.line("}");
+                }
+                Statement::ConditionalStatement { condition, body, else_body, .. } => {
+                    let line = format!("if ({}) {{", self.expression(condition, scope)?);
+                    self.line(&line);
+                    self.indent += 1;
+                    self.statements(body, scope)?;
+                    self.indent -= 1;
+                    if let Some(else_body) = else_body {
+                        self.line("} else {");
+                        self.indent += 1;
+                        self.statements(else_body, scope)?;
+                        self.indent -= 1;
+                    }
+                    self.line("}");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Galvan variables live for the whole function, so they all get declared at the top
+    fn collect_locals(&self, statements: &[Statement], scope: &mut Scope, locals: &mut Vec<(String, Type)>) -> Result<(), String> {
+        for statement in statements {
+            match statement {
+                Statement::VariableAssignment { name, value, .. } => {
+                    let ty = check_expression(value, scope, &self.analysis.functions)?;
+                    if !scope.contains_key(name) {locals.push((name.clone(), ty))}
+                    scope.insert(name.clone(), ty);
+                }
+                Statement::Asm { asm, .. } => {
+                    for (_, variable) in &asm.outputs {
+                        if !scope.contains_key(variable) {locals.push((variable.clone(), Type::Int))}
+                        scope.insert(variable.clone(), Type::Int);
+                    }
+                }
+                Statement::While { body, .. } => self.collect_locals(body, scope, locals)?,
+                Statement::ConditionalStatement { body, else_body, .. } => {
+                    self.collect_locals(body, scope, locals)?;
+                    if let Some(else_body) = else_body {self.collect_locals(else_body, scope, locals)?}
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn signature(&self, name: &str, parameters: &[(String, Type)], return_type: Type) -> String {
+        let params: Vec<String> = parameters.iter().map(|(param, ty)| declaration(*ty, &variable_name(param))).collect();
+        let params = if params.is_empty() {"void".to_string()} else {params.join(", ")};
+        format!("{}({})", declaration(return_type, &symbol(name)), params)
+    }
+
+    fn function(&mut self, name: &str, parameters: &[(String, Type)], return_type: Type, body: &[Statement]) -> Result<(), String> {
+        let mut scope: Scope = parameters.iter().cloned().collect();
+        let mut locals = vec![];
+        self.collect_locals(body, &mut scope.clone(), &mut locals)?;
+
+        let signature = self.signature(name, parameters, return_type);
+        self.line(&format!("{} {{", signature));
+        self.indent += 1;
+        for (local, ty) in &locals {
+            let initial = if *ty == Type::Str {"\"\""} else {"0"};
+            self.line(&format!("{} = {};", declaration(*ty, &variable_name(local)), initial));
+        }
+        self.statements(body, &mut scope)?;
+        // Falling off the end returns the default value, same as in the IR
+        if !matches!(body.last(), Some(Statement::ExpressionStatement(Expression::ReturnValue { .. }, _))) {
+            match return_type {
+                Type::Int => self.line("return 0;"),
+                Type::Str => self.line("return \"\";"),
+                Type::Void => {}
+            }
+        }
+        self.indent -= 1;
+        self.line("}");
+        self.line("");
+        Ok(())
+    }
+}
+
+/// Generates a C99 translation unit for the whole program.
+/// `source` is the root file's name, for the `#line` directives of statements the source
+/// map doesn't know about.
+/// `entry` is the entry point of a freestanding program (`_start` if it's None).
+pub fn generate(analysis: &Analysis, source: &str, entry: Option<&str>) -> Result<String, String> {
+    if debug_prints(C_DEBUG_PRINTS) {eprintln!("- - - C")}
+
+    let freestanding = parser::freestanding(&analysis.statements);
+    let mut writer = CWriter { out: String::new(), analysis, source: source.to_string(), indent: 0 };
+    writer.line(&format!("// Generated by galvan from {}", source));
+    // Only the intrinsics the program uses, so most programs stay plain C99
+    let used: Vec<&intrinsics::Intrinsic> = intrinsics::INTRINSICS.iter()
+        .filter(|intrinsic| calls(&analysis.statements, intrinsic.name)).collect();
+    if !used.is_empty() && !freestanding {
+        writer.line("#define _POSIX_C_SOURCE 200809L");
+    }
+    // No libc without an OS, modules.rs made sure nothing needs it
+    if !freestanding {writer.line("#include <inttypes.h>")}
+    writer.line("#include <stdint.h>");
+    if !freestanding {writer.line("#include <stdio.h>")}
+    if !used.is_empty() && !freestanding {
+        for header in ["errno.h", "fcntl.h", "stdlib.h", "string.h", "unistd.h"] {
+            writer.line(&format!("#include <{}>", header));
+        }
+    }
+    writer.line("");
+    for line in ARITHMETIC {writer.line(line)}
+    writer.line("");
+
+    for intrinsic in used {
+        let parameters: Vec<(String, Type)> = ["a", "b", "c"].iter().map(|name| name.to_string())
+            .zip(intrinsic.parameters.iter().copied()).collect();
+        let line = format!("static {} {{ {} }}", writer.signature(intrinsic.name, &parameters, intrinsic.return_type), intrinsic_body(intrinsic.name));
+        writer.line(&line);
+    }
+    if writer.out.ends_with("}\n") {writer.line("")}
+    let interrupts = analysis.statements.iter()
+        .any(|statement| matches!(statement, Statement::FunctionAssignment { attributes, .. } if attributes.interrupt));
+    if interrupts {
+        for line in INTERRUPT_MACRO {writer.line(line)}
+        writer.line("");
+    }
+
+    let functions: Vec<&Statement> = analysis.statements.iter()
+        .filter(|statement| matches!(statement, Statement::FunctionAssignment { .. })).collect();
+    let toplevel: Vec<Statement> = analysis.statements.iter()
+        .filter(|statement| !matches!(statement, Statement::FunctionAssignment { .. } | Statement::Extern { .. } | Statement::Attribute { .. })).cloned().collect();
+    // Same rule as the IR, a library has no main()
+    let library = toplevel.is_empty() && functions.iter().any(|statement| matches!(statement, Statement::FunctionAssignment { export: Some(_), .. }));
+
+    for statement in &analysis.statements {
+        if let Statement::Extern { function, .. } = statement {
+            let mut params: Vec<String> = function.parameters.iter().map(|(_, ty)| ty.c_name()).collect();
+            if function.variadic {params.push("...".to_string())}
+            if params.is_empty() {params.push("void".to_string())}
+            let line = format!("{} {}({}) __asm__({});", function.return_type.c_name(), symbol(&function.name), params.join(", "), c_string(&function.symbol));
+            writer.line(&line);
+        }
+    }
+    // Prototypes first, so functions can call each other in any order
+    for statement in &functions {
+        if let Statement::FunctionAssignment { name, export, attributes, .. } = statement {
+            let info = &analysis.functions[name];
+            let label = match export {
+                Some(export) => format!(" __asm__({})", c_string(export)),
+                None => String::new(),
+            };
+            let prototype = format!("{}{}{};", section_attribute(attributes), writer.signature(name, &parameters(info), info.return_type), label);
+            writer.line(&prototype);
+        }
+    }
+    if !library {
+        let prototype = format!("{};", writer.signature(ENTRY_FUNCTION, &[], Type::Int));
+        writer.line(&prototype);
+    }
+    writer.line("");
+
+    for statement in &functions {
+        if let Statement::FunctionAssignment { name, body, .. } = statement {
+            let info = &analysis.functions[name];
+            writer.function(name, &parameters(info), info.return_type, body)?;
+        }
+    }
+    for statement in &functions {
+        if let Statement::FunctionAssignment { name, attributes, .. } = statement && attributes.interrupt {
+            let line = format!("GV_INTERRUPT({}, {}, {})", section_attribute(attributes).trim_end(), name, symbol(name));
+            writer.line(&line);
+        }
+    }
+    if interrupts {writer.line("")}
+    if !library {
+        writer.function(ENTRY_FUNCTION, &[], Type::Int, &toplevel)?;
+        // Nothing to return to on bare metal, the stack pointer is up to the hardware (or
+        // whatever startup code runs first)
+        if freestanding {
+            writer.line(&format!("void {}(void) {{", entry.unwrap_or(START_SYMBOL)));
+            writer.line(&format!("    {}();", symbol(ENTRY_FUNCTION)));
+            writer.line("    for (;;) {}");
+        } else {
+            writer.line("int main(void) {");
+            writer.line(&format!("    return (int){}();", symbol(ENTRY_FUNCTION)));
+        }
+        writer.line("}");
+    }
+
+    if debug_prints(C_DEBUG_PRINTS) {eprintln!("- - - C done!")}
+    Ok(writer.out)
+}
@@ -0,0 +1,394 @@
+use crate::compiler_settings::*;
+use crate::linker_script;
+use crate::modules::Package;
+use crate::opt;
+use crate::x86::Syntax;
+
+// Command line handling, kept dependency free on purpose.
+// Usage: galvan [command] [file] [options]
+
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+#[derive(PartialEq)]
+pub enum Command {
+    Build,
+    /// Run with the interpreter, no compiling
+    Run,
+    /// Interactive prompt
+    Repl,
+    /// Scaffold a new package
+    New,
+    /// Turn a C header into extern declarations
+    Bindgen,
+    /// Language server for editors
+    Lsp,
+    /// Reformat source files
+    Fmt,
+    /// Warnings about suspicious code
+    Lint,
+    /// Print a file in color, or as HTML
+    Highlight,
+    /// The TextMate grammar for editors
+    Grammar,
+    /// Documentation pages from doc comments
+    Doc,
+    /// Run the code blocks in doc comments
+    Test,
+}
+
+/// What `galvan build` writes out
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+#[derive(PartialEq)]
+pub enum Emit {
+    Ir,
+    Asm,
+    /// Relocatable ELF object, no assembler needed
+    Object,
+    /// Statically linked ELF executable, no assembler or linker needed
+    Executable,
+    /// C99 source
+    C,
+    /// Bytecode disassembly
+    Bytecode,
+    /// Bytecode in the .gvc file format, for `galvan run`
+    Gvc,
+    /// The linker script a freestanding executable gets, for linking the .o or .s with ld
+    LinkerScript,
+    /// Live ranges and register allocation of every function, for the native backends
+    RegAlloc,
+}
+
+/// What native code gets generated, and what `galvan run` runs on
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+#[derive(PartialEq)]
+pub enum Arch {
+    X86_64,
+    /// RV32IMC, `galvan run` uses the built-in simulator
+    Riscv32,
+    /// Thumb-2 for the Cortex-M3/M4/M7, `galvan run` uses the built-in simulator
+    Thumbv7m,
+    /// The ARMv6-M subset of Thumb for the Cortex-M0/M0+, same as Thumbv7m otherwise
+    Thumbv6m,
+    /// WebAssembly, asm is the .wat text format and obj/exe the binary .wasm
+    Wasm32,
+}
+
+/// -O, how hard the optimizer (opt.rs) tries
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+#[derive(PartialEq)]
+pub enum OptLevel {
+    O0,
+    O1,
+    O2,
+    /// Like -O2, but never at the cost of size
+    Os,
+}
+impl OptLevel {
+    /// `0`, `1`, `2` or `s`, what comes after -O and in galvan.toml's opt-level
+    pub fn from_name(name: &str) -> Option<OptLevel> {
+        match name {
+            "0" => Some(OptLevel::O0),
+            "1" => Some(OptLevel::O1),
+            "2" => Some(OptLevel::O2),
+            "s" => Some(OptLevel::Os),
+            _ => None,
+        }
+    }
+}
+
+/// None means not given, see the methods for the defaults. A package build fills them in from
+/// galvan.toml (package.rs).
+#[derive(Debug)]
+#[derive(Clone)]
+pub struct Options {
+    pub command: Command,
+    pub source: Option<String>,
+    pub emit: Option<Emit>,
+    pub output: Option<String>,
+    pub syntax: Syntax,
+    pub arch: Option<Arch>,
+    /// `galvan run` with the bytecode VM instead of the tree-walking interpreter
+    pub vm: bool,
+    /// Extra directories to look for imported modules in
+    pub include: Vec<String>,
+    /// `galvan new --lib`, a package with src/lib.gv instead of src/main.gv
+    pub lib: bool,
+    /// Fail instead of updating galvan.lock
+    pub locked: bool,
+    /// The package graph, when building a package
+    pub packages: Vec<Package>,
+    /// No OS underneath, same as `#![no_std]`
+    pub freestanding: bool,
+    /// Entry point symbol instead of `_start`
+    pub entry: Option<String>,
+    /// Linker script for --emit=exe, instead of the generated one
+    pub linker_script: Option<String>,
+    /// Memory regions for the generated linker script (name, origin, length)
+    pub memory: Vec<(String, u64, u64)>,
+    pub opt_level: Option<OptLevel>,
+    /// -Cpass, optimization passes switched on (true) or off, in the order given
+    pub passes: Vec<(String, bool)>,
+    /// -g, DWARF debug info in x86 asm, obj and exe output
+    pub debug_info: bool,
+    /// `galvan fmt --check`, only say which files aren't formatted
+    pub check: bool,
+    /// `galvan highlight --html`, an HTML page instead of terminal colors
+    pub html: bool,
+    /// `galvan doc --markdown`, Markdown pages instead of HTML
+    pub markdown: bool,
+    /// `galvan test --doctest=<n>`, run only doc test n in this process
+    pub doctest: Option<usize>,
+    /// What every compiler stage did, on stderr
+    pub verbose: bool,
+}
+
+pub const USAGE: &str = "\
+Usage: galvan [command] [file] [options]
+
+Commands:
+    build       Compile a file (default). Without a file (or given a package directory)
+                in a package with a galvan.toml, builds the package into target/
+    run         Run a file with the interpreter (or a .gvc file with the VM),
+                exits with the program's return value. Also works on packages
+    repl        Interactive prompt
+    new <path>  Create a new package in <path>
+    bindgen <header>
+                Turn a C header into Galvan externs and constants, printed or
+                written to -o. Quoted #includes are looked for in -I dirs too
+    lsp         Language server, speaks LSP over stdin and stdout with an editor.
+                Imports are looked for next to the file and in -I dirs
+    fmt         Format a file, or every .gv file in a directory (without one, in the
+                package here) in place. Width and indent come from [fmt] in galvan.toml
+    lint        Warn about unused variables, parameters and functions, unreachable
+                code, constant conditions, self-comparisons, shadowing and naming, in
+                the same files fmt takes. #[allow(rule)] on a function or #![allow(rule)]
+                silences a rule, [lint] in galvan.toml sets levels (allow, warn, deny)
+    highlight <file>
+                Print the file with terminal colors, or with --html as an HTML page,
+                using the compiler's own lexer. Goes to -o if there is one
+    grammar     Print the TextMate grammar (.tmLanguage.json) editors can highlight
+                Galvan with, or write it to -o
+    doc         Write documentation for the pub functions, externs and constants of the
+                package (or file) from its /// and //! comments, as HTML with a search
+                box or with --markdown as Markdown, into target/doc or -o
+    test        Compile and run the code blocks in doc comments, each in a process of
+                its own. ```no_run blocks are only compiled, ```ignore ones skipped
+
+Options:
+    --emit=<kind>   What to output: asm (default), ir, obj, exe, c, bytecode, gvc, ld
+                    (the linker script a freestanding exe gets), regalloc (live
+                    ranges and registers of every function)
+    --syntax=<kind> Assembly syntax: att (default), intel
+    --target=<arch> What to generate code for: x86_64 (default), riscv32, thumbv7m,
+                    thumbv6m (those three asm, ir, ld and regalloc only), wasm32 (asm is
+                    .wat, obj and exe are .wasm). run: riscv32, thumbv7m and thumbv6m
+                    run the program in a simulator
+    --vm            run: compile to bytecode and use the VM, much faster
+    -I <dir>        Also look for imported modules in <dir>, can be given more than once
+    -o <file>       Output file (default: assembly.out)
+    --lib           new: make a library package (src/lib.gv)
+    --locked        Fail if galvan.lock isn't up to date instead of updating it
+    --freestanding  No OS underneath (same as #![no_std]): no print, heap or syscalls
+    --entry=<sym>   Name of the entry point (default: _start)
+    -T <file>       Linker script for --emit=exe, also --linker-script=<file>
+    --memory=<name>=<origin>:<length>
+                    Memory region for the generated linker script, can be given more
+                    than once (default: FLASH=0x8000000:512K RAM=0x20000000:128K)
+    -O<level>       Optimize the IR: -O0 (default), -O1, -O2 (also just -O) or -Os
+                    (small code, for flash). Everything but C, Wasm and the interpreter
+    -Cpass=<passes> Switch optimization passes on or off, like -Cpass=licm,-inline.
+                    Passes: constfold, copyprop, cse, dce, simplifycfg, inline, licm,
+                    strength
+    -g              Debug info (DWARF line table, functions, variables and call frames)
+                    for gdb and objdump -S, x86_64 asm, obj and exe only
+    --check         fmt: change nothing, list the files that aren't formatted and fail
+                    if there are any (for CI)
+    --html          highlight: write an HTML page instead of terminal colors
+    --markdown      doc: write Markdown instead of HTML
+    -v, --verbose   Print what every compiler stage did (tokens, AST, IR, ...) to stderr";
+
+impl Options {
+    pub fn source(&self) -> &str {
+        self.source.as_deref().unwrap_or(SRC_FILE)
+    }
+
+    pub fn emit(&self) -> Emit {
+        self.emit.unwrap_or(Emit::Asm)
+    }
+
+    pub fn arch(&self) -> Arch {
+        self.arch.unwrap_or(Arch::X86_64)
+    }
+
+    pub fn output(&self) -> &str {
+        self.output.as_deref().unwrap_or(OUT_FILE)
+    }
+
+    pub fn opt_level(&self) -> OptLevel {
+        self.opt_level.unwrap_or(OptLevel::O0)
+    }
+}
+
+/// `RAM=0x20000000:128K`
+fn parse_memory(memory: &str) -> Option<(String, u64, u64)> {
+    let (name, rest) = memory.split_once('=')?;
+    let (origin, length) = rest.split_once(':')?;
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {return None}
+    Some((name.to_string(), linker_script::parse_size(origin)?, linker_script::parse_size(length)?))
+}
+
+/// Parses the arguments (without the program name) into `Options`
+pub fn parse_args(args: Vec<String>) -> Result<Options, String> {
+    let mut options = Options {
+        command: Command::Build,
+        source: None,
+        emit: None,
+        output: None,
+        syntax: Syntax::Att,
+        arch: None,
+        vm: false,
+        include: vec![],
+        lib: false,
+        locked: false,
+        packages: vec![],
+        freestanding: false,
+        entry: None,
+        linker_script: None,
+        memory: vec![],
+        opt_level: None,
+        passes: vec![],
+        debug_info: false,
+        check: false,
+        html: false,
+        markdown: false,
+        doctest: None,
+        verbose: false,
+    };
+
+    let mut args = args.into_iter().peekable();
+    if let Some(command) = args.peek() {
+        let command = match command.as_str() {
+            "build" => Some(Command::Build),
+            "run" => Some(Command::Run),
+            "repl" => Some(Command::Repl),
+            "new" => Some(Command::New),
+            "bindgen" => Some(Command::Bindgen),
+            "lsp" => Some(Command::Lsp),
+            "fmt" => Some(Command::Fmt),
+            "lint" => Some(Command::Lint),
+            "highlight" => Some(Command::Highlight),
+            "grammar" => Some(Command::Grammar),
+            "doc" => Some(Command::Doc),
+            "test" => Some(Command::Test),
+            _ => None,
+        };
+        if let Some(command) = command {
+            options.command = command;
+            args.next();
+        }
+    }
+
+    while let Some(arg) = args.next() {
+        if let Some(emit) = arg.strip_prefix("--emit=") {
+            options.emit = Some(match emit {
+                "ir" => Emit::Ir,
+                "asm" => Emit::Asm,
+                "obj" => Emit::Object,
+                "exe" => Emit::Executable,
+                "c" => Emit::C,
+                "bytecode" => Emit::Bytecode,
+                "gvc" => Emit::Gvc,
+                "ld" => Emit::LinkerScript,
+                "regalloc" => Emit::RegAlloc,
+                _ => return Err(format!("Unknown --emit kind '{}'", emit)),
+            });
+        } else if let Some(syntax) = arg.strip_prefix("--syntax=") {
+            options.syntax = match syntax {
+                "att" => Syntax::Att,
+                "intel" => Syntax::Intel,
+                _ => return Err(format!("Unknown --syntax '{}'", syntax)),
+            };
+        } else if let Some(target) = arg.strip_prefix("--target=") {
+            options.arch = Some(match target {
+                "x86_64" => Arch::X86_64,
+                "riscv32" => Arch::Riscv32,
+                "thumbv7m" => Arch::Thumbv7m,
+                "thumbv6m" => Arch::Thumbv6m,
+                "wasm32" => Arch::Wasm32,
+                _ => return Err(format!("Unknown --target '{}', expected x86_64, riscv32, thumbv7m, thumbv6m or wasm32", target)),
+            });
+        } else if arg == "--vm" {
+            options.vm = true;
+        } else if arg == "--lib" {
+            options.lib = true;
+        } else if arg == "--locked" {
+            options.locked = true;
+        } else if arg == "--freestanding" {
+            options.freestanding = true;
+        } else if let Some(entry) = arg.strip_prefix("--entry=") {
+            options.entry = Some(entry.to_string());
+        } else if arg == "-T" {
+            options.linker_script = Some(args.next().ok_or("Expected a linker script after -T")?);
+        } else if let Some(script) = arg.strip_prefix("--linker-script=") {
+            options.linker_script = Some(script.to_string());
+        } else if let Some(memory) = arg.strip_prefix("--memory=") {
+            options.memory.push(parse_memory(memory).ok_or(format!("Expected --memory=<name>=<origin>:<length>, like RAM=0x20000000:128K, not '{}'", memory))?);
+        } else if arg == "-I" {
+            options.include.push(args.next().ok_or("Expected a directory after -I")?);
+        } else if let Some(dir) = arg.strip_prefix("-I") {
+            options.include.push(dir.to_string());
+        } else if arg == "-o" {
+            options.output = Some(args.next().ok_or("Expected a file name after -o")?);
+        } else if arg == "-O" {
+            options.opt_level = Some(OptLevel::O2);
+        } else if let Some(level) = arg.strip_prefix("-O") {
+            options.opt_level = Some(OptLevel::from_name(level).ok_or(format!("Unknown optimization level '{}', expected -O0, -O1, -O2 or -Os", arg))?);
+        } else if let Some(passes) = arg.strip_prefix("-Cpass=") {
+            for pass in passes.split(',') {
+                let (name, on) = match pass.strip_prefix('-') {
+                    Some(name) => (name, false),
+                    None => (pass, true),
+                };
+                if !opt::PASSES.iter().any(|known| known.name == name) {
+                    let known: Vec<&str> = opt::PASSES.iter().map(|known| known.name).collect();
+                    return Err(format!("Unknown optimization pass '{}', expected one of {}", name, known.join(", ")));
+                }
+                options.passes.push((name.to_string(), on));
+            }
+        } else if arg == "-g" {
+            options.debug_info = true;
+        } else if arg == "--check" {
+            options.check = true;
+        } else if arg == "--html" {
+            options.html = true;
+        } else if arg == "--verbose" || arg == "-v" {
+            options.verbose = true;
+        } else if arg == "--markdown" {
+            options.markdown = true;
+        } else if let Some(number) = arg.strip_prefix("--doctest=") {
+            options.doctest = Some(number.parse().map_err(|_| format!("Expected a number after --doctest=, not '{}'", number))?);
+        } else if arg == "-h" || arg == "--help" {
+            return Err(USAGE.to_string());
+        } else if arg.starts_with('-') {
+            return Err(format!("Unknown option '{}'\n\n{}", arg, USAGE));
+        } else if options.source.is_none() {
+            options.source = Some(arg);
+        } else {
+            return Err(format!("Unexpected argument '{}'\n\n{}", arg, USAGE));
+        }
+    }
+    if options.command == Command::New && options.source.is_none() {
+        return Err(format!("Expected a path after new\n\n{}", USAGE));
+    }
+    if options.command == Command::Bindgen && options.source.is_none() {
+        return Err(format!("Expected a header after bindgen\n\n{}", USAGE));
+    }
+    if options.command == Command::Highlight && options.source.is_none() {
+        return Err(format!("Expected a file after highlight\n\n{}", USAGE));
+    }
+
+    Ok(options)
+}
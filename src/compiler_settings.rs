@@ -1,10 +1,25 @@
+use std::sync::atomic::{AtomicBool, Ordering};
 
 //
 // Main
 // 
 
-pub const SRC_FILE: &str = "sourcefile";    // Name of the sourcefile (move to args later)
-pub const OUT_FILE: &str = "assembly.out";
+pub const SRC_FILE: &str = "sourcefile";    // Default sourcefile, when none is given in args
+pub const OUT_FILE: &str = "assembly.out";  // Default output file, when no -o is given
+
+/// Turns every `*_DEBUG_PRINTS` off at runtime, see `set_quiet()`
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+/// Silences the stage debug prints (they go to stderr). Only --verbose turns them on, and
+/// things like the REPL that need the terminal to themselves keep them off even then.
+pub fn set_quiet(quiet: bool) {
+    QUIET.store(quiet, Ordering::Relaxed);
+}
+
+/// Whether a stage with the given `*_DEBUG_PRINTS` setting should print right now
+pub fn debug_prints(setting: bool) -> bool {
+    setting && !QUIET.load(Ordering::Relaxed)
+}
 
 //
 // Lexer
@@ -14,14 +29,21 @@ pub const OUT_FILE: &str = "assembly.out";
 // // There's a ton of hardcoded values in the lexer, bring them here.
 // At this rate I might as well hardcode the rest
 pub const LEX_DEBUG_PRINTS: bool = true;
-pub const KEYWORDS: [&str; 7] = 
-    ["let", "if", "function", "call", "return", "while", "else"];
+pub const KEYWORDS: [&str; 13] = 
+    ["let", "if", "function", "call", "return", "while", "else", "import", "pub", "const", "asm", "extern", "export"];
 pub const WHITESPACE: [char; 4] = 
     [' ', '\n', '\t', '\r'];
 pub const OPEN_BRACES: [char; 3] = 
     ['(', '[', '{'];
 pub const CLOSED_BRACES: [char; 3] = 
     [')', ']', '}'];
+pub const OPERATORS: [(&str, &str); 14] = // How it's written, what it lexes as. `=` alone is the EqualSign
+    [("==", "=="), ("=>", ">="), ("=<", "<="), ("=!", "!="), ("!=", "!="), ("<=", "<="), (">=", ">="),
+     ("<", "<"), (">", ">"), ("+", "+"), ("-", "-"), ("*", "*"), ("/", "/"), ("=", "=")];
+pub const ESCAPES: [(char, char); 4] = // `\n` in a string literal is a newline, ...
+    [('n', '\n'), ('t', '\t'), ('\\', '\\'), ('"', '"')];
+pub const TYPES: [&str; 2] = // What can come after `name:`
+    ["i64", "str"];
 
 // 
 // Parser
@@ -29,7 +51,140 @@ pub const CLOSED_BRACES: [char; 3] =
 pub const PAR_DEBUG_PRINTS: bool = true;
 pub const LINE_SPLITTER: char = ';';
 
+//
+// Modules
+//
+pub const MODULES_DEBUG_PRINTS: bool = true;
+pub const MODULE_EXTENSION: &str = "gv";   // `import a::b;` loads a/b.<extension>
+pub const MODULE_SEPARATOR: &str = "__";   // Function f in module a::b is called a__b__f after linking
+
+//
+// Packages
+//
+pub const PACKAGE_DEBUG_PRINTS: bool = true;
+pub const MANIFEST_FILE: &str = "galvan.toml";
+pub const LOCK_FILE: &str = "galvan.lock";
+pub const BUILD_DIR: &str = "target";            // Package builds go to <package>/target/<name>
+pub const DEFAULT_VERSION: &str = "0.1.0";       // For `galvan new`
+
+//
+// Standard library
+//
+pub const STD_PACKAGE: &str = "std";       // `import std::io;`, can't be used as a package name
+pub const PRELUDE_MODULE: &str = "std::prelude"; // Its pub items work unqualified in every file
+pub const HEAP_BASE: i64 = 0x10000;        // Address of the interpreter's and VM's first allocation
+
 //
 // Semantic Analyzer
 //
-pub const SEMAN_DEBUG_PRINTS: bool = true;
\ No newline at end of file
+pub const SEMAN_DEBUG_PRINTS: bool = true;
+
+//
+// IR
+//
+pub const IR_DEBUG_PRINTS: bool = true;
+pub const ENTRY_FUNCTION: &str = "main"; // Top level statements get lowered into this function
+
+//
+// Optimizer
+//
+pub const OPT_DEBUG_PRINTS: bool = true;
+pub const INLINE_THRESHOLD: usize = 30; // -O2 inlines functions with up to this many instructions
+
+//
+// x86-64 backend
+//
+pub const X86_DEBUG_PRINTS: bool = true;
+pub const SYMBOL_PREFIX: &str = "_gv_";  // Galvan functions are called <prefix><name> in the output
+pub const START_SYMBOL: &str = "_start"; // Process entry point, calls ENTRY_FUNCTION and exits
+pub const C_ENTRY_SYMBOL: &str = "main"; // Entry point instead of START_SYMBOL when linking with libc
+
+//
+// DWARF debug info (-g)
+//
+pub const DWARF_DEBUG_PRINTS: bool = true;
+pub const DWARF_PRODUCER: &str = "galvan"; // DW_AT_producer of the compile unit, the version gets added
+
+//
+// RISC-V backend and simulator
+//
+pub const RISCV_DEBUG_PRINTS: bool = true;
+pub const RISCV_LOAD_ADDRESS: u32 = 0x10000;        // Where the simulator puts the program
+pub const RISCV_MEMORY_SIZE: u32 = 64 * 1024 * 1024; // Simulated memory, the stack starts at the top
+pub const RISCV_STACK_SIZE: u32 = 8 * 1024 * 1024;   // Top part of the memory the heap (brk) can't grow into
+
+//
+// Thumb-2 (Cortex-M) backend and simulator
+//
+pub const THUMB_DEBUG_PRINTS: bool = true;
+pub const THUMB_LOAD_ADDRESS: u32 = 0x10000;        // Where the simulator puts the program
+pub const THUMB_MEMORY_SIZE: u32 = 64 * 1024 * 1024; // Simulated memory, the stack starts at the top
+pub const THUMB_STACK_SIZE: u32 = 8 * 1024 * 1024;   // Top part of the memory the heap (brk) can't grow into
+
+//
+// WebAssembly backend
+//
+pub const WASM_DEBUG_PRINTS: bool = true;
+pub const WASM_IMPORT_MODULE: &str = "env"; // Module name of everything the host provides
+pub const WASM_DATA_START: u32 = 1024;      // Where string literals go in linear memory, the heap follows them
+pub const WASM_PAGE_SIZE: u32 = 65536;
+
+//
+// ELF writer
+//
+pub const ELF_DEBUG_PRINTS: bool = true;
+pub const EXECUTABLE_BASE: u64 = 0x400000; // Load address of executables written without a linker
+
+//
+// Freestanding and linker scripts
+//
+pub const LINKER_DEBUG_PRINTS: bool = true;
+pub const NO_STD_ATTRIBUTE: &str = "no_std";      // `#![no_std]` at the top of the root file, same as --freestanding
+pub const STACK_TOP_SYMBOL: &str = "__stack_top"; // Freestanding entry points load the stack pointer from this
+pub const STACK_SIZE: u64 = 0x4000;               // Stack the generated linker script reserves
+pub const DEFAULT_MEMORY: [(&str, u64, u64); 2] = // Name, origin, length, when there's no --memory
+    [("FLASH", 0x0800_0000, 512 * 1024), ("RAM", 0x2000_0000, 128 * 1024)];
+
+//
+// C backend
+//
+pub const C_DEBUG_PRINTS: bool = true;
+
+//
+// Interpreter
+//
+pub const INTERPRETER_DEBUG_PRINTS: bool = true;
+pub const INTERPRETER_MAX_DEPTH: usize = 10000; // Calls deeper than this are a runtime error instead of a crash
+pub const BACKTRACE_LIMIT: usize = 16; // Runtime errors show this many calls at most (interpreter and VM)
+pub const INTERPRETER_STACK_SIZE: usize = 512 * 1024 * 1024; // Stack of the thread the interpreter runs on
+
+//
+// Bytecode and VM
+//
+pub const BYTECODE_DEBUG_PRINTS: bool = true;
+pub const GVC_MAGIC: [u8; 4] = *b"GVC\0"; // First bytes of every .gvc file
+pub const GVC_VERSION: u32 = 2;           // Bump when the .gvc layout or opcodes change
+pub const VM_DEBUG_PRINTS: bool = true;
+pub const VM_MAX_DEPTH: usize = 100000;   // Calls deeper than this are a runtime error
+
+//
+// Formatter
+//
+pub const FMT_WIDTH: usize = 100; // Longer lines get their arguments one per line, [fmt] width in galvan.toml
+pub const FMT_INDENT: usize = 4;  // Spaces per block level, [fmt] indent
+
+//
+// Highlighting
+//
+pub const GRAMMAR_SCOPE: &str = "source.galvan"; // Scope name of the TextMate grammar, `galvan grammar`
+
+//
+// Documentation
+//
+pub const DOC_DIR: &str = "doc"; // galvan doc writes to target/<this> in the package (or the current directory)
+
+//
+// REPL
+//
+pub const REPL_PROMPT: &str = "galvan> ";
+pub const REPL_CONTINUATION_PROMPT: &str = "   ...> "; // While a block is still open
@@ -0,0 +1,248 @@
+use crate::compiler_settings::ESCAPES;
+use crate::lexer::{lexer, LexSymbol, Lexeme};
+
+// Concrete syntax tree, for the formatter (fmt.rs). The lexer throws whitespace and comments
+// away, here every lexeme keeps the source text in front of it as trivia, so printing the
+// tree gives back the file byte for byte.
+// It's only as deep as the formatter needs: statements, and the `{ ... }` blocks in them.
+// Expressions stay flat lists of lexemes.
+
+//
+// STRUCTS
+//
+
+#[derive(Debug)]
+#[derive(Clone)]
+pub enum Trivia {
+    Whitespace(String),
+    /// `// ...`, without the newline
+    Comment(String),
+    /// Characters the lexer skips without a word
+    Skipped(String),
+}
+
+#[derive(Debug)]
+#[derive(Clone)]
+pub struct Token {
+    pub lexeme: Lexeme,
+    /// Exactly what's in the source
+    pub text: String,
+    /// Everything between the previous lexeme and this one
+    pub leading: Vec<Trivia>,
+}
+
+#[derive(Debug)]
+#[derive(Clone)]
+pub enum Element {
+    Token(Token),
+    Block(Block),
+}
+
+/// `{ ... }`, a function or loop body or the template of an `asm`
+#[derive(Debug)]
+#[derive(Clone)]
+pub struct Block {
+    pub open: Token,
+    pub statements: Vec<Statement>,
+    /// None when the file ends first
+    pub close: Option<Token>,
+}
+
+/// Everything up to the `;`, or up to the `}` of its last block (`if ... else ...` goes on
+/// after the first one)
+#[derive(Debug)]
+#[derive(Clone)]
+pub struct Statement {
+    pub elements: Vec<Element>,
+}
+
+#[derive(Debug)]
+#[derive(Clone)]
+pub struct Cst {
+    pub statements: Vec<Statement>,
+    /// After the last lexeme
+    pub trailing: Vec<Trivia>,
+}
+
+//
+// FUNCTIONS
+//
+
+/// Splits the text between two lexemes into whitespace, comments and whatever else
+fn trivia(text: &str) -> Vec<Trivia> {
+    let mut out = vec![];
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        let length = if c.is_whitespace() {
+            let length = rest.find(|c: char| !c.is_whitespace()).unwrap_or(rest.len());
+            out.push(Trivia::Whitespace(rest[..length].to_string()));
+            length
+        } else if rest.starts_with("//") {
+            let length = rest.find('\n').unwrap_or(rest.len());
+            out.push(Trivia::Comment(rest[..length].trim_end_matches('\r').to_string()));
+            rest[..length].trim_end_matches('\r').len()
+        } else {
+            let length = rest.find(|c: char| c.is_whitespace() || rest.starts_with("//")).filter(|length| *length > 0).unwrap_or(c.len_utf8());
+            out.push(Trivia::Skipped(rest[..length].to_string()));
+            length
+        };
+        rest = &rest[length..];
+    }
+    out
+}
+
+/// Length in bytes of the lexeme starting at the beginning of `source`. Strings and
+/// attributes get measured in the source since their value lost the quotes and escapes.
+fn length(lexeme: &Lexeme, source: &str) -> usize {
+    let mut chars = source.char_indices().skip(1);
+    match lexeme.symbol {
+        LexSymbol::String => {
+            while let Some((index, c)) = chars.next() {
+                match c {
+                    '"' => return index + 1,
+                    // Same escapes as the lexer, anything else after a backslash is just itself
+                    '\\' if ESCAPES.iter().any(|(written, _)| source[index + 1..].starts_with(*written)) => {chars.next();}
+                    _ => {}
+                }
+            }
+            source.len()
+        }
+        LexSymbol::Attribute => {
+            let mut quoted = false;
+            for (index, c) in chars {
+                if c == ']' && !quoted {return index + 1}
+                if c == '"' {quoted = !quoted}
+            }
+            source.len()
+        }
+        _ => source.char_indices().nth(lexeme.value.chars().count()).map(|(index, _)| index).unwrap_or(source.len()),
+    }
+}
+
+/// Byte offset of a lexer LINE : CHARACTER
+fn offset(line_starts: &[usize], source: &str, (line, column): (usize, usize)) -> usize {
+    let start = line_starts[line - 1];
+    start + source[start..].char_indices().nth(column - 1).map(|(index, _)| index).unwrap_or(source.len() - start)
+}
+
+/// Splits statements off `tokens` until the `}` that closes the block they're in (given
+/// back too), or the end of the file. In an asm template every string is its own statement.
+fn statements(tokens: &mut std::iter::Peekable<std::vec::IntoIter<Token>>, nested: bool, template: bool) -> (Vec<Statement>, Option<Token>) {
+    let mut out = vec![];
+    while let Some(token) = tokens.peek() {
+        if nested && token.lexeme.symbol == LexSymbol::FunctionClosingBracket {return (out, tokens.next())}
+        if template {
+            out.push(Statement { elements: vec![Element::Token(tokens.next().unwrap())] });
+            continue;
+        }
+
+        let asm = token.lexeme.symbol == LexSymbol::Keyword && token.lexeme.value == "asm";
+        let mut elements = vec![];
+        while let Some(token) = tokens.peek() {
+            match token.lexeme.symbol {
+                LexSymbol::FunctionClosingBracket if nested => break,
+                LexSymbol::FunctionOpeningBracket => {
+                    let open = tokens.next().unwrap();
+                    let (inner, close) = statements(tokens, true, asm);
+                    elements.push(Element::Block(Block { open, statements: inner, close }));
+                    let more = tokens.peek().is_some_and(|next| next.lexeme.symbol == LexSymbol::Keyword && next.lexeme.value == "else");
+                    if !asm && !more {break}
+                }
+                LexSymbol::EndLine => {
+                    elements.push(Element::Token(tokens.next().unwrap()));
+                    break;
+                }
+                // `#![no_std]` is a statement of its own
+                LexSymbol::Attribute if token.lexeme.value.starts_with('!') => {
+                    let last = elements.is_empty();
+                    elements.push(Element::Token(tokens.next().unwrap()));
+                    if last {break}
+                }
+                _ => elements.push(Element::Token(tokens.next().unwrap())),
+            }
+        }
+        out.push(Statement { elements });
+    }
+    (out, None)
+}
+
+/// Builds the tree of a source file. Anything goes, it doesn't have to parse.
+pub fn parse(source: &str) -> Cst {
+    let mut line_starts = vec![0];
+    line_starts.extend(source.match_indices('\n').map(|(index, _)| index + 1));
+
+    let mut tokens = vec![];
+    let mut end = 0;
+    for lexeme in lexer(source) {
+        if lexeme.symbol == LexSymbol::EOF {break}
+        let start = offset(&line_starts, source, (lexeme.location.0, lexeme.location.1));
+        let length = length(&lexeme, &source[start..]);
+        tokens.push(Token { text: source[start..start + length].to_string(), leading: trivia(&source[end..start]), lexeme });
+        end = start + length;
+    }
+    let (statements, _) = statements(&mut tokens.into_iter().peekable(), false, false);
+    Cst { statements, trailing: trivia(&source[end..]) }
+}
+
+impl std::fmt::Display for Trivia {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Trivia::Whitespace(text) | Trivia::Comment(text) | Trivia::Skipped(text) => f.write_str(text),
+        }
+    }
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for trivia in &self.leading {write!(f, "{}", trivia)?}
+        f.write_str(&self.text)
+    }
+}
+
+impl std::fmt::Display for Statement {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for element in &self.elements {
+            match element {
+                Element::Token(token) => write!(f, "{}", token)?,
+                Element::Block(block) => {
+                    write!(f, "{}", block.open)?;
+                    for statement in &block.statements {write!(f, "{}", statement)?}
+                    if let Some(close) = &block.close {write!(f, "{}", close)?}
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Cst {
+    /// Every token of the tree, in source order
+    pub fn tokens(&self) -> Vec<&Token> {
+        fn flatten<'a>(statements: &'a [Statement], out: &mut Vec<&'a Token>) {
+            for statement in statements {
+                for element in &statement.elements {
+                    match element {
+                        Element::Token(token) => out.push(token),
+                        Element::Block(block) => {
+                            out.push(&block.open);
+                            flatten(&block.statements, out);
+                            if let Some(close) = &block.close {out.push(close)}
+                        }
+                    }
+                }
+            }
+        }
+        let mut out = vec![];
+        flatten(&self.statements, &mut out);
+        out
+    }
+}
+
+/// The source the tree came from, exactly
+impl std::fmt::Display for Cst {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for statement in &self.statements {write!(f, "{}", statement)?}
+        for trivia in &self.trailing {write!(f, "{}", trivia)?}
+        Ok(())
+    }
+}
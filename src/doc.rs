@@ -0,0 +1,785 @@
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+use crate::cli::Options;
+use crate::compiler_settings::*;
+use crate::cst::{self, Trivia};
+use crate::highlight::{self, escape_html};
+use crate::interpreter::Interpreter;
+use crate::json::Json;
+use crate::lexer::{lexer_in_file, LexSymbol};
+use crate::modules::{load_source, mangle, module_files, Package};
+use crate::package;
+use crate::parser::{parser, Expression, Statement};
+use crate::seman::{analyze, parameter_names, signature, Analysis};
+use crate::source_map::add_file;
+
+// Documentation generator. `galvan doc` writes a page (HTML, or Markdown with --markdown) for
+// every module of a package (or a lone file and the modules it imports), with its pub functions, externs and constants and what their
+// `///` comments say about them. `//!` comments anywhere before the first item are about the
+// module itself. Signatures get their types from seman, the same way the language server's
+// hovers do.
+// Comments are Markdown, the handful of it `markdown_html()` knows, and `[name]` or
+// [`module::name`] links to another item. Code blocks in them are doc tests: `galvan test`
+// compiles every one with the module imported and runs it with the interpreter, each in a
+// process of its own so a panic only takes down its own test.
+// There are no structs or enums in Galvan yet, so those are all the items there are.
+
+const DOC_STYLE: &str = "\
+body { font-family: sans-serif; margin: 0; color: #24292f; }
+nav { padding: 0.6em 1.5em; border-bottom: 1px solid #d0d7de; position: relative; }
+nav > a { font-weight: bold; margin-right: 1em; text-decoration: none; }
+#search { width: 20em; padding: 0.3em; }
+#results { position: absolute; background: white; border: 1px solid #d0d7de; list-style: none; margin: 0; padding: 0.3em 0.8em; }
+#results:empty { display: none; }
+main { max-width: 60em; padding: 0 1.5em 2em; }
+.item { margin: 1.5em 0; }
+.item > pre.galvan { font-weight: bold; }
+.source { color: #57606a; }
+code { background: #f6f8fa; padding: 0 0.2em; }
+pre code { padding: 0; }
+";
+
+const SEARCH_SCRIPT: &str = r#"
+document.addEventListener("DOMContentLoaded", function () {
+    var input = document.getElementById("search");
+    var results = document.getElementById("results");
+    input.addEventListener("input", function () {
+        var query = input.value.toLowerCase();
+        results.innerHTML = "";
+        if (!query) return;
+        searchIndex.filter(function (item) {
+            return item.name.toLowerCase().indexOf(query) >= 0;
+        }).slice(0, 20).forEach(function (item) {
+            var entry = document.createElement("li");
+            var link = document.createElement("a");
+            link.href = item.href;
+            link.textContent = item.name;
+            entry.appendChild(link);
+            entry.appendChild(document.createTextNode(" (" + item.kind + ") " + item.summary));
+            results.appendChild(entry);
+        });
+    });
+});
+"#;
+
+//
+// STRUCTS
+//
+
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+#[derive(PartialEq)]
+enum Kind {
+    Constant,
+    Function,
+    Extern,
+}
+
+/// Lines of a doc comment without the `///`, and the line each one is on
+type Comment = Vec<(usize, String)>;
+
+struct Item {
+    kind: Kind,
+    name: String,
+    /// `pub function square(x: i64) -> i64`
+    signature: String,
+    docs: Comment,
+}
+
+struct Module {
+    /// How it's imported, `drivers::uart`, relative to the entry file's directory
+    name: String,
+    path: PathBuf,
+    /// `//!`
+    docs: Comment,
+    items: Vec<Item>,
+}
+
+/// A code block in a doc comment
+struct DocTest {
+    /// `src/math.gv - math::square (line 4)`
+    label: String,
+    /// Index into the project's modules
+    module: usize,
+    code: String,
+    /// `no_run` blocks only get compiled
+    run: bool,
+    /// `ignore` blocks don't even get that
+    ignore: bool,
+}
+
+/// The package (or lone file) being documented
+struct Project {
+    /// The package's name and version, a lone file's name
+    title: String,
+    /// The entry file's directory, module names are relative to it
+    root: PathBuf,
+    /// Where target/ goes, the package's directory
+    dir: PathBuf,
+    /// From package_options(), the root package last. Empty for a file outside a package.
+    packages: Vec<Package>,
+    include: Vec<String>,
+    /// The entry file's module first, the rest by path
+    modules: Vec<Module>,
+}
+
+//
+// FUNCTIONS
+//
+
+impl Kind {
+    fn name(self) -> &'static str {
+        match self {
+            Kind::Constant => "constant",
+            Kind::Function => "function",
+            Kind::Extern => "extern function",
+        }
+    }
+
+    fn heading(self) -> &'static str {
+        match self {
+            Kind::Constant => "Constants",
+            Kind::Function => "Functions",
+            Kind::Extern => "Extern functions",
+        }
+    }
+}
+
+/// The text of a `///` (or `//!`) comment, None for other comments (`////` is one too)
+fn doc_text(comment: &str, marker: &str) -> Option<String> {
+    let rest = comment.strip_prefix(marker)?;
+    if rest.starts_with('/') {return None}
+    Some(rest.strip_prefix(' ').unwrap_or(rest).to_string())
+}
+
+/// The trivia in front of a token on line `end`, with the line each piece starts on
+fn lines(trivia: &[Trivia], end: usize) -> Vec<(usize, &Trivia)> {
+    let newlines = |trivia: &Trivia| trivia.to_string().matches('\n').count();
+    let mut line = end - trivia.iter().map(newlines).sum::<usize>();
+    trivia.iter().map(|trivia| {
+        let start = line;
+        line += newlines(trivia);
+        (start, trivia)
+    }).collect()
+}
+
+/// The `///` lines right in front of an item, anything but whitespace in between means they
+/// aren't about it
+fn item_docs(trivia: &[Trivia], end: usize) -> Comment {
+    let mut docs = vec![];
+    for (line, trivia) in lines(trivia, end) {
+        match trivia {
+            Trivia::Comment(comment) => match doc_text(comment, "///") {
+                Some(text) => docs.push((line, text)),
+                None => docs.clear(),
+            },
+            Trivia::Skipped(_) => docs.clear(),
+            Trivia::Whitespace(_) => {}
+        }
+    }
+    docs
+}
+
+/// `const LIMIT: i64 = 3`, the value as it's written
+fn constant_signature(name: &str, value: &Expression, text: &str, items: &[Item]) -> String {
+    let ty = match value {
+        Expression::String(_) => "str",
+        // Another constant in the same module, the rest has to be a number
+        Expression::Variable(other, _) if items.iter().any(|item| item.kind == Kind::Constant && item.name == *other && item.signature.contains(": str =")) => "str",
+        _ => "i64",
+    };
+    format!("pub const {}: {} = {}", name, ty, text)
+}
+
+/// Reads a module and the doc comments of its pub items. Signatures don't have their return
+/// types yet, see `Project::signatures()`.
+fn read_module(path: &Path, name: String) -> Result<Module, String> {
+    let source = std::fs::read_to_string(path).map_err(|error| format!("Can't read '{}': {}", path.display(), error))?;
+    let file = add_file(&path.display().to_string());
+    let statements = parser(lexer_in_file(&source, file).iter().peekable())?;
+    let tree = cst::parse(&source);
+    let tokens = tree.tokens();
+
+    let docs: Comment = match tokens.first() {
+        Some(first) => lines(&first.leading, first.lexeme.location.0),
+        None => lines(&tree.trailing, 1),
+    }.into_iter().filter_map(|(line, trivia)| match trivia {
+        Trivia::Comment(comment) => doc_text(comment, "//!").map(|text| (line, text)),
+        _ => None,
+    }).collect();
+
+    let mut items: Vec<Item> = vec![];
+    for statement in &statements {
+        let (kind, name) = match statement {
+            Statement::FunctionAssignment { name, public: true, .. } => (Kind::Function, name),
+            Statement::Extern { function, public: true, .. } => (Kind::Extern, &function.name),
+            Statement::ConstAssignment { name, public: true, .. } => (Kind::Constant, name),
+            _ => continue,
+        };
+        let location = statement.location();
+        let Some(mut index) = tokens.iter().position(|token| (token.lexeme.location.0, token.lexeme.location.1) == (location.0, location.1)) else {continue};
+        let start = index;
+        // The statement starts at `function`, `pub`, `export` and `#[...]`s come before that
+        while index > 0 && {
+            let previous = &tokens[index - 1].lexeme;
+            (previous.symbol == LexSymbol::Keyword && ["pub", "export"].contains(&previous.value.as_str()))
+                || (previous.symbol == LexSymbol::Attribute && !previous.value.starts_with('!'))
+        } {
+            index -= 1;
+        }
+        let docs = item_docs(&tokens[index].leading, tokens[index].lexeme.location.0);
+
+        let signature = match statement {
+            Statement::FunctionAssignment { arguments, parameter_types, .. } => {
+                let parameters: Vec<String> = parameter_names(name, arguments).unwrap_or_default().iter().zip(parameter_types)
+                    .map(|(parameter, ty)| format!("{}: {}", parameter, ty)).collect();
+                format!("pub function {}({})", name, parameters.join(", "))
+            }
+            Statement::Extern { function, .. } => format!("pub extern \"C\" function {}", function.signature()),
+            Statement::ConstAssignment { value, .. } => {
+                // Everything between the `=` and the `;`
+                let value_tokens = tokens[start..].iter().skip_while(|token| token.lexeme.symbol != LexSymbol::EqualSign).skip(1)
+                    .take_while(|token| token.lexeme.symbol != LexSymbol::EndLine);
+                let text: String = value_tokens.map(|token| token.to_string()).collect();
+                constant_signature(name, value, text.trim(), &items)
+            }
+            _ => unreachable!(),
+        };
+        items.push(Item { kind, name: name.clone(), signature, docs });
+    }
+    Ok(Module { name, path: path.to_path_buf(), docs, items })
+}
+
+/// What a doc test's code block is, from what comes after the ```. None for blocks that
+/// aren't Galvan. Gives back (run, ignore).
+fn test_kind(info: &str) -> Option<(bool, bool)> {
+    let (mut run, mut ignore) = (true, false);
+    for tag in info.split(',').map(str::trim).filter(|tag| !tag.is_empty()) {
+        match tag {
+            "galvan" => {}
+            "no_run" => run = false,
+            "ignore" => ignore = true,
+            _ => return None,
+        }
+    }
+    Some((run, ignore))
+}
+
+/// The ``` blocks of a comment: the line of the opening ```, what comes after it, and the code
+fn code_blocks(comment: &Comment) -> Vec<(usize, String, String)> {
+    let mut blocks = vec![];
+    let mut lines = comment.iter();
+    while let Some((line, text)) = lines.next() {
+        let Some(info) = text.trim_start().strip_prefix("```") else {continue};
+        let mut code = String::new();
+        for (_, text) in lines.by_ref() {
+            if text.trim_start().starts_with("```") {break}
+            code.push_str(text);
+            code.push('\n');
+        }
+        blocks.push((*line, info.trim().to_string(), code));
+    }
+    blocks
+}
+
+/// The first paragraph of a comment as plain text, for the module list and the search index
+fn summary(comment: &Comment) -> String {
+    let lines: Vec<&str> = comment.iter().map(|(_, text)| text.trim())
+        .take_while(|text| !text.is_empty() && !text.starts_with("```")).collect();
+    lines.join(" ").replace(['`', '[', ']'], "").replace("**", "")
+}
+
+/// File name of a module's page, without the extension
+fn page_name(module: &str) -> String {
+    module.replace("::", ".")
+}
+
+/// Whether a [text](url) link can go into an href: http(s), relative or `#anchor`, nothing
+/// that runs anything (`javascript:`)
+fn safe_url(url: &str) -> bool {
+    let scheme = url.find(|c: char| ['/', '?', '#'].contains(&c)).map_or(url, |end| &url[..end]);
+    match scheme.split_once(':') {
+        Some((scheme, _)) => ["http", "https"].contains(&scheme.to_ascii_lowercase().as_str()),
+        None => true,
+    }
+}
+
+/// Index of the `)` that closes the `(` before `from`, parentheses in between have to match
+fn closing_parenthesis(chars: &[char], from: usize) -> Option<usize> {
+    let mut depth = 0;
+    for (index, c) in chars.iter().enumerate().skip(from) {
+        match c {
+            '(' => depth += 1,
+            ')' if depth == 0 => return Some(index),
+            ')' => depth -= 1,
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Inline Markdown: `code`, **bold**, [text](url) and [item] links (`link` gives their href)
+fn inline_html(text: &str, link: &dyn Fn(&str) -> Option<String>) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    let find = |from: usize, c: char| (from..chars.len()).find(|&index| chars[index] == c);
+    let slice = |from: usize, to: usize| chars[from..to].iter().collect::<String>();
+    let mut out = String::new();
+    let mut strong = false;
+    let mut index = 0;
+    while index < chars.len() {
+        match chars[index] {
+            '`' if let Some(end) = find(index + 1, '`') => {
+                let _ = write!(out, "<code>{}</code>", escape_html(&slice(index + 1, end)));
+                index = end + 1;
+                continue;
+            }
+            '[' if let Some(end) = find(index + 1, ']') => {
+                let label = slice(index + 1, end);
+                if chars.get(end + 1) == Some(&'(') && let Some(close) = closing_parenthesis(&chars, end + 2) {
+                    let url = slice(end + 2, close);
+                    match safe_url(url.trim()) {
+                        true => {let _ = write!(out, "<a href=\"{}\">{}</a>", escape_html(url.trim()), inline_html(&label, link));}
+                        false => out.push_str(&escape_html(&slice(index, close + 1))),
+                    }
+                    index = close + 1;
+                    continue;
+                }
+                if let Some(href) = link(label.trim_matches('`')) {
+                    let _ = write!(out, "<a href=\"{}\">{}</a>", escape_html(&href), inline_html(&label, link));
+                    index = end + 1;
+                    continue;
+                }
+            }
+            '*' if chars.get(index + 1) == Some(&'*') => {
+                out.push_str(if strong {"</strong>"} else {"<strong>"});
+                strong = !strong;
+                index += 2;
+                continue;
+            }
+            _ => {}
+        }
+        out.push_str(&escape_html(&chars[index].to_string()));
+        index += 1;
+    }
+    if strong {out.push_str("</strong>")}
+    out
+}
+
+/// A doc comment as HTML. Knows paragraphs, `#` headings, `-` lists and ``` blocks, the
+/// Galvan ones get highlighted.
+fn markdown_html(comment: &Comment, link: &dyn Fn(&str) -> Option<String>) -> String {
+    let mut out = String::new();
+    let mut paragraph: Vec<&str> = vec![];
+    let mut list = false;
+    let flush = |out: &mut String, paragraph: &mut Vec<&str>, list: &mut bool| {
+        if !paragraph.is_empty() {
+            let _ = writeln!(out, "<p>{}</p>", inline_html(&paragraph.join(" "), link));
+            paragraph.clear();
+        }
+        if *list {
+            out.push_str("</ul>\n");
+            *list = false;
+        }
+    };
+    let mut lines = comment.iter().map(|(_, text)| text.as_str());
+    while let Some(line) = lines.next() {
+        let trimmed = line.trim();
+        if let Some(info) = trimmed.strip_prefix("```") {
+            flush(&mut out, &mut paragraph, &mut list);
+            let mut code = String::new();
+            for line in lines.by_ref() {
+                if line.trim_start().starts_with("```") {break}
+                code.push_str(line);
+                code.push('\n');
+            }
+            match test_kind(info) {
+                Some(_) => out.push_str(&highlight::html(&code)),
+                None => {let _ = writeln!(out, "<pre><code>{}</code></pre>", escape_html(&code));}
+            }
+        } else if trimmed.is_empty() {
+            flush(&mut out, &mut paragraph, &mut list);
+        } else if let Some((hashes, heading)) = trimmed.split_once(' ') && !hashes.is_empty() && hashes.len() <= 3 && hashes.chars().all(|c| c == '#') {
+            flush(&mut out, &mut paragraph, &mut list);
+            let _ = writeln!(out, "<h{}>{}</h{}>", hashes.len() + 3, inline_html(heading, link), hashes.len() + 3);
+        } else if let Some(entry) = trimmed.strip_prefix("- ").or_else(|| trimmed.strip_prefix("* ")) {
+            if !paragraph.is_empty() || !list {flush(&mut out, &mut paragraph, &mut list)}
+            if !list {out.push_str("<ul>\n")}
+            list = true;
+            let _ = writeln!(out, "<li>{}</li>", inline_html(entry, link));
+        } else {
+            if list {flush(&mut out, &mut paragraph, &mut list)}
+            paragraph.push(trimmed);
+        }
+    }
+    flush(&mut out, &mut paragraph, &mut list);
+    out
+}
+
+/// A doc comment as Markdown, with [item] links pointing somewhere
+fn markdown_links(comment: &Comment, link: &dyn Fn(&str) -> Option<String>) -> String {
+    let mut out = String::new();
+    let mut code = false;
+    for (_, line) in comment {
+        if line.trim_start().starts_with("```") {code = !code}
+        if code || line.trim_start().starts_with("```") {
+            out.push_str(line);
+            out.push('\n');
+            continue;
+        }
+        let chars: Vec<char> = line.chars().collect();
+        let mut quoted = false;
+        let mut index = 0;
+        while index < chars.len() {
+            if chars[index] == '`' {quoted = !quoted}
+            if chars[index] == '[' && !quoted && let Some(end) = (index + 1..chars.len()).find(|&end| chars[end] == ']')
+                && chars.get(end + 1) != Some(&'(') {
+                let label: String = chars[index + 1..end].iter().collect();
+                if let Some(href) = link(label.trim_matches('`')) {
+                    let _ = write!(out, "[{}]({})", label, href);
+                    index = end + 1;
+                    continue;
+                }
+            }
+            out.push(chars[index]);
+            index += 1;
+        }
+        out.push('\n');
+    }
+    out
+}
+
+/// The `[name]` links of a comment and their lines, the ones outside code and not followed by
+/// a `(url)`
+fn item_links(comment: &Comment) -> Vec<(usize, String)> {
+    let mut links = vec![];
+    let mut code = false;
+    for (line, text) in comment {
+        if text.trim_start().starts_with("```") {code = !code}
+        if code || text.trim_start().starts_with("```") {continue}
+        let chars: Vec<char> = text.chars().collect();
+        let mut quoted = false;
+        for (index, c) in chars.iter().enumerate() {
+            if *c == '`' {quoted = !quoted}
+            if *c == '[' && !quoted && let Some(end) = (index + 1..chars.len()).find(|&end| chars[end] == ']')
+                && chars.get(end + 1) != Some(&'(') {
+                let label: String = chars[index + 1..end].iter().collect();
+                links.push((*line, label.trim_matches('`').to_string()));
+            }
+        }
+    }
+    links
+}
+
+impl Project {
+    /// Everything `galvan doc` and `galvan test` work on, from options that went through
+    /// package_options()
+    fn new(options: &Options) -> Result<Project, String> {
+        let entry = PathBuf::from(options.source());
+        if !entry.is_file() {return Err(format!("Can't find '{}'", entry.display()))}
+        let root = entry.parent().map(Path::to_path_buf).unwrap_or_default();
+        let dir = entry.ancestors().skip(1).find(|dir| dir.join(MANIFEST_FILE).is_file()).map(Path::to_path_buf);
+
+        let (files, title) = match (options.packages.last(), &dir) {
+            (Some(package), Some(dir)) => {
+                let manifest = package::read_manifest(dir)?;
+                let mut files = match root.as_os_str().is_empty() {
+                    true => package::project_files(Some("."))?.0.into_iter().map(|file| file.strip_prefix(".").map(Path::to_path_buf).unwrap_or(file)).collect(),
+                    false => package::project_files(Some(&root.display().to_string()))?.0,
+                };
+                // The entry file's module goes first
+                files.retain(|file| *file != entry);
+                files.insert(0, entry.clone());
+                (files, format!("{} {}", package.name, package::version_string(manifest.version)))
+            }
+            _ => (vec![entry.clone()], entry.file_stem().unwrap_or_default().to_string_lossy().to_string()),
+        };
+
+        let mut modules = vec![];
+        let mut documented = vec![];
+        for file in files {
+            let relative = file.strip_prefix(&root).unwrap_or(&file).with_extension("");
+            let name: Vec<String> = relative.components().map(|part| part.as_os_str().to_string_lossy().to_string()).collect();
+            documented.push(file.canonicalize().unwrap_or(file.clone()));
+            modules.push(read_module(&file, name.join("::"))?);
+        }
+        // What the entry file imports from elsewhere (a lone file's neighbours, -I directories)
+        let imported = module_files(&entry.display().to_string(), &options.include, &options.packages)?;
+        for (name, file) in imported.into_iter().skip(1) {
+            if documented.contains(&file.canonicalize().unwrap_or(file.clone())) {continue}
+            documented.push(file.canonicalize().unwrap_or(file.clone()));
+            modules.push(read_module(&file, name)?);
+        }
+        let mut project = Project { title, root, dir: dir.unwrap_or_default(), packages: options.packages.clone(), include: options.include.clone(), modules };
+        project.signatures();
+        Ok(project)
+    }
+
+    /// Compiles `code` with `import <module>;` in front of it, like a file next to the entry
+    /// file would be
+    fn compile(&self, module: &Module, code: &str) -> Result<Analysis, String> {
+        let path = self.root.join("<doc test>");
+        let mut packages = self.packages.clone();
+        if let Some(root) = packages.last_mut() {root.entry = path.clone()}
+        // On the same line, so line numbers in errors are the block's own
+        let text = format!("import {}; {}", module.name, code);
+        load_source(&path.display().to_string(), &text, &self.include, &packages).and_then(analyze)
+    }
+
+    /// Adds the return types seman works out to the functions' signatures. A module that
+    /// doesn't compile on its own (one with top level code) keeps them without.
+    fn signatures(&mut self) {
+        for index in 0..self.modules.len() {
+            let module = &self.modules[index];
+            if !module.items.iter().any(|item| item.kind == Kind::Function) {continue}
+            let analysis = match self.compile(module, "") {
+                Ok(analysis) => analysis,
+                Err(error) => {
+                    eprintln!("warning: {} can't be imported, its signatures don't get return types: {}", module.path.display(), error);
+                    continue;
+                }
+            };
+            let name = module.name.clone();
+            for item in self.modules[index].items.iter_mut().filter(|item| item.kind == Kind::Function) {
+                if let Some(info) = analysis.functions.get(&mangle(&name, &item.name)) {
+                    item.signature = format!("pub {}", signature(&item.name, info));
+                }
+            }
+        }
+    }
+
+    /// Every Galvan code block in every doc comment, in order
+    fn tests(&self) -> Vec<DocTest> {
+        let mut tests = vec![];
+        for (index, module) in self.modules.iter().enumerate() {
+            let comments = std::iter::once((module.name.clone(), &module.docs))
+                .chain(module.items.iter().map(|item| (format!("{}::{}", module.name, item.name), &item.docs)));
+            for (name, comment) in comments {
+                for (line, info, code) in code_blocks(comment) {
+                    let Some((run, ignore)) = test_kind(&info) else {continue};
+                    let label = format!("{} - {} (line {})", module.path.display(), name, line);
+                    tests.push(DocTest { label, module: index, code, run, ignore });
+                }
+            }
+        }
+        tests
+    }
+
+    /// Where `[target]` in a comment of module `from` points: its page and the item on it
+    fn resolve(&self, from: usize, target: &str) -> Option<(String, Option<String>)> {
+        let module_named = |name: &str| self.modules.iter().find(|module| module.name == name)
+            .or_else(|| self.modules.iter().find(|module| module.name.rsplit("::").next() == Some(name)));
+        let item = |module: &Module, name: &str| module.items.iter().any(|item| item.name == name)
+            .then(|| (page_name(&module.name), Some(name.to_string())));
+        if let Some((qualifier, name)) = target.rsplit_once("::") && let Some(module) = module_named(qualifier) && let Some(found) = item(module, name) {
+            return Some(found);
+        }
+        if !target.contains("::") && let Some(found) = item(&self.modules[from], target) {
+            return Some(found);
+        }
+        module_named(target).map(|module| (page_name(&module.name), None))
+    }
+
+    /// Warns about every `[name]` that doesn't point anywhere, those end up as plain text
+    fn check_links(&self) {
+        for (index, module) in self.modules.iter().enumerate() {
+            for comment in std::iter::once(&module.docs).chain(module.items.iter().map(|item| &item.docs)) {
+                for (line, target) in item_links(comment) {
+                    if self.resolve(index, &target).is_none() {
+                        eprintln!("warning: [{}] on line {} of {} isn't an item or module, it stays plain text", target, line, module.path.display());
+                    }
+                }
+            }
+        }
+    }
+
+    /// `resolve()` as a link to a file with `extension`
+    fn href(&self, from: usize, target: &str, extension: &str) -> Option<String> {
+        self.resolve(from, target).map(|(page, anchor)| match anchor {
+            Some(anchor) => format!("{}.{}#{}", page, extension, anchor),
+            None => format!("{}.{}", page, extension),
+        })
+    }
+
+    /// The search index as a script, `searchIndex` is what SEARCH_SCRIPT searches
+    fn search_index(&self) -> String {
+        let mut entries = vec![];
+        for module in &self.modules {
+            let page = format!("{}.html", page_name(&module.name));
+            entries.push(Json::object(vec![
+                ("name", Json::string(&module.name)),
+                ("kind", Json::string("module")),
+                ("summary", Json::String(summary(&module.docs))),
+                ("href", Json::String(page.clone())),
+            ]));
+            for item in &module.items {
+                entries.push(Json::object(vec![
+                    ("name", Json::String(format!("{}::{}", module.name, item.name))),
+                    ("kind", Json::string(item.kind.name())),
+                    ("summary", Json::String(summary(&item.docs))),
+                    ("href", Json::String(format!("{}#{}", page, item.name))),
+                ]));
+            }
+        }
+        format!("var searchIndex = {};\n{}", Json::Array(entries).pretty(), SEARCH_SCRIPT)
+    }
+
+    fn html_page(&self, title: &str, body: &str) -> String {
+        let name = escape_html(self.title.split(' ').next().unwrap_or_default());
+        format!("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n<link rel=\"stylesheet\" href=\"style.css\">\n<script src=\"search.js\" defer></script>\n</head>\n<body>\n<nav><a href=\"index.html\">{}</a><input id=\"search\" type=\"search\" placeholder=\"Search {}\" autocomplete=\"off\"><ul id=\"results\"></ul></nav>\n<main>\n{}</main>\n</body>\n</html>\n",
+            escape_html(title), name, name, body)
+    }
+
+    /// Every page as (file name, contents)
+    fn html(&self) -> Vec<(String, String)> {
+        let mut index = format!("<h1>{}</h1>\n", escape_html(&self.title));
+        if let Some(first) = self.modules.first() {
+            index.push_str(&markdown_html(&first.docs, &|target| self.href(0, target, "html")));
+        }
+        index.push_str("<h2>Modules</h2>\n<ul>\n");
+        for module in &self.modules {
+            let _ = writeln!(index, "<li><a href=\"{}.html\"><code>{}</code></a> {}</li>", page_name(&module.name), escape_html(&module.name), escape_html(&summary(&module.docs)));
+        }
+        index.push_str("</ul>\n");
+        let mut pages = vec![("index.html".to_string(), self.html_page(&self.title, &index))];
+
+        for (number, module) in self.modules.iter().enumerate() {
+            let link = |target: &str| self.href(number, target, "html");
+            let mut body = format!("<h1>Module <code>{}</code></h1>\n<p class=\"source\">{}</p>\n", escape_html(&module.name), escape_html(&module.path.display().to_string()));
+            body.push_str(&markdown_html(&module.docs, &link));
+            for kind in [Kind::Constant, Kind::Function, Kind::Extern] {
+                let items: Vec<&Item> = module.items.iter().filter(|item| item.kind == kind).collect();
+                if items.is_empty() {continue}
+                let _ = writeln!(body, "<h2>{}</h2>", kind.heading());
+                for item in items {
+                    let _ = write!(body, "<div class=\"item\" id=\"{}\">\n{}{}</div>\n", item.name, highlight::html(&item.signature), markdown_html(&item.docs, &link));
+                }
+            }
+            pages.push((format!("{}.html", page_name(&module.name)), self.html_page(&module.name, &body)));
+        }
+        pages.push(("style.css".to_string(), format!("{}{}", highlight::STYLE, DOC_STYLE)));
+        pages.push(("search.js".to_string(), self.search_index()));
+        pages
+    }
+
+    /// Every page as (file name, contents), in Markdown
+    fn markdown(&self) -> Vec<(String, String)> {
+        let mut index = format!("# {}\n\n", self.title);
+        if let Some(first) = self.modules.first() && !first.docs.is_empty() {
+            index.push_str(&markdown_links(&first.docs, &|target| self.href(0, target, "md")));
+            index.push('\n');
+        }
+        index.push_str("## Modules\n\n");
+        for module in &self.modules {
+            let _ = writeln!(index, "{}", format!("- [`{}`]({}.md) {}", module.name, page_name(&module.name), summary(&module.docs)).trim_end());
+        }
+        let mut pages = vec![("index.md".to_string(), index)];
+
+        for (number, module) in self.modules.iter().enumerate() {
+            let link = |target: &str| self.href(number, target, "md");
+            let mut page = format!("# Module `{}`\n\n{}\n\n", module.name, module.path.display());
+            if !module.docs.is_empty() {
+                page.push_str(&markdown_links(&module.docs, &link));
+                page.push('\n');
+            }
+            for kind in [Kind::Constant, Kind::Function, Kind::Extern] {
+                let items: Vec<&Item> = module.items.iter().filter(|item| item.kind == kind).collect();
+                if items.is_empty() {continue}
+                let _ = write!(page, "## {}\n\n", kind.heading());
+                for item in items {
+                    let _ = write!(page, "<a id=\"{}\"></a>\n\n### `{}`\n\n```galvan\n{}\n```\n\n", item.name, item.name, item.signature);
+                    if !item.docs.is_empty() {
+                        page.push_str(&markdown_links(&item.docs, &link));
+                        page.push('\n');
+                    }
+                }
+            }
+            pages.push((format!("{}.md", page_name(&module.name)), page));
+        }
+        pages
+    }
+}
+
+/// `galvan doc`, writes the pages to -o or the package's target/doc
+pub fn doc(options: Options) -> Result<(), String> {
+    set_quiet(true);
+    let options = package::package_options(options)?;
+    let project = Project::new(&options)?;
+    project.check_links();
+    let out = match &options.output {
+        Some(output) => PathBuf::from(output),
+        None => project.dir.join(BUILD_DIR).join(DOC_DIR),
+    };
+    std::fs::create_dir_all(&out).map_err(|error| format!("Can't create '{}': {}", out.display(), error))?;
+    let pages = if options.markdown {project.markdown()} else {project.html()};
+    for (name, contents) in &pages {
+        let path = out.join(name);
+        std::fs::write(&path, contents).map_err(|error| format!("Can't write '{}': {}", path.display(), error))?;
+    }
+    let items: usize = project.modules.iter().map(|module| module.items.len()).sum();
+    let index = out.join(if options.markdown {"index.md"} else {"index.html"});
+    println!("Documented {} item{} in {} module{}, see {}", items, if items == 1 {""} else {"s"},
+        project.modules.len(), if project.modules.len() == 1 {""} else {"s"}, index.display());
+    Ok(())
+}
+
+/// `galvan test`, runs every doc test in a `galvan test --doctest=<n>` of its own
+pub fn test(options: Options) -> Result<(), String> {
+    set_quiet(true);
+    let source = options.source.clone();
+    let include = options.include.clone();
+    let project = Project::new(&package::package_options(options)?)?;
+    let tests = project.tests();
+    let executable = std::env::current_exe().map_err(|error| format!("Can't find the galvan executable: {}", error))?;
+
+    println!("running {} doc test{}", tests.len(), if tests.len() == 1 {""} else {"s"});
+    let (mut passed, mut ignored, mut failures) = (0, 0, vec![]);
+    for (number, test) in tests.iter().enumerate() {
+        if test.ignore {
+            println!("test {} ... ignored", test.label);
+            ignored += 1;
+            continue;
+        }
+        let mut command = std::process::Command::new(&executable);
+        command.arg("test").args(source.iter()).arg(format!("--doctest={}", number + 1));
+        for dir in &include {command.arg("-I").arg(dir);}
+        let output = command.output().map_err(|error| format!("Can't run {}: {}", executable.display(), error))?;
+        if output.status.success() {
+            println!("test {} ... ok", test.label);
+            passed += 1;
+        } else {
+            println!("test {} ... FAILED", test.label);
+            let mut log = String::from_utf8_lossy(&output.stdout).to_string();
+            log.push_str(&String::from_utf8_lossy(&output.stderr));
+            if let Some(code) = output.status.code() {log.push_str(&format!("exit code {}\n", code))}
+            failures.push((&test.label, log));
+        }
+    }
+
+    if !failures.is_empty() {
+        println!("\nfailures:");
+        for (label, log) in &failures {
+            print!("\n---- {} ----\n{}", label, log);
+        }
+    }
+    println!("\ntest result: {}. {} passed; {} failed; {} ignored", if failures.is_empty() {"ok"} else {"FAILED"}, passed, failures.len(), ignored);
+    match failures.len() {
+        0 => Ok(()),
+        1 => Err("1 doc test failed".to_string()),
+        failed => Err(format!("{} doc tests failed", failed)),
+    }
+}
+
+/// `galvan test --doctest=<n>`, compiles doc test n (from 1) and runs it here, gives back its
+/// exit code
+pub fn run_test(options: Options, number: usize) -> Result<i64, String> {
+    set_quiet(true);
+    let project = Project::new(&package::package_options(options)?)?;
+    let tests = project.tests();
+    let Some(test) = number.checked_sub(1).and_then(|index| tests.get(index)) else {
+        return Err(format!("There's no doc test {}, there are {}", number, tests.len()));
+    };
+    let analysis = project.compile(&project.modules[test.module], &test.code)?;
+    if !test.run {return Ok(0)}
+    Interpreter::new().run(&analysis).map_err(|error| format!("runtime error: {}", error))
+}
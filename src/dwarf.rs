@@ -0,0 +1,581 @@
+use crate::compiler_settings::*;
+use crate::seman::Type;
+use crate::x86::{Inst, Reg};
+
+// DWARF 4 debug info for the x86 backend (-g): .debug_line from the IR's `line` instructions,
+// .debug_info with every function and its parameters and locals (which all live in the frame,
+// so one location is good for the whole function), and .debug_frame to unwind through the rbp
+// frames. That's what gdb and objdump -S need.
+// Nothing here knows any addresses. The backend puts labels in the code where the debug info
+// needs one, and the sections refer to those with fixups: the assembly output prints them as
+// directives for the assembler, the ELF writer fills them in (or makes relocations of them).
+
+//
+// STRUCTS
+//
+
+/// A value in a debug section that depends on where things end up
+#[derive(Debug)]
+#[derive(Clone)]
+pub enum Fixup {
+    /// Address of a label, 8 bytes
+    Address(String),
+    /// `to - from` for two labels in the same code section
+    Delta {from: String, to: String, size: usize},
+    /// Offset of the start of another debug section (4 bytes), the linker moves those around
+    /// when it puts them together with other objects'
+    SectionOffset(&'static str),
+}
+impl Fixup {
+    pub fn size(&self) -> usize {
+        match self {
+            Fixup::Address(_) => 8,
+            Fixup::Delta { size, .. } => *size,
+            Fixup::SectionOffset(_) => 4,
+        }
+    }
+}
+
+/// One of the .debug_* sections, the fixups' bytes are zero in `data`
+#[derive(Debug)]
+pub struct Section {
+    pub name: &'static str,
+    pub data: Vec<u8>,
+    /// Offset in `data` -> what goes there
+    pub fixups: Vec<(usize, Fixup)>,
+}
+
+/// Where something changes for the unwinder, see `Function::frame()`
+#[derive(Debug)]
+#[derive(Clone)]
+pub enum FrameEvent {
+    /// After `push rbp`
+    PushedRbp,
+    /// After `mov rbp, rsp`
+    FramePointer,
+    /// After the callee-saved registers got saved, with where they are below rbp
+    Saved(Vec<(Reg, i32)>),
+    /// After the `pop rbp` of a return, rsp points at the return address again
+    Left,
+    /// After that return's `ret`, whatever comes next is back in the frame
+    Returned,
+}
+
+/// A parameter or local, which lives at `offset` from rbp
+#[derive(Debug)]
+pub struct Variable {
+    pub name: String,
+    pub ty: Type,
+    pub offset: i32,
+}
+
+/// What the debug info needs to know about one function's code. The backend fills this in
+/// while it generates the function, the labels it asks for go in the code.
+#[derive(Debug)]
+pub struct Function {
+    pub name: String,
+    pub symbol: String,
+    /// Label right after the last instruction
+    pub end: String,
+    pub external: bool,
+    pub return_type: Type,
+    /// File and line of the first row, which covers the prologue
+    pub declaration: Option<(usize, usize)>,
+    pub parameters: Vec<Variable>,
+    pub locals: Vec<Variable>,
+    /// Line table rows (label, file, line, column)
+    rows: Vec<(String, usize, usize, usize)>,
+    frame: Vec<(String, FrameEvent)>,
+    labels: usize,
+}
+impl Function {
+    pub fn new(name: &str, symbol: &str, external: bool, return_type: Type) -> Function {
+        Function {
+            name: name.to_string(),
+            symbol: symbol.to_string(),
+            end: format!(".L{}_end", symbol),
+            external,
+            return_type,
+            declaration: None,
+            parameters: vec![],
+            locals: vec![],
+            rows: vec![],
+            frame: vec![],
+            labels: 0,
+        }
+    }
+
+    fn label(&mut self, out: &mut Vec<Inst>) -> String {
+        let label = format!(".L{}_debug{}", self.symbol, self.labels);
+        self.labels += 1;
+        out.push(Inst::Label(label.clone()));
+        label
+    }
+
+    /// The code that comes next is from `line` in `file` (an index into the module's files)
+    pub fn row(&mut self, out: &mut Vec<Inst>, file: usize, line: usize, column: usize) {
+        if self.rows.is_empty() {self.declaration = Some((file, line))}
+        let label = self.label(out);
+        self.rows.push((label, file, line, column));
+    }
+
+    /// The instruction that was just generated changed how to unwind the frame
+    pub fn frame(&mut self, out: &mut Vec<Inst>, event: FrameEvent) {
+        let label = self.label(out);
+        self.frame.push((label, event));
+    }
+
+    /// Ends the function's code
+    pub fn end(&self, out: &mut Vec<Inst>) {
+        out.push(Inst::Label(self.end.clone()));
+    }
+}
+
+// Tags, attributes and forms
+const DW_TAG_FORMAL_PARAMETER: u8 = 0x05;
+const DW_TAG_POINTER_TYPE: u8 = 0x0f;
+const DW_TAG_COMPILE_UNIT: u8 = 0x11;
+const DW_TAG_TYPEDEF: u8 = 0x16;
+const DW_TAG_BASE_TYPE: u8 = 0x24;
+const DW_TAG_SUBPROGRAM: u8 = 0x2e;
+const DW_TAG_VARIABLE: u8 = 0x34;
+const DW_AT_LOCATION: u8 = 0x02;
+const DW_AT_NAME: u8 = 0x03;
+const DW_AT_BYTE_SIZE: u8 = 0x0b;
+const DW_AT_STMT_LIST: u8 = 0x10;
+const DW_AT_LOW_PC: u8 = 0x11;
+const DW_AT_HIGH_PC: u8 = 0x12;
+const DW_AT_LANGUAGE: u8 = 0x13;
+const DW_AT_COMP_DIR: u8 = 0x1b;
+const DW_AT_PRODUCER: u8 = 0x25;
+const DW_AT_DECL_FILE: u8 = 0x3a;
+const DW_AT_DECL_LINE: u8 = 0x3b;
+const DW_AT_ENCODING: u8 = 0x3e;
+const DW_AT_EXTERNAL: u8 = 0x3f;
+const DW_AT_FRAME_BASE: u8 = 0x40;
+const DW_AT_TYPE: u8 = 0x49;
+const DW_AT_RANGES: u8 = 0x55;
+const DW_AT_LINKAGE_NAME: u8 = 0x6e;
+const DW_FORM_ADDR: u8 = 0x01;
+const DW_FORM_DATA2: u8 = 0x05;
+const DW_FORM_DATA8: u8 = 0x07;
+const DW_FORM_STRING: u8 = 0x08;
+const DW_FORM_DATA1: u8 = 0x0b;
+const DW_FORM_FLAG: u8 = 0x0c;
+const DW_FORM_UDATA: u8 = 0x0f;
+const DW_FORM_REF4: u8 = 0x13;
+const DW_FORM_SEC_OFFSET: u8 = 0x17;
+const DW_FORM_EXPRLOC: u8 = 0x18;
+const DW_ATE_SIGNED: u8 = 0x05;
+const DW_ATE_UNSIGNED_CHAR: u8 = 0x08;
+/// gdb has no idea about Galvan, but C's expressions work the same on integers
+const DW_LANG_C99: u16 = 0x0c;
+const DW_OP_FBREG: u8 = 0x91;
+const DW_OP_CALL_FRAME_CFA: u8 = 0x9c;
+
+// Line number program
+const DW_LNS_COPY: u8 = 0x01;
+const DW_LNS_ADVANCE_LINE: u8 = 0x03;
+const DW_LNS_SET_FILE: u8 = 0x04;
+const DW_LNS_SET_COLUMN: u8 = 0x05;
+const DW_LNS_FIXED_ADVANCE_PC: u8 = 0x09;
+const DW_LNE_END_SEQUENCE: u8 = 0x01;
+const DW_LNE_SET_ADDRESS: u8 = 0x02;
+const LINE_BASE: i8 = -5;
+const LINE_RANGE: u8 = 14;
+const OPCODE_BASE: u8 = 13;
+const STANDARD_OPCODE_LENGTHS: [u8; 12] = [0, 1, 1, 1, 1, 0, 0, 0, 1, 0, 0, 1];
+
+// Call frame instructions
+const DW_CFA_ADVANCE_LOC4: u8 = 0x04;
+const DW_CFA_REMEMBER_STATE: u8 = 0x0a;
+const DW_CFA_RESTORE_STATE: u8 = 0x0b;
+const DW_CFA_DEF_CFA: u8 = 0x0c;
+const DW_CFA_DEF_CFA_REGISTER: u8 = 0x0d;
+const DW_CFA_DEF_CFA_OFFSET: u8 = 0x0e;
+const DW_CFA_OFFSET: u8 = 0x80;
+const DW_CFA_RESTORE: u8 = 0xc0;
+const RBP: u8 = 6;
+const RSP: u8 = 7;
+const RETURN_ADDRESS: u8 = 16;
+const DATA_ALIGNMENT: i64 = -8;
+
+// Abbreviation codes, see `abbreviations()`
+const ABBREV_COMPILE_UNIT: u64 = 1;
+const ABBREV_SUBPROGRAM: u64 = 2;
+const ABBREV_VOID_SUBPROGRAM: u64 = 3;
+const ABBREV_PARAMETER: u64 = 4;
+const ABBREV_VARIABLE: u64 = 5;
+const ABBREV_BASE_TYPE: u64 = 6;
+const ABBREV_POINTER: u64 = 7;
+const ABBREV_TYPEDEF: u64 = 8;
+
+/// Builds a section's bytes
+struct Writer {
+    data: Vec<u8>,
+    fixups: Vec<(usize, Fixup)>,
+}
+impl Writer {
+    fn new() -> Writer {
+        Writer { data: vec![], fixups: vec![] }
+    }
+
+    fn u8(&mut self, value: u8) {self.data.push(value)}
+    fn u16(&mut self, value: u16) {self.data.extend_from_slice(&value.to_le_bytes())}
+    fn u32(&mut self, value: u32) {self.data.extend_from_slice(&value.to_le_bytes())}
+    fn u64(&mut self, value: u64) {self.data.extend_from_slice(&value.to_le_bytes())}
+
+    fn uleb(&mut self, mut value: u64) {
+        loop {
+            let byte = (value & 0x7f) as u8;
+            value >>= 7;
+            if value == 0 {self.u8(byte); return}
+            self.u8(byte | 0x80);
+        }
+    }
+
+    fn sleb(&mut self, mut value: i64) {
+        loop {
+            let byte = (value & 0x7f) as u8;
+            value >>= 7;
+            if (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0) {self.u8(byte); return}
+            self.u8(byte | 0x80);
+        }
+    }
+
+    /// NUL terminated
+    fn string(&mut self, value: &str) {
+        self.data.extend_from_slice(value.as_bytes());
+        self.u8(0);
+    }
+
+    fn fixup(&mut self, fixup: Fixup) {
+        self.fixups.push((self.data.len(), fixup.clone()));
+        self.data.resize(self.data.len() + fixup.size(), 0);
+    }
+
+    /// Room for a 4-byte length, filled in by `end_length()`
+    fn start_length(&mut self) -> usize {
+        self.u32(0);
+        self.data.len()
+    }
+
+    /// Everything written since `start_length()`
+    fn end_length(&mut self, start: usize) {
+        let length = (self.data.len() - start) as u32;
+        self.data[start - 4..start].copy_from_slice(&length.to_le_bytes());
+    }
+
+    fn section(self, name: &'static str) -> Section {
+        Section { name, data: self.data, fixups: self.fixups }
+    }
+}
+
+//
+// FUNCTIONS
+//
+
+/// DWARF's numbering of the registers, not the hardware's
+fn register_number(reg: Reg) -> u8 {
+    match reg {
+        Reg::Rax => 0, Reg::Rdx => 1, Reg::Rcx => 2, Reg::Rbx => 3,
+        Reg::Rsi => 4, Reg::Rdi => 5, Reg::Rbp => 6, Reg::Rsp => 7,
+        reg => reg.number(),
+    }
+}
+
+/// Code, tag, whether it has children, and the attributes with their forms
+type Abbreviation = (u64, u8, bool, &'static [(u8, u8)]);
+
+/// .debug_abbrev, the shapes of the entries in .debug_info
+fn abbreviations() -> Section {
+    let mut out = Writer::new();
+    let entries: [Abbreviation; 8] = [
+        (ABBREV_COMPILE_UNIT, DW_TAG_COMPILE_UNIT, true, &[(DW_AT_PRODUCER, DW_FORM_STRING), (DW_AT_LANGUAGE, DW_FORM_DATA2),
+            (DW_AT_NAME, DW_FORM_STRING), (DW_AT_COMP_DIR, DW_FORM_STRING), (DW_AT_LOW_PC, DW_FORM_ADDR),
+            (DW_AT_RANGES, DW_FORM_SEC_OFFSET), (DW_AT_STMT_LIST, DW_FORM_SEC_OFFSET)]),
+        (ABBREV_SUBPROGRAM, DW_TAG_SUBPROGRAM, true, &[(DW_AT_NAME, DW_FORM_STRING), (DW_AT_LINKAGE_NAME, DW_FORM_STRING),
+            (DW_AT_DECL_FILE, DW_FORM_UDATA), (DW_AT_DECL_LINE, DW_FORM_UDATA), (DW_AT_EXTERNAL, DW_FORM_FLAG),
+            (DW_AT_LOW_PC, DW_FORM_ADDR), (DW_AT_HIGH_PC, DW_FORM_DATA8), (DW_AT_FRAME_BASE, DW_FORM_EXPRLOC), (DW_AT_TYPE, DW_FORM_REF4)]),
+        (ABBREV_VOID_SUBPROGRAM, DW_TAG_SUBPROGRAM, true, &[(DW_AT_NAME, DW_FORM_STRING), (DW_AT_LINKAGE_NAME, DW_FORM_STRING),
+            (DW_AT_DECL_FILE, DW_FORM_UDATA), (DW_AT_DECL_LINE, DW_FORM_UDATA), (DW_AT_EXTERNAL, DW_FORM_FLAG),
+            (DW_AT_LOW_PC, DW_FORM_ADDR), (DW_AT_HIGH_PC, DW_FORM_DATA8), (DW_AT_FRAME_BASE, DW_FORM_EXPRLOC)]),
+        (ABBREV_PARAMETER, DW_TAG_FORMAL_PARAMETER, false, &[(DW_AT_NAME, DW_FORM_STRING), (DW_AT_TYPE, DW_FORM_REF4), (DW_AT_LOCATION, DW_FORM_EXPRLOC)]),
+        (ABBREV_VARIABLE, DW_TAG_VARIABLE, false, &[(DW_AT_NAME, DW_FORM_STRING), (DW_AT_TYPE, DW_FORM_REF4), (DW_AT_LOCATION, DW_FORM_EXPRLOC)]),
+        (ABBREV_BASE_TYPE, DW_TAG_BASE_TYPE, false, &[(DW_AT_NAME, DW_FORM_STRING), (DW_AT_ENCODING, DW_FORM_DATA1), (DW_AT_BYTE_SIZE, DW_FORM_DATA1)]),
+        (ABBREV_POINTER, DW_TAG_POINTER_TYPE, false, &[(DW_AT_BYTE_SIZE, DW_FORM_DATA1), (DW_AT_TYPE, DW_FORM_REF4)]),
+        (ABBREV_TYPEDEF, DW_TAG_TYPEDEF, false, &[(DW_AT_NAME, DW_FORM_STRING), (DW_AT_TYPE, DW_FORM_REF4)]),
+    ];
+    for (code, tag, children, attributes) in entries {
+        out.uleb(code);
+        out.uleb(tag as u64);
+        out.u8(children as u8);
+        for (attribute, form) in attributes {
+            out.uleb(*attribute as u64);
+            out.uleb(*form as u64);
+        }
+        out.u16(0);
+    }
+    out.u8(0);
+    out.section(".debug_abbrev")
+}
+
+/// .debug_info: one compile unit with the types, and every function with its variables in it
+fn info(files: &[String], functions: &[Function]) -> Section {
+    let mut out = Writer::new();
+    let unit = out.start_length();
+    out.u16(4);
+    out.fixup(Fixup::SectionOffset(".debug_abbrev"));
+    out.u8(8);
+
+    out.uleb(ABBREV_COMPILE_UNIT);
+    out.string(&format!("{} {}", DWARF_PRODUCER, env!("CARGO_PKG_VERSION")));
+    out.u16(DW_LANG_C99);
+    out.string(files.first().map(String::as_str).unwrap_or(""));
+    out.string(&std::env::current_dir().map(|dir| dir.display().to_string()).unwrap_or_default());
+    // Base address for the ranges, they're absolute
+    out.u64(0);
+    out.fixup(Fixup::SectionOffset(".debug_ranges"));
+    out.fixup(Fixup::SectionOffset(".debug_line"));
+
+    // i64, and str is a pointer to bytes (so gdb prints it as a string)
+    let int = out.data.len() as u32;
+    out.uleb(ABBREV_BASE_TYPE);
+    out.string("i64");
+    out.u8(DW_ATE_SIGNED);
+    out.u8(8);
+    let byte = out.data.len() as u32;
+    out.uleb(ABBREV_BASE_TYPE);
+    out.string("u8");
+    out.u8(DW_ATE_UNSIGNED_CHAR);
+    out.u8(1);
+    let pointer = out.data.len() as u32;
+    out.uleb(ABBREV_POINTER);
+    out.u8(8);
+    out.u32(byte);
+    let string = out.data.len() as u32;
+    out.uleb(ABBREV_TYPEDEF);
+    out.string("str");
+    out.u32(pointer);
+    let type_reference = |ty: Type| if ty == Type::Str {string} else {int};
+
+    for function in functions {
+        out.uleb(if function.return_type == Type::Void {ABBREV_VOID_SUBPROGRAM} else {ABBREV_SUBPROGRAM});
+        out.string(&function.name);
+        out.string(&function.symbol);
+        // File 0 and line 0 are "don't know"
+        let (file, line) = function.declaration.map(|(file, line)| (file + 1, line)).unwrap_or((0, 0));
+        out.uleb(file as u64);
+        out.uleb(line as u64);
+        out.u8(function.external as u8);
+        out.fixup(Fixup::Address(function.symbol.clone()));
+        out.fixup(Fixup::Delta { from: function.symbol.clone(), to: function.end.clone(), size: 8 });
+        out.uleb(1);
+        out.u8(DW_OP_CALL_FRAME_CFA);
+        if function.return_type != Type::Void {out.u32(type_reference(function.return_type))}
+
+        let variables = function.parameters.iter().map(|variable| (ABBREV_PARAMETER, variable))
+            .chain(function.locals.iter().map(|variable| (ABBREV_VARIABLE, variable)));
+        for (abbreviation, variable) in variables {
+            out.uleb(abbreviation);
+            out.string(&variable.name);
+            out.u32(type_reference(variable.ty));
+            // The frame base is the CFA, which is 16 above rbp (return address and saved rbp)
+            let mut location = Writer::new();
+            location.u8(DW_OP_FBREG);
+            location.sleb(variable.offset as i64 - 16);
+            out.uleb(location.data.len() as u64);
+            out.data.extend(location.data);
+        }
+        out.u8(0);
+    }
+    out.u8(0);
+    out.end_length(unit);
+    out.section(".debug_info")
+}
+
+/// .debug_ranges, where the compile unit's code is
+fn ranges(functions: &[Function]) -> Section {
+    let mut out = Writer::new();
+    for function in functions {
+        out.fixup(Fixup::Address(function.symbol.clone()));
+        out.fixup(Fixup::Address(function.end.clone()));
+    }
+    out.u64(0);
+    out.u64(0);
+    out.section(".debug_ranges")
+}
+
+/// .debug_line, a sequence for every function that has rows
+fn lines(files: &[String], functions: &[Function]) -> Section {
+    let mut out = Writer::new();
+    let unit = out.start_length();
+    out.u16(4);
+    let header = out.start_length();
+    out.u8(1); // minimum_instruction_length
+    out.u8(1); // maximum_operations_per_instruction
+    out.u8(1); // default_is_stmt
+    out.u8(LINE_BASE as u8);
+    out.u8(LINE_RANGE);
+    out.u8(OPCODE_BASE);
+    out.data.extend_from_slice(&STANDARD_OPCODE_LENGTHS);
+    out.u8(0); // No include directories, paths are relative to the compile unit's directory
+    for file in files {
+        out.string(file);
+        out.uleb(0); // directory
+        out.uleb(0); // modification time
+        out.uleb(0); // length
+    }
+    out.u8(0);
+    out.end_length(header);
+
+    for function in functions.iter().filter(|function| !function.rows.is_empty()) {
+        out.u8(0);
+        out.uleb(9);
+        out.u8(DW_LNE_SET_ADDRESS);
+        out.fixup(Fixup::Address(function.symbol.clone()));
+        // Every sequence starts over at file 1, line 1
+        let (mut file, mut line) = (0, 1);
+        let mut previous = &function.symbol;
+        for (label, row_file, row_line, column) in &function.rows {
+            out.u8(DW_LNS_FIXED_ADVANCE_PC);
+            out.fixup(Fixup::Delta { from: previous.clone(), to: label.clone(), size: 2 });
+            if *row_file != file {
+                out.u8(DW_LNS_SET_FILE);
+                out.uleb(*row_file as u64 + 1);
+                file = *row_file;
+            }
+            if *row_line != line {
+                out.u8(DW_LNS_ADVANCE_LINE);
+                out.sleb(*row_line as i64 - line as i64);
+                line = *row_line;
+            }
+            out.u8(DW_LNS_SET_COLUMN);
+            out.uleb(*column as u64);
+            out.u8(DW_LNS_COPY);
+            previous = label;
+        }
+        out.u8(DW_LNS_FIXED_ADVANCE_PC);
+        out.fixup(Fixup::Delta { from: previous.clone(), to: function.end.clone(), size: 2 });
+        out.u8(0);
+        out.uleb(1);
+        out.u8(DW_LNE_END_SEQUENCE);
+    }
+    out.end_length(unit);
+    out.section(".debug_line")
+}
+
+/// Pads a CIE or FDE to a multiple of 8 bytes with DW_CFA_nop
+fn pad(out: &mut Writer, start: usize) {
+    while !(out.data.len() - start + 4).is_multiple_of(8) {out.u8(0)}
+}
+
+/// .debug_frame: a CIE for the state at a call (CFA is rsp + 8, the return address is right
+/// below it), and an FDE for every function with what its prologue and returns change
+fn frames(functions: &[Function]) -> Section {
+    let mut out = Writer::new();
+    let cie = out.start_length();
+    out.u32(0xffff_ffff);
+    out.u8(1); // version
+    out.string(""); // augmentation
+    out.uleb(1); // code alignment
+    out.sleb(DATA_ALIGNMENT);
+    out.u8(RETURN_ADDRESS);
+    out.u8(DW_CFA_DEF_CFA);
+    out.uleb(RSP as u64);
+    out.uleb(8);
+    out.u8(DW_CFA_OFFSET | RETURN_ADDRESS);
+    out.uleb(1);
+    pad(&mut out, cie);
+    out.end_length(cie);
+
+    for function in functions {
+        let fde = out.start_length();
+        out.fixup(Fixup::SectionOffset(".debug_frame"));
+        out.fixup(Fixup::Address(function.symbol.clone()));
+        out.fixup(Fixup::Delta { from: function.symbol.clone(), to: function.end.clone(), size: 8 });
+        // The saved registers, restored before every return
+        let mut saved: Vec<u8> = vec![];
+        let mut previous = &function.symbol;
+        for (label, event) in &function.frame {
+            out.u8(DW_CFA_ADVANCE_LOC4);
+            out.fixup(Fixup::Delta { from: previous.clone(), to: label.clone(), size: 4 });
+            previous = label;
+            match event {
+                FrameEvent::PushedRbp => {
+                    out.u8(DW_CFA_DEF_CFA_OFFSET);
+                    out.uleb(16);
+                    out.u8(DW_CFA_OFFSET | RBP);
+                    out.uleb(2);
+                }
+                FrameEvent::FramePointer => {
+                    out.u8(DW_CFA_DEF_CFA_REGISTER);
+                    out.uleb(RBP as u64);
+                }
+                FrameEvent::Saved(registers) => for (reg, offset) in registers {
+                    // Offsets from the CFA in multiples of the data alignment
+                    let number = register_number(*reg);
+                    out.u8(DW_CFA_OFFSET | number);
+                    out.uleb(((*offset as i64 - 16) / DATA_ALIGNMENT) as u64);
+                    saved.push(number);
+                }
+                FrameEvent::Left => {
+                    out.u8(DW_CFA_REMEMBER_STATE);
+                    out.u8(DW_CFA_DEF_CFA);
+                    out.uleb(RSP as u64);
+                    out.uleb(8);
+                    for number in std::iter::once(&RBP).chain(&saved) {out.u8(DW_CFA_RESTORE | number)}
+                }
+                FrameEvent::Returned => out.u8(DW_CFA_RESTORE_STATE),
+            }
+        }
+        pad(&mut out, fde);
+        out.end_length(fde);
+    }
+    out.section(".debug_frame")
+}
+
+/// All the debug sections for the functions, `files` are the module's
+pub fn generate(files: &[String], functions: &[Function]) -> Vec<Section> {
+    if debug_prints(DWARF_DEBUG_PRINTS) {eprintln!("- - - DWARF")}
+    let sections = vec![abbreviations(), info(files, functions), ranges(functions), lines(files, functions), frames(functions)];
+    if debug_prints(DWARF_DEBUG_PRINTS) {
+        for section in &sections {eprintln!("{}: {} bytes, {} fixups", section.name, section.data.len(), section.fixups.len())}
+        eprintln!("- - - DWARF done!");
+    }
+    sections
+}
+
+/// Label at the start of a debug section in the assembly output, for `Fixup::SectionOffset`
+pub fn section_label(name: &str) -> String {
+    format!(".L{}", name.trim_start_matches('.'))
+}
+
+/// The section as assembler directives, the assembler works out the fixups
+pub fn to_assembly(section: &Section) -> String {
+    let mut out = format!("\n.section {},\"\",@progbits\n{}:\n", section.name, section_label(section.name));
+    let mut bytes: Vec<String> = vec![];
+    let flush = |out: &mut String, bytes: &mut Vec<String>| {
+        for line in bytes.chunks(16) {out.push_str(&format!("    .byte {}\n", line.join(", ")))}
+        bytes.clear();
+    };
+    let mut position = 0;
+    let mut fixups = section.fixups.iter().peekable();
+    while position < section.data.len() {
+        if let Some((_, fixup)) = fixups.next_if(|(offset, _)| *offset == position) {
+            flush(&mut out, &mut bytes);
+            let directive = match fixup.size() {2 => ".2byte", 4 => ".long", _ => ".quad"};
+            match fixup {
+                Fixup::Address(label) => out.push_str(&format!("    {} {}\n", directive, label)),
+                Fixup::Delta { from, to, .. } => out.push_str(&format!("    {} {} - {}\n", directive, to, from)),
+                Fixup::SectionOffset(name) => out.push_str(&format!("    {} {}\n", directive, section_label(name))),
+            }
+            position += fixup.size();
+            continue;
+        }
+        bytes.push(format!("{:#04x}", section.data[position]));
+        position += 1;
+    }
+    flush(&mut out, &mut bytes);
+    out
+}
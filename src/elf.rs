@@ -0,0 +1,619 @@
+use std::collections::HashMap;
+
+use crate::compiler_settings::*;
+use crate::dwarf::{self, Fixup};
+use crate::linker_script::{self, Input, LinkerScript};
+use crate::x86::{Inst, Program};
+use crate::x86_encoder::{encode, Encoded, RelocationTarget};
+
+// ELF64 writer for the x86 backend. Writes either a relocatable object (.o) that
+// can be linked with anything else, or a statically linked executable that doesn't
+// need a linker at all.
+// Executables with a linker script (freestanding ones always have one) get their sections
+// placed by linker_script.rs, one PT_LOAD per page range, so they load at the addresses the
+// hardware wants.
+// With -g the program's .debug_* sections (dwarf.rs) go in too. Objects get relocations for the
+// addresses in them, executables get them filled in.
+
+//
+// STRUCTS
+//
+
+const ET_REL: u16 = 1;
+const ET_EXEC: u16 = 2;
+const EM_X86_64: u16 = 0x3E;
+
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHT_RELA: u32 = 4;
+const SHT_NOBITS: u32 = 8;
+
+const SHF_WRITE: u64 = 1;
+const SHF_ALLOC: u64 = 2;
+const SHF_EXECINSTR: u64 = 4;
+const SHF_INFO_LINK: u64 = 0x40;
+
+const STB_LOCAL: u8 = 0;
+const STB_GLOBAL: u8 = 1;
+const STT_NOTYPE: u8 = 0;
+const STT_FUNC: u8 = 2;
+const STT_SECTION: u8 = 3;
+const SHN_ABS: u16 = 0xFFF1;
+
+const R_X86_64_64: u32 = 1;
+const R_X86_64_PC32: u32 = 2;
+const R_X86_64_PLT32: u32 = 4;
+const R_X86_64_32: u32 = 10;
+
+const PT_NULL: u32 = 0;
+const PT_LOAD: u32 = 1;
+const PT_GNU_STACK: u32 = 0x6474_E551;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+
+const HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+const SECTION_HEADER_SIZE: usize = 64;
+const SYMBOL_SIZE: usize = 24;
+const PAGE_SIZE: usize = 0x1000;
+
+struct Section {
+    name: String,
+    kind: u32,
+    flags: u64,
+    address: u64,
+    data: Vec<u8>,
+    link: u32,
+    info: u32,
+    align: u64,
+    entry_size: u64,
+    /// Size of a SHT_NOBITS section, which has no data in the file
+    memory_size: u64,
+}
+impl Section {
+    fn new(name: &str, kind: u32, flags: u64, data: Vec<u8>, align: u64) -> Section {
+        Section { name: name.to_string(), kind, flags, address: 0, data, link: 0, info: 0, align, entry_size: 0, memory_size: 0 }
+    }
+
+    fn size(&self) -> u64 {
+        if self.kind == SHT_NOBITS {self.memory_size} else {self.data.len() as u64}
+    }
+}
+
+struct ProgramHeader {
+    kind: u32,
+    flags: u32,
+    offset: u64,
+    address: u64,
+    size: u64,
+    /// Bigger than `size` when the end isn't in the file (zeroed memory)
+    memory_size: u64,
+    align: u64,
+}
+
+/// Symbol table and its string table, built together
+struct Symbols {
+    table: Vec<u8>,
+    strings: Vec<u8>,
+    count: usize,
+    /// Index of the first global, the symbol table's sh_info
+    info: usize,
+}
+impl Symbols {
+    fn new() -> Symbols {
+        // Both tables start with an empty entry
+        Symbols { table: vec![0; SYMBOL_SIZE], strings: vec![0], count: 1, info: 1 }
+    }
+
+    fn add(&mut self, name: &str, binding: u8, kind: u8, section: u16, value: u64, size: u64) -> usize {
+        let name_offset = if name.is_empty() {0} else {
+            let offset = self.strings.len();
+            self.strings.extend_from_slice(name.as_bytes());
+            self.strings.push(0);
+            offset
+        };
+        put_u32(&mut self.table, name_offset as u32);
+        self.table.push(binding << 4 | kind);
+        self.table.push(0);
+        put_u16(&mut self.table, section);
+        put_u64(&mut self.table, value);
+        put_u64(&mut self.table, size);
+        self.count += 1;
+        self.count - 1
+    }
+}
+
+//
+// FUNCTIONS
+//
+
+fn put_u16(out: &mut Vec<u8>, value: u16) {out.extend_from_slice(&value.to_le_bytes())}
+fn put_u32(out: &mut Vec<u8>, value: u32) {out.extend_from_slice(&value.to_le_bytes())}
+fn put_u64(out: &mut Vec<u8>, value: u64) {out.extend_from_slice(&value.to_le_bytes())}
+
+fn align(value: usize, alignment: usize) -> usize {
+    value.div_ceil(alignment) * alignment
+}
+
+/// File offsets of each section's data, everything comes right after the headers. Sections
+/// with an address get an offset in the same spot of a page, so they can be mmapped.
+fn layout(sections: &[Section], program_headers: usize) -> Vec<usize> {
+    let mut offset = HEADER_SIZE + program_headers * PROGRAM_HEADER_SIZE;
+    let mut offsets = vec![];
+    for section in sections {
+        offset = align(offset, section.align.max(1) as usize);
+        if section.address != 0 && section.flags & SHF_ALLOC != 0 {
+            offset += (section.address as usize).wrapping_sub(offset) % PAGE_SIZE;
+        }
+        offsets.push(offset);
+        offset += section.data.len();
+    }
+    offsets
+}
+
+/// Writes the whole file. Section 0 (the null section) and .shstrtab get added here.
+fn write_elf(kind: u16, entry: u64, program_headers: &[ProgramHeader], mut sections: Vec<Section>) -> Vec<u8> {
+    sections.push(Section::new(".shstrtab", SHT_STRTAB, 0, vec![], 1));
+    let mut names = vec![0u8];
+    let mut name_offsets = vec![];
+    for section in &sections {
+        name_offsets.push(names.len() as u32);
+        names.extend_from_slice(section.name.as_bytes());
+        names.push(0);
+    }
+    sections.last_mut().unwrap().data = names;
+
+    let offsets = layout(&sections, program_headers.len());
+    let end = offsets.last().unwrap() + sections.last().unwrap().data.len();
+    let section_headers = align(end, 8);
+
+    let mut out = vec![];
+    out.extend_from_slice(&[0x7F, b'E', b'L', b'F', 2, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    put_u16(&mut out, kind);
+    put_u16(&mut out, EM_X86_64);
+    put_u32(&mut out, 1);
+    put_u64(&mut out, entry);
+    put_u64(&mut out, if program_headers.is_empty() {0} else {HEADER_SIZE as u64});
+    put_u64(&mut out, section_headers as u64);
+    put_u32(&mut out, 0);
+    put_u16(&mut out, HEADER_SIZE as u16);
+    put_u16(&mut out, if program_headers.is_empty() {0} else {PROGRAM_HEADER_SIZE as u16});
+    put_u16(&mut out, program_headers.len() as u16);
+    put_u16(&mut out, SECTION_HEADER_SIZE as u16);
+    put_u16(&mut out, sections.len() as u16 + 1);
+    put_u16(&mut out, sections.len() as u16); // .shstrtab is the last one
+
+    for header in program_headers {
+        put_u32(&mut out, header.kind);
+        put_u32(&mut out, header.flags);
+        put_u64(&mut out, header.offset);
+        put_u64(&mut out, header.address);
+        put_u64(&mut out, header.address);
+        put_u64(&mut out, header.size);
+        put_u64(&mut out, header.memory_size);
+        put_u64(&mut out, header.align);
+    }
+
+    for (section, offset) in sections.iter().zip(&offsets) {
+        out.resize(*offset, 0);
+        out.extend_from_slice(&section.data);
+    }
+    out.resize(section_headers, 0);
+
+    out.extend_from_slice(&[0; SECTION_HEADER_SIZE]);
+    for ((section, offset), name) in sections.iter().zip(&offsets).zip(&name_offsets) {
+        put_u32(&mut out, *name);
+        put_u32(&mut out, section.kind);
+        put_u64(&mut out, section.flags);
+        put_u64(&mut out, section.address);
+        put_u64(&mut out, *offset as u64);
+        put_u64(&mut out, section.size());
+        put_u32(&mut out, section.link);
+        put_u32(&mut out, section.info);
+        put_u64(&mut out, section.align);
+        put_u64(&mut out, section.entry_size);
+    }
+    out
+}
+
+/// Rodata as one blob, and where every label in it starts
+fn rodata(program: &Program) -> (Vec<u8>, HashMap<String, usize>) {
+    let mut data = vec![];
+    let mut labels = HashMap::new();
+    for (label, bytes) in &program.rodata {
+        labels.insert(label.clone(), data.len());
+        data.extend_from_slice(bytes);
+    }
+    (data, labels)
+}
+
+/// Symbols that aren't `.L` locals, sorted by address, with their sizes
+fn function_symbols(encoded: &Encoded) -> Vec<(String, usize, usize)> {
+    let mut symbols: Vec<(String, usize)> = encoded.labels.iter()
+        .filter(|(label, _)| !label.starts_with(".L"))
+        .map(|(label, offset)| (label.clone(), *offset))
+        .collect();
+    symbols.sort_by_key(|(label, offset)| (*offset, label.clone()));
+    let mut sized = vec![];
+    for (index, (label, offset)) in symbols.iter().enumerate() {
+        let end = symbols.get(index + 1).map(|(_, next)| *next).unwrap_or(encoded.code.len());
+        sized.push((label.clone(), *offset, end - offset));
+    }
+    sized
+}
+
+/// A debug section's data with the fixups filled in. `label` gives the code section a label is
+/// in and its address (its offset in objects). Addresses are only filled in when `absolute`,
+/// in objects they're relocations, just like the section offsets (which are 0 in executables).
+fn debug_data(section: &dwarf::Section, label: &dyn Fn(&str) -> Option<(usize, u64)>, absolute: bool) -> Result<Vec<u8>, String> {
+    let find = |name: &str| label(name).ok_or(format!("Debug info refers to '{}', which isn't in the code", name));
+    let mut data = section.data.clone();
+    for (offset, fixup) in &section.fixups {
+        let size = fixup.size();
+        let value = match fixup {
+            Fixup::Delta { from, to, .. } => {
+                let ((from_section, from), (to_section, to)) = (find(from)?, find(to)?);
+                let delta = to.wrapping_sub(from);
+                if from_section != to_section || (size < 8 && delta >> (8 * size) != 0) {
+                    return Err(format!("'{}' is too far from '{}' for the debug info", to, from));
+                }
+                delta
+            }
+            Fixup::Address(name) if absolute => find(name)?.1,
+            Fixup::Address(_) | Fixup::SectionOffset(_) => continue,
+        };
+        data[*offset..*offset + size].copy_from_slice(&value.to_le_bytes()[..size]);
+    }
+    Ok(data)
+}
+
+/// Debug sections for an executable, where every label's address is known
+fn debug_sections(program: &Program, label: &dyn Fn(&str) -> Option<(usize, u64)>) -> Result<Vec<Section>, String> {
+    program.debug.iter()
+        .map(|section| Ok(Section::new(section.name, SHT_PROGBITS, 0, debug_data(section, label, true)?, 1)))
+        .collect()
+}
+
+/// .text and then every `#[section]`, each encoded on its own
+fn code_sections(program: &Program) -> Result<Vec<(String, Encoded)>, String> {
+    let mut sections = vec![(".text".to_string(), encode(&program.text)?)];
+    for (name, text) in &program.sections {
+        sections.push((name.clone(), encode(text)?));
+    }
+    Ok(sections)
+}
+
+/// Relocatable ELF64 object with .text (and a section per `#[section]`), .data, .rodata,
+/// .symtab and a .rela section for every code section
+pub fn write_object(program: &Program) -> Result<Vec<u8>, String> {
+    if debug_prints(ELF_DEBUG_PRINTS) {eprintln!("- - - ELF (object)")}
+    let code = code_sections(program)?;
+    let (rodata, rodata_labels) = rodata(program);
+
+    // Section numbers, in the order they're pushed below (0 is the null section): the code
+    // sections, .data, .rodata, a .rela for every code section, .symtab
+    // sections, .data, .rodata, a .rela for every code section, .symtab, .strtab,
+    // .note.GNU-stack, then the debug sections and a .rela for the ones that need it
+    let code_count = code.len() as u16;
+    let data_section = code_count + 1;
+    let rodata_section = code_count + 2;
+    let symtab_section = 2 * code_count as u32 + 3;
+    let debug_section = symtab_section as u16 + 3;
+
+    let mut symbols = Symbols::new();
+    for index in 1..=code_count {
+        symbols.add("", STB_LOCAL, STT_SECTION, index, 0, 0);
+    }
+    symbols.add("", STB_LOCAL, STT_SECTION, data_section, 0, 0);
+    let rodata_symbol = symbols.add("", STB_LOCAL, STT_SECTION, rodata_section, 0, 0);
+    let debug_symbols: Vec<usize> = (0..program.debug.len())
+        .map(|index| symbols.add("", STB_LOCAL, STT_SECTION, debug_section + index as u16, 0, 0)).collect();
+
+    // Locals have to come before globals
+    let functions: Vec<Vec<(String, usize, usize)>> = code.iter().map(|(_, encoded)| function_symbols(encoded)).collect();
+    let mut symbol_indices: HashMap<String, usize> = HashMap::new();
+    for global in [false, true] {
+        if global {symbols.info = symbols.count}
+        for (section, functions) in functions.iter().enumerate() {
+            for (name, offset, size) in functions.iter().filter(|(name, ..)| program.globals.contains(name) == global) {
+                let binding = if global {STB_GLOBAL} else {STB_LOCAL};
+                let index = symbols.add(name, binding, STT_FUNC, section as u16 + 1, *offset as u64, *size as u64);
+                symbol_indices.insert(name.clone(), index);
+            }
+        }
+    }
+    let first_global = symbols.info;
+
+    let mut relas = vec![];
+    for (section, (name, encoded)) in code.iter().enumerate() {
+        let mut relocations = vec![];
+        for relocation in &encoded.relocations {
+            let (symbol, kind, addend) = match &relocation.target {
+                RelocationTarget::Rodata(label) => {
+                    let offset = rodata_labels.get(label).ok_or(format!("Unknown rodata label '{}'", label))?;
+                    (rodata_symbol, R_X86_64_PC32, relocation.addend + *offset as i64)
+                }
+                RelocationTarget::Symbol(name) => {
+                    // In another section, or not defined here at all and the linker has to find it
+                    let index = match symbol_indices.get(name) {
+                        Some(index) => *index,
+                        None => {
+                            let index = symbols.add(name, STB_GLOBAL, STT_NOTYPE, 0, 0, 0);
+                            symbol_indices.insert(name.clone(), index);
+                            index
+                        }
+                    };
+                    (index, R_X86_64_PLT32, relocation.addend)
+                }
+            };
+            put_u64(&mut relocations, relocation.offset as u64);
+            put_u64(&mut relocations, (symbol as u64) << 32 | kind as u64);
+            put_u64(&mut relocations, addend as u64);
+        }
+        let mut rela = Section::new(&format!(".rela{}", name), SHT_RELA, SHF_INFO_LINK, relocations, 8);
+        rela.link = symtab_section;
+        rela.info = section as u32 + 1;
+        rela.entry_size = 24;
+        relas.push(rela);
+    }
+
+    // Addresses in the debug info are relative to the code section's symbol, offsets of other
+    // debug sections to theirs
+    let label = |name: &str| code.iter().enumerate()
+        .find_map(|(section, (_, encoded))| encoded.labels.get(name).map(|offset| (section, *offset as u64)));
+    let mut debug = vec![];
+    let mut debug_relas = vec![];
+    for (index, section) in program.debug.iter().enumerate() {
+        debug.push(Section::new(section.name, SHT_PROGBITS, 0, debug_data(section, &label, false)?, 1));
+        let mut relocations = vec![];
+        for (offset, fixup) in &section.fixups {
+            let (symbol, kind, addend) = match fixup {
+                Fixup::Address(name) => {
+                    let (code_section, offset) = label(name).ok_or(format!("Debug info refers to '{}', which isn't in the code", name))?;
+                    (code_section + 1, R_X86_64_64, offset)
+                }
+                Fixup::SectionOffset(name) => match program.debug.iter().position(|other| other.name == *name) {
+                    Some(other) => (debug_symbols[other], R_X86_64_32, 0),
+                    None => return Err(format!("Debug info refers to {}, which isn't there", name)),
+                },
+                Fixup::Delta { .. } => continue,
+            };
+            put_u64(&mut relocations, *offset as u64);
+            put_u64(&mut relocations, (symbol as u64) << 32 | kind as u64);
+            put_u64(&mut relocations, addend);
+        }
+        if relocations.is_empty() {continue}
+        let mut rela = Section::new(&format!(".rela{}", section.name), SHT_RELA, SHF_INFO_LINK, relocations, 8);
+        rela.link = symtab_section;
+        rela.info = (debug_section as usize + index) as u32;
+        rela.entry_size = 24;
+        debug_relas.push(rela);
+    }
+
+    let mut symtab = Section::new(".symtab", SHT_SYMTAB, 0, symbols.table, 8);
+    symtab.link = symtab_section + 1;
+    symtab.info = first_global as u32;
+    symtab.entry_size = SYMBOL_SIZE as u64;
+
+    let mut sections: Vec<Section> = code.into_iter()
+        .map(|(name, encoded)| Section::new(&name, SHT_PROGBITS, SHF_ALLOC | SHF_EXECINSTR, encoded.code, 16)).collect();
+    sections.push(Section::new(".data", SHT_PROGBITS, SHF_ALLOC | SHF_WRITE, vec![], 8));
+    sections.push(Section::new(".rodata", SHT_PROGBITS, SHF_ALLOC, rodata, 8));
+    sections.extend(relas);
+    sections.push(symtab);
+    sections.push(Section::new(".strtab", SHT_STRTAB, 0, symbols.strings, 1));
+    // Non-executable stack, same as what `as` puts in
+    sections.push(Section::new(".note.GNU-stack", SHT_PROGBITS, 0, vec![], 1));
+    sections.extend(debug);
+    sections.extend(debug_relas);
+
+    if debug_prints(ELF_DEBUG_PRINTS) {eprintln!("- - - ELF done!")}
+    Ok(write_elf(ET_REL, 0, &[], sections))
+}
+
+/// Statically linked ELF64 executable. Without a linker script everything gets loaded as one
+/// read+execute segment starting at `EXECUTABLE_BASE` (there's nothing writable to load yet),
+/// `#[section]`s just go at the end of .text.
+pub fn write_executable(program: &Program, script: Option<&LinkerScript>) -> Result<Vec<u8>, String> {
+    if let Some(script) = script {return write_linked(program, script)}
+    if debug_prints(ELF_DEBUG_PRINTS) {eprintln!("- - - ELF (executable)")}
+    let text: Vec<Inst> = program.text.iter().chain(program.sections.iter().flat_map(|(_, text)| text)).cloned().collect();
+    let mut encoded = encode(&text)?;
+    let (rodata, rodata_labels) = rodata(program);
+    if let Some(name) = encoded.relocations.iter().find_map(|relocation| match &relocation.target {
+        RelocationTarget::Symbol(name) => Some(name),
+        _ => None,
+    }) {
+        return Err(format!("'{}' isn't defined anywhere, link the object file (--emit=obj) instead", name));
+    }
+
+    let mut sections = vec![
+        Section::new(".text", SHT_PROGBITS, SHF_ALLOC | SHF_EXECINSTR, vec![0; encoded.code.len()], 16),
+        Section::new(".rodata", SHT_PROGBITS, SHF_ALLOC, rodata, 8),
+    ];
+    const PROGRAM_HEADERS: usize = 2;
+    let offsets = layout(&sections, PROGRAM_HEADERS);
+    let text_address = EXECUTABLE_BASE + offsets[0] as u64;
+    let rodata_address = EXECUTABLE_BASE + offsets[1] as u64;
+    sections[0].address = text_address;
+    sections[1].address = rodata_address;
+
+    // Only rodata references are left, all of them PC relative
+    for relocation in &encoded.relocations {
+        if let RelocationTarget::Rodata(label) = &relocation.target {
+            let target = rodata_address + rodata_labels[label] as u64;
+            let place = text_address + relocation.offset as u64;
+            let value = target as i64 + relocation.addend - place as i64;
+            let value = i32::try_from(value).map_err(|_| "Relocation out of range".to_string())?;
+            encoded.code[relocation.offset..relocation.offset + 4].copy_from_slice(&value.to_le_bytes());
+        }
+    }
+
+    let entry = match encoded.labels.get(&program.entry) {
+        Some(offset) => text_address + *offset as u64,
+        None => return Err(format!("No '{}' symbol to start from", program.entry)),
+    };
+
+    // Symbols aren't needed to run, but they make objdump and gdb a lot nicer
+    let mut symbols = Symbols::new();
+    let functions = function_symbols(&encoded);
+    for (name, offset, size) in functions.iter().filter(|(name, ..)| !program.globals.contains(name)) {
+        symbols.add(name, STB_LOCAL, STT_FUNC, 1, text_address + *offset as u64, *size as u64);
+    }
+    let first_global = symbols.count;
+    for (name, offset, size) in functions.iter().filter(|(name, ..)| program.globals.contains(name)) {
+        symbols.add(name, STB_GLOBAL, STT_FUNC, 1, text_address + *offset as u64, *size as u64);
+    }
+
+    let end = offsets[1] + sections[1].data.len();
+    sections[0].data = encoded.code;
+    let mut symtab = Section::new(".symtab", SHT_SYMTAB, 0, symbols.table, 8);
+    symtab.link = 4;
+    symtab.info = first_global as u32;
+    symtab.entry_size = SYMBOL_SIZE as u64;
+    sections.push(symtab);
+    sections.push(Section::new(".strtab", SHT_STRTAB, 0, symbols.strings, 1));
+    sections.extend(debug_sections(program, &|name| encoded.labels.get(name).map(|offset| (0, text_address + *offset as u64)))?);
+
+    let program_headers = [
+        ProgramHeader { kind: PT_LOAD, flags: PF_R | PF_X, offset: 0, address: EXECUTABLE_BASE, size: end as u64, memory_size: end as u64, align: PAGE_SIZE as u64 },
+        ProgramHeader { kind: PT_GNU_STACK, flags: PF_R | PF_W, offset: 0, address: 0, size: 0, memory_size: 0, align: 16 },
+    ];
+
+    if debug_prints(ELF_DEBUG_PRINTS) {eprintln!("- - - ELF done!")}
+    Ok(write_elf(ET_EXEC, entry, &program_headers, sections))
+}
+
+/// Executable laid out by a linker script. Every output section becomes an ELF section at its
+/// address, sections sharing a page share a PT_LOAD (with the permissions of both), and
+/// NOLOAD ones are just zeroed memory.
+fn write_linked(program: &Program, script: &LinkerScript) -> Result<Vec<u8>, String> {
+    if debug_prints(ELF_DEBUG_PRINTS) {eprintln!("- - - ELF (linked executable)")}
+    let mut code = code_sections(program)?;
+    let (rodata, rodata_labels) = rodata(program);
+    let mut inputs: Vec<Input> = code.iter()
+        .map(|(name, encoded)| Input { name: name.clone(), size: encoded.code.len() as u64, align: 16 }).collect();
+    inputs.push(Input { name: ".rodata".to_string(), size: rodata.len() as u64, align: 8 });
+    let placement = linker_script::link(script, &inputs)?;
+    let rodata_address = placement.addresses[code.len()];
+
+    // Every symbol there is, the script's and the code's
+    let mut addresses = placement.symbols.clone();
+    for ((_, encoded), base) in code.iter().zip(&placement.addresses) {
+        for (label, offset) in encoded.labels.iter().filter(|(label, _)| !label.starts_with(".L")) {
+            addresses.insert(label.clone(), base + *offset as u64);
+        }
+    }
+    for ((_, encoded), base) in code.iter_mut().zip(&placement.addresses) {
+        for relocation in &encoded.relocations {
+            let target = match &relocation.target {
+                RelocationTarget::Rodata(label) => rodata_address + rodata_labels[label.as_str()] as u64,
+                RelocationTarget::Symbol(name) => match addresses.get(name) {
+                    Some(address) => *address,
+                    None => return Err(format!("'{}' isn't defined anywhere, link the object file (--emit=obj) instead", name)),
+                },
+            };
+            let place = base + relocation.offset as u64;
+            let value = i32::try_from(target as i64 + relocation.addend - place as i64)
+                .map_err(|_| format!("'{}' is too far away from the code using it ({:#x} to {:#x})", match &relocation.target {
+                    RelocationTarget::Rodata(label) | RelocationTarget::Symbol(label) => label,
+                }, place, target))?;
+            encoded.code[relocation.offset..relocation.offset + 4].copy_from_slice(&value.to_le_bytes());
+        }
+    }
+
+    // ELF section (1-based) every input ended up in, for the symbols
+    let mut input_sections = vec![0u16; inputs.len()];
+    let mut sections = vec![];
+    for placed in placement.sections.iter().filter(|placed| placed.size > 0) {
+        let mut section = if placed.noload {
+            if !placed.inputs.is_empty() {return Err(format!("NOLOAD section '{}' can't have code or strings in it", placed.name))}
+            let mut section = Section::new(&placed.name, SHT_NOBITS, SHF_ALLOC | SHF_WRITE, vec![], 16);
+            section.memory_size = placed.size;
+            section
+        } else {
+            let mut data = vec![0; placed.size as usize];
+            for &input in &placed.inputs {
+                let bytes = if input < code.len() {&code[input].1.code} else {&rodata};
+                let start = (placement.addresses[input] - placed.address) as usize;
+                data[start..start + bytes.len()].copy_from_slice(bytes);
+                input_sections[input] = sections.len() as u16 + 1;
+            }
+            let executable = placed.inputs.iter().any(|input| *input < code.len());
+            Section::new(&placed.name, SHT_PROGBITS, if executable {SHF_ALLOC | SHF_EXECINSTR} else {SHF_ALLOC}, data, 16)
+        };
+        section.address = placed.address;
+        sections.push(section);
+    }
+
+    // Neighbouring sections in the same page have to be one segment, or mapping the second
+    // one would take away the first one's permissions
+    let loaded = sections.len();
+    let program_headers_count = loaded + 1;
+    let offsets = layout(&sections, program_headers_count);
+    let mut program_headers: Vec<ProgramHeader> = vec![];
+    for (section, offset) in sections.iter().zip(&offsets) {
+        let flags = PF_R | if section.flags & SHF_EXECINSTR != 0 {PF_X} else {0} | if section.flags & SHF_WRITE != 0 {PF_W} else {0};
+        if let Some(last) = program_headers.last_mut()
+            && last.size == last.memory_size
+            && section.address / PAGE_SIZE as u64 <= (last.address + last.memory_size).saturating_sub(1) / PAGE_SIZE as u64 {
+            // NOLOAD ones only make the segment bigger in memory, the loader zeroes the rest
+            if section.kind == SHT_NOBITS {
+                last.memory_size = section.address + section.size() - last.address;
+                last.flags |= flags;
+                continue;
+            }
+            if section.address.checked_sub(last.address) == (*offset as u64).checked_sub(last.offset) {
+                last.size = section.address + section.size() - last.address;
+                last.memory_size = last.size;
+                last.flags |= flags;
+                continue;
+            }
+        }
+        let size = if section.kind == SHT_NOBITS {0} else {section.size()};
+        program_headers.push(ProgramHeader { kind: PT_LOAD, flags, offset: *offset as u64, address: section.address, size, memory_size: section.size(), align: PAGE_SIZE as u64 });
+    }
+    // Merged segments leave unused program header slots, those become PT_NULL
+    while program_headers.len() < loaded {
+        program_headers.push(ProgramHeader { kind: PT_NULL, flags: 0, offset: 0, address: 0, size: 0, memory_size: 0, align: 0 });
+    }
+    program_headers.push(ProgramHeader { kind: PT_GNU_STACK, flags: PF_R | PF_W, offset: 0, address: 0, size: 0, memory_size: 0, align: 16 });
+
+    let entry_symbol = script.entry.as_ref().unwrap_or(&program.entry);
+    let entry = match addresses.get(entry_symbol) {
+        Some(address) => *address,
+        None => return Err(format!("No '{}' symbol to start from", entry_symbol)),
+    };
+
+    let mut symbols = Symbols::new();
+    for global in [false, true] {
+        if global {symbols.info = symbols.count}
+        for (input, (_, encoded)) in code.iter().enumerate() {
+            for (name, offset, size) in function_symbols(encoded).iter().filter(|(name, ..)| program.globals.contains(name) == global) {
+                let binding = if global {STB_GLOBAL} else {STB_LOCAL};
+                symbols.add(name, binding, STT_FUNC, input_sections[input], placement.addresses[input] + *offset as u64, *size as u64);
+            }
+        }
+        if global {
+            let mut script_symbols: Vec<(&String, &u64)> = placement.symbols.iter().collect();
+            script_symbols.sort();
+            for (name, address) in script_symbols {
+                symbols.add(name, STB_GLOBAL, STT_NOTYPE, SHN_ABS, *address, 0);
+            }
+        }
+    }
+    let first_global = symbols.info;
+    let mut symtab = Section::new(".symtab", SHT_SYMTAB, 0, symbols.table, 8);
+    symtab.link = sections.len() as u32 + 2;
+    symtab.info = first_global as u32;
+    symtab.entry_size = SYMBOL_SIZE as u64;
+    sections.push(symtab);
+    sections.push(Section::new(".strtab", SHT_STRTAB, 0, symbols.strings, 1));
+    sections.extend(debug_sections(program, &|name| code.iter().zip(&placement.addresses).enumerate()
+        .find_map(|(input, ((_, encoded), base))| encoded.labels.get(name).map(|offset| (input, base + *offset as u64))))?);
+
+    if debug_prints(ELF_DEBUG_PRINTS) {eprintln!("- - - ELF done!")}
+    Ok(write_elf(ET_EXEC, entry, &program_headers, sections))
+}
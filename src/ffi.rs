@@ -0,0 +1,118 @@
+use crate::seman::Type;
+
+// C interop. `extern "C" function puts(s: str) -> i32;` declares a C function Galvan code can
+// call, `export function` makes a Galvan function callable from C under its plain name.
+// Galvan only has 64-bit values, so C's smaller integer types only matter at the border:
+// arguments get cut down to the C type and results get extended back to an i64, which is
+// what the System V ABI leaves to the compiler.
+
+//
+// STRUCTS
+//
+
+/// Types that can show up in an extern declaration
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+pub enum CType {
+    Int {bits: u8, signed: bool},
+    /// `*T`, an address on the Galvan side (a str works too)
+    Pointer(Box<CType>),
+    /// `const char*`, a Galvan string
+    Str,
+    Void,
+}
+
+/// `extern "C" function symbol(parameters) -> return_type;`
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+pub struct Extern {
+    /// What Galvan code calls it, mangled like any other function
+    pub name: String,
+    /// The C symbol, the name as it was written
+    pub symbol: String,
+    pub parameters: Vec<(String, CType)>,
+    /// Ends in `...`, like printf
+    pub variadic: bool,
+    pub return_type: CType,
+}
+
+//
+// FUNCTIONS
+//
+
+impl CType {
+    /// Everything but pointers, those are a `*` in front of one of these
+    pub fn from_name(name: &str) -> Option<CType> {
+        let int = |bits, signed| Some(CType::Int { bits, signed });
+        match name {
+            "i8" => int(8, true),
+            "i16" => int(16, true),
+            "i32" => int(32, true),
+            "i64" => int(64, true),
+            "u8" => int(8, false),
+            "u16" => int(16, false),
+            "u32" => int(32, false),
+            "u64" => int(64, false),
+            "str" => Some(CType::Str),
+            "void" => Some(CType::Void),
+            _ => None,
+        }
+    }
+
+    /// The type a value of this has in Galvan
+    pub fn galvan(&self) -> Type {
+        match self {
+            CType::Int { .. } | CType::Pointer(_) => Type::Int,
+            CType::Str => Type::Str,
+            CType::Void => Type::Void,
+        }
+    }
+
+    /// Whether a Galvan value of type `ty` can be passed as this
+    pub fn accepts(&self, ty: Type) -> bool {
+        match self {
+            CType::Pointer(_) => ty == Type::Int || ty == Type::Str,
+            other => other.galvan() == ty && ty != Type::Void,
+        }
+    }
+
+    /// How C spells it
+    pub fn c_name(&self) -> String {
+        match self {
+            CType::Int { bits, signed: true } => format!("int{}_t", bits),
+            CType::Int { bits, signed: false } => format!("uint{}_t", bits),
+            CType::Pointer(inner) => format!("{}*", inner.c_name()),
+            CType::Str => "const char*".to_string(),
+            CType::Void => "void".to_string(),
+        }
+    }
+
+    /// Parses `u8`, `*u8`, `**void`, ...
+    pub fn parse(text: &str) -> Option<CType> {
+        match text.strip_prefix('*') {
+            Some(inner) => Some(CType::Pointer(Box::new(CType::parse(inner)?))),
+            None => CType::from_name(text),
+        }
+    }
+}
+impl std::fmt::Display for CType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CType::Int { bits, signed } => write!(f, "{}{}", if *signed {"i"} else {"u"}, bits),
+            CType::Pointer(inner) => write!(f, "*{}", inner),
+            CType::Str => write!(f, "str"),
+            CType::Void => write!(f, "void"),
+        }
+    }
+}
+
+impl Extern {
+    /// `puts(s: str, ...) -> i32`, the IR dumps it like this
+    pub fn signature(&self) -> String {
+        let mut parameters: Vec<String> = self.parameters.iter().map(|(name, ty)| format!("{}: {}", name, ty)).collect();
+        if self.variadic {parameters.push("...".to_string())}
+        format!("{}({}) -> {}", self.symbol, parameters.join(", "), self.return_type)
+    }
+}
@@ -0,0 +1,430 @@
+use std::path::PathBuf;
+
+use crate::compiler_settings::{set_quiet, FMT_INDENT, FMT_WIDTH};
+use crate::cst::{self, Element, Trivia};
+use crate::lexer::{lexer_in_file, LexSymbol};
+use crate::parser::{parser, Expression, Statement};
+use crate::source_map::{add_file, position};
+
+// `galvan fmt`, pretty-prints the concrete syntax tree (cst.rs):
+// - every block level indents by `indent` spaces, `{` stays on the line it opens, `}` gets
+//   its own (`} else {` shares one)
+// - spaces around operators and `=`, after commas and `:`, none inside parentheses
+// - comments stay where they were, runs of blank lines become one
+// - a line longer than `width` gets the arguments of its first call (or parameters) one
+//   per line
+// Formatting twice changes nothing, and the formatted file has to parse into the same
+// statements as the original, or the file is left alone.
+
+//
+// STRUCTS
+//
+
+/// [fmt] in galvan.toml
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+pub struct Style {
+    /// Longest line before arguments get broken up
+    pub width: usize,
+    /// Spaces per block level
+    pub indent: usize,
+}
+impl Default for Style {
+    fn default() -> Style {
+        Style { width: FMT_WIDTH, indent: FMT_INDENT }
+    }
+}
+
+struct Printer<'a> {
+    style: &'a Style,
+    out: String,
+    /// The line being written, without its indentation
+    line: String,
+    /// Block level of `line`
+    level: usize,
+    /// The last two lexemes printed, symbol and text, for spacing
+    recent: Vec<(LexSymbol, String)>,
+    /// In an `asm` statement, where `in (`, `out (` and `clobber (` get a space
+    asm: bool,
+}
+
+//
+// FUNCTIONS
+//
+
+/// What a lexeme looks like formatted. Operators by their value, the lexer reads `=>` as `>=`.
+fn text(token: &cst::Token) -> &str {
+    match token.lexeme.symbol {
+        LexSymbol::OperationalSymbol => &token.lexeme.value,
+        _ => &token.text,
+    }
+}
+
+fn has_comments(token: &cst::Token) -> bool {
+    token.leading.iter().any(|trivia| matches!(trivia, Trivia::Comment(_)))
+}
+
+/// Whether there's a space between the last lexemes printed and `next`
+fn space(recent: &[(LexSymbol, String)], next: &cst::Token, asm: bool) -> bool {
+    use LexSymbol::*;
+    let Some((previous, previous_text)) = recent.last() else {return false};
+    let before = recent.len().checked_sub(2).map(|index| recent[index].0);
+    match (*previous, next.lexeme.symbol) {
+        (_, EndLine | Comma | GenericClosingBracket) => false,
+        (GenericOpeningBracket, _) => false,
+        (Comma, _) => true,
+        // `x: str`, but `a::b`
+        (_, DoubleDot) => false,
+        (DoubleDot, _) => before != Some(DoubleDot) && next.lexeme.symbol != DoubleDot,
+        // `...` of a variadic extern
+        (Dot, _) | (_, Dot) => false,
+        // `->` of an extern
+        (OperationalSymbol, OperationalSymbol) if previous_text == "-" && next.lexeme.value == ">" => false,
+        // Calls and parameter lists, but `in (a = x)`
+        (Identifier, GenericOpeningBracket) => asm && ["in", "out", "clobber"].contains(&previous_text.as_str()),
+        _ => true,
+    }
+}
+
+impl Printer<'_> {
+    fn flush(&mut self) {
+        if self.line.is_empty() {return}
+        self.out.push_str(&" ".repeat(self.style.indent * self.level));
+        self.out.push_str(&self.line);
+        self.out.push('\n');
+        self.line.clear();
+    }
+
+    /// One blank line, unless it's the start of the file or a block
+    fn blank(&mut self) {
+        self.flush();
+        if !self.out.is_empty() && !self.out.ends_with("{\n") && !self.out.ends_with("\n\n") {self.out.push('\n')}
+    }
+
+    /// Comments and blank lines in front of something that starts a line at `level`. A
+    /// comment on the same line as the line still open goes at its end. No blank line
+    /// before a `}`.
+    fn comments(&mut self, trivia: &[Trivia], level: usize, closing: bool) {
+        let mut newlines = 0;
+        for trivia in trivia {
+            match trivia {
+                Trivia::Whitespace(text) => newlines += text.matches('\n').count(),
+                Trivia::Comment(text) => {
+                    if newlines == 0 && !self.line.is_empty() {
+                        self.line.push(' ');
+                    } else {
+                        if newlines > 1 {self.blank()} else {self.flush()}
+                        self.level = level;
+                    }
+                    self.line.push_str(text.trim_end());
+                    self.flush();
+                    newlines = 0;
+                }
+                Trivia::Skipped(_) => {}
+            }
+        }
+        if newlines > 1 && !closing {self.blank()} else {self.flush()}
+    }
+
+    /// Prints a lexeme on the open line. Comments in front of it end the line, what follows
+    /// goes one level deeper (except after a `}`, `else` lines up with it). `first` is a
+    /// statement's first lexeme, its comments are done.
+    fn token(&mut self, token: &cst::Token, level: usize, first: bool) {
+        let continuation = match self.recent.last() {
+            Some((LexSymbol::FunctionClosingBracket, _)) => level,
+            _ => level + 1,
+        };
+        let mut continued = false;
+        if !first && has_comments(token) {
+            let mut newlines = 0;
+            for trivia in &token.leading {
+                match trivia {
+                    Trivia::Whitespace(text) => newlines += text.matches('\n').count(),
+                    Trivia::Comment(text) => {
+                        if newlines == 0 && !self.line.is_empty() {
+                            self.line.push(' ');
+                        } else {
+                            self.flush();
+                            self.level = continuation;
+                        }
+                        self.line.push_str(text.trim_end());
+                        self.flush();
+                        newlines = 0;
+                    }
+                    Trivia::Skipped(_) => {}
+                }
+            }
+            continued = true;
+        }
+        // Outer attributes get lines of their own
+        if self.recent.last().is_some_and(|(symbol, _)| *symbol == LexSymbol::Attribute) {self.flush()}
+
+        if self.line.is_empty() {
+            self.level = if continued {continuation} else {level};
+        } else if space(&self.recent, token, self.asm) {
+            self.line.push(' ');
+        }
+        self.line.push_str(text(token));
+        self.remember(token.lexeme.symbol, text(token));
+    }
+
+    fn remember(&mut self, symbol: LexSymbol, text: &str) {
+        if self.recent.len() == 2 {self.recent.remove(0);}
+        self.recent.push((symbol, text.to_string()));
+    }
+
+    /// How long `tokens` are printed flat after what's on the line
+    fn measure(&self, tokens: &[&cst::Token]) -> usize {
+        let mut recent = self.recent.clone();
+        let mut empty = self.line.is_empty();
+        let mut width = 0;
+        for token in tokens {
+            if !empty && space(&recent, token, self.asm) {width += 1}
+            width += text(token).chars().count();
+            if recent.len() == 2 {recent.remove(0);}
+            recent.push((token.lexeme.symbol, text(token).to_string()));
+            empty = false;
+        }
+        width
+    }
+
+    /// Prints lexemes without comments or attributes, breaking up parentheses when the line
+    /// gets too long. `tail` is what still comes on the line after them.
+    fn wrap(&mut self, tokens: &[&cst::Token], level: usize, tail: usize) {
+        let indent = self.style.indent * if self.line.is_empty() {level} else {self.level};
+        if indent + self.line.chars().count() + self.measure(tokens) + tail <= self.style.width {
+            for token in tokens {self.token(token, level, true)}
+            return;
+        }
+
+        // Outermost parentheses, the first ones with a comma in them or else the first non-empty ones
+        let mut groups = vec![];
+        let mut depth = 0;
+        let mut open = 0;
+        let mut commas = vec![];
+        for (index, token) in tokens.iter().enumerate() {
+            match token.lexeme.symbol {
+                LexSymbol::GenericOpeningBracket => {
+                    if depth == 0 {open = index; commas.clear()}
+                    depth += 1;
+                }
+                LexSymbol::GenericClosingBracket if depth > 0 => {
+                    depth -= 1;
+                    if depth == 0 && index > open + 1 {groups.push((open, index, commas.clone()))}
+                }
+                LexSymbol::Comma if depth == 1 => commas.push(index),
+                _ => {}
+            }
+        }
+        let group = groups.iter().find(|(_, _, commas)| !commas.is_empty()).or(groups.first());
+        let Some((open, close, commas)) = group else {
+            for token in tokens {self.token(token, level, true)}
+            return;
+        };
+
+        for token in &tokens[..=*open] {self.token(token, level, true)}
+        self.flush();
+        let mut start = open + 1;
+        for &end in commas.iter().chain([close]) {
+            let last = end == *close;
+            self.wrap(&tokens[start..end], level + 1, if last {0} else {1});
+            if !last {self.token(tokens[end], level + 1, true)}
+            self.flush();
+            start = end + 1;
+        }
+        self.wrap(&tokens[*close..], level, tail);
+    }
+
+    /// Lexemes between blocks. `first` when they start the statement.
+    fn run(&mut self, tokens: &[&cst::Token], level: usize, first: bool, tail: usize) {
+        let plain = tokens.iter().enumerate().all(|(index, token)| (index == 0 && first) || !has_comments(token))
+            && tokens.iter().all(|token| token.lexeme.symbol != LexSymbol::Attribute);
+        if plain {
+            self.wrap(tokens, level, tail);
+        } else {
+            for (index, token) in tokens.iter().enumerate() {self.token(token, level, index == 0 && first)}
+        }
+    }
+
+    fn statement(&mut self, statement: &cst::Statement, level: usize) {
+        let Some(Element::Token(start)) = statement.elements.first() else {return};
+        self.comments(&start.leading, level, false);
+        // Stray `;`s go, their comments stay
+        if statement.elements.len() == 1 && start.lexeme.symbol == LexSymbol::EndLine {return}
+
+        self.recent.clear();
+        self.asm = start.lexeme.symbol == LexSymbol::Keyword && start.lexeme.value == "asm";
+        let mut run = vec![];
+        let mut first = true;
+        for element in &statement.elements {
+            match element {
+                Element::Token(token) => run.push(token),
+                Element::Block(block) => {
+                    // Room for the ` {`
+                    self.run(&run, level, first, 2);
+                    run.clear();
+                    first = false;
+                    self.block(block, level);
+                }
+            }
+        }
+        self.run(&run, level, first, 0);
+    }
+
+    fn block(&mut self, block: &cst::Block, level: usize) {
+        self.token(&block.open, level, false);
+        let asm = self.asm;
+        let empty = block.statements.is_empty() && !block.close.as_ref().is_some_and(has_comments);
+        if !empty {
+            for statement in &block.statements {self.statement(statement, level + 1)}
+        }
+        self.asm = asm;
+        let Some(close) = &block.close else {return};
+        if !empty {
+            self.comments(&close.leading, level + 1, true);
+            self.level = level;
+        }
+        self.line.push('}');
+        self.recent.clear();
+        self.remember(LexSymbol::FunctionClosingBracket, "}");
+    }
+}
+
+/// Zeroes every location, formatting moves things around
+fn strip(statements: &mut [Statement]) {
+    for statement in statements {
+        match statement {
+            Statement::ExpressionStatement(_, location)
+            | Statement::VariableAssignment { location, .. }
+            | Statement::FunctionAssignment { location, .. }
+            | Statement::While { location, .. }
+            | Statement::ConditionalStatement { location, .. }
+            | Statement::Import { location, .. }
+            | Statement::ConstAssignment { location, .. }
+            | Statement::Asm { location, .. }
+            | Statement::Extern { location, .. }
+            | Statement::Attribute { location, .. } => *location = (0, 0, 0),
+        }
+        match statement {
+            Statement::VariableAssignment { name_location, .. }
+            | Statement::FunctionAssignment { name_location, .. }
+            | Statement::Extern { name_location, .. } => *name_location = (0, 0, 0),
+            Statement::ConstAssignment { name_location, value_location, .. } => {
+                *name_location = (0, 0, 0);
+                *value_location = (0, 0, 0);
+            }
+            _ => {}
+        }
+        match statement {
+            Statement::ExpressionStatement(value, _)
+            | Statement::VariableAssignment { value, .. }
+            | Statement::ConstAssignment { value, .. } => strip_expression(value),
+            Statement::FunctionAssignment { arguments, body, .. } => {
+                arguments.iter_mut().for_each(strip_expression);
+                strip(body);
+            }
+            Statement::While { condition, body, .. } => {
+                strip_expression(condition);
+                strip(body);
+            }
+            Statement::ConditionalStatement { condition, body, else_body, .. } => {
+                strip_expression(condition);
+                strip(body);
+                if let Some(else_body) = else_body {strip(else_body)}
+            }
+            Statement::Asm { asm, .. } => asm.inputs.iter_mut().for_each(|(_, value)| strip_expression(value)),
+            _ => {}
+        }
+    }
+}
+
+/// `strip()` for the names inside an expression
+fn strip_expression(expression: &mut Expression) {
+    match expression {
+        Expression::Variable(_, location) => *location = (0, 0, 0),
+        Expression::FunctionCall { args, location, .. } => {
+            *location = (0, 0, 0);
+            args.iter_mut().for_each(strip_expression);
+        }
+        Expression::Operation(operation) => {
+            strip_expression(&mut operation.left);
+            strip_expression(&mut operation.right);
+        }
+        Expression::ReturnValue { value } => strip_expression(value),
+        Expression::Number(_) | Expression::String(_) => {}
+    }
+}
+
+/// The statements of a source file without their locations, to compare before and after
+fn syntax(source: &str, path: &str) -> Result<String, String> {
+    let tokens = lexer_in_file(source, add_file(path));
+    let mut statements = parser(tokens.iter().peekable())?;
+    strip(&mut statements);
+    Ok(format!("{:?}", statements))
+}
+
+/// Formats a source file. It has to parse, and can't have anything the lexer skips (that
+/// would get lost).
+pub fn format(source: &str, path: &str, style: &Style) -> Result<String, String> {
+    let before = syntax(source, path)?;
+    let tree = cst::parse(source);
+    if tree.to_string() != source {return Err(format!("Can't format '{}', the syntax tree doesn't match the file", path))}
+
+    let mut tokens = vec![];
+    for statement in &tree.statements {collect(statement, &mut tokens)}
+    for token in tokens {
+        if let Some(Trivia::Skipped(text)) = token.leading.iter().find(|trivia| matches!(trivia, Trivia::Skipped(_))) {
+            return Err(format!("Unexpected '{}' before position {}", text, position(token.lexeme.location)));
+        }
+    }
+    if let Some(Trivia::Skipped(text)) = tree.trailing.iter().find(|trivia| matches!(trivia, Trivia::Skipped(_))) {
+        return Err(format!("Unexpected '{}' at the end of {}", text, path));
+    }
+
+    let mut printer = Printer { style, out: String::new(), line: String::new(), level: 0, recent: vec![], asm: false };
+    for statement in &tree.statements {printer.statement(statement, 0)}
+    printer.comments(&tree.trailing, 0, true);
+    printer.flush();
+
+    if syntax(&printer.out, path)? != before {
+        return Err(format!("Formatting '{}' would change what it means, that's a bug in the formatter", path));
+    }
+    Ok(printer.out)
+}
+
+/// Every lexeme of a statement, blocks included
+fn collect<'a>(statement: &'a cst::Statement, out: &mut Vec<&'a cst::Token>) {
+    for element in &statement.elements {
+        match element {
+            Element::Token(token) => out.push(token),
+            Element::Block(block) => {
+                out.push(&block.open);
+                for statement in &block.statements {collect(statement, out)}
+                out.extend(&block.close);
+            }
+        }
+    }
+}
+
+/// `galvan fmt`, rewrites the files that aren't formatted. With `check` it only lists them,
+/// and fails if there are any.
+pub fn fmt(files: &[PathBuf], style: &Style, check: bool) -> Result<(), String> {
+    set_quiet(true);
+    let mut unformatted = 0;
+    for file in files {
+        let source = std::fs::read_to_string(file).map_err(|error| format!("Can't read '{}': {}", file.display(), error))?;
+        let formatted = format(&source, &file.display().to_string(), style)?;
+        if formatted == source {continue}
+        if check {
+            let line = source.lines().zip(formatted.lines()).take_while(|(a, b)| a == b).count() + 1;
+            println!("{} isn't formatted (from line {} on)", file.display(), line);
+            unformatted += 1;
+        } else {
+            std::fs::write(file, formatted).map_err(|error| format!("Can't write '{}': {}", file.display(), error))?;
+        }
+    }
+    if unformatted > 0 {
+        return Err(format!("{} file{} need{} formatting, run galvan fmt", unformatted, if unformatted == 1 {""} else {"s"}, if unformatted == 1 {"s"} else {""}));
+    }
+    Ok(())
+}
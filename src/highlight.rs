@@ -0,0 +1,283 @@
+use std::fmt::Write;
+
+use crate::compiler_settings::*;
+use crate::cst::{self, Trivia};
+use crate::json::Json;
+use crate::lexer::{LexSymbol, Lexeme};
+
+// Syntax highlighting. `galvan highlight` runs a file through the real lexer (by way of the
+// concrete syntax tree in cst.rs, which keeps the comments the lexer drops) and prints it in
+// color, or as HTML for documentation pages. `galvan grammar` writes a TextMate grammar for
+// editors, put together from the keyword, operator and type tables the lexer and parser use,
+// so a new keyword shows up in both without anyone writing a regex by hand.
+
+//
+// STRUCTS
+//
+
+/// What a piece of source gets colored as
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+#[derive(PartialEq)]
+pub enum Class {
+    Plain,
+    Keyword,
+    /// Defined or called, anything right in front of a `(`
+    Function,
+    /// `i64` or `str` after a `:` or `->`
+    Type,
+    String,
+    Number,
+    Operator,
+    Attribute,
+    Comment,
+    /// Brackets, `;`, `,`, `.` and `:`
+    Punctuation,
+    /// Characters the lexer skips
+    Invalid,
+}
+
+//
+// FUNCTIONS
+//
+
+impl Class {
+    /// The HTML class is `gv-<name>`
+    fn name(self) -> &'static str {
+        match self {
+            Class::Plain => "plain",
+            Class::Keyword => "keyword",
+            Class::Function => "function",
+            Class::Type => "type",
+            Class::String => "string",
+            Class::Number => "number",
+            Class::Operator => "operator",
+            Class::Attribute => "attribute",
+            Class::Comment => "comment",
+            Class::Punctuation => "punctuation",
+            Class::Invalid => "invalid",
+        }
+    }
+
+    /// TextMate scope, `keyword.control.galvan`
+    fn scope(self) -> String {
+        scoped(match self {
+            Class::Plain => "source",
+            Class::Keyword => "keyword.control",
+            Class::Function => "entity.name.function",
+            Class::Type => "storage.type",
+            Class::String => "string.quoted.double",
+            Class::Number => "constant.numeric",
+            Class::Operator => "keyword.operator",
+            Class::Attribute => "meta.attribute",
+            Class::Comment => "comment.line.double-slash",
+            Class::Punctuation => "punctuation.separator",
+            Class::Invalid => "invalid.illegal",
+        })
+    }
+
+    /// SGR parameters for terminals, None stays uncolored
+    fn ansi(self) -> Option<&'static str> {
+        match self {
+            Class::Keyword => Some("35"),
+            Class::Function => Some("34"),
+            Class::Type => Some("36"),
+            Class::String => Some("32"),
+            Class::Number => Some("33"),
+            Class::Attribute => Some("33"),
+            Class::Comment => Some("90"),
+            Class::Invalid => Some("31"),
+            Class::Plain | Class::Operator | Class::Punctuation => None,
+        }
+    }
+}
+
+/// Adds text to the end, next to the last piece if that's the same class
+fn push(out: &mut Vec<(Class, String)>, class: Class, text: &str) {
+    match out.last_mut() {
+        Some((last, previous)) if *last == class => previous.push_str(text),
+        _ => out.push((class, text.to_string())),
+    }
+}
+
+fn push_trivia(out: &mut Vec<(Class, String)>, trivia: &[Trivia]) {
+    for trivia in trivia {
+        match trivia {
+            Trivia::Whitespace(text) => push(out, Class::Plain, text),
+            Trivia::Comment(text) => push(out, Class::Comment, text),
+            Trivia::Skipped(text) => push(out, Class::Invalid, text),
+        }
+    }
+}
+
+fn is(lexeme: Option<&Lexeme>, symbol: LexSymbol, value: &str) -> bool {
+    lexeme.is_some_and(|lexeme| lexeme.symbol == symbol && lexeme.value == value)
+}
+
+/// The source cut into colored pieces, which put back together are the source again
+pub fn classify(source: &str) -> Vec<(Class, String)> {
+    let tree = cst::parse(source);
+    let tokens = tree.tokens();
+
+    let mut out = vec![];
+    for (index, token) in tokens.iter().enumerate() {
+        push_trivia(&mut out, &token.leading);
+        let before = |back: usize| index.checked_sub(back).map(|index| &tokens[index].lexeme);
+        let next = tokens.get(index + 1).map(|token| &token.lexeme);
+        let lexeme = &token.lexeme;
+        let class = match lexeme.symbol {
+            LexSymbol::Keyword => Class::Keyword,
+            // `x: str` and `-> i64`, but not `module::str`
+            LexSymbol::Identifier if TYPES.contains(&lexeme.value.as_str())
+                && ((is(before(1), LexSymbol::DoubleDot, ":") && !is(before(2), LexSymbol::DoubleDot, ":"))
+                    || (is(before(1), LexSymbol::OperationalSymbol, ">") && is(before(2), LexSymbol::OperationalSymbol, "-"))) => Class::Type,
+            LexSymbol::Identifier if is(next, LexSymbol::GenericOpeningBracket, "(") => Class::Function,
+            LexSymbol::Identifier => Class::Plain,
+            LexSymbol::String => Class::String,
+            LexSymbol::Integer => Class::Number,
+            LexSymbol::OperationalSymbol | LexSymbol::EqualSign => Class::Operator,
+            LexSymbol::Attribute => Class::Attribute,
+            _ => Class::Punctuation,
+        };
+        push(&mut out, class, &token.text);
+    }
+    push_trivia(&mut out, &tree.trailing);
+    out
+}
+
+/// `<`, `>`, `&` and `"` as entities
+pub fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+/// The source as a `<pre class="galvan">`, every piece in a `<span class="gv-...">`. Needs
+/// STYLE (or CSS of its own) somewhere on the page.
+pub fn html(source: &str) -> String {
+    let mut out = String::from("<pre class=\"galvan\"><code>");
+    for (class, text) in classify(source) {
+        match class {
+            Class::Plain => out.push_str(&escape_html(&text)),
+            _ => {let _ = write!(out, "<span class=\"gv-{}\">{}</span>", class.name(), escape_html(&text));}
+        }
+    }
+    out.push_str("</code></pre>\n");
+    out
+}
+
+/// Colors for what `html()` writes
+pub const STYLE: &str = "\
+pre.galvan { background: #f6f8fa; padding: 0.8em 1em; border-radius: 4px; overflow-x: auto; }
+.gv-keyword { color: #a626a4; font-weight: bold; }
+.gv-function { color: #4078f2; }
+.gv-type { color: #0184bc; }
+.gv-string { color: #50a14f; }
+.gv-number { color: #986801; }
+.gv-operator { color: #383a42; }
+.gv-attribute { color: #c18401; }
+.gv-comment { color: #a0a1a7; font-style: italic; }
+.gv-punctuation { color: #383a42; }
+.gv-invalid { color: #e45649; text-decoration: underline wavy; }
+";
+
+/// A whole HTML page with the highlighted source on it
+pub fn html_page(source: &str, title: &str) -> String {
+    format!("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n<style>\n{}</style>\n</head>\n<body>\n{}</body>\n</html>\n",
+        escape_html(title), STYLE, html(source))
+}
+
+/// The source with ANSI colors, for terminals
+pub fn ansi(source: &str) -> String {
+    let mut out = String::new();
+    for (class, text) in classify(source) {
+        match class.ansi() {
+            Some(color) => {let _ = write!(out, "\x1b[{}m{}\x1b[0m", color, text);}
+            None => out.push_str(&text),
+        }
+    }
+    out
+}
+
+/// Backslashes in front of everything a regex would read as more than itself
+fn regex(text: &str) -> String {
+    let mut out = String::new();
+    for c in text.chars() {
+        if "\\^$.|?*+()[]{}/-".contains(c) {out.push('\\')}
+        out.push(c);
+    }
+    out
+}
+
+/// A TextMate scope name, the language goes at the end
+fn scoped(name: &str) -> String {
+    format!("{}.{}", name, GRAMMAR_SCOPE.rsplit('.').next().unwrap_or_default())
+}
+
+/// A TextMate pattern that colors all of `regex` as `class`
+fn pattern(class: Class, regex: &str) -> Json {
+    Json::object(vec![("name", Json::String(class.scope())), ("match", Json::string(regex))])
+}
+
+/// The TextMate grammar (VS Code, Sublime, and everything else that reads .tmLanguage.json)
+pub fn textmate() -> Json {
+    let scope = |class: Class| Json::object(vec![("name", Json::String(class.scope()))]);
+
+    // Longest first, so `==` doesn't get colored as two `=`
+    let mut operators: Vec<&str> = OPERATORS.iter().map(|(spelling, _)| *spelling).collect();
+    operators.sort_by_key(|spelling| std::cmp::Reverse(spelling.len()));
+    let operators: Vec<String> = operators.iter().map(|spelling| regex(spelling)).collect();
+    let escapes: String = ESCAPES.iter().map(|(written, _)| regex(&written.to_string())).collect();
+    let punctuation: String = OPEN_BRACES.iter().chain(CLOSED_BRACES.iter()).chain([LINE_SPLITTER, ',', '.', ':'].iter())
+        .map(|c| regex(&c.to_string())).collect();
+
+    let patterns = vec![
+        pattern(Class::Comment, "//.*$"),
+        pattern(Class::Attribute, "#!?\\[(?:\"[^\"]*\"|[^\\]\"])*\\]"),
+        Json::object(vec![
+            ("name", Json::String(Class::String.scope())),
+            ("begin", Json::string("\"")),
+            ("end", Json::string("\"")),
+            ("patterns", Json::Array(vec![Json::object(vec![
+                ("name", Json::String(scoped("constant.character.escape"))),
+                ("match", Json::String(format!("\\\\[{}]", escapes))),
+            ])])),
+        ]),
+        pattern(Class::Number, "\\b[0-9][0-9.]*"),
+        Json::object(vec![
+            ("match", Json::String(format!("(?<=[^:]:|->)\\s*\\b({})\\b", TYPES.join("|")))),
+            ("captures", Json::object(vec![("1", scope(Class::Type))])),
+        ]),
+        pattern(Class::Keyword, &format!("\\b(?:{})\\b", KEYWORDS.join("|"))),
+        pattern(Class::Function, "\\b[A-Za-z_][A-Za-z0-9_]*(?=\\s*\\()"),
+        pattern(Class::Operator, &operators.join("|")),
+        pattern(Class::Punctuation, &format!("[{}]", punctuation)),
+    ];
+    Json::object(vec![
+        ("name", Json::string("Galvan")),
+        ("scopeName", Json::string(GRAMMAR_SCOPE)),
+        ("fileTypes", Json::Array(vec![Json::string(MODULE_EXTENSION)])),
+        ("patterns", Json::Array(patterns)),
+    ])
+}
+
+/// Writes to `output`, or prints when there's none
+fn write_out(text: &str, output: Option<&str>) -> Result<(), String> {
+    match output {
+        Some(output) => std::fs::write(output, text).map_err(|error| format!("Can't write '{}': {}", output, error)),
+        None => {print!("{}", text); Ok(())}
+    }
+}
+
+/// `galvan highlight`, the file in color, or as an HTML page with --html
+pub fn highlight(path: &str, html: bool, output: Option<&str>) -> Result<(), String> {
+    set_quiet(true);
+    let source = std::fs::read_to_string(path).map_err(|error| format!("Can't read '{}': {}", path, error))?;
+    match html {
+        true => write_out(&html_page(&source, path), output),
+        false => write_out(&ansi(&source), output),
+    }
+}
+
+/// `galvan grammar`
+pub fn grammar(output: Option<&str>) -> Result<(), String> {
+    write_out(&(textmate().pretty() + "\n"), output)
+}
@@ -0,0 +1,319 @@
+use std::collections::HashMap;
+
+use crate::compiler_settings::*;
+use crate::intrinsics::{self, Runtime};
+use crate::lexer::Location;
+use crate::parser::{Expression, Operator, Statement};
+use crate::seman::{Analysis, Scope, Type};
+use crate::source_map::position;
+
+// Tree-walking interpreter, runs the `Vec<Statement>` straight from the parser.
+// Slow, but it's the simplest way to actually run something and check the output.
+
+//
+// STRUCTS
+//
+
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+pub enum Value {
+    Int(i64),
+    Str(String),
+    Void,
+}
+impl std::fmt::Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Value::Int(value) => write!(f, "{}", value),
+            Value::Str(value) => write!(f, "{}", value),
+            Value::Void => write!(f, "void"),
+        }
+    }
+}
+
+/// Runtime error, with the position of the statement that caused it
+#[derive(Debug)]
+pub struct RuntimeError {
+    pub message: String,
+    pub location: Location,
+    /// Function names from the outermost call inwards
+    pub backtrace: Vec<String>,
+}
+impl std::fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} at position {}", self.message, position(self.location))?;
+        // Innermost calls first, deep recursion would flood the terminal otherwise
+        for function in self.backtrace.iter().rev().take(BACKTRACE_LIMIT) {
+            write!(f, "\n    in {}", function)?;
+        }
+        if self.backtrace.len() > BACKTRACE_LIMIT {
+            write!(f, "\n    ... {} more", self.backtrace.len() - BACKTRACE_LIMIT)?;
+        }
+        Ok(())
+    }
+}
+
+/// What running a statement did to the control flow
+enum Flow {
+    Normal,
+    Return(Value),
+}
+
+/// One function call's worth of state
+struct Frame {
+    function: String,
+    variables: HashMap<String, Value>,
+}
+
+pub struct Interpreter {
+    functions: HashMap<String, (Vec<String>, Vec<Statement>)>,
+    /// From semantic analysis, only needed for what falling off the end returns
+    return_types: HashMap<String, Type>,
+    stack: Vec<Frame>,
+    /// Memory and files for the intrinsics
+    runtime: Runtime,
+    /// `extern "C"` functions, name -> C symbol. Only there to give a good error when called.
+    externs: HashMap<String, String>,
+}
+
+//
+// FUNCTIONS
+//
+
+const ASM_UNSUPPORTED: &str = "Inline assembly can't run in the interpreter, compile the program to assembly or C instead";
+
+/// Where the first `asm` block is, function bodies included
+fn find_asm(statements: &[Statement]) -> Option<Location> {
+    statements.iter().find_map(|statement| match statement {
+        Statement::Asm { location, .. } => Some(*location),
+        Statement::FunctionAssignment { body, .. } | Statement::While { body, .. } => find_asm(body),
+        Statement::ConditionalStatement { body, else_body, .. } => find_asm(body).or_else(|| else_body.as_deref().and_then(find_asm)),
+        _ => None,
+    })
+}
+
+impl Default for Interpreter {
+    fn default() -> Interpreter {
+        Interpreter::new()
+    }
+}
+impl Interpreter {
+    pub fn new() -> Interpreter {
+        Interpreter {
+            functions: HashMap::new(),
+            return_types: HashMap::new(),
+            stack: vec![Frame { function: ENTRY_FUNCTION.to_string(), variables: HashMap::new() }],
+            runtime: Runtime::default(),
+            externs: HashMap::new(),
+        }
+    }
+
+    fn error(&self, message: String, location: Location) -> RuntimeError {
+        RuntimeError { message, location, backtrace: self.stack.iter().map(|frame| frame.function.clone()).collect() }
+    }
+
+    fn frame(&mut self) -> &mut Frame {
+        self.stack.last_mut().unwrap()
+    }
+
+    pub fn evaluate(&mut self, expression: &Expression, location: Location) -> Result<Value, RuntimeError> {
+        match expression {
+            Expression::Number(number) => Ok(Value::Int(*number)),
+            Expression::String(string) => Ok(Value::Str(string.clone())),
+            Expression::Variable(name, _) => match self.frame().variables.get(name) {
+                Some(value) => Ok(value.clone()),
+                None => Err(self.error(format!("Unknown variable '{}'", name), location)),
+            },
+            Expression::Operation(operation) => {
+                let left = self.evaluate(&operation.left, location)?;
+                let right = self.evaluate(&operation.right, location)?;
+                let (Value::Int(left), Value::Int(right)) = (&left, &right) else {
+                    return Err(self.error(format!("Operator {:?} only works on i64, not {:?} and {:?}", operation.operator, left, right), location));
+                };
+                let (left, right) = (*left, *right);
+                Ok(Value::Int(match operation.operator {
+                    // Wrapping, same as the native backends
+                    Operator::Addition => left.wrapping_add(right),
+                    Operator::Subtraction => left.wrapping_sub(right),
+                    Operator::Multiplication => left.wrapping_mul(right),
+                    Operator::Division => {
+                        if right == 0 {return Err(self.error("Division by zero".to_string(), location))}
+                        left.wrapping_div(right)
+                    }
+                    Operator::LesserThan => (left < right) as i64,
+                    Operator::GreaterThan => (left > right) as i64,
+                    Operator::EqualLesserThan => (left <= right) as i64,
+                    Operator::EqualGreaterThan => (left >= right) as i64,
+                    Operator::EqualTo => (left == right) as i64,
+                    Operator::Inequal => (left != right) as i64,
+                }))
+            }
+            Expression::FunctionCall { target, args, .. } => {
+                let mut values = vec![];
+                for arg in args {
+                    values.push(self.evaluate(arg, location)?);
+                }
+                self.call(target, values, location)
+            }
+            Expression::ReturnValue { .. } => Err(self.error("Return used as a value".to_string(), location)),
+        }
+    }
+
+    /// Calls a function (or a builtin) with already evaluated arguments
+    pub fn call(&mut self, target: &str, args: Vec<Value>, location: Location) -> Result<Value, RuntimeError> {
+        if target == "print" {
+            let line: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+            println!("{}", line.join(" "));
+            return Ok(Value::Void);
+        }
+        if let Some(index) = intrinsics::index(target) {
+            return self.runtime.call(index, &args, &mut std::io::stdout()).map_err(|error| self.error(error, location));
+        }
+
+        if let Some(symbol) = self.externs.get(target) {
+            return Err(self.error(format!("Can't call C function '{}' in the interpreter, compile the program and link it instead", symbol), location));
+        }
+        let Some((parameters, body)) = self.functions.get(target).cloned() else {
+            return Err(self.error(format!("Unknown function '{}'", target), location));
+        };
+        if parameters.len() != args.len() {
+            return Err(self.error(format!("Function '{}' takes {} arguments, {} were given", target, parameters.len(), args.len()), location));
+        }
+        if self.stack.len() >= INTERPRETER_MAX_DEPTH {
+            return Err(self.error(format!("Stack overflow calling '{}'", target), location));
+        }
+
+        self.stack.push(Frame { function: target.to_string(), variables: parameters.into_iter().zip(args).collect() });
+        let flow = self.run_block(&body);
+        self.stack.pop();
+        match flow? {
+            Flow::Return(value) => Ok(value),
+            // Falling off the end returns the default value, same as the compiled code
+            Flow::Normal => match self.return_types.get(target) {
+                Some(Type::Str) => Ok(Value::Str(String::new())),
+                _ => Ok(Value::Int(0)),
+            },
+        }
+    }
+
+    fn run_block(&mut self, statements: &[Statement]) -> Result<Flow, RuntimeError> {
+        for statement in statements {
+            if let Flow::Return(value) = self.run_statement(statement)? {
+                return Ok(Flow::Return(value));
+            }
+        }
+        Ok(Flow::Normal)
+    }
+
+    fn condition(&mut self, condition: &Expression, location: Location) -> Result<bool, RuntimeError> {
+        match self.evaluate(condition, location)? {
+            Value::Int(value) => Ok(value != 0),
+            other => Err(self.error(format!("Condition has to be an i64, not {:?}", other), location)),
+        }
+    }
+
+    fn run_statement(&mut self, statement: &Statement) -> Result<Flow, RuntimeError> {
+        let location = statement.location();
+        match statement {
+            Statement::ExpressionStatement(Expression::ReturnValue { value }, _) => {
+                Ok(Flow::Return(self.evaluate(value, location)?))
+            }
+            Statement::ExpressionStatement(expression, _) => {
+                self.evaluate(expression, location)?;
+                Ok(Flow::Normal)
+            }
+            Statement::VariableAssignment { name, value, .. } => {
+                let value = self.evaluate(value, location)?;
+                self.frame().variables.insert(name.clone(), value);
+                Ok(Flow::Normal)
+            }
+            Statement::FunctionAssignment { name, arguments, body, .. } => {
+                let mut parameters = vec![];
+                for argument in arguments {
+                    match argument {
+                        Expression::Variable(param, _) => parameters.push(param.clone()),
+                        _ => return Err(self.error(format!("Parameters of function '{}' have to be plain names", name), location)),
+                    }
+                }
+                self.functions.insert(name.clone(), (parameters, body.clone()));
+                Ok(Flow::Normal)
+            }
+            Statement::Import { .. } | Statement::ConstAssignment { .. } => {
+                Err(self.error("Imports and constants only work in files, see modules.rs".to_string(), location))
+            }
+            Statement::Asm { .. } => Err(self.error(ASM_UNSUPPORTED.to_string(), location)),
+            // Freestanding programs run like any other one here, they just can't print
+            Statement::Attribute { .. } => Ok(Flow::Normal),
+            Statement::Extern { function, .. } => {
+                self.externs.insert(function.name.clone(), function.symbol.clone());
+                Ok(Flow::Normal)
+            }
+            Statement::While { condition, body, .. } => {
+                while self.condition(condition, location)? {
+                    if let Flow::Return(value) = self.run_block(body)? {
+                        return Ok(Flow::Return(value));
+                    }
+                }
+                Ok(Flow::Normal)
+            }
+            Statement::ConditionalStatement { condition, body, else_body, .. } => {
+                if self.condition(condition, location)? {
+                    self.run_block(body)
+                } else if let Some(else_body) = else_body {
+                    self.run_block(else_body)
+                } else {
+                    Ok(Flow::Normal)
+                }
+            }
+        }
+    }
+
+    /// Types of the top level variables that exist right now
+    pub fn scope(&self) -> Scope {
+        self.stack[0].variables.iter().filter_map(|(name, value)| match value {
+            Value::Int(_) => Some((name.clone(), Type::Int)),
+            Value::Str(_) => Some((name.clone(), Type::Str)),
+            Value::Void => None,
+        }).collect()
+    }
+
+    /// Runs more top level statements on top of whatever ran before, variables and
+    /// functions stick around (the REPL needs this). `analysis` has to cover all of them.
+    /// Returns the value of a top level `return`, if one ran.
+    pub fn run_more(&mut self, analysis: &Analysis, statements: &[Statement]) -> Result<Option<Value>, RuntimeError> {
+        for (name, info) in &analysis.functions {
+            self.return_types.insert(name.clone(), info.return_type);
+        }
+
+        // Better to refuse the whole program than to stop halfway through it
+        if let Some(location) = find_asm(statements) {
+            return Err(self.error(ASM_UNSUPPORTED.to_string(), location));
+        }
+        // Functions get defined first so they can be called from anywhere
+        let definition = |statement: &&Statement| matches!(statement, Statement::FunctionAssignment { .. } | Statement::Extern { .. });
+        for statement in statements.iter().filter(definition) {
+            self.run_statement(statement)?;
+        }
+        for statement in statements.iter().filter(|statement| !definition(statement)) {
+            if let Flow::Return(value) = self.run_statement(statement)? {
+                return Ok(Some(value));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Runs a whole program, returns the exit code (the top level's return)
+    pub fn run(&mut self, analysis: &Analysis) -> Result<i64, RuntimeError> {
+        if debug_prints(INTERPRETER_DEBUG_PRINTS) {eprintln!("- - - INTERPRETER")}
+
+        let exit_code = match self.run_more(analysis, &analysis.statements)? {
+            Some(Value::Int(exit_code)) => exit_code,
+            // Seman only lets i64 top level returns through
+            _ => 0,
+        };
+
+        if debug_prints(INTERPRETER_DEBUG_PRINTS) {eprintln!("- - - Interpreter done!")}
+        Ok(exit_code)
+    }
+}
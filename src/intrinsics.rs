@@ -0,0 +1,206 @@
+use std::collections::HashMap;
+use std::io::{Read, Write};
+
+use crate::compiler_settings::*;
+use crate::interpreter::Value;
+use crate::seman::Type;
+
+// Intrinsics, the handful of builtins the standard library (std/*.gv) is written on top of.
+// Everything that can be written in Galvan is, these are just memory, strings and syscalls.
+// The native backends turn them into tiny helper functions (straight syscalls on x86, libc in
+// C), the interpreter and the VM share the `Runtime` here. Memory for those two is one
+// Vec<u8>, addresses are offsets into it plus HEAP_BASE so 0 stays an invalid address.
+// The syscall ones return -errno on failure, like Linux does.
+
+//
+// STRUCTS
+//
+
+pub struct Intrinsic {
+    pub name: &'static str,
+    pub parameters: &'static [Type],
+    pub return_type: Type,
+}
+
+/// Heap and open files of a running program (interpreter or VM)
+#[derive(Default)]
+pub struct Runtime {
+    heap: Vec<u8>,
+    /// Files opened with __sys_open, 0-2 are always the standard streams
+    files: HashMap<i64, std::fs::File>,
+}
+
+//
+// FUNCTIONS
+//
+
+const I: Type = Type::Int;
+const S: Type = Type::Str;
+
+pub const INTRINSICS: [Intrinsic; 11] = [
+    // Zeroed memory, never freed
+    Intrinsic { name: "__alloc", parameters: &[I], return_type: I },
+    Intrinsic { name: "__load8", parameters: &[I], return_type: I },
+    Intrinsic { name: "__store8", parameters: &[I, I], return_type: Type::Void },
+    // Byte `index` of a string, 0 right after the end
+    Intrinsic { name: "__str_byte", parameters: &[S, I], return_type: I },
+    // The NUL terminated bytes at an address as a string
+    Intrinsic { name: "__str_at", parameters: &[I], return_type: S },
+    // (fd, address, length)
+    Intrinsic { name: "__sys_read", parameters: &[I, I, I], return_type: I },
+    Intrinsic { name: "__sys_write", parameters: &[I, I, I], return_type: I },
+    // (fd, string), so printing a string doesn't need a buffer
+    Intrinsic { name: "__sys_write_str", parameters: &[I, S], return_type: I },
+    // (path, flags, mode), flags are Linux's O_* values
+    Intrinsic { name: "__sys_open", parameters: &[S, I, I], return_type: I },
+    Intrinsic { name: "__sys_close", parameters: &[I], return_type: I },
+    Intrinsic { name: "__sys_exit", parameters: &[I], return_type: Type::Void },
+];
+
+pub fn index(name: &str) -> Option<usize> {
+    INTRINSICS.iter().position(|intrinsic| intrinsic.name == name)
+}
+
+/// Whether the intrinsic is something only an OS can do (the heap and the syscalls), which
+/// freestanding programs don't get
+pub fn needs_os(name: &str) -> bool {
+    name == "__alloc" || (name.starts_with("__sys_") && index(name).is_some())
+}
+
+/// -errno of an IO error, like the syscall would give back
+fn errno(error: std::io::Error) -> Value {
+    Value::Int(-(error.raw_os_error().unwrap_or(5) as i64)) // EIO when there's no better one
+}
+
+/// What `open()` with Linux's O_* flags and a mode does, for everything that fakes the syscall
+pub fn open_options(flags: i64, mode: i64) -> std::fs::OpenOptions {
+    let mut options = std::fs::OpenOptions::new();
+    match flags & 3 {
+        0 => options.read(true),
+        1 => options.write(true),
+        _ => options.read(true).write(true),
+    };
+    options.create(flags & 0o100 != 0).truncate(flags & 0o1000 != 0).append(flags & 0o2000 != 0);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, mode as u32);
+    #[cfg(not(unix))]
+    let _ = mode;
+    options
+}
+
+impl Runtime {
+    /// Heap index of `length` bytes at `address`
+    fn range(&self, address: i64, length: i64) -> Result<std::ops::Range<usize>, String> {
+        let start = address.checked_sub(HEAP_BASE).filter(|start| *start >= 0);
+        let end = start.and_then(|start| start.checked_add(length)).filter(|end| length >= 0 && *end as usize <= self.heap.len());
+        match (start, end) {
+            (Some(start), Some(end)) => Ok(start as usize..end as usize),
+            _ => Err(format!("Memory access to {:#x} ({} bytes) is outside of anything allocated", address, length)),
+        }
+    }
+
+    fn open(&mut self, path: &str, flags: i64, mode: i64) -> Value {
+        match open_options(flags, mode).open(path) {
+            Ok(file) => {
+                let fd = (3..).find(|fd| !self.files.contains_key(fd)).unwrap();
+                self.files.insert(fd, file);
+                Value::Int(fd)
+            }
+            Err(error) => errno(error),
+        }
+    }
+
+    /// Runs intrinsic number `index`, `out` is where fd 1 goes so it stays in order with print()
+    pub fn call(&mut self, index: usize, args: &[Value], out: &mut dyn Write) -> Result<Value, String> {
+        let int = |arg: usize| match args.get(arg) {
+            Some(Value::Int(value)) => Ok(*value),
+            other => Err(format!("{} expects an i64 as argument {}, not {:?}", INTRINSICS[index].name, arg + 1, other)),
+        };
+        let string = |arg: usize| match args.get(arg) {
+            Some(Value::Str(value)) => Ok(value.as_str()),
+            other => Err(format!("{} expects a str as argument {}, not {:?}", INTRINSICS[index].name, arg + 1, other)),
+        };
+
+        Ok(match INTRINSICS[index].name {
+            "__alloc" => {
+                let size = int(0)?;
+                if !(0..=1 << 32).contains(&size) {return Err(format!("Can't allocate {} bytes", size))}
+                // 8-byte aligned like the native allocators
+                let start = self.heap.len().div_ceil(8) * 8;
+                self.heap.resize(start + size as usize, 0);
+                Value::Int(start as i64 + HEAP_BASE)
+            }
+            "__load8" => {
+                let range = self.range(int(0)?, 1)?;
+                Value::Int(self.heap[range.start] as i64)
+            }
+            "__store8" => {
+                let range = self.range(int(0)?, 1)?;
+                self.heap[range.start] = int(1)? as u8;
+                Value::Void
+            }
+            "__str_byte" => {
+                let (string, byte) = (string(0)?, int(1)?);
+                match byte {
+                    _ if byte == string.len() as i64 => Value::Int(0),
+                    0.. if byte < string.len() as i64 => Value::Int(string.as_bytes()[byte as usize] as i64),
+                    _ => return Err(format!("String index {} is out of bounds, the string is {} bytes", byte, string.len())),
+                }
+            }
+            "__str_at" => {
+                let start = self.range(int(0)?, 0)?.start;
+                let Some(length) = self.heap[start..].iter().position(|byte| *byte == 0) else {
+                    return Err(format!("String at {:#x} has no NUL terminator", int(0)?));
+                };
+                Value::Str(String::from_utf8_lossy(&self.heap[start..start + length]).to_string())
+            }
+            "__sys_read" => {
+                let (fd, range) = (int(0)?, self.range(int(1)?, int(2)?)?);
+                let buffer = &mut self.heap[range];
+                let result = match fd {
+                    0 => std::io::stdin().read(buffer),
+                    _ => match self.files.get_mut(&fd) {
+                        Some(file) => file.read(buffer),
+                        None => return Ok(Value::Int(-9)), // EBADF
+                    },
+                };
+                result.map_or_else(errno, |count| Value::Int(count as i64))
+            }
+            "__sys_write" => {
+                let (fd, range) = (int(0)?, self.range(int(1)?, int(2)?)?);
+                let buffer = &self.heap[range];
+                let result = match fd {
+                    1 => out.write_all(buffer),
+                    2 => out.flush().and_then(|_| std::io::stderr().write_all(buffer)),
+                    _ => match self.files.get_mut(&fd) {
+                        Some(file) => file.write_all(buffer),
+                        None => return Ok(Value::Int(-9)),
+                    },
+                };
+                result.map_or_else(errno, |_| Value::Int(buffer.len() as i64))
+            }
+            "__sys_write_str" => {
+                let (fd, string) = (int(0)?, string(1)?);
+                let result = match fd {
+                    1 => out.write_all(string.as_bytes()),
+                    2 => out.flush().and_then(|_| std::io::stderr().write_all(string.as_bytes())),
+                    _ => match self.files.get_mut(&fd) {
+                        Some(file) => file.write_all(string.as_bytes()),
+                        None => return Ok(Value::Int(-9)),
+                    },
+                };
+                result.map_or_else(errno, |_| Value::Int(string.len() as i64))
+            }
+            "__sys_open" => self.open(string(0)?, int(1)?, int(2)?),
+            "__sys_close" => match self.files.remove(&int(0)?) {
+                Some(_) => Value::Int(0),
+                None => Value::Int(-9),
+            },
+            "__sys_exit" => {
+                let _ = out.flush();
+                std::process::exit(int(0)? as i32);
+            }
+            name => return Err(format!("Intrinsic '{}' isn't implemented", name)),
+        })
+    }
+}
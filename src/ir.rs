@@ -0,0 +1,1311 @@
+use std::collections::HashMap;
+
+use crate::compiler_settings::*;
+use crate::ffi::{CType, Extern};
+use crate::intrinsics;
+use crate::lexer::Location;
+use crate::parser::{expand_asm, Attributes, Expression, Operator, Statement};
+use crate::seman::{Analysis, FunctionInfo, Type};
+use crate::source_map::file_path;
+
+// Three-address-code IR, sitting between the checked AST and the backends.
+// Every function is a list of basic blocks, every block ends with exactly one terminator.
+// Virtual registers are typed and assigned exactly once, variables live in
+// function-level locals which are accessed with load/store.
+// C functions (ffi.rs) are declared in the module and called like any other function.
+// A module with exported functions and no top level code is a library, it has no entry function.
+// With -g every statement starts with a `line` instruction, which the backends that write debug
+// info turn into line table rows (see dwarf.rs).
+
+//
+// STRUCTS
+//
+
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+#[derive(PartialEq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Lt,
+    Gt,
+    Le,
+    Ge,
+    Eq,
+    Ne,
+}
+impl BinaryOp {
+    pub fn from_operator(operator: Operator) -> BinaryOp {
+        match operator {
+            Operator::Addition => BinaryOp::Add,
+            Operator::Subtraction => BinaryOp::Sub,
+            Operator::Multiplication => BinaryOp::Mul,
+            Operator::Division => BinaryOp::Div,
+            Operator::LesserThan => BinaryOp::Lt,
+            Operator::GreaterThan => BinaryOp::Gt,
+            Operator::EqualLesserThan => BinaryOp::Le,
+            Operator::EqualGreaterThan => BinaryOp::Ge,
+            Operator::EqualTo => BinaryOp::Eq,
+            Operator::Inequal => BinaryOp::Ne,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            BinaryOp::Add => "add",
+            BinaryOp::Sub => "sub",
+            BinaryOp::Mul => "mul",
+            BinaryOp::Div => "div",
+            BinaryOp::Lt => "lt",
+            BinaryOp::Gt => "gt",
+            BinaryOp::Le => "le",
+            BinaryOp::Ge => "ge",
+            BinaryOp::Eq => "eq",
+            BinaryOp::Ne => "ne",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<BinaryOp> {
+        [BinaryOp::Add, BinaryOp::Sub, BinaryOp::Mul, BinaryOp::Div, BinaryOp::Lt,
+         BinaryOp::Gt, BinaryOp::Le, BinaryOp::Ge, BinaryOp::Eq, BinaryOp::Ne]
+            .into_iter().find(|op| op.name() == name)
+    }
+}
+
+/// Input of an instruction, either a virtual register or an immediate integer
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+#[derive(PartialEq)]
+pub enum Operand {
+    Register(usize),
+    Constant(i64),
+}
+
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+pub enum Instruction {
+    /// `%dest = copy value`
+    Copy {dest: usize, value: Operand},
+    /// `%dest = string s<index>`, address of a string in the module string table
+    StringAddress {dest: usize, index: usize},
+    /// `%dest = <op> left, right`
+    Binary {dest: usize, op: BinaryOp, left: Operand, right: Operand},
+    /// `%dest = load <local>`
+    Load {dest: usize, local: usize},
+    /// `store <local>, value`
+    Store {local: usize, value: Operand},
+    /// `%dest = call function(args)`, dest is None for void functions
+    Call {dest: Option<usize>, function: String, args: Vec<Operand>},
+    /// `asm volatile "template" in (a = value) out (b = local) clobber ("rax")`, inline
+    /// assembly (see parser::InlineAsm), outputs go straight into locals
+    Asm {template: String, inputs: Vec<(String, Operand)>, outputs: Vec<(String, usize)>, clobbers: Vec<String>, volatile: bool},
+    /// `line f<file> <line>:<column>`, the code after it comes from there in the source. Only
+    /// there with -g, and does nothing by itself.
+    Line {file: usize, line: usize, column: usize},
+}
+
+impl Instruction {
+    /// The virtual register the instruction assigns
+    pub fn dest(&self) -> Option<usize> {
+        match self {
+            Instruction::Copy { dest, .. } | Instruction::StringAddress { dest, .. } | Instruction::Binary { dest, .. } | Instruction::Load { dest, .. } => Some(*dest),
+            Instruction::Call { dest, .. } => *dest,
+            Instruction::Store { .. } | Instruction::Asm { .. } | Instruction::Line { .. } => None,
+        }
+    }
+
+    /// Everything the instruction reads
+    pub fn operands(&self) -> Vec<&Operand> {
+        match self {
+            Instruction::Copy { value, .. } | Instruction::Store { value, .. } => vec![value],
+            Instruction::Binary { left, right, .. } => vec![left, right],
+            Instruction::Call { args, .. } => args.iter().collect(),
+            Instruction::Asm { inputs, .. } => inputs.iter().map(|(_, value)| value).collect(),
+            Instruction::StringAddress { .. } | Instruction::Load { .. } | Instruction::Line { .. } => vec![],
+        }
+    }
+
+    pub fn operands_mut(&mut self) -> Vec<&mut Operand> {
+        match self {
+            Instruction::Copy { value, .. } | Instruction::Store { value, .. } => vec![value],
+            Instruction::Binary { left, right, .. } => vec![left, right],
+            Instruction::Call { args, .. } => args.iter_mut().collect(),
+            Instruction::Asm { inputs, .. } => inputs.iter_mut().map(|(_, value)| value).collect(),
+            Instruction::StringAddress { .. } | Instruction::Load { .. } | Instruction::Line { .. } => vec![],
+        }
+    }
+
+    pub fn is_line(&self) -> bool {
+        matches!(self, Instruction::Line { .. })
+    }
+}
+
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+pub enum Terminator {
+    Jump(usize),
+    /// Goes to `then_block` if the condition is not zero
+    Branch {condition: Operand, then_block: usize, else_block: usize},
+    Return(Option<Operand>),
+}
+impl Terminator {
+    pub fn successors(&self) -> Vec<usize> {
+        match self {
+            Terminator::Jump(target) => vec![*target],
+            Terminator::Branch { then_block, else_block, .. } => vec![*then_block, *else_block],
+            Terminator::Return(_) => vec![],
+        }
+    }
+
+    pub fn operand(&self) -> Option<&Operand> {
+        match self {
+            Terminator::Branch { condition, .. } => Some(condition),
+            Terminator::Return(value) => value.as_ref(),
+            Terminator::Jump(_) => None,
+        }
+    }
+
+    pub fn operand_mut(&mut self) -> Option<&mut Operand> {
+        match self {
+            Terminator::Branch { condition, .. } => Some(condition),
+            Terminator::Return(value) => value.as_mut(),
+            Terminator::Jump(_) => None,
+        }
+    }
+
+    /// Every jump to `from` goes to `to` instead
+    pub fn retarget(&mut self, from: usize, to: usize) {
+        match self {
+            Terminator::Jump(target) => if *target == from {*target = to},
+            Terminator::Branch { then_block, else_block, .. } => {
+                if *then_block == from {*then_block = to}
+                if *else_block == from {*else_block = to}
+            }
+            Terminator::Return(_) => {}
+        }
+    }
+}
+
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+pub struct BasicBlock {
+    pub instructions: Vec<Instruction>,
+    pub terminator: Terminator,
+}
+
+/// Function-level symbol table entry
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+pub struct Local {
+    pub name: String,
+    pub ty: Type,
+}
+
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+pub struct Function {
+    pub name: String,
+    /// The first `parameters` locals are the parameters, in order
+    pub parameters: usize,
+    pub return_type: Type,
+    pub locals: Vec<Local>,
+    /// Type of every virtual register, indexed by register number
+    pub registers: Vec<Type>,
+    /// Block 0 is the entry block
+    pub blocks: Vec<BasicBlock>,
+    /// C symbol of an `export function`
+    pub export: Option<String>,
+    /// `#[interrupt]` and `#[section(...)]` matter to the backends
+    pub attributes: Attributes,
+}
+impl Function {
+    pub fn local_index(&self, name: &str) -> Option<usize> {
+        self.locals.iter().position(|local| local.name == name)
+    }
+}
+
+#[derive(Debug)]
+#[derive(Clone, Default)]
+#[derive(PartialEq)]
+pub struct Module {
+    pub strings: Vec<String>,
+    /// `extern "C"` functions, called by their `name`
+    pub externs: Vec<Extern>,
+    pub functions: Vec<Function>,
+    /// `#![no_std]`, no OS underneath and the entry point runs on bare metal
+    pub freestanding: bool,
+    /// Name of the entry point instead of `_start`, --entry
+    pub entry_symbol: Option<String>,
+    /// Source files `line` instructions point into, empty without -g
+    pub files: Vec<String>,
+}
+impl Module {
+    pub fn external(&self, name: &str) -> Option<&Extern> {
+        self.externs.iter().find(|external| external.name == name)
+    }
+
+    /// Whether the program gets linked together with C code, see the backends
+    pub fn uses_c(&self) -> bool {
+        !self.externs.is_empty() || self.functions.iter().any(|function| function.export.is_some())
+    }
+
+    /// A library has no top level code, so no entry point either
+    pub fn entry(&self) -> Option<&Function> {
+        self.functions.iter().find(|function| function.name == ENTRY_FUNCTION)
+    }
+}
+
+/// Symbol name of a Galvan function in the backends' output, prefixed so it can't clash with anything else
+pub fn symbol(name: &str) -> String {
+    format!("{}{}", SYMBOL_PREFIX, name)
+}
+
+//
+// LOWERING
+//
+
+/// State for lowering one function
+struct FunctionBuilder<'a> {
+    function: Function,
+    current: usize,
+    strings: &'a mut Vec<String>,
+    /// The module's source files, what `line`s point into
+    files: &'a mut Vec<String>,
+    signatures: &'a HashMap<String, FunctionInfo>,
+}
+impl FunctionBuilder<'_> {
+    fn new_register(&mut self, ty: Type) -> usize {
+        self.function.registers.push(ty);
+        self.function.registers.len() - 1
+    }
+
+    fn new_block(&mut self) -> usize {
+        // Placeholder terminator, every block gets a real one before lowering finishes
+        self.function.blocks.push(BasicBlock { instructions: vec![], terminator: Terminator::Return(None) });
+        self.function.blocks.len() - 1
+    }
+
+    fn emit(&mut self, instruction: Instruction) {
+        let current = self.current;
+        self.function.blocks[current].instructions.push(instruction);
+    }
+
+    /// Ends the current block, and continues in `next`
+    fn terminate(&mut self, terminator: Terminator, next: usize) {
+        let current = self.current;
+        self.function.blocks[current].terminator = terminator;
+        self.current = next;
+    }
+
+    fn local(&mut self, name: &str, ty: Type) -> usize {
+        match self.function.local_index(name) {
+            Some(index) => index,
+            None => {
+                self.function.locals.push(Local { name: name.to_string(), ty });
+                self.function.locals.len() - 1
+            }
+        }
+    }
+
+    /// Marks where the code that comes next is from
+    fn line(&mut self, location: Location) {
+        let path = file_path(location.2).unwrap_or("<unknown>".to_string());
+        let file = match self.files.iter().position(|file| *file == path) {
+            Some(index) => index,
+            None => {
+                self.files.push(path);
+                self.files.len() - 1
+            }
+        };
+        self.emit(Instruction::Line { file, line: location.0, column: location.1 });
+    }
+
+    fn string(&mut self, value: &str) -> usize {
+        match self.strings.iter().position(|string| string == value) {
+            Some(index) => index,
+            None => {
+                self.strings.push(value.to_string());
+                self.strings.len() - 1
+            }
+        }
+    }
+
+    fn lower_expression(&mut self, expression: &Expression) -> Result<Option<Operand>, String> {
+        match expression {
+            Expression::Number(number) => Ok(Some(Operand::Constant(*number))),
+            Expression::String(string) => {
+                let index = self.string(string);
+                let dest = self.new_register(Type::Str);
+                self.emit(Instruction::StringAddress { dest, index });
+                Ok(Some(Operand::Register(dest)))
+            }
+            Expression::Variable(name, _) => {
+                let local = match self.function.local_index(name) {
+                    Some(local) => local,
+                    None => return Err(format!("Unknown variable '{}' in IR lowering", name)),
+                };
+                let dest = self.new_register(self.function.locals[local].ty);
+                self.emit(Instruction::Load { dest, local });
+                Ok(Some(Operand::Register(dest)))
+            }
+            Expression::Operation(operation) => {
+                let left = self.lower_value(&operation.left)?;
+                let right = self.lower_value(&operation.right)?;
+                let dest = self.new_register(Type::Int);
+                self.emit(Instruction::Binary { dest, op: BinaryOp::from_operator(operation.operator), left, right });
+                Ok(Some(Operand::Register(dest)))
+            }
+            Expression::FunctionCall { target, args, .. } => {
+                let mut operands = vec![];
+                for arg in args {
+                    operands.push(self.lower_value(arg)?);
+                }
+                let return_type = match self.signatures.get(target) {
+                    Some(function) => function.return_type,
+                    None => return Err(format!("Unknown function '{}' in IR lowering", target)),
+                };
+                let dest = if return_type == Type::Void {None} else {Some(self.new_register(return_type))};
+                self.emit(Instruction::Call { dest, function: target.clone(), args: operands });
+                Ok(dest.map(Operand::Register))
+            }
+            Expression::ReturnValue { value } => {
+                let value = self.lower_value(value)?;
+                let dead = self.new_block();
+                self.terminate(Terminator::Return(Some(value)), dead);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Same as `lower_expression()`, but the expression has to produce something
+    fn lower_value(&mut self, expression: &Expression) -> Result<Operand, String> {
+        match self.lower_expression(expression)? {
+            Some(operand) => Ok(operand),
+            None => Err(format!("Expression {:?} doesn't have a value", expression)),
+        }
+    }
+
+    fn lower_statements(&mut self, statements: &[Statement]) -> Result<(), String> {
+        for statement in statements {
+            // A loop's line goes in its header, so every check of the condition is on it
+            if !matches!(statement, Statement::While { .. }) {self.line(statement.location())}
+            match statement {
+                Statement::ExpressionStatement(expression, _) => {
+                    self.lower_expression(expression)?;
+                }
+                Statement::VariableAssignment { name, value, .. } => {
+                    let value = self.lower_value(value)?;
+                    let ty = match value {
+                        Operand::Register(register) => self.function.registers[register],
+                        Operand::Constant(_) => Type::Int,
+                    };
+                    let local = self.local(name, ty);
+                    self.emit(Instruction::Store { local, value });
+                }
+                Statement::Asm { asm, .. } => {
+                    let mut inputs = vec![];
+                    for (name, value) in &asm.inputs {
+                        inputs.push((name.clone(), self.lower_value(value)?));
+                    }
+                    let outputs = asm.outputs.iter().map(|(name, variable)| (name.clone(), self.local(variable, Type::Int))).collect();
+                    self.emit(Instruction::Asm {
+                        template: asm.template.clone(),
+                        inputs,
+                        outputs,
+                        clobbers: asm.clobbers.clone(),
+                        volatile: asm.volatile,
+                    });
+                }
+                Statement::FunctionAssignment { name, .. } => {
+                    return Err(format!("Nested function '{}' in IR lowering", name));
+                }
+                Statement::Import { .. } | Statement::ConstAssignment { .. } => {
+                    return Err("Imports and constants should be resolved before IR lowering".to_string());
+                }
+                Statement::Extern { function, .. } => {
+                    return Err(format!("Nested extern '{}' in IR lowering", function.name));
+                }
+                Statement::Attribute { name, .. } => {
+                    return Err(format!("#![{}] should be taken out before it gets lowered", name));
+                }
+                Statement::While { condition, body, location } => {
+                    let header = self.new_block();
+                    let body_block = self.new_block();
+                    let exit = self.new_block();
+                    self.terminate(Terminator::Jump(header), header);
+                    self.line(*location);
+
+                    let condition = self.lower_value(condition)?;
+                    self.terminate(Terminator::Branch { condition, then_block: body_block, else_block: exit }, body_block);
+
+                    self.lower_statements(body)?;
+                    self.terminate(Terminator::Jump(header), exit);
+                }
+                Statement::ConditionalStatement { condition, body, else_body, .. } => {
+                    let condition = self.lower_value(condition)?;
+                    let then_block = self.new_block();
+                    let else_block = self.new_block();
+                    let merge = if else_body.is_some() {self.new_block()} else {else_block};
+                    self.terminate(Terminator::Branch { condition, then_block, else_block }, then_block);
+
+                    self.lower_statements(body)?;
+                    if let Some(else_body) = else_body {
+                        self.terminate(Terminator::Jump(merge), else_block);
+                        self.lower_statements(else_body)?;
+                    }
+                    self.terminate(Terminator::Jump(merge), merge);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds the fall-off-the-end return and throws away blocks nothing jumps to
+    fn finish(mut self) -> Function {
+        let value = match self.function.return_type {
+            Type::Int => Some(Operand::Constant(0)),
+            Type::Str => {
+                let index = self.string("");
+                let dest = self.new_register(Type::Str);
+                self.emit(Instruction::StringAddress { dest, index });
+                Some(Operand::Register(dest))
+            }
+            Type::Void => None,
+        };
+        let current = self.current;
+        self.terminate(Terminator::Return(value), current);
+
+        remove_unreachable_blocks(&mut self.function);
+        self.function
+    }
+}
+
+/// Removes blocks that can't be reached from the entry block, and renumbers the rest
+pub fn remove_unreachable_blocks(function: &mut Function) {
+    let mut reachable = vec![false; function.blocks.len()];
+    let mut stack = vec![0];
+    while let Some(block) = stack.pop() {
+        if reachable[block] {continue}
+        reachable[block] = true;
+        stack.extend(function.blocks[block].terminator.successors());
+    }
+
+    let mut renumber = vec![0; function.blocks.len()];
+    let mut count = 0;
+    for (index, is_reachable) in reachable.iter().enumerate() {
+        if *is_reachable {
+            renumber[index] = count;
+            count += 1;
+        }
+    }
+
+    let blocks = std::mem::take(&mut function.blocks);
+    for (index, mut block) in blocks.into_iter().enumerate() {
+        if !reachable[index] {continue}
+        block.terminator = match block.terminator {
+            Terminator::Jump(target) => Terminator::Jump(renumber[target]),
+            Terminator::Branch { condition, then_block, else_block } => Terminator::Branch {
+                condition,
+                then_block: renumber[then_block],
+                else_block: renumber[else_block],
+            },
+            terminator => terminator,
+        };
+        function.blocks.push(block);
+    }
+}
+
+/// `location` is where the function is declared, the top level doesn't have one
+fn lower_function(name: &str, parameters: &[(String, Type)], return_type: Type, body: &[Statement], location: Option<Location>,
+    module: &mut Module, signatures: &HashMap<String, FunctionInfo>) -> Result<Function, String> {
+    let mut builder = FunctionBuilder {
+        function: Function {
+            name: name.to_string(),
+            parameters: parameters.len(),
+            return_type,
+            locals: parameters.iter().map(|(param, ty)| Local { name: param.clone(), ty: *ty }).collect(),
+            registers: vec![],
+            blocks: vec![],
+            export: None,
+            attributes: Attributes::default(),
+        },
+        current: 0,
+        strings: &mut module.strings,
+        files: &mut module.files,
+        signatures,
+    };
+    builder.new_block();
+    // The prologue belongs to the declaration
+    if let Some(location) = location {builder.line(location)}
+    builder.lower_statements(body)?;
+    Ok(builder.finish())
+}
+
+/// Lowers the checked AST into IR. Top level statements end up in `ENTRY_FUNCTION`, unless
+/// the program is a library (see `Module::entry()`). `debug_info` is -g, see `Instruction::Line`.
+pub fn lower(analysis: &Analysis, debug_info: bool) -> Result<Module, String> {
+    if debug_prints(IR_DEBUG_PRINTS) {eprintln!("- - - IR")}
+
+    let mut module = Module::default();
+    let mut toplevel: Vec<Statement> = vec![];
+    for statement in &analysis.statements {
+        match statement {
+            Statement::FunctionAssignment { name, body, export, attributes, location, .. } => {
+                let info = &analysis.functions[name];
+                let parameters: Vec<(String, Type)> = info.parameters.iter().cloned().zip(info.parameter_types.iter().copied()).collect();
+                let mut function = lower_function(name, &parameters, info.return_type, body, Some(*location),
+                    &mut module, &analysis.functions)?;
+                function.export = export.clone();
+                function.attributes = attributes.clone();
+                module.functions.push(function);
+            }
+            Statement::Extern { function, .. } => module.externs.push(function.clone()),
+            Statement::Attribute { .. } => module.freestanding = true,
+            other => toplevel.push(other.clone()),
+        }
+    }
+    let library = toplevel.is_empty() && module.functions.iter().any(|function| function.export.is_some());
+    if !library {
+        let entry = lower_function(ENTRY_FUNCTION, &[], Type::Int, &toplevel, None, &mut module, &analysis.functions)?;
+        module.functions.push(entry);
+    }
+    if !debug_info {
+        for block in module.functions.iter_mut().flat_map(|function| &mut function.blocks) {
+            block.instructions.retain(|instruction| !instruction.is_line());
+        }
+        module.files.clear();
+    }
+
+    verify(&module)?;
+    if debug_prints(IR_DEBUG_PRINTS) {eprintln!("{}", module)}
+    if debug_prints(IR_DEBUG_PRINTS) {eprintln!("- - - IR done!")}
+    Ok(module)
+}
+
+//
+// VERIFIER
+//
+
+/// Checks that the module is well formed: registers are defined once before use (within
+/// a block, or anywhere for cross-block uses), types line up and jumps go somewhere real.
+pub fn verify(module: &Module) -> Result<(), String> {
+    let signatures: HashMap<&str, &Function> = module.functions.iter().map(|function| (function.name.as_str(), function)).collect();
+    for (index, external) in module.externs.iter().enumerate() {
+        if signatures.contains_key(external.name.as_str()) || module.externs[..index].iter().any(|other| other.name == external.name) {
+            return Err(format!("IR error: extern '{}' is defined twice", external.name));
+        }
+    }
+    for function in &module.functions {
+        let error = |message: String| Err(format!("IR error in function '{}': {}", function.name, message));
+        if function.blocks.is_empty() {return error("no blocks".to_string())}
+        if function.parameters > function.locals.len() {return error("more parameters than locals".to_string())}
+        let parameters = &function.locals[..function.parameters];
+        if let Some(index) = (0..parameters.len()).find(|index| parameters[..*index].iter().any(|other| other.name == parameters[*index].name)) {
+            return error(format!("parameter #{} is called '{}' like one before it", index, parameters[index].name));
+        }
+
+        let mut defined = vec![false; function.registers.len()];
+        let mut define = |register: usize| -> Result<(), String> {
+            match defined.get_mut(register) {
+                None => Err(format!("register %{} has no type", register)),
+                Some(true) => Err(format!("register %{} is assigned twice", register)),
+                Some(slot) => {*slot = true; Ok(())}
+            }
+        };
+        for block in &function.blocks {
+            for instruction in &block.instructions {
+                let result = match instruction {
+                    Instruction::Copy { dest, .. } | Instruction::StringAddress { dest, .. }
+                    | Instruction::Binary { dest, .. } | Instruction::Load { dest, .. } => define(*dest),
+                    Instruction::Call { dest: Some(dest), .. } => define(*dest),
+                    Instruction::Store { .. } | Instruction::Call { dest: None, .. } | Instruction::Asm { .. } | Instruction::Line { .. } => Ok(()),
+                };
+                if let Err(message) = result {return error(message)}
+            }
+        }
+
+        let type_of = |operand: &Operand| -> Result<Type, String> {
+            match operand {
+                Operand::Constant(_) => Ok(Type::Int),
+                Operand::Register(register) => {
+                    if !defined.get(*register).copied().unwrap_or(false) {
+                        return Err(format!("register %{} is used but never assigned", register));
+                    }
+                    Ok(function.registers[*register])
+                }
+            }
+        };
+        let check = |result: Result<(), String>| -> Result<(), String> {
+            match result {
+                Ok(()) => Ok(()),
+                Err(message) => Err(format!("IR error in function '{}': {}", function.name, message)),
+            }
+        };
+        for (index, block) in function.blocks.iter().enumerate() {
+            for (position, instruction) in block.instructions.iter().enumerate() {
+                check(verify_instruction(instruction, function, &signatures, module, &type_of)
+                    .map_err(|message| format!("bb{} instruction {}: {}", index, position, message)))?;
+            }
+            check(match &block.terminator {
+                Terminator::Jump(_) => Ok(()),
+                Terminator::Branch { condition, .. } => {
+                    if type_of(condition)? != Type::Int {Err("branch condition has to be an i64".to_string())} else {Ok(())}
+                }
+                Terminator::Return(value) => {
+                    let ty = match value {Some(value) => type_of(value)?, None => Type::Void};
+                    if ty != function.return_type {Err(format!("returning {} from a function returning {}", ty, function.return_type))}
+                    else {Ok(())}
+                }
+            })?;
+            for successor in block.terminator.successors() {
+                if successor >= function.blocks.len() {
+                    return check(Err(format!("bb{} jumps to bb{} which doesn't exist", index, successor)));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn verify_instruction(instruction: &Instruction, function: &Function, signatures: &HashMap<&str, &Function>, module: &Module,
+    type_of: &dyn Fn(&Operand) -> Result<Type, String>) -> Result<(), String> {
+    let externs = &module.externs;
+    let local = |local: usize| -> Result<Type, String> {
+        match function.locals.get(local) {
+            Some(local) => Ok(local.ty),
+            None => Err(format!("local #{} doesn't exist", local)),
+        }
+    };
+    match instruction {
+        Instruction::Copy { dest, value } => {
+            if type_of(value)? != function.registers[*dest] {return Err(format!("copy into %{} changes the type", dest))}
+        }
+        Instruction::StringAddress { dest, .. } => {
+            if function.registers[*dest] != Type::Str {return Err(format!("string address in %{} which isn't str", dest))}
+        }
+        Instruction::Binary { dest, left, right, .. } => {
+            if type_of(left)? != Type::Int || type_of(right)? != Type::Int || function.registers[*dest] != Type::Int {
+                return Err(format!("arithmetic into %{} has to be on i64", dest));
+            }
+        }
+        Instruction::Load { dest, local: index } => {
+            if local(*index)? != function.registers[*dest] {return Err(format!("load into %{} changes the type", dest))}
+        }
+        Instruction::Store { local: index, value } => {
+            if local(*index)? != type_of(value)? {return Err(format!("store to local #{} changes the type", index))}
+        }
+        Instruction::Call { dest, function: target, args } => {
+            for arg in args {type_of(arg)?;}
+            let ty = dest.map(|dest| function.registers[dest]).unwrap_or(Type::Void);
+            if let Some(external) = externs.iter().find(|external| external.name == *target) {
+                let expected = external.parameters.len();
+                if args.len() < expected || (args.len() > expected && !external.variadic) {
+                    return Err(format!("call to extern '{}' with {} arguments instead of {}", target, args.len(), expected));
+                }
+                for ((_, c_type), arg) in external.parameters.iter().zip(args) {
+                    if !c_type.accepts(type_of(arg)?) {return Err(format!("call to extern '{}' passes {} as {}", target, type_of(arg)?, c_type))}
+                }
+                if ty != external.return_type.galvan() {
+                    return Err(format!("call to extern '{}' expects {}, but it returns {}", target, ty, external.return_type));
+                }
+            }
+            let builtin = target == "print" || intrinsics::index(target).is_some();
+            if !builtin && !signatures.contains_key(target.as_str()) && !externs.iter().any(|external| external.name == *target) {
+                return Err(format!("call to '{}' which doesn't exist", target));
+            }
+            // Builtins aren't in the module, so there's nothing more to check for them
+            if let Some(callee) = signatures.get(target.as_str()) {
+                if callee.parameters != args.len() {
+                    return Err(format!("call to '{}' with {} arguments instead of {}", target, args.len(), callee.parameters));
+                }
+                if ty != callee.return_type {
+                    return Err(format!("call to '{}' expects {}, but it returns {}", target, ty, callee.return_type));
+                }
+            }
+        }
+        Instruction::Asm { template, inputs, outputs, .. } => {
+            for (_, value) in inputs {type_of(value)?;}
+            for (name, index) in outputs {
+                if local(*index)? != Type::Int {return Err(format!("asm output '{}' has to go into an i64 local", name))}
+            }
+            let names: Vec<&String> = inputs.iter().map(|(name, _)| name).chain(outputs.iter().map(|(name, _)| name)).collect();
+            if let Some(index) = (0..names.len()).find(|index| names[..*index].contains(&names[*index])) {
+                return Err(format!("asm has two operands called '{}'", names[index]));
+            }
+            expand_asm(template, &mut |name| match names.iter().any(|operand| *operand == name) {
+                true => Ok(String::new()),
+                false => Err(format!("asm uses '{{{}}}' which isn't an operand", name)),
+            })?;
+        }
+        Instruction::Line { file, .. } => {
+            if *file >= module.files.len() {return Err(format!("line in file f{} which doesn't exist", file))}
+        }
+    }
+    Ok(())
+}
+
+//
+// TEXT FORMAT
+//
+
+impl std::fmt::Display for Operand {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Operand::Register(register) => write!(f, "%{}", register),
+            Operand::Constant(value) => write!(f, "{}", value),
+        }
+    }
+}
+
+impl Function {
+    fn fmt_instruction(&self, f: &mut std::fmt::Formatter<'_>, instruction: &Instruction) -> std::fmt::Result {
+        let dest = |f: &mut std::fmt::Formatter<'_>, register: usize| write!(f, "%{}: {} = ", register, self.registers[register]);
+        match instruction {
+            Instruction::Copy { dest: register, value } => {dest(f, *register)?; write!(f, "copy {}", value)}
+            Instruction::StringAddress { dest: register, index } => {dest(f, *register)?; write!(f, "string s{}", index)}
+            Instruction::Binary { dest: register, op, left, right } => {dest(f, *register)?; write!(f, "{} {}, {}", op.name(), left, right)}
+            Instruction::Load { dest: register, local } => {dest(f, *register)?; write!(f, "load {}", self.locals[*local].name)}
+            Instruction::Store { local, value } => write!(f, "store {}, {}", self.locals[*local].name, value),
+            Instruction::Call { dest: register, function, args } => {
+                if let Some(register) = register {dest(f, *register)?}
+                let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+                write!(f, "call {}({})", function, args.join(", "))
+            }
+            Instruction::Asm { template, inputs, outputs, clobbers, volatile } => {
+                write!(f, "asm{} {:?}", if *volatile {" volatile"} else {""}, template)?;
+                if !inputs.is_empty() {
+                    let inputs: Vec<String> = inputs.iter().map(|(name, value)| format!("{} = {}", name, value)).collect();
+                    write!(f, " in ({})", inputs.join(", "))?;
+                }
+                if !outputs.is_empty() {
+                    let outputs: Vec<String> = outputs.iter().map(|(name, local)| format!("{} = {}", name, self.locals[*local].name)).collect();
+                    write!(f, " out ({})", outputs.join(", "))?;
+                }
+                if !clobbers.is_empty() {
+                    let clobbers: Vec<String> = clobbers.iter().map(|clobber| format!("{:?}", clobber)).collect();
+                    write!(f, " clobber ({})", clobbers.join(", "))?;
+                }
+                Ok(())
+            }
+            Instruction::Line { file, line, column } => write!(f, "line f{} {}:{}", file, line, column),
+        }
+    }
+
+    /// One instruction the way it's printed in a function, for dumps that go line by line
+    pub fn instruction_text(&self, instruction: &Instruction) -> String {
+        struct Text<'a>(&'a Function, &'a Instruction);
+        impl std::fmt::Display for Text<'_> {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                self.0.fmt_instruction(f, self.1)
+            }
+        }
+        Text(self, instruction).to_string()
+    }
+
+    pub fn terminator_text(&self, terminator: &Terminator) -> String {
+        match terminator {
+            Terminator::Jump(target) => format!("jump bb{}", target),
+            Terminator::Branch { condition, then_block, else_block } => format!("branch {}, bb{}, bb{}", condition, then_block, else_block),
+            Terminator::Return(Some(value)) => format!("ret {}", value),
+            Terminator::Return(None) => "ret".to_string(),
+        }
+    }
+}
+
+impl std::fmt::Display for Function {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let params: Vec<String> = self.locals[..self.parameters].iter()
+            .map(|local| format!("{}: {}", local.name, local.ty)).collect();
+        if self.attributes.interrupt {write!(f, "interrupt ")?}
+        if self.attributes.panic_handler {write!(f, "panic_handler ")?}
+        if let Some(section) = &self.attributes.section {write!(f, "section {:?} ", section)?}
+        if let Some(export) = &self.export {write!(f, "export {} ", export)?}
+        writeln!(f, "function {}({}) -> {} {{", self.name, params.join(", "), self.return_type)?;
+        for local in &self.locals[self.parameters..] {
+            writeln!(f, "    local {}: {}", local.name, local.ty)?;
+        }
+        for (index, block) in self.blocks.iter().enumerate() {
+            writeln!(f, "bb{}:", index)?;
+            for instruction in &block.instructions {
+                write!(f, "    ")?;
+                self.fmt_instruction(f, instruction)?;
+                writeln!(f)?;
+            }
+            writeln!(f, "    {}", self.terminator_text(&block.terminator))?;
+        }
+        write!(f, "}}")
+    }
+}
+
+impl std::fmt::Display for Module {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.freestanding {writeln!(f, "no_std")?}
+        if let Some(entry) = &self.entry_symbol {writeln!(f, "entry {}", entry)?}
+        for (index, file) in self.files.iter().enumerate() {
+            writeln!(f, "file f{} = {:?}", index, file)?;
+        }
+        for (index, string) in self.strings.iter().enumerate() {
+            writeln!(f, "string s{} = {:?}", index, string)?;
+        }
+        for external in &self.externs {
+            // `extern name = symbol(...)` when the name got mangled
+            if external.name == external.symbol {writeln!(f, "extern {}", external.signature())?}
+            else {writeln!(f, "extern {} = {}", external.name, external.signature())?}
+        }
+        for function in &self.functions {
+            writeln!(f)?;
+            writeln!(f, "{}", function)?;
+        }
+        Ok(())
+    }
+}
+
+//
+// TEXT PARSER
+//
+
+/// Splits one line of IR text into tokens. Punctuation gets its own token,
+/// strings keep their quotes (and escapes) so they can be unescaped later.
+fn ir_tokens(line: &str) -> Result<Vec<String>, String> {
+    let mut tokens = vec![];
+    let mut chars = line.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {chars.next(); continue}
+        if c == ';' {break} // Comment until end of line
+        if "(),:={}".contains(c) || (c == '-' && tokens.last().is_some_and(|last: &String| last == ")")) {
+            chars.next();
+            if c == '-' {
+                if chars.next() != Some('>') {return Err("Expected '->'".to_string())}
+                tokens.push("->".to_string());
+            } else {
+                tokens.push(c.to_string());
+            }
+            continue;
+        }
+        if c == '"' {
+            let mut token = String::from(chars.next().unwrap());
+            let mut escaped = false;
+            loop {
+                let Some(ch) = chars.next() else {return Err("Unterminated string".to_string())};
+                token.push(ch);
+                if escaped {escaped = false}
+                else if ch == '\\' {escaped = true}
+                else if ch == '"' {break}
+            }
+            tokens.push(token);
+            continue;
+        }
+        let mut token = String::new();
+        while let Some(&ch) = chars.peek() {
+            if ch.is_whitespace() || "(),:={};\"".contains(ch) {break}
+            token.push(ch);
+            chars.next();
+        }
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+/// Undoes the `{:?}` escaping used when dumping strings
+fn unescape(quoted: &str) -> Result<String, String> {
+    let inner = &quoted[1..quoted.len() - 1];
+    let mut out = String::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {out.push(c); continue}
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some('t') => out.push('\t'),
+            Some('r') => out.push('\r'),
+            Some('0') => out.push('\0'),
+            Some('\\') => out.push('\\'),
+            Some('"') => out.push('"'),
+            Some('\'') => out.push('\''),
+            Some('u') => {
+                let code: String = chars.by_ref().skip(1).take_while(|ch| *ch != '}').collect();
+                let code = u32::from_str_radix(&code, 16).map_err(|_| format!("Invalid escape in {}", quoted))?;
+                out.push(char::from_u32(code).ok_or(format!("Invalid escape in {}", quoted))?);
+            }
+            _ => return Err(format!("Invalid escape in {}", quoted)),
+        }
+    }
+    Ok(out)
+}
+
+fn parse_type(token: &str) -> Result<Type, String> {
+    match token {
+        "i64" => Ok(Type::Int),
+        "str" => Ok(Type::Str),
+        "void" => Ok(Type::Void),
+        _ => Err(format!("Unknown type '{}'", token)),
+    }
+}
+
+fn parse_number(token: &str, prefix: &str) -> Result<usize, String> {
+    match token.strip_prefix(prefix).map(|number| number.parse::<usize>()) {
+        Some(Ok(number)) => Ok(number),
+        _ => Err(format!("Expected {}<number>, not '{}'", prefix, token)),
+    }
+}
+
+/// Cursor over the tokens of one line
+struct Line<'a> {
+    tokens: &'a [String],
+    position: usize,
+    number: usize,
+}
+impl<'a> Line<'a> {
+    fn next(&mut self) -> Result<&'a str, String> {
+        let token = self.tokens.get(self.position).ok_or(format!("Unexpected end of line {}", self.number))?;
+        self.position += 1;
+        Ok(token)
+    }
+
+    fn expect(&mut self, expectation: &str) -> Result<(), String> {
+        let token = self.next()?;
+        if token != expectation {return Err(format!("Expected '{}', not '{}' on line {}", expectation, token, self.number))}
+        Ok(())
+    }
+
+    fn peek(&self) -> Option<&'a str> {
+        self.tokens.get(self.position).map(|token| token.as_str())
+    }
+
+    fn done(&self) -> Result<(), String> {
+        match self.peek() {
+            None => Ok(()),
+            Some(token) => Err(format!("Unexpected '{}' on line {}", token, self.number)),
+        }
+    }
+
+    fn operand(&mut self) -> Result<Operand, String> {
+        let token = self.next()?;
+        if token.starts_with('%') {
+            return Ok(Operand::Register(parse_number(token, "%")?));
+        }
+        match token.parse::<i64>() {
+            Ok(value) => Ok(Operand::Constant(value)),
+            Err(_) => Err(format!("Expected operand, not '{}' on line {}", token, self.number)),
+        }
+    }
+}
+
+fn parse_c_type(line: &mut Line) -> Result<CType, String> {
+    let token = line.next()?;
+    CType::parse(token).ok_or(format!("Unknown C type '{}' on line {}", token, line.number))
+}
+
+/// `extern name = symbol(a: i32, ...) -> i32`, after the `extern`
+fn parse_extern(line: &mut Line) -> Result<Extern, String> {
+    let name = line.next()?.to_string();
+    let symbol = if line.peek() == Some("=") {
+        line.next()?;
+        line.next()?.to_string()
+    } else {
+        name.clone()
+    };
+    let mut external = Extern { name, symbol, parameters: vec![], variadic: false, return_type: CType::Void };
+    line.expect("(")?;
+    while line.peek() != Some(")") {
+        if line.peek() == Some("...") {
+            line.next()?;
+            external.variadic = true;
+        } else {
+            let parameter = line.next()?.to_string();
+            line.expect(":")?;
+            external.parameters.push((parameter, parse_c_type(line)?));
+        }
+        if line.peek() == Some(",") {line.next()?;}
+    }
+    line.expect(")")?;
+    line.expect("->")?;
+    external.return_type = parse_c_type(line)?;
+    Ok(external)
+}
+
+/// State for parsing one function
+struct FunctionParser {
+    function: Function,
+    /// Label number -> block index, blocks get an index the first time they're mentioned
+    labels: HashMap<usize, usize>,
+    /// Which blocks have actually been written out (not just jumped to)
+    defined: Vec<bool>,
+}
+impl FunctionParser {
+    fn local(&self, name: &str, line: usize) -> Result<usize, String> {
+        self.function.local_index(name).ok_or(format!("Unknown local '{}' on line {}", name, line))
+    }
+
+    fn define(&mut self, register: usize, ty: Type) {
+        if self.function.registers.len() <= register {
+            self.function.registers.resize(register + 1, Type::Void);
+        }
+        self.function.registers[register] = ty;
+    }
+
+    /// Blocks are written as `bb<n>` but they don't have to be in order in the text
+    fn block(&mut self, label: &str) -> Result<usize, String> {
+        let number = parse_number(label, "bb")?;
+        if let Some(index) = self.labels.get(&number) {return Ok(*index)}
+        self.function.blocks.push(BasicBlock { instructions: vec![], terminator: Terminator::Return(None) });
+        self.defined.push(false);
+        self.labels.insert(number, self.function.blocks.len() - 1);
+        Ok(self.function.blocks.len() - 1)
+    }
+}
+
+/// Parses IR text (the same format `Display` produces) back into a `Module`.
+/// Meant for writing IR by hand, so the result also goes through `verify()`.
+pub fn parse_ir(text: &str) -> Result<Module, String> {
+    let mut module = Module::default();
+    let mut current: Option<FunctionParser> = None;
+    let mut block: Option<usize> = None;
+    let mut terminated = false;
+
+    for (index, raw) in text.lines().enumerate() {
+        let tokens = ir_tokens(raw)?;
+        if tokens.is_empty() {continue}
+        let mut line = Line { tokens: &tokens, position: 0, number: index + 1 };
+
+        let Some(parser) = current.as_mut() else {
+            match line.next()? {
+                "string" => {
+                    let number = parse_number(line.next()?, "s")?;
+                    line.expect("=")?;
+                    let value = line.next()?;
+                    if !value.starts_with('"') {return Err(format!("Expected string on line {}", line.number))}
+                    if number != module.strings.len() {return Err(format!("String s{} out of order on line {}", number, line.number))}
+                    module.strings.push(unescape(value)?);
+                    line.done()?;
+                }
+                "file" => {
+                    let number = parse_number(line.next()?, "f")?;
+                    line.expect("=")?;
+                    let path = line.next()?;
+                    if !path.starts_with('"') {return Err(format!("Expected the file's path on line {}", line.number))}
+                    if number != module.files.len() {return Err(format!("File f{} out of order on line {}", number, line.number))}
+                    module.files.push(unescape(path)?);
+                    line.done()?;
+                }
+                "extern" => {
+                    module.externs.push(parse_extern(&mut line)?);
+                    line.done()?;
+                }
+                "no_std" => {
+                    module.freestanding = true;
+                    line.done()?;
+                }
+                "entry" => {
+                    module.entry_symbol = Some(line.next()?.to_string());
+                    line.done()?;
+                }
+                mut keyword @ ("interrupt" | "panic_handler" | "section" | "export" | "function") => {
+                    // `interrupt section ".vectors" export symbol function name(...)`
+                    let mut attributes = Attributes::default();
+                    let mut export = None;
+                    loop {
+                        match keyword {
+                            "interrupt" => attributes.interrupt = true,
+                            "panic_handler" => attributes.panic_handler = true,
+                            "section" => {
+                                let section = line.next()?;
+                                if !section.starts_with('"') {return Err(format!("Expected section name on line {}", line.number))}
+                                attributes.section = Some(unescape(section)?);
+                            }
+                            "export" => export = Some(line.next()?.to_string()),
+                            "function" => break,
+                            token => return Err(format!("Expected 'function', not '{}' on line {}", token, line.number)),
+                        }
+                        keyword = line.next()?;
+                    }
+                    let name = line.next()?.to_string();
+                    line.expect("(")?;
+                    let mut locals = vec![];
+                    while line.peek() != Some(")") {
+                        let name = line.next()?.to_string();
+                        line.expect(":")?;
+                        locals.push(Local { name, ty: parse_type(line.next()?)? });
+                        if line.peek() == Some(",") {line.next()?;}
+                    }
+                    line.expect(")")?;
+                    line.expect("->")?;
+                    let return_type = parse_type(line.next()?)?;
+                    line.expect("{")?;
+                    line.done()?;
+                    current = Some(FunctionParser {
+                        function: Function { name, parameters: locals.len(), return_type, locals, registers: vec![], blocks: vec![], export, attributes },
+                        labels: HashMap::new(),
+                        defined: vec![],
+                    });
+                    block = None;
+                }
+                token => return Err(format!("Expected 'string', 'file', 'extern', 'no_std', 'entry' or 'function', not '{}' on line {}", token, line.number)),
+            }
+            continue;
+        };
+
+        let first = line.next()?.to_string();
+        if first == "}" {
+            line.done()?;
+            if block.is_some() && !terminated {return Err(format!("Block doesn't end in a terminator before line {}", line.number))}
+            let parser = current.take().unwrap();
+            if parser.function.blocks.is_empty() {return Err(format!("Function '{}' has no blocks", parser.function.name))}
+            if let Some(label) = parser.labels.iter().find(|(_, index)| !parser.defined[**index]).map(|(label, _)| label) {
+                return Err(format!("Function '{}' jumps to bb{} which doesn't exist", parser.function.name, label));
+            }
+            module.functions.push(parser.function);
+            continue;
+        }
+        if first == "local" {
+            if block.is_some() {return Err(format!("Locals have to come before the first block, line {}", line.number))}
+            let name = line.next()?.to_string();
+            line.expect(":")?;
+            let ty = parse_type(line.next()?)?;
+            line.done()?;
+            parser.function.locals.push(Local { name, ty });
+            continue;
+        }
+        if first.starts_with("bb") && line.peek() == Some(":") {
+            line.next()?;
+            line.done()?;
+            if block.is_some() && !terminated {return Err(format!("Block doesn't end in a terminator before line {}", line.number))}
+            if block.is_none() && parse_number(&first, "bb")? != 0 {
+                return Err(format!("The first block has to be bb0, line {}", line.number));
+            }
+            let index = parser.block(&first)?;
+            if parser.defined[index] {return Err(format!("Block {} defined twice, line {}", first, line.number))}
+            parser.defined[index] = true;
+            block = Some(index);
+            terminated = false;
+            continue;
+        }
+
+        let Some(current_block) = block else {return Err(format!("Instruction outside of a block on line {}", line.number))};
+        if terminated {return Err(format!("Instruction after a terminator on line {}", line.number))}
+
+        // Terminators
+        let terminator = match first.as_str() {
+            "jump" => Some(Terminator::Jump(parser.block(line.next()?)?)),
+            "branch" => {
+                let condition = line.operand()?;
+                line.expect(",")?;
+                let then_block = parser.block(line.next()?)?;
+                line.expect(",")?;
+                let else_block = parser.block(line.next()?)?;
+                Some(Terminator::Branch { condition, then_block, else_block })
+            }
+            "ret" => Some(Terminator::Return(if line.peek().is_some() {Some(line.operand()?)} else {None})),
+            _ => None,
+        };
+        if let Some(terminator) = terminator {
+            line.done()?;
+            parser.function.blocks[current_block].terminator = terminator;
+            terminated = true;
+            continue;
+        }
+
+        // Instructions, `%n: type = ...` or the ones without a destination
+        let mut dest = None;
+        let mut opcode = first.clone();
+        if first.starts_with('%') {
+            let register = parse_number(&first, "%")?;
+            line.expect(":")?;
+            let ty = parse_type(line.next()?)?;
+            line.expect("=")?;
+            parser.define(register, ty);
+            dest = Some(register);
+            opcode = line.next()?.to_string();
+        }
+        let need_dest = |dest: Option<usize>| dest.ok_or(format!("'{}' needs a destination register on line {}", opcode, line.number));
+        let instruction = match opcode.as_str() {
+            "copy" => Instruction::Copy { dest: need_dest(dest)?, value: line.operand()? },
+            "string" => Instruction::StringAddress { dest: need_dest(dest)?, index: parse_number(line.next()?, "s")? },
+            "load" => {
+                let dest = need_dest(dest)?;
+                let local = parser.local(line.next()?, line.number)?;
+                Instruction::Load { dest, local }
+            }
+            "store" => {
+                let local = parser.local(line.next()?, line.number)?;
+                line.expect(",")?;
+                Instruction::Store { local, value: line.operand()? }
+            }
+            "asm" => {
+                let volatile = line.peek() == Some("volatile");
+                if volatile {line.next()?;}
+                let template = line.next()?;
+                if !template.starts_with('"') {return Err(format!("Expected the asm template string on line {}", line.number))}
+                let template = unescape(template)?;
+                let (mut inputs, mut outputs, mut clobbers) = (vec![], vec![], vec![]);
+                for section in ["in", "out", "clobber"] {
+                    if line.peek() != Some(section) {continue}
+                    line.next()?;
+                    line.expect("(")?;
+                    while line.peek() != Some(")") {
+                        match section {
+                            "clobber" => {
+                                let clobber = line.next()?;
+                                if !clobber.starts_with('"') {return Err(format!("Expected a clobber string on line {}", line.number))}
+                                clobbers.push(unescape(clobber)?);
+                            }
+                            _ => {
+                                let name = line.next()?.to_string();
+                                line.expect("=")?;
+                                if section == "in" {inputs.push((name, line.operand()?))}
+                                else {outputs.push((name, parser.local(line.next()?, line.number)?))}
+                            }
+                        }
+                        if line.peek() == Some(",") {line.next()?;}
+                    }
+                    line.expect(")")?;
+                }
+                Instruction::Asm { template, inputs, outputs, clobbers, volatile }
+            }
+            "line" => {
+                let file = parse_number(line.next()?, "f")?;
+                let number = |line: &mut Line| -> Result<usize, String> {
+                    let token = line.next()?;
+                    token.parse().map_err(|_| format!("Expected a number, not '{}' on line {}", token, line.number))
+                };
+                let source_line = number(&mut line)?;
+                line.expect(":")?;
+                Instruction::Line { file, line: source_line, column: number(&mut line)? }
+            }
+            "call" => {
+                let function = line.next()?.to_string();
+                line.expect("(")?;
+                let mut args = vec![];
+                while line.peek() != Some(")") {
+                    args.push(line.operand()?);
+                    if line.peek() == Some(",") {line.next()?;}
+                }
+                line.expect(")")?;
+                Instruction::Call { dest, function, args }
+            }
+            op => match BinaryOp::from_name(op) {
+                Some(op) => {
+                    let dest = need_dest(dest)?;
+                    let left = line.operand()?;
+                    line.expect(",")?;
+                    Instruction::Binary { dest, op, left, right: line.operand()? }
+                }
+                None => return Err(format!("Unknown instruction '{}' on line {}", op, line.number)),
+            },
+        };
+        line.done()?;
+        parser.function.blocks[current_block].instructions.push(instruction);
+    }
+
+    if let Some(parser) = current {
+        return Err(format!("Function '{}' is missing its closing '}}'", parser.function.name));
+    }
+    verify(&module)?;
+    Ok(module)
+}
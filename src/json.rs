@@ -0,0 +1,278 @@
+use std::fmt::Write;
+
+// Just enough JSON for the language server (lsp.rs): parsing what the editor sends and
+// printing what goes back. Numbers are f64 like in JavaScript, LSP only ever uses small
+// integers anyway.
+
+//
+// STRUCTS
+//
+
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    /// Keys stay in the order they came in (or were put in)
+    Object(Vec<(String, Json)>),
+}
+
+struct Parser<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+    /// Arrays and objects the cursor is in
+    depth: usize,
+}
+
+/// How deep arrays and objects can nest, each level is a recursion. LSP never goes past a
+/// handful
+const MAX_DEPTH: usize = 128;
+
+//
+// FUNCTIONS
+//
+
+impl Json {
+    /// Builds an object out of `("key", value)` pairs
+    pub fn object(entries: Vec<(&str, Json)>) -> Json {
+        Json::Object(entries.into_iter().map(|(key, value)| (key.to_string(), value)).collect())
+    }
+
+    pub fn string(value: &str) -> Json {
+        Json::String(value.to_string())
+    }
+
+    /// Value under `key` of an object, Null for anything missing
+    pub fn get(&self, key: &str) -> &Json {
+        match self {
+            Json::Object(entries) => entries.iter().find(|(name, _)| name == key).map(|(_, value)| value).unwrap_or(&Json::Null),
+            _ => &Json::Null,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn as_usize(&self) -> Option<usize> {
+        match self {
+            Json::Number(value) if *value >= 0.0 && value.fract() == 0.0 => Some(*value as usize),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> &[Json] {
+        match self {
+            Json::Array(values) => values,
+            _ => &[],
+        }
+    }
+
+    /// Like Display, but one entry per line and indented by two spaces, for files people read
+    pub fn pretty(&self) -> String {
+        let mut out = String::new();
+        self.write_pretty(&mut out, 0);
+        out
+    }
+
+    fn write_pretty(&self, out: &mut String, level: usize) {
+        let (entries, open, close): (Vec<(Option<&str>, &Json)>, char, char) = match self {
+            Json::Array(values) if !values.is_empty() => (values.iter().map(|value| (None, value)).collect(), '[', ']'),
+            Json::Object(entries) if !entries.is_empty() => (entries.iter().map(|(key, value)| (Some(key.as_str()), value)).collect(), '{', '}'),
+            other => return out.push_str(&other.to_string()),
+        };
+        out.push(open);
+        for (index, (key, value)) in entries.iter().enumerate() {
+            if index > 0 {out.push(',')}
+            out.push('\n');
+            out.push_str(&"  ".repeat(level + 1));
+            if let Some(key) = key {out.push_str(&format!("{}: ", Json::string(key)))}
+            value.write_pretty(out, level + 1);
+        }
+        out.push('\n');
+        out.push_str(&"  ".repeat(level));
+        out.push(close);
+    }
+}
+
+impl From<bool> for Json {
+    fn from(value: bool) -> Json {Json::Bool(value)}
+}
+
+impl From<usize> for Json {
+    fn from(value: usize) -> Json {Json::Number(value as f64)}
+}
+
+impl std::fmt::Display for Json {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(value) => write!(f, "{}", value),
+            Json::Number(value) if value.fract() == 0.0 && value.abs() < 1e15 => write!(f, "{}", *value as i64),
+            Json::Number(value) => write!(f, "{}", value),
+            Json::String(value) => {
+                f.write_char('"')?;
+                for c in value.chars() {
+                    match c {
+                        '"' => f.write_str("\\\"")?,
+                        '\\' => f.write_str("\\\\")?,
+                        '\n' => f.write_str("\\n")?,
+                        '\r' => f.write_str("\\r")?,
+                        '\t' => f.write_str("\\t")?,
+                        c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+                        c => f.write_char(c)?,
+                    }
+                }
+                f.write_char('"')
+            }
+            Json::Array(values) => {
+                f.write_char('[')?;
+                for (index, value) in values.iter().enumerate() {
+                    if index > 0 {f.write_char(',')?}
+                    write!(f, "{}", value)?;
+                }
+                f.write_char(']')
+            }
+            Json::Object(entries) => {
+                f.write_char('{')?;
+                for (index, (key, value)) in entries.iter().enumerate() {
+                    if index > 0 {f.write_char(',')?}
+                    write!(f, "{}:{}", Json::String(key.clone()), value)?;
+                }
+                f.write_char('}')
+            }
+        }
+    }
+}
+
+impl Parser<'_> {
+    fn skip_whitespace(&mut self) {
+        while self.chars.next_if(|c| c.is_ascii_whitespace()).is_some() {}
+    }
+
+    fn expect(&mut self, expected: &str) -> Result<(), String> {
+        for c in expected.chars() {
+            if self.chars.next() != Some(c) {return Err(format!("Expected '{}'", expected))}
+        }
+        Ok(())
+    }
+
+    fn value(&mut self) -> Result<Json, String> {
+        self.skip_whitespace();
+        if matches!(self.chars.peek(), Some('[' | '{')) && self.depth == MAX_DEPTH {
+            return Err(format!("Nested more than {} levels deep", MAX_DEPTH));
+        }
+        match self.chars.peek() {
+            Some('n') => self.expect("null").map(|_| Json::Null),
+            Some('t') => self.expect("true").map(|_| Json::Bool(true)),
+            Some('f') => self.expect("false").map(|_| Json::Bool(false)),
+            Some('"') => self.string().map(Json::String),
+            Some('[') => {
+                self.chars.next();
+                let mut values = vec![];
+                self.skip_whitespace();
+                if self.chars.next_if_eq(&']').is_some() {return Ok(Json::Array(values))}
+                self.depth += 1;
+                loop {
+                    values.push(self.value()?);
+                    self.skip_whitespace();
+                    match self.chars.next() {
+                        Some(',') => {}
+                        Some(']') => {
+                            self.depth -= 1;
+                            return Ok(Json::Array(values));
+                        }
+                        _ => return Err("Expected ',' or ']' in an array".to_string()),
+                    }
+                }
+            }
+            Some('{') => {
+                self.chars.next();
+                let mut entries = vec![];
+                self.skip_whitespace();
+                if self.chars.next_if_eq(&'}').is_some() {return Ok(Json::Object(entries))}
+                self.depth += 1;
+                loop {
+                    self.skip_whitespace();
+                    if self.chars.peek() != Some(&'"') {return Err("Expected a key in an object".to_string())}
+                    let key = self.string()?;
+                    self.skip_whitespace();
+                    self.expect(":")?;
+                    entries.push((key, self.value()?));
+                    self.skip_whitespace();
+                    match self.chars.next() {
+                        Some(',') => {}
+                        Some('}') => {
+                            self.depth -= 1;
+                            return Ok(Json::Object(entries));
+                        }
+                        _ => return Err("Expected ',' or '}' in an object".to_string()),
+                    }
+                }
+            }
+            Some(c) if *c == '-' || c.is_ascii_digit() => {
+                let mut number = String::new();
+                while let Some(c) = self.chars.next_if(|c| c.is_ascii_digit() || ['-', '+', '.', 'e', 'E'].contains(c)) {
+                    number.push(c);
+                }
+                number.parse().map(Json::Number).map_err(|_| format!("Invalid number '{}'", number))
+            }
+            Some(c) => Err(format!("Unexpected '{}'", c)),
+            None => Err("Unexpected end of input".to_string()),
+        }
+    }
+
+    /// A string literal, the cursor is on the opening quote
+    fn string(&mut self) -> Result<String, String> {
+        self.chars.next();
+        let mut out = String::new();
+        loop {
+            match self.chars.next() {
+                Some('"') => return Ok(out),
+                Some('\\') => match self.chars.next() {
+                    Some('n') => out.push('\n'),
+                    Some('r') => out.push('\r'),
+                    Some('t') => out.push('\t'),
+                    Some('b') => out.push('\u{8}'),
+                    Some('f') => out.push('\u{c}'),
+                    Some('u') => {
+                        let mut code = self.hex4()?;
+                        // Surrogate pairs, anything broken becomes U+FFFD
+                        if (0xd800..0xdc00).contains(&code) && self.chars.next_if_eq(&'\\').is_some() {
+                            self.expect("u")?;
+                            let low = self.hex4()?;
+                            code = 0x10000 + ((code - 0xd800) << 10) + low.wrapping_sub(0xdc00);
+                        }
+                        out.push(char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER));
+                    }
+                    Some(c) => out.push(c),
+                    None => return Err("Unterminated string".to_string()),
+                },
+                Some(c) => out.push(c),
+                None => return Err("Unterminated string".to_string()),
+            }
+        }
+    }
+
+    fn hex4(&mut self) -> Result<u32, String> {
+        let digits: String = (0..4).filter_map(|_| self.chars.next()).collect();
+        u32::from_str_radix(&digits, 16).map_err(|_| format!("Invalid escape '\\u{}'", digits))
+    }
+}
+
+/// Parses one JSON value, nothing but whitespace can come after it
+pub fn parse(text: &str) -> Result<Json, String> {
+    let mut parser = Parser { chars: text.chars().peekable(), depth: 0 };
+    let value = parser.value()?;
+    parser.skip_whitespace();
+    match parser.chars.next() {
+        Some(c) => Err(format!("Unexpected '{}' after the value", c)),
+        None => Ok(value),
+    }
+}
@@ -1,4 +1,5 @@
-use crate::compiler_settings::{CLOSED_BRACES, KEYWORDS, LEX_DEBUG_PRINTS, LINE_SPLITTER, OPEN_BRACES, WHITESPACE};
+use crate::compiler_settings::{debug_prints, CLOSED_BRACES, ESCAPES, KEYWORDS, LEX_DEBUG_PRINTS, LINE_SPLITTER, OPEN_BRACES, OPERATORS, WHITESPACE};
+use crate::source_map::FileId;
 use std::iter::Peekable;
 
 
@@ -20,6 +21,8 @@ pub enum LexSymbol {
     Dot,
     DoubleDot,
     Comma,
+    /// `#[name]` or `#![name]`, the value is everything between the brackets (with the `!`)
+    Attribute,
     EOF,
 }
 
@@ -29,23 +32,27 @@ pub enum LexSymbol {
 pub struct Lexeme { 
     pub symbol: LexSymbol,
     pub value: String,
-    pub location: (usize, usize), // LINE : CHARACTER
+    pub location: Location, // Where the lexeme starts
 }
 impl Lexeme {
-    pub fn new(symbol: LexSymbol, value: String, location: (usize, usize)) -> Self {Lexeme{symbol:symbol, value:value, location: location}}
+    pub fn new(symbol: LexSymbol, value: String, location: Location) -> Self {Lexeme{symbol:symbol, value:value, location: location}}
 }
 
+/// Position in the source, LINE : CHARACTER (both start from 1), and the file it's in
+pub type Location = (usize, usize, FileId);
+
 /// Takes in a peekable chars iterator, returns with the next possible Lexeme.
 /// Keep running it until iterator runs out to get out all Lexemes.
-fn lex_token(chars: &mut Peekable<impl Iterator<Item = char>>, loc: &mut (usize, usize)) -> Option<Lexeme> {
+fn lex_token(chars: &mut Peekable<impl Iterator<Item = char>>, loc: &mut (usize, usize), file: FileId) -> Option<Lexeme> {
     while let Some(&c) = chars.peek() {
         // Token is whitespace, ignore
         if WHITESPACE.contains(&c) { 
-            if &c == &'\n' {*loc = (loc.0 + 1, 1 as usize)} 
+            if c == '\n' {*loc = (loc.0 + 1, 1)} 
             else {*loc = (loc.0, loc.1 + 1)}
             chars.next(); // Go to next char
             continue;
         }
+        let start = (loc.0, loc.1, file);
 
         // Identifier or keyword
         if c.is_ascii_alphabetic() || c == '_' {
@@ -60,9 +67,9 @@ fn lex_token(chars: &mut Peekable<impl Iterator<Item = char>>, loc: &mut (usize,
                 }
             }
             if KEYWORDS.contains(&ident.as_str()) {
-                return Some(Lexeme::new(LexSymbol::Keyword, ident, *loc));
+                return Some(Lexeme::new(LexSymbol::Keyword, ident, start));
             } else {
-                return Some(Lexeme::new(LexSymbol::Identifier, ident, *loc));
+                return Some(Lexeme::new(LexSymbol::Identifier, ident, start));
             }
         }
 
@@ -70,11 +77,7 @@ fn lex_token(chars: &mut Peekable<impl Iterator<Item = char>>, loc: &mut (usize,
         if c.is_ascii_digit() {
             let mut num = String::new();
             while let Some(&ch) = chars.peek() {
-                if ch.is_ascii_digit() {
-                    num.push(ch);
-                    *loc = (loc.0, loc.1 + 1);
-                    chars.next();
-                } else if ch == '.' {
+                if ch.is_ascii_digit() || ch == '.' {
                     num.push(ch);
                     *loc = (loc.0, loc.1 + 1);
                     chars.next();
@@ -82,7 +85,7 @@ fn lex_token(chars: &mut Peekable<impl Iterator<Item = char>>, loc: &mut (usize,
                     break;
                 }
             }
-            return Some(Lexeme::new(LexSymbol::Integer, num, *loc));
+            return Some(Lexeme::new(LexSymbol::Integer, num, start));
         }
 
         // String literal
@@ -91,142 +94,100 @@ fn lex_token(chars: &mut Peekable<impl Iterator<Item = char>>, loc: &mut (usize,
             chars.next(); // consume opening quote
             let mut val = String::new();
             while let Some(ch) = chars.next() {
-                *loc = (loc.0, loc.1 + 1);
+                if ch == '\n' {*loc = (loc.0 + 1, 1)}
+                else {*loc = (loc.0, loc.1 + 1)}
                 if ch == '"' {
                     break;
                 }
+                // Escapes, anything else after a backslash stays as it is
+                if ch == '\\' && let Some(&(_, escaped)) = chars.peek().and_then(|next| ESCAPES.iter().find(|(written, _)| written == next)) {
+                    chars.next();
+                    *loc = (loc.0, loc.1 + 1);
+                    val.push(escaped);
+                    continue;
+                }
                 val.push(ch);
             }
-            return Some(Lexeme::new(LexSymbol::String, val, *loc));
+            return Some(Lexeme::new(LexSymbol::String, val, start));
         }
     
         // Braces
         if OPEN_BRACES.contains(&c) { // TODO: This brace setup is stupid, make it better
-            if c == '(' {chars.next(); *loc = (loc.0, loc.1 + 1); return Some(Lexeme::new(LexSymbol::GenericOpeningBracket, c.to_string(), *loc));}
-            else if c == '{' {chars.next(); *loc = (loc.0, loc.1 + 1); return Some(Lexeme::new(LexSymbol::FunctionOpeningBracket, c.to_string(), *loc));}
+            if c == '(' {chars.next(); *loc = (loc.0, loc.1 + 1); return Some(Lexeme::new(LexSymbol::GenericOpeningBracket, c.to_string(), start));}
+            else if c == '{' {chars.next(); *loc = (loc.0, loc.1 + 1); return Some(Lexeme::new(LexSymbol::FunctionOpeningBracket, c.to_string(), start));}
         }
         if CLOSED_BRACES.contains(&c) {
-            if c == ')' {chars.next(); *loc = (loc.0, loc.1 + 1); return Some(Lexeme::new(LexSymbol::GenericClosingBracket, c.to_string(), *loc));}
-            else if c == '}' {chars.next(); *loc = (loc.0, loc.1 + 1); return Some(Lexeme::new(LexSymbol::FunctionClosingBracket, c.to_string(), *loc));}
+            if c == ')' {chars.next(); *loc = (loc.0, loc.1 + 1); return Some(Lexeme::new(LexSymbol::GenericClosingBracket, c.to_string(), start));}
+            else if c == '}' {chars.next(); *loc = (loc.0, loc.1 + 1); return Some(Lexeme::new(LexSymbol::FunctionClosingBracket, c.to_string(), start));}
         } 
     
         // Line splitter
         if c == LINE_SPLITTER {
             chars.next();
             *loc = (loc.0, loc.1 + 1);
-            return Some(Lexeme::new(LexSymbol::EndLine, LINE_SPLITTER.to_string(), *loc))
+            return Some(Lexeme::new(LexSymbol::EndLine, LINE_SPLITTER.to_string(), start))
         }
 
-        // Operational Symbols and GT/LT Math Symbols
-        if c == '=' || c == '!' || c == '<' || c == '>' {
-            // I LOVE MASSIVE READ TABLES MMMMMMMMM
-            match c {
-                '=' => {
-                    chars.next();
-                    *loc = (loc.0, loc.1 + 1);
-                    let c = chars.peek().unwrap(); // FIXME: Unwrap :(
-                    match c {                             // Fix the ones below too
-                        '=' => {
-                            chars.next();
-                            *loc = (loc.0, loc.1 + 1);
-                            return Some(Lexeme::new(LexSymbol::OperationalSymbol, "==".to_string(), *loc))
-                        }
-                        '>' => {
-                            chars.next();
-                            *loc = (loc.0, loc.1 + 1);
-                            return Some(Lexeme::new(LexSymbol::OperationalSymbol, ">=".to_string(), *loc))
-                        }
-                        '<' => {
-                            chars.next();
-                            *loc = (loc.0, loc.1 + 1);
-                            return Some(Lexeme::new(LexSymbol::OperationalSymbol, "<=".to_string(), *loc))
-                        }
-                        '!' => {
-                            chars.next();
-                            *loc = (loc.0, loc.1 + 1);
-                            return Some(Lexeme::new(LexSymbol::OperationalSymbol, "!=".to_string(), *loc))
-                        }
-                        _ => {
-                            return Some(Lexeme::new(LexSymbol::EqualSign, "=".to_string(), *loc))
-                        }
-                    }
-                },
-                '!' => {
-                    chars.next();
-                    *loc = (loc.0, loc.1 + 1);
-                    let c = chars.peek().unwrap();
-                    match c {
-                        '=' => {
-                            chars.next();
-                            *loc = (loc.0, loc.1 + 1);
-                            return Some(Lexeme::new(LexSymbol::OperationalSymbol, "!=".to_string(), *loc))
-                        }
-                        _ => {
-                            continue;
-                        }
-                        
-                    }
-                },
-                '<' => {
-                    chars.next();
-                    *loc = (loc.0, loc.1 + 1);
-                    let c = chars.peek().unwrap();
-                    match c {
-                        '=' => {
-                            chars.next();
-                            *loc = (loc.0, loc.1 + 1);
-                            return Some(Lexeme::new(LexSymbol::OperationalSymbol, "<=".to_string(), *loc))
-                        }
-                        _ => {
-                            return Some(Lexeme::new(LexSymbol::OperationalSymbol, "<".to_string(), *loc))
-                        }
-                    }
-                },
-                '>' => {
-                    chars.next();
-                    *loc = (loc.0, loc.1 + 1);
-                    let c = chars.peek().unwrap();
-                    match c {
-                        '=' => {
-                            chars.next();
-                            *loc = (loc.0, loc.1 + 1);
-                            return Some(Lexeme::new(LexSymbol::OperationalSymbol, ">=".to_string(), *loc))
-                        }
-                        _ => {
-                            return Some(Lexeme::new(LexSymbol::OperationalSymbol, ">".to_string(), *loc))
-                        }
-                    }
-                },
-                _ => {chars.next(); *loc = (loc.0, loc.1 + 1); continue;} // Should never happen
-            }
-        }
-
-        // Rest of the OperationalSymbols
-        if c == '+' || c == '-' || c == '*' || c == '/' {
+        // Operators, two characters when OPERATORS has them
+        if OPERATORS.iter().any(|(spelling, _)| spelling.starts_with(c)) {
             chars.next();
             *loc = (loc.0, loc.1 + 1);
-            return Some(Lexeme::new(LexSymbol::OperationalSymbol, c.to_string(), *loc))
+            // `//` is a comment until the end of the line instead
+            if c == '/' && chars.peek() == Some(&'/') {
+                while chars.next_if(|ch| *ch != '\n').is_some() {}
+                continue;
+            }
+            let next = chars.peek().copied().unwrap_or_default(); // The end of the file is a \0
+            if let Some((_, value)) = OPERATORS.iter().find(|(spelling, _)| *spelling == format!("{}{}", c, next)) {
+                chars.next();
+                *loc = (loc.0, loc.1 + 1);
+                return Some(Lexeme::new(LexSymbol::OperationalSymbol, value.to_string(), start))
+            }
+            match OPERATORS.iter().find(|(spelling, _)| *spelling == c.to_string()) {
+                Some(("=", _)) => return Some(Lexeme::new(LexSymbol::EqualSign, "=".to_string(), start)),
+                Some((_, value)) => return Some(Lexeme::new(LexSymbol::OperationalSymbol, value.to_string(), start)),
+                None => continue, // A `!` without a `=`
+            }
         }
 
         // Dot
         if c == '.' {
             chars.next();
             *loc = (loc.0, loc.1 + 1);
-            return Some(Lexeme::new(LexSymbol::Dot, '.'.to_string(), *loc))
+            return Some(Lexeme::new(LexSymbol::Dot, '.'.to_string(), start))
         }
 
         // Comma
         if c == ',' {
             chars.next();
             *loc = (loc.0, loc.1 + 1);
-            return Some(Lexeme::new(LexSymbol::Comma, ','.to_string(), *loc))
+            return Some(Lexeme::new(LexSymbol::Comma, ','.to_string(), start))
         }
 
         // Double dot ( : )
         if c == ':' {
             chars.next();
             *loc = (loc.0, loc.1 + 1);
-            return Some(Lexeme::new(LexSymbol::DoubleDot, ":".to_string(), *loc))
+            return Some(Lexeme::new(LexSymbol::DoubleDot, ":".to_string(), start))
+        }
+
+        // Attributes, `#[interrupt]`, `#![no_std]`, ...
+        if c == '#' {
+            chars.next();
+            *loc = (loc.0, loc.1 + 1);
+            let mut value = String::new();
+            if chars.next_if_eq(&'!').is_some() {value.push('!'); *loc = (loc.0, loc.1 + 1)}
+            if chars.next_if_eq(&'[').is_none() {continue}
+            *loc = (loc.0, loc.1 + 1);
+            let mut quoted = false;
+            for ch in chars.by_ref() {
+                if ch == '\n' {*loc = (loc.0 + 1, 1)}
+                else {*loc = (loc.0, loc.1 + 1)}
+                if ch == ']' && !quoted {break}
+                if ch == '"' {quoted = !quoted}
+                value.push(ch);
+            }
+            return Some(Lexeme::new(LexSymbol::Attribute, value, start))
         }
 
         // Unrecognized: skip
@@ -239,18 +200,28 @@ fn lex_token(chars: &mut Peekable<impl Iterator<Item = char>>, loc: &mut (usize,
 
 /// Takes string, returns Vec<LexSm>
 pub fn lexer(content: &str) -> Vec<Lexeme> {
-    if LEX_DEBUG_PRINTS {println!("- - - LEXER")}
+    lexer_in_file(content, 0)
+}
+
+/// `lexer()` for a file in the source map, the lexemes' locations point at `file`. The last
+/// lexeme is always an EOF.
+pub fn lexer_in_file(content: &str, file: FileId) -> Vec<Lexeme> {
+    if debug_prints(LEX_DEBUG_PRINTS) {eprintln!("- - - LEXER")}
 
     // Main lexer loop
     let mut chars = content.chars().peekable();
     let mut tokens = Vec::new();
-    let mut location_tracker: (usize, usize) = (1, 1);
-    while let Some(token) = lex_token(&mut chars, &mut location_tracker) {
+    let mut location_tracker = (1, 1);
+    // Right after the last lexeme, where errors about the input ending too early point
+    let mut end = location_tracker;
+    while let Some(token) = lex_token(&mut chars, &mut location_tracker, file) {
         tokens.push(token);
+        end = location_tracker;
     }
+    tokens.push(Lexeme::new(LexSymbol::EOF, String::new(), (end.0, end.1, file)));
 
-    if LEX_DEBUG_PRINTS {println!("LEXED TOKENS:\n{:#?}", tokens)}
+    if debug_prints(LEX_DEBUG_PRINTS) {eprintln!("LEXED TOKENS:\n{:#?}", tokens)}
 
-    if LEX_DEBUG_PRINTS {println!("- - - Lexer done!")}
+    if debug_prints(LEX_DEBUG_PRINTS) {eprintln!("- - - Lexer done!")}
     return tokens;
 }
\ No newline at end of file
@@ -0,0 +1,534 @@
+use std::collections::HashMap;
+
+use crate::compiler_settings::*;
+
+// Linker scripts, for freestanding executables. A small subset of GNU ld's format: ENTRY,
+// MEMORY regions, and SECTIONS with input section patterns (KEEP, wildcards), NOLOAD, `> REGION`
+// and symbol/location counter assignments with ORIGIN, LENGTH and ALIGN. Enough to place code
+// at the addresses a microcontroller wants it at.
+// Without -T the compiler generates one (`generate()`), --emit=ld writes it out so the .o or .s
+// can be linked by GNU ld the same way the built-in linker (elf.rs) does it.
+// Galvan has no writable data, so there's no .data to copy around and AT> is ignored.
+
+//
+// STRUCTS
+//
+
+#[derive(Debug)]
+#[derive(Clone)]
+enum Expr {
+    Number(u64),
+    /// `.`, the location counter
+    Dot,
+    Symbol(String),
+    Origin(String),
+    Length(String),
+    /// `ALIGN(n)` is `ALIGN(., n)`
+    Align(Box<Expr>, Box<Expr>),
+    Binary(char, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug)]
+#[derive(Clone)]
+enum Command {
+    /// `*(.text .text*)`, or in a `KEEP(...)` (everything is kept anyway)
+    Input(Vec<String>),
+    /// `symbol = expr;`, `.` moves the location counter
+    Assign(String, Expr),
+}
+
+#[derive(Debug)]
+#[derive(Clone)]
+struct OutputSection {
+    name: String,
+    noload: bool,
+    commands: Vec<Command>,
+    region: Option<String>,
+}
+
+#[derive(Debug)]
+#[derive(Clone)]
+enum Item {
+    Section(OutputSection),
+    Assign(String, Expr),
+}
+
+#[derive(Debug)]
+#[derive(Clone)]
+pub struct Region {
+    pub name: String,
+    pub origin: u64,
+    pub length: u64,
+}
+
+#[derive(Debug)]
+#[derive(Clone)]
+pub struct LinkerScript {
+    pub entry: Option<String>,
+    pub memory: Vec<Region>,
+    items: Vec<Item>,
+    /// Assignments outside of SECTIONS, done after everything is placed
+    assignments: Vec<(String, Expr)>,
+}
+
+/// An input section for `link()`
+pub struct Input {
+    pub name: String,
+    pub size: u64,
+    pub align: u64,
+}
+
+/// Where an output section ended up, `inputs` are indices into the inputs
+#[derive(Debug)]
+pub struct Placed {
+    pub name: String,
+    pub address: u64,
+    pub size: u64,
+    pub noload: bool,
+    pub inputs: Vec<usize>,
+}
+
+/// Result of `link()`
+#[derive(Debug)]
+pub struct Layout {
+    pub sections: Vec<Placed>,
+    /// Address of every input
+    pub addresses: Vec<u64>,
+    /// Symbols the script defined
+    pub symbols: HashMap<String, u64>,
+}
+
+struct Parser {
+    tokens: Vec<(String, usize)>,
+    position: usize,
+}
+
+//
+// FUNCTIONS
+//
+
+/// `0x20000000`, `128K`, `1M` or a plain number
+pub fn parse_size(text: &str) -> Option<u64> {
+    let (number, multiplier) = match text.strip_suffix(['K', 'k']) {
+        Some(number) => (number, 1024),
+        None => match text.strip_suffix(['M', 'm']) {
+            Some(number) => (number, 1024 * 1024),
+            None => (text, 1),
+        },
+    };
+    let value = match number.strip_prefix("0x").or_else(|| number.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16).ok()?,
+        None => number.parse().ok()?,
+    };
+    value.checked_mul(multiplier)
+}
+
+fn align(value: u64, alignment: u64) -> u64 {
+    if alignment <= 1 {value} else {value.div_ceil(alignment) * alignment}
+}
+
+/// Tokens and the line they're on. Words take `.` and `*` too, so `.text*` is one token.
+fn tokenize(text: &str) -> Result<Vec<(String, usize)>, String> {
+    let mut tokens = vec![];
+    let mut line = 1;
+    let mut chars = text.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c == '\n' {line += 1}
+        if c.is_whitespace() {chars.next(); continue}
+        if c == '/' {
+            chars.next();
+            if chars.next_if_eq(&'*').is_none() {tokens.push(("/".to_string(), line)); continue}
+            let mut last = ' ';
+            loop {
+                match chars.next() {
+                    Some('/') if last == '*' => break,
+                    Some(c) => {if c == '\n' {line += 1} last = c}
+                    None => return Err(format!("Unclosed comment in linker script on line {}", line)),
+                }
+            }
+            continue;
+        }
+        if c.is_ascii_alphanumeric() || "_.*$".contains(c) {
+            let mut word = String::new();
+            while let Some(c) = chars.next_if(|c| c.is_ascii_alphanumeric() || "_.*$".contains(*c)) {
+                word.push(c);
+            }
+            tokens.push((word, line));
+            continue;
+        }
+        chars.next();
+        tokens.push((c.to_string(), line));
+    }
+    Ok(tokens)
+}
+
+impl Parser {
+    fn peek(&self) -> &str {
+        self.tokens.get(self.position).map(|(token, _)| token.as_str()).unwrap_or("")
+    }
+
+    fn line(&self) -> usize {
+        self.tokens.get(self.position).or(self.tokens.last()).map(|(_, line)| *line).unwrap_or(1)
+    }
+
+    fn next(&mut self) -> Result<String, String> {
+        match self.tokens.get(self.position) {
+            Some((token, _)) => {self.position += 1; Ok(token.clone())}
+            None => Err("Linker script ends too early".to_string()),
+        }
+    }
+
+    fn expect(&mut self, expectation: &str) -> Result<(), String> {
+        let line = self.line();
+        let token = self.next()?;
+        if token != expectation {return Err(format!("Expected '{}', not '{}' on line {} of the linker script", expectation, token, line))}
+        Ok(())
+    }
+
+    fn primary(&mut self) -> Result<Expr, String> {
+        let line = self.line();
+        let token = self.next()?;
+        let argument = |parser: &mut Parser| -> Result<String, String> {
+            parser.expect("(")?;
+            let name = parser.next()?;
+            parser.expect(")")?;
+            Ok(name)
+        };
+        Ok(match token.as_str() {
+            "(" => {
+                let inner = self.expression()?;
+                self.expect(")")?;
+                inner
+            }
+            "." => Expr::Dot,
+            "ORIGIN" | "ORG" => Expr::Origin(argument(self)?),
+            "LENGTH" | "LEN" => Expr::Length(argument(self)?),
+            "ALIGN" => {
+                self.expect("(")?;
+                let first = self.expression()?;
+                let aligned = if self.peek() == "," {
+                    self.next()?;
+                    Expr::Align(Box::new(first), Box::new(self.expression()?))
+                } else {
+                    Expr::Align(Box::new(Expr::Dot), Box::new(first))
+                };
+                self.expect(")")?;
+                aligned
+            }
+            _ if token.starts_with(|c: char| c.is_ascii_digit()) => match parse_size(&token) {
+                Some(value) => Expr::Number(value),
+                None => return Err(format!("Invalid number '{}' on line {} of the linker script", token, line)),
+            },
+            _ if token.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') => Expr::Symbol(token),
+            _ => return Err(format!("Expected an expression, not '{}' on line {} of the linker script", token, line)),
+        })
+    }
+
+    fn term(&mut self) -> Result<Expr, String> {
+        let mut left = self.primary()?;
+        while ["*", "/"].contains(&self.peek()) {
+            let operator = self.next()?.chars().next().unwrap();
+            left = Expr::Binary(operator, Box::new(left), Box::new(self.primary()?));
+        }
+        Ok(left)
+    }
+
+    fn expression(&mut self) -> Result<Expr, String> {
+        let mut left = self.term()?;
+        while ["+", "-"].contains(&self.peek()) {
+            let operator = self.next()?.chars().next().unwrap();
+            left = Expr::Binary(operator, Box::new(left), Box::new(self.term()?));
+        }
+        Ok(left)
+    }
+
+    /// `name = expr;`, after the name
+    fn assignment(&mut self, name: String) -> Result<(String, Expr), String> {
+        self.expect("=")?;
+        let value = self.expression()?;
+        self.expect(";")?;
+        Ok((name, value))
+    }
+
+    /// `*(.text .text*)`, after the file pattern
+    fn input(&mut self) -> Result<Vec<String>, String> {
+        self.expect("(")?;
+        let mut patterns = vec![];
+        while self.peek() != ")" {
+            patterns.push(self.next()?);
+        }
+        self.next()?;
+        Ok(patterns)
+    }
+
+    /// `name = ORIGIN = x, LENGTH = y` after the name, `(rwx)` is skipped
+    fn region(&mut self, name: String) -> Result<Region, String> {
+        if self.peek() == "(" {
+            while self.next()? != ")" {}
+        }
+        self.expect(":")?;
+        let mut origin = None;
+        let mut length = None;
+        for index in 0..2 {
+            if index == 1 {self.expect(",")?}
+            let line = self.line();
+            let key = self.next()?;
+            self.expect("=")?;
+            let value = evaluate(&self.expression()?, 0, &HashMap::new(), &[])?;
+            match key.as_str() {
+                "ORIGIN" | "ORG" | "o" | "org" => origin = Some(value),
+                "LENGTH" | "LEN" | "l" | "len" => length = Some(value),
+                _ => return Err(format!("Expected ORIGIN or LENGTH, not '{}' on line {} of the linker script", key, line)),
+            }
+        }
+        match (origin, length) {
+            (Some(origin), Some(length)) => Ok(Region { name, origin, length }),
+            _ => Err(format!("Memory region '{}' needs an ORIGIN and a LENGTH", name)),
+        }
+    }
+
+    fn output_section(&mut self, name: String) -> Result<OutputSection, String> {
+        let mut section = OutputSection { name, noload: false, commands: vec![], region: None };
+        if self.peek() == "(" {
+            self.next()?;
+            let line = self.line();
+            let kind = self.next()?;
+            if kind != "NOLOAD" {return Err(format!("Only (NOLOAD) is supported, not ({}) on line {} of the linker script", kind, line))}
+            section.noload = true;
+            self.expect(")")?;
+        }
+        self.expect(":")?;
+        self.expect("{")?;
+        while self.peek() != "}" {
+            let line = self.line();
+            let token = self.next()?;
+            match token.as_str() {
+                "KEEP" => {
+                    self.expect("(")?;
+                    self.next()?; // File pattern
+                    section.commands.push(Command::Input(self.input()?));
+                    self.expect(")")?;
+                }
+                _ if self.peek() == "(" && token.contains('*') => section.commands.push(Command::Input(self.input()?)),
+                _ if self.peek() == "=" => {
+                    let (name, value) = self.assignment(token)?;
+                    section.commands.push(Command::Assign(name, value));
+                }
+                _ => return Err(format!("Unsupported linker script command '{}' on line {}", token, line)),
+            }
+        }
+        self.next()?;
+        loop {
+            match self.peek() {
+                ">" => {
+                    self.next()?;
+                    section.region = Some(self.next()?);
+                }
+                // Load address, there's no .data that would need one
+                "AT" => {
+                    self.next()?;
+                    self.expect(">")?;
+                    self.next()?;
+                }
+                _ => break,
+            }
+        }
+        Ok(section)
+    }
+}
+
+/// Parses a linker script
+pub fn parse(text: &str) -> Result<LinkerScript, String> {
+    let mut parser = Parser { tokens: tokenize(text)?, position: 0 };
+    let mut script = LinkerScript { entry: None, memory: vec![], items: vec![], assignments: vec![] };
+    while parser.position < parser.tokens.len() {
+        let line = parser.line();
+        let token = parser.next()?;
+        match token.as_str() {
+            "ENTRY" => {
+                parser.expect("(")?;
+                script.entry = Some(parser.next()?);
+                parser.expect(")")?;
+            }
+            "MEMORY" => {
+                parser.expect("{")?;
+                while parser.peek() != "}" {
+                    let name = parser.next()?;
+                    let region = parser.region(name)?;
+                    script.memory.push(region);
+                }
+                parser.next()?;
+            }
+            "SECTIONS" => {
+                parser.expect("{")?;
+                while parser.peek() != "}" {
+                    let name = parser.next()?;
+                    if parser.peek() == "=" {
+                        let (name, value) = parser.assignment(name)?;
+                        script.items.push(Item::Assign(name, value));
+                    } else {
+                        script.items.push(Item::Section(parser.output_section(name)?));
+                    }
+                }
+                parser.next()?;
+            }
+            _ if parser.peek() == "=" => {
+                let assignment = parser.assignment(token)?;
+                script.assignments.push(assignment);
+            }
+            _ => return Err(format!("Unsupported linker script command '{}' on line {}", token, line)),
+        }
+    }
+    Ok(script)
+}
+
+fn evaluate(expr: &Expr, dot: u64, symbols: &HashMap<String, u64>, memory: &[Region]) -> Result<u64, String> {
+    let region = |name: &str| memory.iter().find(|region| region.name == name).ok_or(format!("There's no memory region '{}'", name));
+    Ok(match expr {
+        Expr::Number(value) => *value,
+        Expr::Dot => dot,
+        Expr::Symbol(name) => *symbols.get(name).ok_or(format!("Linker script symbol '{}' isn't defined (yet)", name))?,
+        Expr::Origin(name) => region(name)?.origin,
+        Expr::Length(name) => region(name)?.length,
+        Expr::Align(value, alignment) => align(evaluate(value, dot, symbols, memory)?, evaluate(alignment, dot, symbols, memory)?),
+        Expr::Binary(operator, left, right) => {
+            let (left, right) = (evaluate(left, dot, symbols, memory)?, evaluate(right, dot, symbols, memory)?);
+            match operator {
+                '+' => left.wrapping_add(right),
+                '-' => left.wrapping_sub(right),
+                '*' => left.wrapping_mul(right),
+                _ => left.checked_div(right).ok_or("Division by zero in the linker script")?,
+            }
+        }
+    })
+}
+
+/// `*` is anything, `?` one character
+fn matches(pattern: &str, name: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern.len() == name.len() && pattern.chars().zip(name.chars()).all(|(p, n)| p == '?' || p == n),
+        Some((prefix, rest)) => {
+            name.len() >= prefix.len() && matches(prefix, &name[..prefix.len()])
+                && (prefix.len()..=name.len()).any(|start| name.is_char_boundary(start) && matches(rest, &name[start..]))
+        }
+    }
+}
+
+/// Lays the input sections out the way the script says. Every input has to end up somewhere.
+pub fn link(script: &LinkerScript, inputs: &[Input]) -> Result<Layout, String> {
+    if debug_prints(LINKER_DEBUG_PRINTS) {eprintln!("- - - LINKER")}
+
+    let mut regions: HashMap<&str, u64> = script.memory.iter().map(|region| (region.name.as_str(), region.origin)).collect();
+    let mut symbols = HashMap::new();
+    let mut addresses: Vec<Option<u64>> = vec![None; inputs.len()];
+    let mut sections = vec![];
+    let mut dot = 0;
+    for item in &script.items {
+        let section = match item {
+            Item::Assign(name, value) => {
+                let value = evaluate(value, dot, &symbols, &script.memory)?;
+                if name == "." {dot = value} else {symbols.insert(name.clone(), value);}
+                continue;
+            }
+            Item::Section(section) => section,
+        };
+        let region = match &section.region {
+            Some(name) => Some(script.memory.iter().find(|region| region.name == *name)
+                .ok_or(format!("Section '{}' goes in memory region '{}', which isn't in MEMORY", section.name, name))?),
+            None => None,
+        };
+        let start = match region {
+            Some(region) => regions[region.name.as_str()],
+            None => dot,
+        };
+
+        // Which inputs go in, so the section can start aligned for all of them
+        let mut placed: Vec<usize> = vec![];
+        for command in &section.commands {
+            let Command::Input(patterns) = command else {continue};
+            for (index, input) in inputs.iter().enumerate() {
+                if addresses[index].is_none() && !placed.contains(&index) && patterns.iter().any(|pattern| matches(pattern, &input.name)) {
+                    placed.push(index);
+                }
+            }
+        }
+        let alignment = placed.iter().map(|index| inputs[*index].align).max().unwrap_or(1);
+        let address = align(start, alignment);
+        let mut location = address;
+        let mut order = vec![];
+        for command in &section.commands {
+            match command {
+                Command::Input(patterns) => {
+                    for &index in &placed {
+                        if addresses[index].is_some() || !patterns.iter().any(|pattern| matches(pattern, &inputs[index].name)) {continue}
+                        location = align(location, inputs[index].align);
+                        addresses[index] = Some(location);
+                        location += inputs[index].size;
+                        order.push(index);
+                    }
+                }
+                Command::Assign(name, value) => {
+                    let value = evaluate(value, location, &symbols, &script.memory)?;
+                    if name != "." {symbols.insert(name.clone(), value); continue}
+                    if value < location {return Err(format!("The location counter can't go backwards in section '{}'", section.name))}
+                    location = value;
+                }
+            }
+        }
+
+        if let Some(region) = region {
+            let end = region.origin + region.length;
+            if location > end {
+                return Err(format!("Section '{}' doesn't fit in memory region '{}' ({} bytes too many)", section.name, region.name, location - end));
+            }
+            regions.insert(&region.name, location);
+        } else {
+            dot = location;
+        }
+        if debug_prints(LINKER_DEBUG_PRINTS) {eprintln!("{} at {:#x}, {} bytes", section.name, address, location - address)}
+        sections.push(Placed { name: section.name.clone(), address, size: location - address, noload: section.noload, inputs: order });
+    }
+    if let Some(index) = addresses.iter().position(Option::is_none) {
+        return Err(format!("The linker script doesn't put section '{}' anywhere", inputs[index].name));
+    }
+    for (name, value) in &script.assignments {
+        let value = evaluate(value, dot, &symbols, &script.memory)?;
+        symbols.insert(name.clone(), value);
+    }
+
+    if debug_prints(LINKER_DEBUG_PRINTS) {eprintln!("- - - Linker done!")}
+    Ok(Layout { sections, addresses: addresses.into_iter().map(Option::unwrap).collect(), symbols })
+}
+
+/// The linker script freestanding programs get without -T: `placed` (the `#[section]`s) first in
+/// the first memory region, then the code and strings, and a stack at the end of the last one
+pub fn generate(memory: &[(String, u64, u64)], entry: &str, placed: &[String]) -> String {
+    let memory: Vec<(String, u64, u64)> = match memory.is_empty() {
+        true => DEFAULT_MEMORY.iter().map(|(name, origin, length)| (name.to_string(), *origin, *length)).collect(),
+        false => memory.to_vec(),
+    };
+    let size = |length: u64| match length {
+        _ if length.is_multiple_of(1024 * 1024) && length > 0 => format!("{}M", length / (1024 * 1024)),
+        _ if length.is_multiple_of(1024) && length > 0 => format!("{}K", length / 1024),
+        _ => format!("{:#x}", length),
+    };
+    let first = &memory[0].0;
+    let last = &memory[memory.len() - 1].0;
+
+    let mut out = String::new();
+    out.push_str("/* Generated by galvan */\n");
+    out.push_str(&format!("ENTRY({})\n\nMEMORY\n{{\n", entry));
+    for (index, (name, origin, length)) in memory.iter().enumerate() {
+        let attributes = if index == 0 {"rx"} else {"rwx"};
+        out.push_str(&format!("    {} ({}) : ORIGIN = {:#x}, LENGTH = {}\n", name, attributes, origin, size(*length)));
+    }
+    out.push_str("}\n\nSECTIONS\n{\n");
+    for section in placed {
+        out.push_str(&format!("    {} : {{ KEEP(*({})) }} > {}\n", section, section, first));
+    }
+    out.push_str(&format!("    .text : {{ *(.text .text.*) }} > {}\n", first));
+    out.push_str(&format!("    .rodata : {{ *(.rodata .rodata.*) }} > {}\n", first));
+    out.push_str(&format!("    .stack (NOLOAD) : {{ . = ALIGN(16); . = . + {:#x}; {} = .; }} > {}\n", STACK_SIZE, STACK_TOP_SYMBOL, last));
+    out.push_str("}\n");
+    out
+}
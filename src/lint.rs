@@ -0,0 +1,422 @@
+use std::collections::HashSet;
+use std::path::PathBuf;
+
+use crate::compiler_settings::set_quiet;
+use crate::lexer::{lexer_in_file, LexSymbol, Lexeme, Location};
+use crate::parser::{allowed_lints, parser, Expression, Operator, Statement};
+use crate::seman::builtin_functions;
+use crate::source_map::{add_file, position};
+
+// `galvan lint`, warnings about code that compiles but probably isn't what was meant. Works
+// on one file's statements straight from the parser, before modules.rs mangles anything.
+// Every rule has a name that `#[allow(name)]` (on a function) and `#![allow(name)]` (for
+// the whole file) silence, and [lint] in galvan.toml sets its level. Names starting with
+// `_` never count as unused.
+
+//
+// STRUCTS
+//
+
+/// What a lint does when it finds something
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+#[derive(PartialEq)]
+pub enum Level {
+    Allow,
+    /// The default
+    Warn,
+    /// An error, `galvan lint` fails
+    Deny,
+}
+impl Level {
+    /// `allow`, `warn` or `deny`, like in galvan.toml
+    pub fn from_name(name: &str) -> Option<Level> {
+        match name {
+            "allow" => Some(Level::Allow),
+            "warn" => Some(Level::Warn),
+            "deny" => Some(Level::Deny),
+            _ => None,
+        }
+    }
+}
+
+/// Every rule there is
+pub const RULES: [&str; 8] = [
+    "unused_variable",    // Assigned, never read
+    "unused_parameter",
+    "unused_function",    // Not pub, not exported, not a handler and never called
+    "unreachable_code",   // After a return, or a loop that never ends
+    "constant_condition", // `if (1 == 1)`, `while (0)`, `while (1)` without a return in it
+    "self_comparison",    // `x == x`
+    "shadowing",          // A function's variable named like a top level one, or a variable named like a function
+    "naming",             // snake_case functions and variables, UPPER_CASE constants
+];
+
+#[derive(Debug)]
+pub struct Lint {
+    pub rule: &'static str,
+    pub message: String,
+    pub location: Location,
+}
+
+struct Linter<'a> {
+    tokens: &'a [Lexeme],
+    /// Functions of the file and the builtins
+    functions: HashSet<String>,
+    /// Variables of the top level, functions can't see them
+    globals: HashSet<String>,
+    /// What the file allows, plus what the function being looked at does
+    allow: Vec<String>,
+    lints: Vec<Lint>,
+}
+
+//
+// FUNCTIONS
+//
+
+fn rule(name: &str) -> &'static str {
+    RULES.iter().find(|rule| **rule == name).unwrap()
+}
+
+fn snake_case(name: &str) -> String {
+    let mut out = String::new();
+    let mut previous = '_';
+    for c in name.chars() {
+        if c.is_ascii_uppercase() && (previous.is_ascii_lowercase() || previous.is_ascii_digit()) {out.push('_')}
+        out.push(c.to_ascii_lowercase());
+        previous = c;
+    }
+    out
+}
+
+/// Whether two expressions are written the same, wherever they are
+fn same(a: &Expression, b: &Expression) -> bool {
+    match (a, b) {
+        (Expression::Number(a), Expression::Number(b)) => a == b,
+        (Expression::String(a), Expression::String(b)) | (Expression::Variable(a, _), Expression::Variable(b, _)) => a == b,
+        (Expression::Operation(a), Expression::Operation(b)) =>
+            a.operator == b.operator && same(&a.left, &b.left) && same(&a.right, &b.right),
+        (Expression::FunctionCall { target: a, args: a_args, .. }, Expression::FunctionCall { target: b, args: b_args, .. }) =>
+            a == b && a_args.len() == b_args.len() && a_args.iter().zip(b_args).all(|(a, b)| same(a, b)),
+        (Expression::ReturnValue { value: a }, Expression::ReturnValue { value: b }) => same(a, b),
+        _ => false,
+    }
+}
+
+/// Calls `f` on the expression and everything in it
+fn walk(expression: &Expression, f: &mut impl FnMut(&Expression)) {
+    f(expression);
+    match expression {
+        Expression::Operation(operation) => {
+            walk(&operation.left, f);
+            walk(&operation.right, f);
+        }
+        Expression::FunctionCall { args, .. } => for arg in args {walk(arg, f)},
+        Expression::ReturnValue { value } => walk(value, f),
+        Expression::Number(_) | Expression::String(_) | Expression::Variable(_, _) => {}
+    }
+}
+
+/// The expressions right in a statement, not the ones in its blocks
+fn expressions(statement: &Statement) -> Vec<&Expression> {
+    match statement {
+        Statement::ExpressionStatement(expression, _) => vec![expression],
+        Statement::VariableAssignment { value, .. } | Statement::ConstAssignment { value, .. } => vec![value],
+        Statement::While { condition, .. } | Statement::ConditionalStatement { condition, .. } => vec![condition],
+        Statement::Asm { asm, .. } => asm.inputs.iter().map(|(_, value)| value).collect(),
+        _ => vec![],
+    }
+}
+
+/// Calls `f` on every statement, the ones in blocks too
+fn statements(statements: &[Statement], f: &mut impl FnMut(&Statement)) {
+    for statement in statements {
+        f(statement);
+        match statement {
+            Statement::While { body, .. } => self::statements(body, f),
+            Statement::ConditionalStatement { body, else_body, .. } => {
+                self::statements(body, f);
+                if let Some(else_body) = else_body {self::statements(else_body, f)}
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Variables the statements read, and functions they call
+fn uses(body: &[Statement]) -> (HashSet<String>, HashSet<String>) {
+    let mut reads = HashSet::new();
+    let mut calls = HashSet::new();
+    statements(body, &mut |statement| {
+        for expression in expressions(statement) {
+            walk(expression, &mut |expression| match expression {
+                Expression::Variable(name, _) => {reads.insert(name.clone());}
+                Expression::FunctionCall { target, .. } => {calls.insert(target.clone());}
+                _ => {}
+            });
+        }
+    });
+    (reads, calls)
+}
+
+/// Variables the statements define, by `let` or as an asm output, where they first do
+fn definitions(body: &[Statement]) -> Vec<(String, Location)> {
+    let mut out: Vec<(String, Location)> = vec![];
+    statements(body, &mut |statement| {
+        let names: Vec<&String> = match statement {
+            Statement::VariableAssignment { name, .. } => vec![name],
+            Statement::Asm { asm, .. } => asm.outputs.iter().map(|(_, variable)| variable).collect(),
+            _ => vec![],
+        };
+        for name in names {
+            if !out.iter().any(|(other, _)| other == name) {out.push((name.clone(), statement.location()))}
+        }
+    });
+    out
+}
+
+/// Whether there's a `return` anywhere in the statements
+fn returns(body: &[Statement]) -> bool {
+    let mut found = false;
+    statements(body, &mut |statement| {
+        if let Statement::ExpressionStatement(Expression::ReturnValue { .. }, _) = statement {found = true}
+    });
+    found
+}
+
+/// Value of an expression made of nothing but numbers. Constants don't count, a condition
+/// on one is usually a setting.
+fn constant(expression: &Expression) -> Option<i64> {
+    match expression {
+        Expression::Number(value) => Some(*value),
+        Expression::Operation(operation) => {
+            let (left, right) = (constant(&operation.left)?, constant(&operation.right)?);
+            match operation.operator {
+                Operator::Addition => left.checked_add(right),
+                Operator::Subtraction => left.checked_sub(right),
+                Operator::Multiplication => left.checked_mul(right),
+                Operator::Division => left.checked_div(right),
+                Operator::LesserThan => Some((left < right) as i64),
+                Operator::GreaterThan => Some((left > right) as i64),
+                Operator::EqualLesserThan => Some((left <= right) as i64),
+                Operator::EqualGreaterThan => Some((left >= right) as i64),
+                Operator::EqualTo => Some((left == right) as i64),
+                Operator::Inequal => Some((left != right) as i64),
+            }
+        }
+        _ => None,
+    }
+}
+
+impl Linter<'_> {
+    fn report(&mut self, rule: &'static str, message: String, location: Location) {
+        if self.allow.iter().any(|allowed| allowed == rule) {return}
+        self.lints.push(Lint { rule, message, location });
+    }
+
+    /// Where `name` is, the first time it comes up from `from` on
+    fn locate(&self, from: Location, name: &str) -> Location {
+        self.tokens.iter()
+            .filter(|token| (token.location.0, token.location.1) >= (from.0, from.1))
+            .find(|token| token.symbol == LexSymbol::Identifier && token.value == name)
+            .map(|token| token.location).unwrap_or(from)
+    }
+
+    fn self_comparisons(&mut self, expression: &Expression, location: Location) {
+        let mut found = vec![];
+        walk(expression, &mut |expression| {
+            let Expression::Operation(operation) = expression else {return};
+            let always = match operation.operator {
+                Operator::EqualTo | Operator::EqualLesserThan | Operator::EqualGreaterThan => true,
+                Operator::Inequal | Operator::LesserThan | Operator::GreaterThan => false,
+                _ => return,
+            };
+            if !same(&operation.left, &operation.right) {return}
+            // Numbers are constant_condition's, calls can give something else every time
+            let mut variable = false;
+            let mut call = false;
+            walk(&operation.left, &mut |side| match side {
+                Expression::Variable(_, _) => variable = true,
+                Expression::FunctionCall { .. } => call = true,
+                _ => {}
+            });
+            if !variable || call {return}
+            found.push(match &*operation.left {
+                Expression::Variable(name, _) => format!("'{}' is compared with itself, that's always {}", name, always),
+                _ => format!("Both sides of the comparison are the same, that's always {}", always),
+            });
+        });
+        for message in found {self.report(rule("self_comparison"), message, location)}
+    }
+
+    /// unreachable_code, constant_condition and self_comparison, gives back whether the end
+    /// of the statements can be reached
+    fn flow(&mut self, body: &[Statement]) -> bool {
+        let mut reachable = true;
+        let mut reported = false;
+        for statement in body {
+            let location = statement.location();
+            if !reachable && !reported {
+                self.report(rule("unreachable_code"), "Unreachable code".to_string(), location);
+                reported = true;
+            }
+            for expression in expressions(statement) {self.self_comparisons(expression, location)}
+            let ends = match statement {
+                Statement::ExpressionStatement(Expression::ReturnValue { .. }, _) => false,
+                Statement::While { condition, body, .. } => {
+                    self.flow(body);
+                    match constant(condition) {
+                        Some(0) => {
+                            self.report(rule("constant_condition"), "The loop's condition is always false, it never runs".to_string(), location);
+                            true
+                        }
+                        Some(_) if !returns(body) => {
+                            self.report(rule("constant_condition"), "The loop's condition is always true and there's no return in it, it never ends".to_string(), location);
+                            false
+                        }
+                        _ => true,
+                    }
+                }
+                Statement::ConditionalStatement { condition, body, else_body, .. } => {
+                    if let Some(value) = constant(condition) {
+                        self.report(rule("constant_condition"), format!("The condition is always {}", value != 0), location);
+                    }
+                    let then = self.flow(body);
+                    let otherwise = else_body.as_ref().is_none_or(|else_body| self.flow(else_body));
+                    then || otherwise
+                }
+                _ => true,
+            };
+            if !ends {reachable = false}
+        }
+        reachable
+    }
+
+    /// A function's body or the top level (without `function`), `parameters` with where they are
+    fn body(&mut self, body: &[Statement], parameters: &[(String, Location)], function: Option<&str>) {
+        let (reads, _) = uses(body);
+        let mut variables: Vec<(String, Location, &str)> = parameters.iter().map(|(name, location)| (name.clone(), *location, "Parameter")).collect();
+        for (name, location) in definitions(body) {
+            if parameters.iter().any(|(parameter, _)| *parameter == name) {continue}
+            let location = self.locate(location, &name);
+            variables.push((name, location, "Variable"));
+        }
+
+        for (name, location, what) in variables {
+            if !reads.contains(&name) && !name.starts_with('_') {
+                let rule = if what == "Parameter" {rule("unused_parameter")} else {rule("unused_variable")};
+                self.report(rule, format!("{} '{}' is never read", what, name), location);
+            }
+            if name.chars().any(|c| c.is_ascii_uppercase()) {
+                self.report(rule("naming"), format!("{} '{}' should be snake_case, like '{}'", what, name, snake_case(&name)), location);
+            }
+            if let Some(function) = function && self.globals.contains(&name) {
+                self.report(rule("shadowing"), format!("{} '{}' of '{}' isn't the top level's '{}', functions can't see top level variables", what, name, function, name), location);
+            }
+            if self.functions.contains(&name) {
+                self.report(rule("shadowing"), format!("{} '{}' has the same name as a function", what, name), location);
+            }
+        }
+        self.flow(body);
+    }
+}
+
+/// Lints a source file, it has to parse
+pub fn lint(source: &str, path: &str) -> Result<Vec<Lint>, String> {
+    let tokens = lexer_in_file(source, add_file(path));
+    let program = parser(tokens.iter().peekable())?;
+
+    let file_allows = allowed_lints(&program);
+    let mut allows: Vec<(&Vec<String>, Location)> = vec![];
+    for statement in &program {
+        if let Statement::Attribute { location, .. } = statement {allows.push((&file_allows, *location))}
+        if let Statement::FunctionAssignment { attributes, location, .. } = statement {allows.push((&attributes.allow, *location))}
+    }
+    for (rules, location) in allows {
+        if let Some(unknown) = rules.iter().find(|name| !RULES.contains(&name.as_str())) {
+            return Err(format!("Unknown lint '{}', expected one of {} at position {}", unknown, RULES.join(", "), position(location)));
+        }
+    }
+
+    let toplevel: Vec<Statement> = program.iter()
+        .filter(|statement| !matches!(statement, Statement::FunctionAssignment { .. } | Statement::Extern { .. } | Statement::Attribute { .. }))
+        .cloned().collect();
+    let mut functions: HashSet<String> = builtin_functions().into_iter().map(|function| function.name).collect();
+    for statement in &program {
+        match statement {
+            Statement::FunctionAssignment { name, .. } => {functions.insert(name.clone());}
+            Statement::Extern { function, .. } => {functions.insert(function.name.clone());}
+            _ => {}
+        }
+    }
+    let globals = definitions(&toplevel).into_iter().map(|(name, _)| name).collect();
+    let mut linter = Linter { tokens: &tokens, functions, globals, allow: file_allows.clone(), lints: vec![] };
+
+    // What gets called, a function calling itself doesn't count
+    let (_, mut called) = uses(&toplevel);
+    for statement in &program {
+        if let Statement::FunctionAssignment { name, body, .. } = statement {
+            let (_, calls) = uses(body);
+            called.extend(calls.into_iter().filter(|call| call != name));
+        }
+    }
+
+    for statement in &program {
+        match statement {
+            Statement::FunctionAssignment { name, arguments, body, public, export, attributes, location, .. } => {
+                linter.allow = file_allows.iter().chain(&attributes.allow).cloned().collect();
+                let at = linter.locate(*location, name);
+                // The hardware or the linker uses these
+                let handler = attributes.interrupt || attributes.panic_handler || attributes.section.is_some();
+                if !public && export.is_none() && !handler && !called.contains(name) && !name.starts_with('_') {
+                    linter.report(rule("unused_function"), format!("Function '{}' is never called", name), at);
+                }
+                if name.chars().any(|c| c.is_ascii_uppercase()) {
+                    linter.report(rule("naming"), format!("Function '{}' should be snake_case, like '{}'", name, snake_case(name)), at);
+                }
+                let parameters: Vec<(String, Location)> = arguments.iter().filter_map(|argument| match argument {
+                    Expression::Variable(parameter, _) => Some((parameter.clone(), linter.locate(at, parameter))),
+                    _ => None,
+                }).collect();
+                // The panic handler's message is there because it has to be
+                let parameters = if attributes.panic_handler {vec![]} else {parameters};
+                linter.body(body, &parameters, Some(name));
+            }
+            Statement::ConstAssignment { name, location, .. } if name.chars().any(|c| c.is_ascii_lowercase()) => {
+                linter.allow = file_allows.clone();
+                let at = linter.locate(*location, name);
+                linter.report(rule("naming"), format!("Constant '{}' should be UPPER_CASE, like '{}'", name, snake_case(name).to_ascii_uppercase()), at);
+            }
+            _ => {}
+        }
+    }
+    linter.allow = file_allows.clone();
+    linter.body(&toplevel, &[], None);
+
+    let mut lints = linter.lints;
+    lints.sort_by_key(|lint| (lint.location.0, lint.location.1));
+    Ok(lints)
+}
+
+/// `galvan lint`, prints what the lints find in the files at their level (`levels`, the rest
+/// warn), fails if a denied one found something
+pub fn lint_files(files: &[PathBuf], levels: &[(String, Level)]) -> Result<(), String> {
+    set_quiet(true);
+    let mut denied = 0;
+    for file in files {
+        let source = std::fs::read_to_string(file).map_err(|error| format!("Can't read '{}': {}", file.display(), error))?;
+        for lint in lint(&source, &file.display().to_string())? {
+            let level = levels.iter().find(|(rule, _)| rule == lint.rule).map(|(_, level)| *level).unwrap_or(Level::Warn);
+            let kind = match level {
+                Level::Allow => continue,
+                Level::Warn => "warning",
+                Level::Deny => {denied += 1; "error"}
+            };
+            println!("{}: {} at position {} [{}]", kind, lint.message, position(lint.location), lint.rule);
+        }
+    }
+    if denied > 0 {
+        return Err(format!("{} denied lint{} found something", denied, if denied == 1 {""} else {"s"}));
+    }
+    Ok(())
+}
@@ -0,0 +1,785 @@
+use std::collections::HashMap;
+use std::io::{BufRead, Read, Write};
+use std::path::Path;
+
+use crate::compiler_settings::*;
+use crate::json::{self, Json};
+use crate::lexer::{lexer, LexSymbol, Lexeme, Location};
+use crate::modules::{demangle, mangle, resolve_source, std_module, std_source, Constant};
+use crate::parser::{parser, Expression, Statement};
+use crate::seman::{analyze, builtin_functions, check_expression, parameter_names, signature, FunctionInfo, Scope, Type};
+use crate::source_map::{add_file, file_path, FileId};
+
+// Language server, `galvan lsp`. Speaks LSP (JSON-RPC with Content-Length headers) with an
+// editor over stdin and stdout.
+// Every change to a document runs it through the whole frontend (modules, lexer, parser,
+// seman) for diagnostics, the compiler stops at the first error so there's one at most.
+// The linked program gets indexed into symbols for everything else, so names resolve the
+// way modules.rs and seman resolve them: definitions, references, rename and hovers follow
+// them into imported modules and the prelude, document symbols and completion stay in the
+// document. There's nothing to find while the program doesn't load (seman can still fail).
+// Types come from the last time seman got through the program, so they stay around while
+// it's broken halfway through an edit.
+// The standard library only exists inside the compiler, its modules get written to the temp
+// directory when the editor wants to show them.
+
+//
+// STRUCTS
+//
+
+/// Start and end (exclusive) in the source, LINE : CHARACTER like lexer Locations
+type Range = ((usize, usize), (usize, usize));
+
+/// A range in one of the program's files
+type Place = (FileId, Range);
+
+/// JSON-RPC error code and message
+type RpcError = (i64, String);
+
+/// Biggest message body the server takes, anything bigger is an editor gone wrong
+const MAX_MESSAGE: usize = 64 * 1024 * 1024;
+
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+#[derive(PartialEq)]
+enum SymbolKind {
+    Function,
+    /// `extern "C" function`
+    Extern,
+    Constant,
+    Parameter,
+    Variable,
+}
+
+#[derive(Debug)]
+struct Symbol {
+    /// As it's written, without the module path
+    name: String,
+    kind: SymbolKind,
+    /// The name where it's defined, the first `let` for variables. None for builtins, they
+    /// aren't defined anywhere.
+    definition: Option<Place>,
+    /// The whole definition, for document symbols. Only the document's own functions,
+    /// constants and externs get one, it's the name for everything else.
+    extent: Range,
+    /// Function (index into the symbols) a parameter or variable belongs to, None for the
+    /// top level and everything that isn't a variable
+    function: Option<usize>,
+    /// What a hover shows
+    detail: String,
+}
+
+/// What the linked program defines and uses
+#[derive(Debug)]
+#[derive(Default)]
+struct Index {
+    symbols: Vec<Symbol>,
+    /// Every name that refers to a symbol, definitions included, in source order
+    occurrences: Vec<(Place, usize)>,
+    /// Lines of the files the occurrences are in, for LSP positions
+    files: HashMap<FileId, Vec<String>>,
+}
+
+/// Goes through the linked program the way seman does, see `index()`
+struct Resolver<'a> {
+    index: Index,
+    /// Seman's, from the last time it got through
+    functions: &'a HashMap<String, FunctionInfo>,
+    /// Mangled name -> symbol, for calls
+    callable: HashMap<String, usize>,
+}
+
+struct Document {
+    /// The ID the compiler gave its path, the index's places in it have it
+    file: FileId,
+    text: String,
+    lines: Vec<String>,
+    tokens: Vec<Lexeme>,
+    index: Index,
+    /// From the last time seman got through the program
+    functions: HashMap<String, FunctionInfo>,
+}
+
+struct Server {
+    /// URI -> document, for every open one
+    documents: HashMap<String, Document>,
+    /// -I directories, for imports
+    include: Vec<String>,
+    /// Pub functions of the prelude (name, signature), they work without an import
+    prelude: Vec<(String, String)>,
+    initialized: bool,
+    shutdown: bool,
+}
+
+//
+// FUNCTIONS
+//
+
+/// LSP's SymbolKind and CompletionItemKind of a symbol
+fn lsp_kinds(kind: SymbolKind) -> (usize, usize) {
+    match kind {
+        SymbolKind::Function | SymbolKind::Extern => (12, 3),
+        SymbolKind::Constant => (14, 21),
+        SymbolKind::Parameter | SymbolKind::Variable => (13, 6),
+    }
+}
+
+fn name_range(token: &Lexeme) -> Range {
+    let (line, column, _) = token.location;
+    ((line, column), (line, column + token.value.chars().count()))
+}
+
+/// Where `name` is when it starts at `location`
+fn place(location: Location, name: &str) -> Place {
+    let (line, column, file) = location;
+    (file, ((line, column), (line, column + name.chars().count())))
+}
+
+fn contains(range: Range, (line, column): (usize, usize)) -> bool {
+    (line, column) >= range.0 && (line, column) <= range.1
+}
+
+/// LSP Position of a LINE : CHARACTER in `lines`, which counts in UTF-16 code units from 0
+fn lsp_position(lines: &[String], (line, column): (usize, usize)) -> Json {
+    let text = lines.get(line.saturating_sub(1)).map(String::as_str).unwrap_or("");
+    let character: usize = text.chars().take(column.saturating_sub(1)).map(char::len_utf16).sum();
+    Json::object(vec![("line", line.saturating_sub(1).into()), ("character", character.into())])
+}
+
+/// The whole definition around the name at `name`, from its `pub`, `export`, `extern "C"`
+/// or `#[...]`s to its `;` or closing `}`
+fn extent(tokens: &[Lexeme], name: (usize, usize)) -> Option<Range> {
+    let at = tokens.iter().position(|token| (token.location.0, token.location.1) == name)?;
+    let leading = |token: &Lexeme| match token.symbol {
+        LexSymbol::Keyword => ["function", "const", "pub", "export", "extern"].contains(&token.value.as_str()),
+        LexSymbol::Attribute => !token.value.starts_with('!'),
+        // The "C"
+        LexSymbol::String => true,
+        _ => false,
+    };
+    let mut start = at;
+    while start > 0 && leading(&tokens[start - 1]) {start -= 1}
+    let mut depth = 0;
+    let end = tokens[at..].iter().find(|token| {
+        match token.symbol {
+            LexSymbol::FunctionOpeningBracket => depth += 1,
+            LexSymbol::FunctionClosingBracket => depth -= 1,
+            LexSymbol::EndLine => {}
+            _ => return false,
+        }
+        depth == 0
+    })?;
+    Some(((tokens[start].location.0, tokens[start].location.1), name_range(end).1))
+}
+
+/// `file://` URI of a path
+fn path_uri(path: &Path) -> String {
+    let path = std::path::absolute(path).unwrap_or(path.to_path_buf());
+    let mut uri = "file://".to_string();
+    for byte in path.to_string_lossy().bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => uri.push(byte as char),
+            _ => uri.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    uri
+}
+
+impl Index {
+    fn define(&mut self, name: &str, kind: SymbolKind, location: Option<Location>, function: Option<usize>, detail: String) -> usize {
+        let definition = location.map(|location| place(location, name));
+        self.symbols.push(Symbol {
+            name: name.to_string(),
+            kind,
+            definition,
+            extent: definition.map_or(((0, 0), (0, 0)), |(_, range)| range),
+            function,
+            detail,
+        });
+        let symbol = self.symbols.len() - 1;
+        if let Some(definition) = definition {self.occurrences.push((definition, symbol))}
+        symbol
+    }
+
+    /// The name at `location` refers to `symbol`
+    fn refer(&mut self, location: Location, symbol: usize) {
+        // Made up by the compiler, it's nowhere in the source
+        if location.0 == 0 {return}
+        let name = &self.symbols[symbol].name;
+        self.occurrences.push((place(location, name), symbol));
+    }
+
+    /// The occurrence of a symbol at `position` in `file`
+    fn at(&self, file: FileId, position: (usize, usize)) -> Option<(Place, usize)> {
+        self.occurrences.iter().find(|((found, range), _)| *found == file && contains(*range, position)).copied()
+    }
+
+    /// The function whose body `position` in `file` is in
+    fn function_at(&self, file: FileId, position: (usize, usize)) -> Option<usize> {
+        self.symbols.iter().position(|symbol| {
+            symbol.kind == SymbolKind::Function && symbol.definition.is_some_and(|(found, _)| found == file) && contains(symbol.extent, position)
+        })
+    }
+
+    fn range(&self, (file, range): Place) -> Json {
+        let lines = self.files.get(&file).map(Vec::as_slice).unwrap_or_default();
+        Json::object(vec![("start", lsp_position(lines, range.0)), ("end", lsp_position(lines, range.1))])
+    }
+}
+
+impl Resolver<'_> {
+    /// The symbol of a function, builtins get theirs the first time they're called
+    fn function(&mut self, target: &str) -> Option<usize> {
+        if let Some(symbol) = self.callable.get(target) {return Some(*symbol)}
+        let builtin = builtin_functions().into_iter().find(|builtin| builtin.name == target)?;
+        let symbol = self.index.define(target, SymbolKind::Function, None, None, signature(target, &builtin));
+        self.callable.insert(target.to_string(), symbol);
+        Some(symbol)
+    }
+
+    fn expression(&mut self, expression: &Expression, scope: &HashMap<String, usize>) {
+        match expression {
+            Expression::Variable(name, location) => if let Some(symbol) = scope.get(name) {self.index.refer(*location, *symbol)},
+            Expression::FunctionCall { target, args, location } => {
+                if let Some(symbol) = self.function(target) {self.index.refer(*location, symbol)}
+                for arg in args {self.expression(arg, scope)}
+            }
+            Expression::Operation(operation) => {
+                self.expression(&operation.left, scope);
+                self.expression(&operation.right, scope);
+            }
+            Expression::ReturnValue { value } => self.expression(value, scope),
+            Expression::Number(_) | Expression::String(_) => {}
+        }
+    }
+
+    /// A function body or the top level. `let` defines a variable unless there's one already,
+    /// then it assigns to that, and every block shares the one scope, like in seman.
+    /// `types` are the variables' types as far as seman can tell.
+    fn block(&mut self, statements: &[Statement], scope: &mut HashMap<String, usize>, types: &mut Scope, function: Option<usize>) {
+        for statement in statements {
+            match statement {
+                Statement::ExpressionStatement(expression, _) => self.expression(expression, scope),
+                Statement::VariableAssignment { name, value, name_location, .. } => {
+                    self.expression(value, scope);
+                    let ty = check_expression(value, types, self.functions).ok();
+                    match scope.get(name) {
+                        Some(symbol) => self.index.refer(*name_location, *symbol),
+                        None => {
+                            let detail = match ty {
+                                Some(ty) => format!("let {}: {}", name, ty),
+                                None => format!("let {}", name),
+                            };
+                            let symbol = self.index.define(name, SymbolKind::Variable, Some(*name_location), function, detail);
+                            scope.insert(name.clone(), symbol);
+                        }
+                    }
+                    if let Some(ty) = ty {types.entry(name.clone()).or_insert(ty);}
+                }
+                Statement::Asm { asm, .. } => {
+                    for (_, value) in &asm.inputs {self.expression(value, scope)}
+                    // Outputs don't know where they are in the source, so they only get a type
+                    for (_, variable) in &asm.outputs {types.entry(variable.clone()).or_insert(Type::Int);}
+                }
+                Statement::While { condition, body, .. } => {
+                    self.expression(condition, scope);
+                    self.block(body, scope, types, function);
+                }
+                Statement::ConditionalStatement { condition, body, else_body, .. } => {
+                    self.expression(condition, scope);
+                    self.block(body, scope, types, function);
+                    if let Some(else_body) = else_body {self.block(else_body, scope, types, function)}
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Goes through the linked program (modules.rs), collecting what's defined where and what
+/// every name refers to. Calls already name the function they go to, constants got inlined
+/// and `constants` remembers where, variables are looked up the way seman does it.
+fn index(statements: &[Statement], constants: &[Constant], functions: &HashMap<String, FunctionInfo>) -> Index {
+    let mut resolver = Resolver { index: Index::default(), functions, callable: HashMap::new() };
+    // Functions can be called before they're defined
+    for statement in statements {
+        let (name, kind, location) = match statement {
+            Statement::FunctionAssignment { name, name_location, .. } => (name, SymbolKind::Function, name_location),
+            Statement::Extern { function, name_location, .. } => (&function.name, SymbolKind::Extern, name_location),
+            _ => continue,
+        };
+        let full = demangle(name);
+        let detail = match functions.get(name) {
+            Some(info) => signature(&full, info),
+            None => format!("function {}", full),
+        };
+        let written = full.rsplit("::").next().unwrap_or(&full);
+        let symbol = resolver.index.define(written, kind, Some(*location), None, detail);
+        resolver.callable.insert(name.clone(), symbol);
+    }
+    for constant in constants {
+        let ty = if let Expression::String(_) = constant.value {Type::Str} else {Type::Int};
+        let detail = format!("const {}: {}", constant.name, ty);
+        let symbol = resolver.index.define(&constant.name, SymbolKind::Constant, Some(constant.location), None, detail);
+        for location in &constant.uses {resolver.index.refer(*location, symbol)}
+    }
+
+    let mut toplevel = (HashMap::new(), Scope::new());
+    for statement in statements {
+        match statement {
+            Statement::FunctionAssignment { name, arguments, parameter_types, body, .. } => {
+                let function = resolver.callable[name];
+                let (mut scope, mut types) = (HashMap::new(), Scope::new());
+                for (argument, ty) in arguments.iter().zip(parameter_types) {
+                    let Expression::Variable(parameter, location) = argument else {continue};
+                    let symbol = resolver.index.define(parameter, SymbolKind::Parameter, Some(*location), Some(function), format!("{}: {}", parameter, ty));
+                    scope.insert(parameter.clone(), symbol);
+                    types.insert(parameter.clone(), *ty);
+                }
+                resolver.block(body, &mut scope, &mut types, Some(function));
+            }
+            other => resolver.block(std::slice::from_ref(other), &mut toplevel.0, &mut toplevel.1, None),
+        }
+    }
+    let mut index = resolver.index;
+    index.occurrences.sort_by_key(|(place, _)| *place);
+    index.occurrences.dedup();
+    index
+}
+
+/// Pub functions of the prelude with their signatures. Seman only gets to see the ones a
+/// program calls, so the rest come from the source (without a return type).
+fn prelude() -> Vec<(String, String)> {
+    let Some(source) = std_module(PRELUDE_MODULE.rsplit("::").next().unwrap()) else {return vec![]};
+    let Ok(statements) = parser(lexer(source).iter().peekable()) else {return vec![]};
+    statements.iter().filter_map(|statement| match statement {
+        Statement::FunctionAssignment { name, arguments, parameter_types, public: true, .. } => {
+            let parameters: Vec<String> = parameter_names(name, arguments).unwrap_or_default().iter().zip(parameter_types)
+                .map(|(parameter, ty)| format!("{}: {}", parameter, ty)).collect();
+            Some((name.clone(), format!("function {}({})", name, parameters.join(", "))))
+        }
+        _ => None,
+    }).collect()
+}
+
+/// Path of a `file://` URI, anything else stays as it is
+fn uri_path(uri: &str) -> String {
+    let Some(path) = uri.strip_prefix("file://") else {return uri.to_string()};
+    let mut bytes = vec![];
+    let mut rest = path.as_bytes();
+    while let Some((&byte, after)) = rest.split_first() {
+        let escaped = std::str::from_utf8(after.get(..2).unwrap_or_default()).ok().and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(escaped) if byte == b'%' => {bytes.push(escaped); rest = &after[2..]}
+            _ => {bytes.push(byte); rest = after}
+        }
+    }
+    String::from_utf8_lossy(&bytes).to_string()
+}
+
+fn response(id: &Json, result: Result<Json, RpcError>) -> Json {
+    match result {
+        Ok(result) => Json::object(vec![("jsonrpc", Json::string("2.0")), ("id", id.clone()), ("result", result)]),
+        Err((code, message)) => Json::object(vec![
+            ("jsonrpc", Json::string("2.0")),
+            ("id", id.clone()),
+            ("error", Json::object(vec![("code", Json::Number(code as f64)), ("message", Json::String(message))])),
+        ]),
+    }
+}
+
+fn notification(method: &str, params: Json) -> Json {
+    Json::object(vec![("jsonrpc", Json::string("2.0")), ("method", Json::string(method)), ("params", params)])
+}
+
+
+impl Document {
+    fn new(file: FileId, text: String) -> Document {
+        Document {
+            file,
+            lines: text.split('\n').map(str::to_string).collect(),
+            tokens: lexer(&text),
+            text,
+            index: Index::default(),
+            functions: HashMap::new(),
+        }
+    }
+
+    fn range(&self, range: Range) -> Json {
+        Json::object(vec![("start", lsp_position(&self.lines, range.0)), ("end", lsp_position(&self.lines, range.1))])
+    }
+
+    /// LINE : CHARACTER of an LSP Position
+    fn location(&self, position: &Json) -> Option<(usize, usize)> {
+        let line = position.get("line").as_usize()?;
+        let character = position.get("character").as_usize()?;
+        let mut units = 0;
+        let mut column = 1;
+        for c in self.lines.get(line).map(String::as_str).unwrap_or("").chars() {
+            if units >= character {break}
+            units += c.len_utf16();
+            column += 1;
+        }
+        Some((line + 1, column))
+    }
+
+    /// Where the lexeme at `location` ends, one character if there's none
+    fn token_end(&self, (line, column): (usize, usize)) -> (usize, usize) {
+        let Some(token) = self.tokens.iter().find(|token| (token.location.0, token.location.1) == (line, column)) else {return (line, column + 1)};
+        // Strings and attributes lost their quotes and escapes, so those get measured in the source
+        let closing = match token.symbol {
+            LexSymbol::String => '"',
+            LexSymbol::Attribute => ']',
+            _ => return name_range(token).1,
+        };
+        let source: Vec<char> = self.lines[line - 1].chars().skip(column).collect();
+        let mut length = 1;
+        let mut escaped = false;
+        for c in source {
+            length += 1;
+            if c == closing && !escaped {break}
+            escaped = c == '\\' && !escaped;
+        }
+        (line, column + length)
+    }
+
+    /// Turns a compiler error into a diagnostic, at the position the message ends with
+    fn diagnostic(&self, path: &str, error: &str) -> Json {
+        let position = error.rsplit_once(" at position ").and_then(|(message, at)| {
+            let (position, file) = at.split_once(" in ").map_or((at, None), |(position, file)| (position, Some(file)));
+            let (line, column) = position.split_once(':')?;
+            Some((message, (line.parse::<usize>().ok()?, column.parse::<usize>().ok()?), file))
+        });
+        let (message, range) = match position {
+            Some((message, position, file)) if position.0 > 0 && file.is_none_or(|file| file == path) => (message.to_string(), (position, self.token_end(position))),
+            // Somewhere in an imported module, or nowhere in particular
+            _ => (error.to_string(), ((1, 1), (1, 1))),
+        };
+        Json::object(vec![
+            ("range", self.range(range)),
+            ("severity", 1.into()),
+            ("source", Json::string("galvan")),
+            ("message", Json::String(message)),
+        ])
+    }
+
+    /// Indexes the linked program, and reads the other files it's in for their lines
+    fn reindex(&mut self, statements: &[Statement], constants: &[Constant]) {
+        self.index = index(statements, constants, &self.functions);
+        for ((file, _), _) in &self.index.occurrences {
+            if self.index.files.contains_key(file) {continue}
+            let text = match file_path(*file) {
+                _ if *file == self.file => self.text.clone(),
+                Some(path) => match std_source(Path::new(&path)) {
+                    Some(source) => source.to_string(),
+                    None => std::fs::read_to_string(&path).unwrap_or_default(),
+                },
+                None => String::new(),
+            };
+            self.index.files.insert(*file, text.split('\n').map(str::to_string).collect());
+        }
+        for symbol in &mut self.index.symbols {
+            let own = symbol.definition.filter(|(file, _)| *file == self.file);
+            if let Some((_, range)) = own && matches!(symbol.kind, SymbolKind::Function | SymbolKind::Extern | SymbolKind::Constant) {
+                symbol.extent = extent(&self.tokens, range.0).unwrap_or(range);
+            }
+        }
+    }
+
+    /// URI the editor can open `file` with, the document's is `uri`. None for C headers,
+    /// their bindings only exist in the compiler.
+    fn uri(&self, uri: &str, file: FileId) -> Option<String> {
+        if file == self.file {return Some(uri.to_string())}
+        let path = file_path(file)?;
+        if let Some(source) = std_source(Path::new(&path)) {
+            // `<std>/io.gv`
+            let copy = std::env::temp_dir().join(format!("galvan-{}", env!("CARGO_PKG_VERSION"))).join(path.replace(['<', '>'], ""));
+            if std::fs::read_to_string(&copy).ok().as_deref() != Some(source) {
+                std::fs::create_dir_all(copy.parent()?).ok()?;
+                std::fs::write(&copy, source).ok()?;
+            }
+            return Some(path_uri(&copy));
+        }
+        Path::new(&path).extension().is_some_and(|extension| extension == MODULE_EXTENSION).then(|| path_uri(Path::new(&path)))
+    }
+
+    /// `{"uri": ..., "range": ...}` of a place, the document's URI is `uri`
+    fn lsp_location(&self, uri: &str, place: Place) -> Option<Json> {
+        Some(Json::object(vec![("uri", Json::String(self.uri(uri, place.0)?)), ("range", self.index.range(place))]))
+    }
+}
+
+impl Server {
+    fn document<'a>(&'a self, params: &'a Json) -> Result<(&'a str, &'a Document), RpcError> {
+        let uri = params.get("textDocument").get("uri").as_str().unwrap_or("");
+        match self.documents.get(uri) {
+            Some(document) => Ok((uri, document)),
+            None => Err((-32602, format!("'{}' isn't open", uri))),
+        }
+    }
+
+    /// The document and the LINE : CHARACTER a request is about
+    fn position<'a>(&'a self, params: &'a Json) -> Result<(&'a str, &'a Document, (usize, usize)), RpcError> {
+        let (uri, document) = self.document(params)?;
+        match document.location(params.get("position")) {
+            Some(position) => Ok((uri, document, position)),
+            None => Err((-32602, "Expected a position".to_string())),
+        }
+    }
+
+    /// Reindexes and rechecks a document, gives back its diagnostics
+    fn update(&mut self, uri: &str, text: String) -> Json {
+        let path = uri_path(uri);
+        let mut document = Document::new(add_file(&path), text);
+        if let Some(old) = self.documents.remove(uri) {
+            document.functions = old.functions;
+        }
+        // A crash in the compiler shouldn't take the editor's language server with it
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let (statements, constants) = resolve_source(&path, &document.text, &self.include)?;
+            let analysis = analyze(statements.clone());
+            Ok::<_, String>((statements, constants, analysis))
+        }));
+        let mut diagnostics = vec![];
+        match result {
+            Ok(Ok((statements, constants, analysis))) => {
+                match analysis {
+                    Ok(analysis) => document.functions = analysis.functions,
+                    Err(error) => diagnostics.push(document.diagnostic(&path, &error)),
+                }
+                document.reindex(&statements, &constants);
+            }
+            Ok(Err(error)) => diagnostics.push(document.diagnostic(&path, &error)),
+            Err(_) => diagnostics.push(document.diagnostic(&path, "The compiler crashed on this file")),
+        }
+        self.documents.insert(uri.to_string(), document);
+        notification("textDocument/publishDiagnostics", Json::object(vec![("uri", Json::string(uri)), ("diagnostics", Json::Array(diagnostics))]))
+    }
+
+    fn notification(&mut self, method: &str, params: &Json) -> Vec<Json> {
+        let uri = params.get("textDocument").get("uri").as_str().unwrap_or("").to_string();
+        match method {
+            "textDocument/didOpen" => {
+                let text = params.get("textDocument").get("text").as_str().unwrap_or("").to_string();
+                vec![self.update(&uri, text)]
+            }
+            // Full sync, so the last change is the whole text
+            "textDocument/didChange" => match params.get("contentChanges").as_array().last().and_then(|change| change.get("text").as_str()) {
+                Some(text) => vec![self.update(&uri, text.to_string())],
+                None => vec![],
+            },
+            "textDocument/didClose" => {
+                self.documents.remove(&uri);
+                vec![notification("textDocument/publishDiagnostics", Json::object(vec![("uri", Json::String(uri)), ("diagnostics", Json::Array(vec![]))]))]
+            }
+            _ => vec![],
+        }
+    }
+
+    fn request(&mut self, method: &str, params: &Json) -> Result<Json, RpcError> {
+        match method {
+            "initialize" => {
+                self.initialized = true;
+                Ok(Json::object(vec![
+                    ("capabilities", Json::object(vec![
+                        ("textDocumentSync", 1.into()),
+                        ("definitionProvider", true.into()),
+                        ("referencesProvider", true.into()),
+                        ("hoverProvider", true.into()),
+                        ("documentSymbolProvider", true.into()),
+                        ("completionProvider", Json::object(vec![])),
+                        ("renameProvider", true.into()),
+                    ])),
+                    ("serverInfo", Json::object(vec![("name", Json::string("galvan")), ("version", Json::string(env!("CARGO_PKG_VERSION")))])),
+                ]))
+            }
+            "shutdown" => {
+                self.shutdown = true;
+                Ok(Json::Null)
+            }
+            "textDocument/definition" => {
+                let (uri, document, position) = self.position(params)?;
+                let definition = document.index.at(document.file, position).and_then(|(_, symbol)| document.index.symbols[symbol].definition);
+                Ok(definition.and_then(|definition| document.lsp_location(uri, definition)).unwrap_or(Json::Null))
+            }
+            "textDocument/references" => {
+                let (uri, document, position) = self.position(params)?;
+                let Some((_, symbol)) = document.index.at(document.file, position) else {return Ok(Json::Array(vec![]))};
+                let declaration = params.get("context").get("includeDeclaration") != &Json::Bool(false);
+                let definition = document.index.symbols[symbol].definition;
+                Ok(Json::Array(document.index.occurrences.iter()
+                    .filter(|(place, found)| *found == symbol && (declaration || Some(*place) != definition))
+                    .filter_map(|(place, _)| document.lsp_location(uri, *place))
+                    .collect()))
+            }
+            "textDocument/hover" => {
+                let (_, document, position) = self.position(params)?;
+                Ok(match document.index.at(document.file, position) {
+                    Some(((_, range), symbol)) => Json::object(vec![
+                        ("contents", Json::object(vec![
+                            ("kind", Json::string("markdown")),
+                            ("value", Json::String(format!("```galvan\n{}\n```", document.index.symbols[symbol].detail))),
+                        ])),
+                        ("range", document.range(range)),
+                    ]),
+                    None => Json::Null,
+                })
+            }
+            "textDocument/documentSymbol" => {
+                let (_, document) = self.document(params)?;
+                let symbols = &document.index.symbols;
+                let json = |index: usize, children: Option<Vec<Json>>| {
+                    let symbol = &symbols[index];
+                    let mut fields = vec![
+                        ("name", Json::String(symbol.name.clone())),
+                        ("detail", Json::String(symbol.detail.clone())),
+                        ("kind", lsp_kinds(symbol.kind).0.into()),
+                        ("range", document.range(symbol.extent)),
+                        ("selectionRange", document.range(symbol.definition.unwrap().1)),
+                    ];
+                    if let Some(children) = children {fields.push(("children", Json::Array(children)))}
+                    Json::object(fields)
+                };
+                let own = |index: &usize| symbols[*index].definition.is_some_and(|(file, _)| file == document.file);
+                Ok(Json::Array((0..symbols.len()).filter(own).filter(|index| symbols[*index].function.is_none()).map(|index| {
+                    let children = (0..symbols.len()).filter(|child| symbols[*child].function == Some(index)).map(|child| json(child, None)).collect();
+                    json(index, Some(children))
+                }).collect()))
+            }
+            "textDocument/completion" => {
+                let (_, document, position) = self.position(params)?;
+                let function = document.index.function_at(document.file, position);
+                let mut items: Vec<(String, usize, String)> = vec![];
+                for symbol in &document.index.symbols {
+                    let own = symbol.definition.is_some_and(|(file, _)| file == document.file);
+                    let visible = match symbol.kind {
+                        SymbolKind::Parameter | SymbolKind::Variable => symbol.function == function,
+                        _ => true,
+                    };
+                    if own && visible {items.push((symbol.name.clone(), lsp_kinds(symbol.kind).1, symbol.detail.clone()))}
+                }
+                let print = builtin_functions().into_iter().find(|builtin| builtin.name == "print").unwrap();
+                items.push((print.name.clone(), 3, signature(&print.name, &print)));
+                for (name, prelude) in &self.prelude {
+                    let detail = document.functions.get(&mangle(PRELUDE_MODULE, name)).map_or(prelude.clone(), |info| signature(name, info));
+                    items.push((name.clone(), 3, detail));
+                }
+                for keyword in KEYWORDS {
+                    items.push((keyword.to_string(), 14, String::new()));
+                }
+                let mut seen = vec![];
+                items.retain(|(label, _, _)| if seen.contains(label) {false} else {seen.push(label.clone()); true});
+                Ok(Json::Array(items.into_iter().map(|(label, kind, detail)| {
+                    Json::object(vec![("label", Json::String(label)), ("kind", kind.into()), ("detail", Json::String(detail))])
+                }).collect()))
+            }
+            "textDocument/rename" => {
+                let (uri, document, position) = self.position(params)?;
+                let new_name = params.get("newName").as_str().unwrap_or("");
+                let valid = new_name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+                    && new_name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') && !KEYWORDS.contains(&new_name);
+                if !valid {return Err((-32803, format!("'{}' isn't a valid name", new_name)))}
+                let Some((_, symbol)) = document.index.at(document.file, position) else {
+                    return Err((-32803, "There's nothing here to rename".to_string()));
+                };
+                let symbols = &document.index.symbols;
+                let renamed = &symbols[symbol];
+                let in_std = |file: FileId| file_path(file).is_some_and(|path| std_source(Path::new(&path)).is_some());
+                let Some((file, _)) = renamed.definition.filter(|(file, _)| !in_std(*file) && document.uri(uri, *file).is_some()) else {
+                    return Err((-32803, format!("'{}' comes with the compiler or a C header, it can't be renamed", renamed.name)));
+                };
+                let clash = symbols.iter().enumerate().any(|(other, found)| {
+                    other != symbol && found.name == new_name && found.definition.is_some_and(|(found_file, _)| found_file == file)
+                        && (found.function.is_none() || renamed.function.is_none() || found.function == renamed.function)
+                });
+                if clash {return Err((-32803, format!("'{}' is already defined", new_name)))}
+                // Every file it's used in, in the order they come up
+                let mut changes: Vec<(String, Json)> = vec![];
+                for (place, _) in document.index.occurrences.iter().filter(|(_, found)| *found == symbol) {
+                    let Some(file_uri) = document.uri(uri, place.0) else {continue};
+                    let edit = Json::object(vec![("range", document.index.range(*place)), ("newText", Json::string(new_name))]);
+                    match changes.iter_mut().find(|(found, _)| *found == file_uri) {
+                        Some((_, Json::Array(edits))) => edits.push(edit),
+                        _ => changes.push((file_uri, Json::Array(vec![edit]))),
+                    }
+                }
+                Ok(Json::object(vec![("changes", Json::Object(changes))]))
+            }
+            _ => Err((-32601, format!("Unknown method '{}'", method))),
+        }
+    }
+
+    /// Handles one message from the editor, gives back what goes back to it
+    fn message(&mut self, message: &Json) -> Vec<Json> {
+        let method = message.get("method").as_str().unwrap_or("");
+        let params = message.get("params");
+        let id = message.get("id");
+        // Responses, the server never asks the editor anything
+        if method.is_empty() {return vec![]}
+        if *id == Json::Null {return self.notification(method, params)}
+        if !self.initialized && method != "initialize" {
+            return vec![response(id, Err((-32002, "The server isn't initialized yet".to_string())))];
+        }
+        vec![response(id, self.request(method, params))]
+    }
+}
+
+/// Reads one message, None at the end of the input
+fn read_message(input: &mut impl BufRead) -> Result<Option<String>, String> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        match input.read_line(&mut line) {
+            Ok(0) => return Ok(None),
+            Ok(_) => {}
+            Err(error) => return Err(format!("Can't read stdin: {}", error)),
+        }
+        let line = line.trim_end();
+        if line.is_empty() && length.is_some() {break}
+        if let Some((name, value)) = line.split_once(':') && name.eq_ignore_ascii_case("Content-Length") {
+            length = Some(value.trim().parse::<usize>().map_err(|_| format!("Invalid Content-Length '{}'", value.trim()))?);
+        }
+    }
+    let length = length.unwrap();
+    if length > MAX_MESSAGE {
+        return Err(format!("Content-Length {} is over the limit of {} bytes", length, MAX_MESSAGE));
+    }
+    // Grows with what actually comes in instead of trusting the header up front
+    let mut body = vec![];
+    input.take(length as u64).read_to_end(&mut body).map_err(|error| format!("Can't read stdin: {}", error))?;
+    if body.len() < length {return Err("stdin ended in the middle of a message".to_string())}
+    Ok(Some(String::from_utf8_lossy(&body).to_string()))
+}
+
+fn write_message(output: &mut impl Write, message: &Json) -> Result<(), String> {
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body).and_then(|_| output.flush())
+        .map_err(|error| format!("Can't write stdout: {}", error))
+}
+
+/// `galvan lsp`, runs until the editor sends `exit` or closes stdin
+pub fn lsp(include: &[String]) -> Result<(), String> {
+    // stdout belongs to the protocol
+    set_quiet(true);
+
+    let mut server = Server { documents: HashMap::new(), include: include.to_vec(), prelude: prelude(), initialized: false, shutdown: false };
+    let stdin = std::io::stdin();
+    let mut input = stdin.lock();
+    let mut output = std::io::stdout().lock();
+    while let Some(text) = read_message(&mut input)? {
+        let message = match json::parse(&text) {
+            Ok(message) => message,
+            Err(error) => {
+                write_message(&mut output, &response(&Json::Null, Err((-32700, format!("Invalid JSON: {}", error)))))?;
+                continue;
+            }
+        };
+        if message.get("method").as_str() == Some("exit") {
+            return if server.shutdown {Ok(())} else {Err("Got exit before shutdown".to_string())};
+        }
+        for reply in server.message(&message) {
+            write_message(&mut output, &reply)?;
+        }
+    }
+    Ok(())
+}
@@ -1,18 +1,277 @@
-mod compiler_settings; use std::fs::read_to_string;
-use compiler_settings::*;
-mod lexer; use lexer::*;
-mod parser; use crate::parser::*;
+#![allow(clippy::needless_return, clippy::redundant_field_names)] // House style, see parser.rs
+#![allow(clippy::upper_case_acronyms, clippy::enum_variant_names)] // LexSymbol::EOF, Statement::*Statement
+mod compiler_settings; use compiler_settings::*; use std::fs::read_to_string;
+mod cli; use cli::*;
+mod source_map;
+mod lexer;
+mod parser;
+mod modules;
+mod intrinsics;
+mod ffi;
+mod bindgen;
+mod package;
 mod seman; use crate::seman::*;
+mod ir; use crate::ir::*;
+mod regalloc;
+mod opt;
+mod backend;
+mod x86;
+mod x86_encoder;
+mod dwarf;
+mod elf;
+mod riscv;
+mod riscv_encoder;
+mod riscv_sim;
+mod thumb;
+mod thumb_encoder;
+mod thumb_sim;
+mod wasm;
+mod wasm_encoder;
+mod linker_script;
+mod c_backend;
+mod interpreter; use crate::interpreter::*;
+mod repl;
+mod json;
+mod lsp;
+mod cst;
+mod fmt;
+mod lint;
+mod highlight;
+mod doc;
+mod bytecode;
+mod vm;
 
-fn main() {
-    // TODO: Get source file via args
-    // FIXME: make sure file exists
-    let sourcefile = read_to_string(SRC_FILE).unwrap();
+/// Runs the source file (and everything it imports) through lexer, parser, the module
+/// loader and semantic analysis
+fn frontend(options: &Options) -> Result<Analysis, String> {
+    let statements = modules::load_program(options.source(), &options.include, &options.packages, options.freestanding)?;
+
+    analyze(statements)
+}
+
+/// `galvan build`
+fn build(options: &Options) -> Result<(), String> {
+    let output = if options.arch() == Arch::Wasm32 && !matches!(options.emit(), Emit::Ir | Emit::C) {
+        // Wasm has structured control flow, so it works from the AST too
+        if options.source().ends_with(".ir") {return Err("The Wasm backend needs Galvan source, not IR".to_string())}
+        let program = wasm::generate(&frontend(options)?, options.entry.as_deref())?;
+        match options.emit() {
+            Emit::Asm => program.to_wat().into_bytes(),
+            Emit::Object | Emit::Executable => wasm_encoder::encode(&program),
+            Emit::RegAlloc => return Err("Wasm has no registers to allocate, the runtime does that".to_string()),
+            _ => return Err("The Wasm backend writes .wat (--emit=asm) and .wasm (--emit=obj or exe), nothing else".to_string()),
+        }
+    } else if options.emit() == Emit::C {
+        // The C backend works from the AST to keep the output readable
+        if options.source().ends_with(".ir") {return Err("The C backend needs Galvan source, not IR".to_string())}
+        c_backend::generate(&frontend(options)?, options.source(), options.entry.as_deref())?.into_bytes()
+    } else {
+        // Hand-written IR skips the frontend completely
+        let mut module = if options.source().ends_with(".ir") {
+            let sourcefile = match read_to_string(options.source()) {
+                Ok(sourcefile) => sourcefile,
+                Err(error) => return Err(format!("Can't read '{}': {}", options.source(), error)),
+            };
+            let mut module = parse_ir(&sourcefile)?;
+            module.freestanding |= options.freestanding;
+            module
+        } else {
+            lower(&frontend(options)?, options.debug_info)?
+        };
+        if options.entry.is_some() {module.entry_symbol = options.entry.clone()}
+        opt::optimize(&mut module, options.opt_level(), &options.passes)?;
+        let riscv = options.arch() == Arch::Riscv32;
+        let thumb = thumb_profile(options.arch()).is_some();
+        let profile = thumb_profile(options.arch()).unwrap_or(thumb::Profile::V7m);
+        match options.emit() {
+            Emit::Ir => module.to_string().into_bytes(),
+            Emit::Asm if thumb => thumb::generate(&module, profile)?.to_assembly().into_bytes(),
+            Emit::Object | Emit::Executable if thumb => {
+                return Err("The Thumb backend only writes assembly (--emit=asm), assemble and link it with an ARM toolchain, or run it with `galvan run --target=thumbv7m` (or thumbv6m)".to_string());
+            }
+            Emit::LinkerScript if thumb => {
+                let program = thumb::generate(&module, profile)?;
+                generated_linker_script(options, &program.entry, &program.sections).into_bytes()
+            }
+            Emit::RegAlloc if thumb => thumb::dump_allocation(&module, profile).into_bytes(),
+            Emit::Asm if riscv => riscv::generate(&module)?.to_assembly().into_bytes(),
+            Emit::Object | Emit::Executable if riscv => {
+                return Err("The RISC-V backend only writes assembly (--emit=asm), assemble and link it with a RISC-V toolchain, or run it with `galvan run --target=riscv32`".to_string());
+            }
+            Emit::LinkerScript if riscv => {
+                let program = riscv::generate(&module)?;
+                generated_linker_script(options, &program.entry, &program.sections).into_bytes()
+            }
+            Emit::RegAlloc if riscv => riscv::dump_allocation(&module).into_bytes(),
+            Emit::RegAlloc => x86::dump_allocation(&module).into_bytes(),
+            Emit::Asm => x86::generate(&module)?.to_assembly(options.syntax).into_bytes(),
+            Emit::Object => elf::write_object(&x86::generate(&module)?)?,
+            Emit::Executable if module.uses_c() => {
+                return Err("The program uses C functions, build it with --emit=obj (or asm) and link it with a C compiler".to_string());
+            }
+            Emit::Executable => {
+                let program = x86::generate(&module)?;
+                elf::write_executable(&program, linker_script(options, &module, &program)?.as_ref())?
+            }
+            Emit::LinkerScript => {
+                let program = x86::generate(&module)?;
+                generated_linker_script(options, &program.entry, &program.sections).into_bytes()
+            }
+            Emit::Bytecode => bytecode::compile(&module)?.to_string().into_bytes(),
+            Emit::Gvc => bytecode::write_gvc(&bytecode::compile(&module)?),
+            Emit::C => unreachable!(),
+        }
+    };
+    if let Err(error) = std::fs::write(options.output(), output) {
+        return Err(format!("Can't write '{}': {}", options.output(), error));
+    }
+    #[cfg(unix)]
+    if options.emit() == Emit::Executable && options.arch() != Arch::Wasm32 {
+        use std::os::unix::fs::PermissionsExt;
+        let _ = std::fs::set_permissions(options.output(), std::fs::Permissions::from_mode(0o755));
+    }
+    Ok(())
+}
+
+/// Which Cortex-M profile a Thumb target is, None for the other targets
+fn thumb_profile(arch: Arch) -> Option<thumb::Profile> {
+    match arch {
+        Arch::Thumbv7m => Some(thumb::Profile::V7m),
+        Arch::Thumbv6m => Some(thumb::Profile::V6m),
+        _ => None,
+    }
+}
+
+/// The linker script a freestanding program gets without -T, `sections` are the program's
+/// custom ones (any backend's)
+fn generated_linker_script<T>(options: &Options, entry: &str, sections: &[(String, T)]) -> String {
+    let placed: Vec<String> = sections.iter().map(|(name, _)| name.clone()).collect();
+    linker_script::generate(&options.memory, entry, &placed)
+}
 
-    let lexersymbols = lexer(&sourcefile);
+/// -T if there is one, the generated script for freestanding programs, otherwise nothing (the
+/// executable gets the usual hosted layout)
+fn linker_script(options: &Options, module: &Module, program: &x86::Program) -> Result<Option<linker_script::LinkerScript>, String> {
+    match &options.linker_script {
+        Some(path) => {
+            let text = read_to_string(path).map_err(|error| format!("Can't read '{}': {}", path, error))?;
+            let mut script = linker_script::parse(&text).map_err(|error| format!("{} ({})", error, path))?;
+            // Like ld, --entry wins over the script's ENTRY
+            if options.entry.is_some() {script.entry = options.entry.clone()}
+            Ok(Some(script))
+        }
+        None if module.freestanding => linker_script::parse(&generated_linker_script(options, &program.entry, &program.sections)).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// `galvan run`, returns the program's exit code
+fn run(options: &Options) -> Result<i64, String> {
+    // Already compiled bytecode
+    if options.source().ends_with(".gvc") {
+        let bytes = match std::fs::read(options.source()) {
+            Ok(bytes) => bytes,
+            Err(error) => return Err(format!("Can't read '{}': {}", options.source(), error)),
+        };
+        let program = bytecode::read_gvc(&bytes).map_err(|error| format!("Invalid .gvc file '{}': {}", options.source(), error))?;
+        return vm::run(&program).map_err(|error| format!("runtime error: {}", error));
+    }
 
-    let lexeme = lexersymbols.iter().peekable();
-    let statements = parser(lexeme).expect("Failed parsing");
+    let analysis = frontend(options)?;
+    if options.arch() == Arch::Riscv32 {
+        let mut module = lower(&analysis, false)?;
+        if options.entry.is_some() {module.entry_symbol = options.entry.clone()}
+        opt::optimize(&mut module, options.opt_level(), &options.passes)?;
+        if module.uses_c() {
+            return Err("The program uses C functions, the RISC-V simulator can't run those, build it with --emit=asm and link it with a C compiler".to_string());
+        }
+        let program = riscv::generate(&module)?;
+        let image = riscv_encoder::encode(&program, RISCV_LOAD_ADDRESS, &[(STACK_TOP_SYMBOL.to_string(), RISCV_MEMORY_SIZE)])?;
+        return riscv_sim::run(&image).map_err(|error| format!("runtime error: {}", error));
+    }
+    if options.arch() == Arch::Wasm32 {
+        return Err("There's no WebAssembly runtime built in, build a .wasm with --emit=exe and run it in a browser or node (see the readme)".to_string());
+    }
+    if let Some(profile) = thumb_profile(options.arch()) {
+        let mut module = lower(&analysis, false)?;
+        if options.entry.is_some() {module.entry_symbol = options.entry.clone()}
+        opt::optimize(&mut module, options.opt_level(), &options.passes)?;
+        if module.uses_c() {
+            return Err("The program uses C functions, the Thumb simulator can't run those, build it with --emit=asm and link it with a C compiler".to_string());
+        }
+        let program = thumb::generate(&module, profile)?;
+        let image = thumb_encoder::encode(&program, THUMB_LOAD_ADDRESS, &[(STACK_TOP_SYMBOL.to_string(), THUMB_MEMORY_SIZE)])?;
+        return thumb_sim::run(&image, profile).map_err(|error| format!("runtime error: {}", error));
+    }
+    if options.vm {
+        let mut module = lower(&analysis, false)?;
+        opt::optimize(&mut module, options.opt_level(), &options.passes)?;
+        let program = bytecode::compile(&module)?;
+        return vm::run(&program).map_err(|error| format!("runtime error: {}", error));
+    }
+    with_interpreter_stack(move || match Interpreter::new().run(&analysis) {
+        Ok(exit_code) => Ok(exit_code),
+        Err(error) => Err(format!("runtime error: {}", error)),
+    })
+}
+
+/// `galvan bindgen`, prints the Galvan version of a header unless there's a -o
+fn bindgen(options: &Options) -> Result<(), String> {
+    let include: Vec<std::path::PathBuf> = options.include.iter().map(std::path::PathBuf::from).collect();
+    let source = bindgen::bindgen(std::path::Path::new(options.source()), &include)?;
+    match &options.output {
+        Some(output) => std::fs::write(output, source).map_err(|error| format!("Can't write '{}': {}", output, error)),
+        None => {print!("{}", source); Ok(())}
+    }
+}
+
+/// Runs `f` on a thread with a bigger stack. Every Galvan call is a bunch of Rust calls,
+/// the main thread's stack isn't enough to reach INTERPRETER_MAX_DEPTH.
+fn with_interpreter_stack<T: Send + 'static>(f: impl FnOnce() -> Result<T, String> + Send + 'static) -> Result<T, String> {
+    let thread = std::thread::Builder::new().stack_size(INTERPRETER_STACK_SIZE).spawn(f);
+    match thread.map_err(|error| error.to_string())?.join() {
+        Ok(result) => result,
+        Err(_) => Err("The interpreter crashed".to_string()),
+    }
+}
+
+fn main() {
+    let options = match parse_args(std::env::args().skip(1).collect()) {
+        Ok(options) => options,
+        Err(error) => {eprintln!("{}", error); std::process::exit(2)}
+    };
+    // The stage prints are for working on the compiler, stdout belongs to the program
+    set_quiet(!options.verbose);
 
-    let _ = analyze(statements);
+    let result = match options.command {
+        Command::Build => package::package_options(options).and_then(|options| build(&options)),
+        Command::Run => match package::package_options(options).and_then(|options| run(&options)) {
+            // Same as the compiled program, only the low 8 bits make it to the shell
+            Ok(exit_code) => std::process::exit(exit_code as i32),
+            Err(error) => Err(error),
+        },
+        Command::Repl => with_interpreter_stack(repl::repl),
+        Command::New => package::new_package(options.source(), options.lib),
+        Command::Bindgen => bindgen(&options),
+        Command::Lsp => lsp::lsp(&options.include),
+        Command::Fmt => package::project_files(options.source.as_deref())
+            .and_then(|(files, manifest)| fmt::fmt(&files, &manifest.map(|manifest| manifest.style).unwrap_or_default(), options.check)),
+        Command::Lint => package::project_files(options.source.as_deref())
+            .and_then(|(files, manifest)| lint::lint_files(&files, &manifest.map(|manifest| manifest.lints).unwrap_or_default())),
+        Command::Highlight => highlight::highlight(options.source(), options.html, options.output.as_deref()),
+        Command::Grammar => highlight::grammar(options.output.as_deref()),
+        Command::Doc => doc::doc(options),
+        Command::Test => match options.doctest {
+            // One doc test, for the `galvan test` that started this one
+            Some(number) => match with_interpreter_stack(move || doc::run_test(options, number)) {
+                Ok(exit_code) => std::process::exit(exit_code as i32),
+                Err(error) => Err(error),
+            },
+            None => doc::test(options),
+        },
+    };
+    if let Err(error) = result {
+        eprintln!("error: {}", error);
+        std::process::exit(1);
+    }
 }
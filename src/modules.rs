@@ -0,0 +1,752 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use crate::bindgen::bindgen;
+use crate::compiler_settings::*;
+use crate::ffi::Extern;
+use crate::intrinsics;
+use crate::lexer::{lexer_in_file, Location};
+use crate::parser::{self, parser, Expression, InlineAsm, Operation, Operator, Statement};
+use crate::seman::Type;
+use crate::source_map::{add_file, file_path, position, FileId};
+
+// Module system. `import drivers::uart;` looks for drivers/uart.gv in the search path (the
+// root file's directory, then every -I directory), loads it once, and makes its `pub`
+// functions and constants usable as `uart::name`.
+// Everything gets flattened into one list of statements for seman: functions from other
+// modules get the module path mangled into their name, constants get inlined.
+// Building a package (package.rs) adds its dependencies: `import drivers;` is the entry file
+// of the `drivers` package, `import drivers::uart;` the uart.gv next to it. Modules of a
+// dependency are named after it, so two packages can both have a `uart` module.
+// The standard library (std/*.gv) is baked into the compiler and always there as `std::*`.
+// Its prelude gets loaded for every program, and whatever a file doesn't define itself falls
+// back to the prelude's pub functions. Std functions nothing calls get dropped after linking.
+// `extern "C"` functions belong to their module like any other function, only their C symbol
+// (and an `export function`'s) is left alone.
+// `import c "hal.h";` runs the header through bindgen.rs and loads the result as a module
+// named after the file, `hal::HAL_Init()`.
+
+//
+// STRUCTS
+//
+
+#[derive(Debug)]
+pub struct Module {
+    /// `drivers::uart`, empty for the root
+    pub name: String,
+    pub file: FileId,
+    pub statements: Vec<Statement>,
+    /// Alias (last part of the path) -> full module name
+    pub imports: HashMap<String, String>,
+    /// Index into the packages it belongs to
+    pub package: usize,
+}
+
+/// A package as far as the module loader cares, see package.rs
+#[derive(Debug)]
+#[derive(Clone)]
+pub struct Package {
+    pub name: String,
+    /// The entry file, its directory is where the package's other modules are
+    pub entry: PathBuf,
+    /// Indices of the packages it can import
+    pub dependencies: Vec<usize>,
+}
+
+/// A constant, linking inlines it everywhere it's used. The language server still wants to
+/// know where that was.
+#[derive(Debug)]
+pub struct Constant {
+    pub name: String,
+    /// Where its name is in the `const` line
+    pub location: Location,
+    /// Already folded into a Number or String
+    pub value: Expression,
+    pub public: bool,
+    /// Every name that got replaced with the value
+    pub uses: Vec<Location>,
+}
+
+/// What a module has to offer, name -> is it pub
+#[derive(Default)]
+struct Items {
+    functions: HashMap<String, bool>,
+    /// Index into the constants of the program
+    constants: HashMap<String, usize>,
+}
+
+struct Loader {
+    /// Where the root package's modules are (the root file's directory, then every -I directory)
+    search_path: Vec<PathBuf>,
+    /// Dependencies first, the root package is the last one
+    packages: Vec<Package>,
+    /// Dependencies always come before the modules importing them, the root ends up last
+    modules: Vec<Module>,
+    /// Canonical paths of the loaded modules, to load each file only once
+    paths: Vec<PathBuf>,
+    /// Modules being loaded right now (name, canonical path), for cycle detection
+    loading: Vec<(String, PathBuf)>,
+    /// Text of the root file when it isn't read from disk (an editor's unsaved buffer)
+    root_text: Option<String>,
+}
+
+/// Rewrites one module's statements, see `link()`
+struct Linker<'a> {
+    module: &'a Module,
+    items: &'a HashMap<String, Items>,
+    constants: &'a mut Vec<Constant>,
+}
+
+//
+// FUNCTIONS
+//
+
+/// Sources of the standard library's modules, `std::<name>`
+const STD_MODULES: [(&str, &str); 6] = [
+    ("core", include_str!("../std/core.gv")),
+    ("io", include_str!("../std/io.gv")),
+    ("math", include_str!("../std/math.gv")),
+    ("mem", include_str!("../std/mem.gv")),
+    ("prelude", include_str!("../std/prelude.gv")),
+    ("str", include_str!("../std/str.gv")),
+];
+
+/// Made-up path of a std module, it only shows up in errors
+fn std_path(module: &str) -> PathBuf {
+    PathBuf::from(format!("<{}>/{}.{}", STD_PACKAGE, module, MODULE_EXTENSION))
+}
+
+/// Source of the std module at `path`, None for real files
+pub fn std_source(path: &Path) -> Option<&'static str> {
+    STD_MODULES.iter().find(|(module, _)| std_path(module) == path).map(|(_, source)| *source)
+}
+
+/// Source of the std module `name` (`io`, `prelude`, ...)
+pub fn std_module(name: &str) -> Option<&'static str> {
+    std_source(&std_path(name))
+}
+
+/// Name a module's function gets in the flattened program, root functions keep theirs
+pub fn mangle(module: &str, name: &str) -> String {
+    if module.is_empty() {return name.to_string()}
+    format!("{}{}{}", module.replace("::", MODULE_SEPARATOR), MODULE_SEPARATOR, name)
+}
+
+/// What a function was called in the source, `a::b::f` for `a__b__f`. Intrinsics start with
+/// the separator too, so those stay as they are.
+pub fn demangle(name: &str) -> String {
+    match name.starts_with(MODULE_SEPARATOR) {
+        true => name.to_string(),
+        false => name.replace(MODULE_SEPARATOR, "::"),
+    }
+}
+
+/// Function names get joined with MODULE_SEPARATOR after their module's path, so they can't
+/// have it in them, or `a::b__c` and `a::b::c` would be the same function
+fn check_function_name(name: &str) -> Result<(), String> {
+    match name.contains(MODULE_SEPARATOR) {
+        true => Err(format!("Function '{}' can't have '{}' in its name, module paths are joined with it", name, MODULE_SEPARATOR)),
+        false => Ok(()),
+    }
+}
+
+/// Same for every part of a module's path, which also can't end in `_` (`a_::f` and `a::_f`
+/// would both be `a___f`)
+pub fn valid_module_name(name: &str) -> bool {
+    !name.contains(MODULE_SEPARATOR) && !name.ends_with('_')
+}
+
+fn at(location: Location) -> impl Fn(String) -> String {
+    move |error| format!("{} at position {}", error, position(location))
+}
+
+fn display_name(module: &str) -> &str {
+    if module.is_empty() {"the root module"} else {module}
+}
+
+impl Loader {
+    fn is_root(&self, package: usize) -> bool {
+        package == self.packages.len() - 1
+    }
+
+    /// Directories a package's own modules are in
+    fn search_path(&self, package: usize) -> Vec<PathBuf> {
+        if self.is_root(package) {return self.search_path.clone()}
+        vec![self.packages[package].entry.parent().map(Path::to_path_buf).unwrap_or_default()]
+    }
+
+    /// Finds the file behind `import <path>;` in `package`, gives back the file, the module's
+    /// full name and the package it's in
+    fn find(&self, import: &str, package: usize) -> Result<(PathBuf, String, usize), String> {
+        let (first, rest) = match import.split_once("::") {
+            Some((first, rest)) => (first, Some(rest)),
+            None => (import, None),
+        };
+        if let Some(part) = import.split("::").find(|part| !valid_module_name(part)) {
+            return Err(format!("Module name '{}' can't have '{}' in it or end in '_'", part, MODULE_SEPARATOR));
+        }
+        if first == STD_PACKAGE {
+            let Some(module) = rest else {
+                return Err(format!("'{}' is the standard library, import one of its modules like {}::io", first, first));
+            };
+            if std_source(&std_path(module)).is_none() {
+                return Err(format!("The standard library has no module '{}'", module));
+            }
+            return Ok((std_path(module), import.to_string(), package));
+        }
+        let dependency = self.packages[package].dependencies.iter().copied().find(|&dependency| self.packages[dependency].name == first);
+        let (package, name, relative) = match dependency {
+            Some(dependency) => match rest {
+                None => return Ok((self.packages[dependency].entry.clone(), import.to_string(), dependency)),
+                Some(rest) => (dependency, import.to_string(), rest),
+            },
+            None if self.is_root(package) => (package, import.to_string(), import),
+            None => (package, format!("{}::{}", self.packages[package].name, import), import),
+        };
+
+        let relative: PathBuf = relative.split("::").collect();
+        let relative = relative.with_extension(MODULE_EXTENSION);
+        let dirs = self.search_path(package);
+        match dirs.iter().map(|dir| dir.join(&relative)).find(|path| path.is_file()) {
+            Some(found) => Ok((found, name, package)),
+            None => {
+                let dirs: Vec<String> = dirs.iter()
+                    .map(|dir| if dir.as_os_str().is_empty() {".".to_string()} else {dir.display().to_string()}).collect();
+                Err(format!("Can't find module '{}' ({} in {})", import, relative.display(), dirs.join(", ")))
+            }
+        }
+    }
+
+    /// Finds the header behind `import c "<header>";`, same search path as modules
+    fn find_header(&self, header: &str, package: usize) -> Result<(PathBuf, String, usize), String> {
+        let dirs = self.search_path(package);
+        let Some(found) = dirs.iter().map(|dir| dir.join(header)).find(|path| path.is_file()) else {
+            return Err(format!("Can't find header '{}'", header));
+        };
+        // `stm32f4xx-hal.h` is `stm32f4xx_hal`
+        let stem = found.file_stem().unwrap_or_default().to_string_lossy();
+        let mut module: String = stem.chars().map(|c| if c.is_ascii_alphanumeric() {c} else {'_'}).collect();
+        if module.starts_with(|c: char| c.is_ascii_digit()) {module.insert(0, '_')}
+        if KEYWORDS.contains(&module.as_str()) || module == STD_PACKAGE || !valid_module_name(&module) {
+            return Err(format!("Header '{}' would be a module called '{}', which isn't allowed", header, module));
+        }
+        let name = if self.is_root(package) {module} else {format!("{}::{}", self.packages[package].name, module)};
+        Ok((found, name, package))
+    }
+
+    fn load(&mut self, name: &str, path: &Path, package: usize) -> Result<(), String> {
+        let canonical = path.canonicalize().unwrap_or(path.to_path_buf());
+        let text = match std_source(path) {
+            Some(source) => source.to_string(),
+            None if name.is_empty() && let Some(text) = &self.root_text => text.clone(),
+            None if path.extension().is_some_and(|extension| extension == "h") => bindgen(path, &self.search_path(package))?,
+            None => match std::fs::read_to_string(path) {
+                Ok(text) => text,
+                Err(error) => return Err(format!("Can't read '{}': {}", path.display(), error)),
+            },
+        };
+        let file = add_file(&path.display().to_string());
+        let tokens = lexer_in_file(&text, file);
+        let statements = parser(tokens.iter().peekable())?;
+
+        self.loading.push((name.to_string(), canonical.clone()));
+        let mut imports = HashMap::new();
+        for statement in &statements {
+            let Statement::Import { path: import, header, location } = statement else {continue};
+            let at = at(*location);
+            let (found, full_name, found_package) = match header {
+                true => self.find_header(import, package),
+                false => self.find(import, package),
+            }.map_err(&at)?;
+            let alias = full_name.rsplit("::").next().unwrap().to_string();
+            if imports.insert(alias.clone(), full_name.clone()).is_some() {
+                return Err(at(format!("Two imports are called '{}'", alias)));
+            }
+
+            let found_canonical = found.canonicalize().unwrap_or(found.clone());
+            if let Some(start) = self.loading.iter().position(|(_, path)| *path == found_canonical) {
+                let mut cycle: Vec<&str> = self.loading[start..].iter().map(|(name, _)| display_name(name)).collect();
+                cycle.push(&full_name);
+                return Err(at(format!("Import cycle: {}", cycle.join(" -> "))));
+            }
+            if self.paths.contains(&found_canonical) {continue}
+            self.load(&full_name, &found, found_package)?;
+        }
+        self.loading.pop();
+
+        self.paths.push(canonical);
+        self.modules.push(Module { name: name.to_string(), file, statements, imports, package });
+        Ok(())
+    }
+}
+
+/// The type seman gives a folded value
+fn type_of(value: &Expression) -> Type {
+    if let Expression::String(_) = value {Type::Str} else {Type::Int}
+}
+
+/// Constant folding for constant values, they have to end up as a plain literal
+fn fold(expression: &Expression) -> Result<Expression, String> {
+    match expression {
+        Expression::Number(_) | Expression::String(_) => Ok(expression.clone()),
+        Expression::Operation(operation) => {
+            let (left, right) = (fold(&operation.left)?, fold(&operation.right)?);
+            let (Expression::Number(left), Expression::Number(right)) = (&left, &right) else {
+                return Err(format!("Operator {:?} only works on i64, not {} and {}", operation.operator, type_of(&left), type_of(&right)));
+            };
+            let (left, right) = (*left, *right);
+            Ok(Expression::Number(match operation.operator {
+                Operator::Addition => left.wrapping_add(right),
+                Operator::Subtraction => left.wrapping_sub(right),
+                Operator::Multiplication => left.wrapping_mul(right),
+                Operator::Division => {
+                    if right == 0 {return Err("Division by zero".to_string())}
+                    left.wrapping_div(right)
+                }
+                Operator::LesserThan => (left < right) as i64,
+                Operator::GreaterThan => (left > right) as i64,
+                Operator::EqualLesserThan => (left <= right) as i64,
+                Operator::EqualGreaterThan => (left >= right) as i64,
+                Operator::EqualTo => (left == right) as i64,
+                Operator::Inequal => (left != right) as i64,
+            }))
+        }
+        Expression::Variable(name, _) => Err(format!("'{}' isn't a constant defined before this one", name)),
+        _ => Err("Constants can only use literals, operators and other constants".to_string()),
+    }
+}
+
+impl Linker<'_> {
+    /// Finds the module behind a `module::` prefix, by alias or by full name
+    fn imported(&self, qualifier: &str) -> Result<&str, String> {
+        if let Some(module) = self.module.imports.get(qualifier) {return Ok(module)}
+        match self.module.imports.values().find(|module| *module == qualifier) {
+            Some(module) => Ok(module),
+            None => Err(format!("Module '{}' isn't imported in {}", qualifier, display_name(&self.module.name))),
+        }
+    }
+
+    fn function(&self, target: &str) -> Result<String, String> {
+        let Some((qualifier, name)) = target.rsplit_once("::") else {
+            // Own function, then the prelude, then a builtin (or a mistake seman will report)
+            if self.items[&self.module.name].functions.contains_key(target) {return Ok(mangle(&self.module.name, target))}
+            if let Some(prelude) = self.items.get(PRELUDE_MODULE) && prelude.functions.get(target) == Some(&true) {
+                return Ok(mangle(PRELUDE_MODULE, target));
+            }
+            return Ok(target.to_string());
+        };
+        let module = self.imported(qualifier)?;
+        match self.items[module].functions.get(name) {
+            Some(true) => Ok(mangle(module, name)),
+            Some(false) => Err(format!("Function '{}' in module '{}' isn't pub", name, module)),
+            None => Err(format!("Module '{}' has no function '{}'", module, name)),
+        }
+    }
+
+    /// Index of a constant, None if `name` is just a variable
+    fn constant(&self, name: &str, own: &Items) -> Result<Option<usize>, String> {
+        let Some((qualifier, constant)) = name.rsplit_once("::") else {
+            return Ok(own.constants.get(name).copied());
+        };
+        let module = self.imported(qualifier)?;
+        match self.items[module].constants.get(constant) {
+            Some(&index) if self.constants[index].public => Ok(Some(index)),
+            Some(_) => Err(format!("Constant '{}' in module '{}' isn't pub", constant, module)),
+            None => Err(format!("Module '{}' has no constant '{}'", module, constant)),
+        }
+    }
+
+    fn expression(&mut self, expression: &Expression, own: &Items) -> Result<Expression, String> {
+        Ok(match expression {
+            Expression::Variable(name, location) => match self.constant(name, own)? {
+                Some(index) => {
+                    self.constants[index].uses.push(*location);
+                    self.constants[index].value.clone()
+                }
+                None => expression.clone(),
+            },
+            Expression::Operation(operation) => Expression::Operation(Operation {
+                left: Box::new(self.expression(&operation.left, own)?),
+                operator: operation.operator,
+                right: Box::new(self.expression(&operation.right, own)?),
+            }),
+            Expression::FunctionCall { target, args, location } => {
+                let mut new_args = vec![];
+                for arg in args {
+                    new_args.push(self.expression(arg, own)?);
+                }
+                Expression::FunctionCall { target: self.function(target)?, args: new_args, location: *location }
+            }
+            Expression::ReturnValue { value } => Expression::ReturnValue { value: Box::new(self.expression(value, own)?) },
+            Expression::Number(_) | Expression::String(_) => expression.clone(),
+        })
+    }
+
+    fn block(&mut self, statements: &[Statement], own: &Items) -> Result<Vec<Statement>, String> {
+        let mut out = vec![];
+        for statement in statements {
+            let at = at(statement.location());
+            out.push(match statement {
+                Statement::ExpressionStatement(expression, location) => {
+                    Statement::ExpressionStatement(self.expression(expression, own).map_err(&at)?, *location)
+                }
+                Statement::VariableAssignment { name, value, location, name_location } => {
+                    if own.constants.contains_key(name) {return Err(at(format!("Can't assign to constant '{}'", name)))}
+                    Statement::VariableAssignment { name: name.clone(), value: self.expression(value, own).map_err(&at)?, location: *location, name_location: *name_location }
+                }
+                Statement::Asm { asm, location } => {
+                    let mut inputs = vec![];
+                    for (name, value) in &asm.inputs {
+                        inputs.push((name.clone(), self.expression(value, own).map_err(&at)?));
+                    }
+                    if let Some((_, variable)) = asm.outputs.iter().find(|(_, variable)| own.constants.contains_key(variable)) {
+                        return Err(at(format!("Can't assign to constant '{}'", variable)));
+                    }
+                    Statement::Asm { asm: InlineAsm { inputs, ..asm.clone() }, location: *location }
+                }
+                Statement::While { condition, body, location } => Statement::While {
+                    condition: self.expression(condition, own).map_err(&at)?,
+                    body: self.block(body, own)?,
+                    location: *location,
+                },
+                Statement::ConditionalStatement { condition, body, else_body, location } => Statement::ConditionalStatement {
+                    condition: self.expression(condition, own).map_err(&at)?,
+                    body: self.block(body, own)?,
+                    else_body: match else_body {
+                        Some(else_body) => Some(self.block(else_body, own)?),
+                        None => None,
+                    },
+                    location: *location,
+                },
+                // Seman reports these with a better message
+                other => other.clone(),
+            });
+        }
+        Ok(out)
+    }
+
+    /// Flattens the module into `out`
+    fn link(&mut self, own: &Items, out: &mut Vec<Statement>) -> Result<(), String> {
+        for statement in &self.module.statements {
+            let at = at(statement.location());
+            match statement {
+                Statement::Import { .. } | Statement::ConstAssignment { .. } => {}
+                Statement::FunctionAssignment { name, arguments, parameter_types, body, public, export, attributes, location, name_location } => {
+                    for argument in arguments {
+                        if let Expression::Variable(param, _) = argument && own.constants.contains_key(param) {
+                            return Err(at(format!("Parameter '{}' of '{}' has the same name as a constant", param, name)));
+                        }
+                    }
+                    out.push(Statement::FunctionAssignment {
+                        name: mangle(&self.module.name, name),
+                        arguments: arguments.clone(),
+                        parameter_types: parameter_types.clone(),
+                        body: self.block(body, own)?,
+                        public: *public,
+                        export: export.clone(),
+                        attributes: attributes.clone(),
+                        location: *location,
+                        name_location: *name_location,
+                    });
+                }
+                // Called like any other function of the module, the C symbol stays as it is
+                Statement::Extern { function, public, location, name_location } => out.push(Statement::Extern {
+                    function: Extern { name: mangle(&self.module.name, &function.name), ..function.clone() },
+                    public: *public,
+                    location: *location,
+                    name_location: *name_location,
+                }),
+                // `#![allow(...)]`, only galvan lint cares
+                Statement::Attribute { name, .. } if name != NO_STD_ATTRIBUTE => {}
+                Statement::Attribute { name, .. } if !self.module.name.is_empty() => {
+                    return Err(at(format!("#![{}] only works in the root file, not in module '{}'", name, self.module.name)));
+                }
+                // Imported modules don't get to run anything, only the root does
+                _ if !self.module.name.is_empty() => {
+                    return Err(at(format!("Module '{}' can only contain functions, constants and imports", self.module.name)));
+                }
+                other => out.extend(self.block(std::slice::from_ref(other), own)?),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Puts all the modules together into one program, gives back the constants it inlined too
+fn link(modules: &[Module]) -> Result<(Vec<Statement>, Vec<Constant>), String> {
+    let mut items: HashMap<String, Items> = HashMap::new();
+    let mut constants = vec![];
+    let mut out = vec![];
+    for module in modules {
+        let mut own = Items::default();
+        for statement in &module.statements {
+            match statement {
+                Statement::FunctionAssignment { name, public, location, .. } => {
+                    check_function_name(name).map_err(at(*location))?;
+                    own.functions.insert(name.clone(), *public);
+                }
+                Statement::Extern { function, public, location, .. } => {
+                    check_function_name(&function.name).map_err(at(*location))?;
+                    own.functions.insert(function.name.clone(), *public);
+                }
+                _ => {}
+            }
+        }
+        // Constants get evaluated in order, so they can use the ones before them
+        items.insert(module.name.clone(), Items::default());
+        for statement in &module.statements {
+            let Statement::ConstAssignment { name, value, public, location, name_location, value_location } = statement else {continue};
+            let value_at = at(*value_location);
+            let at = at(*location);
+            if own.constants.contains_key(name) {return Err(at(format!("Constant '{}' is already defined", name)))}
+            if own.functions.contains_key(name) {return Err(at(format!("'{}' is both a constant and a function", name)))}
+            let mut linker = Linker { module, items: &items, constants: &mut constants };
+            let value = linker.expression(value, &own).and_then(|value| fold(&value))
+                .map_err(|error| value_at(format!("Constant '{}': {}", name, error)))?;
+            own.constants.insert(name.clone(), constants.len());
+            constants.push(Constant { name: name.clone(), location: *name_location, value, public: *public, uses: vec![] });
+        }
+        items.insert(module.name.clone(), own);
+        let mut linker = Linker { module, items: &items, constants: &mut constants };
+        linker.link(&items[&module.name], &mut out)?;
+    }
+    Ok((out, constants))
+}
+
+/// Function calls in `statements`, nested ones included
+fn called<'a>(statements: &'a [Statement], out: &mut Vec<&'a str>) {
+    fn in_expression<'a>(expression: &'a Expression, out: &mut Vec<&'a str>) {
+        match expression {
+            Expression::FunctionCall { target, args, .. } => {
+                out.push(target);
+                args.iter().for_each(|arg| in_expression(arg, out));
+            }
+            Expression::Operation(operation) => {
+                in_expression(&operation.left, out);
+                in_expression(&operation.right, out);
+            }
+            Expression::ReturnValue { value } => in_expression(value, out),
+            _ => {}
+        }
+    }
+    for statement in statements {
+        match statement {
+            Statement::ExpressionStatement(expression, _) => in_expression(expression, out),
+            Statement::VariableAssignment { value, .. } => in_expression(value, out),
+            Statement::FunctionAssignment { body, .. } => called(body, out),
+            Statement::While { condition, body, .. } => {
+                in_expression(condition, out);
+                called(body, out);
+            }
+            Statement::ConditionalStatement { condition, body, else_body, .. } => {
+                in_expression(condition, out);
+                called(body, out);
+                if let Some(else_body) = else_body {called(else_body, out)}
+            }
+            Statement::Asm { asm, .. } => asm.inputs.iter().for_each(|(_, value)| in_expression(value, out)),
+            _ => {}
+        }
+    }
+}
+
+/// Drops the std functions that nothing ends up calling, so programs only carry the parts
+/// of the standard library they use
+fn prune(statements: Vec<Statement>) -> Vec<Statement> {
+    let prefix = format!("{}{}", STD_PACKAGE, MODULE_SEPARATOR);
+    let std_function = |statement: &Statement| match statement {
+        Statement::FunctionAssignment { name, .. } if name.starts_with(&prefix) => Some(name.clone()),
+        _ => None,
+    };
+    let bodies: HashMap<String, &Statement> = statements.iter()
+        .filter_map(|statement| std_function(statement).map(|name| (name, statement))).collect();
+
+    // Everything outside std is used, and whatever it calls
+    let mut used: HashSet<&str> = HashSet::new();
+    let mut todo: Vec<&str> = vec![];
+    for statement in statements.iter().filter(|statement| std_function(statement).is_none()) {
+        called(std::slice::from_ref(statement), &mut todo);
+    }
+    while let Some(function) = todo.pop() {
+        if !used.insert(function) {continue}
+        if let Some(body) = bodies.get(function) {
+            called(std::slice::from_ref(*body), &mut todo);
+        }
+    }
+    let used: HashSet<String> = used.into_iter().map(str::to_string).collect();
+    statements.into_iter().filter(|statement| std_function(statement).is_none_or(|name| used.contains(&name))).collect()
+}
+
+/// The std function panics end up in, the prelude's `panic` and `assert` go through it
+fn std_panic() -> String {
+    mangle(&format!("{}::core", STD_PACKAGE), "panic")
+}
+
+/// Points std's panic at the `#[panic_handler]` if there is one. Freestanding programs have
+/// nowhere to print to or exit to, so their panic hangs after the handler (if it returns).
+fn redirect_panic(statements: &mut [Statement], freestanding: bool) -> Result<(), String> {
+    let mut handler = None;
+    for statement in statements.iter() {
+        let Statement::FunctionAssignment { name, attributes, location, .. } = statement else {continue};
+        if !attributes.panic_handler {continue}
+        if handler.is_some() {return Err(at(*location)(format!("'{}' is the second #[panic_handler], there can only be one", name)))}
+        handler = Some(name.clone());
+    }
+    if handler.is_none() && !freestanding {return Ok(())}
+
+    let panic = std_panic();
+    for statement in statements.iter_mut() {
+        let Statement::FunctionAssignment { name, body, arguments, location, .. } = statement else {continue};
+        if *name != panic {continue}
+        let message = arguments[0].clone();
+        let mut new_body = vec![];
+        if let Some(handler) = &handler {
+            // Made up, there's no call in the source
+            new_body.push(Statement::ExpressionStatement(Expression::FunctionCall { target: handler.clone(), args: vec![message], location: (0, 0, 0) }, *location));
+        }
+        if freestanding {
+            new_body.push(Statement::While { condition: Expression::Number(1), body: vec![], location: *location });
+            new_body.push(Statement::ExpressionStatement(Expression::ReturnValue { value: Box::new(Expression::Number(0)) }, *location));
+        } else {
+            new_body.append(body);
+        }
+        *body = new_body;
+    }
+    Ok(())
+}
+
+/// Freestanding programs can't use anything that needs an OS underneath (print, the heap,
+/// syscalls), and have to bring their own panic handler if anything can panic.
+/// Runs after `prune()`, so only what the program really calls counts.
+fn check_freestanding(statements: &[Statement]) -> Result<(), String> {
+    let prefix = format!("{}{}", STD_PACKAGE, MODULE_SEPARATOR);
+    let bodies: HashMap<&str, &Statement> = statements.iter().filter_map(|statement| match statement {
+        Statement::FunctionAssignment { name, .. } => Some((name.as_str(), statement)),
+        _ => None,
+    }).collect();
+    let has_handler = statements.iter()
+        .any(|statement| matches!(statement, Statement::FunctionAssignment { attributes, .. } if attributes.panic_handler));
+
+    // The first function `wanted` that calling `function` ends up in, if there is one
+    fn reaches<'a>(function: &'a str, wanted: &dyn Fn(&str) -> bool, bodies: &HashMap<&str, &'a Statement>, seen: &mut HashSet<&'a str>) -> Option<&'a str> {
+        if wanted(function) {return Some(function)}
+        if !seen.insert(function) {return None}
+        let mut calls = vec![];
+        called(std::slice::from_ref(*bodies.get(function)?), &mut calls);
+        calls.into_iter().find_map(|call| reaches(call, wanted, bodies, seen))
+    }
+    let panic = std_panic();
+    let panics = |function: &str| function == panic;
+    let needs_os = |function: &str| function == "print" || intrinsics::needs_os(function);
+
+    // Checked per statement (and per statement of a function), so the error points somewhere useful
+    let mut checked: Vec<&Statement> = vec![];
+    for statement in statements {
+        match statement {
+            Statement::FunctionAssignment { name, .. } if name.starts_with(&prefix) => {}
+            Statement::FunctionAssignment { body, .. } => checked.extend(body),
+            other => checked.push(other),
+        }
+    }
+    for statement in checked {
+        let at = at(statement.location());
+        let mut calls = vec![];
+        called(std::slice::from_ref(statement), &mut calls);
+        for call in calls {
+            if !has_handler && reaches(call, &panics, &bodies, &mut HashSet::new()).is_some() {
+                return Err(at(format!("'{}' can panic, a freestanding program needs a #[panic_handler] function for that", demangle(call))));
+            }
+            match reaches(call, &needs_os, &bodies, &mut HashSet::new()) {
+                Some(os) if os == call => return Err(at(format!("'{}' needs an operating system, which a freestanding program doesn't have", demangle(call)))),
+                Some(os) => return Err(at(format!("'{}' needs an operating system (it ends up calling '{}'), which a freestanding program doesn't have", demangle(call), demangle(os)))),
+                None => {}
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Loads the file at `path` and every module it imports, and flattens them into one program.
+/// `include` are extra directories to look for modules in. `packages` is the package graph
+/// when building a package (the root package last, its entry is `path`), empty otherwise.
+/// `freestanding` is --freestanding, same as a `#![no_std]` in the root file.
+pub fn load_program(path: &str, include: &[String], packages: &[Package], freestanding: bool) -> Result<Vec<Statement>, String> {
+    load_all(path, None, include, packages, freestanding).map(|(statements, _)| statements)
+}
+
+/// `load_program()` for a root file that's already in memory, `path` is still where its
+/// imports get looked for. Doc tests (doc.rs) use it for code that only exists in a comment.
+pub fn load_source(path: &str, text: &str, include: &[String], packages: &[Package]) -> Result<Vec<Statement>, String> {
+    load_all(path, Some(text.to_string()), include, packages, false).map(|(statements, _)| statements)
+}
+
+/// `load_source()` plus the constants that got inlined, with everywhere they were used. The
+/// language server finds its way around a buffer that isn't saved yet with it.
+pub fn resolve_source(path: &str, text: &str, include: &[String]) -> Result<(Vec<Statement>, Vec<Constant>), String> {
+    load_all(path, Some(text.to_string()), include, &[], false)
+}
+
+/// The root package's own modules a program is made of, as (module name, file), the entry
+/// file first with an empty name. Headers and the standard library aren't in it. For
+/// `galvan doc`, which documents the modules a lone file imports too.
+pub fn module_files(path: &str, include: &[String], packages: &[Package]) -> Result<Vec<(String, PathBuf)>, String> {
+    let loader = load_modules(path, None, include, packages)?;
+    let root = loader.packages.len() - 1;
+    let mut files: Vec<(String, PathBuf)> = loader.modules.iter()
+        .filter(|module| module.package == root && !module.name.starts_with(&format!("{}::", STD_PACKAGE)))
+        .filter_map(|module| Some((module.name.clone(), PathBuf::from(file_path(module.file)?))))
+        .filter(|(_, file)| file.extension().is_some_and(|extension| extension == MODULE_EXTENSION))
+        .collect();
+    // The root module is loaded last
+    files.rotate_right(1);
+    Ok(files)
+}
+
+/// Reads and parses the root file and everything it imports, dependencies before the modules
+/// importing them
+fn load_modules(path: &str, root_text: Option<String>, include: &[String], packages: &[Package]) -> Result<Loader, String> {
+    let root = Path::new(path);
+    // Empty for a file in the working directory, so paths in errors don't get a `./`
+    let root_dir = root.parent().map(Path::to_path_buf).unwrap_or_default();
+    let mut search_path = vec![root_dir];
+    search_path.extend(include.iter().map(PathBuf::from));
+
+    let mut packages = packages.to_vec();
+    if packages.is_empty() {
+        packages.push(Package { name: String::new(), entry: root.to_path_buf(), dependencies: vec![] });
+    }
+    let mut loader = Loader { search_path, packages, modules: vec![], paths: vec![], loading: vec![], root_text };
+    let prelude = PRELUDE_MODULE.rsplit("::").next().unwrap();
+    loader.load(PRELUDE_MODULE, &std_path(prelude), loader.packages.len() - 1)?;
+    // Every dependency gets compiled, even the ones nothing imports, in dependency order
+    for package in 0..loader.packages.len() - 1 {
+        let entry = loader.packages[package].entry.clone();
+        let canonical = entry.canonicalize().unwrap_or(entry.clone());
+        if loader.paths.contains(&canonical) {continue}
+        let name = loader.packages[package].name.clone();
+        loader.load(&name, &entry, package)?;
+    }
+    loader.load("", root, loader.packages.len() - 1)?;
+    Ok(loader)
+}
+
+fn load_all(path: &str, root_text: Option<String>, include: &[String], packages: &[Package], freestanding: bool) -> Result<(Vec<Statement>, Vec<Constant>), String> {
+    if debug_prints(MODULES_DEBUG_PRINTS) {eprintln!("- - - MODULES")}
+
+    let loader = load_modules(path, root_text, include, packages)?;
+    let (mut statements, constants) = link(&loader.modules)?;
+    let freestanding = freestanding || parser::freestanding(&statements);
+    if freestanding && !parser::freestanding(&statements) {
+        statements.insert(0, Statement::Attribute { name: NO_STD_ATTRIBUTE.to_string(), location: (0, 0, 0) });
+    }
+    redirect_panic(&mut statements, freestanding)?;
+    let statements = prune(statements);
+    if freestanding {check_freestanding(&statements)?}
+
+    if debug_prints(MODULES_DEBUG_PRINTS) {
+        for module in &loader.modules {
+            eprintln!("{} (file {}, package {}), imports {:?}", display_name(&module.name), module.file, module.package, module.imports);
+        }
+    }
+    if debug_prints(MODULES_DEBUG_PRINTS) {eprintln!("- - - Modules done!")}
+    Ok((statements, constants))
+}
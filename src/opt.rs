@@ -0,0 +1,1033 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::cli::OptLevel;
+use crate::compiler_settings::*;
+use crate::ir::{remove_unreachable_blocks, symbol, verify, BasicBlock, BinaryOp, Function, Instruction, Local, Module, Operand, Terminator};
+use crate::seman::Type;
+use crate::vm::binary;
+
+// IR optimizer, runs between lowering and the backends that work from the IR (x86.rs, riscv.rs,
+// thumb.rs and the bytecode VM). -O0/-O1/-O2/-Os pick a pipeline of passes, and every pass can be
+// switched on or off by name with -Cpass=name,-name. The module gets verified after every pass,
+// so a broken pass shows up as an IR error naming it instead of as a miscompile.
+// Passes stay within what the IR promises: virtual registers are assigned once, so a register
+// can be replaced by whatever it was copied from anywhere, while locals can be stored to many
+// times and need dataflow. -Os never makes code bigger: it only inlines functions that are
+// smaller than the call, or that have a single call and go away with it, and it doesn't add
+// blocks for loop-invariant code or strength reduction.
+
+//
+// PASS MANAGER
+//
+
+/// What the passes need to know about the optimization level
+pub struct Config {
+    /// -Os, nothing that makes the code bigger
+    pub size: bool,
+}
+
+pub struct Pass {
+    pub name: &'static str,
+    run: fn(&mut Module, &Config),
+}
+
+/// Every pass there is, -Cpass takes these names
+pub const PASSES: [Pass; 8] = [
+    Pass { name: "constfold", run: constfold },
+    Pass { name: "copyprop", run: copyprop },
+    Pass { name: "cse", run: cse },
+    Pass { name: "dce", run: dce },
+    Pass { name: "simplifycfg", run: simplifycfg },
+    Pass { name: "inline", run: inline },
+    Pass { name: "licm", run: licm },
+    Pass { name: "strength", run: strength },
+];
+
+/// Passes of an optimization level, in the order they run
+fn pipeline(level: OptLevel) -> &'static [&'static str] {
+    match level {
+        OptLevel::O0 => &[],
+        OptLevel::O1 => &["constfold", "copyprop", "cse", "dce", "simplifycfg"],
+        // Inlining first so the rest sees through calls, then everything again once loop
+        // invariant code and strength reduction left new copies and constants behind
+        OptLevel::O2 | OptLevel::Os => &["inline", "simplifycfg", "constfold", "copyprop", "cse", "licm", "strength",
+            "constfold", "copyprop", "cse", "dce", "simplifycfg", "copyprop", "dce"],
+    }
+}
+
+fn instruction_count(module: &Module) -> usize {
+    module.functions.iter().flat_map(|function| &function.blocks).map(|block| block.instructions.len() + 1).sum()
+}
+
+/// Runs the passes of `level` over the module. `toggles` are the -Cpass ones: a pass switched
+/// off doesn't run at all, one switched on that isn't in the pipeline runs after it. The last
+/// toggle of a pass wins.
+pub fn optimize(module: &mut Module, level: OptLevel, toggles: &[(String, bool)]) -> Result<(), String> {
+    let toggle = |name: &str| toggles.iter().rev().find(|(pass, _)| pass == name).map(|(_, on)| *on);
+    let mut passes: Vec<&str> = pipeline(level).iter().copied().filter(|name| toggle(name) != Some(false)).collect();
+    for (name, _) in toggles {
+        if toggle(name) == Some(true) && !pipeline(level).contains(&name.as_str()) && !passes.contains(&name.as_str()) {
+            passes.push(name);
+        }
+    }
+    if passes.is_empty() {return Ok(())}
+
+    if debug_prints(OPT_DEBUG_PRINTS) {eprintln!("- - - Optimizer")}
+    let config = Config { size: level == OptLevel::Os };
+    for name in passes {
+        let Some(pass) = PASSES.iter().find(|pass| pass.name == name) else {
+            return Err(format!("Unknown optimization pass '{}'", name));
+        };
+        let before = instruction_count(module);
+        (pass.run)(module, &config);
+        if let Err(error) = verify(module) {
+            return Err(format!("{} (after the {} pass)", error, name));
+        }
+        if debug_prints(OPT_DEBUG_PRINTS) {eprintln!("{}: {} -> {} instructions", name, before, instruction_count(module))}
+    }
+    if debug_prints(OPT_DEBUG_PRINTS) {eprintln!("{}", module)}
+    if debug_prints(OPT_DEBUG_PRINTS) {eprintln!("- - - Optimizer done!")}
+    Ok(())
+}
+
+//
+// HELPERS
+//
+
+/// Replaces every read of a register `replace` has something for
+fn replace_uses(function: &mut Function, replace: &dyn Fn(usize) -> Option<Operand>) {
+    for block in &mut function.blocks {
+        let operands = block.instructions.iter_mut().flat_map(|instruction| instruction.operands_mut()).chain(block.terminator.operand_mut());
+        for operand in operands {
+            if let Operand::Register(register) = operand
+                && let Some(replacement) = replace(*register) {*operand = replacement}
+        }
+    }
+}
+
+/// Replaces the result of every copy with what it copied, and drops the copies. Registers are
+/// only assigned once, so that holds everywhere the copy's result is used.
+fn propagate_copies(function: &mut Function) -> bool {
+    let mut copies: HashMap<usize, Operand> = HashMap::new();
+    for instruction in function.blocks.iter().flat_map(|block| &block.instructions) {
+        if let Instruction::Copy { dest, value } = instruction {copies.insert(*dest, *value);}
+    }
+    if copies.is_empty() {return false}
+
+    let resolve = |register: usize| {
+        let mut value = *copies.get(&register)?;
+        while let Operand::Register(next) = value {
+            match copies.get(&next) {
+                Some(next) => value = *next,
+                None => break,
+            }
+        }
+        Some(value)
+    };
+    replace_uses(function, &resolve);
+    for block in &mut function.blocks {
+        block.instructions.retain(|instruction| !matches!(instruction, Instruction::Copy { .. }));
+    }
+    true
+}
+
+/// Whether the instruction does nothing but compute its result, so it can go when the result
+/// isn't used or move somewhere else. Division traps on zero, and x86's idiv on MIN / -1, so
+/// only a constant divisor that's neither counts.
+fn is_pure(instruction: &Instruction) -> bool {
+    match instruction {
+        Instruction::Copy { .. } | Instruction::StringAddress { .. } | Instruction::Load { .. } => true,
+        Instruction::Binary { op: BinaryOp::Div, right, .. } => matches!(right, Operand::Constant(divisor) if *divisor != 0 && *divisor != -1),
+        Instruction::Binary { .. } => true,
+        Instruction::Store { .. } | Instruction::Call { .. } | Instruction::Asm { .. } | Instruction::Line { .. } => false,
+    }
+}
+
+/// Locals an instruction writes, stores and asm outputs
+fn written_locals(instruction: &Instruction) -> Vec<usize> {
+    match instruction {
+        Instruction::Store { local, .. } => vec![*local],
+        Instruction::Asm { outputs, .. } => outputs.iter().map(|(_, local)| *local).collect(),
+        _ => vec![],
+    }
+}
+
+fn predecessors(function: &Function) -> Vec<Vec<usize>> {
+    let mut predecessors = vec![vec![]; function.blocks.len()];
+    for (index, block) in function.blocks.iter().enumerate() {
+        for successor in block.terminator.successors() {predecessors[successor].push(index)}
+    }
+    predecessors
+}
+
+/// The reachable blocks in reverse postorder, every block comes before its successors
+/// (back edges aside)
+fn reverse_postorder(function: &Function) -> Vec<usize> {
+    let mut visited = vec![false; function.blocks.len()];
+    let mut order = vec![];
+    // (block, whether its successors are done)
+    let mut stack = vec![(0, false)];
+    while let Some((block, done)) = stack.pop() {
+        if done {order.push(block); continue}
+        if visited[block] {continue}
+        visited[block] = true;
+        stack.push((block, true));
+        for successor in function.blocks[block].terminator.successors().into_iter().rev() {
+            if !visited[successor] {stack.push((successor, false))}
+        }
+    }
+    order.reverse();
+    order
+}
+
+/// Immediate dominator of every block, the entry block is its own and unreachable blocks have
+/// none. Cooper, Harvey and Kennedy's "A Simple, Fast Dominance Algorithm".
+fn dominators(function: &Function) -> Vec<Option<usize>> {
+    let order = reverse_postorder(function);
+    let mut rank = vec![usize::MAX; function.blocks.len()];
+    for (position, block) in order.iter().enumerate() {rank[*block] = position}
+    let predecessors = predecessors(function);
+
+    let mut idom: Vec<Option<usize>> = vec![None; function.blocks.len()];
+    idom[0] = Some(0);
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for block in order.iter().skip(1) {
+            let mut new_idom: Option<usize> = None;
+            for predecessor in &predecessors[*block] {
+                if idom[*predecessor].is_none() {continue}
+                new_idom = Some(match new_idom {
+                    None => *predecessor,
+                    Some(mut other) => {
+                        let mut finger = *predecessor;
+                        while finger != other {
+                            while rank[finger] > rank[other] {finger = idom[finger].unwrap()}
+                            while rank[other] > rank[finger] {other = idom[other].unwrap()}
+                        }
+                        finger
+                    }
+                });
+            }
+            if new_idom.is_some() && idom[*block] != new_idom {
+                idom[*block] = new_idom;
+                changed = true;
+            }
+        }
+    }
+    idom
+}
+
+fn dominates(idom: &[Option<usize>], dominator: usize, mut block: usize) -> bool {
+    loop {
+        if block == dominator {return true}
+        match idom[block] {
+            Some(parent) if parent != block => block = parent,
+            _ => return false,
+        }
+    }
+}
+
+/// A natural loop: the header dominates every block in the body, and the body jumps back to it
+struct Loop {
+    header: usize,
+    body: Vec<bool>,
+}
+impl Loop {
+    fn contains(&self, block: usize) -> bool {
+        self.body.get(block).copied().unwrap_or(false)
+    }
+}
+
+/// Every loop of the function, inner ones first. Back edges to the same header make one loop.
+fn loops(function: &Function) -> Vec<Loop> {
+    let idom = dominators(function);
+    let predecessors = predecessors(function);
+    let mut loops: Vec<Loop> = vec![];
+    for (block, _) in function.blocks.iter().enumerate().filter(|(block, _)| idom[*block].is_some()) {
+        for header in function.blocks[block].terminator.successors() {
+            if !dominates(&idom, header, block) {continue}
+            let index = match loops.iter().position(|other| other.header == header) {
+                Some(index) => index,
+                None => {
+                    let mut body = vec![false; function.blocks.len()];
+                    body[header] = true;
+                    loops.push(Loop { header, body });
+                    loops.len() - 1
+                }
+            };
+            // Everything that reaches the back edge without going through the header
+            let mut stack = vec![block];
+            while let Some(member) = stack.pop() {
+                if loops[index].body[member] {continue}
+                loops[index].body[member] = true;
+                stack.extend(predecessors[member].iter().filter(|predecessor| idom[**predecessor].is_some()));
+            }
+        }
+    }
+    loops.sort_by_key(|found| found.body.iter().filter(|member| **member).count());
+    loops
+}
+
+/// The block right before the loop, where code that runs once before it goes: the only block
+/// outside the loop that jumps to the header. With `create`, one gets added when there's none.
+fn preheader(function: &mut Function, found: &Loop, create: bool) -> Option<usize> {
+    // The entry block has the caller as a predecessor too
+    if found.header == 0 {return None}
+    let outside: Vec<usize> = predecessors(function)[found.header].iter().copied().filter(|block| !found.contains(*block)).collect();
+    if let [block] = outside[..]
+        && function.blocks[block].terminator == Terminator::Jump(found.header) {return Some(block)}
+    if !create {return None}
+    let preheader = function.blocks.len();
+    function.blocks.push(BasicBlock { instructions: vec![], terminator: Terminator::Jump(found.header) });
+    for block in outside {function.blocks[block].terminator.retarget(found.header, preheader)}
+    Some(preheader)
+}
+
+/// Functions something outside the module can call: the entry function, exports, interrupt
+/// and panic handlers, functions placed in a section, the --entry one and anything inline
+/// assembly mentions.
+/// None when the module has neither an entry function nor exports, then there's no telling.
+fn roots(module: &Module) -> Option<HashSet<String>> {
+    if module.entry().is_none() && module.functions.iter().all(|function| function.export.is_none()) {return None}
+    let templates: Vec<&String> = module.functions.iter().flat_map(|function| &function.blocks).flat_map(|block| &block.instructions)
+        .filter_map(|instruction| match instruction {Instruction::Asm { template, .. } => Some(template), _ => None}).collect();
+    Some(module.functions.iter().filter(|function| {
+        let attributes = &function.attributes;
+        function.name == ENTRY_FUNCTION || function.export.is_some() || attributes.interrupt || attributes.panic_handler
+            || module.entry_symbol.as_ref().is_some_and(|entry| *entry == symbol(&function.name))
+            || attributes.section.is_some() || templates.iter().any(|template| template.contains(&symbol(&function.name)))
+    }).map(|function| function.name.clone()).collect())
+}
+
+//
+// CONSTANT FOLDING AND PROPAGATION
+//
+
+/// What a binary operation with at least one constant or twice the same register comes down
+/// to, without knowing the registers' values
+fn simplify(op: BinaryOp, left: Operand, right: Operand) -> Option<Operand> {
+    use Operand::{Constant, Register};
+    if let (Constant(left), Constant(right)) = (left, right) {
+        // Division by zero stays, it's a runtime error
+        return binary(op, left, right).ok().map(Constant);
+    }
+    match (op, left, right) {
+        (BinaryOp::Add, value, Constant(0)) | (BinaryOp::Add, Constant(0), value)
+        | (BinaryOp::Sub, value, Constant(0))
+        | (BinaryOp::Mul, value, Constant(1)) | (BinaryOp::Mul, Constant(1), value)
+        | (BinaryOp::Div, value, Constant(1)) => Some(value),
+        (BinaryOp::Mul, _, Constant(0)) | (BinaryOp::Mul, Constant(0), _) => Some(Constant(0)),
+        (BinaryOp::Sub | BinaryOp::Ne | BinaryOp::Lt | BinaryOp::Gt, Register(left), Register(right)) if left == right => Some(Constant(0)),
+        (BinaryOp::Eq | BinaryOp::Le | BinaryOp::Ge, Register(left), Register(right)) if left == right => Some(Constant(1)),
+        _ => None,
+    }
+}
+
+/// Constant value of every local at the start of every block, None where it isn't known.
+/// Blocks that can't be reached have no state.
+fn local_constants(function: &Function) -> Vec<Option<Vec<Option<i64>>>> {
+    let transfer = |block: &BasicBlock, state: &mut Vec<Option<i64>>| {
+        for instruction in &block.instructions {
+            match instruction {
+                Instruction::Store { local, value: Operand::Constant(value) } => state[*local] = Some(*value),
+                _ => for local in written_locals(instruction) {state[local] = None},
+            }
+        }
+    };
+
+    let mut states: Vec<Option<Vec<Option<i64>>>> = vec![None; function.blocks.len()];
+    // Parameters come from the caller, and nothing else is known before the first store either
+    states[0] = Some(vec![None; function.locals.len()]);
+    let order = reverse_postorder(function);
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for block in &order {
+            let Some(mut state) = states[*block].clone() else {continue};
+            transfer(&function.blocks[*block], &mut state);
+            for successor in function.blocks[*block].terminator.successors() {
+                let merged = match &states[successor] {
+                    None => state.clone(),
+                    Some(old) => old.iter().zip(&state).map(|(old, new)| if old == new {*old} else {None}).collect(),
+                };
+                if states[successor].as_ref() != Some(&merged) {
+                    states[successor] = Some(merged);
+                    changed = true;
+                }
+            }
+        }
+    }
+    states
+}
+
+/// `constfold`: computes what can be computed at compile time, replaces registers and loads
+/// that are known to be constant with the constant, and turns branches on constants into jumps
+fn constfold(module: &mut Module, _config: &Config) {
+    for function in &mut module.functions {
+        loop {
+            let mut changed = false;
+
+            let states = local_constants(function);
+            for (block, state) in function.blocks.iter_mut().zip(states) {
+                let Some(mut state) = state else {continue};
+                for instruction in &mut block.instructions {
+                    if let Instruction::Load { dest, local } = instruction
+                        && let Some(value) = state[*local] {
+                            *instruction = Instruction::Copy { dest: *dest, value: Operand::Constant(value) };
+                            changed = true;
+                        }
+                    match instruction {
+                        Instruction::Store { local, value: Operand::Constant(value) } => state[*local] = Some(*value),
+                        _ => for local in written_locals(instruction) {state[local] = None},
+                    }
+                }
+            }
+
+            for instruction in function.blocks.iter_mut().flat_map(|block| &mut block.instructions) {
+                if let Instruction::Binary { dest, op, left, right } = instruction
+                    && let Some(value) = simplify(*op, *left, *right) {
+                        *instruction = Instruction::Copy { dest: *dest, value };
+                        changed = true;
+                    }
+            }
+            changed |= propagate_copies(function);
+
+            let mut folded = false;
+            for block in &mut function.blocks {
+                if let Terminator::Branch { condition: Operand::Constant(condition), then_block, else_block } = block.terminator {
+                    block.terminator = Terminator::Jump(if condition != 0 {then_block} else {else_block});
+                    folded = true;
+                }
+            }
+            if folded {remove_unreachable_blocks(function)}
+            if !changed && !folded {break}
+        }
+    }
+}
+
+//
+// COPY PROPAGATION
+//
+
+/// `copyprop`: uses whatever a register was copied from instead of the copy, and within a block
+/// reuses the value a local was last stored or loaded with instead of loading it again
+fn copyprop(module: &mut Module, _config: &Config) {
+    for function in &mut module.functions {
+        for block in &mut function.blocks {
+            let mut known: HashMap<usize, Operand> = HashMap::new();
+            for instruction in &mut block.instructions {
+                match instruction {
+                    Instruction::Store { local, value } => {known.insert(*local, *value);}
+                    Instruction::Load { dest, local } => match known.get(local) {
+                        Some(value) => *instruction = Instruction::Copy { dest: *dest, value: *value },
+                        None => {known.insert(*local, Operand::Register(*dest));}
+                    },
+                    _ => for local in written_locals(instruction) {known.remove(&local);},
+                }
+            }
+        }
+        propagate_copies(function);
+    }
+}
+
+//
+// COMMON SUBEXPRESSION ELIMINATION
+//
+
+/// What an instruction computes, for finding the same thing computed twice
+#[derive(PartialEq)]
+enum Expression {
+    Binary(BinaryOp, Operand, Operand),
+    String(usize),
+}
+
+/// The same computation always gives the same expression: operands of commutative operations
+/// in order, and greater than turned around into lesser than
+fn expression(instruction: &Instruction) -> Option<(usize, Expression)> {
+    let key = |operand: &Operand| match operand {
+        Operand::Register(register) => (0, *register as i64),
+        Operand::Constant(value) => (1, *value),
+    };
+    match instruction {
+        Instruction::StringAddress { dest, index } => Some((*dest, Expression::String(*index))),
+        Instruction::Binary { dest, op, left, right } => {
+            let (op, left, right) = match op {
+                BinaryOp::Gt => (BinaryOp::Lt, *right, *left),
+                BinaryOp::Ge => (BinaryOp::Le, *right, *left),
+                BinaryOp::Add | BinaryOp::Mul | BinaryOp::Eq | BinaryOp::Ne if key(right) < key(left) => (*op, *right, *left),
+                _ => (*op, *left, *right),
+            };
+            Some((*dest, Expression::Binary(op, left, right)))
+        }
+        _ => None,
+    }
+}
+
+/// `cse`: walks the dominator tree, and an expression that was already computed in a block
+/// dominating this one becomes a copy of that result
+fn cse(module: &mut Module, _config: &Config) {
+    for function in &mut module.functions {
+        let idom = dominators(function);
+        let mut children = vec![vec![]; function.blocks.len()];
+        for (block, parent) in idom.iter().enumerate() {
+            if let Some(parent) = parent
+                && *parent != block {children[*parent].push(block)}
+        }
+
+        // (expression, register it's in), the ones of the blocks dominating the current one
+        let mut available: Vec<(Expression, usize)> = vec![];
+        // (block, how many expressions its dominators made available)
+        let mut stack = vec![(0, 0)];
+        let mut changed = false;
+        while let Some((block, scope)) = stack.pop() {
+            available.truncate(scope);
+            for instruction in &mut function.blocks[block].instructions {
+                let Some((dest, expression)) = expression(instruction) else {continue};
+                match available.iter().find(|(other, _)| *other == expression) {
+                    Some((_, register)) => {
+                        *instruction = Instruction::Copy { dest, value: Operand::Register(*register) };
+                        changed = true;
+                    }
+                    None => available.push((expression, dest)),
+                }
+            }
+            let scope = available.len();
+            for child in children[block].iter().rev() {stack.push((*child, scope))}
+        }
+        if changed {propagate_copies(function);}
+    }
+}
+
+//
+// DEAD CODE ELIMINATION
+//
+
+/// Removes stores to locals that get stored again (or the function returns) before anything
+/// loads them, found with the usual backwards liveness
+fn remove_dead_stores(function: &mut Function) -> bool {
+    let transfer = |block: &BasicBlock, live: &mut HashSet<usize>| {
+        for instruction in block.instructions.iter().rev() {
+            for local in written_locals(instruction) {live.remove(&local);}
+            if let Instruction::Load { local, .. } = instruction {live.insert(*local);}
+        }
+    };
+    let mut live_in: Vec<HashSet<usize>> = vec![HashSet::new(); function.blocks.len()];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (index, block) in function.blocks.iter().enumerate().rev() {
+            let mut live: HashSet<usize> = block.terminator.successors().iter().flat_map(|successor| live_in[*successor].iter().copied()).collect();
+            transfer(block, &mut live);
+            if live != live_in[index] {
+                live_in[index] = live;
+                changed = true;
+            }
+        }
+    }
+
+    let mut removed = false;
+    for index in 0..function.blocks.len() {
+        let mut live: HashSet<usize> = function.blocks[index].terminator.successors().iter().flat_map(|successor| live_in[*successor].iter().copied()).collect();
+        let instructions = std::mem::take(&mut function.blocks[index].instructions);
+        let mut kept = vec![];
+        for instruction in instructions.into_iter().rev() {
+            if let Instruction::Store { local, .. } = instruction
+                && !live.contains(&local) {removed = true; continue}
+            for local in written_locals(&instruction) {live.remove(&local);}
+            if let Instruction::Load { local, .. } = instruction {live.insert(local);}
+            kept.push(instruction);
+        }
+        kept.reverse();
+        function.blocks[index].instructions = kept;
+    }
+    removed
+}
+
+/// Removes pure instructions whose result nobody reads
+fn remove_unused_results(function: &mut Function) -> bool {
+    let mut removed = false;
+    loop {
+        let mut used = vec![false; function.registers.len()];
+        for block in &function.blocks {
+            let operands = block.instructions.iter().flat_map(|instruction| instruction.operands()).chain(block.terminator.operand());
+            for operand in operands {
+                if let Operand::Register(register) = operand {used[*register] = true}
+            }
+        }
+        let mut changed = false;
+        for block in &mut function.blocks {
+            block.instructions.retain(|instruction| {
+                let dead = is_pure(instruction) && instruction.dest().is_some_and(|dest| !used[dest]);
+                changed |= dead;
+                !dead
+            });
+        }
+        if !changed {return removed}
+        removed = true;
+    }
+}
+
+/// Removes locals nothing uses anymore (parameters stay, the caller passes them) and renumbers
+/// the rest
+fn remove_unused_locals(function: &mut Function) {
+    let mut used = vec![false; function.locals.len()];
+    for local in used.iter_mut().take(function.parameters) {*local = true}
+    for instruction in function.blocks.iter().flat_map(|block| &block.instructions) {
+        match instruction {
+            Instruction::Load { local, .. } | Instruction::Store { local, .. } => used[*local] = true,
+            Instruction::Asm { outputs, .. } => for (_, local) in outputs {used[*local] = true},
+            _ => {}
+        }
+    }
+    if used.iter().all(|used| *used) {return}
+
+    let mut renumber = vec![0; function.locals.len()];
+    let mut count = 0;
+    for (index, used) in used.iter().enumerate() {
+        if *used {renumber[index] = count; count += 1}
+    }
+    let locals = std::mem::take(&mut function.locals);
+    function.locals = locals.into_iter().zip(&used).filter(|(_, used)| **used).map(|(local, _)| local).collect();
+    for instruction in function.blocks.iter_mut().flat_map(|block| &mut block.instructions) {
+        match instruction {
+            Instruction::Load { local, .. } | Instruction::Store { local, .. } => *local = renumber[*local],
+            Instruction::Asm { outputs, .. } => for (_, local) in outputs {*local = renumber[*local]},
+            _ => {}
+        }
+    }
+}
+
+/// `dce`: removes unused results, stores nobody loads, unused locals, blocks that can't be
+/// reached and functions that are never called
+fn dce(module: &mut Module, _config: &Config) {
+    for function in &mut module.functions {
+        remove_unreachable_blocks(function);
+        while remove_unused_results(function) | remove_dead_stores(function) {}
+        remove_unused_locals(function);
+    }
+
+    let Some(roots) = roots(module) else {return};
+    let mut called: HashSet<String> = HashSet::new();
+    let mut stack: Vec<String> = roots.into_iter().collect();
+    while let Some(name) = stack.pop() {
+        if !called.insert(name.clone()) {continue}
+        let Some(function) = module.functions.iter().find(|function| function.name == name) else {continue};
+        for instruction in function.blocks.iter().flat_map(|block| &block.instructions) {
+            if let Instruction::Call { function: target, .. } = instruction {stack.push(target.clone())}
+        }
+    }
+    module.functions.retain(|function| called.contains(&function.name));
+}
+
+//
+// CONTROL FLOW
+//
+
+/// `simplifycfg`: branches that go to the same block either way become jumps, jumps to empty
+/// blocks that only jump on go straight there, and a block only ever jumped to from one other
+/// block gets merged into it
+fn simplifycfg(module: &mut Module, _config: &Config) {
+    for function in &mut module.functions {
+        loop {
+            let mut changed = false;
+            for block in &mut function.blocks {
+                if let Terminator::Branch { then_block, else_block, .. } = block.terminator
+                    && then_block == else_block {
+                        block.terminator = Terminator::Jump(then_block);
+                        changed = true;
+                    }
+            }
+
+            for index in 0..function.blocks.len() {
+                let Terminator::Jump(target) = function.blocks[index].terminator else {continue};
+                // Lines don't count, the block jumped to has its own
+                if !function.blocks[index].instructions.iter().all(Instruction::is_line) || target == index {continue}
+                for block in &mut function.blocks {
+                    if block.terminator.successors().contains(&index) {
+                        block.terminator.retarget(index, target);
+                        changed = true;
+                    }
+                }
+            }
+
+            let predecessors = predecessors(function);
+            for index in 0..function.blocks.len() {
+                let Terminator::Jump(target) = function.blocks[index].terminator else {continue};
+                if target == index || target == 0 || predecessors[target] != [index] {continue}
+                let merged = std::mem::replace(&mut function.blocks[target], BasicBlock { instructions: vec![], terminator: Terminator::Return(None) });
+                function.blocks[index].instructions.extend(merged.instructions);
+                function.blocks[index].terminator = merged.terminator;
+                changed = true;
+                // The predecessors changed, the rest waits for the next round
+                break;
+            }
+
+            remove_unreachable_blocks(function);
+            if !changed {break}
+        }
+    }
+}
+
+//
+// INLINING
+//
+
+/// Instructions and terminators, lines don't count so -g doesn't change what gets inlined
+fn size(function: &Function) -> usize {
+    function.blocks.iter().map(|block| block.instructions.iter().filter(|instruction| !instruction.is_line()).count() + 1).sum()
+}
+
+/// A name for a local that the function doesn't have yet
+fn unique_local(function: &Function, name: String) -> String {
+    if function.local_index(&name).is_none() {return name}
+    (2..).map(|count| format!("{}.{}", name, count)).find(|name| function.local_index(name).is_none()).unwrap()
+}
+
+/// Puts the body of `callee` where the call at `instruction` in `block` is. The block gets
+/// split in two: the first half stores the arguments into the callee's parameters and jumps to
+/// its body, every return stores the result and jumps to the second half, which loads it.
+/// Returns the block the second half is in.
+fn inline_call(function: &mut Function, block: usize, instruction: usize, callee: &Function) -> usize {
+    let Instruction::Call { dest, args, .. } = function.blocks[block].instructions[instruction].clone() else {unreachable!()};
+
+    let registers = function.registers.len();
+    function.registers.extend(&callee.registers);
+    let mut locals = vec![];
+    for local in &callee.locals {
+        let name = unique_local(function, format!("{}.{}", callee.name, local.name));
+        function.locals.push(Local { name, ty: local.ty });
+        locals.push(function.locals.len() - 1);
+    }
+    let result = dest.map(|_| {
+        let name = unique_local(function, format!("{}.return", callee.name));
+        function.locals.push(Local { name, ty: callee.return_type });
+        function.locals.len() - 1
+    });
+
+    let after = function.blocks.len();
+    let body = after + 1;
+    // With -g the second half is back on the caller's line
+    let line = function.blocks[block].instructions[..instruction].iter().rev().find(|instruction| instruction.is_line()).cloned();
+    let rest = function.blocks[block].instructions.split_off(instruction + 1);
+    function.blocks[block].instructions.pop();
+    let mut instructions: Vec<Instruction> = line.into_iter().collect();
+    instructions.extend(dest.zip(result).map(|(dest, local)| Instruction::Load { dest, local }));
+    instructions.extend(rest);
+    let terminator = std::mem::replace(&mut function.blocks[block].terminator, Terminator::Jump(body));
+    for (parameter, arg) in args.into_iter().enumerate() {
+        function.blocks[block].instructions.push(Instruction::Store { local: locals[parameter], value: arg });
+    }
+    function.blocks.push(BasicBlock { instructions, terminator });
+
+    let operand = |operand: Operand| match operand {
+        Operand::Register(register) => Operand::Register(registers + register),
+        constant => constant,
+    };
+    for (index, original) in callee.blocks.iter().enumerate() {
+        let mut instructions = vec![];
+        // The callee's first line is its declaration, which only the prologue had
+        let skip = if index == 0 && original.instructions.first().is_some_and(Instruction::is_line) {1} else {0};
+        for instruction in &original.instructions[skip..] {
+            let mut instruction = instruction.clone();
+            for value in instruction.operands_mut() {*value = operand(*value)}
+            match &mut instruction {
+                Instruction::Copy { dest, .. } | Instruction::StringAddress { dest, .. } | Instruction::Binary { dest, .. } => *dest += registers,
+                Instruction::Load { dest, local } => {*dest += registers; *local = locals[*local]}
+                Instruction::Store { local, .. } => *local = locals[*local],
+                Instruction::Call { dest, .. } => if let Some(dest) = dest {*dest += registers},
+                Instruction::Asm { outputs, .. } => for (_, local) in outputs {*local = locals[*local]},
+                Instruction::Line { .. } => {}
+            }
+            instructions.push(instruction);
+        }
+        let terminator = match &original.terminator {
+            Terminator::Jump(target) => Terminator::Jump(body + target),
+            Terminator::Branch { condition, then_block, else_block } => Terminator::Branch {
+                condition: operand(*condition), then_block: body + then_block, else_block: body + else_block,
+            },
+            Terminator::Return(value) => {
+                if let (Some(local), Some(value)) = (result, value) {instructions.push(Instruction::Store { local, value: operand(*value) })}
+                Terminator::Jump(after)
+            }
+        };
+        function.blocks.push(BasicBlock { instructions, terminator });
+    }
+    after
+}
+
+/// Functions in the order their calls get inlined: callees before callers, so a body that
+/// gets inlined already has its own calls inlined. Recursion just ends the walk.
+fn bottom_up(module: &Module) -> Vec<usize> {
+    let mut order = vec![];
+    let mut visited = vec![false; module.functions.len()];
+    // (function, whether its callees are done)
+    let mut stack: Vec<(usize, bool)> = (0..module.functions.len()).rev().map(|index| (index, false)).collect();
+    while let Some((index, done)) = stack.pop() {
+        if done {order.push(index); continue}
+        if visited[index] {continue}
+        visited[index] = true;
+        stack.push((index, true));
+        for instruction in module.functions[index].blocks.iter().flat_map(|block| &block.instructions) {
+            let Instruction::Call { function: target, .. } = instruction else {continue};
+            if let Some(callee) = module.functions.iter().position(|function| function.name == *target) && !visited[callee] {
+                stack.push((callee, false));
+            }
+        }
+    }
+    order
+}
+
+/// `inline`: puts the bodies of small functions where they're called. -O2 inlines anything up
+/// to `INLINE_THRESHOLD` instructions, -Os only what's no bigger than the call itself, or what
+/// is called once and can go away afterwards. Recursive functions never get inlined.
+fn inline(module: &mut Module, config: &Config) {
+    let roots = roots(module);
+    let mut calls: HashMap<String, usize> = HashMap::new();
+    for instruction in module.functions.iter().flat_map(|function| &function.blocks).flat_map(|block| &block.instructions) {
+        if let Instruction::Call { function, .. } = instruction {*calls.entry(function.clone()).or_default() += 1}
+    }
+    let inlinable = |function: &Function| {
+        let attributes = &function.attributes;
+        if attributes.interrupt || attributes.panic_handler || attributes.section.is_some() {return false}
+        // Labels in inline assembly can't be there twice
+        let instructions = || function.blocks.iter().flat_map(|block| &block.instructions);
+        if instructions().any(|instruction| matches!(instruction, Instruction::Asm { .. })) {return false}
+        if instructions().any(|instruction| matches!(instruction, Instruction::Call { function: target, .. } if *target == function.name)) {return false}
+        let removable = roots.as_ref().is_some_and(|roots| !roots.contains(&function.name)) && calls.get(&function.name) == Some(&1);
+        match config.size {
+            true => size(function) <= function.parameters + 2 || removable,
+            false => size(function) <= INLINE_THRESHOLD,
+        }
+    };
+
+    for index in bottom_up(module) {
+        let mut function = module.functions[index].clone();
+        // Only the function's own blocks, the ones that came with an inlined body are done
+        let mut pending: Vec<usize> = (0..function.blocks.len()).rev().collect();
+        while let Some(block) = pending.pop() {
+            let call = function.blocks[block].instructions.iter().enumerate().find_map(|(at, instruction)| match instruction {
+                Instruction::Call { function: target, .. } if *target != function.name => module.functions.iter()
+                    .find(|callee| callee.name == *target && inlinable(callee)).map(|callee| (at, callee)),
+                _ => None,
+            });
+            if let Some((at, callee)) = call {pending.push(inline_call(&mut function, block, at, callee))}
+        }
+        module.functions[index] = function;
+    }
+}
+
+//
+// LOOPS
+//
+
+/// Moves the pure instructions of a loop whose operands come from outside it into the
+/// preheader, returns whether it moved anything
+fn hoist(function: &mut Function, found: &Loop, config: &Config) -> bool {
+    let blocks: Vec<usize> = (0..function.blocks.len()).filter(|block| found.contains(*block)).collect();
+    let mut defined: HashSet<usize> = HashSet::new();
+    let mut stored: HashSet<usize> = HashSet::new();
+    for instruction in blocks.iter().flat_map(|block| &function.blocks[*block].instructions) {
+        defined.extend(instruction.dest());
+        stored.extend(written_locals(instruction));
+    }
+
+    // Over and over, since moving one instruction can make the ones using it movable
+    let mut hoisted: Vec<(usize, usize)> = vec![];
+    loop {
+        let mut moved = false;
+        for block in &blocks {
+            for (index, instruction) in function.blocks[*block].instructions.iter().enumerate() {
+                if hoisted.contains(&(*block, index)) || !is_pure(instruction) {continue}
+                if let Instruction::Load { local, .. } = instruction
+                    && stored.contains(local) {continue}
+                let invariant = instruction.operands().iter().all(|operand| match operand {
+                    Operand::Register(register) => !defined.contains(register),
+                    Operand::Constant(_) => true,
+                });
+                if invariant {
+                    hoisted.push((*block, index));
+                    defined.remove(&instruction.dest().unwrap());
+                    moved = true;
+                }
+            }
+        }
+        if !moved {break}
+    }
+    if hoisted.is_empty() {return false}
+    let Some(preheader) = preheader(function, found, !config.size) else {return false};
+
+    let instructions: Vec<Instruction> = hoisted.iter().map(|(block, index)| function.blocks[*block].instructions[*index].clone()).collect();
+    for block in blocks {
+        let mut index = 0;
+        function.blocks[block].instructions.retain(|_| {
+            index += 1;
+            !hoisted.contains(&(block, index - 1))
+        });
+    }
+    function.blocks[preheader].instructions.extend(instructions);
+    true
+}
+
+/// `licm`: loop-invariant code motion, computes what's the same on every iteration once before
+/// the loop. -Os only does it when the loop already has a block to put the code in.
+fn licm(module: &mut Module, config: &Config) {
+    for function in &mut module.functions {
+        // Loops get found again after every change, the blocks moved around
+        'next: loop {
+            for found in loops(function) {
+                if hoist(function, &found, config) {continue 'next}
+            }
+            break;
+        }
+    }
+}
+
+/// An induction variable: the loop's only store to `local` is `local = local + step`
+struct Induction {
+    local: usize,
+    step: i64,
+    /// Block the store is in
+    block: usize,
+}
+
+fn induction_variables(function: &Function, found: &Loop) -> Vec<Induction> {
+    let mut stores: HashMap<usize, Vec<(usize, usize)>> = HashMap::new();
+    for block in (0..function.blocks.len()).filter(|block| found.contains(*block)) {
+        for (index, instruction) in function.blocks[block].instructions.iter().enumerate() {
+            for local in written_locals(instruction) {
+                // Asm outputs count, but never as an induction variable
+                let at = if matches!(instruction, Instruction::Store { .. }) {index} else {usize::MAX};
+                stores.entry(local).or_default().push((block, at));
+            }
+        }
+    }
+
+    let mut found_variables = vec![];
+    for (local, stores) in stores {
+        let [(block, index)] = stores[..] else {continue};
+        if index == usize::MAX {continue}
+        let instructions = &function.blocks[block].instructions;
+        let Instruction::Store { value: Operand::Register(value), .. } = instructions[index] else {continue};
+        // The new value and the load it comes from have to be right there in the block
+        let definition = |register: usize| instructions[..index].iter().find(|instruction| instruction.dest() == Some(register));
+        let loaded = |operand: Operand| matches!(operand, Operand::Register(register)
+            if matches!(definition(register), Some(Instruction::Load { local: other, .. }) if *other == local));
+        let step = match definition(value) {
+            Some(Instruction::Binary { op: BinaryOp::Add, left, right: Operand::Constant(step), .. }) if loaded(*left) => *step,
+            Some(Instruction::Binary { op: BinaryOp::Add, left: Operand::Constant(step), right, .. }) if loaded(*right) => *step,
+            Some(Instruction::Binary { op: BinaryOp::Sub, left, right: Operand::Constant(step), .. }) if loaded(*left) => step.wrapping_neg(),
+            _ => continue,
+        };
+        found_variables.push(Induction { local, step, block });
+    }
+    found_variables.sort_by_key(|variable| variable.local);
+    found_variables
+}
+
+/// `variable * factor` in the loop, as (result, register the variable was loaded into)
+fn scaled_uses(function: &Function, found: &Loop, variable: usize, factor: i64) -> Vec<(usize, usize)> {
+    let loads: HashSet<usize> = (0..function.blocks.len()).filter(|block| found.contains(*block))
+        .flat_map(|block| &function.blocks[block].instructions)
+        .filter_map(|instruction| match instruction {
+            Instruction::Load { dest, local } if *local == variable => Some(*dest),
+            _ => None,
+        }).collect();
+    (0..function.blocks.len()).filter(|block| found.contains(*block))
+        .flat_map(|block| &function.blocks[block].instructions)
+        .filter_map(|instruction| match instruction {
+            Instruction::Binary { dest, op: BinaryOp::Mul, left: Operand::Register(register), right: Operand::Constant(constant) }
+            | Instruction::Binary { dest, op: BinaryOp::Mul, left: Operand::Constant(constant), right: Operand::Register(register) }
+                if loads.contains(register) && *constant == factor => Some((*dest, *register)),
+            _ => None,
+        }).collect()
+}
+
+/// Replaces `induction * constant` in the loop with a local that gets the step times the
+/// constant added every time the induction variable changes. Returns whether it did.
+fn reduce_induction(function: &mut Function, found: &Loop) -> bool {
+    for variable in induction_variables(function, found) {
+        let factors: Vec<i64> = (-1..=2).collect();
+        let factor = (0..function.blocks.len()).filter(|block| found.contains(*block))
+            .flat_map(|block| &function.blocks[block].instructions)
+            .find_map(|instruction| match instruction {
+                Instruction::Binary { op: BinaryOp::Mul, right: Operand::Constant(factor), .. }
+                | Instruction::Binary { op: BinaryOp::Mul, left: Operand::Constant(factor), .. } if !factors.contains(factor) => Some(*factor),
+                _ => None,
+            })
+            .filter(|factor| !scaled_uses(function, found, variable.local, *factor).is_empty());
+        let Some(factor) = factor else {continue};
+        let uses = scaled_uses(function, found, variable.local, factor);
+        let Some(preheader) = preheader(function, found, true) else {return false};
+
+        let name = unique_local(function, format!("{}*{}", function.locals[variable.local].name, factor));
+        function.locals.push(Local { name, ty: Type::Int });
+        let scaled = function.locals.len() - 1;
+        let register = |function: &mut Function| {
+            function.registers.push(Type::Int);
+            function.registers.len() - 1
+        };
+
+        // Before the loop: scaled = variable * factor
+        let (loaded, product) = (register(function), register(function));
+        function.blocks[preheader].instructions.extend([
+            Instruction::Load { dest: loaded, local: variable.local },
+            Instruction::Binary { dest: product, op: BinaryOp::Mul, left: Operand::Register(loaded), right: Operand::Constant(factor) },
+            Instruction::Store { local: scaled, value: Operand::Register(product) },
+        ]);
+
+        // Right after every load of the variable the multiplication uses, the scaled one gets
+        // loaded too, and the multiplication becomes a copy of that
+        for (result, loaded) in uses {
+            let copy = register(function);
+            for block in (0..function.blocks.len()).filter(|block| found.contains(*block)) {
+                let instructions = &mut function.blocks[block].instructions;
+                if let Some(index) = instructions.iter().position(|instruction| instruction.dest() == Some(loaded)) {
+                    instructions.insert(index + 1, Instruction::Load { dest: copy, local: scaled });
+                }
+                if let Some(index) = instructions.iter().position(|instruction| instruction.dest() == Some(result)) {
+                    instructions[index] = Instruction::Copy { dest: result, value: Operand::Register(copy) };
+                }
+            }
+        }
+
+        // Right after the variable's store: scaled += step * factor
+        let instructions = &function.blocks[variable.block].instructions;
+        let index = instructions.iter().position(|instruction| matches!(instruction, Instruction::Store { local, .. } if *local == variable.local)).unwrap();
+        let (old, new) = (register(function), register(function));
+        function.blocks[variable.block].instructions.splice(index + 1..index + 1, [
+            Instruction::Load { dest: old, local: scaled },
+            Instruction::Binary { dest: new, op: BinaryOp::Add, left: Operand::Register(old), right: Operand::Constant(variable.step.wrapping_mul(factor)) },
+            Instruction::Store { local: scaled, value: Operand::Register(new) },
+        ]);
+        return true;
+    }
+    false
+}
+
+/// `strength`: cheaper instructions for the same result. `x * 2` becomes `x + x`, multiplying
+/// or dividing by -1 becomes a subtraction from 0, and outside -Os a loop variable times a
+/// constant becomes an addition every time the variable changes.
+fn strength(module: &mut Module, config: &Config) {
+    for function in &mut module.functions {
+        for instruction in function.blocks.iter_mut().flat_map(|block| &mut block.instructions) {
+            let Instruction::Binary { dest, op, left, right } = instruction else {continue};
+            let replacement = match (*op, *left, *right) {
+                (BinaryOp::Mul, value, Operand::Constant(2)) | (BinaryOp::Mul, Operand::Constant(2), value) => (BinaryOp::Add, value, value),
+                (BinaryOp::Mul, value, Operand::Constant(-1)) | (BinaryOp::Mul, Operand::Constant(-1), value)
+                | (BinaryOp::Div, value, Operand::Constant(-1)) => (BinaryOp::Sub, Operand::Constant(0), value),
+                _ => continue,
+            };
+            *instruction = Instruction::Binary { dest: *dest, op: replacement.0, left: replacement.1, right: replacement.2 };
+        }
+
+        if config.size {continue}
+        // The variable moved blocks around when it made a preheader, so the loops get found again
+        'next: loop {
+            for found in loops(function) {
+                if reduce_induction(function, &found) {continue 'next}
+            }
+            break;
+        }
+    }
+}
@@ -0,0 +1,661 @@
+use std::path::{Path, PathBuf};
+
+use crate::cli::{Arch, Command, Emit, OptLevel, Options};
+use crate::compiler_settings::*;
+use crate::fmt::Style;
+use crate::lint::{self, Level};
+use crate::modules::{valid_module_name, Package};
+
+// Packages, a directory with a galvan.toml:
+//
+//     [package]
+//     name = "blinky"
+//     version = "0.1.0"
+//     entry = "src/main.gv"      # optional, src/main.gv or else src/lib.gv
+//     target = "x86_64-linux"    # optional, x86_64-linux, riscv32-linux, thumbv7m-linux, thumbv6m-linux, wasm32, c or gvc
+//     opt-level = "s"            # optional, 0, 1, 2 or s, -O on the command line wins
+//
+//     [fmt]                      # optional, for galvan fmt
+//     width = 100
+//     indent = 4
+//
+//     [lint]                     # optional, levels of galvan lint's rules: allow, warn or deny
+//     shadowing = "allow"
+//
+//     [dependencies]
+//     drivers = { path = "../drivers", version = "0.2" }
+//
+// Dependencies are local directories only, no registry and no network. Building a package
+// resolves the whole graph, writes galvan.lock and hands the graph to the module loader,
+// which compiles every package in dependency order.
+// The TOML parser knows just enough TOML for manifests.
+
+const MAIN_TEMPLATE: &str = "call print(\"Hello from {name}!\");\nreturn 0;\n";
+const LIB_TEMPLATE: &str = "pub function add(a, b) {\n    return a + b;\n}\n";
+
+//
+// STRUCTS
+//
+
+/// Strings, integers and inline tables of strings, that's all a manifest needs
+#[derive(Debug)]
+enum TomlValue {
+    String(String),
+    Integer(u64),
+    Table(Vec<(String, String)>),
+}
+
+#[derive(Debug)]
+struct TomlEntry {
+    section: String,
+    key: String,
+    value: TomlValue,
+    line: usize,
+}
+
+struct Toml<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+}
+
+/// What a package gets built into when there's no --emit
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+#[derive(PartialEq)]
+pub enum Target {
+    /// Static ELF executable
+    X86_64Linux,
+    /// RV32IMC assembly (for qemu-riscv32 or `galvan run`)
+    Riscv32Linux,
+    /// Thumb-2 assembly (for qemu-arm or `galvan run`)
+    Thumbv7mLinux,
+    /// ARMv6-M Thumb assembly (for qemu-arm or `galvan run`)
+    Thumbv6mLinux,
+    /// A .wasm module for a browser or node
+    Wasm32,
+    /// C99 source
+    C,
+    /// Bytecode for `galvan run`
+    Gvc,
+}
+
+#[derive(Debug)]
+pub struct Dependency {
+    pub name: String,
+    /// Relative to the package depending on it
+    pub path: String,
+    /// `0.2` takes any 0.2.x, like Cargo's default
+    pub version: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct Manifest {
+    pub name: String,
+    pub version: (u64, u64, u64),
+    /// Relative to the package directory
+    pub entry: String,
+    pub target: Target,
+    pub opt_level: Option<OptLevel>,
+    pub dependencies: Vec<Dependency>,
+    /// [fmt]
+    pub style: Style,
+    /// [lint], rules that don't warn
+    pub lints: Vec<(String, Level)>,
+}
+
+/// A package in the resolved graph
+#[derive(Debug)]
+struct Resolved {
+    manifest: Manifest,
+    /// How it was reached, for error messages and the module loader
+    path: PathBuf,
+    /// Canonical, two packages are the same if this is
+    dir: PathBuf,
+    /// Indices, always smaller than the package's own
+    dependencies: Vec<usize>,
+}
+
+struct Resolver {
+    /// Dependencies before the packages needing them, the root ends up last
+    packages: Vec<Resolved>,
+    /// Packages being resolved right now (name, canonical dir), for cycle detection
+    resolving: Vec<(String, PathBuf)>,
+}
+
+//
+// FUNCTIONS
+//
+
+impl Toml<'_> {
+    fn space(&mut self) {
+        while self.chars.next_if(|c| *c == ' ' || *c == '\t').is_some() {}
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), String> {
+        self.space();
+        match self.chars.next() {
+            Some(c) if c == expected => Ok(()),
+            Some(c) => Err(format!("Expected '{}', found '{}'", expected, c)),
+            None => Err(format!("Expected '{}'", expected)),
+        }
+    }
+
+    fn key(&mut self) -> Result<String, String> {
+        self.space();
+        let mut key = String::new();
+        while let Some(c) = self.chars.next_if(|c| c.is_ascii_alphanumeric() || *c == '_' || *c == '-') {
+            key.push(c);
+        }
+        if key.is_empty() {return Err("Expected a key".to_string())}
+        Ok(key)
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect('"')?;
+        let mut string = String::new();
+        loop {
+            match self.chars.next() {
+                Some('"') => return Ok(string),
+                Some('\\') => match self.chars.next() {
+                    Some('"') => string.push('"'),
+                    Some('\\') => string.push('\\'),
+                    Some('n') => string.push('\n'),
+                    Some('t') => string.push('\t'),
+                    other => return Err(format!("Unknown escape '\\{}'", other.unwrap_or(' '))),
+                },
+                Some(c) => string.push(c),
+                None => return Err("Unterminated string".to_string()),
+            }
+        }
+    }
+
+    fn value(&mut self) -> Result<TomlValue, String> {
+        self.space();
+        match self.chars.peek() {
+            Some('"') => Ok(TomlValue::String(self.string()?)),
+            Some('{') => {
+                self.chars.next();
+                let mut fields: Vec<(String, String)> = vec![];
+                self.space();
+                if self.chars.next_if_eq(&'}').is_some() {return Ok(TomlValue::Table(fields))}
+                loop {
+                    let key = self.key()?;
+                    self.expect('=')?;
+                    let value = self.string()?;
+                    if fields.iter().any(|(other, _)| *other == key) {return Err(format!("'{}' is given twice", key))}
+                    fields.push((key, value));
+                    self.space();
+                    match self.chars.next() {
+                        Some(',') => {}
+                        Some('}') => return Ok(TomlValue::Table(fields)),
+                        _ => return Err("Expected ',' or '}'".to_string()),
+                    }
+                }
+            }
+            Some(c) if c.is_ascii_digit() => {
+                let mut digits = String::new();
+                while let Some(c) = self.chars.next_if(|c| c.is_ascii_digit() || *c == '_') {
+                    if c != '_' {digits.push(c)}
+                }
+                digits.parse().map(TomlValue::Integer).map_err(|_| format!("Integer '{}' is too big", digits))
+            }
+            _ => Err("Only strings, integers and { ... } tables are supported".to_string()),
+        }
+    }
+
+    /// Nothing but a comment left on the line
+    fn end(&mut self) -> Result<(), String> {
+        self.space();
+        match self.chars.peek() {
+            None | Some('#') => Ok(()),
+            Some(c) => Err(format!("Unexpected '{}'", c)),
+        }
+    }
+}
+
+/// Every `key = value` in the file with its section, the ones before any [section] get ""
+fn parse_toml(text: &str, file: &str) -> Result<Vec<TomlEntry>, String> {
+    let mut entries: Vec<TomlEntry> = vec![];
+    let mut section = String::new();
+    for (index, line) in text.lines().enumerate() {
+        let at = |error: String| format!("{} at line {} in {}", error, index + 1, file);
+        let mut toml = Toml { chars: line.chars().peekable() };
+        toml.space();
+        match toml.chars.peek() {
+            None | Some('#') => continue,
+            Some('[') => {
+                toml.chars.next();
+                section = toml.key().map_err(at)?;
+                toml.expect(']').map_err(at)?;
+            }
+            Some(_) => {
+                let key = toml.key().map_err(at)?;
+                toml.expect('=').map_err(at)?;
+                let value = toml.value().map_err(at)?;
+                if entries.iter().any(|entry| entry.section == section && entry.key == key) {
+                    return Err(at(format!("'{}' is given twice", key)));
+                }
+                entries.push(TomlEntry { section: section.clone(), key, value, line: index + 1 });
+            }
+        }
+        toml.end().map_err(at)?;
+    }
+    Ok(entries)
+}
+
+impl Target {
+    fn from_name(name: &str) -> Option<Target> {
+        match name {
+            "x86_64-linux" => Some(Target::X86_64Linux),
+            "riscv32-linux" => Some(Target::Riscv32Linux),
+            "thumbv7m-linux" => Some(Target::Thumbv7mLinux),
+            "thumbv6m-linux" => Some(Target::Thumbv6mLinux),
+            "wasm32" => Some(Target::Wasm32),
+            "c" => Some(Target::C),
+            "gvc" => Some(Target::Gvc),
+            _ => None,
+        }
+    }
+
+    fn emit(self) -> Emit {
+        match self {
+            Target::X86_64Linux | Target::Wasm32 => Emit::Executable,
+            Target::Riscv32Linux | Target::Thumbv7mLinux | Target::Thumbv6mLinux => Emit::Asm,
+            Target::C => Emit::C,
+            Target::Gvc => Emit::Gvc,
+        }
+    }
+
+    fn arch(self) -> Arch {
+        match self {
+            Target::Riscv32Linux => Arch::Riscv32,
+            Target::Thumbv7mLinux => Arch::Thumbv7m,
+            Target::Thumbv6mLinux => Arch::Thumbv6m,
+            Target::Wasm32 => Arch::Wasm32,
+            _ => Arch::X86_64,
+        }
+    }
+}
+
+/// What a package build's output file ends in
+fn extension(emit: Emit, arch: Arch) -> &'static str {
+    match emit {
+        Emit::Asm if arch == Arch::Wasm32 => ".wat",
+        Emit::Object | Emit::Executable if arch == Arch::Wasm32 => ".wasm",
+        Emit::Ir => ".ir",
+        Emit::Asm => ".s",
+        Emit::Object => ".o",
+        Emit::Executable => "",
+        Emit::C => ".c",
+        Emit::Bytecode => ".bytecode",
+        Emit::Gvc => ".gvc",
+        Emit::LinkerScript => ".ld",
+        Emit::RegAlloc => ".regalloc",
+    }
+}
+
+/// Package names end up in mangled function names, so they have to be plain identifiers that
+/// work as a module name (and not `std`, that one's taken)
+fn valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c.is_ascii_lowercase() || c == '_')
+        && chars.all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+        && !KEYWORDS.contains(&name)
+        && name != STD_PACKAGE
+        && valid_module_name(name)
+}
+
+fn parse_version(version: &str) -> Option<(u64, u64, u64)> {
+    let parts: Vec<u64> = version.split('.').map(|part| part.parse().ok()).collect::<Option<_>>()?;
+    match parts[..] {
+        [major, minor, patch] => Some((major, minor, patch)),
+        _ => None,
+    }
+}
+
+pub fn version_string(version: (u64, u64, u64)) -> String {
+    format!("{}.{}.{}", version.0, version.1, version.2)
+}
+
+/// Whether `version` satisfies `requirement`: at least the requirement, and the same up to the
+/// first part that isn't 0 (so 0.2 takes 0.2.5 but not 0.3.0, 1.2 takes 1.9.0 but not 2.0.0).
+/// `^0.2` is the same as `0.2`, like in Cargo
+fn compatible(requirement: &str, version: (u64, u64, u64)) -> Result<bool, String> {
+    let bare = requirement.strip_prefix('^').unwrap_or(requirement).trim_start();
+    let parts: Option<Vec<u64>> = bare.split('.').map(|part| part.parse().ok()).collect();
+    let Some(parts) = parts.filter(|parts| parts.len() <= 3) else {
+        return Err(format!("Invalid version requirement '{}'", requirement));
+    };
+    let minimum = (parts[0], parts.get(1).copied().unwrap_or(0), parts.get(2).copied().unwrap_or(0));
+    if version < minimum {return Ok(false)}
+    Ok(if minimum.0 > 0 || parts.len() == 1 {
+        version.0 == minimum.0
+    } else if minimum.1 > 0 || parts.len() == 2 {
+        version.0 == 0 && version.1 == minimum.1
+    } else {
+        version == minimum
+    })
+}
+
+/// Reads and checks `<dir>/galvan.toml`
+pub fn read_manifest(dir: &Path) -> Result<Manifest, String> {
+    let path = dir.join(MANIFEST_FILE);
+    let text = match std::fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(error) => return Err(format!("Can't read '{}': {}", path.display(), error)),
+    };
+    let file = path.display().to_string();
+
+    let mut name = None;
+    let mut version = None;
+    let mut entry = None;
+    let mut target = Target::X86_64Linux;
+    let mut opt_level = None;
+    let mut dependencies = vec![];
+    let mut style = Style::default();
+    let mut lints = vec![];
+    for TomlEntry { section, key, value, line } in parse_toml(&text, &file)? {
+        let at = |error: String| format!("{} at line {} in {}", error, line, file);
+        match (section.as_str(), key.as_str(), value) {
+            ("package", "name", TomlValue::String(value)) => {
+                if !valid_name(&value) {return Err(at(format!("'{}' isn't a valid package name, use lowercase letters, digits and single _s, not at the end", value)))}
+                name = Some(value);
+            }
+            ("package", "version", TomlValue::String(value)) => {
+                version = Some(parse_version(&value).ok_or_else(|| at(format!("Version '{}' isn't MAJOR.MINOR.PATCH", value)))?);
+            }
+            ("package", "entry", TomlValue::String(value)) => entry = Some(value),
+            ("package", "target", TomlValue::String(value)) => {
+                target = Target::from_name(&value).ok_or_else(|| at(format!("Unknown target '{}', expected x86_64-linux, riscv32-linux, thumbv7m-linux, thumbv6m-linux, wasm32, c or gvc", value)))?;
+            }
+            ("package", "opt-level", TomlValue::String(value)) => {
+                opt_level = Some(OptLevel::from_name(&value).ok_or_else(|| at(format!("Unknown opt-level '{}', expected 0, 1, 2 or s", value)))?);
+            }
+            ("dependencies", _, TomlValue::Table(fields)) => {
+                let mut path = None;
+                let mut version = None;
+                for (field, value) in fields {
+                    match field.as_str() {
+                        "path" => path = Some(value),
+                        "version" => version = Some(value),
+                        _ => return Err(at(format!("Unknown field '{}' in dependency '{}'", field, key))),
+                    }
+                }
+                let Some(path) = path else {
+                    return Err(at(format!("Dependency '{}' needs a path, only local packages are supported", key)));
+                };
+                dependencies.push(Dependency { name: key, path, version });
+            }
+            ("dependencies", _, TomlValue::String(_)) => {
+                return Err(at(format!("Dependency '{}' needs a path: {} = {{ path = \"...\" }}", key, key)));
+            }
+            ("fmt", "width", TomlValue::Integer(value)) => style.width = value as usize,
+            ("fmt", "indent", TomlValue::Integer(value)) => style.indent = value as usize,
+            ("package", "name" | "version" | "entry" | "target" | "opt-level", _) => return Err(at(format!("'{}' has to be a string", key))),
+            ("fmt", "width" | "indent", _) => return Err(at(format!("'{}' has to be a number", key))),
+            ("package", _, _) => return Err(at(format!("Unexpected '{}' in [package]", key))),
+            ("fmt", _, _) => return Err(at(format!("Unexpected '{}' in [fmt]", key))),
+            ("lint", _, _) if !lint::RULES.contains(&key.as_str()) => {
+                return Err(at(format!("Unknown lint '{}', expected one of {}", key, lint::RULES.join(", "))));
+            }
+            ("lint", _, TomlValue::String(value)) => {
+                let level = Level::from_name(&value).ok_or_else(|| at(format!("Unknown level '{}', expected allow, warn or deny", value)))?;
+                lints.push((key, level));
+            }
+            ("lint", _, _) => return Err(at(format!("The level of '{}' has to be \"allow\", \"warn\" or \"deny\"", key))),
+            _ if section.is_empty() => return Err(at(format!("'{}' has to be in a [section]", key))),
+            _ => return Err(at(format!("Unknown section [{}]", section))),
+        }
+    }
+
+    let Some(name) = name else {return Err(format!("{} needs a package name", file))};
+    let Some(version) = version else {return Err(format!("{} needs a package version", file))};
+    // No entry given: an application if there's a main.gv, a library otherwise
+    let entry = match entry {
+        Some(entry) => entry,
+        None if dir.join("src/main.gv").is_file() => "src/main.gv".to_string(),
+        None => "src/lib.gv".to_string(),
+    };
+    if !dir.join(&entry).is_file() {
+        return Err(format!("Entry '{}' of package '{}' doesn't exist", dir.join(&entry).display(), name));
+    }
+    Ok(Manifest { name, version, entry, target, opt_level, dependencies, style, lints })
+}
+
+impl Resolver {
+    /// Resolves the package in `path` and everything it depends on, gives back its index
+    fn resolve(&mut self, path: &Path) -> Result<usize, String> {
+        let shown = if path.as_os_str().is_empty() {Path::new(".")} else {path};
+        let dir = match shown.canonicalize() {
+            Ok(dir) => dir,
+            Err(error) => return Err(format!("Can't find package '{}': {}", shown.display(), error)),
+        };
+        if let Some(start) = self.resolving.iter().position(|(_, other)| *other == dir) {
+            let mut cycle: Vec<&str> = self.resolving[start..].iter().map(|(name, _)| name.as_str()).collect();
+            cycle.push(&self.resolving[start].0);
+            return Err(format!("Dependency cycle: {}", cycle.join(" -> ")));
+        }
+        if let Some(index) = self.packages.iter().position(|package| package.dir == dir) {return Ok(index)}
+
+        let manifest = read_manifest(path)?;
+        self.resolving.push((manifest.name.clone(), dir.clone()));
+        let mut dependencies = vec![];
+        for dependency in &manifest.dependencies {
+            let index = self.resolve(&path.join(&dependency.path))?;
+            let found = &self.packages[index];
+            if found.manifest.name != dependency.name {
+                return Err(format!("Dependency '{}' of '{}' points at {}, but that package is called '{}'",
+                    dependency.name, manifest.name, found.path.display(), found.manifest.name));
+            }
+            if let Some(requirement) = &dependency.version && !compatible(requirement, found.manifest.version)? {
+                return Err(format!("'{}' needs {} {}, but {} has version {}",
+                    manifest.name, dependency.name, requirement, found.path.display(), version_string(found.manifest.version)));
+            }
+            dependencies.push(index);
+        }
+        self.resolving.pop();
+
+        if let Some(other) = self.packages.iter().find(|package| package.manifest.name == manifest.name) {
+            return Err(format!("Two different packages are called '{}': {} and {}", manifest.name, other.path.display(), shown.display()));
+        }
+        self.packages.push(Resolved { manifest, path: path.to_path_buf(), dir, dependencies });
+        Ok(self.packages.len() - 1)
+    }
+}
+
+/// `to` relative to `from`, both canonical
+fn relative(from: &Path, to: &Path) -> String {
+    let from: Vec<_> = from.components().collect();
+    let to: Vec<_> = to.components().collect();
+    let common = from.iter().zip(&to).take_while(|(a, b)| a == b).count();
+    let mut parts = vec!["..".to_string(); from.len() - common];
+    parts.extend(to[common..].iter().map(|part| part.as_os_str().to_string_lossy().to_string()));
+    if parts.is_empty() {".".to_string()} else {parts.join("/")}
+}
+
+/// Manifest and .gv files of a package, relative paths with `/`, build output and hidden
+/// directories left out
+fn source_files(dir: &Path, relative: &str, out: &mut Vec<String>) -> Result<(), String> {
+    let entries = match std::fs::read_dir(dir.join(relative)) {
+        Ok(entries) => entries,
+        Err(error) => return Err(format!("Can't read '{}': {}", dir.join(relative).display(), error)),
+    };
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if name.starts_with('.') || (relative.is_empty() && name == BUILD_DIR) {continue}
+        let path = if relative.is_empty() {name.clone()} else {format!("{}/{}", relative, name)};
+        if entry.path().is_dir() {
+            source_files(dir, &path, out)?;
+        } else if (relative.is_empty() && name == MANIFEST_FILE) || name.ends_with(&format!(".{}", MODULE_EXTENSION)) {
+            out.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// FNV-1a over a package's source files (names and contents), so galvan.lock notices when
+/// a dependency changes
+fn checksum(dir: &Path) -> Result<u64, String> {
+    let mut files = vec![];
+    source_files(dir, "", &mut files)?;
+    files.sort();
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for file in files {
+        let contents = std::fs::read(dir.join(&file)).map_err(|error| format!("Can't read '{}': {}", file, error))?;
+        for byte in file.bytes().chain([0]).chain(contents) {
+            hash = (hash ^ byte as u64).wrapping_mul(0x100000001b3);
+        }
+    }
+    Ok(hash)
+}
+
+/// galvan.lock: every package in dependency order. The root's own sources aren't
+/// checksummed, they change all the time.
+fn lockfile(packages: &[Resolved]) -> Result<String, String> {
+    let root = packages.last().unwrap();
+    let mut out = "# Written by galvan build, don't edit by hand\n".to_string();
+    for package in packages {
+        out.push_str("\n[[package]]\n");
+        out.push_str(&format!("name = \"{}\"\n", package.manifest.name));
+        out.push_str(&format!("version = \"{}\"\n", version_string(package.manifest.version)));
+        out.push_str(&format!("path = \"{}\"\n", relative(&root.dir, &package.dir)));
+        if package.dir != root.dir {
+            out.push_str(&format!("checksum = \"fnv1a:{:016x}\"\n", checksum(&package.dir)?));
+        }
+        let dependencies: Vec<String> = package.dependencies.iter().map(|&index| format!("\"{}\"", packages[index].manifest.name)).collect();
+        out.push_str(&format!("dependencies = [{}]\n", dependencies.join(", ")));
+    }
+    Ok(out)
+}
+
+fn update_lockfile(packages: &[Resolved], locked: bool) -> Result<(), String> {
+    let path = packages.last().unwrap().path.join(LOCK_FILE);
+    let text = lockfile(packages)?;
+    let old = std::fs::read_to_string(&path).ok();
+    if old.as_deref() == Some(text.as_str()) {return Ok(())}
+    if locked {
+        let problem = if old.is_some() {"is out of date"} else {"doesn't exist"};
+        return Err(format!("{} {}, but --locked was given", path.display(), problem));
+    }
+    std::fs::write(&path, text).map_err(|error| format!("Can't write '{}': {}", path.display(), error))
+}
+
+/// The package directory `options` are about: no file given and there's a galvan.toml here,
+/// or given a package directory or its galvan.toml
+fn package_dir(options: &Options) -> Option<PathBuf> {
+    let Some(source) = &options.source else {
+        return Path::new(MANIFEST_FILE).is_file().then(PathBuf::new);
+    };
+    let path = Path::new(source);
+    if path.is_dir() && path.join(MANIFEST_FILE).is_file() {return Some(path.to_path_buf())}
+    if path.file_name().is_some_and(|name| name == MANIFEST_FILE) {
+        return Some(path.parent().map(Path::to_path_buf).unwrap_or_default());
+    }
+    None
+}
+
+/// Turns options about a package into options for building its entry: resolves the package
+/// graph, updates galvan.lock, and fills in the entry, the target's --emit and an output in
+/// target/. Options about a plain file come back unchanged.
+pub fn package_options(options: Options) -> Result<Options, String> {
+    let Some(dir) = package_dir(&options) else {return Ok(options)};
+    if debug_prints(PACKAGE_DEBUG_PRINTS) {eprintln!("- - - PACKAGE")}
+
+    let mut resolver = Resolver { packages: vec![], resolving: vec![] };
+    resolver.resolve(&dir)?;
+    let packages = resolver.packages;
+    update_lockfile(&packages, options.locked)?;
+
+    let root = packages.last().unwrap();
+    let mut options = options;
+    options.source = Some(root.path.join(&root.manifest.entry).display().to_string());
+    let emit = options.emit.unwrap_or(root.manifest.target.emit());
+    options.emit = Some(emit);
+    let arch = options.arch.unwrap_or(root.manifest.target.arch());
+    options.arch = Some(arch);
+    if options.opt_level.is_none() {options.opt_level = root.manifest.opt_level}
+    if options.output.is_none() && options.command == Command::Build {
+        let build_dir = root.path.join(BUILD_DIR);
+        if let Err(error) = std::fs::create_dir_all(&build_dir) {
+            return Err(format!("Can't create '{}': {}", build_dir.display(), error));
+        }
+        options.output = Some(build_dir.join(format!("{}{}", root.manifest.name, extension(emit, arch))).display().to_string());
+    }
+    options.packages = packages.iter().map(|package| Package {
+        name: package.manifest.name.clone(),
+        entry: package.path.join(&package.manifest.entry),
+        dependencies: package.dependencies.clone(),
+    }).collect();
+
+    if debug_prints(PACKAGE_DEBUG_PRINTS) {
+        for package in &packages {
+            let dependencies: Vec<&str> = package.dependencies.iter().map(|&index| packages[index].manifest.name.as_str()).collect();
+            eprintln!("{} {} ({}), depends on {:?}", package.manifest.name, version_string(package.manifest.version), package.dir.display(), dependencies);
+        }
+    }
+    if debug_prints(PACKAGE_DEBUG_PRINTS) {eprintln!("- - - Package done!")}
+    Ok(options)
+}
+
+/// What `galvan fmt` and `galvan lint` work on: the given file, or every .gv file in the
+/// given directory (the package here when there's no path), and the manifest of the package
+/// they're in, if they are
+pub fn project_files(source: Option<&str>) -> Result<(Vec<PathBuf>, Option<Manifest>), String> {
+    let path = match source {
+        Some(source) if Path::new(source).file_name().is_some_and(|name| name == MANIFEST_FILE) => {
+            Path::new(source).parent().map(Path::to_path_buf).unwrap_or_default()
+        }
+        Some(source) => PathBuf::from(source),
+        None if Path::new(MANIFEST_FILE).is_file() => PathBuf::from("."),
+        None => return Err(format!("Expected a file or directory, or a {} here\n\n{}", MANIFEST_FILE, crate::cli::USAGE)),
+    };
+    let files = if path.is_dir() {
+        let mut files = vec![];
+        source_files(&path, "", &mut files)?;
+        files.retain(|file| file != MANIFEST_FILE);
+        files.sort();
+        // `src/main.gv` rather than `./src/main.gv`
+        files.iter().map(|file| if source.is_none() {PathBuf::from(file)} else {path.join(file)}).collect()
+    } else if path.is_file() {
+        vec![path.clone()]
+    } else {
+        return Err(format!("Can't find '{}'", path.display()));
+    };
+
+    // The closest galvan.toml, in the directory or above it
+    let start = std::fs::canonicalize(&path).map_err(|error| format!("Can't read '{}': {}", path.display(), error))?;
+    let manifest = match start.ancestors().find(|dir| dir.join(MANIFEST_FILE).is_file()) {
+        Some(dir) => Some(read_manifest(dir)?),
+        None => None,
+    };
+    Ok((files, manifest))
+}
+
+/// `galvan new <path>`, the package is named after the directory
+pub fn new_package(path: &str, lib: bool) -> Result<(), String> {
+    let dir = Path::new(path);
+    let name = dir.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+    if !valid_name(&name) {
+        return Err(format!("'{}' isn't a valid package name, use lowercase letters, digits and single _s, not at the end", name));
+    }
+    if dir.exists() {return Err(format!("'{}' already exists", dir.display()))}
+
+    let (entry, code) = if lib {
+        ("src/lib.gv", LIB_TEMPLATE.to_string())
+    } else {
+        ("src/main.gv", MAIN_TEMPLATE.replace("{name}", &name))
+    };
+    let manifest = format!("[package]\nname = \"{}\"\nversion = \"{}\"\nentry = \"{}\"\ntarget = \"x86_64-linux\"\n\n[dependencies]\n# drivers = {{ path = \"../drivers\" }}\n",
+        name, DEFAULT_VERSION, entry);
+    let files = [
+        (MANIFEST_FILE, manifest),
+        (entry, code),
+        (".gitignore", format!("/{}\n", BUILD_DIR)),
+    ];
+    for (file, contents) in files {
+        let path = dir.join(file);
+        let result = std::fs::create_dir_all(path.parent().unwrap()).and_then(|_| std::fs::write(&path, contents));
+        if let Err(error) = result {return Err(format!("Can't write '{}': {}", path.display(), error))}
+    }
+    println!("Created {} package '{}' in {}", if lib {"library"} else {"application"}, name, dir.display());
+    Ok(())
+}
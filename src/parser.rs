@@ -1,4 +1,4 @@
-use crate::{compiler_settings::PAR_DEBUG_PRINTS, lexer::{LexSymbol, Lexeme}};
+use crate::{compiler_settings::{debug_prints, NO_STD_ATTRIBUTE, PAR_DEBUG_PRINTS, TYPES}, ffi::{CType, Extern}, lexer::{LexSymbol, Lexeme, Location}, seman::Type, source_map::position};
 
 // TODO: Custom ParserError type
 // Include position information, expected symbol and actual symbol
@@ -12,9 +12,11 @@ use crate::{compiler_settings::PAR_DEBUG_PRINTS, lexer::{LexSymbol, Lexeme}};
 pub enum Expression {
     Number(i64),
     String(String),
-    Variable(String),
+    /// The name and where it is
+    Variable(String, Location),
     Operation(Operation),
-    FunctionCall {target: String, args: Vec<Expression>},
+    /// `location` is where the function's name is, the last part of `module::name`
+    FunctionCall {target: String, args: Vec<Expression>, location: Location},
     ReturnValue {value: Box<Expression>}
     // ^ // TODO: Make return into a Statement instead 
          // Will require extra work ughhh
@@ -23,13 +25,14 @@ pub enum Expression {
 #[derive(Debug)]
 #[derive(Clone)]
 pub struct Operation {
-    left: Box<Expression>,
-    operator: Operator,
-    right: Box<Expression>
+    pub left: Box<Expression>,
+    pub operator: Operator,
+    pub right: Box<Expression>
 }
 
 #[derive(Debug)]
-#[derive(Clone)]
+#[derive(Clone, Copy)]
+#[derive(PartialEq)]
 pub enum Operator {
     Addition,
     Subtraction,
@@ -44,27 +47,141 @@ pub enum Operator {
 }
 
 #[derive(Debug)]
+#[derive(Clone)]
 pub enum Statement {
-    ExpressionStatement(Expression),
-    VariableAssignment {name: String, value: Expression},
-    FunctionAssignment {name: String, arguments: Vec<Expression>, body: Vec<Statement>},
-    While {condition: Expression, body: Vec<Statement>},
-    ConditionalStatement {condition: Expression, body: Vec<Statement>, else_body: Option<Vec<Statement>>},
+    ExpressionStatement(Expression, Location),
+    /// `name_location` is where the name is, `location` where the statement starts, the same
+    /// goes for functions, constants and externs
+    VariableAssignment {name: String, value: Expression, location: Location, name_location: Location},
+    /// `parameter_types` has one entry per argument, `i64` when there's no `: type`.
+    /// `export` is the C symbol of an `export function`, its name before modules.rs mangles it.
+    /// `attributes` are the `#[...]`s in front of it.
+    FunctionAssignment {name: String, arguments: Vec<Expression>, parameter_types: Vec<Type>, body: Vec<Statement>, public: bool, export: Option<String>, attributes: Attributes, location: Location, name_location: Location},
+    While {condition: Expression, body: Vec<Statement>, location: Location},
+    ConditionalStatement {condition: Expression, body: Vec<Statement>, else_body: Option<Vec<Statement>>, location: Location},
+    /// `import drivers::uart;`, resolved (and removed) by modules.rs. `header` is
+    /// `import c "hal.h";`, the path is the header's then.
+    Import {path: String, header: bool, location: Location},
+    /// `const NAME = value;`, inlined everywhere by modules.rs. `value_location` is where the
+    /// value starts, for errors evaluating it.
+    ConstAssignment {name: String, value: Expression, public: bool, location: Location, name_location: Location, value_location: Location},
+    /// `asm volatile { "..." } in (a = x) out (b = y) clobber ("rax");`
+    Asm {asm: InlineAsm, location: Location},
+    /// `extern "C" function puts(s: str) -> i32;`, see ffi.rs
+    Extern {function: Extern, public: bool, location: Location, name_location: Location},
+    /// `#![no_std]`, the only inner attribute there is
+    Attribute {name: String, location: Location},
+}
+impl Statement {
+    /// Where the statement starts in the source (its first lexeme)
+    pub fn location(&self) -> Location {
+        match self {
+            Statement::ExpressionStatement(_, location) => *location,
+            Statement::VariableAssignment { location, .. } => *location,
+            Statement::FunctionAssignment { location, .. } => *location,
+            Statement::While { location, .. } => *location,
+            Statement::ConditionalStatement { location, .. } => *location,
+            Statement::Import { location, .. } => *location,
+            Statement::ConstAssignment { location, .. } => *location,
+            Statement::Asm { location, .. } => *location,
+            Statement::Extern { location, .. } => *location,
+            Statement::Attribute { location, .. } => *location,
+        }
+    }
+}
+
+/// Inline assembly. The template goes into the output as-is, except that `{name}` becomes
+/// the register the compiler picked for operand `name` (and `{{`/`}}` are plain braces).
+#[derive(Debug)]
+#[derive(Clone)]
+pub struct InlineAsm {
+    /// The instructions, one per line
+    pub template: String,
+    /// Operand name -> value that gets put in its register before the assembly runs
+    pub inputs: Vec<(String, Expression)>,
+    /// Operand name -> variable its register gets stored in afterwards
+    pub outputs: Vec<(String, String)>,
+    /// Registers the assembly changes besides the outputs, plus "cc" and "memory"
+    pub clobbers: Vec<String>,
+    /// Never removed or moved, even if nothing uses the outputs
+    pub volatile: bool,
+}
+
+/// The `#[...]`s a function can have, for bare metal programs
+#[derive(Debug)]
+#[derive(Clone, Default)]
+#[derive(PartialEq)]
+pub struct Attributes {
+    /// `#[interrupt]`, only the hardware calls it, and it saves every register
+    pub interrupt: bool,
+    /// `#[panic_handler]`, gets the message when the program panics
+    pub panic_handler: bool,
+    /// `#[section(".vectors")]`, the output section it goes in instead of .text
+    pub section: Option<String>,
+    /// `#[allow(unused_variable, ...)]`, lints (lint.rs) that stay quiet about the function
+    pub allow: Vec<String>,
 }
 
 //
 // FUNCTIONS
 //
 
+/// Whether the program has a `#![no_std]` (or got one from --freestanding)
+pub fn freestanding(statements: &[Statement]) -> bool {
+    statements.iter().any(|statement| matches!(statement, Statement::Attribute { name, .. } if name == NO_STD_ATTRIBUTE))
+}
+
+/// The lints of an `allow(a, b)` attribute, None if it isn't one
+pub fn allowed(attribute: &str) -> Option<Vec<String>> {
+    let rules = attribute.trim().strip_prefix("allow")?.trim().strip_prefix('(')?.strip_suffix(')')?;
+    let rules: Vec<String> = rules.split(',').map(|rule| rule.trim().to_string()).collect();
+    let valid = |rule: &String| !rule.is_empty() && rule.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    rules.iter().all(valid).then_some(rules)
+}
+
+/// Lints the whole file allows with `#![allow(...)]`
+pub fn allowed_lints(statements: &[Statement]) -> Vec<String> {
+    statements.iter().filter_map(|statement| match statement {
+        Statement::Attribute { name, .. } => allowed(name),
+        _ => None,
+    }).flatten().collect()
+}
+
+/// Goes through an asm template, replacing every `{name}` with `operand(name)`
+pub fn expand_asm(template: &str, operand: &mut dyn FnMut(&str) -> Result<String, String>) -> Result<String, String> {
+    let mut out = String::new();
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.next_if_eq(&'{').is_some() => out.push('{'),
+            '}' if chars.next_if_eq(&'}').is_some() => out.push('}'),
+            '{' => {
+                let mut name = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(ch) => name.push(ch),
+                        None => return Err(format!("Unclosed '{{{}' in inline assembly", name)),
+                    }
+                }
+                out.push_str(&operand(name.trim())?);
+            }
+            '}' => return Err("Stray '}' in inline assembly, use '}}' for a brace".to_string()),
+            c => out.push(c),
+        }
+    }
+    Ok(out)
+}
+
 /// Peeks the lexeme, and handles unwrap.
 /// 
 /// Returns `Lexeme::EOF` Lexeme if it hits a None
 fn peek_lexeme(lexeme: &mut std::iter::Peekable<std::slice::Iter<'_, Lexeme>>) -> Lexeme {
     let lexeme: Option<&&Lexeme> = lexeme.peek(); 
-    if lexeme.is_none() { // TODO: Remember to change EOF lexeme location at some point
-        return Lexeme { symbol: LexSymbol::EOF, value: String::new(), location: (0, 0) }
-    } else { // FIXME: Don't clone the lexeme on peeking
-        return lexeme.unwrap().to_owned().clone() // Peak programming
+    if let Some(lexeme) = lexeme { // FIXME: Don't clone the lexeme on peeking
+        return lexeme.to_owned().clone() // Peak programming
+    } else { // Past the lexer's EOF, nothing should get here
+        return Lexeme { symbol: LexSymbol::EOF, value: String::new(), location: (0, 0, 0) }
     }
 }
 
@@ -80,26 +197,47 @@ fn parse_single_expression(lexeme: &mut std::iter::Peekable<std::slice::Iter<'_,
             Ok(Expression::String(content))
         }
         LexSymbol::Integer => {
-            let strint = &peek_lexeme(lexeme).value;
-            let int = strint.parse::<i64>();
-            if int.is_err() {return Err("Invalid integer".to_string())}
+            let integer = peek_lexeme(lexeme);
+            let int = integer.value.parse::<i64>();
+            if int.is_err() {return Err(format!("Invalid integer '{}' at position {}", integer.value, position(integer.location)))}
             lexeme.next();
             Ok(Expression::Number(int.unwrap()))
         }
         LexSymbol::Identifier => {
             // Check if it's a function or a variable (check for braces)
-            let idname = peek_lexeme(lexeme).value.clone();
-            lexeme.next();
+            let (idname, location) = parse_path(lexeme)?;
             if peek_lexeme(lexeme).symbol == LexSymbol::GenericOpeningBracket {
                 lexeme.next();
                 let args = parse_arguments(lexeme)?;
                 lexeme.next(); // Get over last endbracket
-                Ok(Expression::FunctionCall { target: idname, args: args })
+                Ok(Expression::FunctionCall { target: idname, args: args, location })
             }
-            else {Ok(Expression::Variable(idname))}
+            else {Ok(Expression::Variable(idname, location))}
         }
-        symbol => {return Err(format!("Expected expression, not {:?}", symbol))}
+        symbol => {return Err(format!("Expected expression, not {:?} at position {}", symbol, position(peek_lexeme(lexeme).location)))}
+    }
+}
+
+/// Parses a name that can have a module path in front of it (`uart::init`), gives back the
+/// location of its last part too. Runs `lexer.next()` until after the last identifier.
+/// 
+/// Expects format `[Identifier] (DoubleDot DoubleDot Identifier)...`
+fn parse_path(lexeme: &mut std::iter::Peekable<std::slice::Iter<'_, Lexeme>>) -> Result<(String, Location), String> {
+    let mut location = peek_lexeme(lexeme).location;
+    let mut path = expect(LexSymbol::Identifier, lexeme)?;
+    loop {
+        // `::` is two DoubleDots, look two ahead so a lone `:` is left alone
+        let mut ahead = lexeme.clone();
+        if peek_lexeme(&mut ahead).symbol != LexSymbol::DoubleDot {break}
+        ahead.next();
+        if peek_lexeme(&mut ahead).symbol != LexSymbol::DoubleDot {break}
+        lexeme.next();
+        lexeme.next();
+        path.push_str("::");
+        location = peek_lexeme(lexeme).location;
+        path.push_str(&expect(LexSymbol::Identifier, lexeme)?);
     }
+    return Ok((path, location))
 }
 
 /// Parses an expression. Returns the expression or a parse error.
@@ -116,9 +254,9 @@ fn parse_expression(lexeme: &mut std::iter::Peekable<std::slice::Iter<'_, Lexeme
 /// Used for better_parse() only. Returns the precedence for symbol in a 
 /// operator-precedence parse system. 
 fn precedence(strin: &str) -> i64 {
-    if [">", "<", "=", "==", "!=", ">=", "<="].contains(&strin.as_ref()) {0}
-    else if ["+", "-"].contains(&strin.as_ref()) {1}
-    else if ["/", "*"].contains(&strin.as_ref()) {2}
+    if [">", "<", "=", "==", "!=", ">=", "<="].contains(&strin) {0}
+    else if ["+", "-"].contains(&strin) {1}
+    else if ["/", "*"].contains(&strin) {2}
     else {-1}
 }
 
@@ -160,7 +298,7 @@ fn better_parse(mut left: Expression, min_importance: i64, lexeme: &mut std::ite
             ">=" => Operator::EqualGreaterThan, 
             "!=" => Operator::Inequal, 
             "==" => Operator::EqualTo, 
-            _ => return Err("Expected OperationalSymbol".to_string()) // TODO: change error to something good
+            other => return Err(format!("Unknown operator '{}' at position {}", other, position(op.location)))
         }};
         left = Expression::Operation(Operation {
             left: Box::new(left), 
@@ -182,7 +320,7 @@ fn parse_arguments(lexeme: &mut std::iter::Peekable<std::slice::Iter<'_, Lexeme>
     // Get the first argument
     let mut args: Vec<Expression> = vec![];
     let expr = parse_expression(lexeme);
-    if expr.is_err() {return Err(expr.unwrap_err())}
+    if let Err(error) = expr {return Err(error)}
     args.push(expr?);
     // TODO: add types to argument parsing
     // (and to the rest of the lang as well ig)
@@ -191,18 +329,199 @@ fn parse_arguments(lexeme: &mut std::iter::Peekable<std::slice::Iter<'_, Lexeme>
     if peek_lexeme(lexeme).symbol == LexSymbol::Comma {
         lexeme.next();
         let res_args = parse_arguments(lexeme);
-        if res_args.is_err() {return Err(res_args.unwrap_err())}
-        args.append(&mut res_args.unwrap());
+        let mut res_args = res_args?;
+        args.append(&mut res_args);
     }
 
     Ok(args)
 }
 
+/// Parameters of a function definition, `(a, b: str)`. Runs `lexeme.next()` until the
+/// closing bracket (cursor to closebracket), like `parse_arguments()`.
+/// 
+/// Expects format `(Identifier) (DoubleDot Identifier) (Comma) ... (ClosingBracket)`
+fn parse_parameters(lexeme: &mut std::iter::Peekable<std::slice::Iter<'_, Lexeme>>) -> Result<(Vec<Expression>, Vec<Type>), String> {
+    let mut names = vec![];
+    let mut types = vec![];
+    while peek_lexeme(lexeme).symbol != LexSymbol::GenericClosingBracket {
+        if !names.is_empty() {expect(LexSymbol::Comma, lexeme)?;}
+        let location = peek_lexeme(lexeme).location;
+        names.push(Expression::Variable(expect(LexSymbol::Identifier, lexeme)?, location));
+        let mut ty = Type::Int;
+        if peek_lexeme(lexeme).symbol == LexSymbol::DoubleDot {
+            lexeme.next();
+            let location = peek_lexeme(lexeme).location;
+            ty = match expect(LexSymbol::Identifier, lexeme)?.as_str() {
+                "i64" => Type::Int,
+                "str" => Type::Str,
+                other => return Err(format!("Unknown parameter type '{}', expected {} at position {}", other, TYPES.join(" or "), position(location))),
+            };
+        }
+        types.push(ty);
+    }
+    Ok((names, types))
+}
+
+/// Operands of an asm block, `(name = expr, name)`. A lone name is short for `name = name`.
+/// Runs `lexeme.next()` until after the closing bracket.
+/// 
+/// Expects format `[OpeningBracket] (Identifier) (EqualSign Expr) (Comma) ... [ClosingBracket]`
+fn parse_asm_operands(lexeme: &mut std::iter::Peekable<std::slice::Iter<'_, Lexeme>>) -> Result<Vec<(String, Expression)>, String> {
+    expect(LexSymbol::GenericOpeningBracket, lexeme)?;
+    let mut operands = vec![];
+    while peek_lexeme(lexeme).symbol != LexSymbol::GenericClosingBracket {
+        if !operands.is_empty() {expect(LexSymbol::Comma, lexeme)?;}
+        let location = peek_lexeme(lexeme).location;
+        let name = expect(LexSymbol::Identifier, lexeme)?;
+        let mut value = Expression::Variable(name.clone(), location);
+        if peek_lexeme(lexeme).symbol == LexSymbol::EqualSign {
+            lexeme.next();
+            value = parse_expression(lexeme)?;
+        }
+        operands.push((name, value));
+    }
+    lexeme.next();
+    Ok(operands)
+}
+
+/// Parses `asm volatile { "..." "..." } in (...) out (...) clobber (...);`, everything
+/// after the `asm`. The sections are optional but have to be in that order.
+fn parse_asm(lexeme: &mut std::iter::Peekable<std::slice::Iter<'_, Lexeme>>) -> Result<InlineAsm, String> {
+    let volatile = peek_lexeme(lexeme).symbol == LexSymbol::Identifier && peek_lexeme(lexeme).value == "volatile";
+    if volatile {lexeme.next();}
+    expect(LexSymbol::FunctionOpeningBracket, lexeme)?;
+    let mut lines = vec![];
+    while peek_lexeme(lexeme).symbol != LexSymbol::FunctionClosingBracket {
+        lines.push(expect(LexSymbol::String, lexeme)?);
+    }
+    lexeme.next();
+
+    let mut asm = InlineAsm { template: lines.join("\n"), inputs: vec![], outputs: vec![], clobbers: vec![], volatile };
+    for section in ["in", "out", "clobber"] {
+        let next = peek_lexeme(lexeme);
+        if next.symbol != LexSymbol::Identifier || next.value != section {continue}
+        lexeme.next();
+        match section {
+            "in" => asm.inputs = parse_asm_operands(lexeme)?,
+            "out" => {
+                for (name, value) in parse_asm_operands(lexeme)? {
+                    let Expression::Variable(variable, _) = value else {
+                        return Err(format!("Output '{}' of inline assembly has to go into a variable at position {}", name, position(next.location)));
+                    };
+                    asm.outputs.push((name, variable));
+                }
+            }
+            _ => {
+                expect(LexSymbol::GenericOpeningBracket, lexeme)?;
+                while peek_lexeme(lexeme).symbol != LexSymbol::GenericClosingBracket {
+                    if !asm.clobbers.is_empty() {expect(LexSymbol::Comma, lexeme)?;}
+                    asm.clobbers.push(expect(LexSymbol::String, lexeme)?);
+                }
+                lexeme.next();
+            }
+        }
+    }
+    expect(LexSymbol::EndLine, lexeme)?;
+    Ok(asm)
+}
+
+/// A C type in an extern declaration, `i32`, `*u8`, `**void`...
+fn parse_c_type(lexeme: &mut std::iter::Peekable<std::slice::Iter<'_, Lexeme>>) -> Result<CType, String> {
+    let location = peek_lexeme(lexeme).location;
+    let mut text = String::new();
+    while peek_lexeme(lexeme).symbol == LexSymbol::OperationalSymbol && peek_lexeme(lexeme).value == "*" {
+        lexeme.next();
+        text.push('*');
+    }
+    text.push_str(&expect(LexSymbol::Identifier, lexeme)?);
+    match CType::parse(&text) {
+        Some(ty) => Ok(ty),
+        None => Err(format!("Unknown C type '{}', expected i8-i64, u8-u64, str, void or a pointer to one at position {}", text, position(location))),
+    }
+}
+
+/// Parses `extern "C" function name(a: i32, ...) -> i32;`, everything after the `extern`.
+/// Without `-> type` it returns void, like a C function would.
+fn parse_extern(lexeme: &mut std::iter::Peekable<std::slice::Iter<'_, Lexeme>>) -> Result<Extern, String> {
+    let abi = peek_lexeme(lexeme);
+    if expect(LexSymbol::String, lexeme)? != "C" {
+        return Err(format!("Only extern \"C\" is supported, not \"{}\" at position {}", abi.value, position(abi.location)));
+    }
+    let keyword = peek_lexeme(lexeme);
+    if keyword.value != "function" {
+        return Err(format!("Expected 'function' after extern \"C\", not '{}' at position {}", keyword.value, position(keyword.location)));
+    }
+    lexeme.next();
+    let name = expect(LexSymbol::Identifier, lexeme)?;
+    expect(LexSymbol::GenericOpeningBracket, lexeme)?;
+    let mut function = Extern { name: name.clone(), symbol: name, parameters: vec![], variadic: false, return_type: CType::Void };
+    while peek_lexeme(lexeme).symbol != LexSymbol::GenericClosingBracket {
+        if !function.parameters.is_empty() {expect(LexSymbol::Comma, lexeme)?;}
+        // `...` has to be last
+        if peek_lexeme(lexeme).symbol == LexSymbol::Dot {
+            for _ in 0..3 {expect(LexSymbol::Dot, lexeme)?;}
+            function.variadic = true;
+            break;
+        }
+        let parameter = expect(LexSymbol::Identifier, lexeme)?;
+        expect(LexSymbol::DoubleDot, lexeme)?;
+        let location = peek_lexeme(lexeme).location;
+        let ty = parse_c_type(lexeme)?;
+        if ty == CType::Void {return Err(format!("Parameter '{}' can't be void at position {}", parameter, position(location)))}
+        function.parameters.push((parameter, ty));
+    }
+    expect(LexSymbol::GenericClosingBracket, lexeme)?;
+    // `->` is a `-` and a `>` as far as the lexer cares
+    if peek_lexeme(lexeme).value == "-" {
+        lexeme.next();
+        let arrow = peek_lexeme(lexeme);
+        if arrow.value != ">" {return Err(format!("Expected '->', not '-{}' at position {}", arrow.value, position(arrow.location)))}
+        lexeme.next();
+        function.return_type = parse_c_type(lexeme)?;
+    }
+    expect(LexSymbol::EndLine, lexeme)?;
+    Ok(function)
+}
+
+/// Adds one `#[...]` to `attributes`, `name` or `name("argument")`
+fn parse_attribute(attribute: &Lexeme, attributes: &mut Attributes) -> Result<(), String> {
+    let at = position(attribute.location);
+    if attribute.value.split('(').next().unwrap_or_default().trim() == "allow" {
+        let Some(rules) = allowed(&attribute.value) else {
+            return Err(format!("Expected lint names, like #[allow(unused_variable, shadowing)] at position {}", at));
+        };
+        attributes.allow.extend(rules);
+        return Ok(());
+    }
+    let (name, argument) = match attribute.value.split_once('(') {
+        Some((name, rest)) => {
+            let Some(argument) = rest.trim().strip_suffix(')').map(str::trim) else {
+                return Err(format!("Expected ')' to close attribute '#[{}]' at position {}", attribute.value, at));
+            };
+            match argument.strip_prefix('"').and_then(|argument| argument.strip_suffix('"')) {
+                Some(argument) => (name.trim(), Some(argument.to_string())),
+                None => return Err(format!("The argument of '#[{}]' has to be a string at position {}", name.trim(), at)),
+            }
+        }
+        None => (attribute.value.trim(), None),
+    };
+    match (name, argument) {
+        ("interrupt", None) => attributes.interrupt = true,
+        ("panic_handler", None) => attributes.panic_handler = true,
+        ("section", Some(section)) if !section.is_empty() => attributes.section = Some(section),
+        ("section", _) => return Err(format!("Expected a section name, #[section(\".vectors\")] at position {}", at)),
+        ("interrupt" | "panic_handler", Some(_)) => return Err(format!("'#[{}]' doesn't take an argument at position {}", name, at)),
+        _ => return Err(format!("Unknown attribute '#[{}]', expected interrupt, panic_handler, section or allow at position {}", name, at)),
+    }
+    Ok(())
+}
+
 /// Parses a singular "line", basically anything until `LexSymbol::EndLine`.
 /// Unlike `parse_single_expression()`, this one includes keywords and such.
 fn parse_single(lexeme: &mut std::iter::Peekable<std::slice::Iter<'_, Lexeme>>) -> Result<Option<Statement>, String> { 
     let mut outtoken: Option<Statement> = None;
     let lex_val = peek_lexeme(lexeme).value;
+    let location = peek_lexeme(lexeme).location;
     match peek_lexeme(lexeme).symbol {
         // Keywords, see compiler_settings.rs for specifics
         LexSymbol::Keyword => {
@@ -211,6 +530,7 @@ fn parse_single(lexeme: &mut std::iter::Peekable<std::slice::Iter<'_, Lexeme>>)
             // Defining a variable
             if lex_val == "let" {
                 lexeme.next();
+                let name_location = peek_lexeme(lexeme).location;
                 let variablename = expect(LexSymbol::Identifier, lexeme)?;
                 expect(LexSymbol::EqualSign, lexeme)?;
                 let expression = {
@@ -218,7 +538,9 @@ fn parse_single(lexeme: &mut std::iter::Peekable<std::slice::Iter<'_, Lexeme>>)
                 };
                 outtoken = Some(Statement::VariableAssignment { 
                     name: variablename,
-                    value: expression 
+                    value: expression,
+                    location,
+                    name_location
                 });
                 expect(LexSymbol::EndLine, lexeme)?;
                 // STOP
@@ -227,26 +549,32 @@ fn parse_single(lexeme: &mut std::iter::Peekable<std::slice::Iter<'_, Lexeme>>)
             // Defining function
             else if lex_val == "function" {
                 lexeme.next();
+                let name_location = peek_lexeme(lexeme).location;
                 let functionname = expect(LexSymbol::Identifier, lexeme)?;
                 expect(LexSymbol::GenericOpeningBracket, lexeme)?;
-                let arguments = parse_arguments(lexeme)?;
-                // FIXME: You can pass function calls etc as function arguments
+                let (arguments, parameter_types) = parse_parameters(lexeme)?;
                 lexeme.next(); // Jump over ending bracket
                 expect(LexSymbol::FunctionOpeningBracket, lexeme)?;
                 let internals = parse_until_symbol(LexSymbol::FunctionClosingBracket, lexeme)?;
-                lexeme.next();
+                expect(LexSymbol::FunctionClosingBracket, lexeme)?;
 
                 outtoken = Some(Statement::FunctionAssignment {
                     name: functionname, 
                     arguments: arguments, 
+                    parameter_types,
                     body: internals, 
+                    public: false,
+                    export: None,
+                    attributes: Attributes::default(),
+                    location,
+                    name_location
                 });
             }
 
             // Calling function
             else if lex_val == "call" {
                 lexeme.next();
-                let target = expect(LexSymbol::Identifier, lexeme)?;
+                let (target, target_location) = parse_path(lexeme)?;
                 expect(LexSymbol::GenericOpeningBracket, lexeme)?;
                 let arguments = parse_arguments(lexeme)?;
                 expect(LexSymbol::GenericClosingBracket, lexeme)?;
@@ -255,7 +583,9 @@ fn parse_single(lexeme: &mut std::iter::Peekable<std::slice::Iter<'_, Lexeme>>)
                         Expression::FunctionCall { 
                         target: target,
                         args: arguments,
-                    }
+                        location: target_location,
+                    },
+                    location
                 ));
             }
 
@@ -267,7 +597,8 @@ fn parse_single(lexeme: &mut std::iter::Peekable<std::slice::Iter<'_, Lexeme>>)
                 outtoken = Some(Statement::ExpressionStatement(
                     Expression::ReturnValue { 
                         value: Box::new(returning)
-                    }
+                    },
+                    location
                 ))
             }
 
@@ -279,20 +610,21 @@ fn parse_single(lexeme: &mut std::iter::Peekable<std::slice::Iter<'_, Lexeme>>)
                 expect(LexSymbol::GenericClosingBracket, lexeme)?;
                 expect(LexSymbol::FunctionOpeningBracket, lexeme)?;
                 let body = parse_until_symbol(LexSymbol::FunctionClosingBracket, lexeme)?;
-                lexeme.next();
+                expect(LexSymbol::FunctionClosingBracket, lexeme)?;
 
                 let mut else_body = None;
                 if peek_lexeme(lexeme).value == "else" {
                     lexeme.next();
                     expect(LexSymbol::FunctionOpeningBracket, lexeme)?;
                     else_body = Some(parse_until_symbol(LexSymbol::FunctionClosingBracket, lexeme)?);
-                    lexeme.next();
+                    expect(LexSymbol::FunctionClosingBracket, lexeme)?;
                 }
 
                 outtoken = Some(Statement::ConditionalStatement {
                     condition, 
                     body,
-                    else_body: else_body
+                    else_body: else_body,
+                    location
                 })
             }
 
@@ -305,21 +637,126 @@ fn parse_single(lexeme: &mut std::iter::Peekable<std::slice::Iter<'_, Lexeme>>)
                 expect(LexSymbol::GenericClosingBracket, lexeme)?;
                 expect(LexSymbol::FunctionOpeningBracket, lexeme)?;
                 let body = parse_until_symbol(LexSymbol::FunctionClosingBracket, lexeme)?;
-                lexeme.next();
+                expect(LexSymbol::FunctionClosingBracket, lexeme)?;
 
                 outtoken = Some(Statement::While {
                     condition, 
-                    body 
+                    body,
+                    location
                 })
             }
 
+            // Imports, see modules.rs
+            else if lex_val == "import" {
+                lexeme.next();
+                // `import c "hal.h";`, a C header through bindgen.rs
+                let mut ahead = lexeme.clone();
+                let header = ahead.next().is_some_and(|next| next.symbol == LexSymbol::Identifier && next.value == "c")
+                    && peek_lexeme(&mut ahead).symbol == LexSymbol::String;
+                let path = if header {
+                    lexeme.next();
+                    expect(LexSymbol::String, lexeme)?
+                } else {
+                    parse_path(lexeme)?.0
+                };
+                expect(LexSymbol::EndLine, lexeme)?;
+                outtoken = Some(Statement::Import { path, header, location })
+            }
+
+            // Constants
+            else if lex_val == "const" {
+                lexeme.next();
+                let name_location = peek_lexeme(lexeme).location;
+                let name = expect(LexSymbol::Identifier, lexeme)?;
+                expect(LexSymbol::EqualSign, lexeme)?;
+                let value_location = peek_lexeme(lexeme).location;
+                let value = parse_expression(lexeme)?;
+                expect(LexSymbol::EndLine, lexeme)?;
+                outtoken = Some(Statement::ConstAssignment { name, value, public: false, location, name_location, value_location })
+            }
+
+            // Visible from other modules, only for functions (extern and export ones too) and constants
+            else if lex_val == "pub" {
+                lexeme.next();
+                let next = peek_lexeme(lexeme);
+                if !["function", "const", "extern", "export"].contains(&next.value.as_str()) {
+                    return Err(format!("Expected 'function', 'const', 'extern' or 'export' after 'pub', not '{}' at position {}", next.value, position(next.location)));
+                }
+                outtoken = match parse_single(lexeme)? {
+                    Some(Statement::FunctionAssignment { name, arguments, parameter_types, body, export, attributes, location, name_location, .. }) =>
+                        Some(Statement::FunctionAssignment { name, arguments, parameter_types, body, public: true, export, attributes, location, name_location }),
+                    Some(Statement::ConstAssignment { name, value, location, name_location, value_location, .. }) =>
+                        Some(Statement::ConstAssignment { name, value, public: true, location, name_location, value_location }),
+                    Some(Statement::Extern { function, location, name_location, .. }) =>
+                        Some(Statement::Extern { function, public: true, location, name_location }),
+                    other => other,
+                };
+            }
+
+            // Inline assembly
+            else if lex_val == "asm" {
+                lexeme.next();
+                outtoken = Some(Statement::Asm { asm: parse_asm(lexeme)?, location })
+            }
+
+            // C functions, see ffi.rs
+            else if lex_val == "extern" {
+                lexeme.next();
+                // `"C" function name`
+                let name_location = lexeme.clone().nth(2).map_or(location, |name| name.location);
+                outtoken = Some(Statement::Extern { function: parse_extern(lexeme)?, public: false, location, name_location })
+            }
+
+            // Callable from C under its own name
+            else if lex_val == "export" {
+                lexeme.next();
+                let next = peek_lexeme(lexeme);
+                if next.value != "function" {
+                    return Err(format!("Expected 'function' after 'export', not '{}' at position {}", next.value, position(next.location)));
+                }
+                outtoken = match parse_single(lexeme)? {
+                    Some(Statement::FunctionAssignment { name, arguments, parameter_types, body, public, attributes, location, name_location, .. }) =>
+                        Some(Statement::FunctionAssignment { export: Some(name.clone()), name, arguments, parameter_types, body, public, attributes, location, name_location }),
+                    other => other,
+                };
+            }
+
             // (Else catch guard)
             else if lex_val == "else" {
                 let lx = peek_lexeme(lexeme);
-                return Err(format!("'Else' not conjoined to an 'if' clause at position {}:{}", lx.location.0, lx.location.1));
+                return Err(format!("'Else' not conjoined to an 'if' clause at position {}", position(lx.location)));
             }
 
-            else {return Err(format!("Unexpected keyword '{}', non-matching Lexer-Parser versions?", peek_lexeme(lexeme).value))}
+            else {
+                let keyword = peek_lexeme(lexeme);
+                return Err(format!("Unexpected keyword '{}', non-matching Lexer-Parser versions? at position {}", keyword.value, position(keyword.location)));
+            }
+        }
+
+        // `#![no_std]` and `#![allow(...)]` on their own, `#[...]`s go on the function after them
+        LexSymbol::Attribute => {
+            if let Some(name) = lex_val.strip_prefix('!') {
+                lexeme.next();
+                if name.trim() != NO_STD_ATTRIBUTE && allowed(name).is_none() {
+                    return Err(format!("Unknown attribute '#![{}]', expected #![{}] or #![allow(...)] at position {}", name.trim(), NO_STD_ATTRIBUTE, position(location)));
+                }
+                outtoken = Some(Statement::Attribute { name: name.trim().to_string(), location });
+            } else {
+                let mut attributes = Attributes::default();
+                while peek_lexeme(lexeme).symbol == LexSymbol::Attribute && !peek_lexeme(lexeme).value.starts_with('!') {
+                    parse_attribute(&peek_lexeme(lexeme), &mut attributes)?;
+                    lexeme.next();
+                }
+                let next = peek_lexeme(lexeme);
+                if next.symbol != LexSymbol::Keyword || !["function", "pub", "export"].contains(&next.value.as_str()) {
+                    return Err(format!("Attributes only go on functions, not on '{}' at position {}", next.value, position(next.location)));
+                }
+                outtoken = match parse_single(lexeme)? {
+                    Some(Statement::FunctionAssignment { name, arguments, parameter_types, body, public, export, location, name_location, .. }) =>
+                        Some(Statement::FunctionAssignment { name, arguments, parameter_types, body, public, export, attributes, location, name_location }),
+                    _ => return Err(format!("Attributes only go on functions at position {}", position(location))),
+                };
+            }
         }
 
         // "Breaking symbols"
@@ -339,8 +776,7 @@ fn parse_until_symbol(stopsymbol: LexSymbol, lexeme: &mut std::iter::Peekable<st
         if peek_lexeme(lexeme).symbol == stopsymbol || peek_lexeme(lexeme).symbol == LexSymbol::EOF
             {break}
         let statement = parse_single(lexeme)?;
-        if statement.is_none() {continue}
-        else {outtokens.push(statement.unwrap());}
+        if let Some(statement) = statement {outtokens.push(statement);}
     }
     return Ok(outtokens);
 }
@@ -359,24 +795,35 @@ fn expect(expectation: LexSymbol, lexeme: &mut std::iter::Peekable<std::slice::I
         return returnable;
     } else {
         let symbol = peek_lexeme(lexeme).symbol;
-        let position = peek_lexeme(lexeme).location;
-        return Err(format!("Expected {:?}, not {:?} at position {}:{}", expectation, symbol, position.0, position.1))
+        let location = peek_lexeme(lexeme).location;
+        return Err(format!("Expected {:?}, not {:?} at position {}", expectation, symbol, position(location)))
     }
 }
 
 /// Parser entrypoint, turns a `Vec<Lexeme>` to `Vec<Statement>`
 pub fn parser(mut lexeme: std::iter::Peekable<std::slice::Iter<'_, Lexeme>>) -> Result<Vec<Statement>, String> {
-    if PAR_DEBUG_PRINTS {println!("- - - PARSER")}
+    if debug_prints(PAR_DEBUG_PRINTS) {eprintln!("- - - PARSER")}
 
     let mut outtokens: Vec<Statement> = vec![];  
     loop {
         if peek_lexeme(&mut lexeme).symbol == LexSymbol::EOF {break}
         let statement = parse_single(&mut lexeme)?;
-        if statement.is_none() {continue}
-        else {outtokens.push(statement.unwrap());}
+        if let Some(statement) = statement {outtokens.push(statement);}
     }
 
-    if PAR_DEBUG_PRINTS {println!("\nStatement dump:\n{:#?}\n", outtokens)}
-    if PAR_DEBUG_PRINTS {println!("- - - Parser done!")}
+    if debug_prints(PAR_DEBUG_PRINTS) {eprintln!("\nStatement dump:\n{:#?}\n", outtokens)}
+    if debug_prints(PAR_DEBUG_PRINTS) {eprintln!("- - - Parser done!")}
     return Ok(outtokens)
-}
\ No newline at end of file
+}
+
+/// Parses a lone expression (optionally followed by a `;`), for places like the REPL
+/// that evaluate expressions on their own.
+pub fn expression_parser(mut lexeme: std::iter::Peekable<std::slice::Iter<'_, Lexeme>>) -> Result<Expression, String> {
+    let expression = parse_expression(&mut lexeme)?;
+    if peek_lexeme(&mut lexeme).symbol == LexSymbol::EndLine {lexeme.next();}
+    let rest = peek_lexeme(&mut lexeme);
+    if rest.symbol != LexSymbol::EOF {
+        return Err(format!("Unexpected {:?} after the expression at position {}", rest.symbol, position(rest.location)))
+    }
+    return Ok(expression)
+}
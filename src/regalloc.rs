@@ -0,0 +1,295 @@
+use std::collections::HashSet;
+use std::fmt::Debug;
+
+use crate::ir::{BinaryOp, Function, Instruction, Module, Operand, Terminator};
+use crate::seman::Type;
+
+// Register allocation shared by the native backends (x86.rs, riscv.rs, thumb.rs). Liveness
+// over the IR's virtual registers gives every one a live range, then linear scan hands out
+// the registers the backend lets it use, and whatever doesn't fit gets spilled to the stack
+// for its whole life. Ranges that live across a call only get callee-saved registers, so
+// nothing has to be saved around calls, and the backend saves the callee-saved registers the
+// function ends up using in its prologue.
+// A value can take more than one register (an i64 on a 32-bit machine), every word gets its
+// own home. Variables (locals) still live on the stack, only the virtual registers get
+// allocated. `--emit=regalloc` prints what came out of it.
+
+//
+// STRUCTS
+//
+
+/// What the allocator needs to know about a backend's registers
+pub trait Register: Copy + PartialEq + Debug {
+    fn name(&self) -> &'static str;
+}
+
+/// Where one word of a virtual register lives
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+#[derive(PartialEq)]
+pub enum Home<R> {
+    Reg(R),
+    /// Slot number in the frame's spill area
+    Spill(usize),
+}
+
+/// A backend's side of the allocation
+pub struct Registers<R: 'static> {
+    /// What gets handed out, in order of preference. Caller-saved ones should come first
+    /// since they're free to use (but don't survive calls).
+    pub pool: &'static [R],
+    /// The ones in the pool that survive calls
+    pub callee_saved: &'static [R],
+    /// Registers (words) a value of the type takes up
+    pub words: fn(Type) -> usize,
+    /// Whether division calls a runtime routine
+    pub division_calls: bool,
+}
+
+/// First and last position a virtual register is live at
+type Interval = (usize, usize);
+
+/// What the allocator came up with for one function
+pub struct Allocation<R> {
+    /// Home of every word of every virtual register
+    pub homes: Vec<Vec<Home<R>>>,
+    /// Number of spill slots (a word each) used
+    pub spills: usize,
+    /// Live range of every virtual register, None for the ones that are never used
+    pub intervals: Vec<Option<Interval>>,
+    /// Positions of the instructions that clobber the caller-saved registers, and whether
+    /// they do it before they're done reading their operands
+    pub calls: Vec<(usize, bool)>,
+}
+
+//
+// LIVENESS
+//
+
+pub fn operand_type(function: &Function, operand: &Operand) -> Type {
+    match operand {
+        Operand::Register(register) => function.registers[*register],
+        Operand::Constant(_) => Type::Int,
+    }
+}
+
+/// Virtual registers an instruction reads
+fn uses(instruction: &Instruction) -> Vec<usize> {
+    instruction.operands().into_iter().filter_map(|operand| match operand {
+        Operand::Register(register) => Some(*register),
+        Operand::Constant(_) => None,
+    }).collect()
+}
+
+fn terminator_uses(terminator: &Terminator) -> Vec<usize> {
+    match terminator.operand() {
+        Some(Operand::Register(register)) => vec![*register],
+        _ => vec![],
+    }
+}
+
+/// Whether the caller-saved registers are gone after the instruction (calls, and the runtime
+/// routines and inline assembly that count as one)
+fn clobbers<R>(instruction: &Instruction, registers: &Registers<R>) -> bool {
+    match instruction {
+        Instruction::Call { .. } | Instruction::Asm { .. } => true,
+        Instruction::Binary { op: BinaryOp::Div, .. } => registers.division_calls,
+        _ => false,
+    }
+}
+
+/// Whether the instruction calls something before it's done reading its operands: `print`
+/// is a call per argument, and printing flushes C's stdout first in a program that uses C
+fn clobbers_early(instruction: &Instruction, module: &Module) -> bool {
+    match instruction {
+        Instruction::Call { function, .. } => function == "print" || (module.uses_c() && ["__sys_write", "__sys_write_str"].contains(&function.as_str())),
+        _ => false,
+    }
+}
+
+/// Live range of every virtual register as the first and last position it's live at, None
+/// for the ones that are never used. Positions number every instruction and terminator of
+/// the function in block order. Also gives the positions of instructions that clobber the
+/// caller-saved registers, and whether they do it before reading their operands.
+fn liveness<R>(function: &Function, module: &Module, registers: &Registers<R>) -> (Vec<Option<Interval>>, Vec<(usize, bool)>) {
+    let blocks = &function.blocks;
+    let mut starts = vec![];
+    let mut position = 0;
+    for block in blocks {
+        starts.push(position);
+        position += block.instructions.len() + 1;
+    }
+
+    // What every block reads before writing it, and what it writes
+    let mut read_first: Vec<HashSet<usize>> = vec![HashSet::new(); blocks.len()];
+    let mut written: Vec<HashSet<usize>> = vec![HashSet::new(); blocks.len()];
+    for (index, block) in blocks.iter().enumerate() {
+        for instruction in &block.instructions {
+            for register in uses(instruction) {
+                if !written[index].contains(&register) {read_first[index].insert(register);}
+            }
+            if let Some(register) = instruction.dest() {written[index].insert(register);}
+        }
+        for register in terminator_uses(&block.terminator) {
+            if !written[index].contains(&register) {read_first[index].insert(register);}
+        }
+    }
+
+    // The usual backwards dataflow, until nothing changes
+    let mut live_in: Vec<HashSet<usize>> = vec![HashSet::new(); blocks.len()];
+    let mut live_out: Vec<HashSet<usize>> = vec![HashSet::new(); blocks.len()];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for index in (0..blocks.len()).rev() {
+            let out: HashSet<usize> = blocks[index].terminator.successors().iter().flat_map(|successor| live_in[*successor].iter().copied()).collect();
+            let mut into = read_first[index].clone();
+            into.extend(out.difference(&written[index]));
+            if into != live_in[index] || out != live_out[index] {
+                live_in[index] = into;
+                live_out[index] = out;
+                changed = true;
+            }
+        }
+    }
+
+    let mut intervals: Vec<Option<Interval>> = vec![None; function.registers.len()];
+    fn extend(intervals: &mut [Option<Interval>], register: usize, position: usize) {
+        intervals[register] = Some(match intervals[register] {
+            Some((start, end)) => (start.min(position), end.max(position)),
+            None => (position, position),
+        });
+    }
+    let mut calls = vec![];
+    for (index, block) in blocks.iter().enumerate() {
+        let start = starts[index];
+        let end = start + block.instructions.len();
+        for register in &live_in[index] {extend(&mut intervals, *register, start)}
+        for register in &live_out[index] {extend(&mut intervals, *register, end)}
+        for (offset, instruction) in block.instructions.iter().enumerate() {
+            for register in uses(instruction).into_iter().chain(instruction.dest()) {
+                extend(&mut intervals, register, start + offset);
+            }
+            if clobbers(instruction, registers) {calls.push((start + offset, clobbers_early(instruction, module)))}
+        }
+        for register in terminator_uses(&block.terminator) {extend(&mut intervals, register, end)}
+    }
+    (intervals, calls)
+}
+
+//
+// LINEAR SCAN
+//
+
+/// Linear scan: goes through the live ranges by start, and gives each one registers that
+/// aren't taken at that point. Ranges that live across a call only get callee-saved ones.
+/// When there aren't enough, whichever range ends last (this one or one that already has
+/// registers) goes to the stack for its whole life.
+pub fn allocate<R: Register>(function: &Function, module: &Module, registers: &Registers<R>) -> Allocation<R> {
+    let (intervals, calls) = liveness(function, module, registers);
+    let words = registers.words;
+    let mut homes: Vec<Vec<Home<R>>> = vec![vec![]; function.registers.len()];
+    let mut order: Vec<usize> = (0..function.registers.len())
+        .filter(|register| intervals[*register].is_some() && words(function.registers[*register]) > 0).collect();
+    order.sort_by_key(|register| intervals[*register].unwrap().0);
+
+    let pool = registers.pool;
+    let mut free: Vec<R> = pool.to_vec();
+    let mut active: Vec<usize> = vec![];
+    let mut spills = 0;
+    let give_back = |free: &mut Vec<R>, homes: &[Home<R>]| {
+        free.extend(homes.iter().filter_map(|home| match home {Home::Reg(reg) => Some(*reg), Home::Spill(_) => None}));
+        free.sort_by_key(|reg| pool.iter().position(|pool| pool == reg));
+    };
+    for register in order {
+        let (start, end) = intervals[register].unwrap();
+        let mut still_active = vec![];
+        for other in active {
+            if intervals[other].unwrap().1 < start {give_back(&mut free, &homes[other])} else {still_active.push(other)}
+        }
+        active = still_active;
+
+        let crosses_call = calls.iter().any(|(call, early)| start < *call && (*call < end || (*early && *call == end)));
+        let usable = |reg: &R| !crosses_call || registers.callee_saved.contains(reg);
+        let needed = words(function.registers[register]);
+        let available = free.iter().filter(|reg| usable(reg)).count();
+        if available < needed {
+            let victim = active.iter().copied()
+                .filter(|other| intervals[*other].unwrap().1 > end)
+                .filter(|other| available + homes[*other].iter().filter(|home| matches!(home, Home::Reg(reg) if usable(reg))).count() >= needed)
+                .max_by_key(|other| intervals[*other].unwrap().1);
+            let Some(victim) = victim else {
+                homes[register] = (spills..spills + needed).map(Home::Spill).collect();
+                spills += needed;
+                continue;
+            };
+            give_back(&mut free, &homes[victim]);
+            for home in homes[victim].iter_mut() {
+                *home = Home::Spill(spills);
+                spills += 1;
+            }
+            active.retain(|other| *other != victim);
+        }
+        let picked: Vec<R> = free.iter().copied().filter(usable).take(needed).collect();
+        free.retain(|reg| !picked.contains(reg));
+        homes[register] = picked.into_iter().map(Home::Reg).collect();
+        active.push(register);
+    }
+    Allocation { homes, spills, intervals, calls }
+}
+
+impl<R: Register> Allocation<R> {
+    /// The callee-saved registers the function uses, in the backend's order. The prologue
+    /// saves these.
+    pub fn saved(&self, registers: &Registers<R>) -> Vec<R> {
+        registers.callee_saved.iter().copied()
+            .filter(|reg| self.homes.iter().flatten().any(|home| *home == Home::Reg(*reg))).collect()
+    }
+}
+
+//
+// PRINTING
+//
+
+/// `--emit=regalloc`: every function's IR with the positions the live ranges are in, then
+/// the range and home of every virtual register
+pub fn dump<R: Register>(module: &Module, registers: &Registers<R>) -> String {
+    let mut out = String::new();
+    for function in &module.functions {
+        let allocation = allocate(function, module, registers);
+        let saved: Vec<&str> = allocation.saved(registers).iter().map(|reg| reg.name()).collect();
+        let calls: Vec<String> = allocation.calls.iter()
+            .map(|(position, early)| if *early {format!("{} (early)", position)} else {position.to_string()}).collect();
+        out += &format!("function {}\n", function.name);
+        out += &format!("    spill slots: {}\n", allocation.spills);
+        out += &format!("    callee-saved: {}\n", if saved.is_empty() {"none".to_string()} else {saved.join(", ")});
+        out += &format!("    calls at: {}\n", if calls.is_empty() {"none".to_string()} else {calls.join(", ")});
+
+        let mut position = 0;
+        for (index, block) in function.blocks.iter().enumerate() {
+            out += &format!("bb{}:\n", index);
+            for instruction in &block.instructions {
+                out += &format!("{:>5}  {}\n", position, function.instruction_text(instruction));
+                position += 1;
+            }
+            out += &format!("{:>5}  {}\n", position, function.terminator_text(&block.terminator));
+            position += 1;
+        }
+
+        out += "registers:\n";
+        for (register, ty) in function.registers.iter().enumerate() {
+            let Some((start, end)) = allocation.intervals[register] else {
+                out += &format!("    %{}: {} unused\n", register, ty);
+                continue;
+            };
+            let homes: Vec<String> = allocation.homes[register].iter().map(|home| match home {
+                Home::Reg(reg) => reg.name().to_string(),
+                Home::Spill(slot) => format!("spill {}", slot),
+            }).collect();
+            let homes = if homes.is_empty() {"nothing".to_string()} else {homes.join(":")};
+            out += &format!("    %{}: {} [{}, {}] {}\n", register, ty, start, end, homes);
+        }
+        out += "\n";
+    }
+    out
+}
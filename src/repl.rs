@@ -0,0 +1,177 @@
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+
+use crate::compiler_settings::*;
+use crate::interpreter::{Interpreter, Value};
+use crate::lexer::{lexer, LexSymbol, Lexeme};
+use crate::parser::{expression_parser, parser, Statement};
+use crate::seman::{analyze, builtin_functions, check_expression, FunctionInfo};
+
+// Interactive prompt on top of the interpreter.
+// Every entry gets type checked together with everything entered before it (seman only
+// knows how to check whole programs), and then only the new statements are run.
+
+const HELP: &str = "\
+Type statements (`let x = 1;`, `function f(a) { ... }`) or bare expressions (`x * 2`).
+Unfinished blocks wait for more lines.
+
+:tokens <code>  Show the lexed tokens
+:ast <code>     Show the parsed AST
+:type <expr>    Show the type of an expression
+:reset          Forget all variables and functions
+:help           Show this
+:quit           Exit (so does Ctrl-D)";
+
+//
+// STRUCTS
+//
+
+struct Repl {
+    /// Every statement entered so far, redefined functions replaced
+    history: Vec<Statement>,
+    functions: HashMap<String, FunctionInfo>,
+    interpreter: Interpreter,
+}
+
+//
+// FUNCTIONS
+//
+
+/// Whether the input still has open brackets, so the entry isn't over yet
+fn unfinished(tokens: &[Lexeme]) -> bool {
+    let mut depth = 0;
+    for token in tokens {
+        match token.symbol {
+            LexSymbol::FunctionOpeningBracket | LexSymbol::GenericOpeningBracket => depth += 1,
+            LexSymbol::FunctionClosingBracket | LexSymbol::GenericClosingBracket => depth -= 1,
+            _ => {}
+        }
+    }
+    depth > 0
+}
+
+/// Statements start with a keyword, anything else gets evaluated as an expression
+fn is_statement(tokens: &[Lexeme]) -> bool {
+    tokens.first().is_some_and(|token| token.symbol == LexSymbol::Keyword)
+}
+
+impl Repl {
+    fn new() -> Repl {
+        Repl {
+            history: vec![],
+            functions: builtin_functions().into_iter().map(|info| (info.name.clone(), info)).collect(),
+            interpreter: Interpreter::new(),
+        }
+    }
+
+    fn statements(&mut self, new: Vec<Statement>) -> Result<(), String> {
+        // A function defined again replaces the old one instead of being an error
+        let redefined: Vec<&String> = new.iter().filter_map(|statement| match statement {
+            Statement::FunctionAssignment { name, .. } => Some(name),
+            _ => None,
+        }).collect();
+        let mut program: Vec<Statement> = self.history.iter().filter(|statement| match statement {
+            Statement::FunctionAssignment { name, .. } => !redefined.contains(&name),
+            _ => true,
+        }).cloned().collect();
+        program.extend(new.iter().cloned());
+
+        let analysis = analyze(program)?;
+        // Checked, so it's part of the program now, even if running it fails halfway
+        let result = self.interpreter.run_more(&analysis, &new);
+        self.history = analysis.statements;
+        self.functions = analysis.functions;
+        match result {
+            Ok(Some(value)) => println!("{}", value),
+            Ok(None) => {}
+            Err(error) => return Err(format!("runtime error: {}", error)),
+        }
+        Ok(())
+    }
+
+    fn expression(&mut self, tokens: &[Lexeme]) -> Result<(), String> {
+        let expression = expression_parser(tokens.iter().peekable())?;
+        check_expression(&expression, &self.interpreter.scope(), &self.functions)?;
+        match self.interpreter.evaluate(&expression, tokens[0].location) {
+            // print() already printed
+            Ok(Value::Void) => {}
+            Ok(value) => println!("{}", value),
+            Err(error) => return Err(format!("runtime error: {}", error)),
+        }
+        Ok(())
+    }
+
+    fn command(&mut self, line: &str) -> Result<bool, String> {
+        let (command, argument) = line.split_once(' ').unwrap_or((line, ""));
+        let tokens = lexer(argument);
+        match command {
+            ":tokens" => {
+                for token in tokens.iter().filter(|token| token.symbol != LexSymbol::EOF) {
+                    println!("{}:{}\t{:?}\t{}", token.location.0, token.location.1, token.symbol, token.value);
+                }
+            }
+            ":ast" => {
+                if is_statement(&tokens) {
+                    println!("{:#?}", parser(tokens.iter().peekable())?);
+                } else {
+                    println!("{:#?}", expression_parser(tokens.iter().peekable())?);
+                }
+            }
+            ":type" => {
+                let expression = expression_parser(tokens.iter().peekable())?;
+                println!("{}", check_expression(&expression, &self.interpreter.scope(), &self.functions)?);
+            }
+            ":reset" => *self = Repl::new(),
+            ":help" => println!("{}", HELP),
+            ":quit" | ":q" => return Ok(false),
+            _ => return Err(format!("Unknown command '{}', see :help", command)),
+        }
+        Ok(true)
+    }
+
+    /// Handles one complete entry, returns false when it's time to quit
+    fn entry(&mut self, input: &str) -> Result<bool, String> {
+        let trimmed = input.trim();
+        if trimmed.starts_with(':') {return self.command(trimmed)}
+
+        let tokens = lexer(input);
+        if tokens[0].symbol == LexSymbol::EOF {return Ok(true)}
+        if is_statement(&tokens) {
+            self.statements(parser(tokens.iter().peekable())?)?;
+        } else {
+            self.expression(&tokens)?;
+        }
+        Ok(true)
+    }
+}
+
+/// `galvan repl`, runs until `:quit` or the end of stdin
+pub fn repl() -> Result<(), String> {
+    // The stage dumps would bury everything else
+    set_quiet(true);
+    println!("Galvan REPL, :help for help");
+
+    let mut repl = Repl::new();
+    let stdin = std::io::stdin();
+    let mut input = String::new();
+    loop {
+        print!("{}", if input.is_empty() {REPL_PROMPT} else {REPL_CONTINUATION_PROMPT});
+        let _ = std::io::stdout().flush();
+
+        let mut line = String::new();
+        match stdin.lock().read_line(&mut line) {
+            Ok(0) => {println!(); return Ok(())}
+            Ok(_) => {}
+            Err(error) => return Err(format!("Can't read stdin: {}", error)),
+        }
+        input.push_str(&line);
+        if !input.trim_start().starts_with(':') && unfinished(&lexer(&input)) {continue}
+
+        let entry = std::mem::take(&mut input);
+        match repl.entry(&entry) {
+            Ok(true) => {}
+            Ok(false) => return Ok(()),
+            Err(error) => eprintln!("error: {}", error),
+        }
+    }
+}
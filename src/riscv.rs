@@ -0,0 +1,1157 @@
+use crate::backend::*;
+use crate::compiler_settings::*;
+use crate::ffi::{CType, Extern};
+use crate::intrinsics::INTRINSICS;
+use crate::ir::{symbol, BinaryOp, Function, Instruction, Module, Operand, Terminator};
+use crate::parser::expand_asm;
+use crate::regalloc::{self, operand_type, Allocation, Home, Register, Registers};
+use crate::seman::Type;
+
+// RISC-V RV32IMC backend with the ILP32 calling convention, for the embedded boards. Same IR
+// as x86.rs, virtual registers go wherever regalloc.rs puts them (t3-t5 and s1-s11, or a
+// spill slot). Variables still live on the stack.
+// Galvan's i64s are register pairs on a 32-bit machine (low word first, like C's int64_t),
+// a str is one register. 64-bit division and printing numbers go through small runtime
+// routines, everything else is inline.
+// The output is GNU assembler for `-march=rv32imc -mabi=ilp32`, the assembler picks the
+// compressed (C) forms. Linux programs use the RV32 syscalls so qemu-riscv32 runs them, and
+// riscv_encoder.rs and riscv_sim.rs run the same program without any RISC-V tools around.
+// Freestanding programs, interrupts and sections work like on x86 (mret instead of iretq).
+
+//
+// STRUCTS
+//
+
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+#[derive(PartialEq)]
+#[allow(dead_code)] // The order is the hardware numbering, so they all stay even if unused
+pub enum Reg {
+    Zero, Ra, Sp, Gp, Tp, T0, T1, T2, S0, S1, A0, A1, A2, A3, A4, A5, A6, A7,
+    S2, S3, S4, S5, S6, S7, S8, S9, S10, S11, T3, T4, T5, T6,
+}
+impl Reg {
+    const ALL: [Reg; 32] = [Reg::Zero, Reg::Ra, Reg::Sp, Reg::Gp, Reg::Tp, Reg::T0, Reg::T1, Reg::T2,
+        Reg::S0, Reg::S1, Reg::A0, Reg::A1, Reg::A2, Reg::A3, Reg::A4, Reg::A5, Reg::A6, Reg::A7,
+        Reg::S2, Reg::S3, Reg::S4, Reg::S5, Reg::S6, Reg::S7, Reg::S8, Reg::S9, Reg::S10, Reg::S11,
+        Reg::T3, Reg::T4, Reg::T5, Reg::T6];
+
+    /// ABI name (a0, s1, ...), `x<n>` or fp
+    pub fn from_name(name: &str) -> Option<Reg> {
+        if name == "fp" {return Some(Reg::S0)}
+        if let Some(number) = name.strip_prefix('x').and_then(|number| number.parse::<usize>().ok()) {
+            return Reg::ALL.get(number).copied();
+        }
+        Reg::ALL.into_iter().find(|reg| reg.name() == name)
+    }
+
+    /// Hardware register number, used by the encoder
+    pub fn number(&self) -> u32 {
+        *self as u32
+    }
+
+    pub fn name(&self) -> &'static str {
+        ["zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4", "a5",
+         "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4", "t5", "t6"][self.number() as usize]
+    }
+}
+
+/// Registers arguments are passed in, in order
+pub const ARGUMENT_REGISTERS: [Reg; 8] = [Reg::A0, Reg::A1, Reg::A2, Reg::A3, Reg::A4, Reg::A5, Reg::A6, Reg::A7];
+
+/// What the register allocator hands out, caller-saved ones first since they're free to use
+/// (but don't survive calls). The a registers are left alone so calls never have to shuffle
+/// arguments around, t0-t2 are scratch and t6 is for addresses that don't fit an offset.
+const POOL: [Reg; 14] = [Reg::T3, Reg::T4, Reg::T5, Reg::S1, Reg::S2, Reg::S3, Reg::S4, Reg::S5,
+    Reg::S6, Reg::S7, Reg::S8, Reg::S9, Reg::S10, Reg::S11];
+const CALLEE_SAVED: [Reg; 11] = [Reg::S1, Reg::S2, Reg::S3, Reg::S4, Reg::S5, Reg::S6, Reg::S7,
+    Reg::S8, Reg::S9, Reg::S10, Reg::S11];
+
+/// Registers inline assembly operands can end up in, none of them hold anything across it
+const ASM_REGISTERS: [Reg; 11] = [Reg::A0, Reg::A1, Reg::A2, Reg::A3, Reg::A4, Reg::A5, Reg::A6, Reg::A7,
+    Reg::T0, Reg::T1, Reg::T2];
+
+/// Register-register operations, the ones with an immediate version have `imm_name()`
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+#[derive(PartialEq)]
+#[allow(dead_code)] // The whole of RV32IM, the encoder and simulator know all of them
+pub enum Op {
+    Add, Sub, Sll, Slt, Sltu, Xor, Srl, Sra, Or, And,
+    Mul, Mulh, Mulhsu, Mulhu, Div, Divu, Rem, Remu,
+}
+impl Op {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Op::Add => "add", Op::Sub => "sub", Op::Sll => "sll", Op::Slt => "slt", Op::Sltu => "sltu",
+            Op::Xor => "xor", Op::Srl => "srl", Op::Sra => "sra", Op::Or => "or", Op::And => "and",
+            Op::Mul => "mul", Op::Mulh => "mulh", Op::Mulhsu => "mulhsu", Op::Mulhu => "mulhu",
+            Op::Div => "div", Op::Divu => "divu", Op::Rem => "rem", Op::Remu => "remu",
+        }
+    }
+
+    /// addi, slli, ... None for the ones without an immediate form (sub and the M extension)
+    pub fn imm_name(&self) -> Option<&'static str> {
+        match self {
+            Op::Add => Some("addi"), Op::Sll => Some("slli"), Op::Slt => Some("slti"), Op::Sltu => Some("sltiu"),
+            Op::Xor => Some("xori"), Op::Srl => Some("srli"), Op::Sra => Some("srai"), Op::Or => Some("ori"),
+            Op::And => Some("andi"),
+            _ => None,
+        }
+    }
+}
+
+/// Size (and signedness, for loads) of a memory access
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+#[derive(PartialEq)]
+#[allow(dead_code)]
+pub enum Width {
+    Byte, ByteUnsigned, Half, HalfUnsigned, Word,
+}
+impl Width {
+    fn load_name(&self) -> &'static str {
+        match self {Width::Byte => "lb", Width::ByteUnsigned => "lbu", Width::Half => "lh", Width::HalfUnsigned => "lhu", Width::Word => "lw"}
+    }
+
+    fn store_name(&self) -> &'static str {
+        match self {Width::Byte | Width::ByteUnsigned => "sb", Width::Half | Width::HalfUnsigned => "sh", Width::Word => "sw"}
+    }
+}
+
+/// Branch conditions
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+#[derive(PartialEq)]
+pub enum Cond {
+    Eq, Ne, Lt, Ge, Ltu, Geu,
+}
+impl Cond {
+    pub fn name(&self) -> &'static str {
+        match self {Cond::Eq => "eq", Cond::Ne => "ne", Cond::Lt => "lt", Cond::Ge => "ge", Cond::Ltu => "ltu", Cond::Geu => "geu"}
+    }
+
+    pub fn inverse(&self) -> Cond {
+        match self {Cond::Eq => Cond::Ne, Cond::Ne => Cond::Eq, Cond::Lt => Cond::Ge, Cond::Ge => Cond::Lt, Cond::Ltu => Cond::Geu, Cond::Geu => Cond::Ltu}
+    }
+}
+
+/// One assembly instruction (or label). Destination comes first, like in the assembly.
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+pub enum Inst {
+    Label(String),
+    /// `li rd, imm`, lui and addi as needed
+    Li(Reg, i32),
+    /// `la rd, symbol`, auipc and addi
+    La(Reg, String),
+    /// `rd = rs1 <op> rs2`
+    Op(Op, Reg, Reg, Reg),
+    /// `rd = rs1 <op> imm`, only for ops with an `imm_name()`
+    OpImm(Op, Reg, Reg, i32),
+    /// `rd = [base + offset]`
+    Load(Width, Reg, Reg, i32),
+    /// `[base + offset] = rs`
+    Store(Width, Reg, Reg, i32),
+    Branch(Cond, Reg, Reg, String),
+    Jump(String),
+    Call(String),
+    Ret,
+    Ecall,
+    Ebreak,
+    /// Return from a machine mode interrupt
+    Mret,
+    /// Inline assembly, the template with the register of every operand
+    Asm {template: String, operands: Vec<(String, Reg)>},
+}
+
+/// Everything that ends up in the output file
+#[derive(Debug)]
+pub struct Program {
+    pub text: Vec<Inst>,
+    /// Label -> bytes (strings get their NUL terminator included)
+    pub rodata: Vec<(String, Vec<u8>)>,
+    /// Symbols visible outside the object
+    pub globals: Vec<String>,
+    /// Code of `#[section(...)]` functions, by section name
+    pub sections: Vec<(String, Vec<Inst>)>,
+    /// Where an executable starts
+    pub entry: String,
+}
+
+//
+// REGISTER ALLOCATION
+//
+
+/// Registers a value of the type takes up
+impl Register for Reg {
+    fn name(&self) -> &'static str {
+        Reg::name(self)
+    }
+}
+
+const REGISTERS: Registers<Reg> = Registers { pool: &POOL, callee_saved: &CALLEE_SAVED, words: words_32, division_calls: true };
+
+/// `--emit=regalloc`
+pub fn dump_allocation(module: &Module) -> String {
+    regalloc::dump(module, &REGISTERS)
+}
+
+//
+// CODE GENERATION
+//
+
+/// (a0:a1 / a2:a3), the quotient in a0:a1, traps with ebreak on division by zero
+pub const DIV64: &str = "__gv_div64";
+/// Unsigned (a0:a1 / a2:a3), the quotient in a0:a1 and the remainder in a2:a3
+const UDIVMOD64: &str = "__gv_udivmod64";
+
+// Linux RV32 syscall numbers
+const SYS_OPENAT: i32 = 56;
+const SYS_CLOSE: i32 = 57;
+const SYS_READ: i32 = 63;
+const SYS_WRITE: i32 = 64;
+const SYS_EXIT: i32 = 93;
+const SYS_BRK: i32 = 214;
+const AT_FDCWD: i32 = -100;
+
+/// Whether the value fits a 12-bit signed immediate
+pub fn fits_12(value: i32) -> bool {
+    (-2048..2048).contains(&value)
+}
+
+fn mv(dest: Reg, source: Reg) -> Inst {
+    Inst::OpImm(Op::Add, dest, source, 0)
+}
+
+/// Where one word of an argument goes
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+#[derive(PartialEq)]
+enum ArgPlace {
+    Reg(Reg),
+    /// Offset from the stack pointer at the call
+    Stack(i32),
+}
+
+/// The ILP32 calling convention. Arguments (by number of words, and whether they're a
+/// variadic one) go into a0-a7 a word at a time, an i64 takes two, low word first, and gets
+/// split between a7 and the stack if it has to. The rest goes on the stack with its natural
+/// alignment, variadic i64s start at an even register. Returns where every word goes, and the
+/// stack space that takes (a multiple of 16).
+fn assign_arguments(arguments: &[(usize, bool)]) -> (Vec<Vec<ArgPlace>>, i32) {
+    let mut next = 0;
+    let mut stack = 0;
+    let mut places = vec![];
+    for (words, variadic) in arguments {
+        let mut place = vec![];
+        if *words == 2 && *variadic && next % 2 == 1 {next += 1}
+        if *words == 2 && next >= ARGUMENT_REGISTERS.len() {stack = (stack + 7) / 8 * 8}
+        for _ in 0..*words {
+            if next < ARGUMENT_REGISTERS.len() {
+                place.push(ArgPlace::Reg(ARGUMENT_REGISTERS[next]));
+                next += 1;
+            } else {
+                place.push(ArgPlace::Stack(stack));
+                stack += 4;
+            }
+        }
+        places.push(place);
+    }
+    (places, (stack + 15) / 16 * 16)
+}
+
+/// Words and variadic-ness of every argument of a call
+fn call_arguments(function: &Function, args: &[Operand], external: Option<&Extern>) -> Vec<(usize, bool)> {
+    args.iter().enumerate().map(|(index, arg)| match external.and_then(|external| external.parameters.get(index)) {
+        Some((_, CType::Int { bits: 64, .. })) => (2, false),
+        Some(_) => (1, false),
+        None => (words_32(operand_type(function, arg)), external.is_some()),
+    }).collect()
+}
+
+/// Stack layout of one function. s0 points at the top of the frame (the stack pointer the
+/// caller had), below it are the return address, the old s0, the callee-saved registers the
+/// function uses, 8 bytes for every local (low word first) and the spill slots. Outgoing
+/// stack arguments are at the bottom, at sp.
+struct Frame {
+    size: i32,
+    saved: Vec<Reg>,
+    locals: Vec<i32>,
+    spill_base: i32,
+}
+impl Frame {
+    fn new(function: &Function, allocation: &Allocation<Reg>, outgoing: i32) -> Frame {
+        let saved = allocation.saved(&REGISTERS);
+        let mut offset = (8 + 4 * saved.len() as i32 + 7) / 8 * 8;
+        let mut locals = vec![];
+        for _ in &function.locals {
+            offset += 8;
+            locals.push(-offset);
+        }
+        let spill_base = -offset - 4;
+        offset += 4 * allocation.spills as i32;
+        let size = (offset + outgoing + 15) / 16 * 16;
+        Frame { size, saved, locals, spill_base }
+    }
+
+    fn spill(&self, slot: usize) -> i32 {
+        self.spill_base - 4 * slot as i32
+    }
+}
+
+/// Generates one function, `homes` is where the register allocator put every virtual register
+struct Generator<'a> {
+    out: &'a mut Vec<Inst>,
+    function: &'a Function,
+    module: &'a Module,
+    homes: Vec<Vec<Home<Reg>>>,
+    frame: Frame,
+}
+impl Generator<'_> {
+    /// Base register and offset for `offset(s0)`, through t6 when it doesn't fit 12 bits
+    fn address(&mut self, offset: i32) -> (Reg, i32) {
+        if fits_12(offset) {return (Reg::S0, offset)}
+        self.out.push(Inst::Li(Reg::T6, offset));
+        self.out.push(Inst::Op(Op::Add, Reg::T6, Reg::T6, Reg::S0));
+        (Reg::T6, 0)
+    }
+
+    fn load(&mut self, reg: Reg, offset: i32) {
+        let (base, offset) = self.address(offset);
+        self.out.push(Inst::Load(Width::Word, reg, base, offset));
+    }
+
+    fn store(&mut self, reg: Reg, offset: i32) {
+        let (base, offset) = self.address(offset);
+        self.out.push(Inst::Store(Width::Word, reg, base, offset));
+    }
+
+    /// Register with word `word` of the operand in it: its home, or `scratch` if it has to be
+    /// loaded (or is a constant). Zero words are the zero register, and so is the missing
+    /// upper word of a str.
+    fn read(&mut self, operand: &Operand, word: usize, scratch: Reg) -> Reg {
+        match operand {
+            Operand::Constant(value) => {
+                let value = if word == 0 {*value as i32} else {(*value >> 32) as i32};
+                if value == 0 {return Reg::Zero}
+                self.out.push(Inst::Li(scratch, value));
+                scratch
+            }
+            Operand::Register(register) => match self.homes[*register].get(word) {
+                Some(Home::Reg(reg)) => *reg,
+                Some(Home::Spill(slot)) => {
+                    let offset = self.frame.spill(*slot);
+                    self.load(scratch, offset);
+                    scratch
+                }
+                None => Reg::Zero,
+            },
+        }
+    }
+
+    /// Same as `read()`, but the value always ends up in `target`
+    fn read_into(&mut self, operand: &Operand, word: usize, target: Reg) {
+        let reg = self.read(operand, word, target);
+        if reg != target {self.out.push(mv(target, reg))}
+    }
+
+    /// Register to compute word `word` of `dest` in: its home, or `scratch` if it's spilled
+    fn target(&self, dest: usize, word: usize, scratch: Reg) -> Reg {
+        match self.homes[dest].get(word) {
+            Some(Home::Reg(reg)) => *reg,
+            _ => scratch,
+        }
+    }
+
+    /// Puts `value` into word `word` of `dest`, after computing it into `target()`
+    fn write(&mut self, dest: usize, word: usize, value: Reg) {
+        match self.homes[dest].get(word).copied() {
+            Some(Home::Reg(reg)) if reg != value => self.out.push(mv(reg, value)),
+            Some(Home::Spill(slot)) => {
+                let offset = self.frame.spill(slot);
+                self.store(value, offset);
+            }
+            _ => {}
+        }
+    }
+
+    fn local_words(&self, local: usize) -> usize {
+        words_32(self.function.locals[local].ty)
+    }
+
+    fn binary(&mut self, dest: usize, op: BinaryOp, left: &Operand, right: &Operand) {
+        if op == BinaryOp::Div {
+            self.read_into(left, 0, Reg::A0);
+            self.read_into(left, 1, Reg::A1);
+            self.read_into(right, 0, Reg::A2);
+            self.read_into(right, 1, Reg::A3);
+            self.out.push(Inst::Call(DIV64.to_string()));
+            self.write(dest, 0, Reg::A0);
+            self.write(dest, 1, Reg::A1);
+            return;
+        }
+        // Comparisons are the same as lt with the operands swapped and/or the result flipped
+        let (left, right) = match op {BinaryOp::Gt | BinaryOp::Le => (right, left), _ => (left, right)};
+        let (al, ah) = (self.read(left, 0, Reg::A0), self.read(left, 1, Reg::A1));
+        let (bl, bh) = (self.read(right, 0, Reg::A2), self.read(right, 1, Reg::A3));
+        // The allocator never gives dest a register its operands are in, it's live at the same time
+        let (dl, dh) = (self.target(dest, 0, Reg::A4), self.target(dest, 1, Reg::A5));
+        let (t0, t1, t2) = (Reg::T0, Reg::T1, Reg::T2);
+        let out = &mut self.out;
+        match op {
+            BinaryOp::Add => out.extend([
+                Inst::Op(Op::Add, dl, al, bl),
+                Inst::Op(Op::Sltu, t0, dl, bl), // carry
+                Inst::Op(Op::Add, dh, ah, bh),
+                Inst::Op(Op::Add, dh, dh, t0),
+            ]),
+            BinaryOp::Sub => out.extend([
+                Inst::Op(Op::Sltu, t0, al, bl), // borrow
+                Inst::Op(Op::Sub, dl, al, bl),
+                Inst::Op(Op::Sub, dh, ah, bh),
+                Inst::Op(Op::Sub, dh, dh, t0),
+            ]),
+            // Only the low 64 bits: al*bl in full, plus the cross products in the upper word
+            BinaryOp::Mul => out.extend([
+                Inst::Op(Op::Mulhu, t0, al, bl),
+                Inst::Op(Op::Mul, t1, al, bh),
+                Inst::Op(Op::Add, t0, t0, t1),
+                Inst::Op(Op::Mul, t1, ah, bl),
+                Inst::Op(Op::Add, dh, t0, t1),
+                Inst::Op(Op::Mul, dl, al, bl),
+            ]),
+            BinaryOp::Eq | BinaryOp::Ne => {
+                out.extend([
+                    Inst::Op(Op::Xor, t0, al, bl),
+                    Inst::Op(Op::Xor, t1, ah, bh),
+                    Inst::Op(Op::Or, t0, t0, t1),
+                ]);
+                out.push(if op == BinaryOp::Eq {Inst::OpImm(Op::Sltu, dl, t0, 1)} else {Inst::Op(Op::Sltu, dl, Reg::Zero, t0)});
+                out.push(mv(dh, Reg::Zero));
+            }
+            // Signed compare of the upper words, or unsigned of the lower ones if those are equal
+            _ => {
+                out.extend([
+                    Inst::Op(Op::Slt, t0, ah, bh),
+                    Inst::Op(Op::Xor, t1, ah, bh),
+                    Inst::OpImm(Op::Sltu, t1, t1, 1),
+                    Inst::Op(Op::Sltu, t2, al, bl),
+                    Inst::Op(Op::And, t1, t1, t2),
+                    Inst::Op(Op::Or, dl, t0, t1),
+                ]);
+                if matches!(op, BinaryOp::Le | BinaryOp::Ge) {out.push(Inst::OpImm(Op::Xor, dl, dl, 1))}
+                out.push(mv(dh, Reg::Zero));
+            }
+        }
+        self.write(dest, 0, dl);
+        self.write(dest, 1, dh);
+    }
+
+    /// `print(a, b, ...)` prints every argument separated by spaces, and a newline at the end
+    fn print(&mut self, args: &[Operand]) {
+        for (index, arg) in args.iter().enumerate() {
+            if index > 0 {
+                self.out.push(Inst::Li(Reg::A0, b' ' as i32));
+                self.out.push(Inst::Call(PRINT_CHAR.to_string()));
+            }
+            self.read_into(arg, 0, Reg::A0);
+            if operand_type(self.function, arg) == Type::Str {
+                self.out.push(Inst::Call(PRINT_STR.to_string()));
+            } else {
+                self.read_into(arg, 1, Reg::A1);
+                self.out.push(Inst::Call(PRINT_INT.to_string()));
+            }
+        }
+        self.out.push(Inst::Li(Reg::A0, b'\n' as i32));
+        self.out.push(Inst::Call(PRINT_CHAR.to_string()));
+    }
+
+    /// A call to a Galvan function, or to a C one when `external` is given
+    fn call(&mut self, target: &str, args: &[Operand], dest: Option<usize>, external: Option<&Extern>) {
+        let (places, _) = assign_arguments(&call_arguments(self.function, args, external));
+        // Stack arguments first, they go through t0 and the a registers aren't touched yet
+        for (index, (arg, place)) in args.iter().zip(&places).enumerate() {
+            let c_type = external.and_then(|external| external.parameters.get(index)).map(|(_, ty)| ty);
+            for (word, part) in place.iter().enumerate() {
+                let ArgPlace::Stack(offset) = part else {continue};
+                self.read_into(arg, word, Reg::T0);
+                if place.len() == 1 {narrow(self.out, Reg::T0, c_type)}
+                self.out.push(Inst::Store(Width::Word, Reg::T0, Reg::Sp, *offset));
+            }
+        }
+        for (index, (arg, place)) in args.iter().zip(&places).enumerate() {
+            let c_type = external.and_then(|external| external.parameters.get(index)).map(|(_, ty)| ty);
+            for (word, part) in place.iter().enumerate() {
+                let ArgPlace::Reg(reg) = part else {continue};
+                self.read_into(arg, word, *reg);
+                if place.len() == 1 {narrow(self.out, *reg, c_type)}
+            }
+        }
+        match external {
+            Some(external) => self.out.push(Inst::Call(external.symbol.clone())),
+            None => self.out.push(Inst::Call(symbol(target))),
+        }
+        let Some(dest) = dest else {return};
+        if let Some(external) = external {
+            match &external.return_type {
+                CType::Int { bits: 64, .. } => {}
+                CType::Int { bits, signed } => {
+                    narrow(self.out, Reg::A0, Some(&CType::Int { bits: *bits, signed: *signed }));
+                    self.out.push(if *signed {Inst::OpImm(Op::Sra, Reg::A1, Reg::A0, 31)} else {mv(Reg::A1, Reg::Zero)});
+                }
+                // Addresses are unsigned
+                _ => self.out.push(mv(Reg::A1, Reg::Zero)),
+            }
+        }
+        self.write(dest, 0, Reg::A0);
+        self.write(dest, 1, Reg::A1);
+    }
+
+    /// Inline assembly: inputs go into free registers (their low word, registers are 32 bits
+    /// here), outputs get their own ones and are sign extended into their variable afterwards.
+    /// Clobbered callee-saved registers get saved around it, caller-saved ones are simply not
+    /// used (nothing lives in them across it, it counts as a call).
+    fn asm(&mut self, template: &str, inputs: &[(String, Operand)], outputs: &[(String, usize)], clobbers: &[String]) -> Result<(), String> {
+        let mut free = ASM_REGISTERS.to_vec();
+        let mut saved = vec![];
+        for clobber in clobbers {
+            if clobber == "cc" || clobber == "memory" {continue}
+            match Reg::from_name(clobber) {
+                Some(reg @ (Reg::Sp | Reg::S0 | Reg::Zero)) => return Err(format!("Inline assembly can't clobber {}, the stack frame lives in it", reg.name())),
+                Some(reg) if ASM_REGISTERS.contains(&reg) => free.retain(|free| *free != reg),
+                Some(Reg::Ra | Reg::T3 | Reg::T4 | Reg::T5 | Reg::T6) => {}
+                Some(reg) => if !saved.contains(&reg) {saved.push(reg)},
+                None => return Err(format!("Unknown register '{}' in the clobber list of inline assembly", clobber)),
+            }
+        }
+        if inputs.len() + outputs.len() > free.len() {
+            return Err(format!("Inline assembly has {} operands, but only {} registers are left for them", inputs.len() + outputs.len(), free.len()));
+        }
+
+        let save_size = (4 * saved.len() as i32 + 15) / 16 * 16;
+        if save_size > 0 {self.out.push(Inst::OpImm(Op::Add, Reg::Sp, Reg::Sp, -save_size))}
+        for (index, reg) in saved.iter().enumerate() {
+            self.out.push(Inst::Store(Width::Word, *reg, Reg::Sp, 4 * index as i32));
+        }
+        let mut operands = vec![];
+        for ((name, value), reg) in inputs.iter().zip(&free) {
+            self.read_into(value, 0, *reg);
+            operands.push((name.clone(), *reg));
+        }
+        let output_registers = free[inputs.len()..].to_vec();
+        for ((name, _), reg) in outputs.iter().zip(&output_registers) {
+            operands.push((name.clone(), *reg));
+        }
+        self.out.push(Inst::Asm { template: template.to_string(), operands });
+        for ((_, local), reg) in outputs.iter().zip(&output_registers) {
+            let offset = self.frame.locals[*local];
+            self.store(*reg, offset);
+            self.out.push(Inst::OpImm(Op::Sra, *reg, *reg, 31));
+            self.store(*reg, offset + 4);
+        }
+        for (index, reg) in saved.iter().enumerate() {
+            self.out.push(Inst::Load(Width::Word, *reg, Reg::Sp, 4 * index as i32));
+        }
+        if save_size > 0 {self.out.push(Inst::OpImm(Op::Add, Reg::Sp, Reg::Sp, save_size))}
+        Ok(())
+    }
+
+    fn prologue(&mut self) {
+        let size = self.frame.size;
+        self.out.push(mv(Reg::T6, Reg::Sp));
+        if fits_12(-size) {
+            self.out.push(Inst::OpImm(Op::Add, Reg::Sp, Reg::Sp, -size));
+        } else {
+            self.out.push(Inst::Li(Reg::T0, size));
+            self.out.push(Inst::Op(Op::Sub, Reg::Sp, Reg::Sp, Reg::T0));
+        }
+        self.out.extend([
+            Inst::Store(Width::Word, Reg::Ra, Reg::T6, -4),
+            Inst::Store(Width::Word, Reg::S0, Reg::T6, -8),
+            mv(Reg::S0, Reg::T6),
+        ]);
+        for (index, reg) in self.frame.saved.clone().iter().enumerate() {
+            self.out.push(Inst::Store(Width::Word, *reg, Reg::S0, -12 - 4 * index as i32));
+        }
+
+        // Parameters into their locals, the ones on the stack are at the top of the caller's frame
+        let parameters: Vec<(usize, bool)> = self.function.locals[..self.function.parameters].iter().map(|local| (words_32(local.ty), false)).collect();
+        let (places, _) = assign_arguments(&parameters);
+        for (local, place) in places.iter().enumerate() {
+            for (word, place) in place.iter().enumerate() {
+                let offset = self.frame.locals[local] + 4 * word as i32;
+                let reg = match place {
+                    ArgPlace::Reg(reg) => *reg,
+                    ArgPlace::Stack(stack) => {
+                        self.load(Reg::T0, *stack);
+                        Reg::T0
+                    }
+                };
+                self.store(reg, offset);
+            }
+        }
+    }
+
+    fn epilogue(&mut self) {
+        for (index, reg) in self.frame.saved.clone().iter().enumerate() {
+            self.out.push(Inst::Load(Width::Word, *reg, Reg::S0, -12 - 4 * index as i32));
+        }
+        // Everything gets read before sp moves back up, an interrupt could land in between
+        self.out.extend([
+            mv(Reg::T6, Reg::S0),
+            Inst::Load(Width::Word, Reg::Ra, Reg::T6, -4),
+            Inst::Load(Width::Word, Reg::S0, Reg::T6, -8),
+            mv(Reg::Sp, Reg::T6),
+            Inst::Ret,
+        ]);
+    }
+
+    fn generate(&mut self) -> Result<(), String> {
+        let function = self.function;
+        self.out.push(Inst::Label(symbol(&function.name)));
+        self.prologue();
+
+        for (index, block) in function.blocks.iter().enumerate() {
+            self.out.push(Inst::Label(block_label(function, index)));
+            for instruction in &block.instructions {
+                match instruction {
+                    Instruction::Copy { dest, value } => {
+                        for word in 0..words_32(function.registers[*dest]) {
+                            let reg = self.read(value, word, Reg::A0);
+                            self.write(*dest, word, reg);
+                        }
+                    }
+                    Instruction::StringAddress { dest, index } => {
+                        let reg = self.target(*dest, 0, Reg::A0);
+                        self.out.push(Inst::La(reg, string_label(*index)));
+                        self.write(*dest, 0, reg);
+                    }
+                    Instruction::Binary { dest, op, left, right } => self.binary(*dest, *op, left, right),
+                    Instruction::Load { dest, local } => {
+                        for word in 0..self.local_words(*local) {
+                            let reg = self.target(*dest, word, Reg::A0);
+                            let offset = self.frame.locals[*local] + 4 * word as i32;
+                            self.load(reg, offset);
+                            self.write(*dest, word, reg);
+                        }
+                    }
+                    Instruction::Store { local, value } => {
+                        for word in 0..self.local_words(*local) {
+                            let reg = self.read(value, word, Reg::A0);
+                            let offset = self.frame.locals[*local] + 4 * word as i32;
+                            self.store(reg, offset);
+                        }
+                    }
+                    Instruction::Call { dest, function: target, args } => {
+                        if self.module.uses_c() && ["print", "__sys_write", "__sys_write_str"].contains(&target.as_str()) {
+                            // fflush(NULL), so whatever C code printed comes out first
+                            self.out.push(Inst::Li(Reg::A0, 0));
+                            self.out.push(Inst::Call("fflush".to_string()));
+                        }
+                        if target == "print" {self.print(args)}
+                        else {self.call(target, args, *dest, self.module.external(target))}
+                    }
+                    Instruction::Asm { template, inputs, outputs, clobbers, .. } => {
+                        self.asm(template, inputs, outputs, clobbers)
+                            .map_err(|error| format!("{} (in function '{}')", error, function.name))?;
+                    }
+                    // Only the x86 backend writes debug info
+                    Instruction::Line { .. } => {}
+                }
+            }
+            let next = index + 1;
+            match &block.terminator {
+                Terminator::Jump(target) => if *target != next {self.out.push(Inst::Jump(block_label(function, *target)))},
+                Terminator::Branch { condition: Operand::Constant(value), then_block, else_block } => {
+                    let target = if *value != 0 {then_block} else {else_block};
+                    if *target != next {self.out.push(Inst::Jump(block_label(function, *target)))}
+                }
+                Terminator::Branch { condition, then_block, else_block } => {
+                    let low = self.read(condition, 0, Reg::T0);
+                    let high = self.read(condition, 1, Reg::T1);
+                    let value = if high == Reg::Zero {low} else {
+                        self.out.push(Inst::Op(Op::Or, Reg::T0, low, high));
+                        Reg::T0
+                    };
+                    if *then_block == next {
+                        self.out.push(Inst::Branch(Cond::Eq, value, Reg::Zero, block_label(function, *else_block)));
+                    } else {
+                        self.out.push(Inst::Branch(Cond::Ne, value, Reg::Zero, block_label(function, *then_block)));
+                        if *else_block != next {self.out.push(Inst::Jump(block_label(function, *else_block)))}
+                    }
+                }
+                Terminator::Return(value) => {
+                    if let Some(value) = value {
+                        for (word, reg) in ARGUMENT_REGISTERS.iter().enumerate().take(words_32(function.return_type)) {
+                            self.read_into(value, word, *reg);
+                        }
+                    }
+                    self.epilogue();
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Cuts the register down to the C type it's passed as (sign or zero extending the rest of
+/// the 32 bits), the callee can count on that
+fn narrow(out: &mut Vec<Inst>, reg: Reg, ty: Option<&CType>) {
+    match narrow_int(ty, 32) {
+        Some((8, false)) => out.push(Inst::OpImm(Op::And, reg, reg, 0xff)),
+        Some((bits, signed)) => out.extend([
+            Inst::OpImm(Op::Sll, reg, reg, 32 - bits as i32),
+            Inst::OpImm(if signed {Op::Sra} else {Op::Srl}, reg, reg, 32 - bits as i32),
+        ]),
+        None => {}
+    }
+}
+
+fn generate_function(out: &mut Vec<Inst>, function: &Function, module: &Module) -> Result<(), String> {
+    let allocation = regalloc::allocate(function, module, &REGISTERS);
+    let outgoing = function.blocks.iter().flat_map(|block| &block.instructions).map(|instruction| match instruction {
+        Instruction::Call { function: target, args, .. } if target != "print" => {
+            assign_arguments(&call_arguments(function, args, module.external(target))).1
+        }
+        _ => 0,
+    }).max().unwrap_or(0);
+    let frame = Frame::new(function, &allocation, outgoing);
+    Generator { out, function, module, homes: allocation.homes, frame }.generate()
+}
+
+/// Process entry point, runs the top level and exits with whatever it returned
+fn generate_start(out: &mut Vec<Inst>, entry: &str) {
+    out.extend([
+        Inst::Label(entry.to_string()),
+        Inst::Call(symbol(ENTRY_FUNCTION)),
+        Inst::Li(Reg::A7, SYS_EXIT),
+        Inst::Ecall,
+    ]);
+}
+
+/// Bare metal entry point, the stack pointer comes from the linker script's STACK_TOP_SYMBOL
+/// and the end is a hang
+fn generate_freestanding_start(out: &mut Vec<Inst>, entry: &str) {
+    let hang = format!(".L{}_hang", entry);
+    out.extend([
+        Inst::Label(entry.to_string()),
+        Inst::La(Reg::Sp, STACK_TOP_SYMBOL.to_string()),
+        Inst::Call(symbol(ENTRY_FUNCTION)),
+        Inst::Label(hang.clone()),
+        Inst::Jump(hang),
+    ]);
+}
+
+/// Registers an interrupt wrapper saves, the caller-saved ones (the Galvan function takes
+/// care of the rest). 16 of them keep sp 16-byte aligned.
+const INTERRUPT_SAVED: [Reg; 16] = [Reg::Ra, Reg::T0, Reg::T1, Reg::T2, Reg::A0, Reg::A1, Reg::A2, Reg::A3,
+    Reg::A4, Reg::A5, Reg::A6, Reg::A7, Reg::T3, Reg::T4, Reg::T5, Reg::T6];
+
+/// `#[interrupt]` wrapper under the function's own name: saves the caller-saved registers,
+/// calls the Galvan function and returns with mret
+fn generate_interrupt(out: &mut Vec<Inst>, function: &Function) {
+    let size = 4 * INTERRUPT_SAVED.len() as i32;
+    out.push(Inst::Label(function.name.clone()));
+    out.push(Inst::OpImm(Op::Add, Reg::Sp, Reg::Sp, -size));
+    out.extend(INTERRUPT_SAVED.iter().enumerate().map(|(index, reg)| Inst::Store(Width::Word, *reg, Reg::Sp, 4 * index as i32)));
+    out.push(Inst::Call(symbol(&function.name)));
+    out.extend(INTERRUPT_SAVED.iter().enumerate().map(|(index, reg)| Inst::Load(Width::Word, *reg, Reg::Sp, 4 * index as i32)));
+    out.push(Inst::OpImm(Op::Add, Reg::Sp, Reg::Sp, size));
+    out.push(Inst::Mret);
+}
+
+/// `rd = -rd` for the pair (low, high)
+fn negate(low: Reg, high: Reg) -> [Inst; 4] {
+    [
+        Inst::Op(Op::Sltu, Reg::T6, Reg::Zero, low), // borrow out of the low word
+        Inst::Op(Op::Sub, low, Reg::Zero, low),
+        Inst::Op(Op::Sub, high, Reg::Zero, high),
+        Inst::Op(Op::Sub, high, high, Reg::T6),
+    ]
+}
+
+/// Strlen of the string at `start` into `length`, with `cursor` and t0 as scratch
+fn strlen(out: &mut Vec<Inst>, start: Reg, cursor: Reg, length: Reg, name: &str) {
+    let (repeat, done) = (format!(".L{}_loop", name), format!(".L{}_done", name));
+    out.extend([
+        mv(cursor, start),
+        Inst::Label(repeat.clone()),
+        Inst::Load(Width::ByteUnsigned, Reg::T0, cursor, 0),
+        Inst::Branch(Cond::Eq, Reg::T0, Reg::Zero, done.clone()),
+        Inst::OpImm(Op::Add, cursor, cursor, 1),
+        Inst::Jump(repeat),
+        Inst::Label(done),
+        Inst::Op(Op::Sub, length, cursor, start),
+    ]);
+}
+
+/// 64-bit division, shift-subtract one bit at a time. Only used with divisors up to 2^63, so
+/// the remainder always fits 64 bits after the shift.
+fn generate_division(out: &mut Vec<Inst>) {
+    let label = |name: &str| name.to_string();
+    let (a0, a1, a2, a3) = (Reg::A0, Reg::A1, Reg::A2, Reg::A3);
+    let (t0, t1, t2, t3) = (Reg::T0, Reg::T1, Reg::T2, Reg::T3);
+    // __gv_udivmod64: the quotient builds up in a0:a1 as the dividend shifts out of it,
+    // the remainder in t0:t1
+    out.extend([
+        Inst::Label(label(UDIVMOD64)),
+        Inst::Li(t0, 0),
+        Inst::Li(t1, 0),
+        Inst::Li(t2, 64),
+        Inst::Label(label(".Ludivmod_loop")),
+        Inst::OpImm(Op::Sll, t1, t1, 1),
+        Inst::OpImm(Op::Srl, t3, t0, 31),
+        Inst::Op(Op::Or, t1, t1, t3),
+        Inst::OpImm(Op::Sll, t0, t0, 1),
+        Inst::OpImm(Op::Srl, t3, a1, 31),
+        Inst::Op(Op::Or, t0, t0, t3),
+        Inst::OpImm(Op::Sll, a1, a1, 1),
+        Inst::OpImm(Op::Srl, t3, a0, 31),
+        Inst::Op(Op::Or, a1, a1, t3),
+        Inst::OpImm(Op::Sll, a0, a0, 1),
+        // remainder >= divisor?
+        Inst::Branch(Cond::Ltu, t1, a3, label(".Ludivmod_next")),
+        Inst::Branch(Cond::Ne, t1, a3, label(".Ludivmod_subtract")),
+        Inst::Branch(Cond::Ltu, t0, a2, label(".Ludivmod_next")),
+        Inst::Label(label(".Ludivmod_subtract")),
+        Inst::Op(Op::Sltu, t3, t0, a2),
+        Inst::Op(Op::Sub, t0, t0, a2),
+        Inst::Op(Op::Sub, t1, t1, a3),
+        Inst::Op(Op::Sub, t1, t1, t3),
+        Inst::OpImm(Op::Or, a0, a0, 1),
+        Inst::Label(label(".Ludivmod_next")),
+        Inst::OpImm(Op::Add, t2, t2, -1),
+        Inst::Branch(Cond::Ne, t2, Reg::Zero, label(".Ludivmod_loop")),
+        mv(a2, t0),
+        mv(a3, t1),
+        Inst::Ret,
+    ]);
+
+    // __gv_div64: signed on top of the unsigned one, rounds towards zero like C. Dividing by
+    // zero traps, like it does on x86.
+    out.extend([
+        Inst::Label(label(DIV64)),
+        Inst::Op(Op::Or, t0, a2, a3),
+        Inst::Branch(Cond::Ne, t0, Reg::Zero, label(".Ldiv64_divisor")),
+        Inst::Ebreak,
+        Inst::Label(label(".Ldiv64_divisor")),
+        Inst::OpImm(Op::Add, Reg::Sp, Reg::Sp, -16),
+        Inst::Store(Width::Word, Reg::Ra, Reg::Sp, 12),
+        Inst::Op(Op::Xor, t0, a1, a3),
+        Inst::OpImm(Op::Sra, t0, t0, 31), // -1 if the result is negative
+        Inst::Store(Width::Word, t0, Reg::Sp, 8),
+        Inst::Branch(Cond::Ge, a1, Reg::Zero, label(".Ldiv64_dividend")),
+    ]);
+    out.extend(negate(a0, a1));
+    out.extend([
+        Inst::Label(label(".Ldiv64_dividend")),
+        Inst::Branch(Cond::Ge, a3, Reg::Zero, label(".Ldiv64_divide")),
+    ]);
+    out.extend(negate(a2, a3));
+    out.extend([
+        Inst::Label(label(".Ldiv64_divide")),
+        Inst::Call(label(UDIVMOD64)),
+        Inst::Load(Width::Word, t0, Reg::Sp, 8),
+        Inst::Branch(Cond::Eq, t0, Reg::Zero, label(".Ldiv64_done")),
+    ]);
+    out.extend(negate(a0, a1));
+    out.extend([
+        Inst::Label(label(".Ldiv64_done")),
+        Inst::Load(Width::Word, Reg::Ra, Reg::Sp, 12),
+        Inst::OpImm(Op::Add, Reg::Sp, Reg::Sp, 16),
+        Inst::Ret,
+    ]);
+}
+
+/// Tiny runtime for `print`, all of it straight syscalls so nothing needs to be linked in
+fn generate_runtime(out: &mut Vec<Inst>) {
+    let label = |name: &str| name.to_string();
+    let write = [Inst::Li(Reg::A7, SYS_WRITE), Inst::Ecall];
+
+    // __gv_print_char(a0: char)
+    out.extend([
+        Inst::Label(label(PRINT_CHAR)),
+        Inst::OpImm(Op::Add, Reg::Sp, Reg::Sp, -16),
+        Inst::Store(Width::Byte, Reg::A0, Reg::Sp, 0),
+        Inst::Li(Reg::A0, 1),
+        mv(Reg::A1, Reg::Sp),
+        Inst::Li(Reg::A2, 1),
+    ]);
+    out.extend(write.clone());
+    out.extend([
+        Inst::OpImm(Op::Add, Reg::Sp, Reg::Sp, 16),
+        Inst::Ret,
+    ]);
+
+    // __gv_print_str(a0: NUL terminated string)
+    out.push(Inst::Label(label(PRINT_STR)));
+    out.push(mv(Reg::A1, Reg::A0));
+    strlen(out, Reg::A1, Reg::A2, Reg::A2, "print_str");
+    out.push(Inst::Li(Reg::A0, 1));
+    out.extend(write.clone());
+    out.push(Inst::Ret);
+
+    // __gv_print_int(a0:a1: i64), digits get written backwards into a buffer on the stack,
+    // the magnitude as unsigned so the most negative number works too
+    out.extend([
+        Inst::Label(label(PRINT_INT)),
+        Inst::OpImm(Op::Add, Reg::Sp, Reg::Sp, -48),
+        Inst::Store(Width::Word, Reg::Ra, Reg::Sp, 44),
+        Inst::Store(Width::Word, Reg::S1, Reg::Sp, 40),
+        Inst::Store(Width::Word, Reg::S2, Reg::Sp, 36),
+        Inst::OpImm(Op::Add, Reg::S1, Reg::Sp, 31),
+        Inst::OpImm(Op::Sra, Reg::S2, Reg::A1, 31), // negative?
+        Inst::Branch(Cond::Eq, Reg::S2, Reg::Zero, label(".Lprint_int_loop")),
+    ]);
+    out.extend(negate(Reg::A0, Reg::A1));
+    out.extend([
+        Inst::Label(label(".Lprint_int_loop")),
+        Inst::Li(Reg::A2, 10),
+        Inst::Li(Reg::A3, 0),
+        Inst::Call(label(UDIVMOD64)),
+        Inst::OpImm(Op::Add, Reg::A2, Reg::A2, b'0' as i32),
+        Inst::Store(Width::Byte, Reg::A2, Reg::S1, 0),
+        Inst::OpImm(Op::Add, Reg::S1, Reg::S1, -1),
+        Inst::Op(Op::Or, Reg::T0, Reg::A0, Reg::A1),
+        Inst::Branch(Cond::Ne, Reg::T0, Reg::Zero, label(".Lprint_int_loop")),
+        Inst::Branch(Cond::Eq, Reg::S2, Reg::Zero, label(".Lprint_int_write")),
+        Inst::Li(Reg::T0, b'-' as i32),
+        Inst::Store(Width::Byte, Reg::T0, Reg::S1, 0),
+        Inst::OpImm(Op::Add, Reg::S1, Reg::S1, -1),
+        Inst::Label(label(".Lprint_int_write")),
+        Inst::OpImm(Op::Add, Reg::A1, Reg::S1, 1),
+        Inst::OpImm(Op::Add, Reg::A2, Reg::Sp, 32),
+        Inst::Op(Op::Sub, Reg::A2, Reg::A2, Reg::A1),
+        Inst::Li(Reg::A0, 1),
+    ]);
+    out.extend(write);
+    out.extend([
+        Inst::Load(Width::Word, Reg::Ra, Reg::Sp, 44),
+        Inst::Load(Width::Word, Reg::S1, Reg::Sp, 40),
+        Inst::Load(Width::Word, Reg::S2, Reg::Sp, 36),
+        Inst::OpImm(Op::Add, Reg::Sp, Reg::Sp, 48),
+        Inst::Ret,
+    ]);
+}
+
+/// Helper functions for the intrinsics (see intrinsics.rs) the module calls. They're called
+/// like any Galvan function, so an i64 argument is a register pair.
+fn generate_intrinsics(out: &mut Vec<Inst>, module: &Module) {
+    // Syscalls give back an i32, -errno on failure
+    let syscall = |number: i32| [Inst::Li(Reg::A7, number), Inst::Ecall, Inst::OpImm(Op::Sra, Reg::A1, Reg::A0, 31), Inst::Ret];
+    for intrinsic in INTRINSICS {
+        let used = module.functions.iter().flat_map(|function| &function.blocks).flat_map(|block| &block.instructions)
+            .any(|instruction| matches!(instruction, Instruction::Call { function, .. } if function == intrinsic.name));
+        if !used {continue}
+
+        out.push(Inst::Label(symbol(intrinsic.name)));
+        match intrinsic.name {
+            // Linked with libc, so leave the heap to calloc and exit through exit()
+            "__alloc" if module.uses_c() => out.extend([
+                Inst::OpImm(Op::Add, Reg::Sp, Reg::Sp, -16),
+                Inst::Store(Width::Word, Reg::Ra, Reg::Sp, 12),
+                Inst::Li(Reg::A1, 1),
+                Inst::Call("calloc".to_string()),
+                Inst::Li(Reg::A1, 0),
+                Inst::Load(Width::Word, Reg::Ra, Reg::Sp, 12),
+                Inst::OpImm(Op::Add, Reg::Sp, Reg::Sp, 16),
+                Inst::Ret,
+            ]),
+            "__sys_exit" if module.uses_c() => out.push(Inst::Call("exit".to_string())),
+            // Moves the program break up, rounded to 8 bytes. Fresh memory from brk is zeroed.
+            "__alloc" => out.extend([
+                Inst::OpImm(Op::Add, Reg::T1, Reg::A0, 7),
+                Inst::OpImm(Op::And, Reg::T1, Reg::T1, -8),
+                Inst::Li(Reg::A0, 0),
+                Inst::Li(Reg::A7, SYS_BRK), // brk(0), the current break
+                Inst::Ecall,
+                mv(Reg::T2, Reg::A0),
+                Inst::Op(Op::Add, Reg::A0, Reg::A0, Reg::T1),
+                mv(Reg::T1, Reg::A0),
+                Inst::Li(Reg::A7, SYS_BRK),
+                Inst::Ecall,
+                Inst::Branch(Cond::Ne, Reg::A0, Reg::T1, ".Lalloc_failed".to_string()),
+                mv(Reg::A0, Reg::T2),
+                Inst::Li(Reg::A1, 0),
+                Inst::Ret,
+                Inst::Label(".Lalloc_failed".to_string()),
+                Inst::Li(Reg::A0, -12), // ENOMEM
+                Inst::Li(Reg::A1, -1),
+                Inst::Ret,
+            ]),
+            "__load8" => out.extend([
+                Inst::Load(Width::ByteUnsigned, Reg::A0, Reg::A0, 0),
+                Inst::Li(Reg::A1, 0),
+                Inst::Ret,
+            ]),
+            "__store8" => out.extend([
+                Inst::Store(Width::Byte, Reg::A2, Reg::A0, 0),
+                Inst::Ret,
+            ]),
+            // (a0: str, a1:a2: index)
+            "__str_byte" => out.extend([
+                Inst::Op(Op::Add, Reg::A0, Reg::A0, Reg::A1),
+                Inst::Load(Width::ByteUnsigned, Reg::A0, Reg::A0, 0),
+                Inst::Li(Reg::A1, 0),
+                Inst::Ret,
+            ]),
+            // Strings are addresses already
+            "__str_at" => out.push(Inst::Ret),
+            // (a0:a1, a2:a3, a4:a5) into (a0, a1, a2)
+            "__sys_read" | "__sys_write" => {
+                out.extend([mv(Reg::A1, Reg::A2), mv(Reg::A2, Reg::A4)]);
+                out.extend(syscall(if intrinsic.name == "__sys_read" {SYS_READ} else {SYS_WRITE}));
+            }
+            // (a0:a1: fd, a2: string)
+            "__sys_write_str" => {
+                out.push(mv(Reg::A1, Reg::A2));
+                strlen(out, Reg::A1, Reg::A3, Reg::A2, "write_str");
+                out.extend(syscall(SYS_WRITE));
+            }
+            // (a0: path, a1:a2: flags, a3:a4: mode), there's only openat on RV32
+            "__sys_open" => {
+                out.extend([mv(Reg::A2, Reg::A1), mv(Reg::A1, Reg::A0), Inst::Li(Reg::A0, AT_FDCWD)]);
+                out.extend(syscall(SYS_OPENAT));
+            }
+            "__sys_close" => out.extend(syscall(SYS_CLOSE)),
+            "__sys_exit" => out.extend(syscall(SYS_EXIT)),
+            name => unreachable!("intrinsic '{}' has no RISC-V version", name),
+        }
+    }
+}
+
+/// Generates the whole program for a module
+pub fn generate(module: &Module) -> Result<Program, String> {
+    if debug_prints(RISCV_DEBUG_PRINTS) {eprintln!("- - - RISC-V")}
+
+    let mut text = vec![];
+    let mut globals = vec![];
+    let mut sections: Vec<(String, Vec<Inst>)> = vec![];
+    let entry = module.entry_symbol.clone().unwrap_or(START_SYMBOL.to_string());
+    // libc's _start calls main, so a program linked with it starts right in the entry function
+    let c_entry = module.uses_c() && !module.freestanding;
+    match module.entry() {
+        Some(_) if c_entry => globals.push(C_ENTRY_SYMBOL.to_string()),
+        Some(_) if module.freestanding => {
+            generate_freestanding_start(&mut text, &entry);
+            globals.push(entry.clone());
+        }
+        Some(_) => {
+            generate_start(&mut text, &entry);
+            globals.push(entry.clone());
+        }
+        None => {}
+    }
+    for function in &module.functions {
+        let out = match &function.attributes.section {
+            Some(section) if section != ".text" => match sections.iter().position(|(name, _)| name == section) {
+                Some(index) => &mut sections[index].1,
+                None => {
+                    sections.push((section.clone(), vec![]));
+                    &mut sections.last_mut().unwrap().1
+                }
+            },
+            _ => &mut text,
+        };
+        if function.attributes.interrupt {
+            generate_interrupt(out, function);
+            globals.push(function.name.clone());
+        }
+        if function.name == ENTRY_FUNCTION && c_entry {out.push(Inst::Label(C_ENTRY_SYMBOL.to_string()))}
+        if let Some(export) = &function.export {
+            out.push(Inst::Label(export.clone()));
+            globals.push(export.clone());
+        }
+        generate_function(out, function, module)?;
+    }
+    // Nothing to print to without an OS, seman already made sure nothing does
+    if !module.freestanding {generate_runtime(&mut text)}
+    let divides = module.functions.iter().flat_map(|function| &function.blocks).flat_map(|block| &block.instructions)
+        .any(|instruction| matches!(instruction, Instruction::Binary { op: BinaryOp::Div, .. }));
+    if divides || !module.freestanding {generate_division(&mut text)}
+    generate_intrinsics(&mut text, module);
+
+    let rodata = module.strings.iter().enumerate().map(|(index, string)| {
+        let mut bytes = string.as_bytes().to_vec();
+        bytes.push(0);
+        (string_label(index), bytes)
+    }).collect();
+
+    if debug_prints(RISCV_DEBUG_PRINTS) {eprintln!("- - - RISC-V done!")}
+    Ok(Program { text, rodata, globals, sections, entry })
+}
+
+//
+// PRINTING
+//
+
+/// Instruction as a line of assembly, without the indentation. The usual pseudoinstructions
+/// (mv, seqz, snez, neg, not, beqz, bnez) get printed as such.
+pub fn print_inst(inst: &Inst) -> String {
+    match inst {
+        Inst::Label(label) => format!("{}:", label),
+        Inst::Li(rd, imm) => format!("li {}, {}", rd.name(), imm),
+        Inst::La(rd, label) => format!("la {}, {}", rd.name(), label),
+        Inst::OpImm(Op::Add, rd, rs, 0) => format!("mv {}, {}", rd.name(), rs.name()),
+        Inst::OpImm(Op::Sltu, rd, rs, 1) => format!("seqz {}, {}", rd.name(), rs.name()),
+        Inst::OpImm(Op::Xor, rd, rs, -1) => format!("not {}, {}", rd.name(), rs.name()),
+        Inst::Op(Op::Sltu, rd, Reg::Zero, rs) => format!("snez {}, {}", rd.name(), rs.name()),
+        Inst::Op(Op::Sub, rd, Reg::Zero, rs) => format!("neg {}, {}", rd.name(), rs.name()),
+        Inst::Op(op, rd, rs1, rs2) => format!("{} {}, {}, {}", op.name(), rd.name(), rs1.name(), rs2.name()),
+        Inst::OpImm(op, rd, rs, imm) => format!("{} {}, {}, {}", op.imm_name().unwrap_or(op.name()), rd.name(), rs.name(), imm),
+        Inst::Load(width, rd, base, offset) => format!("{} {}, {}({})", width.load_name(), rd.name(), offset, base.name()),
+        Inst::Store(width, rs, base, offset) => format!("{} {}, {}({})", width.store_name(), rs.name(), offset, base.name()),
+        Inst::Branch(cond @ (Cond::Eq | Cond::Ne), rs, Reg::Zero, label) => format!("b{}z {}, {}", cond.name(), rs.name(), label),
+        Inst::Branch(cond, rs1, rs2, label) => format!("b{} {}, {}, {}", cond.name(), rs1.name(), rs2.name(), label),
+        Inst::Jump(label) => format!("j {}", label),
+        Inst::Call(label) => format!("call {}", label),
+        Inst::Ret => "ret".to_string(),
+        Inst::Ecall => "ecall".to_string(),
+        Inst::Ebreak => "ebreak".to_string(),
+        Inst::Mret => "mret".to_string(),
+        Inst::Asm { template, operands } => {
+            let expanded = expand_asm(template, &mut |name| Ok(match operands.iter().find(|(operand, _)| operand == name) {
+                Some((_, reg)) => reg.name().to_string(),
+                None => format!("{{{}}}", name),
+            }));
+            // Already checked by seman or the IR verifier, so this can't really fail
+            let expanded = expanded.unwrap_or(template.clone());
+            expanded.lines().map(str::trim).collect::<Vec<&str>>().join("\n    ")
+        }
+    }
+}
+
+impl Program {
+    /// The program as GNU assembler source
+    pub fn to_assembly(&self) -> String {
+        let mut out = String::new();
+        // The compressed forms are up to the assembler. No relaxation, so nothing ends up
+        // relative to a gp that nobody set up.
+        out.push_str(".attribute arch, \"rv32i2p0_m2p0_c2p0\"\n.option rvc\n.option norelax\n");
+        for global in &self.globals {
+            out.push_str(&format!(".globl {}\n", global));
+        }
+
+        rodata_assembly(&mut out, &self.rodata);
+
+        let sections = std::iter::once((".text".to_string(), &self.text))
+            .chain(self.sections.iter().map(|(name, text)| (format!(".section {},\"ax\",@progbits", name), text)));
+        for (directive, text) in sections {
+            out.push_str(&format!("\n{}\n    .p2align 1\n", directive));
+            for inst in text {
+                match inst {
+                    Inst::Label(label) => {
+                        if !label.starts_with(".L") {out.push('\n')}
+                        out.push_str(&format!("{}:\n", label));
+                    }
+                    inst => out.push_str(&format!("    {}\n", print_inst(inst))),
+                }
+            }
+        }
+        // The stack doesn't need to be executable, ld warns when linking without this
+        out.push_str("\n.section .note.GNU-stack,\"\",@progbits\n");
+        out
+    }
+}
@@ -0,0 +1,350 @@
+use std::collections::HashMap;
+
+use crate::backend::Image;
+use crate::riscv::{fits_12, Cond, Inst, Op, Program, Reg, Width};
+
+// Turns the RISC-V backend's instructions into RV32IMC machine code, so programs can run in
+// riscv_sim.rs without an assembler. Same job as x86_encoder.rs, but there's no object file
+// or linker behind it: everything gets laid out at a fixed address in one go (code, then
+// every custom section, then the strings).
+// Like the assembler, it picks the 16-bit compressed form of an instruction whenever one
+// fits. Jumps, calls and branches depend on how far their target is, so those start out as
+// small as possible and grow until everything fits.
+// The instruction format helpers are shared with the simulator, which decodes compressed
+// instructions by expanding them back into these.
+
+//
+// INSTRUCTION FORMATS
+//
+
+pub const OPCODE_LOAD: u32 = 0x03;
+pub const OPCODE_OP_IMM: u32 = 0x13;
+pub const OPCODE_AUIPC: u32 = 0x17;
+pub const OPCODE_STORE: u32 = 0x23;
+pub const OPCODE_OP: u32 = 0x33;
+pub const OPCODE_LUI: u32 = 0x37;
+pub const OPCODE_BRANCH: u32 = 0x63;
+pub const OPCODE_JALR: u32 = 0x67;
+pub const OPCODE_JAL: u32 = 0x6f;
+pub const OPCODE_SYSTEM: u32 = 0x73;
+
+pub const ECALL: u32 = 0x0000_0073;
+pub const EBREAK: u32 = 0x0010_0073;
+pub const MRET: u32 = 0x3020_0073;
+
+pub fn r_type(funct7: u32, rs2: u32, rs1: u32, funct3: u32, rd: u32, opcode: u32) -> u32 {
+    funct7 << 25 | rs2 << 20 | rs1 << 15 | funct3 << 12 | rd << 7 | opcode
+}
+
+pub fn i_type(imm: i32, rs1: u32, funct3: u32, rd: u32, opcode: u32) -> u32 {
+    ((imm as u32) & 0xfff) << 20 | rs1 << 15 | funct3 << 12 | rd << 7 | opcode
+}
+
+pub fn s_type(imm: i32, rs2: u32, rs1: u32, funct3: u32) -> u32 {
+    let imm = imm as u32;
+    (imm >> 5 & 0x7f) << 25 | rs2 << 20 | rs1 << 15 | funct3 << 12 | (imm & 0x1f) << 7 | OPCODE_STORE
+}
+
+pub fn b_type(offset: i32, rs2: u32, rs1: u32, funct3: u32) -> u32 {
+    let offset = offset as u32;
+    (offset >> 12 & 1) << 31 | (offset >> 5 & 0x3f) << 25 | rs2 << 20 | rs1 << 15 | funct3 << 12
+        | (offset >> 1 & 0xf) << 8 | (offset >> 11 & 1) << 7 | OPCODE_BRANCH
+}
+
+pub fn u_type(imm20: i32, rd: u32, opcode: u32) -> u32 {
+    ((imm20 as u32) & 0xfffff) << 12 | rd << 7 | opcode
+}
+
+pub fn j_type(offset: i32, rd: u32) -> u32 {
+    let offset = offset as u32;
+    (offset >> 20 & 1) << 31 | (offset >> 1 & 0x3ff) << 21 | (offset >> 11 & 1) << 20 | (offset >> 12 & 0xff) << 12
+        | rd << 7 | OPCODE_JAL
+}
+
+/// (funct7, funct3) of a register-register operation
+pub fn op_functs(op: Op) -> (u32, u32) {
+    match op {
+        Op::Add => (0, 0), Op::Sub => (0x20, 0), Op::Sll => (0, 1), Op::Slt => (0, 2), Op::Sltu => (0, 3),
+        Op::Xor => (0, 4), Op::Srl => (0, 5), Op::Sra => (0x20, 5), Op::Or => (0, 6), Op::And => (0, 7),
+        Op::Mul => (1, 0), Op::Mulh => (1, 1), Op::Mulhsu => (1, 2), Op::Mulhu => (1, 3),
+        Op::Div => (1, 4), Op::Divu => (1, 5), Op::Rem => (1, 6), Op::Remu => (1, 7),
+    }
+}
+
+pub fn load_funct3(width: Width) -> u32 {
+    match width {Width::Byte => 0, Width::Half => 1, Width::Word => 2, Width::ByteUnsigned => 4, Width::HalfUnsigned => 5}
+}
+
+pub fn store_funct3(width: Width) -> u32 {
+    match width {Width::Byte | Width::ByteUnsigned => 0, Width::Half | Width::HalfUnsigned => 1, Width::Word => 2}
+}
+
+pub fn branch_funct3(cond: Cond) -> u32 {
+    match cond {Cond::Eq => 0, Cond::Ne => 1, Cond::Lt => 4, Cond::Ge => 5, Cond::Ltu => 6, Cond::Geu => 7}
+}
+
+/// Upper and lower part of a 32-bit value for lui/auipc + a 12-bit immediate (the lower
+/// part is signed, so the upper one rounds)
+pub fn split_immediate(value: i32) -> (i32, i32) {
+    let upper = value.wrapping_add(0x800) >> 12;
+    (upper, value.wrapping_sub(upper << 12))
+}
+
+/// Register number in the 3-bit fields of compressed instructions (x8-x15 only)
+fn compressed_reg(reg: Reg) -> Option<u16> {
+    (8..16).contains(&reg.number()).then(|| reg.number() as u16 - 8)
+}
+
+fn fits_6(value: i32) -> bool {
+    (-32..32).contains(&value)
+}
+
+/// Bits of a value, `bit(value, 5)` and so on, for the scrambled compressed immediates
+fn bit(value: i32, index: u32) -> u16 {
+    (value >> index & 1) as u16
+}
+
+/// Offset field of c.j and c.jal
+fn cj_offset(offset: i32) -> u16 {
+    bit(offset, 11) << 12 | bit(offset, 4) << 11 | ((offset >> 8 & 3) as u16) << 9 | bit(offset, 10) << 8
+        | bit(offset, 6) << 7 | bit(offset, 7) << 6 | ((offset >> 1 & 7) as u16) << 3 | bit(offset, 5) << 2
+}
+
+/// Offset field of c.beqz and c.bnez
+fn cb_offset(offset: i32) -> u16 {
+    bit(offset, 8) << 12 | ((offset >> 3 & 3) as u16) << 10 | ((offset >> 6 & 3) as u16) << 5
+        | ((offset >> 1 & 3) as u16) << 3 | bit(offset, 5) << 2
+}
+
+/// The 16-bit form of an instruction that doesn't depend on addresses, if it has one
+fn compress(inst: &Inst) -> Option<u16> {
+    let reg = |reg: &Reg| reg.number() as u16;
+    match inst {
+        Inst::Li(rd, imm) if *rd != Reg::Zero && fits_6(*imm) => Some(0x4001 | bit(*imm, 5) << 12 | reg(rd) << 7 | (*imm as u16 & 0x1f) << 2),
+        Inst::OpImm(Op::Add, rd, Reg::Zero, imm) => compress(&Inst::Li(*rd, *imm)),
+        // mv
+        Inst::OpImm(Op::Add, rd, rs, 0) if *rd != Reg::Zero && *rs != Reg::Zero => Some(0x8002 | reg(rd) << 7 | reg(rs) << 2),
+        // c.addi16sp
+        Inst::OpImm(Op::Add, Reg::Sp, Reg::Sp, imm) if *imm != 0 && imm % 16 == 0 && (-512..512).contains(imm) => {
+            Some(0x6101 | bit(*imm, 9) << 12 | bit(*imm, 4) << 6 | bit(*imm, 6) << 5 | ((*imm >> 7 & 3) as u16) << 3 | bit(*imm, 5) << 2)
+        }
+        Inst::OpImm(Op::Add, rd, rs, imm) if rd == rs && *rd != Reg::Zero && *imm != 0 && fits_6(*imm) => {
+            Some(0x0001 | bit(*imm, 5) << 12 | reg(rd) << 7 | (*imm as u16 & 0x1f) << 2)
+        }
+        // c.addi4spn
+        Inst::OpImm(Op::Add, rd, Reg::Sp, imm) if *imm > 0 && imm % 4 == 0 && *imm < 1024 => {
+            let rd = compressed_reg(*rd)?;
+            Some(((*imm >> 4 & 3) as u16) << 11 | ((*imm >> 6 & 0xf) as u16) << 7 | bit(*imm, 2) << 6 | bit(*imm, 3) << 5 | rd << 2)
+        }
+        Inst::OpImm(Op::Sll, rd, rs, shift) if rd == rs && *rd != Reg::Zero && (1..32).contains(shift) => {
+            Some(0x0002 | reg(rd) << 7 | (*shift as u16) << 2)
+        }
+        Inst::OpImm(op @ (Op::Srl | Op::Sra), rd, rs, shift) if rd == rs && (1..32).contains(shift) => {
+            let kind = if *op == Op::Srl {0} else {1};
+            Some(0x8001 | kind << 10 | compressed_reg(*rd)? << 7 | (*shift as u16) << 2)
+        }
+        Inst::OpImm(Op::And, rd, rs, imm) if rd == rs && fits_6(*imm) => {
+            Some(0x8801 | bit(*imm, 5) << 12 | compressed_reg(*rd)? << 7 | (*imm as u16 & 0x1f) << 2)
+        }
+        Inst::Op(op @ (Op::Sub | Op::Xor | Op::Or | Op::And), rd, rs1, rs2) if rd == rs1 => {
+            let kind = match op {Op::Sub => 0, Op::Xor => 1, Op::Or => 2, _ => 3};
+            Some(0x8c01 | compressed_reg(*rd)? << 7 | kind << 5 | compressed_reg(*rs2)? << 2)
+        }
+        Inst::Op(Op::Add, rd, rs1, rs2) if *rd != Reg::Zero && *rs1 != Reg::Zero && *rs2 != Reg::Zero && (rd == rs1 || rd == rs2) => {
+            let other = if rd == rs1 {rs2} else {rs1};
+            Some(0x9002 | reg(rd) << 7 | reg(other) << 2)
+        }
+        Inst::Load(Width::Word, rd, Reg::Sp, offset) if *rd != Reg::Zero && offset % 4 == 0 && (0..256).contains(offset) => {
+            Some(0x4002 | bit(*offset, 5) << 12 | reg(rd) << 7 | ((*offset >> 2 & 7) as u16) << 4 | ((*offset >> 6 & 3) as u16) << 2)
+        }
+        Inst::Load(Width::Word, rd, base, offset) if offset % 4 == 0 && (0..128).contains(offset) => {
+            Some(0x4000 | ((*offset >> 3 & 7) as u16) << 10 | compressed_reg(*base)? << 7 | bit(*offset, 2) << 6 | bit(*offset, 6) << 5 | compressed_reg(*rd)? << 2)
+        }
+        Inst::Store(Width::Word, rs, Reg::Sp, offset) if offset % 4 == 0 && (0..256).contains(offset) => {
+            Some(0xc002 | ((*offset >> 2 & 0xf) as u16) << 9 | ((*offset >> 6 & 3) as u16) << 7 | reg(rs) << 2)
+        }
+        Inst::Store(Width::Word, rs, base, offset) if offset % 4 == 0 && (0..128).contains(offset) => {
+            Some(0xc000 | ((*offset >> 3 & 7) as u16) << 10 | compressed_reg(*base)? << 7 | bit(*offset, 2) << 6 | bit(*offset, 6) << 5 | compressed_reg(*rs)? << 2)
+        }
+        Inst::Ret => Some(0x8082),
+        Inst::Ebreak => Some(0x9002),
+        _ => None,
+    }
+}
+
+/// Machine code of an instruction that doesn't depend on addresses
+fn encode_fixed(inst: &Inst) -> Result<Vec<u8>, String> {
+    if let Some(half) = compress(inst) {return Ok(half.to_le_bytes().to_vec())}
+    let words = match inst {
+        Inst::Label(_) => vec![],
+        Inst::Li(rd, imm) if fits_12(*imm) => vec![i_type(*imm, 0, 0, rd.number(), OPCODE_OP_IMM)],
+        Inst::Li(rd, imm) => {
+            let (upper, lower) = split_immediate(*imm);
+            // c.lui if the upper part is small enough
+            let mut bytes = match rd {
+                rd if *rd != Reg::Zero && *rd != Reg::Sp && fits_6(upper) && upper != 0 => {
+                    (0x6001 | bit(upper, 5) << 12 | (rd.number() as u16) << 7 | (upper as u16 & 0x1f) << 2).to_le_bytes().to_vec()
+                }
+                rd => u_type(upper, rd.number(), OPCODE_LUI).to_le_bytes().to_vec(),
+            };
+            if lower != 0 {bytes.extend(encode_fixed(&Inst::OpImm(Op::Add, *rd, *rd, lower))?)}
+            return Ok(bytes);
+        }
+        Inst::Op(op, rd, rs1, rs2) => {
+            let (funct7, funct3) = op_functs(*op);
+            vec![r_type(funct7, rs2.number(), rs1.number(), funct3, rd.number(), OPCODE_OP)]
+        }
+        Inst::OpImm(op, rd, rs, imm) => {
+            let (funct7, funct3) = op_functs(*op);
+            let imm = match op {
+                Op::Sll | Op::Srl | Op::Sra => (funct7 << 5) as i32 | (imm & 0x1f),
+                Op::Add | Op::Slt | Op::Sltu | Op::Xor | Op::Or | Op::And => *imm,
+                op => return Err(format!("{} has no immediate form", op.name())),
+            };
+            vec![i_type(imm, rs.number(), funct3, rd.number(), OPCODE_OP_IMM)]
+        }
+        Inst::Load(width, rd, base, offset) => vec![i_type(*offset, base.number(), load_funct3(*width), rd.number(), OPCODE_LOAD)],
+        Inst::Store(width, rs, base, offset) => vec![s_type(*offset, rs.number(), base.number(), store_funct3(*width))],
+        Inst::Ecall => vec![ECALL],
+        Inst::Ebreak => vec![EBREAK],
+        Inst::Mret => vec![MRET],
+        Inst::Asm { .. } => return Err("Inline assembly can't be encoded by the compiler, build it with --emit=asm and use an assembler".to_string()),
+        Inst::Ret => vec![i_type(0, Reg::Ra.number(), 0, 0, OPCODE_JALR)],
+        Inst::La(..) | Inst::Branch(..) | Inst::Jump(_) | Inst::Call(_) => unreachable!("{:?} depends on addresses", inst),
+    };
+    Ok(words.iter().flat_map(|word| word.to_le_bytes()).collect())
+}
+
+/// Machine code of a jump, call, branch or la at `pc` to `target`, in exactly `size` bytes,
+/// or None if that form doesn't reach
+fn encode_relative(inst: &Inst, pc: u32, target: u32, size: usize) -> Option<Vec<u8>> {
+    let offset = target.wrapping_sub(pc) as i32;
+    let in_range = |bits: u32| offset >= -(1 << (bits - 1)) && offset < 1 << (bits - 1);
+    let words = |words: &[u32]| words.iter().flat_map(|word| word.to_le_bytes()).collect::<Vec<u8>>();
+    match (inst, size) {
+        (Inst::Jump(_), 2) if in_range(12) => Some((0xa001 | cj_offset(offset)).to_le_bytes().to_vec()),
+        (Inst::Call(_), 2) if in_range(12) => Some((0x2001 | cj_offset(offset)).to_le_bytes().to_vec()),
+        (Inst::Jump(_), 4) if in_range(21) => Some(words(&[j_type(offset, 0)])),
+        (Inst::Call(_), 4) if in_range(21) => Some(words(&[j_type(offset, Reg::Ra.number())])),
+        (Inst::Call(_), 8) => {
+            let (upper, lower) = split_immediate(offset);
+            Some(words(&[u_type(upper, Reg::Ra.number(), OPCODE_AUIPC), i_type(lower, Reg::Ra.number(), 0, Reg::Ra.number(), OPCODE_JALR)]))
+        }
+        (Inst::La(rd, _), 8) => {
+            let (upper, lower) = split_immediate(offset);
+            Some(words(&[u_type(upper, rd.number(), OPCODE_AUIPC), i_type(lower, rd.number(), 0, rd.number(), OPCODE_OP_IMM)]))
+        }
+        (Inst::Branch(cond @ (Cond::Eq | Cond::Ne), rs, Reg::Zero, _), 2) if in_range(9) => {
+            let funct3 = if *cond == Cond::Eq {0xc001} else {0xe001};
+            Some((funct3 | compressed_reg(*rs)? << 7 | cb_offset(offset)).to_le_bytes().to_vec())
+        }
+        (Inst::Branch(cond, rs1, rs2, _), 4) if in_range(13) => Some(words(&[b_type(offset, rs2.number(), rs1.number(), branch_funct3(*cond))])),
+        // Too far for a branch: the opposite branch over a jump
+        (Inst::Branch(cond, rs1, rs2, _), 8) => {
+            let jump = offset - 4;
+            (-(1 << 20)..1 << 20).contains(&jump).then(|| words(&[b_type(8, rs2.number(), rs1.number(), branch_funct3(cond.inverse())), j_type(jump, 0)]))
+        }
+        _ => None,
+    }
+}
+
+/// The label a jump, call, branch or la goes to
+fn relative_target(inst: &Inst) -> Option<&String> {
+    match inst {
+        Inst::Jump(label) | Inst::Call(label) | Inst::La(_, label) | Inst::Branch(_, _, _, label) => Some(label),
+        _ => None,
+    }
+}
+
+/// Sizes a relative instruction can have, smallest first
+fn relative_sizes(inst: &Inst) -> &'static [usize] {
+    match inst {
+        Inst::Jump(_) => &[2, 4],
+        Inst::Call(_) => &[2, 4, 8],
+        Inst::La(..) => &[8],
+        _ => &[2, 4, 8],
+    }
+}
+
+/// Lays the program out at `base` and encodes it. `absolute` are symbols with a fixed
+/// address that the program doesn't define itself (the stack top for freestanding programs).
+pub fn encode(program: &Program, base: u32, absolute: &[(String, u32)]) -> Result<Image, String> {
+    let code: Vec<&Inst> = program.text.iter().chain(program.sections.iter().flat_map(|(_, text)| text)).collect();
+
+    // Everything that isn't relative has a size right away
+    let mut fixed: Vec<Option<Vec<u8>>> = vec![];
+    let mut sizes = vec![];
+    for inst in &code {
+        if let Some(label) = relative_target(inst) {
+            if !code.iter().any(|inst| **inst == Inst::Label(label.clone()))
+                && !program.rodata.iter().any(|(name, _)| name == label)
+                && !absolute.iter().any(|(name, _)| name == label) {
+                return Err(format!("'{}' isn't defined anywhere in the program (the built-in encoder can't link in C code)", label));
+            }
+            fixed.push(None);
+            sizes.push(relative_sizes(inst)[0]);
+        } else {
+            let bytes = encode_fixed(inst)?;
+            sizes.push(bytes.len());
+            fixed.push(Some(bytes));
+        }
+    }
+
+    // Grow whatever doesn't reach until everything does, sizes only ever go up so this ends
+    let mut labels: HashMap<&str, u32>;
+    loop {
+        labels = absolute.iter().map(|(name, address)| (name.as_str(), *address)).collect();
+        let mut address = base;
+        for (inst, size) in code.iter().zip(&sizes) {
+            if let Inst::Label(label) = inst {labels.insert(label, address);}
+            address += *size as u32;
+        }
+        address = (address + 3) & !3;
+        for (label, bytes) in &program.rodata {
+            labels.insert(label, address);
+            address += bytes.len() as u32;
+        }
+
+        let mut changed = false;
+        let mut pc = base;
+        for (inst, size) in code.iter().zip(sizes.iter_mut()) {
+            if let Some(label) = relative_target(inst) {
+                let target = labels[label.as_str()];
+                if encode_relative(inst, pc, target, *size).is_none() {
+                    let Some(bigger) = relative_sizes(inst).iter().find(|bigger| **bigger > *size) else {
+                        return Err(format!("'{}' is too far away to jump to", label));
+                    };
+                    *size = *bigger;
+                    changed = true;
+                }
+            }
+            pc += *size as u32;
+        }
+        if !changed {break}
+    }
+
+    let mut bytes = vec![];
+    for ((inst, size), fixed) in code.iter().zip(&sizes).zip(fixed) {
+        match fixed {
+            Some(fixed) => bytes.extend(fixed),
+            None => {
+                let label = relative_target(inst).unwrap();
+                let pc = base + bytes.len() as u32;
+                // Can't fail, the loop above made sure everything fits
+                bytes.extend(encode_relative(inst, pc, labels[label.as_str()], *size).unwrap());
+            }
+        }
+    }
+    while bytes.len() % 4 != 0 {bytes.push(0)}
+    for (_, data) in &program.rodata {bytes.extend(data)}
+
+    let entry = match labels.get(program.entry.as_str()) {
+        Some(entry) => *entry,
+        None => return Err(format!("The program has no entry point '{}' to start at", program.entry)),
+    };
+    let mut symbols: Vec<(String, u32)> = labels.iter()
+        .filter(|(name, address)| !name.starts_with(".L") && **address >= base && **address < base + bytes.len() as u32)
+        .map(|(name, address)| (name.to_string(), *address)).collect();
+    symbols.sort_by_key(|(name, address)| (*address, name.clone()));
+    Ok(Image { base, bytes, entry, symbols })
+}
@@ -0,0 +1,376 @@
+use std::collections::HashMap;
+use std::io::{Read, Write};
+
+use crate::backend::Image;
+use crate::compiler_settings::*;
+use crate::intrinsics::open_options;
+use crate::riscv::DIV64;
+use crate::riscv_encoder::*;
+
+// A small RV32IMC simulator, so RISC-V output can be run without qemu or a board around.
+// Runs user mode code only: all of RV32I, M and C, plus the few Linux syscalls the backend's
+// runtime uses (read, write, openat, close, exit and brk). Memory is one flat array with the
+// program at RISCV_LOAD_ADDRESS, the heap right after it and the stack at the top.
+// Compressed instructions get expanded into their 32-bit versions first, so only those
+// need executing.
+// The program comes from riscv_encoder.rs, which puts it at RISCV_LOAD_ADDRESS and the stack
+// top at the end of the memory.
+// Freestanding programs end in a jump to itself, the simulator stops there instead of
+// spinning forever and the exit code is whatever main left in a0.
+
+//
+// STRUCTS
+//
+
+struct Machine {
+    x: [u32; 32],
+    pc: u32,
+    memory: Vec<u8>,
+    /// Lowest and current program break
+    heap_start: u32,
+    brk: u32,
+    /// Files opened with openat, 0-2 are always the standard streams
+    files: HashMap<u32, std::fs::File>,
+    out: std::io::BufWriter<std::io::Stdout>,
+}
+
+//
+// FUNCTIONS
+//
+
+// Linux errno values the syscalls give back (negated)
+const EBADF: i32 = 9;
+const ENOSYS: i32 = 38;
+const EFAULT: i32 = 14;
+
+fn sign_extend(value: u32, bits: u32) -> i32 {
+    ((value << (32 - bits)) as i32) >> (32 - bits)
+}
+
+/// The 32-bit instruction a compressed one is short for, None if it isn't a valid one
+fn expand(half: u16) -> Option<u32> {
+    let h = half as u32;
+    let bit = |index: u32| h >> index & 1;
+    let rd = h >> 7 & 31;
+    let rs2 = h >> 2 & 31;
+    // The 3-bit register fields, x8-x15
+    let rd_short = (h >> 2 & 7) + 8;
+    let rs1_short = (h >> 7 & 7) + 8;
+    let imm6 = sign_extend(bit(12) << 5 | (h >> 2 & 31), 6);
+    let jump = sign_extend(bit(12) << 11 | bit(11) << 4 | (h >> 9 & 3) << 8 | bit(8) << 10 | bit(7) << 6
+        | bit(6) << 7 | (h >> 3 & 7) << 1 | bit(2) << 5, 12);
+    let branch = sign_extend(bit(12) << 8 | (h >> 10 & 3) << 3 | (h >> 5 & 3) << 6 | (h >> 3 & 3) << 1 | bit(2) << 5, 9);
+    let word_offset = (h >> 10 & 7) << 3 | bit(6) << 2 | bit(5) << 6;
+    let (sp, ra) = (2, 1);
+    Some(match (h & 3, h >> 13) {
+        (0, 0) => {
+            let imm = (h >> 7 & 0xf) << 6 | (h >> 11 & 3) << 4 | bit(5) << 3 | bit(6) << 2;
+            if imm == 0 {return None}
+            i_type(imm as i32, sp, 0, rd_short, OPCODE_OP_IMM)
+        }
+        (0, 2) => i_type(word_offset as i32, rs1_short, 2, rd_short, OPCODE_LOAD),
+        (0, 6) => s_type(word_offset as i32, rd_short, rs1_short, 2),
+        (1, 0) => i_type(imm6, rd, 0, rd, OPCODE_OP_IMM),
+        (1, 1) => j_type(jump, ra),
+        (1, 2) => i_type(imm6, 0, 0, rd, OPCODE_OP_IMM),
+        (1, 3) if rd == sp => {
+            let imm = sign_extend(bit(12) << 9 | bit(6) << 4 | bit(5) << 6 | (h >> 3 & 3) << 7 | bit(2) << 5, 10);
+            if imm == 0 {return None}
+            i_type(imm, sp, 0, sp, OPCODE_OP_IMM)
+        }
+        (1, 3) => {
+            if imm6 == 0 || rd == 0 {return None}
+            u_type(imm6, rd, OPCODE_LUI)
+        }
+        (1, 4) => match h >> 10 & 3 {
+            0 => i_type((h >> 2 & 31) as i32, rs1_short, 5, rs1_short, OPCODE_OP_IMM),
+            1 => i_type(0x400 | (h >> 2 & 31) as i32, rs1_short, 5, rs1_short, OPCODE_OP_IMM),
+            2 => i_type(imm6, rs1_short, 7, rs1_short, OPCODE_OP_IMM),
+            _ if bit(12) == 0 => {
+                let (funct7, funct3) = [(0x20, 0), (0, 4), (0, 6), (0, 7)][(h >> 5 & 3) as usize];
+                r_type(funct7, rd_short, rs1_short, funct3, rs1_short, OPCODE_OP)
+            }
+            _ => return None,
+        },
+        (1, 5) => j_type(jump, 0),
+        (1, 6) => b_type(branch, 0, rs1_short, 0),
+        (1, 7) => b_type(branch, 0, rs1_short, 1),
+        (2, 0) => i_type((h >> 2 & 31) as i32, rd, 1, rd, OPCODE_OP_IMM),
+        (2, 2) => {
+            if rd == 0 {return None}
+            i_type((bit(12) << 5 | (h >> 4 & 7) << 2 | (h >> 2 & 3) << 6) as i32, sp, 2, rd, OPCODE_LOAD)
+        }
+        (2, 4) => match (bit(12), rd, rs2) {
+            (0, 0, 0) => return None,
+            (0, rs1, 0) => i_type(0, rs1, 0, 0, OPCODE_JALR),
+            (0, rd, rs2) => r_type(0, rs2, 0, 0, rd, OPCODE_OP),
+            (_, 0, 0) => EBREAK,
+            (_, rs1, 0) => i_type(0, rs1, 0, ra, OPCODE_JALR),
+            (_, rd, rs2) => r_type(0, rs2, rd, 0, rd, OPCODE_OP),
+        },
+        (2, 6) => s_type(((h >> 9 & 0xf) << 2 | (h >> 7 & 3) << 6) as i32, rs2, sp, 2),
+        _ => return None,
+    })
+}
+
+impl Machine {
+    fn new(image: &Image) -> Machine {
+        let mut memory = vec![0; RISCV_MEMORY_SIZE as usize];
+        let start = image.base as usize;
+        memory[start..start + image.bytes.len()].copy_from_slice(&image.bytes);
+        let heap_start = (image.base + image.bytes.len() as u32 + 0xfff) & !0xfff;
+        let mut x = [0; 32];
+        x[2] = RISCV_MEMORY_SIZE; // sp
+        Machine { x, pc: image.entry, memory, heap_start, brk: heap_start, files: HashMap::new(), out: std::io::BufWriter::new(std::io::stdout()) }
+    }
+
+    /// Memory index of `length` bytes at `address`, anything below the program counts as a
+    /// null pointer
+    fn range(&self, address: u32, length: u32) -> Result<std::ops::Range<usize>, String> {
+        match address.checked_add(length) {
+            Some(end) if address >= RISCV_LOAD_ADDRESS && end <= RISCV_MEMORY_SIZE => Ok(address as usize..end as usize),
+            _ => Err(format!("Memory access to {:#x} ({} bytes) is outside of the memory", address, length)),
+        }
+    }
+
+    fn load(&self, address: u32, length: u32) -> Result<u32, String> {
+        let range = self.range(address, length)?;
+        Ok(self.memory[range].iter().rev().fold(0, |value, byte| value << 8 | *byte as u32))
+    }
+
+    fn store(&mut self, address: u32, length: u32, value: u32) -> Result<(), String> {
+        let range = self.range(address, length)?;
+        self.memory[range].copy_from_slice(&value.to_le_bytes()[..length as usize]);
+        Ok(())
+    }
+
+    /// The NUL terminated string at an address
+    fn string(&self, address: u32) -> Result<String, String> {
+        let start = self.range(address, 0)?.start;
+        match self.memory[start..].iter().position(|byte| *byte == 0) {
+            Some(length) => Ok(String::from_utf8_lossy(&self.memory[start..start + length]).to_string()),
+            None => Err(format!("String at {:#x} has no NUL terminator", address)),
+        }
+    }
+
+    /// Runs the syscall in a7, returns the exit code if it was exit
+    fn syscall(&mut self) -> Result<Option<i64>, String> {
+        let (a0, a1, a2) = (self.x[10], self.x[11], self.x[12]);
+        let result = |result: std::io::Result<usize>| match result {
+            Ok(count) => count as i32,
+            Err(error) => -error.raw_os_error().unwrap_or(5),
+        };
+        let value: i32 = match self.x[17] {
+            // read(fd, buffer, length)
+            63 => {
+                let Ok(range) = self.range(a1, a2) else {return self.returns(-EFAULT)};
+                let buffer = &mut self.memory[range];
+                match a0 {
+                    0 => {
+                        let _ = self.out.flush();
+                        result(std::io::stdin().read(buffer))
+                    }
+                    fd => match self.files.get_mut(&fd) {
+                        Some(file) => result(file.read(buffer)),
+                        None => -EBADF,
+                    },
+                }
+            }
+            // write(fd, buffer, length)
+            64 => {
+                let Ok(range) = self.range(a1, a2) else {return self.returns(-EFAULT)};
+                let buffer = &self.memory[range];
+                let written = match a0 {
+                    1 => self.out.write_all(buffer),
+                    2 => self.out.flush().and_then(|_| std::io::stderr().write_all(buffer)),
+                    fd => match self.files.get_mut(&fd) {
+                        Some(file) => file.write_all(buffer),
+                        None => return self.returns(-EBADF),
+                    },
+                };
+                result(written.map(|_| buffer.len()))
+            }
+            // openat(dirfd, path, flags, mode), only relative to the working directory
+            56 => {
+                let path = self.string(a1)?;
+                match open_options(a2 as i32 as i64, self.x[13] as i64).open(path) {
+                    Ok(file) => {
+                        let fd = (3..).find(|fd| !self.files.contains_key(fd)).unwrap();
+                        self.files.insert(fd, file);
+                        fd as i32
+                    }
+                    Err(error) => result(Err(error)),
+                }
+            }
+            // close(fd)
+            57 => match self.files.remove(&a0) {
+                Some(_) => 0,
+                None => -EBADF,
+            },
+            // exit(code), exit_group(code)
+            93 | 94 => return Ok(Some(a0 as i32 as i64)),
+            // brk(address), 0 (or anything it can't do) just gives back the current one
+            214 => {
+                if a0 >= self.heap_start && a0 <= RISCV_MEMORY_SIZE - RISCV_STACK_SIZE {
+                    if a0 > self.brk {self.memory[self.brk as usize..a0 as usize].fill(0)}
+                    self.brk = a0;
+                }
+                self.brk as i32
+            }
+            _ => -ENOSYS,
+        };
+        self.returns(value)
+    }
+
+    fn returns(&mut self, value: i32) -> Result<Option<i64>, String> {
+        self.x[10] = value as u32;
+        Ok(None)
+    }
+
+    /// Runs one instruction, returns the exit code once the program is done
+    fn step(&mut self) -> Result<Option<i64>, String> {
+        let pc = self.pc;
+        let low = self.load(pc, 2)?;
+        let (inst, size) = if low & 3 != 3 {
+            (expand(low as u16).ok_or(format!("Illegal instruction {:#06x}", low))?, 2)
+        } else {
+            (self.load(pc, 4)?, 4)
+        };
+        let illegal = || format!("Illegal instruction {:#010x}", inst);
+        let next = pc.wrapping_add(size);
+        let rd = (inst >> 7 & 31) as usize;
+        let funct3 = inst >> 12 & 7;
+        let funct7 = inst >> 25;
+        let (a, b) = (self.x[(inst >> 15 & 31) as usize], self.x[(inst >> 20 & 31) as usize]);
+        let imm_i = (inst as i32) >> 20;
+        let imm_s = (inst as i32) >> 25 << 5 | (inst >> 7 & 31) as i32;
+        let imm_b = sign_extend((inst >> 31) << 12 | (inst >> 7 & 1) << 11 | (inst >> 25 & 0x3f) << 5 | (inst >> 8 & 0xf) << 1, 13);
+        let imm_j = sign_extend((inst >> 31) << 20 | (inst >> 12 & 0xff) << 12 | (inst >> 20 & 1) << 11 | (inst >> 21 & 0x3ff) << 1, 21);
+
+        let mut target = next;
+        let mut result = None;
+        match inst & 0x7f {
+            OPCODE_LUI => result = Some(inst & 0xffff_f000),
+            OPCODE_AUIPC => result = Some(pc.wrapping_add(inst & 0xffff_f000)),
+            OPCODE_JAL => {
+                // A jump to itself never ends, that's how freestanding programs stop
+                if imm_j == 0 && rd == 0 {
+                    let _ = self.out.flush();
+                    return Ok(Some(self.x[10] as i32 as i64));
+                }
+                result = Some(next);
+                target = pc.wrapping_add(imm_j as u32);
+            }
+            OPCODE_JALR => {
+                result = Some(next);
+                target = a.wrapping_add(imm_i as u32) & !1;
+            }
+            OPCODE_BRANCH => {
+                let taken = match funct3 {
+                    0 => a == b,
+                    1 => a != b,
+                    4 => (a as i32) < b as i32,
+                    5 => a as i32 >= b as i32,
+                    6 => a < b,
+                    7 => a >= b,
+                    _ => return Err(illegal()),
+                };
+                if taken {target = pc.wrapping_add(imm_b as u32)}
+            }
+            OPCODE_LOAD => {
+                let address = a.wrapping_add(imm_i as u32);
+                result = Some(match funct3 {
+                    0 => self.load(address, 1)? as i8 as u32,
+                    1 => self.load(address, 2)? as i16 as u32,
+                    2 => self.load(address, 4)?,
+                    4 => self.load(address, 1)?,
+                    5 => self.load(address, 2)?,
+                    _ => return Err(illegal()),
+                });
+            }
+            OPCODE_STORE => {
+                let address = a.wrapping_add(imm_s as u32);
+                match funct3 {
+                    0..=2 => self.store(address, 1 << funct3, b)?,
+                    _ => return Err(illegal()),
+                }
+            }
+            OPCODE_OP_IMM => {
+                let imm = imm_i as u32;
+                result = Some(match funct3 {
+                    0 => a.wrapping_add(imm),
+                    1 => a << (imm & 31),
+                    2 => ((a as i32) < imm_i) as u32,
+                    3 => (a < imm) as u32,
+                    4 => a ^ imm,
+                    5 if funct7 == 0x20 => ((a as i32) >> (imm & 31)) as u32,
+                    5 => a >> (imm & 31),
+                    6 => a | imm,
+                    _ => a & imm,
+                });
+            }
+            OPCODE_OP => result = Some(match (funct7, funct3) {
+                (0, 0) => a.wrapping_add(b),
+                (0x20, 0) => a.wrapping_sub(b),
+                (0, 1) => a << (b & 31),
+                (0, 2) => ((a as i32) < b as i32) as u32,
+                (0, 3) => (a < b) as u32,
+                (0, 4) => a ^ b,
+                (0, 5) => a >> (b & 31),
+                (0x20, 5) => ((a as i32) >> (b & 31)) as u32,
+                (0, 6) => a | b,
+                (0, 7) => a & b,
+                (1, 0) => a.wrapping_mul(b),
+                (1, 1) => ((a as i32 as i64 * b as i32 as i64) >> 32) as u32,
+                (1, 2) => ((a as i32 as i64 * b as i64) >> 32) as u32,
+                (1, 3) => ((a as u64 * b as u64) >> 32) as u32,
+                // Division by zero doesn't trap on RISC-V, it has defined results
+                (1, 4) if b == 0 => u32::MAX,
+                (1, 4) => (a as i32).wrapping_div(b as i32) as u32,
+                (1, 5) if b == 0 => u32::MAX,
+                (1, 5) => a / b,
+                (1, 6) if b == 0 => a,
+                (1, 6) => (a as i32).wrapping_rem(b as i32) as u32,
+                (1, 7) if b == 0 => a,
+                (1, 7) => a % b,
+                _ => return Err(illegal()),
+            }),
+            // fence
+            0x0f => {}
+            OPCODE_SYSTEM => match inst {
+                ECALL => {
+                    if let Some(exit_code) = self.syscall()? {
+                        let _ = self.out.flush();
+                        return Ok(Some(exit_code));
+                    }
+                }
+                EBREAK => return Err("Hit an ebreak".to_string()),
+                _ => return Err(format!("Instruction {:#010x} needs machine mode, the simulator only runs user mode code", inst)),
+            },
+            _ => return Err(illegal()),
+        }
+        if let Some(value) = result && rd != 0 {
+            self.x[rd] = value;
+        }
+        self.pc = target;
+        Ok(None)
+    }
+}
+
+/// Runs an encoded program (see riscv_encoder.rs), returns its exit code
+pub fn run(image: &Image) -> Result<i64, String> {
+    let mut machine = Machine::new(image);
+    loop {
+        match machine.step() {
+            Ok(Some(exit_code)) => return Ok(exit_code),
+            Ok(None) => {}
+            Err(error) => {
+                let _ = machine.out.flush();
+                let function = image.symbol_at(machine.pc).unwrap_or("?");
+                // The runtime's division traps with an ebreak, like x86 does with a SIGFPE
+                if function == DIV64 {return Err("Division by zero".to_string())}
+                return Err(format!("{} at {:#x} (in {})", error, machine.pc, function));
+            }
+        }
+    }
+}
@@ -1,10 +1,406 @@
-use crate::parser::Statement;
+use std::collections::HashMap;
+
+use crate::ffi::Extern;
+use crate::intrinsics::INTRINSICS;
+use crate::lexer::Location;
+use crate::modules::demangle;
+use crate::parser::{expand_asm, Expression, InlineAsm, Statement};
 use crate::compiler_settings::*;
+use crate::source_map::position;
+
+//
+// STRUCTS
+//
+
+/// Types of values the language knows about.
+/// Everything without a better guess is an `Int` (64-bit signed).
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+#[derive(PartialEq)]
+pub enum Type {
+    Int,
+    Str,
+    Void,
+}
+impl std::fmt::Display for Type {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Type::Int => write!(f, "i64"),
+            Type::Str => write!(f, "str"),
+            Type::Void => write!(f, "void"),
+        }
+    }
+}
+
+/// Everything the later stages need to know about a function without looking at its body
+#[derive(Debug)]
+#[derive(Clone)]
+pub struct FunctionInfo {
+    pub name: String,
+    pub parameters: Vec<String>,
+    pub parameter_types: Vec<Type>,
+    pub return_type: Type,
+    /// The C declaration of an `extern "C"` function, see ffi.rs
+    pub external: Option<Extern>,
+    /// `#[interrupt]`, Galvan code can't call it
+    pub interrupt: bool,
+}
+
+/// Output of the semantic analyzer, the statements are the same ones that came in,
+/// they're just guaranteed to make sense now.
+#[derive(Debug)]
+pub struct Analysis {
+    pub statements: Vec<Statement>,
+    pub functions: HashMap<String, FunctionInfo>,
+}
+
+/// Variables visible in the current function (or the top level), name -> type
+pub type Scope = HashMap<String, Type>;
+
+//
+// FUNCTIONS
+//
+
+/// Functions that exist without being defined in the source
+pub fn builtin_functions() -> Vec<FunctionInfo> {
+    let mut builtins = vec![
+        // print takes any amount of arguments, see check_expression()
+        FunctionInfo { name: "print".to_string(), parameters: vec![], parameter_types: vec![], return_type: Type::Void, external: None, interrupt: false },
+    ];
+    for intrinsic in &INTRINSICS {
+        builtins.push(FunctionInfo {
+            name: intrinsic.name.to_string(),
+            parameters: (0..intrinsic.parameters.len()).map(|index| format!("arg{}", index)).collect(),
+            parameter_types: intrinsic.parameters.to_vec(),
+            return_type: intrinsic.return_type,
+            external: None,
+            interrupt: false,
+        });
+    }
+    builtins
+}
+
+/// `function name(a: i64, s: str) -> i64` or `extern "C" function puts(s: str) -> i32`, for
+/// hovers (lsp.rs) and documentation (doc.rs)
+pub fn signature(name: &str, info: &FunctionInfo) -> String {
+    if let Some(external) = &info.external {return format!("extern \"C\" function {}", external.signature())}
+    let parameters: Vec<String> = info.parameters.iter().zip(&info.parameter_types).map(|(parameter, ty)| format!("{}: {}", parameter, ty)).collect();
+    // print takes anything
+    let parameters = if name == "print" {"...".to_string()} else {parameters.join(", ")};
+    format!("function {}({}) -> {}", name, parameters, info.return_type)
+}
+
+/// Gets the type of an expression, erroring out if something in it doesn't make sense
+pub fn check_expression(expression: &Expression, scope: &Scope, functions: &HashMap<String, FunctionInfo>) -> Result<Type, String> {
+    match expression {
+        Expression::Number(_) => Ok(Type::Int),
+        Expression::String(_) => Ok(Type::Str),
+        Expression::Variable(name, _) => {
+            match scope.get(name) {
+                Some(ty) => Ok(*ty),
+                None => Err(format!("Unknown variable '{}'", name)),
+            }
+        }
+        Expression::Operation(operation) => {
+            let left = check_expression(&operation.left, scope, functions)?;
+            let right = check_expression(&operation.right, scope, functions)?;
+            if left != Type::Int || right != Type::Int {
+                return Err(format!("Operator {:?} only works on i64, not {} and {}", operation.operator, left, right));
+            }
+            Ok(Type::Int)
+        }
+        Expression::FunctionCall { target, args, .. } => {
+            let function = match functions.get(target) {
+                Some(function) => function,
+                None => return Err(format!("Unknown function '{}'", target)),
+            };
+            let mut arg_types = vec![];
+            for arg in args {
+                arg_types.push(check_expression(arg, scope, functions)?);
+            }
+            if function.interrupt {
+                return Err(format!("'{}' is an interrupt handler, only the hardware gets to call it", demangle(target)));
+            }
+            if target == "print" {
+                if arg_types.contains(&Type::Void) {return Err("Can't print a void value".to_string())}
+                return Ok(Type::Void);
+            }
+            if let Some(external) = &function.external {
+                return check_extern_call(external, &arg_types).map(|_| function.return_type);
+            }
+            if args.len() != function.parameters.len() {
+                return Err(format!("Function '{}' takes {} arguments, {} were given", demangle(target), function.parameters.len(), args.len()));
+            }
+            for (index, (ty, expected)) in arg_types.iter().zip(&function.parameter_types).enumerate() {
+                if ty != expected {
+                    return Err(format!("Argument {} of '{}' has to be {}, not {}", index + 1, demangle(target), expected, ty));
+                }
+            }
+            Ok(function.return_type)
+        }
+        Expression::ReturnValue { value } => check_expression(value, scope, functions),
+    }
+}
 
+/// Arguments of a call to a C function, pointers take a str or an i64 (an address) and
+/// whatever goes into the `...` just can't be void
+fn check_extern_call(external: &Extern, arg_types: &[Type]) -> Result<(), String> {
+    let expected = external.parameters.len();
+    if arg_types.len() < expected || (arg_types.len() > expected && !external.variadic) {
+        let at_least = if external.variadic {"at least "} else {""};
+        return Err(format!("Function '{}' takes {}{} arguments, {} were given", demangle(&external.name), at_least, expected, arg_types.len()));
+    }
+    for (index, ty) in arg_types.iter().enumerate() {
+        match external.parameters.get(index) {
+            Some((_, c_type)) if !c_type.accepts(*ty) => {
+                return Err(format!("Argument {} of '{}' has to be {} (C {}), not {}", index + 1, demangle(&external.name), c_type.galvan(), c_type, ty));
+            }
+            None if *ty == Type::Void => return Err(format!("Argument {} of '{}' is void", index + 1, demangle(&external.name))),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// C symbols the program defines or uses have to be unique, and can't take the names the
+/// backends use themselves
+fn check_symbols(statements: &[Statement]) -> Result<(), String> {
+    let mut externs: HashMap<&str, &Extern> = HashMap::new();
+    for statement in statements {
+        if let Statement::Extern { function, location, .. } = statement
+            && let Some(other) = externs.insert(&function.symbol, function) && other.signature() != function.signature() {
+            return Err(at(*location)(format!("C function '{}' is declared twice, as {} and as {}", function.symbol, other.signature(), function.signature())));
+        }
+    }
+    let mut exports: Vec<&str> = vec![];
+    for statement in statements {
+        let Statement::FunctionAssignment { export: Some(symbol), location, .. } = statement else {continue};
+        let at = at(*location);
+        if exports.contains(&symbol.as_str()) {return Err(at(format!("Two functions are exported as '{}'", symbol)))}
+        if externs.contains_key(symbol.as_str()) {return Err(at(format!("'{}' is exported, but also declared as an extern \"C\" function", symbol)))}
+        if symbol == ENTRY_FUNCTION || symbol == START_SYMBOL || symbol.starts_with(SYMBOL_PREFIX) {
+            return Err(at(format!("Can't export a function as '{}', the compiler uses that name itself", symbol)));
+        }
+        exports.push(symbol);
+    }
+    Ok(())
+}
+
+/// Interrupt handlers get nothing from the hardware, so no parameters (and they keep their
+/// Galvan name as the symbol), panic handlers get the message
+fn check_attributes(statements: &[Statement]) -> Result<(), String> {
+    for statement in statements {
+        let Statement::FunctionAssignment { name, parameter_types, export, attributes, location, .. } = statement else {continue};
+        let at = at(*location);
+        if attributes.interrupt && attributes.panic_handler {
+            return Err(at(format!("'{}' can't be an interrupt handler and the panic handler at once", demangle(name))));
+        }
+        if attributes.interrupt && !parameter_types.is_empty() {
+            return Err(at(format!("Interrupt handler '{}' can't have parameters, the hardware doesn't pass any", demangle(name))));
+        }
+        if attributes.interrupt && export.is_some() {
+            return Err(at(format!("Interrupt handler '{}' can't be exported, it's already callable as '{}'", demangle(name), name)));
+        }
+        if attributes.panic_handler && parameter_types != &[Type::Str] {
+            return Err(at(format!("Panic handler '{}' has to take the message, function {}(message: str)", demangle(name), demangle(name))));
+        }
+    }
+    Ok(())
+}
+
+/// Adds the statement's position to an error that doesn't have one yet
+fn at(location: Location) -> impl Fn(String) -> String {
+    move |error| format!("{} at position {}", error, position(location))
+}
+
+/// Operands have to have different names, every `{name}` in the template has to be one of
+/// them and outputs can only go into i64 variables (which `asm` declares if they're new)
+fn check_asm(asm: &InlineAsm, scope: &mut Scope, functions: &HashMap<String, FunctionInfo>) -> Result<(), String> {
+    let names: Vec<&String> = asm.inputs.iter().map(|(name, _)| name).chain(asm.outputs.iter().map(|(name, _)| name)).collect();
+    if let Some(index) = (0..names.len()).find(|index| names[..*index].contains(&names[*index])) {
+        return Err(format!("Inline assembly has two operands called '{}'", names[index]));
+    }
+    expand_asm(&asm.template, &mut |name| {
+        if names.iter().any(|operand| *operand == name) {Ok(String::new())}
+        else {Err(format!("Inline assembly uses '{{{}}}', but there's no operand called that", name))}
+    })?;
+    for (name, value) in &asm.inputs {
+        if check_expression(value, scope, functions)? == Type::Void {
+            return Err(format!("Input '{}' of inline assembly is void", name));
+        }
+    }
+    for (name, variable) in &asm.outputs {
+        match scope.get(variable) {
+            Some(Type::Int) | None => {scope.insert(variable.clone(), Type::Int);}
+            Some(ty) => return Err(format!("Output '{}' of inline assembly goes into '{}', which is {} instead of i64", name, variable, ty)),
+        }
+    }
+    Ok(())
+}
+
+/// Checks a list of statements inside one function (or the top level).
+/// `returns` collects the types of every `return`, used for inferring return types.
+fn check_block(statements: &[Statement], scope: &mut Scope, functions: &HashMap<String, FunctionInfo>, returns: &mut Vec<Type>) -> Result<(), String> {
+    for statement in statements {
+        let at = at(statement.location());
+        match statement {
+            Statement::ExpressionStatement(expression, _) => {
+                let ty = check_expression(expression, scope, functions).map_err(&at)?;
+                if let Expression::ReturnValue { .. } = expression {
+                    if ty == Type::Void {return Err(at("Can't return a void value".to_string()))}
+                    returns.push(ty);
+                }
+            }
+            Statement::VariableAssignment { name, value, .. } => {
+                let ty = check_expression(value, scope, functions).map_err(&at)?;
+                if ty == Type::Void {
+                    return Err(at(format!("Can't assign a void value to '{}'", name)));
+                }
+                // `let` on an existing variable assigns to it, so the type has to stay the same
+                if let Some(old) = scope.get(name) && *old != ty {
+                    return Err(at(format!("Variable '{}' is {}, can't assign {} to it", name, old, ty)));
+                }
+                scope.insert(name.clone(), ty);
+            }
+            Statement::FunctionAssignment { name, .. } => {
+                return Err(at(format!("Function '{}' has to be defined at the top level", name)));
+            }
+            // modules.rs takes care of these before seman ever runs, if it ran
+            Statement::Import { path, .. } => {
+                return Err(at(format!("Can't import '{}' here, imports only work at the top level of a file", path)));
+            }
+            Statement::ConstAssignment { name, .. } => {
+                return Err(at(format!("Can't define constant '{}' here, constants only work at the top level of a file", name)));
+            }
+            Statement::Asm { asm, .. } => check_asm(asm, scope, functions).map_err(&at)?,
+            Statement::Extern { function, .. } => {
+                return Err(at(format!("Extern function '{}' has to be declared at the top level", function.name)));
+            }
+            Statement::Attribute { name, .. } => {
+                return Err(at(format!("#![{}] only works at the top level of the root file", name)));
+            }
+            Statement::While { condition, body, .. } => {
+                if check_expression(condition, scope, functions).map_err(&at)? != Type::Int {
+                    return Err(at("While condition has to be an i64".to_string()));
+                }
+                check_block(body, scope, functions, returns)?;
+            }
+            Statement::ConditionalStatement { condition, body, else_body, .. } => {
+                if check_expression(condition, scope, functions).map_err(&at)? != Type::Int {
+                    return Err(at("If condition has to be an i64".to_string()));
+                }
+                check_block(body, scope, functions, returns)?;
+                if let Some(else_body) = else_body {
+                    check_block(else_body, scope, functions, returns)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Gets the parameter names of a function definition, they're parsed as expressions
+/// so make sure they're just plain names.
+pub fn parameter_names(name: &str, arguments: &[Expression]) -> Result<Vec<String>, String> {
+    let mut names: Vec<String> = vec![];
+    for argument in arguments {
+        match argument {
+            Expression::Variable(param, _) => {
+                if names.contains(param) {
+                    return Err(format!("Parameter '{}' defined twice in function '{}'", param, demangle(name)));
+                }
+                names.push(param.clone())
+            }
+            _ => return Err(format!("Parameters of function '{}' have to be plain names", demangle(name))),
+        }
+    }
+    Ok(names)
+}
+
+/// Checks every function body, and figures out the return types in the process.
+/// Runs until nothing changes, since return types can depend on other functions.
+fn infer_functions(statements: &[Statement], functions: &mut HashMap<String, FunctionInfo>) -> Result<(), String> {
+    loop {
+        let mut changed = false;
+        for statement in statements {
+            if let Statement::FunctionAssignment { name, body, .. } = statement {
+                let mut scope: Scope = HashMap::new();
+                for (param, ty) in functions[name].parameters.iter().zip(&functions[name].parameter_types) {
+                    scope.insert(param.clone(), *ty);
+                }
+                let mut returns = vec![];
+                check_block(body, &mut scope, functions, &mut returns)?;
+
+                let return_type = returns.first().copied().unwrap_or(Type::Int);
+                if let Some(other) = returns.iter().find(|ty| **ty != return_type) {
+                    return Err(at(statement.location())(format!("Function '{}' returns both {} and {}", demangle(name), return_type, other)));
+                }
+                let function = functions.get_mut(name).unwrap();
+                if function.return_type != return_type {
+                    function.return_type = return_type;
+                    changed = true;
+                }
+            }
+        }
+        if !changed {return Ok(())}
+    }
+}
 
 /// Semantic analysis
-pub fn analyze(statements: Vec<Statement>) {
-    if SEMAN_DEBUG_PRINTS {println!("- - - SEMAN")}
+pub fn analyze(statements: Vec<Statement>) -> Result<Analysis, String> {
+    if debug_prints(SEMAN_DEBUG_PRINTS) {eprintln!("- - - SEMAN")}
+
+    // Collect function signatures first so functions can call ones defined later
+    let mut functions: HashMap<String, FunctionInfo> = HashMap::new();
+    for builtin in builtin_functions() {
+        functions.insert(builtin.name.clone(), builtin);
+    }
+    for statement in &statements {
+        if let Statement::FunctionAssignment { name, arguments, parameter_types, attributes, location, .. } = statement {
+            if functions.contains_key(name) || name == ENTRY_FUNCTION {
+                return Err(at(*location)(format!("Function '{}' is already defined", demangle(name))));
+            }
+            functions.insert(name.clone(), FunctionInfo {
+                name: name.clone(),
+                parameters: parameter_names(name, arguments).map_err(at(*location))?,
+                parameter_types: parameter_types.clone(),
+                return_type: Type::Int,
+                external: None,
+                interrupt: attributes.interrupt,
+            });
+        }
+        if let Statement::Extern { function, location, .. } = statement {
+            if functions.contains_key(&function.name) || function.name == ENTRY_FUNCTION {
+                return Err(at(*location)(format!("Function '{}' is already defined", demangle(&function.name))));
+            }
+            functions.insert(function.name.clone(), FunctionInfo {
+                name: function.name.clone(),
+                parameters: function.parameters.iter().map(|(name, _)| name.clone()).collect(),
+                parameter_types: function.parameters.iter().map(|(_, ty)| ty.galvan()).collect(),
+                return_type: function.return_type.galvan(),
+                external: Some(function.clone()),
+                interrupt: false,
+            });
+        }
+    }
+    check_symbols(&statements)?;
+    check_attributes(&statements)?;
+    infer_functions(&statements, &mut functions)?;
+
+    // The top level works like a function of its own, returning the exit code
+    let toplevel: Vec<&Statement> = statements.iter()
+        .filter(|statement| !matches!(statement, Statement::FunctionAssignment { .. } | Statement::Extern { .. } | Statement::Attribute { .. }))
+        .collect();
+    let mut scope: Scope = HashMap::new();
+    let mut returns = vec![];
+    for statement in toplevel {
+        check_block(std::slice::from_ref(statement), &mut scope, &functions, &mut returns)?;
+    }
+    if returns.iter().any(|ty| *ty != Type::Int) {
+        return Err("Top level can only return an i64 (the exit code)".to_string());
+    }
 
-    if SEMAN_DEBUG_PRINTS {println!("- - - Sem Analysis done!")}
-}
\ No newline at end of file
+    if debug_prints(SEMAN_DEBUG_PRINTS) {eprintln!("Functions:\n{:#?}", functions)}
+    if debug_prints(SEMAN_DEBUG_PRINTS) {eprintln!("- - - Sem Analysis done!")}
+    Ok(Analysis { statements, functions })
+}
@@ -0,0 +1,35 @@
+use std::sync::Mutex;
+
+use crate::lexer::Location;
+
+// Every file that goes into a compilation gets an ID, which is what `Location`s carry around.
+// It's global so error messages deep in the compiler can name the file without everything
+// having to pass the map along.
+
+pub type FileId = usize;
+
+/// Paths of the files, indexed by ID
+static FILES: Mutex<Vec<String>> = Mutex::new(vec![]);
+
+/// Registers a file, the first one added gets ID 0. A path that's already there keeps its
+/// ID, so compiling the same files over and over (the language server on every edit) doesn't
+/// grow the map
+pub fn add_file(path: &str) -> FileId {
+    let mut files = FILES.lock().unwrap();
+    if let Some(id) = files.iter().position(|file| file == path) {return id}
+    files.push(path.to_string());
+    files.len() - 1
+}
+
+pub fn file_path(id: FileId) -> Option<String> {
+    FILES.lock().unwrap().get(id).cloned()
+}
+
+/// `LINE:CHARACTER in path` for error messages, just `LINE:CHARACTER` when the file isn't
+/// known (REPL input)
+pub fn position(location: Location) -> String {
+    match file_path(location.2) {
+        Some(path) => format!("{}:{} in {}", location.0, location.1, path),
+        None => format!("{}:{}", location.0, location.1),
+    }
+}
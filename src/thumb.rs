@@ -0,0 +1,1481 @@
+use crate::backend::*;
+use crate::compiler_settings::*;
+use crate::ffi::{CType, Extern};
+use crate::intrinsics::INTRINSICS;
+use crate::ir::{symbol, BinaryOp, Function, Instruction, Module, Operand, Terminator};
+use crate::parser::expand_asm;
+use crate::regalloc::{self, operand_type, Home, Register, Registers};
+use crate::seman::Type;
+
+// Thumb backend for the Cortex-M boards, with the AAPCS calling convention. Thumb-2 for
+// ARMv7-M (M3, M4, M7) and the 16-bit subset ARMv6-M (M0, M0+) has, see Profile. Same IR
+// as x86.rs and riscv.rs, virtual registers go wherever regalloc.rs puts them (r5-r11, or a
+// spill slot) and variables live on the stack. Instructions work on r0-r4 and r12, values
+// get moved there and back.
+// Galvan's i64s are register pairs (low word first, in an even register pair when passed
+// around), a str is one register. 64-bit division and printing numbers go through small
+// runtime routines, everything else is inline.
+// The output is GNU assembler in unified syntax for `-mcpu=cortex-m3 -mthumb`. Linux programs
+// use the ARM EABI syscalls so qemu-arm runs them, and thumb_encoder.rs and thumb_sim.rs run
+// the same program without any ARM tools around.
+// Freestanding programs get a vector table: the initial stack pointer, the entry point as
+// reset handler and every `#[interrupt]` function in the slot of the exception it's named
+// after. Cortex-M hardware saves the caller-saved registers itself, so interrupt handlers are
+// plain functions.
+// ARMv6-M only has the 16-bit instructions (plus bl) and most of those only reach r0-r7. So
+// there the register allocator gets r5-r7, constants are built with shifts and adds, symbol
+// addresses come from literal pools (`ldr rd, =symbol` and `.ltorg`), multiplication is a
+// runtime routine out of 32-bit muls, and branches that end up too far for the short forms
+// become a bl.
+
+//
+// STRUCTS
+//
+
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+#[derive(PartialEq)]
+#[allow(dead_code)] // The order is the hardware numbering, so they all stay even if unused
+pub enum Reg {
+    R0, R1, R2, R3, R4, R5, R6, R7, R8, R9, R10, R11, R12, Sp, Lr, Pc,
+}
+impl Reg {
+    const ALL: [Reg; 16] = [Reg::R0, Reg::R1, Reg::R2, Reg::R3, Reg::R4, Reg::R5, Reg::R6, Reg::R7,
+        Reg::R8, Reg::R9, Reg::R10, Reg::R11, Reg::R12, Reg::Sp, Reg::Lr, Reg::Pc];
+
+    /// r0-r12, sp, lr, pc, and the ip alias of r12
+    pub fn from_name(name: &str) -> Option<Reg> {
+        if name == "ip" {return Some(Reg::R12)}
+        if let Some(number) = name.strip_prefix('r').and_then(|number| number.parse::<usize>().ok()) {
+            return Reg::ALL.get(number).copied();
+        }
+        Reg::ALL.into_iter().find(|reg| reg.name() == name)
+    }
+
+    /// Hardware register number, used by the encoder
+    pub fn number(&self) -> u32 {
+        *self as u32
+    }
+
+    /// r0-r7, the only ones most 16-bit instructions can use
+    pub fn is_low(&self) -> bool {
+        self.number() < 8
+    }
+
+    pub fn name(&self) -> &'static str {
+        ["r0", "r1", "r2", "r3", "r4", "r5", "r6", "r7", "r8", "r9", "r10", "r11", "r12", "sp", "lr", "pc"][self.number() as usize]
+    }
+}
+
+/// Which Cortex-M architecture the code is for
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+#[derive(PartialEq)]
+pub enum Profile {
+    /// ARMv7-M, all of Thumb-2
+    V7m,
+    /// ARMv6-M, the 16-bit instructions and bl
+    V6m,
+}
+
+/// Registers arguments are passed in, in order
+const ARGUMENT_REGISTERS: [Reg; 4] = [Reg::R0, Reg::R1, Reg::R2, Reg::R3];
+
+/// What the register allocator hands out. r0-r3 are the arguments and r4 and r12 are scratch,
+/// which leaves only callee-saved ones. ARMv6-M can only push and move the low ones.
+const POOL: [Reg; 7] = [Reg::R5, Reg::R6, Reg::R7, Reg::R8, Reg::R9, Reg::R10, Reg::R11];
+const POOL_V6M: [Reg; 3] = [Reg::R5, Reg::R6, Reg::R7];
+
+/// What inline assembly operands go into. r4 is saved by every function anyway, the rest
+/// are caller-saved. ARMv6-M leaves r12 out, its instructions can't use it.
+const ASM_REGISTERS: [Reg; 6] = [Reg::R0, Reg::R1, Reg::R2, Reg::R3, Reg::R4, Reg::R12];
+const ASM_REGISTERS_V6M: [Reg; 5] = [Reg::R0, Reg::R1, Reg::R2, Reg::R3, Reg::R4];
+
+/// Register-register data processing, always the flag setting version (adds, ...)
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+#[derive(PartialEq)]
+pub enum Op {
+    Add, Adc, Sub, Sbc, Orr, Eor,
+}
+impl Op {
+    pub fn name(&self) -> &'static str {
+        match self {Op::Add => "adds", Op::Adc => "adcs", Op::Sub => "subs", Op::Sbc => "sbcs", Op::Orr => "orrs", Op::Eor => "eors"}
+    }
+}
+
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+#[derive(PartialEq)]
+pub enum Shift {
+    Lsl, Lsr, Asr,
+}
+impl Shift {
+    pub fn name(&self) -> &'static str {
+        match self {Shift::Lsl => "lsls", Shift::Lsr => "lsrs", Shift::Asr => "asrs"}
+    }
+}
+
+/// Sign or zero extension of the low byte or half
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+#[derive(PartialEq)]
+pub enum Extend {
+    Sxtb, Uxtb, Sxth, Uxth,
+}
+impl Extend {
+    pub fn name(&self) -> &'static str {
+        match self {Extend::Sxtb => "sxtb", Extend::Uxtb => "uxtb", Extend::Sxth => "sxth", Extend::Uxth => "uxth"}
+    }
+}
+
+/// Size of a memory access, loads are zero extending
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+#[derive(PartialEq)]
+pub enum Width {
+    Byte, Word,
+}
+impl Width {
+    fn suffix(&self) -> &'static str {
+        match self {Width::Byte => "b", Width::Word => ""}
+    }
+}
+
+/// Condition codes, in the hardware numbering
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+#[derive(PartialEq)]
+#[allow(dead_code)]
+pub enum Cond {
+    Eq, Ne, Hs, Lo, Mi, Pl, Vs, Vc, Hi, Ls, Ge, Lt, Gt, Le,
+}
+impl Cond {
+    pub fn name(&self) -> &'static str {
+        ["eq", "ne", "hs", "lo", "mi", "pl", "vs", "vc", "hi", "ls", "ge", "lt", "gt", "le"][*self as usize]
+    }
+
+    /// The condition that holds when this one doesn't, they come in pairs
+    pub fn inverse(&self) -> Cond {
+        const ALL: [Cond; 14] = [Cond::Eq, Cond::Ne, Cond::Hs, Cond::Lo, Cond::Mi, Cond::Pl, Cond::Vs, Cond::Vc,
+            Cond::Hi, Cond::Ls, Cond::Ge, Cond::Lt, Cond::Gt, Cond::Le];
+        ALL[*self as usize ^ 1]
+    }
+}
+
+/// One assembly instruction (or label). Destination comes first, like in the assembly.
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+pub enum Inst {
+    Label(String),
+    /// `movs rd, #imm`, low registers only, sets the flags
+    Movs(Reg, u8),
+    /// `movw rd, #imm`, clears the upper half
+    Movw(Reg, u16),
+    /// `movt rd, #imm`, sets the upper half
+    Movt(Reg, u16),
+    /// movw/movt with the lower/upper half of a symbol's address
+    MovwSymbol(Reg, String),
+    MovtSymbol(Reg, String),
+    /// `mov rd, rm`, any registers, leaves the flags alone
+    Mov(Reg, Reg),
+    /// `rd = rn <op> rm`
+    Op(Op, Reg, Reg, Reg),
+    /// `rd = rn + imm` (or minus). Sets the flags when it fits a 16-bit adds/subs, that is
+    /// with low registers and a small immediate.
+    AddImm(Reg, Reg, i32),
+    /// `add rdn, rm`, any registers, leaves the flags alone
+    AddReg(Reg, Reg),
+    /// `rd = rm <shift> imm`
+    Shift(Shift, Reg, Reg, u32),
+    /// `rsbs rd, rn, #0`
+    Negs(Reg, Reg),
+    /// `umull lo, hi, rn, rm`
+    Umull(Reg, Reg, Reg, Reg),
+    /// `mla rd, rn, rm, ra`, rd = rn * rm + ra
+    Mla(Reg, Reg, Reg, Reg),
+    Cmp(Reg, Reg),
+    /// `cmp rn, #imm`, low registers only
+    CmpImm(Reg, u8),
+    Extend(Extend, Reg, Reg),
+    /// `rt = [base + offset]`
+    Load(Width, Reg, Reg, i32),
+    /// `[base + offset] = rt`
+    Store(Width, Reg, Reg, i32),
+    Push(Vec<Reg>),
+    Pop(Vec<Reg>),
+    Branch(String),
+    BranchCond(Cond, String),
+    /// `bl symbol`
+    Call(String),
+    /// `bx rm`
+    Bx(Reg),
+    Svc(u8),
+    /// Permanently undefined, traps
+    Udf(u8),
+    /// A data word with a symbol's address (or 0), for the vector table
+    Word(Option<String>),
+    /// `muls rd, rn, rd`, the low 32 bits of rd * rn (ARMv6-M has no umull)
+    Muls(Reg, Reg),
+    /// `ldr rd, =symbol`, the address from the next literal pool
+    LoadLiteral(Reg, String),
+    /// `.ltorg`, the literal pool for the LoadLiterals since the last one
+    Pool,
+    /// Inline assembly, the template with the register of every operand
+    Asm {template: String, operands: Vec<(String, Reg)>},
+}
+
+/// Everything that ends up in the output file
+#[derive(Debug)]
+pub struct Program {
+    pub text: Vec<Inst>,
+    /// Label -> bytes (strings get their NUL terminator included)
+    pub rodata: Vec<(String, Vec<u8>)>,
+    /// Symbols visible outside the object
+    pub globals: Vec<String>,
+    /// Code of `#[section(...)]` functions by section name, the vector table comes first
+    pub sections: Vec<(String, Vec<Inst>)>,
+    /// Where an executable starts
+    pub entry: String,
+    /// What the assembler and the encoder get told the CPU is
+    pub profile: Profile,
+}
+
+//
+// FUNCTIONS
+//
+
+/// Registers a value of the type takes up
+impl Register for Reg {
+    fn name(&self) -> &'static str {
+        Reg::name(self)
+    }
+}
+
+const REGISTERS: Registers<Reg> = Registers { pool: &POOL, callee_saved: &POOL, words: words_32, division_calls: true };
+const REGISTERS_V6M: Registers<Reg> = Registers { pool: &POOL_V6M, callee_saved: &POOL_V6M, words: words_32, division_calls: true };
+
+impl Profile {
+    fn registers(self) -> &'static Registers<Reg> {
+        match self {Profile::V7m => &REGISTERS, Profile::V6m => &REGISTERS_V6M}
+    }
+
+    fn asm_registers(self) -> &'static [Reg] {
+        match self {Profile::V7m => &ASM_REGISTERS, Profile::V6m => &ASM_REGISTERS_V6M}
+    }
+
+    /// The system exceptions, and how many external interrupts there can be
+    fn exceptions(self) -> (&'static [(&'static str, usize)], usize) {
+        match self {Profile::V7m => (&EXCEPTIONS, MAX_IRQS), Profile::V6m => (&EXCEPTIONS_V6M, MAX_IRQS_V6M)}
+    }
+}
+
+/// `--emit=regalloc`
+pub fn dump_allocation(module: &Module, profile: Profile) -> String {
+    regalloc::dump(module, profile.registers())
+}
+
+/// (r0:r1 / r2:r3), the quotient in r0:r1, traps with udf on division by zero
+pub const DIV64: &str = "__gv_div64";
+/// (r0:r1 * r2:r3), the low 64 bits in r0:r1. Only ARMv6-M, which has no umull.
+const MUL64: &str = "__gv_mul64";
+/// Unsigned (r0:r1 / r2:r3), the quotient in r0:r1 and the remainder in r2:r3
+const UDIVMOD64: &str = "__gv_udivmod64";
+
+/// Start of the vector table, the simulator boots from it like the hardware does
+pub const VECTOR_TABLE: &str = "__vector_table";
+const VECTOR_TABLE_SECTION: &str = ".vector_table";
+/// What the vector table slots without an `#[interrupt]` function point to, a hang
+const DEFAULT_HANDLER: &str = "__gv_default_handler";
+/// `#[interrupt]` names of the Cortex-M system exceptions and their vector table slot.
+/// External interrupts are irq0, irq1, ... from slot 16 on.
+const EXCEPTIONS: [(&str, usize); 9] = [("nmi", 2), ("hard_fault", 3), ("mem_manage", 4), ("bus_fault", 5),
+    ("usage_fault", 6), ("svcall", 11), ("debug_monitor", 12), ("pendsv", 14), ("systick", 15)];
+/// The ones ARMv6-M has
+const EXCEPTIONS_V6M: [(&str, usize); 5] = [("nmi", 2), ("hard_fault", 3), ("svcall", 11), ("pendsv", 14), ("systick", 15)];
+/// ARMv7-M has room for up to 496 external interrupts, ARMv6-M for 32
+const MAX_IRQS: usize = 496;
+const MAX_IRQS_V6M: usize = 32;
+
+// Linux ARM EABI syscall numbers, the number goes in r7
+const SYS_EXIT: u8 = 1;
+const SYS_READ: u8 = 3;
+const SYS_WRITE: u8 = 4;
+const SYS_OPEN: u8 = 5;
+const SYS_CLOSE: u8 = 6;
+const SYS_BRK: u8 = 45;
+
+/// A 32-bit constant into a register, movs if it's small enough, otherwise movw and movt.
+/// ARMv6-M has neither of those, there it's movs and negs for small negative numbers and
+/// otherwise a byte at a time with shifts and adds (into a low register).
+fn mov32(out: &mut Vec<Inst>, profile: Profile, reg: Reg, value: i32) {
+    if reg.is_low() && (0..256).contains(&value) {
+        out.push(Inst::Movs(reg, value as u8));
+        return;
+    }
+    if profile == Profile::V6m {
+        if (-255..0).contains(&value) {
+            out.extend([Inst::Movs(reg, -value as u8), Inst::Negs(reg, reg)]);
+            return;
+        }
+        let bytes = value.to_be_bytes();
+        let first = bytes.iter().position(|byte| *byte != 0).unwrap();
+        out.push(Inst::Movs(reg, bytes[first]));
+        let mut shift = 0;
+        for byte in &bytes[first + 1..] {
+            shift += 8;
+            if *byte == 0 {continue}
+            out.extend([Inst::Shift(Shift::Lsl, reg, reg, shift), Inst::AddImm(reg, reg, *byte as i32)]);
+            shift = 0;
+        }
+        if shift != 0 {out.push(Inst::Shift(Shift::Lsl, reg, reg, shift))}
+        return;
+    }
+    out.push(Inst::Movw(reg, value as u16));
+    if (value as u32) >> 16 != 0 {out.push(Inst::Movt(reg, ((value as u32) >> 16) as u16))}
+}
+
+/// A symbol's address into a register, movw and movt or a load from a literal pool
+fn symbol_address(out: &mut Vec<Inst>, profile: Profile, reg: Reg, symbol: &str) {
+    match profile {
+        Profile::V7m => out.extend([Inst::MovwSymbol(reg, symbol.to_string()), Inst::MovtSymbol(reg, symbol.to_string())]),
+        Profile::V6m => out.push(Inst::LoadLiteral(reg, symbol.to_string())),
+    }
+}
+
+/// Where one word of an argument goes
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+#[derive(PartialEq)]
+enum ArgPlace {
+    Reg(Reg),
+    /// Offset from the stack pointer at the call
+    Stack(i32),
+}
+
+/// The AAPCS calling convention. Arguments (by number of words) go into r0-r3, an i64 needs
+/// an even register pair and skips r1 (or r3) to get one. Once something doesn't fit it and
+/// everything after it goes on the stack, i64s 8-byte aligned. Variadic arguments work the
+/// same. Returns where every word goes, and the stack space that takes (a multiple of 8).
+fn assign_arguments(arguments: &[usize]) -> (Vec<Vec<ArgPlace>>, i32) {
+    let mut next = 0;
+    let mut stack = 0;
+    let mut places = vec![];
+    for words in arguments {
+        if *words == 2 && next % 2 == 1 {next += 1}
+        if next + words <= ARGUMENT_REGISTERS.len() {
+            places.push((next..next + words).map(|index| ArgPlace::Reg(ARGUMENT_REGISTERS[index])).collect());
+            next += words;
+            continue;
+        }
+        next = ARGUMENT_REGISTERS.len();
+        if *words == 2 {stack = (stack + 7) / 8 * 8}
+        places.push((0..*words).map(|word| ArgPlace::Stack(stack + 4 * word as i32)).collect());
+        stack += 4 * *words as i32;
+    }
+    (places, (stack + 7) / 8 * 8)
+}
+
+/// Words of every argument of a call
+fn call_arguments(function: &Function, args: &[Operand], external: Option<&Extern>) -> Vec<usize> {
+    args.iter().enumerate().map(|(index, arg)| match external.and_then(|external| external.parameters.get(index)) {
+        Some((_, CType::Int { bits: 64, .. })) => 2,
+        Some(_) => 1,
+        None => words_32(operand_type(function, arg)),
+    }).collect()
+}
+
+/// Stack layout of one function. r4, the callee-saved registers the function uses and lr get
+/// pushed on entry, then sp goes down by `size`: outgoing stack arguments are at the bottom,
+/// then 8 bytes for every local (low word first) and after that the spill slots. Everything
+/// is addressed from sp.
+struct Frame {
+    size: i32,
+    outgoing: i32,
+    spill_base: i32,
+    /// Pushed along with r4 and lr
+    saved: Vec<Reg>,
+}
+impl Frame {
+    fn new(function: &Function, allocation: &regalloc::Allocation<Reg>, outgoing: i32, profile: Profile) -> Frame {
+        let saved = allocation.saved(profile.registers());
+        let spill_base = outgoing + 8 * function.locals.len() as i32;
+        let size = spill_base + 4 * allocation.spills as i32;
+        // Whatever the pushes leave over an 8-byte boundary goes on top of this
+        let size = (size + 7) / 8 * 8 + 4 * (saved.len() as i32 % 2);
+        Frame { size, outgoing, spill_base, saved }
+    }
+
+    fn local(&self, local: usize) -> i32 {
+        self.outgoing + 8 * local as i32
+    }
+
+    fn spill(&self, slot: usize) -> i32 {
+        self.spill_base + 4 * slot as i32
+    }
+
+    /// Where the caller's stack arguments start, above everything pushed on entry
+    fn incoming(&self) -> i32 {
+        self.size + 4 * (self.saved.len() as i32 + 2)
+    }
+
+    /// What the prologue pushes (and the epilogue pops, with pc instead of lr)
+    fn pushed(&self, last: Reg) -> Vec<Reg> {
+        let mut pushed = vec![Reg::R4];
+        pushed.extend(&self.saved);
+        pushed.push(last);
+        pushed
+    }
+}
+
+/// Generates one function, `homes` is where the register allocator put every virtual register
+struct Generator<'a> {
+    out: &'a mut Vec<Inst>,
+    function: &'a Function,
+    module: &'a Module,
+    homes: Vec<Vec<Home<Reg>>>,
+    frame: Frame,
+    /// What got pushed since the prologue (around inline assembly), frame offsets move up by it
+    bias: i32,
+    /// For the labels comparisons need
+    labels: usize,
+    profile: Profile,
+}
+impl Generator<'_> {
+    /// Base register and offset for `[sp + offset]`, through `base` when it's too far for an
+    /// immediate offset (4095 bytes, on ARMv6-M 1020)
+    fn address(&mut self, offset: i32, base: Reg) -> (Reg, i32) {
+        let offset = offset + self.bias;
+        let reach = if self.profile == Profile::V6m {1024} else {4096};
+        if offset < reach {return (Reg::Sp, offset)}
+        mov32(self.out, self.profile, base, offset);
+        self.out.push(Inst::AddReg(base, Reg::Sp));
+        (base, 0)
+    }
+
+    /// Through r12, on ARMv6-M the address goes into the register itself
+    fn load(&mut self, reg: Reg, offset: i32) {
+        let base = if self.profile == Profile::V6m {reg} else {Reg::R12};
+        let (base, offset) = self.address(offset, base);
+        self.out.push(Inst::Load(Width::Word, reg, base, offset));
+    }
+
+    /// Through r12, on ARMv6-M that takes another low register and what's in it waits in r12
+    fn store(&mut self, reg: Reg, offset: i32) {
+        let far = self.profile == Profile::V6m && offset + self.bias >= 1024;
+        let base = match (self.profile, reg) {(Profile::V7m, _) => Reg::R12, (_, Reg::R0) => Reg::R1, _ => Reg::R0};
+        if far {self.out.push(Inst::Mov(Reg::R12, base))}
+        let (address, offset) = self.address(offset, base);
+        self.out.push(Inst::Store(Width::Word, reg, address, offset));
+        if far {self.out.push(Inst::Mov(base, Reg::R12))}
+    }
+
+    /// Word `word` of the operand into `reg`, the missing upper word of a str is 0
+    fn read_into(&mut self, operand: &Operand, word: usize, reg: Reg) {
+        match operand {
+            Operand::Constant(value) => {
+                let value = if word == 0 {*value as i32} else {(*value >> 32) as i32};
+                mov32(self.out, self.profile, reg, value);
+            }
+            Operand::Register(register) => match self.homes[*register].get(word) {
+                Some(Home::Reg(home)) => self.out.push(Inst::Mov(reg, *home)),
+                Some(Home::Spill(slot)) => {
+                    let offset = self.frame.spill(*slot);
+                    self.load(reg, offset);
+                }
+                None => mov32(self.out, self.profile, reg, 0),
+            },
+        }
+    }
+
+    /// `reg` into word `word` of the virtual register `dest`, nowhere if it's never read
+    fn write(&mut self, dest: usize, word: usize, reg: Reg) {
+        match self.homes[dest].get(word) {
+            Some(Home::Reg(home)) => self.out.push(Inst::Mov(*home, reg)),
+            Some(Home::Spill(slot)) => {
+                let offset = self.frame.spill(*slot);
+                self.store(reg, offset);
+            }
+            None => {}
+        }
+    }
+
+    /// Writes the r0:r1 result into `dest`, only r0 for a str
+    fn write_result(&mut self, dest: usize) {
+        let count = words_32(self.function.registers[dest]);
+        for (word, reg) in ARGUMENT_REGISTERS.into_iter().take(count).enumerate() {
+            self.write(dest, word, reg);
+        }
+    }
+
+    fn local_words(&self, local: usize) -> usize {
+        words_32(self.function.locals[local].ty)
+    }
+
+    fn label(&mut self) -> String {
+        self.labels += 1;
+        format!(".L{}_{}", symbol(&self.function.name), self.labels)
+    }
+
+    fn binary(&mut self, dest: usize, op: BinaryOp, left: &Operand, right: &Operand) {
+        // Comparisons are lt and ge with the operands swapped
+        let (left, right) = match op {BinaryOp::Gt | BinaryOp::Le => (right, left), _ => (left, right)};
+        self.read_into(left, 0, Reg::R0);
+        self.read_into(left, 1, Reg::R1);
+        self.read_into(right, 0, Reg::R2);
+        self.read_into(right, 1, Reg::R3);
+        let (r0, r1, r2, r3, r4) = (Reg::R0, Reg::R1, Reg::R2, Reg::R3, Reg::R4);
+        // Comparisons set r4 to 1 and clear it again unless the condition holds
+        let condition = match op {
+            BinaryOp::Add => {
+                self.out.extend([Inst::Op(Op::Add, r0, r0, r2), Inst::Op(Op::Adc, r1, r1, r3)]);
+                None
+            }
+            BinaryOp::Sub => {
+                self.out.extend([Inst::Op(Op::Sub, r0, r0, r2), Inst::Op(Op::Sbc, r1, r1, r3)]);
+                None
+            }
+            BinaryOp::Mul if self.profile == Profile::V6m => {
+                self.out.push(Inst::Call(MUL64.to_string()));
+                None
+            }
+            // Only the low 64 bits: r0*r2 in full, plus the cross products in the upper word
+            BinaryOp::Mul => {
+                self.out.extend([
+                    Inst::Umull(Reg::R12, r4, r0, r2),
+                    Inst::Mla(r4, r0, r3, r4),
+                    Inst::Mla(r4, r1, r2, r4),
+                    Inst::Mov(r0, Reg::R12),
+                    Inst::Mov(r1, r4),
+                ]);
+                None
+            }
+            BinaryOp::Div => {
+                self.out.push(Inst::Call(DIV64.to_string()));
+                None
+            }
+            BinaryOp::Eq | BinaryOp::Ne => {
+                self.out.extend([
+                    Inst::Movs(r4, 1),
+                    Inst::Op(Op::Eor, r0, r0, r2),
+                    Inst::Op(Op::Eor, r1, r1, r3),
+                    Inst::Op(Op::Orr, r0, r0, r1),
+                ]);
+                Some(if op == BinaryOp::Eq {Cond::Eq} else {Cond::Ne})
+            }
+            // The 64-bit subtraction sets N and V right for a signed compare (Z isn't)
+            _ => {
+                self.out.extend([
+                    Inst::Movs(r4, 1),
+                    Inst::Cmp(r0, r2),
+                    Inst::Op(Op::Sbc, r1, r1, r3),
+                ]);
+                Some(if matches!(op, BinaryOp::Lt | BinaryOp::Gt) {Cond::Lt} else {Cond::Ge})
+            }
+        };
+        if let Some(condition) = condition {
+            let holds = self.label();
+            self.out.extend([
+                Inst::BranchCond(condition, holds.clone()),
+                Inst::Movs(r4, 0),
+                Inst::Label(holds),
+                Inst::Mov(r0, r4),
+                Inst::Movs(r1, 0),
+            ]);
+        }
+        self.write_result(dest);
+    }
+
+    /// `print(a, b, ...)` prints every argument separated by spaces, and a newline at the end
+    fn print(&mut self, args: &[Operand]) {
+        for (index, arg) in args.iter().enumerate() {
+            if index > 0 {
+                self.out.push(Inst::Movs(Reg::R0, b' '));
+                self.out.push(Inst::Call(PRINT_CHAR.to_string()));
+            }
+            self.read_into(arg, 0, Reg::R0);
+            if operand_type(self.function, arg) == Type::Str {
+                self.out.push(Inst::Call(PRINT_STR.to_string()));
+            } else {
+                self.read_into(arg, 1, Reg::R1);
+                self.out.push(Inst::Call(PRINT_INT.to_string()));
+            }
+        }
+        self.out.push(Inst::Movs(Reg::R0, b'\n'));
+        self.out.push(Inst::Call(PRINT_CHAR.to_string()));
+    }
+
+    /// A call to a Galvan function, or to a C one when `external` is given
+    fn call(&mut self, target: &str, args: &[Operand], dest: Option<usize>, external: Option<&Extern>) {
+        let (places, _) = assign_arguments(&call_arguments(self.function, args, external));
+        for (index, (arg, place)) in args.iter().zip(&places).enumerate() {
+            let c_type = external.and_then(|external| external.parameters.get(index)).map(|(_, ty)| ty);
+            for (word, part) in place.iter().enumerate() {
+                let reg = match part {
+                    ArgPlace::Reg(reg) => *reg,
+                    ArgPlace::Stack(_) => Reg::R4,
+                };
+                self.read_into(arg, word, reg);
+                if place.len() == 1 {narrow(self.out, reg, c_type)}
+                if let ArgPlace::Stack(offset) = part {self.store(reg, *offset)}
+            }
+        }
+        match external {
+            Some(external) => self.out.push(Inst::Call(external.symbol.clone())),
+            None => self.out.push(Inst::Call(symbol(target))),
+        }
+        let Some(dest) = dest else {return};
+        if let Some(external) = external {
+            match &external.return_type {
+                CType::Int { bits: 64, .. } => {}
+                CType::Int { bits, signed } => {
+                    narrow(self.out, Reg::R0, Some(&CType::Int { bits: *bits, signed: *signed }));
+                    if *signed {self.out.push(Inst::Shift(Shift::Asr, Reg::R1, Reg::R0, 31))} else {self.out.push(Inst::Movs(Reg::R1, 0))}
+                }
+                // Addresses are unsigned
+                _ => self.out.push(Inst::Movs(Reg::R1, 0)),
+            }
+        }
+        self.write_result(dest);
+    }
+
+    /// Inline assembly: inputs go into free registers (their low word, registers are 32 bits
+    /// here), outputs get their own ones and are sign extended into their variable afterwards.
+    /// Clobbered callee-saved registers get pushed around it, the caller-saved ones don't hold
+    /// anything between instructions anyway.
+    fn asm(&mut self, template: &str, inputs: &[(String, Operand)], outputs: &[(String, usize)], clobbers: &[String]) -> Result<(), String> {
+        let asm_registers = self.profile.asm_registers();
+        let mut free = asm_registers.to_vec();
+        let mut saved = vec![];
+        for clobber in clobbers {
+            if clobber == "cc" || clobber == "memory" {continue}
+            match Reg::from_name(clobber) {
+                Some(reg @ (Reg::Sp | Reg::Pc)) => return Err(format!("Inline assembly can't clobber {}", reg.name())),
+                Some(reg) if asm_registers.contains(&reg) => free.retain(|free| *free != reg),
+                // Saved in the prologue
+                Some(Reg::Lr) => {}
+                Some(reg) if self.profile == Profile::V6m && !reg.is_low() => {
+                    return Err(format!("Inline assembly can't clobber {} on ARMv6-M, push and pop only reach r0-r7", reg.name()));
+                }
+                Some(reg) => if !saved.contains(&reg) {saved.push(reg)},
+                None => return Err(format!("Unknown register '{}' in the clobber list of inline assembly", clobber)),
+            }
+        }
+        if inputs.len() + outputs.len() > free.len() {
+            return Err(format!("Inline assembly has {} operands, but only {} registers are left for them", inputs.len() + outputs.len(), free.len()));
+        }
+
+        // push wants them in order, and sp stays 8-byte aligned
+        saved.sort_by_key(|reg| reg.number());
+        if saved.len() % 2 == 1 {saved.insert(0, Reg::R4)}
+        if !saved.is_empty() {
+            self.out.push(Inst::Push(saved.clone()));
+            self.bias = 4 * saved.len() as i32;
+        }
+        let mut operands = vec![];
+        for ((name, value), reg) in inputs.iter().zip(&free) {
+            self.read_into(value, 0, *reg);
+            operands.push((name.clone(), *reg));
+        }
+        let output_registers = free[inputs.len()..].to_vec();
+        for ((name, _), reg) in outputs.iter().zip(&output_registers) {
+            operands.push((name.clone(), *reg));
+        }
+        self.out.push(Inst::Asm { template: template.to_string(), operands });
+        for ((_, local), reg) in outputs.iter().zip(&output_registers) {
+            let offset = self.frame.local(*local);
+            self.store(*reg, offset);
+            self.out.push(Inst::Shift(Shift::Asr, *reg, *reg, 31));
+            self.store(*reg, offset + 4);
+        }
+        if !saved.is_empty() {
+            self.out.push(Inst::Pop(saved));
+            self.bias = 0;
+        }
+        Ok(())
+    }
+
+    /// `sp += amount`, through r12 if it doesn't fit an immediate. ARMv6-M's immediates only
+    /// go up to 508 and it can't add r12, but r4 is free in the prologue and epilogue.
+    fn move_sp(&mut self, amount: i32) {
+        if amount == 0 {return}
+        if add_imm_is_short(Reg::Sp, Reg::Sp, amount) || (self.profile == Profile::V7m && amount.abs() < 4096) {
+            self.out.push(Inst::AddImm(Reg::Sp, Reg::Sp, amount));
+        } else {
+            let scratch = if self.profile == Profile::V6m {Reg::R4} else {Reg::R12};
+            mov32(self.out, self.profile, scratch, amount);
+            self.out.push(Inst::AddReg(Reg::Sp, scratch));
+        }
+    }
+
+    fn prologue(&mut self) {
+        self.out.push(Inst::Push(self.frame.pushed(Reg::Lr)));
+        self.move_sp(-self.frame.size);
+
+        // Parameters into their locals, the ones on the stack are at the top of the caller's frame
+        let parameters: Vec<usize> = self.function.locals[..self.function.parameters].iter().map(|local| words_32(local.ty)).collect();
+        let (places, _) = assign_arguments(&parameters);
+        for (local, place) in places.iter().enumerate() {
+            for (word, place) in place.iter().enumerate() {
+                let reg = match place {
+                    ArgPlace::Reg(reg) => *reg,
+                    ArgPlace::Stack(stack) => {
+                        let offset = self.frame.incoming() + stack;
+                        self.load(Reg::R4, offset);
+                        Reg::R4
+                    }
+                };
+                let offset = self.frame.local(local) + 4 * word as i32;
+                self.store(reg, offset);
+            }
+        }
+    }
+
+    fn epilogue(&mut self) {
+        self.move_sp(self.frame.size);
+        self.out.push(Inst::Pop(self.frame.pushed(Reg::Pc)));
+    }
+
+    fn generate(&mut self) -> Result<(), String> {
+        let function = self.function;
+        self.out.push(Inst::Label(symbol(&function.name)));
+        self.prologue();
+
+        for (index, block) in function.blocks.iter().enumerate() {
+            self.out.push(Inst::Label(block_label(function, index)));
+            for instruction in &block.instructions {
+                match instruction {
+                    Instruction::Copy { dest, value } => {
+                        for word in 0..words_32(function.registers[*dest]) {
+                            self.read_into(value, word, Reg::R0);
+                            self.write(*dest, word, Reg::R0);
+                        }
+                    }
+                    Instruction::StringAddress { dest, index } => {
+                        symbol_address(self.out, self.profile, Reg::R0, &string_label(*index));
+                        self.write(*dest, 0, Reg::R0);
+                    }
+                    Instruction::Binary { dest, op, left, right } => self.binary(*dest, *op, left, right),
+                    Instruction::Load { dest, local } => {
+                        for word in 0..self.local_words(*local) {
+                            let offset = self.frame.local(*local) + 4 * word as i32;
+                            self.load(Reg::R0, offset);
+                            self.write(*dest, word, Reg::R0);
+                        }
+                    }
+                    Instruction::Store { local, value } => {
+                        for word in 0..self.local_words(*local) {
+                            self.read_into(value, word, Reg::R0);
+                            let offset = self.frame.local(*local) + 4 * word as i32;
+                            self.store(Reg::R0, offset);
+                        }
+                    }
+                    Instruction::Call { dest, function: target, args } => {
+                        if self.module.uses_c() && ["print", "__sys_write", "__sys_write_str"].contains(&target.as_str()) {
+                            // fflush(NULL), so whatever C code printed comes out first
+                            self.out.push(Inst::Movs(Reg::R0, 0));
+                            self.out.push(Inst::Call("fflush".to_string()));
+                        }
+                        if target == "print" {self.print(args)}
+                        else {self.call(target, args, *dest, self.module.external(target))}
+                    }
+                    Instruction::Asm { template, inputs, outputs, clobbers, .. } => {
+                        self.asm(template, inputs, outputs, clobbers)
+                            .map_err(|error| format!("{} (in function '{}')", error, function.name))?;
+                    }
+                    // Only the x86 backend writes debug info
+                    Instruction::Line { .. } => {}
+                }
+            }
+            let next = index + 1;
+            match &block.terminator {
+                Terminator::Jump(target) => if *target != next {self.out.push(Inst::Branch(block_label(function, *target)))},
+                Terminator::Branch { condition: Operand::Constant(value), then_block, else_block } => {
+                    let target = if *value != 0 {then_block} else {else_block};
+                    if *target != next {self.out.push(Inst::Branch(block_label(function, *target)))}
+                }
+                Terminator::Branch { condition, then_block, else_block } => {
+                    self.read_into(condition, 0, Reg::R0);
+                    self.read_into(condition, 1, Reg::R1);
+                    self.out.push(Inst::Op(Op::Orr, Reg::R0, Reg::R0, Reg::R1));
+                    if *then_block == next {
+                        self.out.push(Inst::BranchCond(Cond::Eq, block_label(function, *else_block)));
+                    } else {
+                        self.out.push(Inst::BranchCond(Cond::Ne, block_label(function, *then_block)));
+                        if *else_block != next {self.out.push(Inst::Branch(block_label(function, *else_block)))}
+                    }
+                }
+                Terminator::Return(value) => {
+                    if let Some(value) = value {
+                        for (word, reg) in ARGUMENT_REGISTERS.iter().enumerate().take(words_32(function.return_type)) {
+                            self.read_into(value, word, *reg);
+                        }
+                    }
+                    self.epilogue();
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Cuts the register down to the C type it's passed as (sign or zero extending the rest of
+/// the 32 bits), the callee can count on that
+fn narrow(out: &mut Vec<Inst>, reg: Reg, ty: Option<&CType>) {
+    match narrow_int(ty, 32) {
+        Some((8, signed)) => out.push(Inst::Extend(if signed {Extend::Sxtb} else {Extend::Uxtb}, reg, reg)),
+        Some((_, signed)) => out.push(Inst::Extend(if signed {Extend::Sxth} else {Extend::Uxth}, reg, reg)),
+        None => {}
+    }
+}
+
+fn generate_function(out: &mut Vec<Inst>, function: &Function, module: &Module, profile: Profile) -> Result<(), String> {
+    let outgoing = function.blocks.iter().flat_map(|block| &block.instructions).map(|instruction| match instruction {
+        Instruction::Call { function: target, args, .. } if target != "print" => {
+            assign_arguments(&call_arguments(function, args, module.external(target))).1
+        }
+        _ => 0,
+    }).max().unwrap_or(0);
+    let allocation = regalloc::allocate(function, module, profile.registers());
+    let frame = Frame::new(function, &allocation, outgoing, profile);
+    Generator { out, function, module, homes: allocation.homes, frame, bias: 0, labels: 0, profile }.generate()
+}
+
+/// Process entry point, runs the top level and exits with whatever it returned
+fn generate_start(out: &mut Vec<Inst>, entry: &str) {
+    out.extend([
+        Inst::Label(entry.to_string()),
+        Inst::Call(symbol(ENTRY_FUNCTION)),
+        Inst::Movs(Reg::R7, SYS_EXIT),
+        Inst::Svc(0),
+    ]);
+}
+
+/// Bare metal entry point (the reset handler), the stack pointer comes from the linker
+/// script's STACK_TOP_SYMBOL and the end is a hang. The hardware already loaded sp from the
+/// vector table, but this way a debugger jumping to the entry point works too.
+fn generate_freestanding_start(out: &mut Vec<Inst>, entry: &str, profile: Profile) {
+    let hang = format!(".L{}_hang", entry);
+    out.push(Inst::Label(entry.to_string()));
+    symbol_address(out, profile, Reg::R0, STACK_TOP_SYMBOL);
+    out.extend([
+        Inst::Mov(Reg::Sp, Reg::R0),
+        Inst::Call(symbol(ENTRY_FUNCTION)),
+        Inst::Label(hang.clone()),
+        Inst::Branch(hang),
+    ]);
+}
+
+/// Vector table slot of an `#[interrupt]` function, by its name
+fn exception_slot(name: &str, profile: Profile) -> Option<usize> {
+    let (exceptions, max_irqs) = profile.exceptions();
+    if let Some((_, slot)) = exceptions.iter().find(|(exception, _)| *exception == name) {return Some(*slot)}
+    match name.strip_prefix("irq").and_then(|number| number.parse::<usize>().ok()) {
+        Some(irq) if irq < max_irqs => Some(16 + irq),
+        _ => None,
+    }
+}
+
+/// The Cortex-M vector table: initial stack pointer, reset handler, then the exceptions and
+/// as many external interrupts as the program handles
+fn generate_vector_table(module: &Module, entry: &str, profile: Profile) -> Result<Vec<Inst>, String> {
+    let mut handlers: Vec<Option<&str>> = vec![None; 16];
+    for function in module.functions.iter().filter(|function| function.attributes.interrupt) {
+        let Some(slot) = exception_slot(&function.name, profile) else {
+            let names: Vec<&str> = profile.exceptions().0.iter().map(|(name, _)| *name).collect();
+            return Err(format!("Interrupt handler '{}' doesn't name a Cortex-M exception, expected {} or irq0, irq1, ...", function.name, names.join(", ")));
+        };
+        if slot >= handlers.len() {handlers.resize(slot + 1, None)}
+        handlers[slot] = Some(&function.name);
+    }
+    let mut out = vec![
+        Inst::Label(VECTOR_TABLE.to_string()),
+        Inst::Word(Some(STACK_TOP_SYMBOL.to_string())),
+        Inst::Word(Some(entry.to_string())),
+    ];
+    let exceptions = profile.exceptions().0;
+    for (slot, handler) in handlers.iter().enumerate().skip(2) {
+        let reserved = slot < 16 && !exceptions.iter().any(|(_, exception)| *exception == slot);
+        out.push(Inst::Word(match handler {
+            Some(handler) => Some(handler.to_string()),
+            None if reserved => None,
+            None => Some(DEFAULT_HANDLER.to_string()),
+        }));
+    }
+    Ok(out)
+}
+
+/// `lo:hi = -lo:hi`, with a low `scratch` register
+fn negate(low: Reg, high: Reg, scratch: Reg) -> [Inst; 4] {
+    [
+        Inst::Movs(scratch, 0),
+        Inst::Negs(low, low),
+        Inst::Op(Op::Sbc, scratch, scratch, high), // 0 - high - borrow
+        Inst::Mov(high, scratch),
+    ]
+}
+
+/// Strlen of the string at `start` into `length`, with `cursor` and `scratch` (a low one)
+fn strlen(out: &mut Vec<Inst>, start: Reg, cursor: Reg, length: Reg, scratch: Reg, name: &str) {
+    let (repeat, done) = (format!(".L{}_loop", name), format!(".L{}_done", name));
+    out.extend([
+        Inst::Mov(cursor, start),
+        Inst::Label(repeat.clone()),
+        Inst::Load(Width::Byte, scratch, cursor, 0),
+        Inst::CmpImm(scratch, 0),
+        Inst::BranchCond(Cond::Eq, done.clone()),
+        Inst::AddImm(cursor, cursor, 1),
+        Inst::Branch(repeat),
+        Inst::Label(done),
+        Inst::Op(Op::Sub, length, cursor, start),
+    ]);
+}
+
+/// 64-bit division, shift-subtract one bit at a time. Only used with divisors up to 2^63, so
+/// the remainder always fits 64 bits after the shift. Nothing in here needs more than ARMv6-M.
+fn generate_division(out: &mut Vec<Inst>) {
+    let label = |name: &str| name.to_string();
+    let (r0, r1, r2, r3, r4, r5, r6, r7) = (Reg::R0, Reg::R1, Reg::R2, Reg::R3, Reg::R4, Reg::R5, Reg::R6, Reg::R7);
+    // __gv_udivmod64: the dividend shifts out of r0:r1 into the remainder in r4:r5 (one long
+    // add-with-carry chain), and the quotient bits shift in behind it
+    out.extend([
+        Inst::Label(label(UDIVMOD64)),
+        Inst::Push(vec![r4, r5, r6, r7, Reg::Lr]),
+        Inst::Movs(r4, 0),
+        Inst::Movs(r5, 0),
+        Inst::Movs(r7, 64),
+        Inst::Label(label(".Ludivmod_loop")),
+        Inst::Op(Op::Add, r0, r0, r0),
+        Inst::Op(Op::Adc, r1, r1, r1),
+        Inst::Op(Op::Adc, r4, r4, r4),
+        Inst::Op(Op::Adc, r5, r5, r5),
+        // remainder - divisor, added back if that borrows
+        Inst::Op(Op::Sub, r4, r4, r2),
+        Inst::Op(Op::Sbc, r5, r5, r3),
+        Inst::BranchCond(Cond::Hs, label(".Ludivmod_fits")),
+        Inst::Op(Op::Add, r4, r4, r2),
+        Inst::Op(Op::Adc, r5, r5, r3),
+        Inst::Branch(label(".Ludivmod_next")),
+        Inst::Label(label(".Ludivmod_fits")),
+        Inst::AddImm(r0, r0, 1),
+        Inst::Label(label(".Ludivmod_next")),
+        Inst::AddImm(r7, r7, -1),
+        Inst::BranchCond(Cond::Ne, label(".Ludivmod_loop")),
+        Inst::Mov(r2, r4),
+        Inst::Mov(r3, r5),
+        Inst::Pop(vec![r4, r5, r6, r7, Reg::Pc]),
+    ]);
+
+    // __gv_div64: signed on top of the unsigned one, rounds towards zero like C. Dividing by
+    // zero traps, like it does on x86.
+    out.extend([
+        Inst::Label(label(DIV64)),
+        Inst::CmpImm(r2, 0),
+        Inst::BranchCond(Cond::Ne, label(".Ldiv64_divisor")),
+        Inst::CmpImm(r3, 0),
+        Inst::BranchCond(Cond::Ne, label(".Ldiv64_divisor")),
+        Inst::Udf(0),
+        Inst::Label(label(".Ldiv64_divisor")),
+        Inst::Push(vec![r4, r5, r6, Reg::Lr]),
+        Inst::Mov(r4, r1),
+        Inst::Op(Op::Eor, r4, r4, r3), // negative if the result is
+        Inst::CmpImm(r1, 0),
+        Inst::BranchCond(Cond::Ge, label(".Ldiv64_dividend")),
+    ]);
+    out.extend(negate(r0, r1, r5));
+    out.extend([
+        Inst::Label(label(".Ldiv64_dividend")),
+        Inst::CmpImm(r3, 0),
+        Inst::BranchCond(Cond::Ge, label(".Ldiv64_divide")),
+    ]);
+    out.extend(negate(r2, r3, r5));
+    out.extend([
+        Inst::Label(label(".Ldiv64_divide")),
+        Inst::Call(label(UDIVMOD64)),
+        Inst::CmpImm(r4, 0),
+        Inst::BranchCond(Cond::Ge, label(".Ldiv64_done")),
+    ]);
+    out.extend(negate(r0, r1, r5));
+    out.extend([
+        Inst::Label(label(".Ldiv64_done")),
+        Inst::Pop(vec![r4, r5, r6, Reg::Pc]),
+    ]);
+}
+
+/// The low 64 bits of a 64-bit product out of 32-bit muls, for ARMv6-M. r0 * r2 comes
+/// together from the products of their 16-bit halves, the cross products only go into the
+/// upper word.
+fn generate_multiplication(out: &mut Vec<Inst>) {
+    let (r0, r1, r2, r3, r4, r5, r6, r7) = (Reg::R0, Reg::R1, Reg::R2, Reg::R3, Reg::R4, Reg::R5, Reg::R6, Reg::R7);
+    out.extend([
+        Inst::Label(MUL64.to_string()),
+        Inst::Push(vec![r4, r5, r6, r7, Reg::Lr]),
+        Inst::Muls(r1, r2),
+        Inst::Mov(r7, r3),
+        Inst::Muls(r7, r0),
+        Inst::Op(Op::Add, r7, r7, r1), // r0 * r3 + r1 * r2
+        Inst::Extend(Extend::Uxth, r4, r0),
+        Inst::Shift(Shift::Lsr, r5, r0, 16),
+        Inst::Extend(Extend::Uxth, r6, r2),
+        Inst::Shift(Shift::Lsr, r2, r2, 16),
+        Inst::Mov(r0, r4),
+        Inst::Muls(r0, r6), // low * low
+        Inst::Mov(r1, r5),
+        Inst::Muls(r1, r2), // high * high
+        Inst::Muls(r5, r6),
+        Inst::Muls(r4, r2),
+        Inst::Op(Op::Add, r4, r4, r5), // the middle ones, that can carry into bit 32
+        Inst::BranchCond(Cond::Lo, ".Lmul64_middle".to_string()),
+        Inst::Movs(r5, 1),
+        Inst::Shift(Shift::Lsl, r5, r5, 16),
+        Inst::Op(Op::Add, r1, r1, r5),
+        Inst::Label(".Lmul64_middle".to_string()),
+        Inst::Shift(Shift::Lsl, r5, r4, 16),
+        Inst::Shift(Shift::Lsr, r4, r4, 16),
+        Inst::Op(Op::Add, r0, r0, r5),
+        Inst::Op(Op::Adc, r1, r1, r4),
+        Inst::Op(Op::Add, r1, r1, r7),
+        Inst::Pop(vec![r4, r5, r6, r7, Reg::Pc]),
+    ]);
+}
+
+/// Tiny runtime for `print`, all of it straight syscalls so nothing needs to be linked in.
+/// r7 is callee-saved, so everything that makes a syscall saves it. Like the division, it's
+/// the same on ARMv6-M.
+fn generate_runtime(out: &mut Vec<Inst>) {
+    let label = |name: &str| name.to_string();
+    let write = [Inst::Movs(Reg::R7, SYS_WRITE), Inst::Svc(0)];
+
+    // __gv_print_char(r0: char)
+    out.extend([
+        Inst::Label(label(PRINT_CHAR)),
+        Inst::Push(vec![Reg::R7, Reg::Lr]),
+        Inst::AddImm(Reg::Sp, Reg::Sp, -8),
+        Inst::Mov(Reg::R1, Reg::Sp),
+        Inst::Store(Width::Byte, Reg::R0, Reg::R1, 0),
+        Inst::Movs(Reg::R0, 1),
+        Inst::Movs(Reg::R2, 1),
+    ]);
+    out.extend(write.clone());
+    out.extend([
+        Inst::AddImm(Reg::Sp, Reg::Sp, 8),
+        Inst::Pop(vec![Reg::R7, Reg::Pc]),
+    ]);
+
+    // __gv_print_str(r0: NUL terminated string)
+    out.extend([
+        Inst::Label(label(PRINT_STR)),
+        Inst::Push(vec![Reg::R7, Reg::Lr]),
+        Inst::Mov(Reg::R1, Reg::R0),
+    ]);
+    strlen(out, Reg::R1, Reg::R3, Reg::R2, Reg::R0, "print_str");
+    out.push(Inst::Movs(Reg::R0, 1));
+    out.extend(write.clone());
+    out.push(Inst::Pop(vec![Reg::R7, Reg::Pc]));
+
+    // __gv_print_int(r0:r1: i64), digits get written backwards into a buffer on the stack,
+    // the magnitude as unsigned so the most negative number works too
+    let (r0, r1, r2, r4, r5) = (Reg::R0, Reg::R1, Reg::R2, Reg::R4, Reg::R5);
+    out.extend([
+        Inst::Label(label(PRINT_INT)),
+        Inst::Push(vec![r4, r5, Reg::R6, Reg::R7, Reg::Lr]),
+        Inst::AddImm(Reg::Sp, Reg::Sp, -28),
+        Inst::AddImm(r4, Reg::Sp, 24),
+        Inst::AddImm(r4, r4, -1),
+        Inst::Shift(Shift::Asr, r5, r1, 31), // negative?
+        Inst::CmpImm(r5, 0),
+        Inst::BranchCond(Cond::Eq, label(".Lprint_int_loop")),
+    ]);
+    out.extend(negate(r0, r1, Reg::R6));
+    out.extend([
+        Inst::Label(label(".Lprint_int_loop")),
+        Inst::Movs(r2, 10),
+        Inst::Movs(Reg::R3, 0),
+        Inst::Call(label(UDIVMOD64)),
+        Inst::AddImm(r2, r2, b'0' as i32),
+        Inst::Store(Width::Byte, r2, r4, 0),
+        Inst::AddImm(r4, r4, -1),
+        Inst::Mov(r2, r0),
+        Inst::Op(Op::Orr, r2, r2, r1),
+        Inst::BranchCond(Cond::Ne, label(".Lprint_int_loop")),
+        Inst::CmpImm(r5, 0),
+        Inst::BranchCond(Cond::Eq, label(".Lprint_int_write")),
+        Inst::Movs(r2, b'-'),
+        Inst::Store(Width::Byte, r2, r4, 0),
+        Inst::AddImm(r4, r4, -1),
+        Inst::Label(label(".Lprint_int_write")),
+        Inst::AddImm(r1, r4, 1),
+        Inst::AddImm(r2, Reg::Sp, 24),
+        Inst::Op(Op::Sub, r2, r2, r1),
+        Inst::Movs(r0, 1),
+    ]);
+    out.extend(write);
+    out.extend([
+        Inst::AddImm(Reg::Sp, Reg::Sp, 28),
+        Inst::Pop(vec![r4, r5, Reg::R6, Reg::R7, Reg::Pc]),
+    ]);
+}
+
+/// Helper functions for the intrinsics (see intrinsics.rs) the module calls. They're called
+/// like any Galvan function, so an i64 argument is an even register pair, and the third one
+/// of the syscalls ends up on the stack.
+fn generate_intrinsics(out: &mut Vec<Inst>, module: &Module, profile: Profile) {
+    // Syscalls give back an i32, -errno on failure
+    let syscall = |number: u8| [
+        Inst::Movs(Reg::R7, number),
+        Inst::Svc(0),
+        Inst::Shift(Shift::Asr, Reg::R1, Reg::R0, 31),
+        Inst::Pop(vec![Reg::R7, Reg::Pc]),
+    ];
+    let save = Inst::Push(vec![Reg::R7, Reg::Lr]);
+    for intrinsic in INTRINSICS {
+        let used = module.functions.iter().flat_map(|function| &function.blocks).flat_map(|block| &block.instructions)
+            .any(|instruction| matches!(instruction, Instruction::Call { function, .. } if function == intrinsic.name));
+        if !used {continue}
+
+        out.push(Inst::Label(symbol(intrinsic.name)));
+        match intrinsic.name {
+            // Linked with libc, so leave the heap to calloc and exit through exit()
+            "__alloc" if module.uses_c() => out.extend([
+                Inst::Push(vec![Reg::R4, Reg::Lr]),
+                Inst::Movs(Reg::R1, 1),
+                Inst::Call("calloc".to_string()),
+                Inst::Movs(Reg::R1, 0),
+                Inst::Pop(vec![Reg::R4, Reg::Pc]),
+            ]),
+            "__sys_exit" if module.uses_c() => out.push(Inst::Call("exit".to_string())),
+            // Moves the program break up, rounded to 8 bytes. Fresh memory from brk is zeroed.
+            "__alloc" => {
+                out.extend([
+                    Inst::Push(vec![Reg::R4, Reg::R5, Reg::R7, Reg::Lr]),
+                    Inst::AddImm(Reg::R4, Reg::R0, 7),
+                    Inst::Shift(Shift::Lsr, Reg::R4, Reg::R4, 3),
+                    Inst::Shift(Shift::Lsl, Reg::R4, Reg::R4, 3),
+                    Inst::Movs(Reg::R0, 0),
+                    Inst::Movs(Reg::R7, SYS_BRK), // brk(0), the current break
+                    Inst::Svc(0),
+                    Inst::Mov(Reg::R5, Reg::R0),
+                    Inst::Op(Op::Add, Reg::R0, Reg::R0, Reg::R4),
+                    Inst::Mov(Reg::R4, Reg::R0),
+                    Inst::Svc(0),
+                    Inst::Cmp(Reg::R0, Reg::R4),
+                    Inst::BranchCond(Cond::Ne, ".Lalloc_failed".to_string()),
+                    Inst::Mov(Reg::R0, Reg::R5),
+                    Inst::Movs(Reg::R1, 0),
+                    Inst::Pop(vec![Reg::R4, Reg::R5, Reg::R7, Reg::Pc]),
+                    Inst::Label(".Lalloc_failed".to_string()),
+                ]);
+                mov32(out, profile, Reg::R0, -12); // ENOMEM
+                mov32(out, profile, Reg::R1, -1);
+                out.push(Inst::Pop(vec![Reg::R4, Reg::R5, Reg::R7, Reg::Pc]));
+            }
+            "__load8" => out.extend([
+                Inst::Load(Width::Byte, Reg::R0, Reg::R0, 0),
+                Inst::Movs(Reg::R1, 0),
+                Inst::Bx(Reg::Lr),
+            ]),
+            "__store8" => out.extend([
+                Inst::Store(Width::Byte, Reg::R2, Reg::R0, 0),
+                Inst::Bx(Reg::Lr),
+            ]),
+            // (r0: str, r2:r3: index)
+            "__str_byte" => out.extend([
+                Inst::Op(Op::Add, Reg::R0, Reg::R0, Reg::R2),
+                Inst::Load(Width::Byte, Reg::R0, Reg::R0, 0),
+                Inst::Movs(Reg::R1, 0),
+                Inst::Bx(Reg::Lr),
+            ]),
+            // Strings are addresses already
+            "__str_at" => out.push(Inst::Bx(Reg::Lr)),
+            // (r0:r1, r2:r3, [sp]) into (r0, r1, r2)
+            "__sys_read" | "__sys_write" => {
+                out.extend([save.clone(), Inst::Mov(Reg::R1, Reg::R2), Inst::Load(Width::Word, Reg::R2, Reg::Sp, 8)]);
+                out.extend(syscall(if intrinsic.name == "__sys_read" {SYS_READ} else {SYS_WRITE}));
+            }
+            // (r0:r1: fd, r2: string)
+            "__sys_write_str" => {
+                out.extend([save.clone(), Inst::Mov(Reg::R1, Reg::R2)]);
+                strlen(out, Reg::R1, Reg::R3, Reg::R2, Reg::R7, "write_str");
+                out.extend(syscall(SYS_WRITE));
+            }
+            // (r0: path, r2:r3: flags, [sp]: mode)
+            "__sys_open" => {
+                out.extend([save.clone(), Inst::Mov(Reg::R1, Reg::R2), Inst::Load(Width::Word, Reg::R2, Reg::Sp, 8)]);
+                out.extend(syscall(SYS_OPEN));
+            }
+            "__sys_close" => {
+                out.push(save.clone());
+                out.extend(syscall(SYS_CLOSE));
+            }
+            "__sys_exit" => out.extend([Inst::Movs(Reg::R7, SYS_EXIT), Inst::Svc(0)]),
+            name => unreachable!("intrinsic '{}' has no Thumb version", name),
+        }
+    }
+}
+
+/// Generates the whole program for a module
+pub fn generate(module: &Module, profile: Profile) -> Result<Program, String> {
+    if debug_prints(THUMB_DEBUG_PRINTS) {eprintln!("- - - Thumb")}
+
+    let mut text = vec![];
+    let mut globals = vec![];
+    let mut sections: Vec<(String, Vec<Inst>)> = vec![];
+    let entry = module.entry_symbol.clone().unwrap_or(START_SYMBOL.to_string());
+    // libc's _start calls main, so a program linked with it starts right in the entry function
+    let c_entry = module.uses_c() && !module.freestanding;
+    match module.entry() {
+        Some(_) if c_entry => globals.push(C_ENTRY_SYMBOL.to_string()),
+        Some(_) if module.freestanding => {
+            sections.push((VECTOR_TABLE_SECTION.to_string(), generate_vector_table(module, &entry, profile)?));
+            globals.push(VECTOR_TABLE.to_string());
+            generate_freestanding_start(&mut text, &entry, profile);
+            globals.push(entry.clone());
+            text.extend([Inst::Label(DEFAULT_HANDLER.to_string()), Inst::Branch(DEFAULT_HANDLER.to_string())]);
+        }
+        Some(_) => {
+            generate_start(&mut text, &entry);
+            globals.push(entry.clone());
+        }
+        None => {}
+    }
+    for function in &module.functions {
+        let out = match &function.attributes.section {
+            Some(section) if section != ".text" => match sections.iter().position(|(name, _)| name == section) {
+                Some(index) => &mut sections[index].1,
+                None => {
+                    sections.push((section.clone(), vec![]));
+                    &mut sections.last_mut().unwrap().1
+                }
+            },
+            _ => &mut text,
+        };
+        // The hardware saves what AAPCS says a function may clobber, so interrupt handlers
+        // are just the function under its own name
+        if function.attributes.interrupt {
+            out.push(Inst::Label(function.name.clone()));
+            globals.push(function.name.clone());
+        }
+        if function.name == ENTRY_FUNCTION && c_entry {out.push(Inst::Label(C_ENTRY_SYMBOL.to_string()))}
+        if let Some(export) = &function.export {
+            out.push(Inst::Label(export.clone()));
+            globals.push(export.clone());
+        }
+        generate_function(out, function, module, profile)?;
+    }
+    // Nothing to print to without an OS, seman already made sure nothing does
+    if !module.freestanding {generate_runtime(&mut text)}
+    let uses = |op: BinaryOp| module.functions.iter().flat_map(|function| &function.blocks).flat_map(|block| &block.instructions)
+        .any(|instruction| matches!(instruction, Instruction::Binary { op: used, .. } if *used == op));
+    if uses(BinaryOp::Div) || !module.freestanding {generate_division(&mut text)}
+    if uses(BinaryOp::Mul) && profile == Profile::V6m {generate_multiplication(&mut text)}
+    generate_intrinsics(&mut text, module, profile);
+    if profile == Profile::V6m {
+        let mut far = 0;
+        text = fit_v6m(text, &mut far);
+        for (name, code) in &mut sections {
+            if name != VECTOR_TABLE_SECTION {*code = fit_v6m(std::mem::take(code), &mut far)}
+        }
+    }
+
+    let rodata = module.strings.iter().enumerate().map(|(index, string)| {
+        let mut bytes = string.as_bytes().to_vec();
+        bytes.push(0);
+        (string_label(index), bytes)
+    }).collect();
+
+    if debug_prints(THUMB_DEBUG_PRINTS) {eprintln!("- - - Thumb done!")}
+    Ok(Program { text, rodata, globals, sections, entry, profile })
+}
+
+/// Most an instruction can take up on ARMv6-M, after fit_v6m turned far branches into bl
+fn v6m_size(inst: &Inst, literals: usize) -> usize {
+    match inst {
+        Inst::Label(_) => 0,
+        Inst::Branch(_) | Inst::Call(_) | Inst::Word(_) => 4,
+        Inst::BranchCond(..) => 6,
+        // Padding up to a word, then one per load
+        Inst::Pool => 2 + 4 * literals,
+        Inst::Asm { template, .. } => 4 * template.lines().count(),
+        _ => 2,
+    }
+}
+
+/// What ARMv6-M needs on top of the code the generator makes: literal pools close enough to
+/// their loads (ldr reaches 1020 bytes ahead), right after an unconditional jump or behind a
+/// branch around them if none comes in time, and branches beyond the 16-bit forms' reach
+/// (2 KiB, conditional ones 256 bytes) turned into bl. Everything is measured with the most
+/// an instruction can take, so what fits here fits in the assembler's output too.
+fn fit_v6m(code: Vec<Inst>, far: &mut usize) -> Vec<Inst> {
+    let mut pooled = vec![];
+    let mut position = 0;
+    // Position of the first load waiting for a pool, and how many there are
+    let mut first = 0;
+    let mut literals = 0;
+    for inst in code {
+        let size = v6m_size(&inst, 0);
+        // With the branch around the pool, and room for this one if it's a load too
+        if literals > 0 && position + size + 4 + v6m_size(&Inst::Pool, literals + 1) - first > 1000 {
+            *far += 1;
+            let skip = format!(".Lpool_skip{}", far);
+            pooled.extend([Inst::Branch(skip.clone()), Inst::Pool, Inst::Label(skip)]);
+            position += 4 + v6m_size(&Inst::Pool, literals);
+            literals = 0;
+        }
+        let ends = matches!(&inst, Inst::Branch(_) | Inst::Bx(_)) || matches!(&inst, Inst::Pop(regs) if regs.contains(&Reg::Pc));
+        if let Inst::LoadLiteral(..) = inst {
+            if literals == 0 {first = position}
+            literals += 1;
+        }
+        pooled.push(inst);
+        position += size;
+        if ends && literals > 0 {
+            pooled.push(Inst::Pool);
+            position += v6m_size(&Inst::Pool, literals);
+            literals = 0;
+        }
+    }
+    if literals > 0 {pooled.push(Inst::Pool)}
+
+    // Where every label is, at the most
+    let mut positions = vec![];
+    let mut labels = std::collections::HashMap::new();
+    let (mut position, mut literals) = (0, 0);
+    for inst in &pooled {
+        positions.push(position);
+        match inst {
+            Inst::Label(label) => {labels.insert(label.clone(), position);}
+            Inst::LoadLiteral(..) => literals += 1,
+            _ => {}
+        }
+        position += v6m_size(inst, literals);
+        if *inst == Inst::Pool {literals = 0}
+    }
+    let mut out = vec![];
+    for (inst, position) in pooled.into_iter().zip(positions) {
+        let distance = |label: &String| labels.get(label).map_or(0, |target: &usize| target.abs_diff(position));
+        match inst {
+            Inst::Branch(label) if distance(&label) > 2000 => out.push(Inst::Call(label)),
+            Inst::BranchCond(cond, label) if distance(&label) > 240 => {
+                *far += 1;
+                let skip = format!(".Lfar{}", far);
+                out.extend([Inst::BranchCond(cond.inverse(), skip.clone()), Inst::Call(label), Inst::Label(skip)]);
+            }
+            inst => out.push(inst),
+        }
+    }
+    out
+}
+
+//
+// PRINTING
+//
+
+/// Whether `rd = rn + imm` has a 16-bit encoding (the flag setting adds/subs for low
+/// registers, or the sp ones that leave the flags alone)
+pub fn add_imm_is_short(rd: Reg, rn: Reg, imm: i32) -> bool {
+    match (rd, rn) {
+        (Reg::Sp, Reg::Sp) => imm % 4 == 0 && imm.abs() < 512,
+        (rd, Reg::Sp) => rd.is_low() && imm % 4 == 0 && (0..1024).contains(&imm),
+        (rd, rn) if rd.is_low() && rn.is_low() => imm.abs() < 8 || (rd == rn && imm.abs() < 256),
+        _ => false,
+    }
+}
+
+fn reg_list(regs: &[Reg]) -> String {
+    regs.iter().map(Reg::name).collect::<Vec<&str>>().join(", ")
+}
+
+/// Instruction as a line of assembly, without the indentation
+pub fn print_inst(inst: &Inst) -> String {
+    match inst {
+        Inst::Label(label) => format!("{}:", label),
+        Inst::Movs(rd, imm) => format!("movs {}, #{}", rd.name(), imm),
+        Inst::Movw(rd, imm) => format!("movw {}, #{}", rd.name(), imm),
+        Inst::Movt(rd, imm) => format!("movt {}, #{}", rd.name(), imm),
+        Inst::MovwSymbol(rd, symbol) => format!("movw {}, #:lower16:{}", rd.name(), symbol),
+        Inst::MovtSymbol(rd, symbol) => format!("movt {}, #:upper16:{}", rd.name(), symbol),
+        Inst::Mov(rd, rm) => format!("mov {}, {}", rd.name(), rm.name()),
+        Inst::Op(op, rd, rn, rm) => format!("{} {}, {}, {}", op.name(), rd.name(), rn.name(), rm.name()),
+        // The short sp forms don't set the flags and drop the s, the long ones are addw/subw
+        Inst::AddImm(rd, rn, imm) => {
+            let short = add_imm_is_short(*rd, *rn, *imm);
+            let name = match (short, *imm < 0) {
+                (true, negative) if *rn == Reg::Sp => if negative {"sub"} else {"add"},
+                (true, negative) => if negative {"subs"} else {"adds"},
+                (false, negative) => if negative {"subw"} else {"addw"},
+            };
+            format!("{} {}, {}, #{}", name, rd.name(), rn.name(), imm.abs())
+        }
+        Inst::AddReg(rd, rm) => format!("add {}, {}", rd.name(), rm.name()),
+        Inst::Shift(shift, rd, rm, imm) => format!("{} {}, {}, #{}", shift.name(), rd.name(), rm.name(), imm),
+        Inst::Negs(rd, rn) => format!("rsbs {}, {}, #0", rd.name(), rn.name()),
+        Inst::Umull(lo, hi, rn, rm) => format!("umull {}, {}, {}, {}", lo.name(), hi.name(), rn.name(), rm.name()),
+        Inst::Mla(rd, rn, rm, ra) => format!("mla {}, {}, {}, {}", rd.name(), rn.name(), rm.name(), ra.name()),
+        Inst::Cmp(rn, rm) => format!("cmp {}, {}", rn.name(), rm.name()),
+        Inst::CmpImm(rn, imm) => format!("cmp {}, #{}", rn.name(), imm),
+        Inst::Extend(extend, rd, rm) => format!("{} {}, {}", extend.name(), rd.name(), rm.name()),
+        Inst::Load(width, rt, base, offset) => format!("ldr{} {}, [{}, #{}]", width.suffix(), rt.name(), base.name(), offset),
+        Inst::Store(width, rt, base, offset) => format!("str{} {}, [{}, #{}]", width.suffix(), rt.name(), base.name(), offset),
+        Inst::Push(regs) => format!("push {{{}}}", reg_list(regs)),
+        Inst::Pop(regs) => format!("pop {{{}}}", reg_list(regs)),
+        Inst::Branch(label) => format!("b {}", label),
+        Inst::BranchCond(cond, label) => format!("b{} {}", cond.name(), label),
+        Inst::Call(label) => format!("bl {}", label),
+        Inst::Bx(rm) => format!("bx {}", rm.name()),
+        Inst::Svc(imm) => format!("svc #{}", imm),
+        Inst::Udf(imm) => format!("udf #{}", imm),
+        Inst::Word(symbol) => format!(".word {}", symbol.as_deref().unwrap_or("0")),
+        Inst::Muls(rd, rn) => format!("muls {}, {}, {}", rd.name(), rn.name(), rd.name()),
+        Inst::LoadLiteral(rd, symbol) => format!("ldr {}, ={}", rd.name(), symbol),
+        Inst::Pool => ".ltorg".to_string(),
+        Inst::Asm { template, operands } => {
+            let expanded = expand_asm(template, &mut |name| Ok(match operands.iter().find(|(operand, _)| operand == name) {
+                Some((_, reg)) => reg.name().to_string(),
+                None => format!("{{{}}}", name),
+            }));
+            // Already checked by seman or the IR verifier, so this can't really fail
+            let expanded = expanded.unwrap_or(template.clone());
+            expanded.lines().map(str::trim).collect::<Vec<&str>>().join("\n    ")
+        }
+    }
+}
+
+impl Program {
+    /// The program as GNU assembler source
+    pub fn to_assembly(&self) -> String {
+        let mut out = String::new();
+        let cpu = match self.profile {Profile::V7m => "cortex-m3", Profile::V6m => "cortex-m0plus"};
+        out.push_str(&format!(".syntax unified\n.cpu {}\n.thumb\n", cpu));
+        for global in &self.globals {
+            out.push_str(&format!(".globl {}\n", global));
+        }
+
+        rodata_assembly(&mut out, &self.rodata);
+
+        let sections = std::iter::once((".text", ".text".to_string(), &self.text))
+            .chain(self.sections.iter().map(|(name, text)| {
+                let flags = if name == VECTOR_TABLE_SECTION {"a"} else {"ax"};
+                (name.as_str(), format!(".section {},\"{}\",%progbits", name, flags), text)
+            }));
+        for (name, directive, text) in sections {
+            let code = name != VECTOR_TABLE_SECTION;
+            out.push_str(&format!("\n{}\n    .p2align {}\n", directive, if code {1} else {2}));
+            for inst in text {
+                match inst {
+                    Inst::Label(label) => {
+                        if !label.starts_with(".L") {
+                            out.push('\n');
+                            // So the linker sets the Thumb bit in their addresses
+                            if code {out.push_str(".thumb_func\n")}
+                        }
+                        out.push_str(&format!("{}:\n", label));
+                    }
+                    inst => out.push_str(&format!("    {}\n", print_inst(inst))),
+                }
+            }
+        }
+        out.push_str("\n.section .note.GNU-stack,\"\",%progbits\n");
+        out
+    }
+}
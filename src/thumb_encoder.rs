@@ -0,0 +1,363 @@
+use std::collections::HashMap;
+
+use crate::backend::Image;
+use crate::thumb::{add_imm_is_short, Extend, Inst, Op, Profile, Program, Reg, Shift, Width};
+
+// Turns the Thumb backend's instructions into Thumb-2 machine code, so programs can run in
+// thumb_sim.rs without an assembler. Same idea as riscv_encoder.rs: everything gets laid out
+// at a fixed address in one go, in the order the generated linker script uses (the custom
+// sections with the vector table first, then the code, then the strings).
+// Like the assembler, it picks the 16-bit encoding of an instruction whenever one fits.
+// Branches depend on how far their target is, so those start out short and grow until
+// everything fits. 32-bit instructions are two halfwords, the first one first.
+// ARMv6-M programs only get the 16-bit encodings and bl, the backend already made sure
+// branches reach and literal pools are close enough. A pool holds a word for every
+// `ldr rd, =symbol` since the one before, aligned to 4 bytes like the assembler does it.
+
+//
+// INSTRUCTION FORMATS
+//
+
+/// Halfword(s) of one instruction as bytes
+fn halves(halves: &[u16]) -> Vec<u8> {
+    halves.iter().flat_map(|half| half.to_le_bytes()).collect()
+}
+
+fn low(reg: Reg) -> u16 {
+    reg.number() as u16
+}
+
+/// The 16-bit data processing opcodes (`010000 op Rm Rdn`)
+fn short_op(op: Op) -> u16 {
+    match op {Op::Eor => 1, Op::Adc => 5, Op::Sbc => 6, Op::Orr => 12, Op::Add | Op::Sub => unreachable!()}
+}
+
+/// The 32-bit data processing (shifted register) opcodes
+fn wide_op(op: Op) -> u16 {
+    match op {Op::Orr => 2, Op::Eor => 4, Op::Add => 8, Op::Adc => 10, Op::Sbc => 11, Op::Sub => 13}
+}
+
+fn shift_type(shift: Shift) -> u16 {
+    match shift {Shift::Lsl => 0, Shift::Lsr => 1, Shift::Asr => 2}
+}
+
+/// movw/movt with a 16-bit immediate, split into imm4:i:imm3:imm8
+fn move_wide(top: bool, rd: Reg, imm: u16) -> Vec<u8> {
+    let first = 0xf240 | (top as u16) << 7 | (imm >> 11 & 1) << 10 | imm >> 12;
+    let second = (imm >> 8 & 7) << 12 | low(rd) << 8 | (imm & 0xff);
+    halves(&[first, second])
+}
+
+/// addw/subw with a 12-bit immediate
+fn add_wide(rd: Reg, rn: Reg, imm: i32) -> Vec<u8> {
+    let value = imm.unsigned_abs() as u16;
+    let first = (if imm < 0 {0xf2a0} else {0xf200}) | (value >> 11 & 1) << 10 | low(rn);
+    halves(&[first, (value >> 8 & 7) << 12 | low(rd) << 8 | (value & 0xff)])
+}
+
+/// Any load or store with an immediate offset
+fn memory(load: bool, width: Width, rt: Reg, base: Reg, offset: i32) -> Result<Vec<u8>, String> {
+    let word = width == Width::Word;
+    let scale = if word {4} else {1};
+    let (rt, rn) = (low(rt), low(base));
+    let load_bit = load as u16;
+    if word && base == Reg::Sp && rt < 8 && offset % 4 == 0 && (0..1024).contains(&offset) {
+        return Ok(halves(&[0x9000 | load_bit << 11 | rt << 8 | (offset / 4) as u16]));
+    }
+    if rt < 8 && rn < 8 && offset % scale == 0 && (0..32 * scale).contains(&offset) {
+        let opcode = if word {0x6000} else {0x7000};
+        return Ok(halves(&[opcode | load_bit << 11 | ((offset / scale) as u16) << 6 | rn << 3 | rt]));
+    }
+    // size is 10 for words, 00 for bytes
+    let size = if word {0x40} else {0};
+    if (0..4096).contains(&offset) {
+        return Ok(halves(&[0xf880 | size | load_bit << 4 | rn, rt << 12 | offset as u16]));
+    }
+    if (-255..0).contains(&offset) {
+        return Ok(halves(&[0xf800 | size | load_bit << 4 | rn, rt << 12 | 0xc00 | (-offset) as u16]));
+    }
+    Err(format!("Offset {} is out of range for a load or store", offset))
+}
+
+/// Register bitmask of a push/pop list
+fn reg_mask(regs: &[Reg]) -> u16 {
+    regs.iter().fold(0, |mask, reg| mask | 1 << reg.number())
+}
+
+/// b.w and bl: the 25-bit offset with its I1/I2 bits folded into J1/J2
+fn long_branch(offset: i32, link: bool) -> Vec<u8> {
+    let value = offset as u32;
+    let s = value >> 24 & 1;
+    let j1 = !(value >> 23 ^ s) & 1;
+    let j2 = !(value >> 22 ^ s) & 1;
+    let first = 0xf000 | s << 10 | (value >> 12 & 0x3ff);
+    let second = 0x9000 | (link as u32) << 14 | j1 << 13 | j2 << 11 | (value >> 1 & 0x7ff);
+    halves(&[first as u16, second as u16])
+}
+
+/// b<cond>.w: a 21-bit offset
+fn long_branch_cond(cond: u32, offset: i32) -> Vec<u8> {
+    let value = offset as u32;
+    let first = 0xf000 | (value >> 20 & 1) << 10 | cond << 6 | (value >> 12 & 0x3f);
+    let second = 0x8000 | (value >> 18 & 1) << 13 | (value >> 19 & 1) << 11 | (value >> 1 & 0x7ff);
+    halves(&[first as u16, second as u16])
+}
+
+fn encode_fixed(inst: &Inst) -> Result<Vec<u8>, String> {
+    let bad = || format!("Can't encode '{}'", crate::thumb::print_inst(inst));
+    Ok(match inst {
+        Inst::Label(_) => vec![],
+        Inst::Movs(rd, imm) if rd.is_low() => halves(&[0x2000 | low(*rd) << 8 | *imm as u16]),
+        Inst::Movw(rd, imm) => move_wide(false, *rd, *imm),
+        Inst::Movt(rd, imm) => move_wide(true, *rd, *imm),
+        Inst::Mov(rd, rm) => halves(&[0x4600 | (low(*rd) & 8) << 4 | low(*rm) << 3 | (low(*rd) & 7)]),
+        Inst::Op(op @ (Op::Add | Op::Sub), rd, rn, rm) if rd.is_low() && rn.is_low() && rm.is_low() => {
+            let opcode = if *op == Op::Add {0x1800} else {0x1a00};
+            halves(&[opcode | low(*rm) << 6 | low(*rn) << 3 | low(*rd)])
+        }
+        Inst::Op(op, rd, rn, rm) if !matches!(op, Op::Add | Op::Sub) && rd == rn && rd.is_low() && rm.is_low() => {
+            halves(&[0x4000 | short_op(*op) << 6 | low(*rm) << 3 | low(*rd)])
+        }
+        Inst::Op(op, rd, rn, rm) => halves(&[0xea10 | wide_op(*op) << 5 | low(*rn), low(*rd) << 8 | low(*rm)]),
+        Inst::AddImm(rd, rn, imm) if add_imm_is_short(*rd, *rn, *imm) => {
+            let (value, negative) = (imm.unsigned_abs() as u16, *imm < 0);
+            halves(&[match (rd, rn) {
+                (Reg::Sp, _) => (if negative {0xb080} else {0xb000}) | (value / 4),
+                (rd, Reg::Sp) => 0xa800 | low(*rd) << 8 | (value / 4),
+                (rd, rn) if value < 8 => (if negative {0x1e00} else {0x1c00}) | value << 6 | low(*rn) << 3 | low(*rd),
+                (rd, _) => (if negative {0x3800} else {0x3000}) | low(*rd) << 8 | value,
+            }])
+        }
+        Inst::AddImm(rd, rn, imm) if imm.abs() < 4096 => add_wide(*rd, *rn, *imm),
+        Inst::AddReg(rd, rm) => halves(&[0x4400 | (low(*rd) & 8) << 4 | low(*rm) << 3 | (low(*rd) & 7)]),
+        Inst::Shift(shift, rd, rm, imm) if *imm < 32 && rd.is_low() && rm.is_low() => {
+            halves(&[shift_type(*shift) << 11 | (*imm as u16) << 6 | low(*rm) << 3 | low(*rd)])
+        }
+        Inst::Shift(shift, rd, rm, imm) if *imm < 32 => {
+            let imm = *imm as u16;
+            halves(&[0xea5f, (imm >> 2) << 12 | low(*rd) << 8 | (imm & 3) << 6 | shift_type(*shift) << 4 | low(*rm)])
+        }
+        Inst::Negs(rd, rn) if rd.is_low() && rn.is_low() => halves(&[0x4240 | low(*rn) << 3 | low(*rd)]),
+        Inst::Umull(lo, hi, rn, rm) => halves(&[0xfba0 | low(*rn), low(*lo) << 12 | low(*hi) << 8 | low(*rm)]),
+        Inst::Mla(rd, rn, rm, ra) => halves(&[0xfb00 | low(*rn), low(*ra) << 12 | low(*rd) << 8 | low(*rm)]),
+        Inst::Cmp(rn, rm) if rn.is_low() && rm.is_low() => halves(&[0x4280 | low(*rm) << 3 | low(*rn)]),
+        Inst::Cmp(rn, rm) => halves(&[0x4500 | (low(*rn) & 8) << 4 | low(*rm) << 3 | (low(*rn) & 7)]),
+        Inst::CmpImm(rn, imm) if rn.is_low() => halves(&[0x2800 | low(*rn) << 8 | *imm as u16]),
+        Inst::Extend(extend, rd, rm) => {
+            let index = match extend {Extend::Sxth => 0, Extend::Sxtb => 1, Extend::Uxth => 2, Extend::Uxtb => 3};
+            if rd.is_low() && rm.is_low() {
+                halves(&[0xb200 | index << 6 | low(*rm) << 3 | low(*rd)])
+            } else {
+                let opcode = [0xfa0f, 0xfa4f, 0xfa1f, 0xfa5f][index as usize];
+                halves(&[opcode, 0xf080 | low(*rd) << 8 | low(*rm)])
+            }
+        }
+        Inst::Load(width, rt, base, offset) => memory(true, *width, *rt, *base, *offset)?,
+        Inst::Store(width, rt, base, offset) => memory(false, *width, *rt, *base, *offset)?,
+        Inst::Push(regs) | Inst::Pop(regs) => {
+            let push = matches!(inst, Inst::Push(_));
+            let extra = if push {Reg::Lr} else {Reg::Pc};
+            let mask = reg_mask(regs);
+            if mask & !(0xff | 1 << extra.number()) == 0 {
+                let opcode = if push {0xb400} else {0xbc00};
+                halves(&[opcode | ((mask >> extra.number()) & 1) << 8 | (mask & 0xff)])
+            } else {
+                halves(&[if push {0xe92d} else {0xe8bd}, mask])
+            }
+        }
+        Inst::Bx(rm) => halves(&[0x4700 | low(*rm) << 3]),
+        Inst::Svc(imm) => halves(&[0xdf00 | *imm as u16]),
+        Inst::Udf(imm) => halves(&[0xde00 | *imm as u16]),
+        Inst::Word(None) => vec![0; 4],
+        Inst::Muls(rdm, rn) if rdm.is_low() && rn.is_low() => halves(&[0x4340 | low(*rn) << 3 | low(*rdm)]),
+        Inst::Asm { .. } => return Err("The built-in encoder can't assemble inline assembly, build with --emit=asm and use an ARM assembler".to_string()),
+        _ => return Err(bad()),
+    })
+}
+
+/// Encodes an instruction that refers to a label at `target`, None if it doesn't reach
+/// with that size
+fn encode_relative(inst: &Inst, pc: u32, target: u32, size: usize) -> Option<Vec<u8>> {
+    // The pc reads as the instruction's address plus 4
+    let offset = target.wrapping_sub(pc + 4) as i32;
+    match (inst, size) {
+        (Inst::Branch(_), 2) if (-2048..2048).contains(&offset) => Some(halves(&[0xe000 | (offset >> 1 & 0x7ff) as u16])),
+        (Inst::Branch(_), 4) if (-(1 << 24)..1 << 24).contains(&offset) => Some(long_branch(offset, false)),
+        (Inst::BranchCond(cond, _), 2) if (-256..256).contains(&offset) => {
+            Some(halves(&[0xd000 | (*cond as u16) << 8 | (offset >> 1 & 0xff) as u16]))
+        }
+        (Inst::BranchCond(cond, _), 4) if (-(1 << 20)..1 << 20).contains(&offset) => Some(long_branch_cond(*cond as u32, offset)),
+        (Inst::Call(_), 4) if (-(1 << 24)..1 << 24).contains(&offset) => Some(long_branch(offset, true)),
+        (Inst::MovwSymbol(rd, _), _) => Some(move_wide(false, *rd, target as u16)),
+        (Inst::MovtSymbol(rd, _), _) => Some(move_wide(true, *rd, (target >> 16) as u16)),
+        (Inst::Word(_), _) => Some(target.to_le_bytes().to_vec()),
+        // The target is the pool entry, the pc is rounded down to a word here
+        (Inst::LoadLiteral(rt, _), 2) if rt.is_low() => {
+            let offset = target.wrapping_sub((pc + 4) & !3);
+            (offset.is_multiple_of(4) && offset < 1024).then(|| halves(&[0x4800 | low(*rt) << 8 | (offset / 4) as u16]))
+        }
+        _ => None,
+    }
+}
+
+fn relative_target(inst: &Inst) -> Option<&String> {
+    match inst {
+        Inst::Branch(label) | Inst::BranchCond(_, label) | Inst::Call(label) => Some(label),
+        Inst::MovwSymbol(_, label) | Inst::MovtSymbol(_, label) | Inst::Word(Some(label)) => Some(label),
+        Inst::LoadLiteral(_, label) => Some(label),
+        _ => None,
+    }
+}
+
+/// Sizes a relative instruction can have, smallest first
+fn relative_sizes(inst: &Inst, profile: Profile) -> &'static [usize] {
+    match inst {
+        Inst::Branch(_) | Inst::BranchCond(..) if profile == Profile::V6m => &[2],
+        Inst::Branch(_) | Inst::BranchCond(..) => &[2, 4],
+        Inst::LoadLiteral(..) => &[2],
+        _ => &[4],
+    }
+}
+
+/// Whether the first halfword starts a 32-bit instruction
+fn is_wide(bytes: &[u8]) -> bool {
+    bytes.len() == 4 && u16::from_le_bytes([bytes[0], bytes[1]]) >> 11 >= 0b11101
+}
+
+/// Where the relative instruction at `index` points: its label, or its word in the literal pool
+fn target_of(index: usize, label: &str, labels: &HashMap<&str, u32>, addresses: &[u32], literals: &HashMap<usize, (usize, usize)>) -> u32 {
+    match literals.get(&index) {
+        Some((pool, slot)) => ((addresses[*pool] + 3) & !3) + 4 * *slot as u32,
+        None => labels[label],
+    }
+}
+
+/// Lays the program out at `base` and encodes it. `absolute` are symbols with a fixed
+/// address that the program doesn't define itself (the stack top for freestanding programs).
+pub fn encode(program: &Program, base: u32, absolute: &[(String, u32)]) -> Result<Image, String> {
+    let code: Vec<&Inst> = program.sections.iter().flat_map(|(_, text)| text).chain(program.text.iter()).collect();
+
+    // The pool and slot of every literal load, and the symbols in every pool
+    let mut literals: HashMap<usize, (usize, usize)> = HashMap::new();
+    let mut pools: HashMap<usize, Vec<&str>> = HashMap::new();
+    let mut waiting = vec![];
+    for (index, inst) in code.iter().enumerate() {
+        match inst {
+            Inst::LoadLiteral(_, symbol) => waiting.push((index, symbol.as_str())),
+            Inst::Pool => {
+                for (slot, (load, _)) in waiting.iter().enumerate() {
+                    literals.insert(*load, (index, slot));
+                }
+                pools.insert(index, waiting.drain(..).map(|(_, symbol)| symbol).collect());
+            }
+            _ => {}
+        }
+    }
+    if let Some((_, symbol)) = waiting.first() {
+        return Err(format!("There's no literal pool after the load of '{}'", symbol));
+    }
+
+    // Everything that isn't relative has a size right away, pools get theirs once their
+    // address is known
+    let mut fixed: Vec<Option<Vec<u8>>> = vec![];
+    let mut sizes = vec![];
+    for inst in &code {
+        if let Inst::Pool = inst {
+            fixed.push(None);
+            sizes.push(0);
+        } else if let Some(label) = relative_target(inst) {
+            if !code.iter().any(|inst| **inst == Inst::Label(label.clone()))
+                && !program.rodata.iter().any(|(name, _)| name == label)
+                && !absolute.iter().any(|(name, _)| name == label) {
+                return Err(format!("'{}' isn't defined anywhere in the program (the built-in encoder can't link in C code)", label));
+            }
+            fixed.push(None);
+            sizes.push(relative_sizes(inst, program.profile)[0]);
+        } else {
+            let bytes = encode_fixed(inst)?;
+            if program.profile == Profile::V6m && is_wide(&bytes) {
+                return Err(format!("'{}' isn't an ARMv6-M instruction", crate::thumb::print_inst(inst)));
+            }
+            sizes.push(bytes.len());
+            fixed.push(Some(bytes));
+        }
+    }
+
+    // Grow whatever doesn't reach until everything does, sizes only ever go up so this ends
+    let mut labels: HashMap<&str, u32>;
+    let mut addresses = vec![];
+    loop {
+        labels = absolute.iter().map(|(name, address)| (name.as_str(), *address)).collect();
+        addresses.clear();
+        let mut address = base;
+        for (index, (inst, size)) in code.iter().zip(sizes.iter_mut()).enumerate() {
+            match inst {
+                Inst::Label(label) => {labels.insert(label, address);}
+                Inst::Pool => *size = (address % 4) as usize + 4 * pools[&index].len(),
+                _ => {}
+            }
+            addresses.push(address);
+            address += *size as u32;
+        }
+        address = (address + 3) & !3;
+        for (label, bytes) in &program.rodata {
+            labels.insert(label, address);
+            address += bytes.len() as u32;
+        }
+
+        let mut changed = false;
+        let mut pc = base;
+        for (index, (inst, size)) in code.iter().zip(sizes.iter_mut()).enumerate() {
+            if let Some(label) = relative_target(inst) {
+                let target = target_of(index, label, &labels, &addresses, &literals);
+                if encode_relative(inst, pc, target, *size).is_none() {
+                    let Some(bigger) = relative_sizes(inst, program.profile).iter().find(|bigger| **bigger > *size) else {
+                        if let Inst::LoadLiteral(..) = inst {return Err(format!("The literal pool with '{}' is too far away", label))}
+                        return Err(format!("'{}' is too far away to jump to", label));
+                    };
+                    *size = *bigger;
+                    changed = true;
+                }
+            }
+            pc += *size as u32;
+        }
+        if !changed {break}
+    }
+
+    // Addresses of code in a data word (the vector table) get the Thumb bit, like the linker
+    // does for .thumb_func symbols
+    let code_labels: Vec<&str> = code.iter().filter_map(|inst| match inst {Inst::Label(label) => Some(label.as_str()), _ => None}).collect();
+    let thumb_bit = |label: &str| (code_labels.contains(&label) && label != crate::thumb::VECTOR_TABLE) as u32;
+    let mut bytes = vec![];
+    for (index, ((inst, size), fixed)) in code.iter().zip(&sizes).zip(fixed).enumerate() {
+        match fixed {
+            Some(fixed) => bytes.extend(fixed),
+            None if **inst == Inst::Pool => {
+                while bytes.len() % 4 != 0 {bytes.push(0)}
+                for symbol in &pools[&index] {
+                    bytes.extend((labels[symbol] | thumb_bit(symbol)).to_le_bytes());
+                }
+            }
+            None => {
+                let label = relative_target(inst).unwrap();
+                let pc = base + bytes.len() as u32;
+                let thumb = if matches!(inst, Inst::Word(_)) {thumb_bit(label)} else {0};
+                let target = target_of(index, label, &labels, &addresses, &literals);
+                // Can't fail, the loop above made sure everything fits
+                bytes.extend(encode_relative(inst, pc, target | thumb, *size).unwrap());
+            }
+        }
+    }
+    while bytes.len() % 4 != 0 {bytes.push(0)}
+    for (_, data) in &program.rodata {bytes.extend(data)}
+
+    let entry = match labels.get(program.entry.as_str()) {
+        Some(entry) => *entry,
+        None => return Err(format!("The program has no entry point '{}' to start at", program.entry)),
+    };
+    let mut symbols: Vec<(String, u32)> = labels.iter()
+        .filter(|(name, address)| !name.starts_with(".L") && **address >= base && **address < base + bytes.len() as u32)
+        .map(|(name, address)| (name.to_string(), *address)).collect();
+    symbols.sort_by_key(|(name, address)| (*address, name.clone()));
+    Ok(Image { base, bytes, entry, symbols })
+}
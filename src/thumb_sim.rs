@@ -0,0 +1,715 @@
+use std::collections::HashMap;
+use std::io::{Read, Write};
+
+use crate::compiler_settings::*;
+use crate::intrinsics::open_options;
+use crate::backend::Image;
+use crate::thumb::{Profile, DIV64, VECTOR_TABLE};
+
+// A small ARMv7-M simulator, so Thumb output can be run without qemu or a board around.
+// Runs the Thumb instruction set the M3/M4 have minus the privileged parts: all of the 16-bit
+// instructions, the common 32-bit ones (data processing with shifts and modified immediates,
+// movw/movt, loads and stores, ldm/stm, multiplies, divides, extends, branches) and IT
+// blocks. No exceptions get raised, so interrupt handlers never run.
+// For ARMv6-M programs everything the M0/M0+ don't have is an illegal instruction: 32-bit
+// ones other than bl and the barriers, cbz/cbnz and IT.
+// Linux programs make the ARM EABI syscalls (svc 0 with the number in r7) the backend's
+// runtime uses. Memory is one flat array with the program at THUMB_LOAD_ADDRESS, the heap
+// right after it and the stack at the top.
+// Freestanding programs boot like the hardware does, with the stack pointer and the reset
+// handler from the vector table. They end in a branch to itself, the simulator stops there
+// instead of spinning forever and the exit code is whatever main left in r0.
+
+//
+// STRUCTS
+//
+
+struct Machine {
+    r: [u32; 16],
+    pc: u32,
+    /// Set by writes to the pc, where the next instruction comes from
+    jump: Option<u32>,
+    n: bool,
+    z: bool,
+    c: bool,
+    v: bool,
+    /// IT block state: the condition in the upper 4 bits, how many instructions are left in
+    /// the lower ones (same as the hardware's ITSTATE)
+    it: u32,
+    profile: Profile,
+    memory: Vec<u8>,
+    /// Lowest and current program break
+    heap_start: u32,
+    brk: u32,
+    /// Files opened with open, 0-2 are always the standard streams
+    files: HashMap<u32, std::fs::File>,
+    out: std::io::BufWriter<std::io::Stdout>,
+}
+
+//
+// FUNCTIONS
+//
+
+// Linux errno values the syscalls give back (negated)
+const EBADF: i32 = 9;
+const EFAULT: i32 = 14;
+const ENOSYS: i32 = 38;
+
+const SP: usize = 13;
+const LR: usize = 14;
+const PC: usize = 15;
+
+// Shift types, the hardware's encoding plus rrx
+const LSL: u32 = 0;
+const LSR: u32 = 1;
+const ASR: u32 = 2;
+const ROR: u32 = 3;
+const RRX: u32 = 4;
+
+fn sign_extend(value: u32, bits: u32) -> i32 {
+    ((value << (32 - bits)) as i32) >> (32 - bits)
+}
+
+/// `value` shifted, and the carry out of it
+fn shift(value: u32, kind: u32, amount: u32, carry: bool) -> (u32, bool) {
+    if amount == 0 && kind != RRX {return (value, carry)}
+    match kind {
+        LSL if amount < 32 => (value << amount, value >> (32 - amount) & 1 == 1),
+        LSL => (0, amount == 32 && value & 1 == 1),
+        LSR if amount < 32 => (value >> amount, value >> (amount - 1) & 1 == 1),
+        LSR => (0, amount == 32 && value >> 31 == 1),
+        ASR if amount < 32 => (((value as i32) >> amount) as u32, value >> (amount - 1) & 1 == 1),
+        ASR => (((value as i32) >> 31) as u32, value >> 31 == 1),
+        ROR => {
+            let result = value.rotate_right(amount % 32);
+            (result, result >> 31 == 1)
+        }
+        _ => ((carry as u32) << 31 | value >> 1, value & 1 == 1),
+    }
+}
+
+/// The shift of an instruction with a 5-bit immediate amount, where 0 means 32 for the
+/// right shifts and rrx for a rotate
+fn immediate_shift(kind: u32, imm5: u32) -> (u32, u32) {
+    match (kind, imm5) {
+        (LSR | ASR, 0) => (kind, 32),
+        (ROR, 0) => (RRX, 1),
+        _ => (kind, imm5),
+    }
+}
+
+/// The 32-bit value of a modified immediate (i:imm3:imm8), and its carry
+fn expand_immediate(imm12: u32, carry: bool) -> (u32, bool) {
+    let byte = imm12 & 0xff;
+    if imm12 >> 10 == 0 {
+        let value = match imm12 >> 8 & 3 {
+            0 => byte,
+            1 => byte << 16 | byte,
+            2 => byte << 24 | byte << 8,
+            _ => byte * 0x0101_0101,
+        };
+        return (value, carry);
+    }
+    let value = (0x80 | (imm12 & 0x7f)).rotate_right(imm12 >> 7);
+    (value, value >> 31 == 1)
+}
+
+/// x + y + carry, with the carry and overflow out of it
+fn add_with_carry(x: u32, y: u32, carry: bool) -> (u32, bool, bool) {
+    let unsigned = x as u64 + y as u64 + carry as u64;
+    let signed = x as i32 as i64 + y as i32 as i64 + carry as i64;
+    let result = unsigned as u32;
+    (result, unsigned >> 32 != 0, result as i32 as i64 != signed)
+}
+
+impl Machine {
+    fn new(image: &Image, profile: Profile) -> Result<Machine, String> {
+        let mut memory = vec![0; THUMB_MEMORY_SIZE as usize];
+        let start = image.base as usize;
+        memory[start..start + image.bytes.len()].copy_from_slice(&image.bytes);
+        let heap_start = (image.base + image.bytes.len() as u32 + 0xfff) & !0xfff;
+        let mut machine = Machine {
+            r: [0; 16], pc: image.entry, jump: None, n: false, z: false, c: false, v: false, it: 0, profile,
+            memory, heap_start, brk: heap_start, files: HashMap::new(), out: std::io::BufWriter::new(std::io::stdout()),
+        };
+        machine.r[SP] = THUMB_MEMORY_SIZE;
+        // Boot from the vector table if there is one, like the hardware
+        if let Some((_, table)) = image.symbols.iter().find(|(name, _)| name == VECTOR_TABLE) {
+            machine.r[SP] = machine.load(*table, 4)?;
+            machine.pc = machine.load(table + 4, 4)? & !1;
+        }
+        Ok(machine)
+    }
+
+    /// Memory index of `length` bytes at `address`, anything below the program counts as a
+    /// null pointer
+    fn range(&self, address: u32, length: u32) -> Result<std::ops::Range<usize>, String> {
+        match address.checked_add(length) {
+            Some(end) if address >= THUMB_LOAD_ADDRESS && end <= THUMB_MEMORY_SIZE => Ok(address as usize..end as usize),
+            _ => Err(format!("Memory access to {:#x} ({} bytes) is outside of the memory", address, length)),
+        }
+    }
+
+    fn load(&self, address: u32, length: u32) -> Result<u32, String> {
+        let range = self.range(address, length)?;
+        Ok(self.memory[range].iter().rev().fold(0, |value, byte| value << 8 | *byte as u32))
+    }
+
+    fn store(&mut self, address: u32, length: u32, value: u32) -> Result<(), String> {
+        let range = self.range(address, length)?;
+        self.memory[range].copy_from_slice(&value.to_le_bytes()[..length as usize]);
+        Ok(())
+    }
+
+    /// The NUL terminated string at an address
+    fn string(&self, address: u32) -> Result<String, String> {
+        let start = self.range(address, 0)?.start;
+        match self.memory[start..].iter().position(|byte| *byte == 0) {
+            Some(length) => Ok(String::from_utf8_lossy(&self.memory[start..start + length]).to_string()),
+            None => Err(format!("String at {:#x} has no NUL terminator", address)),
+        }
+    }
+
+    /// A register, the pc reads as the instruction's address plus 4
+    fn get(&self, reg: u32) -> u32 {
+        if reg as usize == PC {self.pc.wrapping_add(4)} else {self.r[reg as usize]}
+    }
+
+    /// Writing the pc is a branch, which has to stay in Thumb state
+    fn set(&mut self, reg: u32, value: u32) -> Result<(), String> {
+        if reg as usize != PC {
+            self.r[reg as usize] = value;
+            return Ok(());
+        }
+        if value & 1 == 0 {
+            return Err(format!("Branch to {:#x} would leave Thumb state (or return from an exception the simulator never raised)", value));
+        }
+        self.jump = Some(value & !1);
+        Ok(())
+    }
+
+    fn set_nz(&mut self, value: u32) {
+        self.n = value >> 31 == 1;
+        self.z = value == 0;
+    }
+
+    fn condition(&self, cond: u32) -> bool {
+        match cond {
+            0 => self.z, 1 => !self.z, 2 => self.c, 3 => !self.c, 4 => self.n, 5 => !self.n, 6 => self.v, 7 => !self.v,
+            8 => self.c && !self.z, 9 => !self.c || self.z, 10 => self.n == self.v, 11 => self.n != self.v,
+            12 => !self.z && self.n == self.v, 13 => self.z || self.n != self.v,
+            _ => true,
+        }
+    }
+
+    /// Data processing with the 32-bit opcodes, flags only if `setflags`. `carry` is what a
+    /// shift or immediate left for the logical ones.
+    fn alu(&mut self, op: u32, a: u32, b: u32, carry: bool, setflags: bool) -> Result<u32, String> {
+        let arithmetic = |(result, c, v): (u32, bool, bool)| (result, Some((c, v)));
+        let (result, flags) = match op {
+            0 => (a & b, None),
+            1 => (a & !b, None),
+            2 => (a | b, None),
+            3 => (a | !b, None),
+            4 => (a ^ b, None),
+            8 => arithmetic(add_with_carry(a, b, false)),
+            10 => arithmetic(add_with_carry(a, b, self.c)),
+            11 => arithmetic(add_with_carry(a, !b, self.c)),
+            13 => arithmetic(add_with_carry(a, !b, true)),
+            14 => arithmetic(add_with_carry(!a, b, true)),
+            _ => return Err(format!("Undefined data processing opcode {}", op)),
+        };
+        if setflags {
+            self.set_nz(result);
+            match flags {
+                Some((c, v)) => {self.c = c; self.v = v}
+                None => self.c = carry,
+            }
+        }
+        Ok(result)
+    }
+
+    /// Load (sign extending if `signed`) or store of one register
+    fn transfer(&mut self, load: bool, rt: u32, address: u32, size: u32, signed: bool) -> Result<(), String> {
+        if load {
+            let value = self.load(address, size)?;
+            let value = if signed {sign_extend(value, 8 * size) as u32} else {value};
+            self.set(rt, value)
+        } else {
+            self.store(address, size, self.get(rt))
+        }
+    }
+
+    /// ldm/stm (and push/pop), `before` for the decrement before ones
+    fn multiple(&mut self, load: bool, rn: u32, list: u32, before: bool, writeback: bool) -> Result<(), String> {
+        let count = list.count_ones();
+        let base = self.get(rn);
+        let start = if before {base.wrapping_sub(4 * count)} else {base};
+        let mut address = start;
+        for reg in (0..16).filter(|reg| list >> reg & 1 == 1) {
+            self.transfer(load, reg, address, 4, false)?;
+            address += 4;
+        }
+        if writeback && !(load && list >> rn & 1 == 1) {
+            self.set(rn, if before {start} else {base.wrapping_add(4 * count)})?;
+        }
+        Ok(())
+    }
+
+    /// Runs the syscall in r7, returns the exit code if it was exit
+    fn syscall(&mut self) -> Result<Option<i64>, String> {
+        let (r0, r1, r2) = (self.r[0], self.r[1], self.r[2]);
+        let result = |result: std::io::Result<usize>| match result {
+            Ok(count) => count as i32,
+            Err(error) => -error.raw_os_error().unwrap_or(5),
+        };
+        let value: i32 = match self.r[7] {
+            // exit(code), exit_group(code)
+            1 | 248 => return Ok(Some(r0 as i32 as i64)),
+            // read(fd, buffer, length)
+            3 => {
+                let Ok(range) = self.range(r1, r2) else {return self.returns(-EFAULT)};
+                let buffer = &mut self.memory[range];
+                match r0 {
+                    0 => {
+                        let _ = self.out.flush();
+                        result(std::io::stdin().read(buffer))
+                    }
+                    fd => match self.files.get_mut(&fd) {
+                        Some(file) => result(file.read(buffer)),
+                        None => -EBADF,
+                    },
+                }
+            }
+            // write(fd, buffer, length)
+            4 => {
+                let Ok(range) = self.range(r1, r2) else {return self.returns(-EFAULT)};
+                let buffer = &self.memory[range];
+                let written = match r0 {
+                    1 => self.out.write_all(buffer),
+                    2 => self.out.flush().and_then(|_| std::io::stderr().write_all(buffer)),
+                    fd => match self.files.get_mut(&fd) {
+                        Some(file) => file.write_all(buffer),
+                        None => return self.returns(-EBADF),
+                    },
+                };
+                result(written.map(|_| buffer.len()))
+            }
+            // open(path, flags, mode)
+            5 => {
+                let path = self.string(r0)?;
+                match open_options(r1 as i32 as i64, r2 as i64).open(path) {
+                    Ok(file) => {
+                        let fd = (3..).find(|fd| !self.files.contains_key(fd)).unwrap();
+                        self.files.insert(fd, file);
+                        fd as i32
+                    }
+                    Err(error) => result(Err(error)),
+                }
+            }
+            // close(fd)
+            6 => match self.files.remove(&r0) {
+                Some(_) => 0,
+                None => -EBADF,
+            },
+            // brk(address), 0 (or anything it can't do) just gives back the current one
+            45 => {
+                if r0 >= self.heap_start && r0 <= THUMB_MEMORY_SIZE - THUMB_STACK_SIZE {
+                    if r0 > self.brk {self.memory[self.brk as usize..r0 as usize].fill(0)}
+                    self.brk = r0;
+                }
+                self.brk as i32
+            }
+            _ => -ENOSYS,
+        };
+        self.returns(value)
+    }
+
+    fn returns(&mut self, value: i32) -> Result<Option<i64>, String> {
+        self.r[0] = value as u32;
+        Ok(None)
+    }
+
+    /// Stops the program, a branch to itself is how freestanding ones end
+    fn halt(&mut self) -> Result<Option<i64>, String> {
+        let _ = self.out.flush();
+        Ok(Some(self.r[0] as i32 as i64))
+    }
+
+    /// Runs one instruction, returns the exit code once the program is done
+    fn step(&mut self) -> Result<Option<i64>, String> {
+        let first = self.load(self.pc, 2)?;
+        let wide = first >> 11 >= 0b11101;
+        let second = if wide {self.load(self.pc + 2, 2)?} else {0};
+        let next = self.pc.wrapping_add(if wide {4} else {2});
+        self.jump = None;
+        // bl, and dsb/dmb/isb
+        if self.profile == Profile::V6m && wide && second & 0xd000 != 0xd000 && first != 0xf3bf {
+            return Err(format!("Instruction {:#06x} {:#06x} isn't an ARMv6-M one", first, second));
+        }
+
+        // Inside an IT block instructions only run if the condition holds, and the 16-bit
+        // ones don't set the flags
+        let in_it = self.it & 0xf != 0;
+        let result = if in_it && !self.condition(self.it >> 4) {
+            Ok(None)
+        } else if wide {
+            self.execute_wide(first, second)
+        } else {
+            self.execute(first, !in_it)
+        };
+        if in_it {
+            self.it = if self.it & 7 == 0 {0} else {self.it & 0xe0 | (self.it << 1 & 0x1f)};
+        }
+        let result = result?;
+        if result.is_none() {self.pc = self.jump.unwrap_or(next)}
+        Ok(result)
+    }
+
+    /// A 16-bit instruction
+    fn execute(&mut self, h: u32, setflags: bool) -> Result<Option<i64>, String> {
+        let illegal = || format!("Illegal instruction {:#06x}", h);
+        let (rd, rn, rm) = (h & 7, h >> 3 & 7, h >> 6 & 7);
+        let imm8 = h & 0xff;
+        let big = h >> 8 & 7;
+        match h >> 11 {
+            // lsls, lsrs, asrs with an immediate
+            0b00000..=0b00010 => {
+                let (kind, amount) = immediate_shift(h >> 11, h >> 6 & 31);
+                let (result, carry) = shift(self.get(rn), kind, amount, self.c);
+                self.set(rd, result)?;
+                if setflags {self.set_nz(result); self.c = carry}
+            }
+            // adds/subs with a register or a 3-bit immediate
+            0b00011 => {
+                let operand = if h >> 10 & 1 == 1 {rm} else {self.get(rm)};
+                let op = if h >> 9 & 1 == 1 {13} else {8};
+                let result = self.alu(op, self.get(rn), operand, self.c, setflags)?;
+                self.set(rd, result)?;
+            }
+            0b00100 => {
+                self.set(big, imm8)?;
+                if setflags {self.set_nz(imm8)}
+            }
+            0b00101 => {self.alu(13, self.get(big), imm8, self.c, true)?;}
+            0b00110 | 0b00111 => {
+                let op = if h >> 11 == 0b00110 {8} else {13};
+                let result = self.alu(op, self.get(big), imm8, self.c, setflags)?;
+                self.set(big, result)?;
+            }
+            0b01000 if h >> 10 & 1 == 0 => {
+                let (a, b) = (self.get(rd), self.get(rn));
+                match h >> 6 & 15 {
+                    // lsl, lsr, asr, ror by a register
+                    op @ (2 | 3 | 4 | 7) => {
+                        let kind = match op {2 => LSL, 3 => LSR, 4 => ASR, _ => ROR};
+                        let (result, carry) = shift(a, kind, b & 0xff, self.c);
+                        self.set(rd, result)?;
+                        if setflags {self.set_nz(result); self.c = carry}
+                    }
+                    // tst, cmp, cmn
+                    8 => {self.alu(0, a, b, self.c, true)?;}
+                    10 => {self.alu(13, a, b, self.c, true)?;}
+                    11 => {self.alu(8, a, b, self.c, true)?;}
+                    9 => {
+                        let result = self.alu(14, b, 0, self.c, setflags)?;
+                        self.set(rd, result)?;
+                    }
+                    13 => {
+                        let result = a.wrapping_mul(b);
+                        self.set(rd, result)?;
+                        if setflags {self.set_nz(result)}
+                    }
+                    15 => {
+                        let result = !b;
+                        self.set(rd, result)?;
+                        if setflags {self.set_nz(result)}
+                    }
+                    op => {
+                        let op = [0, 4, 0, 0, 0, 10, 11, 0, 0, 0, 0, 0, 2, 0, 1, 0][op as usize];
+                        let result = self.alu(op, a, b, self.c, setflags)?;
+                        self.set(rd, result)?;
+                    }
+                }
+            }
+            // add, cmp and mov with any registers, bx and blx
+            0b01000 => {
+                let rdn = (h >> 4 & 8) | rd;
+                let rm = h >> 3 & 15;
+                let value = match h >> 8 & 3 {
+                    0 => self.get(rdn).wrapping_add(self.get(rm)),
+                    2 => self.get(rm),
+                    1 => {
+                        self.alu(13, self.get(rdn), self.get(rm), self.c, true)?;
+                        return Ok(None);
+                    }
+                    _ => {
+                        if h >> 7 & 1 == 1 {self.r[LR] = (self.pc + 2) | 1}
+                        self.set(PC as u32, self.get(rm))?;
+                        return Ok(None);
+                    }
+                };
+                // Plain branches, these don't care about the Thumb bit
+                if rdn as usize == PC {self.jump = Some(value & !1)} else {self.set(rdn, value)?}
+            }
+            // ldr from a literal
+            0b01001 => {
+                let address = (self.get(PC as u32) & !3) + imm8 * 4;
+                self.transfer(true, big, address, 4, false)?;
+            }
+            // Loads and stores with a register offset
+            0b01010 | 0b01011 => {
+                let address = self.get(rn).wrapping_add(self.get(rm));
+                let (load, size, signed) = [(false, 4, false), (false, 2, false), (false, 1, false), (true, 1, true),
+                    (true, 4, false), (true, 2, false), (true, 1, false), (true, 2, true)][(h >> 9 & 7) as usize];
+                self.transfer(load, rd, address, size, signed)?;
+            }
+            // ... and with an immediate one
+            0b01100..=0b10001 => {
+                let size = match h >> 12 {6 => 4, 7 => 1, _ => 2};
+                let address = self.get(rn).wrapping_add((h >> 6 & 31) * size);
+                self.transfer(h >> 11 & 1 == 1, rd, address, size, false)?;
+            }
+            0b10010 | 0b10011 => {
+                let address = self.r[SP].wrapping_add(imm8 * 4);
+                self.transfer(h >> 11 & 1 == 1, big, address, 4, false)?;
+            }
+            // adr, add rd, sp, #imm
+            0b10100 => self.set(big, (self.get(PC as u32) & !3) + imm8 * 4)?,
+            0b10101 => self.set(big, self.r[SP].wrapping_add(imm8 * 4))?,
+            0b10110 | 0b10111 => return self.miscellaneous(h),
+            0b11000 | 0b11001 => self.multiple(h >> 11 & 1 == 1, big, imm8, false, true)?,
+            0b11010 | 0b11011 => match h >> 8 & 15 {
+                14 => return Err("Hit a udf".to_string()),
+                15 => if let Some(exit_code) = self.syscall()? {
+                    let _ = self.out.flush();
+                    return Ok(Some(exit_code));
+                },
+                cond => if self.condition(cond) {
+                    self.jump = Some(self.get(PC as u32).wrapping_add(sign_extend(imm8 << 1, 9) as u32));
+                },
+            },
+            0b11100 => {
+                let offset = sign_extend((h & 0x7ff) << 1, 12);
+                if offset == -4 {return self.halt()}
+                self.jump = Some(self.get(PC as u32).wrapping_add(offset as u32));
+            }
+            _ => return Err(illegal()),
+        }
+        Ok(None)
+    }
+
+    /// The 16-bit `1011 ....` group
+    fn miscellaneous(&mut self, h: u32) -> Result<Option<i64>, String> {
+        let (rd, rm) = (h & 7, h >> 3 & 7);
+        let it = h >> 8 & 15 == 15 && h & 0xf != 0;
+        if self.profile == Profile::V6m && (matches!(h >> 8 & 15, 1 | 3 | 9 | 11) || it) {
+            return Err(format!("Instruction {:#06x} isn't an ARMv6-M one", h));
+        }
+        match h >> 8 & 15 {
+            0 => {
+                let imm = (h & 0x7f) * 4;
+                self.r[SP] = if h >> 7 & 1 == 1 {self.r[SP].wrapping_sub(imm)} else {self.r[SP].wrapping_add(imm)};
+            }
+            // cbz, cbnz
+            1 | 3 | 9 | 11 => {
+                let zero = self.get(rd) == 0;
+                if zero != (h >> 11 & 1 == 1) {
+                    self.jump = Some(self.get(PC as u32) + ((h >> 9 & 1) << 6 | (h >> 3 & 31) << 1));
+                }
+            }
+            2 => {
+                let value = self.get(rm);
+                self.set(rd, match h >> 6 & 3 {
+                    0 => value as i16 as u32,
+                    1 => value as i8 as u32,
+                    2 => value & 0xffff,
+                    _ => value & 0xff,
+                })?;
+            }
+            4 | 5 => self.multiple(false, SP as u32, (h & 0xff) | (h >> 8 & 1) << LR, true, true)?,
+            12 | 13 => self.multiple(true, SP as u32, (h & 0xff) | (h >> 8 & 1) << PC, false, true)?,
+            // cps, interrupts never happen here anyway
+            6 if h & 0xffe8 == 0xb660 => {}
+            10 => {
+                let value = self.get(rm);
+                self.set(rd, match h >> 6 & 3 {
+                    0 => value.swap_bytes(),
+                    1 => (value & 0x00ff_00ff) << 8 | (value >> 8 & 0x00ff_00ff),
+                    3 => (value as u16).swap_bytes() as i16 as u32,
+                    _ => return Err(format!("Illegal instruction {:#06x}", h)),
+                })?;
+            }
+            14 => return Err("Hit a breakpoint".to_string()),
+            // it, or a hint (nop, wfi, ...)
+            15 => if h & 0xf != 0 {self.it = h & 0xff},
+            _ => return Err(format!("Illegal instruction {:#06x}", h)),
+        }
+        Ok(None)
+    }
+
+    /// A 32-bit instruction
+    fn execute_wide(&mut self, h1: u32, h2: u32) -> Result<Option<i64>, String> {
+        let illegal = || format!("Illegal instruction {:#06x} {:#06x}", h1, h2);
+        let rn = h1 & 15;
+        let (rd, rm) = (h2 >> 8 & 15, h2 & 15);
+        let setflags = h1 >> 4 & 1 == 1;
+        let imm12 = (h1 >> 10 & 1) << 11 | (h2 >> 12 & 7) << 8 | (h2 & 0xff);
+
+        // Data processing, the second operand a shifted register or a modified immediate
+        let data = if h1 & 0xfe00 == 0xea00 {
+            let (kind, amount) = immediate_shift(h2 >> 4 & 3, (h2 >> 12 & 7) << 2 | (h2 >> 6 & 3));
+            Some(shift(self.get(rm), kind, amount, self.c))
+        } else if h1 & 0xfa00 == 0xf000 && h2 & 0x8000 == 0 {
+            Some(expand_immediate(imm12, self.c))
+        } else {
+            None
+        };
+        if let Some((operand, carry)) = data {
+            let op = h1 >> 5 & 15;
+            // orr and orn without a first register are mov and mvn
+            let a = if rn as usize == PC && (op == 2 || op == 3) {0} else {self.get(rn)};
+            let result = self.alu(op, a, operand, carry, setflags)?;
+            // and, eor, add and sub into the pc are tst, teq, cmn and cmp
+            if !(rd as usize == PC && setflags) {self.set(rd, result)?}
+            return Ok(None);
+        }
+
+        // addw, movw, subw, movt (the i bit of the immediate is in the middle of the opcode)
+        let plain = (h1 & 0xfbf0) >> 4;
+        if matches!(plain, 0xf20 | 0xf24 | 0xf2a | 0xf2c) && h2 & 0x8000 == 0 {
+            let imm16 = (h1 & 15) << 12 | imm12;
+            let base = if rn as usize == PC {self.get(rn) & !3} else {self.get(rn)};
+            let value = match plain {
+                0xf20 => base.wrapping_add(imm12),
+                0xf24 => imm16,
+                0xf2a => base.wrapping_sub(imm12),
+                _ => self.get(rd) & 0xffff | imm16 << 16,
+            };
+            self.set(rd, value)?;
+            return Ok(None);
+        }
+
+        match h1 >> 4 {
+            // ldm/stm, push.w/pop.w
+            0xe88 | 0xe89 | 0xe8a | 0xe8b | 0xe90 | 0xe91 | 0xe92 | 0xe93 => {
+                self.multiple(h1 >> 4 & 1 == 1, rn, h2, h1 >> 8 & 1 == 1, h1 >> 5 & 1 == 1)?;
+            }
+            // Loads and stores of one register
+            0xf80..=0xf9f => {
+                let load = h1 >> 4 & 1 == 1;
+                let size = 1 << (h1 >> 5 & 3);
+                let signed = h1 >> 8 & 1 == 1;
+                if size == 8 || (!load && signed) {return Err(illegal())}
+                let base = self.get(rn);
+                if rn as usize == PC {
+                    let base = base & !3;
+                    let address = if h1 >> 7 & 1 == 1 {base + (h2 & 0xfff)} else {base - (h2 & 0xfff)};
+                    self.transfer(load, h2 >> 12, address, size, signed)?;
+                } else if h1 >> 7 & 1 == 1 {
+                    self.transfer(load, h2 >> 12, base.wrapping_add(h2 & 0xfff), size, signed)?;
+                } else if h2 >> 11 & 1 == 1 {
+                    // imm8 with index, add and writeback bits
+                    let imm = h2 & 0xff;
+                    let moved = if h2 >> 9 & 1 == 1 {base.wrapping_add(imm)} else {base.wrapping_sub(imm)};
+                    let address = if h2 >> 10 & 1 == 1 {moved} else {base};
+                    self.transfer(load, h2 >> 12, address, size, signed)?;
+                    if h2 >> 8 & 1 == 1 {self.set(rn, moved)?}
+                } else if h2 >> 6 & 0x3f == 0 {
+                    let address = base.wrapping_add(self.get(rm) << (h2 >> 4 & 3));
+                    self.transfer(load, h2 >> 12, address, size, signed)?;
+                } else {
+                    return Err(illegal());
+                }
+            }
+            // mla, mls, mul
+            0xfb0 => {
+                let ra = h2 >> 12;
+                let product = self.get(rn).wrapping_mul(self.get(rm));
+                let value = match (h2 >> 4 & 15, ra as usize) {
+                    (0, PC) => product,
+                    (0, _) => product.wrapping_add(self.get(ra)),
+                    (1, _) => self.get(ra).wrapping_sub(product),
+                    _ => return Err(illegal()),
+                };
+                self.set(rd, value)?;
+            }
+            // Long multiplies and divides, dividing by zero gives 0 unless the trap is enabled
+            0xfb8..=0xfbf => {
+                let (a, b) = (self.get(rn), self.get(rm));
+                let (lo, hi) = (h2 >> 12, rd);
+                let accumulated = (self.get(hi) as u64) << 32 | self.get(lo) as u64;
+                let long = match (h1 >> 4 & 7, h2 >> 4 & 15) {
+                    (0, 0) => (a as i32 as i64 * b as i32 as i64) as u64,
+                    (2, 0) => a as u64 * b as u64,
+                    (4, 0) => accumulated.wrapping_add((a as i32 as i64 * b as i32 as i64) as u64),
+                    (6, 0) => accumulated.wrapping_add(a as u64 * b as u64),
+                    (1, 15) => {
+                        self.set(rd, if b == 0 {0} else {(a as i32).wrapping_div(b as i32) as u32})?;
+                        return Ok(None);
+                    }
+                    (3, 15) => {
+                        self.set(rd, a.checked_div(b).unwrap_or(0))?;
+                        return Ok(None);
+                    }
+                    _ => return Err(illegal()),
+                };
+                self.set(lo, long as u32)?;
+                self.set(hi, (long >> 32) as u32)?;
+            }
+            // sxth, uxth, sxtb, uxtb (with a rotation), and clz
+            0xfa0 | 0xfa1 | 0xfa4 | 0xfa5 if rn as usize == PC && h2 & 0xf080 == 0xf080 => {
+                let value = self.get(rm).rotate_right(8 * (h2 >> 4 & 3));
+                self.set(rd, match h1 >> 4 & 7 {
+                    0 => value as i16 as u32,
+                    1 => value & 0xffff,
+                    4 => value as i8 as u32,
+                    _ => value & 0xff,
+                })?;
+            }
+            0xfab if h2 & 0xf0f0 == 0xf080 => self.set(rd, self.get(rm).leading_zeros())?,
+            // Branches, and the barriers and hints that live next to them
+            0xf00..=0xf7f if h2 & 0x8000 != 0 => {
+                let s = h1 >> 10 & 1;
+                let (j1, j2) = (h2 >> 13 & 1, h2 >> 11 & 1);
+                if h2 >> 12 & 1 == 1 {
+                    let (i1, i2) = (!(j1 ^ s) & 1, !(j2 ^ s) & 1);
+                    let offset = sign_extend(s << 24 | i1 << 23 | i2 << 22 | (h1 & 0x3ff) << 12 | (h2 & 0x7ff) << 1, 25);
+                    if h2 >> 14 & 1 == 1 {
+                        self.r[LR] = (self.pc + 4) | 1;
+                    } else if offset == -4 {
+                        return self.halt();
+                    }
+                    self.jump = Some(self.get(PC as u32).wrapping_add(offset as u32));
+                } else if h2 >> 14 & 1 == 0 && h1 >> 6 & 14 != 14 {
+                    let offset = sign_extend(s << 20 | j2 << 19 | j1 << 18 | (h1 & 0x3f) << 12 | (h2 & 0x7ff) << 1, 21);
+                    if self.condition(h1 >> 6 & 15) {self.jump = Some(self.get(PC as u32).wrapping_add(offset as u32))}
+                } else if h1 == 0xf3af || h1 == 0xf3bf {
+                    // nop.w and friends, dsb, dmb, isb
+                } else {
+                    return Err(format!("Instruction {:#06x} {:#06x} is a system one the simulator doesn't run", h1, h2));
+                }
+            }
+            _ => return Err(illegal()),
+        }
+        Ok(None)
+    }
+}
+
+/// Runs an encoded program (see thumb_encoder.rs), returns its exit code
+pub fn run(image: &Image, profile: Profile) -> Result<i64, String> {
+    let mut machine = Machine::new(image, profile)?;
+    loop {
+        match machine.step() {
+            Ok(Some(exit_code)) => return Ok(exit_code),
+            Ok(None) => {}
+            Err(error) => {
+                let _ = machine.out.flush();
+                let function = image.symbol_at(machine.pc).unwrap_or("?");
+                // The runtime's division traps with a udf, like x86 does with a SIGFPE
+                if function == DIV64 {return Err("Division by zero".to_string())}
+                return Err(format!("{} at {:#x} (in {})", error, machine.pc, function));
+            }
+        }
+    }
+}
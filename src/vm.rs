@@ -0,0 +1,181 @@
+use std::io::Write;
+
+use crate::bytecode::{Op, Program};
+use crate::compiler_settings::*;
+use crate::interpreter::Value;
+use crate::intrinsics::{Runtime, INTRINSICS};
+use crate::ir::BinaryOp;
+
+// Stack based VM for bytecode.rs programs.
+// All call frames share one value stack, a frame's slots sit right below whatever it pushes.
+
+//
+// STRUCTS
+//
+
+#[derive(Clone, Copy)]
+struct CallFrame {
+    function: usize,
+    /// Next instruction
+    pc: usize,
+    /// Stack index of slot 0
+    base: usize,
+}
+
+struct Vm<'a> {
+    program: &'a Program,
+    stack: Vec<Value>,
+    /// The running function
+    frame: CallFrame,
+    /// Its callers, innermost last
+    callers: Vec<CallFrame>,
+    /// print() output, buffered since it's the hottest thing in most test programs
+    out: std::io::BufWriter<std::io::Stdout>,
+    /// Memory and files for the intrinsics
+    runtime: Runtime,
+}
+
+//
+// FUNCTIONS
+//
+
+pub fn binary(op: BinaryOp, left: i64, right: i64) -> Result<i64, String> {
+    Ok(match op {
+        // Wrapping, same as the native backends
+        BinaryOp::Add => left.wrapping_add(right),
+        BinaryOp::Sub => left.wrapping_sub(right),
+        BinaryOp::Mul => left.wrapping_mul(right),
+        BinaryOp::Div => {
+            if right == 0 {return Err("Division by zero".to_string())}
+            left.wrapping_div(right)
+        }
+        BinaryOp::Lt => (left < right) as i64,
+        BinaryOp::Gt => (left > right) as i64,
+        BinaryOp::Le => (left <= right) as i64,
+        BinaryOp::Ge => (left >= right) as i64,
+        BinaryOp::Eq => (left == right) as i64,
+        BinaryOp::Ne => (left != right) as i64,
+    })
+}
+
+impl Vm<'_> {
+    fn pop(&mut self) -> Result<Value, String> {
+        self.stack.pop().ok_or_else(|| "Stack underflow".to_string())
+    }
+
+    fn pop_int(&mut self) -> Result<i64, String> {
+        match self.pop()? {
+            Value::Int(value) => Ok(value),
+            other => Err(format!("Expected an i64, not {:?}", other)),
+        }
+    }
+
+    /// Starts running `function`, its arguments are the top `parameters` values
+    fn enter(&mut self, function: usize) -> Result<(), String> {
+        let callee = &self.program.functions[function];
+        if self.callers.len() >= VM_MAX_DEPTH {
+            return Err(format!("Stack overflow calling '{}'", callee.name));
+        }
+        let base = self.stack.len().checked_sub(callee.parameters as usize).ok_or_else(|| "Stack underflow".to_string())?;
+        // Everything that isn't a parameter starts out as 0
+        self.stack.resize(base + callee.slots as usize, Value::Int(0));
+        self.callers.push(self.frame);
+        self.frame = CallFrame { function, pc: 0, base };
+        Ok(())
+    }
+
+    /// Runs one instruction, gives back the exit code once the entry function returns
+    fn step(&mut self) -> Result<Option<i64>, String> {
+        let code = &self.program.functions[self.frame.function].code;
+        let Some(op) = code.get(self.frame.pc).copied() else {
+            return Err(format!("Ran off the end of '{}'", self.program.functions[self.frame.function].name));
+        };
+        self.frame.pc += 1;
+        let base = self.frame.base;
+
+        match op {
+            Op::Const(index) => self.stack.push(self.program.constants[index as usize].clone()),
+            Op::Load(slot) => self.stack.push(self.stack[base + slot as usize].clone()),
+            Op::Store(slot) => {
+                let value = self.pop()?;
+                self.stack[base + slot as usize] = value;
+            }
+            Op::Pop => {self.pop()?;}
+            Op::Binary(op) => {
+                let right = self.pop_int()?;
+                let left = self.pop_int()?;
+                self.stack.push(Value::Int(binary(op, left, right)?));
+            }
+            Op::Jump(target) => self.frame.pc = target as usize,
+            Op::JumpIfZero(target) => {
+                if self.pop_int()? == 0 {self.frame.pc = target as usize}
+            }
+            Op::Call(callee, _) => self.enter(callee as usize)?,
+            Op::Print(args) => {
+                let start = self.stack.len().checked_sub(args as usize).ok_or_else(|| "Stack underflow".to_string())?;
+                let line: Vec<String> = self.stack.drain(start..).map(|value| value.to_string()).collect();
+                if let Err(error) = writeln!(self.out, "{}", line.join(" ")) {
+                    return Err(format!("Can't print: {}", error));
+                }
+            }
+            Op::Intrinsic(index) => {
+                let intrinsic = &INTRINSICS[index as usize];
+                let start = self.stack.len().checked_sub(intrinsic.parameters.len()).ok_or_else(|| "Stack underflow".to_string())?;
+                let args: Vec<Value> = self.stack.drain(start..).collect();
+                match self.runtime.call(index as usize, &args, &mut self.out)? {
+                    Value::Void => {}
+                    value => self.stack.push(value),
+                }
+            }
+            Op::Return => {
+                let value = self.pop()?;
+                self.stack.truncate(base);
+                // The entry function has the dummy frame from run() as its caller
+                if self.callers.len() == 1 {
+                    return match value {
+                        Value::Int(exit_code) => Ok(Some(exit_code)),
+                        other => Err(format!("Entry function returned {:?} instead of an i64", other)),
+                    };
+                }
+                self.stack.push(value);
+                self.frame = self.callers.pop().unwrap();
+            }
+        }
+        Ok(None)
+    }
+
+    /// Where the VM currently is, for error messages
+    fn backtrace(&self) -> String {
+        let mut out = String::new();
+        // callers[0] is run()'s dummy frame
+        let frames: Vec<&CallFrame> = std::iter::once(&self.frame).chain(self.callers[1..].iter().rev()).collect();
+        for frame in frames.iter().take(BACKTRACE_LIMIT) {
+            // pc already points past the failing instruction
+            out.push_str(&format!("\n    in {} at {:04}", self.program.functions[frame.function].name, frame.pc.saturating_sub(1)));
+        }
+        if frames.len() > BACKTRACE_LIMIT {
+            out.push_str(&format!("\n    ... {} more", frames.len() - BACKTRACE_LIMIT));
+        }
+        out
+    }
+}
+
+/// Runs a bytecode program, returns the exit code
+pub fn run(program: &Program) -> Result<i64, String> {
+    if debug_prints(VM_DEBUG_PRINTS) {eprintln!("- - - VM")}
+
+    let dummy = CallFrame { function: program.entry as usize, pc: 0, base: 0 };
+    let mut vm = Vm { program, stack: vec![], frame: dummy, callers: vec![], out: std::io::BufWriter::new(std::io::stdout()), runtime: Runtime::default() };
+    let result = vm.enter(program.entry as usize).and_then(|_| loop {
+        if let Some(exit_code) = vm.step()? {break Ok(exit_code)}
+    });
+    // Output before the error should still show up
+    let _ = vm.out.flush();
+    let exit_code = match result {
+        Ok(exit_code) => exit_code,
+        Err(error) => return Err(format!("{}{}", error, vm.backtrace())),
+    };
+
+    if debug_prints(VM_DEBUG_PRINTS) {eprintln!("- - - VM done!")}
+    Ok(exit_code)
+}
@@ -0,0 +1,669 @@
+use std::collections::HashMap;
+
+use crate::c_backend::calls;
+use crate::compiler_settings::*;
+use crate::intrinsics::{self, INTRINSICS};
+use crate::ir::symbol;
+use crate::parser::{Expression, Operator, Statement};
+use crate::seman::{check_expression, Analysis, Scope, Type};
+use crate::source_map::position;
+
+// WebAssembly backend (wasm32). Like the C backend it works straight from the checked AST:
+// Wasm only has structured control flow, and Galvan's if/while map onto if/block/loop as they
+// are, no need to rebuild them from the IR's basic blocks.
+// Variables are Wasm locals, an i64 stays an i64 and a str is the i32 address of its NUL
+// terminated bytes in linear memory. String literals sit at WASM_DATA_START, the heap comes
+// right after them, a bump allocator that grows the memory when it runs out.
+// print() and the syscall intrinsics are imports the host provides, the other intrinsics are
+// small Wasm functions. The top level is exported as `_start` (or --entry) and returns the exit
+// code, the memory is exported as `memory` so the host can read strings out of it.
+// to_wat() writes the text format, wasm_encoder.rs the binary one.
+
+//
+// STRUCTS
+//
+
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+#[derive(PartialEq)]
+pub enum ValType {
+    I32,
+    I64,
+}
+
+/// The numeric instructions the backend uses, all of them take their operands from the stack
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+#[derive(PartialEq)]
+pub enum Numeric {
+    I32Eqz, I32Eq, I32LtU, I32GtU, I32Add, I32Sub, I32And, I32Shl, I32ShrU, I32WrapI64,
+    I64Eqz, I64Eq, I64Ne, I64LtS, I64GtS, I64GtU, I64LeS, I64GeS,
+    I64Add, I64Sub, I64Mul, I64DivS, I64ExtendI32U,
+}
+
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+#[derive(PartialEq)]
+pub enum Inst {
+    I32Const(i32),
+    I64Const(i64),
+    LocalGet(u32),
+    LocalSet(u32),
+    LocalTee(u32),
+    /// The only global is the heap pointer
+    GlobalGet,
+    GlobalSet,
+    Numeric(Numeric),
+    /// Function index, the imports come first
+    Call(u32),
+    Drop,
+    Return,
+    /// Blocks never take or leave values, everything goes through locals
+    Block,
+    Loop,
+    If,
+    Else,
+    End,
+    /// Relative depth of the block it jumps out of (or the loop it jumps back to), 0 is the
+    /// innermost
+    Br(u32),
+    BrIf(u32),
+    Load8U,
+    Store8,
+    MemorySize,
+    MemoryGrow,
+}
+
+/// A function the host provides, always from WASM_IMPORT_MODULE
+#[derive(Debug)]
+pub struct Import {
+    pub name: String,
+    pub params: Vec<ValType>,
+    pub result: Option<ValType>,
+}
+
+#[derive(Debug)]
+pub struct Function {
+    pub name: String,
+    /// The first `params` locals are the parameters
+    pub params: usize,
+    pub locals: Vec<(String, ValType)>,
+    pub result: Option<ValType>,
+    pub body: Vec<Inst>,
+    /// Name it's exported under
+    pub export: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct Program {
+    pub imports: Vec<Import>,
+    pub functions: Vec<Function>,
+    /// The string literals, they go at WASM_DATA_START
+    pub data: Vec<u8>,
+    /// Where the heap starts, right after the data
+    pub heap_start: u32,
+}
+
+struct Generator<'a> {
+    analysis: &'a Analysis,
+    /// Function index of everything callable, Galvan functions by symbol(name) and intrinsics
+    /// by their own name
+    indices: HashMap<String, u32>,
+    /// String literal -> its address
+    strings: HashMap<String, u32>,
+    data: Vec<u8>,
+    /// Whether anything divided, the division helper only gets added then
+    divides: bool,
+    /// The current function's
+    locals: Vec<(String, ValType)>,
+    body: Vec<Inst>,
+}
+
+//
+// FUNCTIONS
+//
+
+/// Helper for `/`, Wasm traps on i64::MIN / -1 where Galvan wraps
+const DIVISION: &str = "__div";
+
+const PRINT_INT: &str = "print_i64";
+const PRINT_STR: &str = "print_str";
+
+impl ValType {
+    pub fn name(self) -> &'static str {
+        match self {
+            ValType::I32 => "i32",
+            ValType::I64 => "i64",
+        }
+    }
+}
+
+fn val_type(ty: Type) -> Option<ValType> {
+    match ty {
+        Type::Int => Some(ValType::I64),
+        Type::Str => Some(ValType::I32),
+        Type::Void => None,
+    }
+}
+
+impl Numeric {
+    pub fn name(self) -> &'static str {
+        match self {
+            Numeric::I32Eqz => "i32.eqz",
+            Numeric::I32Eq => "i32.eq",
+            Numeric::I32LtU => "i32.lt_u",
+            Numeric::I32GtU => "i32.gt_u",
+            Numeric::I32Add => "i32.add",
+            Numeric::I32Sub => "i32.sub",
+            Numeric::I32And => "i32.and",
+            Numeric::I32Shl => "i32.shl",
+            Numeric::I32ShrU => "i32.shr_u",
+            Numeric::I32WrapI64 => "i32.wrap_i64",
+            Numeric::I64Eqz => "i64.eqz",
+            Numeric::I64Eq => "i64.eq",
+            Numeric::I64Ne => "i64.ne",
+            Numeric::I64LtS => "i64.lt_s",
+            Numeric::I64GtS => "i64.gt_s",
+            Numeric::I64GtU => "i64.gt_u",
+            Numeric::I64LeS => "i64.le_s",
+            Numeric::I64GeS => "i64.ge_s",
+            Numeric::I64Add => "i64.add",
+            Numeric::I64Sub => "i64.sub",
+            Numeric::I64Mul => "i64.mul",
+            Numeric::I64DivS => "i64.div_s",
+            Numeric::I64ExtendI32U => "i64.extend_i32_u",
+        }
+    }
+
+    pub fn opcode(self) -> u8 {
+        match self {
+            Numeric::I32Eqz => 0x45,
+            Numeric::I32Eq => 0x46,
+            Numeric::I32LtU => 0x49,
+            Numeric::I32GtU => 0x4b,
+            Numeric::I32Add => 0x6a,
+            Numeric::I32Sub => 0x6b,
+            Numeric::I32And => 0x71,
+            Numeric::I32Shl => 0x74,
+            Numeric::I32ShrU => 0x76,
+            Numeric::I32WrapI64 => 0xa7,
+            Numeric::I64Eqz => 0x50,
+            Numeric::I64Eq => 0x51,
+            Numeric::I64Ne => 0x52,
+            Numeric::I64LtS => 0x53,
+            Numeric::I64GtS => 0x55,
+            Numeric::I64GtU => 0x56,
+            Numeric::I64LeS => 0x57,
+            Numeric::I64GeS => 0x59,
+            Numeric::I64Add => 0x7c,
+            Numeric::I64Sub => 0x7d,
+            Numeric::I64Mul => 0x7e,
+            Numeric::I64DivS => 0x7f,
+            Numeric::I64ExtendI32U => 0xad,
+        }
+    }
+}
+
+/// The comparison for an operator and its opposite, None for arithmetic
+fn comparison(operator: Operator) -> Option<(Numeric, Numeric)> {
+    match operator {
+        Operator::LesserThan => Some((Numeric::I64LtS, Numeric::I64GeS)),
+        Operator::GreaterThan => Some((Numeric::I64GtS, Numeric::I64LeS)),
+        Operator::EqualLesserThan => Some((Numeric::I64LeS, Numeric::I64GtS)),
+        Operator::EqualGreaterThan => Some((Numeric::I64GeS, Numeric::I64LtS)),
+        Operator::EqualTo => Some((Numeric::I64Eq, Numeric::I64Ne)),
+        Operator::Inequal => Some((Numeric::I64Ne, Numeric::I64Eq)),
+        _ => None,
+    }
+}
+
+/// Name of the import for a syscall intrinsic, `__sys_write` -> `sys_write`
+fn import_name(intrinsic: &str) -> String {
+    intrinsic.trim_start_matches('_').to_string()
+}
+
+/// Body of an intrinsic that isn't a syscall, see intrinsics.rs, along with the locals it needs
+/// besides the parameters
+fn intrinsic_body(name: &str) -> (Vec<(&'static str, ValType)>, Vec<Inst>) {
+    use Inst::*;
+    use crate::wasm::Numeric::*;
+    match name {
+        // Returns 0 when the memory can't grow, which std::mem turns into an out of memory panic
+        "__alloc" => (vec![("address", ValType::I32), ("end", ValType::I32)], vec![
+            LocalGet(0), I64Const(u32::MAX as i64), Numeric(I64GtU), If, I64Const(0), Return, End,
+            GlobalGet, LocalTee(1), LocalGet(0), Numeric(I32WrapI64), Numeric(I32Add),
+            I32Const(7), Numeric(I32Add), I32Const(-8), Numeric(I32And), LocalTee(2),
+            LocalGet(1), Numeric(I32LtU), If, I64Const(0), Return, End,
+            LocalGet(2), MemorySize, I32Const(16), Numeric(I32Shl), Numeric(I32GtU), If,
+            LocalGet(2), MemorySize, I32Const(16), Numeric(I32Shl), Numeric(I32Sub),
+            I32Const(WASM_PAGE_SIZE as i32 - 1), Numeric(I32Add), I32Const(16), Numeric(I32ShrU),
+            MemoryGrow, I32Const(-1), Numeric(I32Eq), If, I64Const(0), Return, End,
+            End,
+            LocalGet(2), GlobalSet,
+            LocalGet(1), Numeric(I64ExtendI32U),
+        ]),
+        "__load8" => (vec![], vec![LocalGet(0), Numeric(I32WrapI64), Load8U, Numeric(I64ExtendI32U)]),
+        "__store8" => (vec![], vec![LocalGet(0), Numeric(I32WrapI64), LocalGet(1), Numeric(I32WrapI64), Store8]),
+        "__str_byte" => (vec![], vec![LocalGet(0), LocalGet(1), Numeric(I32WrapI64), Numeric(I32Add), Load8U, Numeric(I64ExtendI32U)]),
+        "__str_at" => (vec![], vec![LocalGet(0), Numeric(I32WrapI64)]),
+        DIVISION => (vec![], vec![
+            LocalGet(1), I64Const(-1), Numeric(I64Eq), If, I64Const(0), LocalGet(0), Numeric(I64Sub), Return, End,
+            LocalGet(0), LocalGet(1), Numeric(I64DivS),
+        ]),
+        _ => unreachable!("the syscall intrinsics are imports"),
+    }
+}
+
+impl Generator<'_> {
+    fn push(&mut self, inst: Inst) {
+        self.body.push(inst);
+    }
+
+    /// Address of a string literal, each one is only stored once
+    fn string(&mut self, value: &str) -> u32 {
+        if let Some(address) = self.strings.get(value) {return *address}
+        let address = WASM_DATA_START + self.data.len() as u32;
+        self.data.extend_from_slice(value.as_bytes());
+        self.data.push(0);
+        self.strings.insert(value.to_string(), address);
+        address
+    }
+
+    fn local(&self, name: &str) -> Result<u32, String> {
+        match self.locals.iter().position(|(local, _)| local == name) {
+            Some(index) => Ok(index as u32),
+            None => Err(format!("Unknown variable '{}' in the Wasm backend", name)),
+        }
+    }
+
+    fn call(&mut self, name: &str) {
+        let index = self.indices[name];
+        self.push(Inst::Call(index));
+    }
+
+    /// Pushes the value of `expression`, returns its type (None for a void call)
+    fn expression(&mut self, expression: &Expression) -> Result<Option<ValType>, String> {
+        Ok(match expression {
+            Expression::Number(number) => {
+                self.push(Inst::I64Const(*number));
+                Some(ValType::I64)
+            }
+            Expression::String(string) => {
+                let address = self.string(string);
+                self.push(Inst::I32Const(address as i32));
+                Some(ValType::I32)
+            }
+            Expression::Variable(name, _) => {
+                let index = self.local(name)?;
+                self.push(Inst::LocalGet(index));
+                Some(self.locals[index as usize].1)
+            }
+            Expression::Operation(operation) => {
+                self.expression(&operation.left)?;
+                self.expression(&operation.right)?;
+                match (operation.operator, comparison(operation.operator)) {
+                    // Comparisons give an i32
+                    (_, Some((compare, _))) => {
+                        self.push(Inst::Numeric(compare));
+                        self.push(Inst::Numeric(Numeric::I64ExtendI32U));
+                    }
+                    (Operator::Addition, _) => self.push(Inst::Numeric(Numeric::I64Add)),
+                    (Operator::Subtraction, _) => self.push(Inst::Numeric(Numeric::I64Sub)),
+                    (Operator::Multiplication, _) => self.push(Inst::Numeric(Numeric::I64Mul)),
+                    _ => {
+                        self.divides = true;
+                        self.call(DIVISION);
+                    }
+                }
+                Some(ValType::I64)
+            }
+            Expression::FunctionCall { target, args, .. } if target == "print" => {
+                self.print(args)?;
+                None
+            }
+            Expression::FunctionCall { target, .. } if self.analysis.functions[target].external.is_some() => {
+                return Err(format!("'{}' is a C function, the Wasm backend can't call those", target));
+            }
+            Expression::FunctionCall { target, args, .. } => {
+                for arg in args {
+                    self.expression(arg)?;
+                }
+                if intrinsics::index(target).is_some() {self.call(target)} else {self.call(&symbol(target))}
+                val_type(self.analysis.functions[target].return_type)
+            }
+            Expression::ReturnValue { .. } => return Err("Return used as a value".to_string()),
+        })
+    }
+
+    /// `print(a, b)` -> print_i64(a), print_str(" "), print_str(b), print_str("\n")
+    fn print(&mut self, args: &[Expression]) -> Result<(), String> {
+        for (index, arg) in args.iter().enumerate() {
+            if index > 0 {
+                let space = self.string(" ");
+                self.push(Inst::I32Const(space as i32));
+                self.call(PRINT_STR);
+            }
+            match self.expression(arg)? {
+                Some(ValType::I64) => self.call(PRINT_INT),
+                Some(ValType::I32) => self.call(PRINT_STR),
+                None => return Err("Can't print a void value".to_string()),
+            }
+        }
+        let newline = self.string("\n");
+        self.push(Inst::I32Const(newline as i32));
+        self.call(PRINT_STR);
+        Ok(())
+    }
+
+    /// Pushes `expression` as an i32 condition, or the opposite of it with `negate`
+    fn condition(&mut self, expression: &Expression, negate: bool) -> Result<(), String> {
+        if let Expression::Operation(operation) = expression && let Some((compare, opposite)) = comparison(operation.operator) {
+            self.expression(&operation.left)?;
+            self.expression(&operation.right)?;
+            self.push(Inst::Numeric(if negate {opposite} else {compare}));
+        } else {
+            self.expression(expression)?;
+            self.push(Inst::Numeric(Numeric::I64Eqz));
+            if !negate {self.push(Inst::Numeric(Numeric::I32Eqz))}
+        }
+        Ok(())
+    }
+
+    fn statements(&mut self, statements: &[Statement]) -> Result<(), String> {
+        for statement in statements {
+            match statement {
+                Statement::ExpressionStatement(Expression::ReturnValue { value }, _) => {
+                    self.expression(value)?;
+                    self.push(Inst::Return);
+                }
+                Statement::ExpressionStatement(expression, _) => {
+                    if self.expression(expression)?.is_some() {self.push(Inst::Drop)}
+                }
+                Statement::VariableAssignment { name, value, .. } => {
+                    self.expression(value)?;
+                    let index = self.local(name)?;
+                    self.push(Inst::LocalSet(index));
+                }
+                // block { loop { br_if !condition out; body; br loop } }
+                Statement::While { condition, body, .. } => {
+                    self.push(Inst::Block);
+                    self.push(Inst::Loop);
+                    self.condition(condition, true)?;
+                    self.push(Inst::BrIf(1));
+                    self.statements(body)?;
+                    self.push(Inst::Br(0));
+                    self.push(Inst::End);
+                    self.push(Inst::End);
+                }
+                Statement::ConditionalStatement { condition, body, else_body, .. } => {
+                    self.condition(condition, false)?;
+                    self.push(Inst::If);
+                    self.statements(body)?;
+                    if let Some(else_body) = else_body {
+                        self.push(Inst::Else);
+                        self.statements(else_body)?;
+                    }
+                    self.push(Inst::End);
+                }
+                Statement::Asm { location, .. } => {
+                    return Err(format!("Inline assembly can't be compiled to WebAssembly at position {}", position(*location)));
+                }
+                Statement::FunctionAssignment { name, .. } => {
+                    return Err(format!("Nested function '{}' in the Wasm backend", name));
+                }
+                Statement::Import { .. } | Statement::ConstAssignment { .. } => {
+                    return Err("Imports and constants should be resolved before the Wasm backend".to_string());
+                }
+                Statement::Extern { function, .. } => {
+                    return Err(format!("Nested extern '{}' in the Wasm backend", function.name));
+                }
+                Statement::Attribute { name, .. } => {
+                    return Err(format!("Nested #![{}] in the Wasm backend", name));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Galvan variables live for the whole function, every one is a Wasm local
+    fn collect_locals(&mut self, statements: &[Statement], scope: &mut Scope) -> Result<(), String> {
+        for statement in statements {
+            match statement {
+                Statement::VariableAssignment { name, value, .. } => {
+                    let ty = check_expression(value, scope, &self.analysis.functions)?;
+                    if !scope.contains_key(name) && let Some(val_type) = val_type(ty) {
+                        self.locals.push((name.clone(), val_type));
+                    }
+                    scope.insert(name.clone(), ty);
+                }
+                Statement::While { body, .. } => self.collect_locals(body, scope)?,
+                Statement::ConditionalStatement { body, else_body, .. } => {
+                    self.collect_locals(body, scope)?;
+                    if let Some(else_body) = else_body {self.collect_locals(else_body, scope)?}
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn function(&mut self, name: &str, parameters: &[(String, Type)], return_type: Type, body: &[Statement], export: Option<String>) -> Result<Function, String> {
+        let mut scope: Scope = parameters.iter().cloned().collect();
+        self.locals = parameters.iter().filter_map(|(param, ty)| Some((param.clone(), val_type(*ty)?))).collect();
+        self.collect_locals(body, &mut scope)?;
+        self.body = vec![];
+        self.statements(body)?;
+        // Falling off the end returns the default value, same as in the IR
+        if !matches!(body.last(), Some(Statement::ExpressionStatement(Expression::ReturnValue { .. }, _))) {
+            match return_type {
+                Type::Int => self.push(Inst::I64Const(0)),
+                Type::Str => {
+                    let empty = self.string("");
+                    self.push(Inst::I32Const(empty as i32));
+                }
+                Type::Void => {}
+            }
+        }
+        Ok(Function {
+            name: symbol(name),
+            params: parameters.len(),
+            locals: std::mem::take(&mut self.locals),
+            result: val_type(return_type),
+            body: std::mem::take(&mut self.body),
+            export,
+        })
+    }
+}
+
+/// Generates a Wasm module for the whole program.
+/// `entry` is the name the top level gets exported under (`_start` if it's None).
+pub fn generate(analysis: &Analysis, entry: Option<&str>) -> Result<Program, String> {
+    if debug_prints(WASM_DEBUG_PRINTS) {eprintln!("- - - Wasm")}
+
+    let statements = &analysis.statements;
+    for statement in statements {
+        match statement {
+            Statement::Extern { function, location, .. } => {
+                return Err(format!("'{}' is a C function, the Wasm backend can't call those, at position {}", function.name, position(*location)));
+            }
+            Statement::FunctionAssignment { name, attributes, location, .. } if attributes.interrupt || attributes.section.is_some() => {
+                return Err(format!("'{}' has #[interrupt] or #[section], which mean nothing in WebAssembly, at position {}", name, position(*location)));
+            }
+            _ => {}
+        }
+    }
+
+    let mut imports = vec![];
+    if calls(statements, "print") {
+        imports.push(Import { name: PRINT_INT.to_string(), params: vec![ValType::I64], result: None });
+        imports.push(Import { name: PRINT_STR.to_string(), params: vec![ValType::I32], result: None });
+    }
+    let mut indices = HashMap::new();
+    for (index, import) in imports.iter().enumerate() {
+        indices.insert(import.name.clone(), index as u32);
+    }
+    // Only the intrinsics the program uses, so a module without print or syscalls needs
+    // nothing from the host
+    let mut helpers = vec![];
+    for intrinsic in INTRINSICS.iter().filter(|intrinsic| calls(statements, intrinsic.name)) {
+        if intrinsic.name.starts_with("__sys_") {
+            indices.insert(intrinsic.name.to_string(), imports.len() as u32);
+            imports.push(Import {
+                name: import_name(intrinsic.name),
+                params: intrinsic.parameters.iter().filter_map(|ty| val_type(*ty)).collect(),
+                result: val_type(intrinsic.return_type),
+            });
+        } else {
+            helpers.push(intrinsic);
+        }
+    }
+    let functions: Vec<&Statement> = statements.iter()
+        .filter(|statement| matches!(statement, Statement::FunctionAssignment { .. })).collect();
+    let toplevel: Vec<Statement> = statements.iter()
+        .filter(|statement| !matches!(statement, Statement::FunctionAssignment { .. } | Statement::Extern { .. } | Statement::Attribute { .. })).cloned().collect();
+    // Same rule as the IR, a library has no main()
+    let library = toplevel.is_empty() && functions.iter().any(|statement| matches!(statement, Statement::FunctionAssignment { export: Some(_), .. }));
+
+    let mut names: Vec<String> = helpers.iter().map(|intrinsic| intrinsic.name.to_string()).collect();
+    for statement in &functions {
+        if let Statement::FunctionAssignment { name, .. } = statement {names.push(symbol(name))}
+    }
+    if !library {names.push(symbol(ENTRY_FUNCTION))}
+    names.push(DIVISION.to_string());
+    for (index, name) in names.into_iter().enumerate() {
+        indices.insert(name, (imports.len() + index) as u32);
+    }
+
+    let mut generator = Generator { analysis, indices, strings: HashMap::new(), data: vec![], divides: false, locals: vec![], body: vec![] };
+    let mut program_functions = vec![];
+    for intrinsic in helpers {
+        let params: Vec<(String, ValType)> = ["a", "b", "c"].iter().map(|name| name.to_string())
+            .zip(intrinsic.parameters.iter().filter_map(|ty| val_type(*ty))).collect();
+        let (locals, body) = intrinsic_body(intrinsic.name);
+        program_functions.push(Function {
+            name: intrinsic.name.to_string(),
+            params: params.len(),
+            locals: params.into_iter().chain(locals.into_iter().map(|(name, ty)| (name.to_string(), ty))).collect(),
+            result: val_type(intrinsic.return_type),
+            body,
+            export: None,
+        });
+    }
+    for statement in &functions {
+        if let Statement::FunctionAssignment { name, body, export, .. } = statement {
+            let info = &analysis.functions[name];
+            let parameters: Vec<(String, Type)> = info.parameters.iter().cloned().zip(info.parameter_types.iter().copied()).collect();
+            let function = generator.function(name, &parameters, info.return_type, body, export.clone())?;
+            program_functions.push(function);
+        }
+    }
+    if !library {
+        let main = generator.function(ENTRY_FUNCTION, &[], Type::Int, &toplevel, Some(entry.unwrap_or(START_SYMBOL).to_string()))?;
+        program_functions.push(main);
+    }
+    if generator.divides {
+        let (_, body) = intrinsic_body(DIVISION);
+        let locals = vec![("a".to_string(), ValType::I64), ("b".to_string(), ValType::I64)];
+        program_functions.push(Function { name: DIVISION.to_string(), params: 2, locals, result: Some(ValType::I64), body, export: None });
+    }
+
+    let heap_start = (WASM_DATA_START + generator.data.len() as u32).div_ceil(8) * 8;
+    if debug_prints(WASM_DEBUG_PRINTS) {eprintln!("- - - Wasm done!")}
+    Ok(Program { imports, functions: program_functions, data: generator.data, heap_start })
+}
+
+/// A string in the text format, anything unusual as \hh
+fn wat_string(bytes: &[u8]) -> String {
+    let mut out = String::from("\"");
+    for byte in bytes {
+        match byte {
+            b'"' | b'\\' => out.push_str(&format!("\\{}", *byte as char)),
+            0x20..=0x7e => out.push(*byte as char),
+            _ => out.push_str(&format!("\\{:02x}", byte)),
+        }
+    }
+    out.push('"');
+    out
+}
+
+impl Program {
+    /// Memory the module starts with, enough for the data and a first bit of heap
+    pub fn pages(&self) -> u32 {
+        self.heap_start.div_ceil(WASM_PAGE_SIZE).max(1)
+    }
+
+    /// Name of function `index`, imports first
+    pub fn function_name(&self, index: u32) -> &str {
+        let index = index as usize;
+        match self.imports.get(index) {
+            Some(import) => &import.name,
+            None => &self.functions[index - self.imports.len()].name,
+        }
+    }
+
+    fn print_inst(&self, inst: &Inst, function: &Function) -> String {
+        match inst {
+            Inst::I32Const(value) => format!("i32.const {}", value),
+            Inst::I64Const(value) => format!("i64.const {}", value),
+            Inst::LocalGet(index) => format!("local.get ${}", function.locals[*index as usize].0),
+            Inst::LocalSet(index) => format!("local.set ${}", function.locals[*index as usize].0),
+            Inst::LocalTee(index) => format!("local.tee ${}", function.locals[*index as usize].0),
+            Inst::GlobalGet => "global.get $heap".to_string(),
+            Inst::GlobalSet => "global.set $heap".to_string(),
+            Inst::Numeric(numeric) => numeric.name().to_string(),
+            Inst::Call(index) => format!("call ${}", self.function_name(*index)),
+            Inst::Drop => "drop".to_string(),
+            Inst::Return => "return".to_string(),
+            Inst::Block => "block".to_string(),
+            Inst::Loop => "loop".to_string(),
+            Inst::If => "if".to_string(),
+            Inst::Else => "else".to_string(),
+            Inst::End => "end".to_string(),
+            Inst::Br(depth) => format!("br {}", depth),
+            Inst::BrIf(depth) => format!("br_if {}", depth),
+            Inst::Load8U => "i32.load8_u".to_string(),
+            Inst::Store8 => "i32.store8".to_string(),
+            Inst::MemorySize => "memory.size".to_string(),
+            Inst::MemoryGrow => "memory.grow".to_string(),
+        }
+    }
+
+    /// The module in the text format, for wat2wasm and friends
+    pub fn to_wat(&self) -> String {
+        let mut out = String::from("(module\n");
+        for import in &self.imports {
+            let mut signature = String::new();
+            for ty in &import.params {signature.push_str(&format!(" (param {})", ty.name()))}
+            if let Some(result) = import.result {signature.push_str(&format!(" (result {})", result.name()))}
+            out.push_str(&format!("  (import \"{}\" \"{}\" (func ${}{}))\n", WASM_IMPORT_MODULE, import.name, import.name, signature));
+        }
+        out.push_str(&format!("  (memory (export \"memory\") {})\n", self.pages()));
+        out.push_str(&format!("  (global $heap (mut i32) (i32.const {}))\n", self.heap_start));
+        for function in &self.functions {
+            out.push_str(&format!("\n  (func ${}", function.name));
+            if let Some(export) = &function.export {out.push_str(&format!(" (export \"{}\")", export))}
+            for (name, ty) in &function.locals[..function.params] {
+                out.push_str(&format!(" (param ${} {})", name, ty.name()));
+            }
+            if let Some(result) = function.result {out.push_str(&format!(" (result {})", result.name()))}
+            out.push('\n');
+            for (name, ty) in &function.locals[function.params..] {
+                out.push_str(&format!("    (local ${} {})\n", name, ty.name()));
+            }
+            let mut depth = 2;
+            for inst in &function.body {
+                if matches!(inst, Inst::End | Inst::Else) {depth -= 1}
+                out.push_str(&"  ".repeat(depth));
+                out.push_str(&self.print_inst(inst, function));
+                out.push('\n');
+                if matches!(inst, Inst::Block | Inst::Loop | Inst::If | Inst::Else) {depth += 1}
+            }
+            out.push_str("  )\n");
+        }
+        if !self.data.is_empty() {
+            out.push_str(&format!("\n  (data (i32.const {}) {})\n", WASM_DATA_START, wat_string(&self.data)));
+        }
+        out.push_str(")\n");
+        out
+    }
+}
@@ -0,0 +1,252 @@
+use crate::compiler_settings::*;
+use crate::wasm::{Function, Inst, Program, ValType};
+
+// Binary .wasm writer for the Wasm backend (wasm.rs). Sections go in the order the spec wants,
+// followed by a "name" section with the function and local names, so browsers and runtimes
+// show the Galvan names in stack traces.
+// Integers are LEB128 everywhere, unsigned ones for sizes and indices, signed ones for constants.
+
+//
+// FUNCTIONS
+//
+
+const MAGIC: &[u8] = b"\0asm";
+const VERSION: u32 = 1;
+
+/// Block type of a block that takes and leaves nothing
+const EMPTY_BLOCK: u8 = 0x40;
+const FUNCTION_TYPE: u8 = 0x60;
+const END: u8 = 0x0b;
+
+const CUSTOM_SECTION: u8 = 0;
+const TYPE_SECTION: u8 = 1;
+const IMPORT_SECTION: u8 = 2;
+const FUNCTION_SECTION: u8 = 3;
+const MEMORY_SECTION: u8 = 5;
+const GLOBAL_SECTION: u8 = 6;
+const EXPORT_SECTION: u8 = 7;
+const CODE_SECTION: u8 = 10;
+const DATA_SECTION: u8 = 11;
+
+const FUNCTION_KIND: u8 = 0;
+const MEMORY_KIND: u8 = 2;
+
+fn unsigned(out: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn signed(out: &mut Vec<u8>, mut value: i64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        // Done once the rest is all sign bits, and the sign bit of this byte agrees
+        if (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0) {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn name(out: &mut Vec<u8>, name: &str) {
+    unsigned(out, name.len() as u64);
+    out.extend_from_slice(name.as_bytes());
+}
+
+/// Section `id` with its size in front
+fn section(out: &mut Vec<u8>, id: u8, contents: &[u8]) {
+    out.push(id);
+    unsigned(out, contents.len() as u64);
+    out.extend_from_slice(contents);
+}
+
+fn val_type(ty: ValType) -> u8 {
+    match ty {
+        ValType::I32 => 0x7f,
+        ValType::I64 => 0x7e,
+    }
+}
+
+fn instruction(out: &mut Vec<u8>, inst: &Inst) {
+    match inst {
+        Inst::I32Const(value) => {out.push(0x41); signed(out, *value as i64)}
+        Inst::I64Const(value) => {out.push(0x42); signed(out, *value)}
+        Inst::LocalGet(index) => {out.push(0x20); unsigned(out, *index as u64)}
+        Inst::LocalSet(index) => {out.push(0x21); unsigned(out, *index as u64)}
+        Inst::LocalTee(index) => {out.push(0x22); unsigned(out, *index as u64)}
+        Inst::GlobalGet => out.extend_from_slice(&[0x23, 0]),
+        Inst::GlobalSet => out.extend_from_slice(&[0x24, 0]),
+        Inst::Numeric(numeric) => out.push(numeric.opcode()),
+        Inst::Call(index) => {out.push(0x10); unsigned(out, *index as u64)}
+        Inst::Drop => out.push(0x1a),
+        Inst::Return => out.push(0x0f),
+        Inst::Block => out.extend_from_slice(&[0x02, EMPTY_BLOCK]),
+        Inst::Loop => out.extend_from_slice(&[0x03, EMPTY_BLOCK]),
+        Inst::If => out.extend_from_slice(&[0x04, EMPTY_BLOCK]),
+        Inst::Else => out.push(0x05),
+        Inst::End => out.push(END),
+        Inst::Br(depth) => {out.push(0x0c); unsigned(out, *depth as u64)}
+        Inst::BrIf(depth) => {out.push(0x0d); unsigned(out, *depth as u64)}
+        // Alignment 2^0 and offset 0
+        Inst::Load8U => out.extend_from_slice(&[0x2d, 0, 0]),
+        Inst::Store8 => out.extend_from_slice(&[0x3a, 0, 0]),
+        Inst::MemorySize => out.extend_from_slice(&[0x3f, 0]),
+        Inst::MemoryGrow => out.extend_from_slice(&[0x40, 0]),
+    }
+}
+
+/// Locals (without the parameters) as runs of the same type, then the instructions
+fn code(function: &Function) -> Vec<u8> {
+    let mut runs: Vec<(u32, ValType)> = vec![];
+    for (_, ty) in &function.locals[function.params..] {
+        match runs.last_mut() {
+            Some((count, last)) if last == ty => *count += 1,
+            _ => runs.push((1, *ty)),
+        }
+    }
+    let mut body = vec![];
+    unsigned(&mut body, runs.len() as u64);
+    for (count, ty) in runs {
+        unsigned(&mut body, count as u64);
+        body.push(val_type(ty));
+    }
+    for inst in &function.body {
+        instruction(&mut body, inst);
+    }
+    body.push(END);
+    let mut out = vec![];
+    unsigned(&mut out, body.len() as u64);
+    out.extend(body);
+    out
+}
+
+/// "name" section with subsections 1 (function names) and 2 (local names)
+fn names(program: &Program) -> Vec<u8> {
+    let mut out = vec![];
+    name(&mut out, "name");
+
+    let mut functions = vec![];
+    unsigned(&mut functions, (program.imports.len() + program.functions.len()) as u64);
+    for index in 0..program.imports.len() + program.functions.len() {
+        unsigned(&mut functions, index as u64);
+        name(&mut functions, program.function_name(index as u32));
+    }
+    section(&mut out, 1, &functions);
+
+    let mut locals = vec![];
+    unsigned(&mut locals, program.functions.len() as u64);
+    for (index, function) in program.functions.iter().enumerate() {
+        unsigned(&mut locals, (program.imports.len() + index) as u64);
+        unsigned(&mut locals, function.locals.len() as u64);
+        for (local, (local_name, _)) in function.locals.iter().enumerate() {
+            unsigned(&mut locals, local as u64);
+            name(&mut locals, local_name);
+        }
+    }
+    section(&mut out, 2, &locals);
+    out
+}
+
+/// The binary module
+pub fn encode(program: &Program) -> Vec<u8> {
+    let mut out = MAGIC.to_vec();
+    out.extend_from_slice(&VERSION.to_le_bytes());
+
+    // Every distinct signature once, imports and functions point into this
+    let mut types: Vec<(Vec<ValType>, Option<ValType>)> = vec![];
+    let mut type_index = |params: Vec<ValType>, result: Option<ValType>| -> u64 {
+        let signature = (params, result);
+        match types.iter().position(|known| *known == signature) {
+            Some(index) => index as u64,
+            None => {
+                types.push(signature);
+                types.len() as u64 - 1
+            }
+        }
+    };
+    let import_types: Vec<u64> = program.imports.iter().map(|import| type_index(import.params.clone(), import.result)).collect();
+    let function_types: Vec<u64> = program.functions.iter()
+        .map(|function| type_index(function.locals[..function.params].iter().map(|(_, ty)| *ty).collect(), function.result)).collect();
+
+    let mut contents = vec![];
+    unsigned(&mut contents, types.len() as u64);
+    for (params, result) in &types {
+        contents.push(FUNCTION_TYPE);
+        unsigned(&mut contents, params.len() as u64);
+        contents.extend(params.iter().map(|ty| val_type(*ty)));
+        unsigned(&mut contents, result.is_some() as u64);
+        contents.extend(result.iter().map(|ty| val_type(*ty)));
+    }
+    section(&mut out, TYPE_SECTION, &contents);
+
+    if !program.imports.is_empty() {
+        let mut contents = vec![];
+        unsigned(&mut contents, program.imports.len() as u64);
+        for (import, ty) in program.imports.iter().zip(&import_types) {
+            name(&mut contents, WASM_IMPORT_MODULE);
+            name(&mut contents, &import.name);
+            contents.push(FUNCTION_KIND);
+            unsigned(&mut contents, *ty);
+        }
+        section(&mut out, IMPORT_SECTION, &contents);
+    }
+
+    let mut contents = vec![];
+    unsigned(&mut contents, function_types.len() as u64);
+    for ty in &function_types {
+        unsigned(&mut contents, *ty);
+    }
+    section(&mut out, FUNCTION_SECTION, &contents);
+
+    // One memory without a maximum
+    let mut contents = vec![1, 0];
+    unsigned(&mut contents, program.pages() as u64);
+    section(&mut out, MEMORY_SECTION, &contents);
+
+    // The heap pointer, a mutable i32
+    let mut contents = vec![1, val_type(ValType::I32), 1, 0x41];
+    signed(&mut contents, program.heap_start as i64);
+    contents.push(END);
+    section(&mut out, GLOBAL_SECTION, &contents);
+
+    let exports: Vec<(usize, &String)> = program.functions.iter().enumerate()
+        .filter_map(|(index, function)| Some((program.imports.len() + index, function.export.as_ref()?))).collect();
+    let mut contents = vec![];
+    unsigned(&mut contents, exports.len() as u64 + 1);
+    name(&mut contents, "memory");
+    contents.extend_from_slice(&[MEMORY_KIND, 0]);
+    for (index, export) in exports {
+        name(&mut contents, export);
+        contents.push(FUNCTION_KIND);
+        unsigned(&mut contents, index as u64);
+    }
+    section(&mut out, EXPORT_SECTION, &contents);
+
+    let mut contents = vec![];
+    unsigned(&mut contents, program.functions.len() as u64);
+    for function in &program.functions {
+        contents.extend(code(function));
+    }
+    section(&mut out, CODE_SECTION, &contents);
+
+    if !program.data.is_empty() {
+        // One active segment for memory 0
+        let mut contents = vec![1, 0, 0x41];
+        signed(&mut contents, WASM_DATA_START as i64);
+        contents.push(END);
+        unsigned(&mut contents, program.data.len() as u64);
+        contents.extend_from_slice(&program.data);
+        section(&mut out, DATA_SECTION, &contents);
+    }
+
+    section(&mut out, CUSTOM_SECTION, &names(program));
+    out
+}
@@ -0,0 +1,965 @@
+use crate::backend::*;
+use crate::compiler_settings::*;
+use crate::dwarf::{self, FrameEvent, Variable};
+use crate::ffi::{CType, Extern};
+use crate::intrinsics::INTRINSICS;
+use crate::ir::{symbol, BinaryOp, Function, Instruction, Module, Operand, Terminator};
+use crate::parser::expand_asm;
+use crate::regalloc::{self, Home, Register, Registers};
+use crate::seman::Type;
+
+// x86-64 System V backend. Turns the IR into a list of `Inst`s, which can then be
+// printed as GNU assembler (AT&T or Intel syntax).
+// Virtual registers go wherever regalloc.rs puts them (r10, r11, rbx and r12-r15, or a spill
+// slot), every local gets its own stack slot, and arithmetic still goes through rax/rcx.
+// Inline assembly gets its operands in the caller-saved registers the allocator leaves alone
+// (loaded right before, stored right after), and is printed as-is, so only the assembly
+// output can have it.
+// A program that uses C (externs or exports) gets linked with libc: it starts at `main`
+// instead of `_start`, memory comes from calloc, exit goes through exit() and stdio's buffers
+// get flushed before anything is written straight to a file descriptor.
+// Freestanding programs (`#![no_std]`) start at a stub that sets up the stack and hangs when
+// the top level is done, `#[interrupt]` functions get a wrapper that saves everything and
+// returns with iretq, and `#[section]` functions go in their own section.
+// With -g (the module has source files) every function gets labels for its `line`s and frame
+// changes, and dwarf.rs turns those into the debug sections.
+
+//
+// STRUCTS
+//
+
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+#[derive(PartialEq)]
+#[allow(dead_code)] // The order is the hardware numbering, so they all stay even if unused
+pub enum Reg {
+    Rax, Rcx, Rdx, Rbx, Rsp, Rbp, Rsi, Rdi,
+    R8, R9, R10, R11, R12, R13, R14, R15,
+}
+impl Reg {
+    const ALL: [Reg; 16] = [Reg::Rax, Reg::Rcx, Reg::Rdx, Reg::Rbx, Reg::Rsp, Reg::Rbp, Reg::Rsi, Reg::Rdi,
+        Reg::R8, Reg::R9, Reg::R10, Reg::R11, Reg::R12, Reg::R13, Reg::R14, Reg::R15];
+
+    pub fn from_name(name: &str) -> Option<Reg> {
+        Reg::ALL.into_iter().find(|reg| reg.name() == name)
+    }
+
+    /// Hardware register number, used by the encoder
+    pub fn number(&self) -> u8 {
+        *self as u8
+    }
+
+    pub fn name(&self) -> &'static str {
+        ["rax", "rcx", "rdx", "rbx", "rsp", "rbp", "rsi", "rdi",
+         "r8", "r9", "r10", "r11", "r12", "r13", "r14", "r15"][self.number() as usize]
+    }
+
+    /// Name of the lowest byte of the register (al, cl, ..., r15b)
+    pub fn byte_name(&self) -> &'static str {
+        ["al", "cl", "dl", "bl", "spl", "bpl", "sil", "dil",
+         "r8b", "r9b", "r10b", "r11b", "r12b", "r13b", "r14b", "r15b"][self.number() as usize]
+    }
+
+    /// Name of the lowest 16 bits (ax, cx, ..., r15w)
+    pub fn word_name(&self) -> &'static str {
+        ["ax", "cx", "dx", "bx", "sp", "bp", "si", "di",
+         "r8w", "r9w", "r10w", "r11w", "r12w", "r13w", "r14w", "r15w"][self.number() as usize]
+    }
+
+    /// Name of the lowest 32 bits (eax, ecx, ..., r15d)
+    pub fn dword_name(&self) -> &'static str {
+        ["eax", "ecx", "edx", "ebx", "esp", "ebp", "esi", "edi",
+         "r8d", "r9d", "r10d", "r11d", "r12d", "r13d", "r14d", "r15d"][self.number() as usize]
+    }
+
+    /// Name of the lowest `bits` bits
+    pub fn sized_name(&self, bits: u8) -> &'static str {
+        match bits {
+            8 => self.byte_name(),
+            16 => self.word_name(),
+            32 => self.dword_name(),
+            _ => self.name(),
+        }
+    }
+}
+
+/// Registers integer arguments are passed in, in order
+pub const ARGUMENT_REGISTERS: [Reg; 6] = [Reg::Rdi, Reg::Rsi, Reg::Rdx, Reg::Rcx, Reg::R8, Reg::R9];
+
+/// What the register allocator hands out, caller-saved ones first. rax, rcx and rdx are
+/// scratch (and idiv's), the argument registers are left alone so calls never have to shuffle
+/// arguments around.
+const POOL: [Reg; 7] = [Reg::R10, Reg::R11, Reg::Rbx, Reg::R12, Reg::R13, Reg::R14, Reg::R15];
+const CALLEE_SAVED: [Reg; 5] = [Reg::Rbx, Reg::R12, Reg::R13, Reg::R14, Reg::R15];
+
+/// Registers inline assembly operands can end up in, all caller-saved and none of them in the
+/// allocator's pool, so nothing has to be kept
+const ASM_REGISTERS: [Reg; 7] = [Reg::Rax, Reg::Rcx, Reg::Rdx, Reg::Rsi, Reg::Rdi, Reg::R8, Reg::R9];
+
+/// Condition codes for jcc/setcc
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+#[derive(PartialEq)]
+pub enum Cond {
+    E, Ne, L, G, Le, Ge,
+}
+impl Cond {
+    pub fn name(&self) -> &'static str {
+        match self {Cond::E => "e", Cond::Ne => "ne", Cond::L => "l", Cond::G => "g", Cond::Le => "le", Cond::Ge => "ge"}
+    }
+}
+
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+pub enum Arg {
+    Reg(Reg),
+    Imm(i64),
+    /// `[base + offset]`
+    Mem {base: Reg, offset: i32},
+    /// `[rip + symbol]`, only for lea
+    Symbol(String),
+}
+
+/// One assembly instruction (or label). Destination comes first, like in Intel syntax.
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+pub enum Inst {
+    Label(String),
+    Mov(Arg, Arg),
+    Lea(Reg, Arg),
+    Add(Reg, Arg),
+    Sub(Reg, Arg),
+    Imul(Reg, Arg),
+    Neg(Reg),
+    /// Sign extends rax into rdx:rax
+    Cqo,
+    Idiv(Reg),
+    /// Unsigned rdx:rax / reg
+    Div(Reg),
+    Cmp(Reg, Arg),
+    /// Sets the low byte of the register
+    Set(Cond, Reg),
+    /// Zero extends a byte (low byte of a register, or memory) into a full register
+    MovzxByte(Reg, Arg),
+    /// Stores the low byte of a register into memory
+    StoreByte(Arg, Reg),
+    /// Sign (or zero) extends the low 8, 16 or 32 bits of a register into all of it
+    Extend(Reg, u8, bool),
+    Jmp(String),
+    Jcc(Cond, String),
+    Call(String),
+    Ret,
+    Push(Reg),
+    Pop(Reg),
+    Syscall,
+    /// Return from an interrupt (iretq)
+    Iret,
+    /// Clears the direction flag, the ABI wants it clear on calls
+    Cld,
+    /// Inline assembly, the template with the register of every operand
+    Asm {template: String, operands: Vec<(String, Reg)>},
+}
+
+/// Everything that ends up in the output file
+#[derive(Debug)]
+pub struct Program {
+    pub text: Vec<Inst>,
+    /// Label -> bytes (strings get their NUL terminator included)
+    pub rodata: Vec<(String, Vec<u8>)>,
+    /// Symbols visible outside the object
+    pub globals: Vec<String>,
+    /// Code of `#[section(...)]` functions, by section name
+    pub sections: Vec<(String, Vec<Inst>)>,
+    /// Where an executable starts
+    pub entry: String,
+    /// The .debug_* sections, empty without -g
+    pub debug: Vec<dwarf::Section>,
+}
+
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+#[derive(PartialEq)]
+pub enum Syntax {
+    Att,
+    Intel,
+}
+
+//
+// REGISTER ALLOCATION
+//
+
+/// Registers a value of the type takes up, everything fits one
+fn words(ty: Type) -> usize {
+    match ty {
+        Type::Int | Type::Str => 1,
+        Type::Void => 0,
+    }
+}
+
+impl Register for Reg {
+    fn name(&self) -> &'static str {
+        Reg::name(self)
+    }
+}
+
+/// Division is inline (idiv on rax and rdx), so it's not a call here
+const REGISTERS: Registers<Reg> = Registers { pool: &POOL, callee_saved: &CALLEE_SAVED, words, division_calls: false };
+
+/// `--emit=regalloc`
+pub fn dump_allocation(module: &Module) -> String {
+    regalloc::dump(module, &REGISTERS)
+}
+
+//
+// CODE GENERATION
+//
+
+/// Stack layout of one function. Below rbp every local gets 8 bytes, then every spill slot,
+/// then the callee-saved registers the function uses get saved.
+struct Frame {
+    locals: usize,
+    spills: usize,
+    /// Where the register allocator put every virtual register
+    homes: Vec<Vec<Home<Reg>>>,
+    saved: Vec<Reg>,
+    size: i32,
+}
+impl Frame {
+    fn new(function: &Function, module: &Module) -> Frame {
+        let allocation = regalloc::allocate(function, module, &REGISTERS);
+        let saved = allocation.saved(&REGISTERS);
+        let slots = function.locals.len() + allocation.spills + saved.len();
+        // Keep rsp 16-byte aligned for calls
+        let size = (slots * 8).div_ceil(16) * 16;
+        Frame { locals: function.locals.len(), spills: allocation.spills, homes: allocation.homes, saved, size: size as i32 }
+    }
+
+    fn slot(&self, slot: usize) -> Arg {
+        Arg::Mem { base: Reg::Rbp, offset: -8 * (slot as i32 + 1) }
+    }
+
+    fn local(&self, local: usize) -> Arg {
+        self.slot(local)
+    }
+
+    /// Home of the virtual register. One that's never read has none, what gets written to it
+    /// stays in rax.
+    fn register(&self, register: usize) -> Arg {
+        match self.homes[register].first() {
+            Some(Home::Reg(reg)) => Arg::Reg(*reg),
+            Some(Home::Spill(slot)) => self.slot(self.locals + slot),
+            None => Arg::Reg(Reg::Rax),
+        }
+    }
+
+    /// Where a callee-saved register is kept while the function runs
+    fn saved_slot(&self, index: usize) -> Arg {
+        self.slot(self.locals + self.spills + index)
+    }
+
+    fn operand(&self, operand: &Operand) -> Arg {
+        match operand {
+            Operand::Register(register) => self.register(*register),
+            Operand::Constant(value) => Arg::Imm(*value),
+        }
+    }
+}
+
+/// `dest = source`, through rax when neither is a register (x86 has no memory to memory move,
+/// and stores only take 32-bit immediates). Moves to itself are left out.
+fn mov(out: &mut Vec<Inst>, dest: Arg, source: Arg) {
+    if dest == source {return}
+    if matches!(dest, Arg::Reg(_)) || matches!(source, Arg::Reg(_)) {
+        out.push(Inst::Mov(dest, source));
+    } else {
+        out.push(Inst::Mov(Arg::Reg(Reg::Rax), source));
+        out.push(Inst::Mov(dest, Arg::Reg(Reg::Rax)));
+    }
+}
+
+fn condition(op: BinaryOp) -> Option<Cond> {
+    match op {
+        BinaryOp::Lt => Some(Cond::L),
+        BinaryOp::Gt => Some(Cond::G),
+        BinaryOp::Le => Some(Cond::Le),
+        BinaryOp::Ge => Some(Cond::Ge),
+        BinaryOp::Eq => Some(Cond::E),
+        BinaryOp::Ne => Some(Cond::Ne),
+        _ => None,
+    }
+}
+
+/// Cuts a value down to the C integer type it's passed as, the callee can count on the
+/// upper bits being a proper extension
+fn extend(out: &mut Vec<Inst>, reg: Reg, ty: Option<&CType>) {
+    if let Some((bits, signed)) = narrow_int(ty, 64) {
+        out.push(Inst::Extend(reg, bits, signed));
+    }
+}
+
+/// A call to a Galvan function, or to a C one when `external` is given
+fn generate_call(out: &mut Vec<Inst>, frame: &Frame, function: &str, args: &[Operand], dest: Option<usize>, external: Option<&Extern>) {
+    let c_type = |index: usize| external.and_then(|external| external.parameters.get(index)).map(|(_, ty)| ty);
+    let stack_args = args.len().saturating_sub(ARGUMENT_REGISTERS.len());
+    // Stack arguments get pushed last-first, pad so rsp is still aligned at the call
+    if stack_args % 2 == 1 {
+        out.push(Inst::Sub(Reg::Rsp, Arg::Imm(8)));
+    }
+    for (index, arg) in args.iter().enumerate().skip(ARGUMENT_REGISTERS.len()).rev() {
+        out.push(Inst::Mov(Arg::Reg(Reg::Rax), frame.operand(arg)));
+        extend(out, Reg::Rax, c_type(index));
+        out.push(Inst::Push(Reg::Rax));
+    }
+    for (index, (arg, register)) in args.iter().zip(ARGUMENT_REGISTERS).enumerate() {
+        out.push(Inst::Mov(Arg::Reg(register), frame.operand(arg)));
+        extend(out, register, c_type(index));
+    }
+    match external {
+        Some(external) => {
+            // al is the number of vector registers used by a variadic call, always none here
+            if external.variadic {out.push(Inst::Mov(Arg::Reg(Reg::Rax), Arg::Imm(0)))}
+            out.push(Inst::Call(external.symbol.clone()));
+        }
+        None => out.push(Inst::Call(symbol(function))),
+    }
+    let cleanup = (stack_args + stack_args % 2) * 8;
+    if cleanup > 0 {
+        out.push(Inst::Add(Reg::Rsp, Arg::Imm(cleanup as i64)));
+    }
+    if let Some(dest) = dest {
+        extend(out, Reg::Rax, external.map(|external| &external.return_type));
+        mov(out, frame.register(dest), Arg::Reg(Reg::Rax));
+    }
+}
+
+/// fflush(NULL), so whatever C code printed comes out before what Galvan writes directly
+fn flush_c_output(out: &mut Vec<Inst>) {
+    out.push(Inst::Mov(Arg::Reg(Reg::Rdi), Arg::Imm(0)));
+    out.push(Inst::Call("fflush".to_string()));
+}
+
+/// `print(a, b, ...)` prints every argument separated by spaces, and a newline at the end
+fn generate_print(out: &mut Vec<Inst>, frame: &Frame, function: &Function, args: &[Operand]) {
+    for (index, arg) in args.iter().enumerate() {
+        if index > 0 {
+            out.push(Inst::Mov(Arg::Reg(Reg::Rdi), Arg::Imm(b' ' as i64)));
+            out.push(Inst::Call(PRINT_CHAR.to_string()));
+        }
+        let printer = match arg {
+            Operand::Register(register) if function.registers[*register] == Type::Str => PRINT_STR,
+            _ => PRINT_INT,
+        };
+        out.push(Inst::Mov(Arg::Reg(Reg::Rdi), frame.operand(arg)));
+        out.push(Inst::Call(printer.to_string()));
+    }
+    out.push(Inst::Mov(Arg::Reg(Reg::Rdi), Arg::Imm(b'\n' as i64)));
+    out.push(Inst::Call(PRINT_CHAR.to_string()));
+}
+
+/// Inline assembly: inputs go into free registers, outputs get their own ones (so the
+/// assembly can write them before it's done reading the inputs) and are stored afterwards.
+/// Clobbered callee-saved registers get saved around it, caller-saved ones are simply not used.
+fn generate_asm(out: &mut Vec<Inst>, frame: &Frame, template: &str, inputs: &[(String, Operand)], outputs: &[(String, usize)], clobbers: &[String]) -> Result<(), String> {
+    let mut free = ASM_REGISTERS.to_vec();
+    let mut saved = vec![];
+    for clobber in clobbers {
+        let name = clobber.trim_start_matches('%');
+        if name == "cc" || name == "memory" {continue}
+        match Reg::from_name(name) {
+            Some(reg @ (Reg::Rsp | Reg::Rbp)) => return Err(format!("Inline assembly can't clobber {}, the stack frame lives in it", reg.name())),
+            Some(reg) if ASM_REGISTERS.contains(&reg) => free.retain(|free| *free != reg),
+            Some(reg) => if !saved.contains(&reg) {saved.push(reg)},
+            None => return Err(format!("Unknown register '{}' in the clobber list of inline assembly", clobber)),
+        }
+    }
+    if inputs.len() + outputs.len() > free.len() {
+        return Err(format!("Inline assembly has {} operands, but only {} registers are left for them", inputs.len() + outputs.len(), free.len()));
+    }
+
+    // Keep rsp 16-byte aligned in case the assembly calls something
+    let padding = saved.len() % 2 == 1;
+    for reg in &saved {out.push(Inst::Push(*reg))}
+    if padding {out.push(Inst::Sub(Reg::Rsp, Arg::Imm(8)))}
+    let mut operands = vec![];
+    for ((name, value), reg) in inputs.iter().zip(&free) {
+        out.push(Inst::Mov(Arg::Reg(*reg), frame.operand(value)));
+        operands.push((name.clone(), *reg));
+    }
+    let output_registers = &free[inputs.len()..];
+    for ((name, _), reg) in outputs.iter().zip(output_registers) {
+        operands.push((name.clone(), *reg));
+    }
+    out.push(Inst::Asm { template: template.to_string(), operands });
+    for ((_, local), reg) in outputs.iter().zip(output_registers) {
+        out.push(Inst::Mov(frame.local(*local), Arg::Reg(*reg)));
+    }
+    if padding {out.push(Inst::Add(Reg::Rsp, Arg::Imm(8)))}
+    for reg in saved.iter().rev() {out.push(Inst::Pop(*reg))}
+    Ok(())
+}
+
+/// What dwarf.rs needs to know about the function besides its code: every local is in its
+/// slot all the time. Compiler made locals (inlining, strength reduction) aren't variables.
+fn debug_function(function: &Function, frame: &Frame) -> dwarf::Function {
+    let mut debug = dwarf::Function::new(&function.name, &symbol(&function.name), function.export.is_some(), function.return_type);
+    for (index, local) in function.locals.iter().enumerate() {
+        let Arg::Mem { offset, .. } = frame.local(index) else {unreachable!()};
+        let variable = Variable { name: local.name.clone(), ty: local.ty, offset };
+        if index < function.parameters {debug.parameters.push(variable)}
+        else if local.name.chars().all(|c| c.is_alphanumeric() || c == '_') {debug.locals.push(variable)}
+    }
+    debug
+}
+
+fn generate_function(out: &mut Vec<Inst>, function: &Function, module: &Module, debug_functions: &mut Vec<dwarf::Function>) -> Result<(), String> {
+    let frame = Frame::new(function, module);
+    let mut debug = (!module.files.is_empty()).then(|| debug_function(function, &frame));
+    out.push(Inst::Label(symbol(&function.name)));
+    // The prologue goes with the function's first line, usually its declaration
+    let first_line = match function.blocks[0].instructions.first() {
+        Some(Instruction::Line { file, line, column }) => Some((*file, *line, *column)),
+        _ => None,
+    };
+    if let (Some(debug), Some((file, line, column))) = (&mut debug, first_line) {debug.row(out, file, line, column)}
+    out.push(Inst::Push(Reg::Rbp));
+    if let Some(debug) = &mut debug {debug.frame(out, FrameEvent::PushedRbp)}
+    out.push(Inst::Mov(Arg::Reg(Reg::Rbp), Arg::Reg(Reg::Rsp)));
+    if let Some(debug) = &mut debug {debug.frame(out, FrameEvent::FramePointer)}
+    if frame.size > 0 {
+        out.push(Inst::Sub(Reg::Rsp, Arg::Imm(frame.size as i64)));
+    }
+    for (index, reg) in frame.saved.iter().enumerate() {
+        out.push(Inst::Mov(frame.saved_slot(index), Arg::Reg(*reg)));
+    }
+    if let Some(debug) = &mut debug && !frame.saved.is_empty() {
+        let saved = frame.saved.iter().enumerate().map(|(index, reg)| match frame.saved_slot(index) {
+            Arg::Mem { offset, .. } => (*reg, offset),
+            _ => unreachable!(),
+        }).collect();
+        debug.frame(out, FrameEvent::Saved(saved));
+    }
+    // Parameters into their locals, the ones after the sixth are above the return address
+    for param in 0..function.parameters {
+        let source = match ARGUMENT_REGISTERS.get(param) {
+            Some(register) => *register,
+            None => {
+                let offset = 16 + 8 * (param - ARGUMENT_REGISTERS.len()) as i32;
+                out.push(Inst::Mov(Arg::Reg(Reg::Rax), Arg::Mem { base: Reg::Rbp, offset }));
+                Reg::Rax
+            }
+        };
+        out.push(Inst::Mov(frame.local(param), Arg::Reg(source)));
+    }
+
+    for (index, block) in function.blocks.iter().enumerate() {
+        out.push(Inst::Label(block_label(function, index)));
+        // The first line already went with the prologue
+        let skip = if index == 0 && first_line.is_some() {1} else {0};
+        for (position, instruction) in block.instructions.iter().enumerate().skip(skip) {
+            match instruction {
+                Instruction::Copy { dest, value } => mov(out, frame.register(*dest), frame.operand(value)),
+                Instruction::StringAddress { dest, index } => {
+                    out.push(Inst::Lea(Reg::Rax, Arg::Symbol(string_label(*index))));
+                    mov(out, frame.register(*dest), Arg::Reg(Reg::Rax));
+                }
+                Instruction::Binary { dest, op, left, right } => {
+                    out.push(Inst::Mov(Arg::Reg(Reg::Rax), frame.operand(left)));
+                    // The right side straight from its register, everything else through rcx
+                    let right = match frame.operand(right) {
+                        Arg::Reg(reg) => reg,
+                        right => {
+                            out.push(Inst::Mov(Arg::Reg(Reg::Rcx), right));
+                            Reg::Rcx
+                        }
+                    };
+                    match op {
+                        BinaryOp::Add => out.push(Inst::Add(Reg::Rax, Arg::Reg(right))),
+                        BinaryOp::Sub => out.push(Inst::Sub(Reg::Rax, Arg::Reg(right))),
+                        BinaryOp::Mul => out.push(Inst::Imul(Reg::Rax, Arg::Reg(right))),
+                        // idiv traps on MIN / -1, everything else wraps, so -1 is a negation instead
+                        BinaryOp::Div => {
+                            let divide = format!("{}_div{}", block_label(function, index), position);
+                            let done = format!("{}_done", divide);
+                            out.push(Inst::Cmp(right, Arg::Imm(-1)));
+                            out.push(Inst::Jcc(Cond::Ne, divide.clone()));
+                            out.push(Inst::Neg(Reg::Rax));
+                            out.push(Inst::Jmp(done.clone()));
+                            out.push(Inst::Label(divide));
+                            out.push(Inst::Cqo);
+                            out.push(Inst::Idiv(right));
+                            out.push(Inst::Label(done));
+                        }
+                        _ => {
+                            let cond = condition(*op).unwrap();
+                            out.push(Inst::Cmp(Reg::Rax, Arg::Reg(right)));
+                            out.push(Inst::Set(cond, Reg::Rax));
+                            out.push(Inst::MovzxByte(Reg::Rax, Arg::Reg(Reg::Rax)));
+                        }
+                    }
+                    mov(out, frame.register(*dest), Arg::Reg(Reg::Rax));
+                }
+                Instruction::Load { dest, local } => mov(out, frame.register(*dest), frame.local(*local)),
+                Instruction::Store { local, value } => mov(out, frame.local(*local), frame.operand(value)),
+                Instruction::Call { dest, function: target, args } => {
+                    if module.uses_c() && ["print", "__sys_write", "__sys_write_str"].contains(&target.as_str()) {
+                        flush_c_output(out);
+                    }
+                    if target == "print" {generate_print(out, &frame, function, args)}
+                    else {generate_call(out, &frame, target, args, *dest, module.external(target))}
+                }
+                Instruction::Asm { template, inputs, outputs, clobbers, .. } => {
+                    generate_asm(out, &frame, template, inputs, outputs, clobbers)
+                        .map_err(|error| format!("{} (in function '{}')", error, function.name))?;
+                }
+                Instruction::Line { file, line, column } => if let Some(debug) = &mut debug {debug.row(out, *file, *line, *column)},
+            }
+        }
+        match &block.terminator {
+            Terminator::Jump(target) => out.push(Inst::Jmp(block_label(function, *target))),
+            Terminator::Branch { condition, then_block, else_block } => {
+                let condition = match frame.operand(condition) {
+                    Arg::Reg(reg) => reg,
+                    condition => {
+                        out.push(Inst::Mov(Arg::Reg(Reg::Rax), condition));
+                        Reg::Rax
+                    }
+                };
+                out.push(Inst::Cmp(condition, Arg::Imm(0)));
+                out.push(Inst::Jcc(Cond::Ne, block_label(function, *then_block)));
+                out.push(Inst::Jmp(block_label(function, *else_block)));
+            }
+            Terminator::Return(value) => {
+                if let Some(value) = value {
+                    out.push(Inst::Mov(Arg::Reg(Reg::Rax), frame.operand(value)));
+                }
+                for (index, reg) in frame.saved.iter().enumerate() {
+                    out.push(Inst::Mov(Arg::Reg(*reg), frame.saved_slot(index)));
+                }
+                out.push(Inst::Mov(Arg::Reg(Reg::Rsp), Arg::Reg(Reg::Rbp)));
+                out.push(Inst::Pop(Reg::Rbp));
+                if let Some(debug) = &mut debug {debug.frame(out, FrameEvent::Left)}
+                out.push(Inst::Ret);
+                if let Some(debug) = &mut debug {debug.frame(out, FrameEvent::Returned)}
+            }
+        }
+    }
+    if let Some(debug) = debug {
+        debug.end(out);
+        debug_functions.push(debug);
+    }
+    Ok(())
+}
+
+/// Process entry point, runs the top level and exits with whatever it returned
+fn generate_start(out: &mut Vec<Inst>, entry: &str) {
+    out.push(Inst::Label(entry.to_string()));
+    out.push(Inst::Call(symbol(ENTRY_FUNCTION)));
+    out.push(Inst::Mov(Arg::Reg(Reg::Rdi), Arg::Reg(Reg::Rax)));
+    out.push(Inst::Mov(Arg::Reg(Reg::Rax), Arg::Imm(60))); // exit
+    out.push(Inst::Syscall);
+}
+
+/// Bare metal entry point, there's no OS to set up a stack or to exit to. The stack pointer
+/// comes from the linker script's STACK_TOP_SYMBOL, and the end is a hang.
+fn generate_freestanding_start(out: &mut Vec<Inst>, entry: &str) {
+    let hang = format!(".L{}_hang", entry);
+    out.extend([
+        Inst::Label(entry.to_string()),
+        Inst::Lea(Reg::Rsp, Arg::Symbol(STACK_TOP_SYMBOL.to_string())),
+        Inst::Call(symbol(ENTRY_FUNCTION)),
+        Inst::Label(hang.clone()),
+        Inst::Jmp(hang),
+    ]);
+}
+
+/// Registers an interrupt wrapper saves, everything but rsp (which the CPU takes care of)
+const INTERRUPT_SAVED: [Reg; 15] = [Reg::Rax, Reg::Rcx, Reg::Rdx, Reg::Rbx, Reg::Rbp, Reg::Rsi, Reg::Rdi,
+    Reg::R8, Reg::R9, Reg::R10, Reg::R11, Reg::R12, Reg::R13, Reg::R14, Reg::R15];
+
+/// `#[interrupt]` wrapper under the function's own name: saves every register, calls the
+/// Galvan function and returns with iretq. The CPU pushes 5 words and 15 more keep rsp
+/// 16-byte aligned for the call (for interrupts without an error code).
+fn generate_interrupt(out: &mut Vec<Inst>, function: &Function) {
+    out.push(Inst::Label(function.name.clone()));
+    out.extend(INTERRUPT_SAVED.iter().map(|reg| Inst::Push(*reg)));
+    out.push(Inst::Cld);
+    out.push(Inst::Call(symbol(&function.name)));
+    out.extend(INTERRUPT_SAVED.iter().rev().map(|reg| Inst::Pop(*reg)));
+    out.push(Inst::Iret);
+}
+
+/// Tiny runtime for `print`, all of it straight syscalls so nothing needs to be linked in
+fn generate_runtime(out: &mut Vec<Inst>) {
+    let label = |name: &str| name.to_string();
+
+    // __gv_print_char(rdi: char)
+    out.extend([
+        Inst::Label(label(PRINT_CHAR)),
+        Inst::Push(Reg::Rdi),
+        Inst::Mov(Arg::Reg(Reg::Rsi), Arg::Reg(Reg::Rsp)),
+        Inst::Mov(Arg::Reg(Reg::Rdx), Arg::Imm(1)),
+        Inst::Mov(Arg::Reg(Reg::Rdi), Arg::Imm(1)),
+        Inst::Mov(Arg::Reg(Reg::Rax), Arg::Imm(1)), // write
+        Inst::Syscall,
+        Inst::Pop(Reg::Rdi),
+        Inst::Ret,
+    ]);
+
+    // __gv_print_str(rdi: NUL terminated string)
+    out.extend([
+        Inst::Label(label(PRINT_STR)),
+        Inst::Mov(Arg::Reg(Reg::Rsi), Arg::Reg(Reg::Rdi)),
+        Inst::Mov(Arg::Reg(Reg::Rdx), Arg::Reg(Reg::Rdi)),
+        Inst::Label(label(".Lprint_str_loop")),
+        Inst::MovzxByte(Reg::Rax, Arg::Mem { base: Reg::Rdx, offset: 0 }),
+        Inst::Cmp(Reg::Rax, Arg::Imm(0)),
+        Inst::Jcc(Cond::E, label(".Lprint_str_write")),
+        Inst::Add(Reg::Rdx, Arg::Imm(1)),
+        Inst::Jmp(label(".Lprint_str_loop")),
+        Inst::Label(label(".Lprint_str_write")),
+        Inst::Sub(Reg::Rdx, Arg::Reg(Reg::Rsi)),
+        Inst::Mov(Arg::Reg(Reg::Rdi), Arg::Imm(1)),
+        Inst::Mov(Arg::Reg(Reg::Rax), Arg::Imm(1)), // write
+        Inst::Syscall,
+        Inst::Ret,
+    ]);
+
+    // __gv_print_int(rdi: i64), digits get written backwards into a buffer on the stack. The
+    // digits come from an unsigned division, i64::MIN stays negative when it's negated
+    out.extend([
+        Inst::Label(label(PRINT_INT)),
+        Inst::Push(Reg::Rbp),
+        Inst::Mov(Arg::Reg(Reg::Rbp), Arg::Reg(Reg::Rsp)),
+        Inst::Sub(Reg::Rsp, Arg::Imm(32)),
+        Inst::Mov(Arg::Reg(Reg::Rax), Arg::Reg(Reg::Rdi)),
+        Inst::Lea(Reg::Rsi, Arg::Mem { base: Reg::Rbp, offset: -1 }),
+        Inst::Mov(Arg::Reg(Reg::R8), Arg::Imm(0)), // negative?
+        Inst::Cmp(Reg::Rax, Arg::Imm(0)),
+        Inst::Jcc(Cond::Ge, label(".Lprint_int_loop")),
+        Inst::Mov(Arg::Reg(Reg::R8), Arg::Imm(1)),
+        Inst::Neg(Reg::Rax),
+        Inst::Label(label(".Lprint_int_loop")),
+        Inst::Mov(Arg::Reg(Reg::Rdx), Arg::Imm(0)),
+        Inst::Mov(Arg::Reg(Reg::Rcx), Arg::Imm(10)),
+        Inst::Div(Reg::Rcx),
+        Inst::Add(Reg::Rdx, Arg::Imm(b'0' as i64)),
+        Inst::StoreByte(Arg::Mem { base: Reg::Rsi, offset: 0 }, Reg::Rdx),
+        Inst::Sub(Reg::Rsi, Arg::Imm(1)),
+        Inst::Cmp(Reg::Rax, Arg::Imm(0)),
+        Inst::Jcc(Cond::Ne, label(".Lprint_int_loop")),
+        Inst::Cmp(Reg::R8, Arg::Imm(0)),
+        Inst::Jcc(Cond::E, label(".Lprint_int_write")),
+        Inst::Mov(Arg::Reg(Reg::Rdx), Arg::Imm(b'-' as i64)),
+        Inst::StoreByte(Arg::Mem { base: Reg::Rsi, offset: 0 }, Reg::Rdx),
+        Inst::Sub(Reg::Rsi, Arg::Imm(1)),
+        Inst::Label(label(".Lprint_int_write")),
+        Inst::Add(Reg::Rsi, Arg::Imm(1)),
+        Inst::Mov(Arg::Reg(Reg::Rdx), Arg::Reg(Reg::Rbp)),
+        Inst::Sub(Reg::Rdx, Arg::Reg(Reg::Rsi)),
+        Inst::Mov(Arg::Reg(Reg::Rdi), Arg::Imm(1)),
+        Inst::Mov(Arg::Reg(Reg::Rax), Arg::Imm(1)), // write
+        Inst::Syscall,
+        Inst::Mov(Arg::Reg(Reg::Rsp), Arg::Reg(Reg::Rbp)),
+        Inst::Pop(Reg::Rbp),
+        Inst::Ret,
+    ]);
+}
+
+/// Helper functions for the intrinsics (see intrinsics.rs) the module calls, they're called
+/// like any Galvan function so arguments are already in rdi, rsi and rdx
+fn generate_intrinsics(out: &mut Vec<Inst>, module: &Module) {
+    let syscall = |number: i64| [Inst::Mov(Arg::Reg(Reg::Rax), Arg::Imm(number)), Inst::Syscall, Inst::Ret];
+    for intrinsic in INTRINSICS {
+        let used = module.functions.iter().flat_map(|function| &function.blocks).flat_map(|block| &block.instructions)
+            .any(|instruction| matches!(instruction, Instruction::Call { function, .. } if function == intrinsic.name));
+        if !used {continue}
+
+        out.push(Inst::Label(symbol(intrinsic.name)));
+        match intrinsic.name {
+            // Linked with libc, so leave the heap to malloc and exit through exit() (which
+            // flushes stdio). Failing calloc gives 0, which mem::alloc takes as out of memory too.
+            "__alloc" if module.uses_c() => out.extend([
+                Inst::Mov(Arg::Reg(Reg::Rsi), Arg::Imm(1)),
+                Inst::Jmp("calloc".to_string()),
+            ]),
+            "__sys_exit" if module.uses_c() => out.push(Inst::Jmp("exit".to_string())),
+            // Moves the program break up, the kernel keeps track of where it is so there's
+            // no writable data needed. Fresh memory from brk is zeroed, and nothing is ever freed.
+            "__alloc" => out.extend([
+                // Round the size up to 8 bytes, so everything stays aligned
+                Inst::Mov(Arg::Reg(Reg::Rax), Arg::Reg(Reg::Rdi)),
+                Inst::Add(Reg::Rax, Arg::Imm(7)),
+                Inst::Mov(Arg::Reg(Reg::Rcx), Arg::Imm(8)),
+                Inst::Cqo,
+                Inst::Idiv(Reg::Rcx),
+                Inst::Imul(Reg::Rax, Arg::Reg(Reg::Rcx)),
+                Inst::Mov(Arg::Reg(Reg::Rsi), Arg::Reg(Reg::Rax)),
+                Inst::Mov(Arg::Reg(Reg::Rdi), Arg::Imm(0)),
+                Inst::Mov(Arg::Reg(Reg::Rax), Arg::Imm(12)), // brk(0), the current break
+                Inst::Syscall,
+                Inst::Mov(Arg::Reg(Reg::R8), Arg::Reg(Reg::Rax)),
+                Inst::Mov(Arg::Reg(Reg::Rdi), Arg::Reg(Reg::Rax)),
+                Inst::Add(Reg::Rdi, Arg::Reg(Reg::Rsi)),
+                Inst::Mov(Arg::Reg(Reg::Rax), Arg::Imm(12)),
+                Inst::Syscall,
+                Inst::Cmp(Reg::Rax, Arg::Reg(Reg::Rdi)),
+                Inst::Jcc(Cond::Ne, ".Lalloc_failed".to_string()),
+                Inst::Mov(Arg::Reg(Reg::Rax), Arg::Reg(Reg::R8)),
+                Inst::Ret,
+                Inst::Label(".Lalloc_failed".to_string()),
+                Inst::Mov(Arg::Reg(Reg::Rax), Arg::Imm(-12)), // ENOMEM
+                Inst::Ret,
+            ]),
+            "__load8" => out.extend([
+                Inst::MovzxByte(Reg::Rax, Arg::Mem { base: Reg::Rdi, offset: 0 }),
+                Inst::Ret,
+            ]),
+            "__store8" => out.extend([
+                Inst::Mov(Arg::Reg(Reg::Rax), Arg::Reg(Reg::Rsi)),
+                Inst::StoreByte(Arg::Mem { base: Reg::Rdi, offset: 0 }, Reg::Rax),
+                Inst::Ret,
+            ]),
+            "__str_byte" => out.extend([
+                Inst::Add(Reg::Rdi, Arg::Reg(Reg::Rsi)),
+                Inst::MovzxByte(Reg::Rax, Arg::Mem { base: Reg::Rdi, offset: 0 }),
+                Inst::Ret,
+            ]),
+            // Strings are addresses already
+            "__str_at" => out.extend([
+                Inst::Mov(Arg::Reg(Reg::Rax), Arg::Reg(Reg::Rdi)),
+                Inst::Ret,
+            ]),
+            "__sys_read" => out.extend(syscall(0)),
+            "__sys_write" => out.extend(syscall(1)),
+            "__sys_write_str" => out.extend([
+                Inst::Mov(Arg::Reg(Reg::Rdx), Arg::Reg(Reg::Rsi)),
+                Inst::Label(".Lwrite_str_loop".to_string()),
+                Inst::MovzxByte(Reg::Rax, Arg::Mem { base: Reg::Rdx, offset: 0 }),
+                Inst::Cmp(Reg::Rax, Arg::Imm(0)),
+                Inst::Jcc(Cond::E, ".Lwrite_str_write".to_string()),
+                Inst::Add(Reg::Rdx, Arg::Imm(1)),
+                Inst::Jmp(".Lwrite_str_loop".to_string()),
+                Inst::Label(".Lwrite_str_write".to_string()),
+                Inst::Sub(Reg::Rdx, Arg::Reg(Reg::Rsi)),
+                Inst::Mov(Arg::Reg(Reg::Rax), Arg::Imm(1)), // write
+                Inst::Syscall,
+                Inst::Ret,
+            ]),
+            "__sys_open" => out.extend(syscall(2)),
+            "__sys_close" => out.extend(syscall(3)),
+            "__sys_exit" => out.extend(syscall(60)),
+            name => unreachable!("intrinsic '{}' has no x86 version", name),
+        }
+    }
+}
+
+/// Generates the whole program for a module
+pub fn generate(module: &Module) -> Result<Program, String> {
+    if debug_prints(X86_DEBUG_PRINTS) {eprintln!("- - - X86")}
+
+    let mut text = vec![];
+    let mut globals = vec![];
+    let mut sections: Vec<(String, Vec<Inst>)> = vec![];
+    let mut debug_functions = vec![];
+    let entry = module.entry_symbol.clone().unwrap_or(START_SYMBOL.to_string());
+    // libc's _start calls main, so a program linked with it starts right in the entry function
+    let c_entry = module.uses_c() && !module.freestanding;
+    match module.entry() {
+        Some(_) if c_entry => globals.push(C_ENTRY_SYMBOL.to_string()),
+        Some(_) if module.freestanding => {
+            generate_freestanding_start(&mut text, &entry);
+            globals.push(entry.clone());
+        }
+        Some(_) => {
+            generate_start(&mut text, &entry);
+            globals.push(entry.clone());
+        }
+        None => {}
+    }
+    for function in &module.functions {
+        let out = match &function.attributes.section {
+            Some(section) if section != ".text" => match sections.iter().position(|(name, _)| name == section) {
+                Some(index) => &mut sections[index].1,
+                None => {
+                    sections.push((section.clone(), vec![]));
+                    &mut sections.last_mut().unwrap().1
+                }
+            },
+            _ => &mut text,
+        };
+        if function.attributes.interrupt {
+            generate_interrupt(out, function);
+            globals.push(function.name.clone());
+        }
+        if function.name == ENTRY_FUNCTION && c_entry {out.push(Inst::Label(C_ENTRY_SYMBOL.to_string()))}
+        if let Some(export) = &function.export {
+            out.push(Inst::Label(export.clone()));
+            globals.push(export.clone());
+        }
+        generate_function(out, function, module, &mut debug_functions)?;
+    }
+    // Nothing to print to without an OS, seman already made sure nothing does
+    if !module.freestanding {generate_runtime(&mut text)}
+    generate_intrinsics(&mut text, module);
+
+    let rodata = module.strings.iter().enumerate().map(|(index, string)| {
+        let mut bytes = string.as_bytes().to_vec();
+        bytes.push(0);
+        (string_label(index), bytes)
+    }).collect();
+
+    let debug = if module.files.is_empty() {vec![]} else {dwarf::generate(&module.files, &debug_functions)};
+
+    if debug_prints(X86_DEBUG_PRINTS) {eprintln!("- - - X86 done!")}
+    Ok(Program { text, rodata, globals, sections, entry, debug })
+}
+
+//
+// PRINTING
+//
+
+impl Arg {
+    fn att(&self) -> String {
+        match self {
+            Arg::Reg(reg) => format!("%{}", reg.name()),
+            Arg::Imm(value) => format!("${}", value),
+            Arg::Mem { base, offset: 0 } => format!("(%{})", base.name()),
+            Arg::Mem { base, offset } => format!("{}(%{})", offset, base.name()),
+            Arg::Symbol(symbol) => format!("{}(%rip)", symbol),
+        }
+    }
+
+    fn intel(&self, size: &str) -> String {
+        match self {
+            Arg::Reg(reg) => reg.name().to_string(),
+            Arg::Imm(value) => value.to_string(),
+            Arg::Mem { base, offset: 0 } => format!("{} PTR [{}]", size, base.name()),
+            Arg::Mem { base, offset } if *offset < 0 => format!("{} PTR [{}-{}]", size, base.name(), -offset),
+            Arg::Mem { base, offset } => format!("{} PTR [{}+{}]", size, base.name(), offset),
+            Arg::Symbol(symbol) => format!("[rip+{}]", symbol),
+        }
+    }
+}
+
+/// Instruction as a line of assembly, without the indentation
+fn print_inst(inst: &Inst, syntax: Syntax) -> String {
+    let reg = |reg: &Reg| Arg::Reg(*reg);
+    // Two operand instruction, destination first
+    let two = |name: &str, dest: &Arg, source: &Arg| match syntax {
+        Syntax::Att => {
+            // Immediates that don't fit in 32 bits need the movabs form
+            let name = match source {
+                Arg::Imm(value) if name == "mov" && i32::try_from(*value).is_err() => "movabs",
+                _ => name,
+            };
+            format!("{}q {}, {}", name, source.att(), dest.att())
+        }
+        Syntax::Intel => format!("{} {}, {}", name, dest.intel("QWORD"), source.intel("QWORD")),
+    };
+    let one = |name: &str, arg: &Arg| match syntax {
+        Syntax::Att => format!("{}q {}", name, arg.att()),
+        Syntax::Intel => format!("{} {}", name, arg.intel("QWORD")),
+    };
+    match inst {
+        Inst::Label(label) => format!("{}:", label),
+        Inst::Mov(dest, source) => two("mov", dest, source),
+        Inst::Lea(dest, source) => two("lea", &reg(dest), source),
+        Inst::Add(dest, source) => two("add", &reg(dest), source),
+        Inst::Sub(dest, source) => two("sub", &reg(dest), source),
+        Inst::Imul(dest, source) => two("imul", &reg(dest), source),
+        Inst::Cmp(dest, source) => two("cmp", &reg(dest), source),
+        Inst::Neg(dest) => one("neg", &reg(dest)),
+        Inst::Idiv(source) => one("idiv", &reg(source)),
+        Inst::Div(source) => one("div", &reg(source)),
+        Inst::Push(source) => one("push", &reg(source)),
+        Inst::Pop(dest) => one("pop", &reg(dest)),
+        Inst::Cqo => match syntax {Syntax::Att => "cqto".to_string(), Syntax::Intel => "cqo".to_string()},
+        Inst::Set(cond, dest) => match syntax {
+            Syntax::Att => format!("set{} %{}", cond.name(), dest.byte_name()),
+            Syntax::Intel => format!("set{} {}", cond.name(), dest.byte_name()),
+        },
+        Inst::MovzxByte(dest, source) => {
+            match (syntax, source) {
+                (Syntax::Att, Arg::Reg(source)) => format!("movzbq %{}, %{}", source.byte_name(), dest.name()),
+                (Syntax::Att, source) => format!("movzbq {}, %{}", source.att(), dest.name()),
+                (Syntax::Intel, Arg::Reg(source)) => format!("movzx {}, {}", dest.name(), source.byte_name()),
+                (Syntax::Intel, source) => format!("movzx {}, {}", dest.name(), source.intel("BYTE")),
+            }
+        }
+        Inst::StoreByte(dest, source) => match syntax {
+            Syntax::Att => format!("movb %{}, {}", source.byte_name(), dest.att()),
+            Syntax::Intel => format!("mov {}, {}", dest.intel("BYTE"), source.byte_name()),
+        },
+        // Writing a 32-bit register clears the upper half, that's the zero extension from 32
+        Inst::Extend(reg, 32, false) => match syntax {
+            Syntax::Att => format!("movl %{}, %{}", reg.dword_name(), reg.dword_name()),
+            Syntax::Intel => format!("mov {}, {}", reg.dword_name(), reg.dword_name()),
+        },
+        Inst::Extend(reg, bits, signed) => {
+            let (att, intel) = match (bits, signed) {
+                (8, true) => ("movsbq", "movsx"),
+                (8, false) => ("movzbq", "movzx"),
+                (16, true) => ("movswq", "movsx"),
+                (16, false) => ("movzwq", "movzx"),
+                _ => ("movslq", "movsxd"),
+            };
+            match syntax {
+                Syntax::Att => format!("{} %{}, %{}", att, reg.sized_name(*bits), reg.name()),
+                Syntax::Intel => format!("{} {}, {}", intel, reg.name(), reg.sized_name(*bits)),
+            }
+        }
+        Inst::Jmp(label) => format!("jmp {}", label),
+        Inst::Jcc(cond, label) => format!("j{} {}", cond.name(), label),
+        Inst::Call(label) => format!("call {}", label),
+        Inst::Ret => "ret".to_string(),
+        Inst::Syscall => "syscall".to_string(),
+        Inst::Iret => "iretq".to_string(),
+        Inst::Cld => "cld".to_string(),
+        Inst::Asm { template, operands } => {
+            let expanded = expand_asm(template, &mut |name| Ok(match operands.iter().find(|(operand, _)| operand == name) {
+                Some((_, reg)) if syntax == Syntax::Att => format!("%{}", reg.name()),
+                Some((_, reg)) => reg.name().to_string(),
+                None => format!("{{{}}}", name),
+            }));
+            // Already checked by seman or the IR verifier, so this can't really fail
+            let expanded = expanded.unwrap_or(template.clone());
+            expanded.lines().map(str::trim).collect::<Vec<&str>>().join("\n    ")
+        }
+    }
+}
+
+impl Program {
+    /// The program as GNU assembler source
+    pub fn to_assembly(&self, syntax: Syntax) -> String {
+        let mut out = String::new();
+        if syntax == Syntax::Intel {out.push_str(".intel_syntax noprefix\n")}
+        for global in &self.globals {
+            out.push_str(&format!(".globl {}\n", global));
+        }
+
+        rodata_assembly(&mut out, &self.rodata);
+
+        let sections = std::iter::once((".text".to_string(), &self.text))
+            .chain(self.sections.iter().map(|(name, text)| (format!(".section {},\"ax\",@progbits", name), text)));
+        for (directive, text) in sections {
+            out.push_str(&format!("\n{}\n", directive));
+            for inst in text {
+                match inst {
+                    Inst::Label(label) => {
+                        if !label.starts_with(".L") {out.push('\n')}
+                        out.push_str(&format!("{}:\n", label));
+                    }
+                    inst => out.push_str(&format!("    {}\n", print_inst(inst, syntax))),
+                }
+            }
+        }
+        for section in &self.debug {
+            out.push_str(&dwarf::to_assembly(section));
+        }
+        // The stack doesn't need to be executable, ld warns when linking without this
+        out.push_str("\n.section .note.GNU-stack,\"\",@progbits\n");
+        out
+    }
+}
@@ -0,0 +1,273 @@
+use std::collections::HashMap;
+
+use crate::x86::{Arg, Cond, Inst, Reg};
+
+// Machine code encoder for the x86 backend's `Inst`s, so object files can be written
+// without going through an external assembler.
+// Every jump and call uses the rel32 form, no relaxation. Good enough.
+
+//
+// STRUCTS
+//
+
+/// Something in the code that points somewhere only the linker (or the ELF writer) knows
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+pub enum RelocationTarget {
+    /// A label in the rodata section
+    Rodata(String),
+    /// A symbol that's not defined in this code (extern function)
+    Symbol(String),
+}
+
+/// A 32-bit PC-relative field that needs filling in, the value is `target + addend - offset`
+#[derive(Debug)]
+#[derive(Clone)]
+pub struct Relocation {
+    pub offset: usize,
+    pub target: RelocationTarget,
+    pub addend: i64,
+}
+
+#[derive(Debug)]
+pub struct Encoded {
+    pub code: Vec<u8>,
+    /// Offset of every label, including the local `.L` ones
+    pub labels: HashMap<String, usize>,
+    pub relocations: Vec<Relocation>,
+}
+
+//
+// FUNCTIONS
+//
+
+fn condition_code(cond: Cond) -> u8 {
+    match cond {
+        Cond::E => 0x4,
+        Cond::Ne => 0x5,
+        Cond::L => 0xC,
+        Cond::Ge => 0xD,
+        Cond::Le => 0xE,
+        Cond::G => 0xF,
+    }
+}
+
+fn fits_i8(value: i64) -> bool {
+    i8::try_from(value).is_ok()
+}
+
+fn imm32(value: i64) -> Result<[u8; 4], String> {
+    match i32::try_from(value) {
+        Ok(value) => Ok(value.to_le_bytes()),
+        Err(_) => Err(format!("Immediate {} doesn't fit in 32 bits", value)),
+    }
+}
+
+/// Label waiting for its address, the rel32 at `offset` is relative to `offset + 4`
+struct Fixup {
+    offset: usize,
+    label: String,
+}
+
+struct Encoder {
+    code: Vec<u8>,
+    labels: HashMap<String, usize>,
+    fixups: Vec<Fixup>,
+    relocations: Vec<Relocation>,
+}
+impl Encoder {
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.code.extend_from_slice(bytes);
+    }
+
+    /// REX prefix, `wide` is REX.W, `reg` and `rm` are the full register numbers
+    fn rex(&mut self, wide: bool, reg: u8, rm: u8, force: bool) {
+        let rex = 0x40 | (wide as u8) << 3 | (reg >> 3) << 2 | (rm >> 3);
+        if rex != 0x40 || force {self.bytes(&[rex])}
+    }
+
+    /// ModRM (and SIB and displacement) for `reg` with a register or memory operand.
+    /// `[rip + symbol]` operands add a relocation.
+    fn modrm(&mut self, reg: u8, arg: &Arg) -> Result<(), String> {
+        let reg = (reg & 7) << 3;
+        match arg {
+            Arg::Reg(rm) => self.bytes(&[0xC0 | reg | (rm.number() & 7)]),
+            Arg::Mem { base, offset } => {
+                let rm = base.number() & 7;
+                // rbp/r13 can't be encoded without a displacement
+                let (mode, displacement): (u8, Vec<u8>) = if *offset == 0 && rm != 5 {
+                    (0x00, vec![])
+                } else if fits_i8(*offset as i64) {
+                    (0x40, vec![*offset as i8 as u8])
+                } else {
+                    (0x80, offset.to_le_bytes().to_vec())
+                };
+                self.bytes(&[mode | reg | rm]);
+                // rsp/r12 always need a SIB byte
+                if rm == 4 {self.bytes(&[0x24])}
+                self.bytes(&displacement);
+            }
+            Arg::Symbol(symbol) => {
+                self.bytes(&[reg | 0x05]);
+                let offset = self.code.len();
+                self.bytes(&[0; 4]);
+                if symbol.starts_with(".Lstr") {
+                    self.relocations.push(Relocation { offset, target: RelocationTarget::Rodata(symbol.clone()), addend: -4 });
+                } else {
+                    self.fixups.push(Fixup { offset, label: symbol.clone() });
+                }
+            }
+            Arg::Imm(_) => return Err("Immediate used as a register/memory operand".to_string()),
+        }
+        Ok(())
+    }
+
+    fn rm_number(arg: &Arg) -> u8 {
+        match arg {
+            Arg::Reg(reg) | Arg::Mem { base: reg, .. } => reg.number(),
+            _ => 0,
+        }
+    }
+
+    /// `op r/m64, reg` style instruction
+    fn wide(&mut self, opcode: &[u8], reg: u8, rm: &Arg) -> Result<(), String> {
+        self.rex(true, reg, Encoder::rm_number(rm), false);
+        self.bytes(opcode);
+        self.modrm(reg, rm)
+    }
+
+    /// add/sub/cmp, `extension` is the /digit of the immediate forms
+    fn arithmetic(&mut self, store: u8, load: u8, extension: u8, dest: Reg, source: &Arg) -> Result<(), String> {
+        match source {
+            Arg::Reg(source) => self.wide(&[store], source.number(), &Arg::Reg(dest)),
+            Arg::Imm(value) if fits_i8(*value) => {
+                self.wide(&[0x83], extension, &Arg::Reg(dest))?;
+                self.bytes(&[*value as i8 as u8]);
+                Ok(())
+            }
+            Arg::Imm(value) => {
+                self.wide(&[0x81], extension, &Arg::Reg(dest))?;
+                let value = imm32(*value)?;
+                self.bytes(&value);
+                Ok(())
+            }
+            memory => self.wide(&[load], dest.number(), memory),
+        }
+    }
+
+    /// rel32 jump/call to a label, filled in once all labels are known
+    fn rel32(&mut self, opcode: &[u8], label: &str) {
+        self.bytes(opcode);
+        self.fixups.push(Fixup { offset: self.code.len(), label: label.to_string() });
+        self.bytes(&[0; 4]);
+    }
+
+    fn encode(&mut self, inst: &Inst) -> Result<(), String> {
+        match inst {
+            Inst::Label(label) => {
+                if self.labels.insert(label.clone(), self.code.len()).is_some() {
+                    return Err(format!("Label '{}' defined twice", label));
+                }
+            }
+            Inst::Mov(dest, source) => match (dest, source) {
+                (Arg::Reg(dest), Arg::Imm(value)) => {
+                    if let Ok(value) = imm32(*value) {
+                        self.wide(&[0xC7], 0, &Arg::Reg(*dest))?;
+                        self.bytes(&value);
+                    } else {
+                        // movabs
+                        self.rex(true, 0, dest.number(), false);
+                        self.bytes(&[0xB8 + (dest.number() & 7)]);
+                        self.bytes(&value.to_le_bytes());
+                    }
+                }
+                (Arg::Reg(dest), source) => self.wide(&[0x8B], dest.number(), source)?,
+                (dest, Arg::Reg(source)) => self.wide(&[0x89], source.number(), dest)?,
+                _ => return Err(format!("Can't encode {:?}", inst)),
+            },
+            Inst::Lea(dest, source) => self.wide(&[0x8D], dest.number(), source)?,
+            Inst::Add(dest, source) => self.arithmetic(0x01, 0x03, 0, *dest, source)?,
+            Inst::Sub(dest, source) => self.arithmetic(0x29, 0x2B, 5, *dest, source)?,
+            Inst::Cmp(dest, source) => self.arithmetic(0x39, 0x3B, 7, *dest, source)?,
+            Inst::Imul(dest, source) => match source {
+                Arg::Imm(_) => return Err(format!("Can't encode {:?}", inst)),
+                source => self.wide(&[0x0F, 0xAF], dest.number(), source)?,
+            },
+            Inst::Neg(dest) => self.wide(&[0xF7], 3, &Arg::Reg(*dest))?,
+            Inst::Cqo => self.bytes(&[0x48, 0x99]),
+            Inst::Idiv(source) => self.wide(&[0xF7], 7, &Arg::Reg(*source))?,
+            Inst::Div(source) => self.wide(&[0xF7], 6, &Arg::Reg(*source))?,
+            Inst::Set(cond, dest) => {
+                // REX needed to get sil/dil/... instead of ah/ch/...
+                self.rex(false, 0, dest.number(), dest.number() >= 4);
+                self.bytes(&[0x0F, 0x90 + condition_code(*cond)]);
+                self.modrm(0, &Arg::Reg(*dest))?;
+            }
+            Inst::MovzxByte(dest, source) => self.wide(&[0x0F, 0xB6], dest.number(), source)?,
+            Inst::Extend(reg, bits, signed) => match (bits, signed) {
+                (8, true) => self.wide(&[0x0F, 0xBE], reg.number(), &Arg::Reg(*reg))?,
+                (8, false) => self.wide(&[0x0F, 0xB6], reg.number(), &Arg::Reg(*reg))?,
+                (16, true) => self.wide(&[0x0F, 0xBF], reg.number(), &Arg::Reg(*reg))?,
+                (16, false) => self.wide(&[0x0F, 0xB7], reg.number(), &Arg::Reg(*reg))?,
+                (32, true) => self.wide(&[0x63], reg.number(), &Arg::Reg(*reg))?,
+                // mov r32, r32
+                (32, false) => {
+                    self.rex(false, reg.number(), reg.number(), false);
+                    self.bytes(&[0x8B]);
+                    self.modrm(reg.number(), &Arg::Reg(*reg))?;
+                }
+                _ => return Err(format!("Can't encode {:?}", inst)),
+            },
+            Inst::StoreByte(dest, source) => {
+                self.rex(false, source.number(), Encoder::rm_number(dest), source.number() >= 4);
+                self.bytes(&[0x88]);
+                self.modrm(source.number(), dest)?;
+            }
+            Inst::Jmp(label) => self.rel32(&[0xE9], label),
+            Inst::Jcc(cond, label) => self.rel32(&[0x0F, 0x80 + condition_code(*cond)], label),
+            Inst::Call(label) => self.rel32(&[0xE8], label),
+            Inst::Ret => self.bytes(&[0xC3]),
+            Inst::Push(source) => {
+                self.rex(false, 0, source.number(), false);
+                self.bytes(&[0x50 + (source.number() & 7)]);
+            }
+            Inst::Pop(dest) => {
+                self.rex(false, 0, dest.number(), false);
+                self.bytes(&[0x58 + (dest.number() & 7)]);
+            }
+            Inst::Syscall => self.bytes(&[0x0F, 0x05]),
+            Inst::Iret => self.bytes(&[0x48, 0xCF]),
+            Inst::Cld => self.bytes(&[0xFC]),
+            Inst::Asm { .. } => return Err("Inline assembly needs a real assembler, build with --emit=asm and run the output through `as`".to_string()),
+        }
+        Ok(())
+    }
+}
+
+/// Encodes the instructions into machine code. Jumps and calls to labels in the code
+/// get resolved right away, anything else ends up in `relocations`.
+pub fn encode(text: &[Inst]) -> Result<Encoded, String> {
+    let mut encoder = Encoder { code: vec![], labels: HashMap::new(), fixups: vec![], relocations: vec![] };
+    for inst in text {
+        encoder.encode(inst)?;
+    }
+
+    for fixup in &encoder.fixups {
+        match encoder.labels.get(&fixup.label) {
+            Some(target) => {
+                let relative = *target as i64 - (fixup.offset as i64 + 4);
+                let relative = imm32(relative)?;
+                encoder.code[fixup.offset..fixup.offset + 4].copy_from_slice(&relative);
+            }
+            None if fixup.label.starts_with(".L") => return Err(format!("Unknown label '{}'", fixup.label)),
+            None => encoder.relocations.push(Relocation {
+                offset: fixup.offset,
+                target: RelocationTarget::Symbol(fixup.label.clone()),
+                addend: -4,
+            }),
+        }
+    }
+
+    Ok(Encoded { code: encoder.code, labels: encoder.labels, relocations: encoder.relocations })
+}
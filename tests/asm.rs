@@ -0,0 +1,63 @@
+use std::process::Command;
+
+mod common;
+use common::{galvan, have, scratch};
+
+// Inline assembly: with binutils around, an `asm volatile` block with inputs, an output and
+// clobbers gets assembled, linked and run. Everything that can't run arbitrary assembly has
+// to refuse the program up front, with a message saying what to do instead.
+
+const SOURCE: &str = r#"function add(a, b) {
+    let sum = 0;
+    asm volatile {
+        "movq {a}, {sum}"
+        "addq {b}, {sum}"
+        "movq $7, %rax"
+    } in (a = a, b = b) out (sum) clobber ("rax", "cc");
+    return sum;
+}
+let x = 40;
+call print(add(x, 2), x);
+return add(1, 2);
+"#;
+
+#[test]
+fn asm_runs() {
+    if !have("as") || !have("ld") {
+        eprintln!("no as or ld, skipping");
+        return;
+    }
+    let dir = scratch("run");
+    std::fs::write(dir.join("main.gv"), SOURCE).unwrap();
+    for level in ["-O0", "-O2"] {
+        let build = galvan(&["build", "main.gv", level, "-o", "main.s"], &dir);
+        assert!(build.status.success(), "{}", String::from_utf8_lossy(&build.stderr));
+        assert!(Command::new("as").args(["main.s", "-o", "main.o"]).current_dir(&dir).status().unwrap().success());
+        assert!(Command::new("ld").args(["main.o", "-o", "main"]).current_dir(&dir).status().unwrap().success());
+        // rax is clobbered in the middle of it, the result still comes back right
+        let run = Command::new(dir.join("main")).output().unwrap();
+        assert_eq!((String::from_utf8_lossy(&run.stdout).as_ref(), run.status.code()), ("42 40\n", Some(3)), "{}", level);
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn asm_is_refused() {
+    let dir = scratch("refused");
+    std::fs::write(dir.join("main.gv"), SOURCE).unwrap();
+    let cases: [(&[&str], &str); 5] = [
+        (&["run", "main.gv"], "error: runtime error: Inline assembly can't run in the interpreter, compile the program to assembly or C instead at position 3:5 in main.gv\n    in main\n"),
+        (&["run", "--vm", "main.gv"], "error: Inline assembly in 'add' can't run on the VM, compile the program to assembly or C instead\n"),
+        (&["build", "main.gv", "--emit=exe", "-o", "main"], "error: Inline assembly needs a real assembler, build with --emit=asm and run the output through `as`\n"),
+        (&["build", "main.gv", "--emit=obj", "-o", "main.o"], "error: Inline assembly needs a real assembler, build with --emit=asm and run the output through `as`\n"),
+        (&["build", "main.gv", "--target=wasm32", "--emit=exe", "-o", "main.wasm"], "error: Inline assembly can't be compiled to WebAssembly at position 3:5 in main.gv\n"),
+    ];
+    for (args, error) in cases {
+        let run = galvan(args, &dir);
+        assert_eq!(run.status.code(), Some(1), "{:?}", args);
+        assert_eq!(String::from_utf8_lossy(&run.stdout), "", "{:?}", args);
+        assert_eq!(String::from_utf8_lossy(&run.stderr), error, "{:?}", args);
+    }
+    assert!(!dir.join("main").exists() && !dir.join("main.o").exists() && !dir.join("main.wasm").exists());
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,140 @@
+use std::process::Command;
+
+mod common;
+use common::{galvan, have, scratch};
+
+// `galvan bindgen` on headers that use the preprocessor, enums, typedefs and structs, what
+// comes out for everything it can't translate, and `import c` linked against the C side.
+
+const HAL: &str = r#"#ifndef HAL_H
+#define HAL_H
+#include "regs.h"
+#include <stdint.h>
+
+#define VERSION 2
+#if VERSION > 2
+#define MODE 3
+#elif VERSION == 2
+#define MODE 2
+#else
+#define MODE 1
+#endif
+#ifdef MISSING
+#define NEVER 1
+#endif
+#define DIV (1/0)
+#define PI 3.14
+#define MASK ((uint8_t)0x1FF)
+#define SQUARE(x) ((x) * (x))
+#define PACKED __attribute__((packed, aligned(4)))
+#define NAME "hal"
+
+typedef enum { STATE_LOW, STATE_HIGH = 5, STATE_NEXT } state_t;
+typedef uint32_t pin_t;
+
+typedef struct {
+    uint8_t flag;
+    uint32_t ODR;
+    uint16_t small;
+    uint64_t wide;
+} GPIO_TypeDef;
+
+#define GPIO_SIZE sizeof(GPIO_TypeDef)
+
+int hal_write(GPIO_TypeDef *port, pin_t pin, state_t state);
+static int helper(int x);
+int counter;
+float scale(float x);
+#endif
+"#;
+
+// Includes hal.h back, the guards have to stop it
+const REGS: &str = "#ifndef REGS_H\n#define REGS_H\n#include \"hal.h\"\n#define REG_BASE 0x40000000\n#endif\n";
+
+const EXPECTED: &str = r#"// Generated by galvan bindgen from hal.h
+// skipped define HAL_H: it's empty, there's no value
+// skipped define REGS_H: it's empty, there's no value
+pub const REG_BASE = 1073741824;
+pub const VERSION = 2;
+pub const MODE = 2;
+// skipped define DIV: division by zero
+// skipped define PI: floating point isn't supported
+pub const MASK = 255;
+// skipped define PACKED: '__attribute__' isn't a constant
+// skipped define NAME: only integers become constants, not strings
+pub const GPIO_SIZE = 24;
+// skipped macro SQUARE(): function-like macros can't be translated
+pub const STATE_LOW = 0;
+pub const STATE_HIGH = 5;
+pub const STATE_NEXT = 6;
+pub const sizeof_GPIO_TypeDef = 24;
+pub const offsetof_GPIO_TypeDef_flag = 0;
+pub const offsetof_GPIO_TypeDef_ODR = 4;
+pub const offsetof_GPIO_TypeDef_small = 8;
+pub const offsetof_GPIO_TypeDef_wide = 16;
+pub extern "C" function hal_write(port: *void, pin: u32, state: i32) -> i32;
+// skipped function helper: it's static, so there's nothing to link against
+// skipped variable counter: C globals can't be used from Galvan
+// skipped float scale(float x): parameter 1: floating point isn't supported
+"#;
+
+fn headers(dir: &std::path::Path) {
+    std::fs::write(dir.join("hal.h"), HAL).unwrap();
+    std::fs::write(dir.join("regs.h"), REGS).unwrap();
+}
+
+#[test]
+fn header_to_galvan() {
+    let dir = scratch("header");
+    headers(&dir);
+    let bindgen = galvan(&["bindgen", "hal.h"], &dir);
+    assert!(bindgen.status.success(), "{}", String::from_utf8_lossy(&bindgen.stderr));
+    assert_eq!(String::from_utf8_lossy(&bindgen.stdout), EXPECTED);
+
+    let bindgen = galvan(&["bindgen", "hal.h", "-o", "hal.gv"], &dir);
+    assert!(bindgen.status.success(), "{}", String::from_utf8_lossy(&bindgen.stderr));
+    assert_eq!(std::fs::read_to_string(dir.join("hal.gv")).unwrap(), EXPECTED);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn include_cycle_without_guards() {
+    let dir = scratch("cycle");
+    std::fs::write(dir.join("a.h"), "#include \"b.h\"\nint a(void);\n#include \"missing.h\"\n").unwrap();
+    std::fs::write(dir.join("b.h"), "#include \"a.h\"\nint b(void);\n").unwrap();
+    let bindgen = galvan(&["bindgen", "a.h"], &dir);
+    assert!(bindgen.status.success(), "{}", String::from_utf8_lossy(&bindgen.stderr));
+    assert_eq!(String::from_utf8_lossy(&bindgen.stdout), "// Generated by galvan bindgen from a.h\n\
+        // skipped #include \"missing.h\": not found\n\
+        pub extern \"C\" function b() -> i32;\npub extern \"C\" function a() -> i32;\n");
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn import_c() {
+    let dir = scratch("import");
+    headers(&dir);
+    // Constants work anywhere, even on the interpreter
+    std::fs::write(dir.join("consts.gv"), "import c \"hal.h\";\nreturn hal::MODE + hal::sizeof_GPIO_TypeDef;\n").unwrap();
+    let run = galvan(&["run", "consts.gv"], &dir);
+    assert_eq!((run.status.code(), String::from_utf8_lossy(&run.stderr).as_ref()), (Some(26), ""));
+
+    if !have("cc") {
+        eprintln!("no cc, skipping");
+        let _ = std::fs::remove_dir_all(&dir);
+        return;
+    }
+    // The layout has to be the one the C compiler picks
+    std::fs::write(dir.join("hal.c"), "#include <stddef.h>\n#include \"hal.h\"\n\
+        int hal_write(GPIO_TypeDef *port, pin_t pin, state_t state) {\n\
+            if (sizeof(GPIO_TypeDef) != 24 || offsetof(GPIO_TypeDef, ODR) != 4 || offsetof(GPIO_TypeDef, wide) != 16) return -1;\n\
+            return port == 0 ? (int)pin * 10 + (int)state : -2;\n}\n").unwrap();
+    std::fs::write(dir.join("main.gv"), "import c \"hal.h\";\nreturn hal::hal_write(0, hal::MODE, hal::STATE_HIGH) + hal::offsetof_GPIO_TypeDef_ODR;\n").unwrap();
+    let build = galvan(&["build", "main.gv", "--emit=obj", "-o", "main.o"], &dir);
+    assert!(build.status.success(), "{}", String::from_utf8_lossy(&build.stderr));
+    let link = Command::new("cc").args(["main.o", "hal.c", "-o", "main"]).current_dir(&dir).output().unwrap();
+    assert!(link.status.success(), "{}", String::from_utf8_lossy(&link.stderr));
+    let run = Command::new(dir.join("main")).output().unwrap();
+    assert_eq!(run.status.code(), Some(29));
+    let _ = std::fs::remove_dir_all(&dir);
+}
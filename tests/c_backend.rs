@@ -0,0 +1,86 @@
+use std::process::Command;
+
+mod common;
+use common::{galvan, have, scratch};
+
+// --emit=c: the C it writes has to compile without warnings and do what the interpreter
+// does. Without a C compiler around there's nothing to check, so the test says so and passes.
+
+const SOURCE: &str = r#"function fact(n) {
+    if (n < 2) {
+        return 1;
+    }
+    return n * fact(n - 1);
+}
+
+let i = 1;
+while (i < 6) {
+    call print(i, fact(i));
+    let i = i + 1;
+}
+let min = 0 - 9223372036854775807;
+call print(min - 1, "quote \" and\ttab");
+return fact(4);
+"#;
+
+const OUTPUT: &str = "1 1\n2 2\n3 6\n4 24\n5 120\n-9223372036854775808 quote \" and\ttab\n";
+
+#[test]
+fn c_compiles_and_runs() {
+    if !have("cc") {
+        eprintln!("no cc, skipping");
+        return;
+    }
+    let dir = scratch("c");
+    std::fs::write(dir.join("main.gv"), SOURCE).unwrap();
+    let build = galvan(&["build", "main.gv", "--emit=c", "-o", "main.c"], &dir);
+    assert!(build.status.success(), "{}", String::from_utf8_lossy(&build.stderr));
+    let c = std::fs::read_to_string(dir.join("main.c")).unwrap();
+    // Errors and debuggers point at the Galvan source
+    assert!(c.contains("#line 3 \"main.gv\""), "{}", c);
+
+    let compile = Command::new("cc").args(["-std=c99", "-Wall", "-Werror", "main.c", "-o", "main"]).current_dir(&dir).output().unwrap();
+    assert!(compile.status.success(), "{}\n{}", String::from_utf8_lossy(&compile.stderr), c);
+    let run = Command::new(dir.join("main")).output().unwrap();
+    assert_eq!(String::from_utf8_lossy(&run.stdout), OUTPUT);
+    assert_eq!(run.status.code(), Some(24));
+
+    let interpreted = galvan(&["run", "main.gv"], &dir);
+    assert_eq!(String::from_utf8_lossy(&interpreted.stdout), OUTPUT);
+    assert_eq!(interpreted.status.code(), Some(24));
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn overflow_wraps() {
+    if !have("cc") {
+        eprintln!("no cc, skipping");
+        return;
+    }
+    // Signed overflow is undefined in C, a compiler is free to fold `x + 1 > x` to 1
+    let dir = scratch("overflow");
+    std::fs::write(dir.join("main.gv"), "function f(x) {\n    return x + 1 > x;\n}\nfunction divide(a, b) {\n    return a / b;\n}\nlet max = 9223372036854775807;\nlet min = 0 - max - 1;\nlet m = 0 - 1;\ncall print(f(max), f(1), max + 1, min - 1, max * 2);\ncall print(divide(min, m), min / m, divide(0 - 7, 2));\n").unwrap();
+    const OUTPUT: &str = "0 1 -9223372036854775808 9223372036854775807 -2\n-9223372036854775808 -9223372036854775808 -3\n";
+    let interpreted = galvan(&["run", "main.gv"], &dir);
+    assert_eq!(String::from_utf8_lossy(&interpreted.stdout), OUTPUT);
+
+    let build = galvan(&["build", "main.gv", "--emit=c", "-o", "main.c"], &dir);
+    assert!(build.status.success(), "{}", String::from_utf8_lossy(&build.stderr));
+    for level in ["-O0", "-O2"] {
+        let compile = Command::new("cc").args(["-std=c99", "-Wall", "-Werror", level, "main.c", "-o", "main"]).current_dir(&dir).output().unwrap();
+        assert!(compile.status.success(), "{}", String::from_utf8_lossy(&compile.stderr));
+        let run = Command::new(dir.join("main")).output().unwrap();
+        assert_eq!((String::from_utf8_lossy(&run.stdout).as_ref(), run.status.code()), (OUTPUT, Some(0)), "{}", level);
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn ir_input_is_refused() {
+    let dir = scratch("ir");
+    std::fs::write(dir.join("main.ir"), "function main() -> i64 {\nbb0:\n    ret 0\n}\n").unwrap();
+    let build = galvan(&["build", "main.ir", "--emit=c", "-o", "main.c"], &dir);
+    assert_eq!(build.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&build.stderr).contains("The C backend needs Galvan source, not IR"));
+    let _ = std::fs::remove_dir_all(&dir);
+}
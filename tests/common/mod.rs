@@ -0,0 +1,35 @@
+#![allow(dead_code)]
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+// What the tests that drive the galvan binary share. Every tests/*.rs is a crate of its own
+// with `mod common;`, and not all of them use everything in here.
+
+/// Runs galvan in `dir`
+pub fn galvan(args: &[&str], dir: &Path) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_galvan")).args(args).current_dir(dir).output().unwrap()
+}
+
+/// A fresh directory in the temp dir, named after the test file so they don't run into each other
+pub fn scratch(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("galvan-{}-{}-{}", env!("CARGO_CRATE_NAME"), std::process::id(), name));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Whether `tool` is installed, tests that need one skip themselves without it
+pub fn have(tool: &str) -> bool {
+    Command::new(tool).arg("--version").output().is_ok_and(|output| output.status.success())
+}
+
+pub fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).to_string()
+}
+
+/// Runs galvan in `dir`, which mustn't complain, gives back stdout and the exit code
+pub fn run(args: &[&str], dir: &Path) -> (String, Option<i32>) {
+    let run = galvan(args, dir);
+    assert_eq!(String::from_utf8_lossy(&run.stderr), "", "{:?}", args);
+    (String::from_utf8_lossy(&run.stdout).to_string(), run.status.code())
+}
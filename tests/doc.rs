@@ -0,0 +1,209 @@
+use std::path::PathBuf;
+
+mod common;
+use common::{galvan, scratch, stderr};
+
+// `galvan doc` and `galvan test` on a library package written to the temp dir: the pages,
+// the links between them, the search index, and the code blocks in comments run as tests.
+
+const LIB: &str = r#"//! Arithmetic, see [`math::square`] and [util].
+//!
+//! ```
+//! let x = lib::add(1, 2);
+//! ```
+
+/// Adds two numbers, a **fast** path.
+///
+/// - works on [LIMIT]
+/// - see [the readme](https://example.com)
+///
+/// ```
+/// if (lib::add(2, 3) != 5) {
+///     call panic("wrong");
+/// }
+/// ```
+pub function add(a: i64, b: i64) {
+    return a + b;
+}
+
+/// How many <there> are
+///
+/// [Counted](https://en.wikipedia.org/wiki/Count_(mathematics)), not [run](javascript:alert(1)).
+pub const LIMIT = 3;
+
+//// Not a doc comment
+pub const NAME = "dt";
+
+/// Nobody sees this
+function hidden() {
+    return 1;
+}
+"#;
+
+const MATH: &str = r#"//! Math.
+
+/// Squares `x`.
+///
+/// ```
+/// return math::square(3) - 9;
+/// ```
+///
+/// ```no_run
+/// call math::square(4);
+/// ```
+///
+/// ```ignore
+/// not galvan at all
+/// ```
+///
+/// ```
+/// call panic("boom");
+/// ```
+///
+/// ```text
+/// not a test either
+/// ```
+#[allow(unused_variable)]
+pub function square(x: i64) {
+    return x * x;
+}
+"#;
+
+const UTIL: &str = "/// Prints it\npub extern \"C\" function puts(s: str) -> i64;\n";
+
+/// A library package with three modules
+fn package(name: &str) -> PathBuf {
+    let dir = scratch(name);
+    std::fs::create_dir_all(dir.join("src")).unwrap();
+    std::fs::write(dir.join("galvan.toml"), "[package]\nname = \"dt\"\nversion = \"0.2.1\"\nentry = \"src/lib.gv\"\n").unwrap();
+    std::fs::write(dir.join("src/lib.gv"), LIB).unwrap();
+    std::fs::write(dir.join("src/math.gv"), MATH).unwrap();
+    std::fs::write(dir.join("src/util.gv"), UTIL).unwrap();
+    dir
+}
+
+#[test]
+fn html_pages() {
+    let dir = package("html");
+    let run = galvan(&["doc"], &dir);
+    assert!(run.status.success(), "{}", String::from_utf8_lossy(&run.stderr));
+    assert!(String::from_utf8_lossy(&run.stdout).contains("Documented 5 items in 3 modules"));
+    let doc = dir.join("target/doc");
+    let read = |name: &str| std::fs::read_to_string(doc.join(name)).unwrap();
+
+    let index = read("index.html");
+    for expected in [
+        "<h1>dt 0.2.1</h1>",
+        "<a href=\"math.html#square\"><code>math::square</code></a> and <a href=\"util.html\">util</a>",
+        "<li><a href=\"math.html\"><code>math</code></a> Math.</li>",
+        "<link rel=\"stylesheet\" href=\"style.css\">",
+        "<input id=\"search\"",
+    ] {
+        assert!(index.contains(expected), "no {} in\n{}", expected, index);
+    }
+
+    let lib = read("lib.html");
+    for expected in [
+        // Return types come from seman
+        "<span class=\"gv-function\">add</span><span class=\"gv-punctuation\">(</span>a<span class=\"gv-punctuation\">:</span> <span class=\"gv-type\">i64</span>",
+        "<span class=\"gv-operator\">-&gt;</span> <span class=\"gv-type\">i64</span></code></pre>",
+        "<div class=\"item\" id=\"add\">",
+        "<p>Adds two numbers, a <strong>fast</strong> path.</p>",
+        "<li>works on <a href=\"lib.html#LIMIT\">LIMIT</a></li>",
+        "<li>see <a href=\"https://example.com\">the readme</a></li>",
+        "<span class=\"gv-keyword\">const</span> LIMIT<span class=\"gv-punctuation\">:</span> <span class=\"gv-type\">i64</span> <span class=\"gv-operator\">=</span> <span class=\"gv-number\">3</span>",
+        "<p>How many &lt;there&gt; are</p>",
+        // Parentheses in URLs are fine, URLs that run something aren't
+        "<p><a href=\"https://en.wikipedia.org/wiki/Count_(mathematics)\">Counted</a>, not [run](javascript:alert(1)).</p>",
+        "NAME<span class=\"gv-punctuation\">:</span> <span class=\"gv-type\">str</span>",
+    ] {
+        assert!(lib.contains(expected), "no {} in\n{}", expected, lib);
+    }
+    assert!(!lib.contains("hidden") && !lib.contains("Not a doc comment"), "{}", lib);
+
+    let math = read("math.html");
+    assert!(math.contains("<p>Squares <code>x</code>.</p>"), "{}", math);
+    assert!(math.contains("<pre><code>not a test either\n</code></pre>"), "{}", math);
+    let util = read("util.html");
+    assert!(util.contains("<h2>Extern functions</h2>") && util.contains("<p>Prints it</p>"), "{}", util);
+
+    let search = read("search.js");
+    assert!(search.starts_with("var searchIndex = ["), "{}", search);
+    assert!(search.contains("\"name\": \"math::square\",\n    \"kind\": \"function\",\n    \"summary\": \"Squares x.\",\n    \"href\": \"math.html#square\""), "{}", search);
+    assert!(read("style.css").contains(".gv-keyword {"));
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn markdown_pages() {
+    let dir = package("markdown");
+    let run = galvan(&["doc", "--markdown", "-o", "md"], &dir);
+    assert!(run.status.success(), "{}", String::from_utf8_lossy(&run.stderr));
+    let index = std::fs::read_to_string(dir.join("md/index.md")).unwrap();
+    assert!(index.starts_with("# dt 0.2.1\n\nArithmetic, see [`math::square`](math.md#square) and [util](util.md).\n"), "{}", index);
+    assert!(index.contains("- [`util`](util.md)\n"), "{}", index);
+    let lib = std::fs::read_to_string(dir.join("md/lib.md")).unwrap();
+    assert!(lib.contains("<a id=\"add\"></a>\n\n### `add`\n\n```galvan\npub function add(a: i64, b: i64) -> i64\n```\n"), "{}", lib);
+    assert!(lib.contains("- works on [LIMIT](lib.md#LIMIT)\n"), "{}", lib);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn doc_tests() {
+    let dir = package("test");
+    let run = galvan(&["test"], &dir);
+    let stdout = String::from_utf8_lossy(&run.stdout);
+    assert_eq!(run.status.code(), Some(1), "{}", stdout);
+    for expected in [
+        "running 6 doc tests\n",
+        "test src/lib.gv - lib (line 3) ... ok\n",
+        "test src/lib.gv - lib::add (line 12) ... ok\n",
+        "test src/math.gv - math::square (line 5) ... ok\n",
+        "test src/math.gv - math::square (line 9) ... ok\n",
+        "test src/math.gv - math::square (line 13) ... ignored\n",
+        "test src/math.gv - math::square (line 17) ... FAILED\n",
+        "---- src/math.gv - math::square (line 17) ----\npanic: boom\nexit code 101\n",
+        "test result: FAILED. 4 passed; 1 failed; 1 ignored\n",
+    ] {
+        assert!(stdout.contains(expected), "no {} in\n{}", expected, stdout);
+    }
+    assert!(String::from_utf8_lossy(&run.stderr).contains("error: 1 doc test failed"));
+
+    // A block that doesn't compile fails with the compiler's error
+    std::fs::write(dir.join("src/math.gv"), MATH.replace("call panic(\"boom\");", "return missing();")).unwrap();
+    let run = galvan(&["test"], &dir);
+    let stdout = String::from_utf8_lossy(&run.stdout);
+    assert!(stdout.contains("error: Unknown function 'missing'"), "{}", stdout);
+
+    std::fs::write(dir.join("src/math.gv"), MATH.replace("call panic(\"boom\");", "return 0;")).unwrap();
+    let run = galvan(&["test"], &dir);
+    let stdout = String::from_utf8_lossy(&run.stdout);
+    assert!(run.status.success(), "{}", stdout);
+    assert!(stdout.ends_with("test result: ok. 5 passed; 0 failed; 1 ignored\n"), "{}", stdout);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn lone_file() {
+    let dir = scratch("file");
+    std::fs::write(dir.join("one.gv"), "/// Doubles\n///\n/// ```\n/// return one::twice(0);\n/// ```\npub function twice(x: i64) {\n    return x * 2;\n}\n").unwrap();
+    let run = galvan(&["doc", "one.gv"], &dir);
+    assert!(run.status.success(), "{}", String::from_utf8_lossy(&run.stderr));
+    assert!(std::fs::read_to_string(dir.join("target/doc/one.html")).unwrap().contains("<p>Doubles</p>"));
+    let run = galvan(&["test", "one.gv"], &dir);
+    assert!(run.status.success(), "{}", String::from_utf8_lossy(&run.stdout));
+    assert!(String::from_utf8_lossy(&run.stdout).contains("test one.gv - one::twice (line 3) ... ok"));
+
+    // The modules it imports get pages, links and doc tests too, links to nothing a warning
+    std::fs::write(dir.join("main.gv"), "//! Uses [one::twice], not [one::thrice].\nimport one;\n\nreturn one::twice(0);\n").unwrap();
+    let run = galvan(&["doc", "main.gv", "-o", "imported"], &dir);
+    assert!(run.status.success(), "{}", String::from_utf8_lossy(&run.stderr));
+    assert!(String::from_utf8_lossy(&run.stdout).contains("Documented 1 item in 2 modules"));
+    assert_eq!(stderr(&run), "warning: [one::thrice] on line 1 of main.gv isn't an item or module, it stays plain text\n");
+    let main = std::fs::read_to_string(dir.join("imported/main.html")).unwrap();
+    assert!(main.contains("<p>Uses <a href=\"one.html#twice\">one::twice</a>, not [one::thrice].</p>"), "{}", main);
+    assert!(std::fs::read_to_string(dir.join("imported/one.html")).unwrap().contains("<p>Doubles</p>"));
+    let run = galvan(&["test", "main.gv"], &dir);
+    assert!(String::from_utf8_lossy(&run.stdout).contains("test one.gv - one::twice (line 3) ... ok"), "{}", String::from_utf8_lossy(&run.stdout));
+    let _ = std::fs::remove_dir_all(&dir);
+}
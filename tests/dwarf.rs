@@ -0,0 +1,124 @@
+use std::path::Path;
+use std::process::Command;
+
+mod common;
+use common::{galvan, have, scratch};
+
+// -g through binutils: readelf has to find every statement in the line table and every
+// parameter and local in .debug_info at the frame offset the code really uses (the frame base
+// is the CFA, rbp + 16), and objdump -S has to show the source next to the code. Once for the
+// built-in ELF writer and once through the system assembler and linker.
+
+const SOURCE: &str = r#"function scale(a, b) {
+    let product = a * b;
+    let shifted = product + 7;
+    return shifted;
+}
+
+let total = scale(6, 7);
+call print(total);
+return 0;
+"#;
+
+fn tool(name: &str, args: &[&str], dir: &Path) -> String {
+    let output = Command::new(name).args(args).current_dir(dir).output().unwrap();
+    assert!(output.status.success(), "{} {:?}: {}", name, args, String::from_utf8_lossy(&output.stderr));
+    String::from_utf8_lossy(&output.stdout).to_string()
+}
+
+/// Source line and address of every row of the line table, without the end of sequence ones
+fn line_table(exe: &str, dir: &Path) -> Vec<(u32, u64)> {
+    tool("readelf", &["--debug-dump=decodedline", exe], dir).lines().filter(|line| line.starts_with("main.gv ")).filter_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        Some((fields[1].parse().ok()?, u64::from_str_radix(fields[2].trim_start_matches("0x"), 16).unwrap()))
+    }).collect()
+}
+
+/// Every parameter and variable of a function with its DW_OP_fbreg offset
+fn variables(exe: &str, function: &str, dir: &Path) -> Vec<(String, String, i64)> {
+    let info = tool("readelf", &["--debug-dump=info", exe], dir);
+    let mut variables = vec![];
+    let (mut inside, mut tag, mut name) = (false, String::new(), String::new());
+    for line in info.lines() {
+        if let Some((_, entry)) = line.split_once("Abbrev Number: ") {
+            if entry.contains("DW_TAG_subprogram") {inside = false}
+            tag = entry.split_once('(').map_or("", |(_, tag)| tag.trim_end_matches(')')).to_string();
+        } else if let Some((_, value)) = line.split_once("DW_AT_name        : ") {
+            name = value.trim().to_string();
+            if tag == "DW_TAG_subprogram" {inside = name == function}
+        } else if let Some((_, offset)) = line.split_once("(DW_OP_fbreg: ") && inside {
+            variables.push((tag.clone(), name.clone(), offset.trim_end_matches(')').parse().unwrap()));
+        }
+    }
+    variables
+}
+
+fn check(exe: &str, dir: &Path) {
+    // Every statement, in order, starting at the function's first instruction
+    let lines = line_table(exe, dir);
+    assert_eq!(lines.iter().map(|(line, _)| *line).collect::<Vec<u32>>(), [1, 2, 3, 4, 7, 8, 9], "{:?}", lines);
+    assert!(lines.windows(2).all(|pair| pair[0].1 < pair[1].1), "{:?}", lines);
+    let symbols = tool("readelf", &["-sW", exe], dir);
+    let scale = symbols.lines().find(|line| line.ends_with(" _gv_scale")).unwrap().split_whitespace().nth(1).unwrap();
+    assert_eq!(lines[0].1, u64::from_str_radix(scale, 16).unwrap());
+
+    let expected = [
+        ("DW_TAG_formal_parameter", "a", -24),
+        ("DW_TAG_formal_parameter", "b", -32),
+        ("DW_TAG_variable", "product", -40),
+        ("DW_TAG_variable", "shifted", -48),
+    ];
+    let found = variables(exe, "scale", dir);
+    assert_eq!(found.iter().map(|(tag, name, offset)| (tag.as_str(), name.as_str(), *offset)).collect::<Vec<_>>(), expected);
+    assert_eq!(variables(exe, "main", dir), [("DW_TAG_variable".to_string(), "total".to_string(), -24)]);
+
+    // The source next to its code, and the code really keeps the variables there: the
+    // parameters get stored on entry, each let stores its variable at the end of its line
+    let disassembly = tool("objdump", &["-S", exe], dir);
+    let function = disassembly.split("\n\n").find(|part| part.contains("<_gv_scale>:")).unwrap();
+    let mut statements: Vec<(&str, Vec<&str>)> = vec![];
+    for line in function.lines().skip(1) {
+        if line.starts_with("  ") && line.contains(":\t") {
+            statements.last_mut().unwrap().1.push(line);
+        } else {
+            statements.push((line.trim(), vec![]));
+        }
+    }
+    let source: Vec<&str> = statements.iter().map(|(source, _)| *source).collect();
+    assert_eq!(source, ["function scale(a, b) {", "let product = a * b;", "let shifted = product + 7;", "return shifted;"]);
+    // fbreg + 16 is the offset from rbp
+    let store = |code: &[&str], register: &str, offset: i64| {
+        let target = format!("{},-{:#x}(%rbp)", register, -(offset + 16));
+        assert!(code.iter().any(|line| line.ends_with(&target)), "no {} in\n{}", target, code.join("\n"));
+    };
+    store(&statements[0].1, "%rdi", -24);
+    store(&statements[0].1, "%rsi", -32);
+    let last_store = |code: &[&str]| code.last().unwrap().rsplit_once(',').unwrap().1.to_string();
+    assert_eq!(last_store(&statements[1].1), format!("-{:#x}(%rbp)", 40 - 16));
+    assert_eq!(last_store(&statements[2].1), format!("-{:#x}(%rbp)", 48 - 16));
+}
+
+#[test]
+fn readelf_and_objdump() {
+    if !have("readelf") || !have("objdump") {
+        eprintln!("no binutils, skipping");
+        return;
+    }
+    let dir = scratch("binutils");
+    std::fs::write(dir.join("main.gv"), SOURCE).unwrap();
+    let build = galvan(&["build", "main.gv", "-g", "--emit=exe", "-o", "main"], &dir);
+    assert!(build.status.success(), "{}", String::from_utf8_lossy(&build.stderr));
+    let run = Command::new(dir.join("main")).output().unwrap();
+    assert_eq!(String::from_utf8_lossy(&run.stdout), "49\n");
+    check("main", &dir);
+
+    // Same from the assembly, with the assembler working out the addresses
+    if have("as") && have("ld") {
+        let build = galvan(&["build", "main.gv", "-g", "--emit=asm", "-o", "main.s"], &dir);
+        assert!(build.status.success(), "{}", String::from_utf8_lossy(&build.stderr));
+        tool("as", &["main.s", "-o", "main.o"], &dir);
+        tool("ld", &["main.o", "-o", "linked"], &dir);
+        check("linked", &dir);
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+}
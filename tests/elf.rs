@@ -0,0 +1,78 @@
+use std::path::Path;
+use std::process::{Command, Output};
+
+mod common;
+use common::{galvan, have, scratch};
+
+// The built-in x86-64 encoder and ELF writer: --emit=exe has to run as it is, and
+// --emit=obj has to link with the system's C compiler, both on their own and with libc.
+
+const SOURCE: &str = r#"function fib(n) {
+    if (n < 2) {
+        return n;
+    }
+    return fib(n - 1) + fib(n - 2);
+}
+
+function many(a, b, c, d, e, f, g, h) {
+    return a - b + c - d + e - f + g * h;
+}
+
+let i = 0;
+let total = 0;
+while (i < 10) {
+    let total = total + fib(i);
+    let i = i + 1;
+}
+call print("fib", total, many(1, 2, 3, 4, 5, 6, 7, 8));
+call print(0 - 1234567890123, 100 / 8);
+return total - 80;
+"#;
+
+const OUTPUT: &str = "fib 88 53\n-1234567890123 12\n";
+
+fn run(exe: &Path) -> Output {
+    Command::new(exe).output().unwrap()
+}
+
+#[test]
+fn executable_runs() {
+    let dir = scratch("exe");
+    std::fs::write(dir.join("main.gv"), SOURCE).unwrap();
+    for args in [&["-o", "main"][..], &["-O2", "-o", "main"], &["-g", "-o", "main"]] {
+        let build = galvan(&[&["build", "main.gv", "--emit=exe"][..], args].concat(), &dir);
+        assert!(build.status.success(), "{}", String::from_utf8_lossy(&build.stderr));
+        let output = run(&dir.join("main"));
+        assert_eq!(String::from_utf8_lossy(&output.stdout), OUTPUT, "{:?}", args);
+        assert_eq!(output.status.code(), Some(8), "{:?}", args);
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn object_links_with_cc() {
+    if !have("cc") {
+        eprintln!("no cc, skipping");
+        return;
+    }
+    let dir = scratch("obj");
+
+    // Without C it starts at _start, so no libc
+    std::fs::write(dir.join("main.gv"), SOURCE).unwrap();
+    let build = galvan(&["build", "main.gv", "--emit=obj", "-o", "main.o"], &dir);
+    assert!(build.status.success(), "{}", String::from_utf8_lossy(&build.stderr));
+    let link = Command::new("cc").args(["-nostdlib", "-static", "main.o", "-o", "main"]).current_dir(&dir).output().unwrap();
+    assert!(link.status.success(), "{}", String::from_utf8_lossy(&link.stderr));
+    let output = run(&dir.join("main"));
+    assert_eq!((String::from_utf8_lossy(&output.stdout).as_ref(), output.status.code()), (OUTPUT, Some(8)));
+
+    // With C it's main, and libc's output and print's come out in order
+    std::fs::write(dir.join("c.gv"), "extern \"C\" function puts(s: str) -> i32;\nextern \"C\" function abs(x: i32) -> i32;\n\ncall puts(\"from libc\");\ncall print(abs(0 - 5), 7 * 6);\nreturn 4;\n").unwrap();
+    let build = galvan(&["build", "c.gv", "--emit=obj", "-o", "c.o"], &dir);
+    assert!(build.status.success(), "{}", String::from_utf8_lossy(&build.stderr));
+    let link = Command::new("cc").args(["c.o", "-o", "c"]).current_dir(&dir).output().unwrap();
+    assert!(link.status.success(), "{}", String::from_utf8_lossy(&link.stderr));
+    let output = run(&dir.join("c"));
+    assert_eq!((String::from_utf8_lossy(&output.stdout).as_ref(), output.status.code()), ("from libc\n5 42\n", Some(4)));
+    let _ = std::fs::remove_dir_all(&dir);
+}
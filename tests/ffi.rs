@@ -0,0 +1,102 @@
+use std::process::Command;
+
+mod common;
+use common::{galvan, have, scratch};
+
+// C interop both ways: a C main() calling Galvan `export` functions, linked against the
+// object, the assembly and the C backend's output, and variadic printf calls with more
+// arguments than fit in registers. Without a C compiler there's nothing to check.
+
+const LIBRARY: &str = r#"extern "C" function printf(format: str, ...) -> i32;
+
+export function square(x) {
+    return x * x;
+}
+
+export function galvan_min(a, b) {
+    if (a < b) {
+        return a;
+    }
+    return b;
+}
+
+export function greeting(n) {
+    call printf("%s %ld %d|\n", "from galvan", n, 0 - 3);
+    return "hi";
+}
+"#;
+
+const CALLER: &str = r#"#include <stdint.h>
+#include <stdio.h>
+
+int64_t square(int64_t);
+int64_t galvan_min(int64_t, int64_t);
+const char *greeting(int64_t);
+
+int main(void) {
+    printf("%ld %ld\n", (long)square(12), (long)galvan_min(-5, 3));
+    fflush(stdout);
+    printf("%s\n", greeting(9000000000));
+    return (int)square(3);
+}
+"#;
+
+const PROGRAM: &str = r#"extern "C" function printf(format: str, ...) -> i32;
+
+let count = printf("%ld %ld %ld %ld %ld %ld %s %c\n", 1, 2, 3, 4, 5, 0 - 6, "seven", 56);
+call print("printed", count);
+let count = printf("no arguments\n");
+return count;
+"#;
+
+#[test]
+fn c_calls_exports() {
+    if !have("cc") {
+        eprintln!("no cc, skipping");
+        return;
+    }
+    let dir = scratch("export");
+    std::fs::write(dir.join("lib.gv"), LIBRARY).unwrap();
+    std::fs::write(dir.join("main.c"), CALLER).unwrap();
+    for (emit, file) in [("obj", "lib.o"), ("asm", "lib.s"), ("c", "lib.c")] {
+        let build = galvan(&["build", "lib.gv", &format!("--emit={}", emit), "-o", file], &dir);
+        assert!(build.status.success(), "{}", String::from_utf8_lossy(&build.stderr));
+        let link = Command::new("cc").args(["-std=c99", "-Wall", "-Werror", "main.c", file, "-o", "main"]).current_dir(&dir).output().unwrap();
+        assert!(link.status.success(), "{}: {}", emit, String::from_utf8_lossy(&link.stderr));
+        let run = Command::new(dir.join("main")).output().unwrap();
+        assert_eq!(String::from_utf8_lossy(&run.stdout), "144 -5\nfrom galvan 9000000000 -3|\nhi\n", "{}", emit);
+        assert_eq!(run.status.code(), Some(9), "{}", emit);
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn variadic_printf() {
+    if !have("cc") {
+        eprintln!("no cc, skipping");
+        return;
+    }
+    let dir = scratch("printf");
+    std::fs::write(dir.join("main.gv"), PROGRAM).unwrap();
+    for (emit, file) in [("obj", "main.o"), ("asm", "main.s"), ("c", "main.c")] {
+        let build = galvan(&["build", "main.gv", &format!("--emit={}", emit), "-o", file], &dir);
+        assert!(build.status.success(), "{}", String::from_utf8_lossy(&build.stderr));
+        let link = Command::new("cc").args([file, "-o", "main"]).current_dir(&dir).output().unwrap();
+        assert!(link.status.success(), "{}: {}", emit, String::from_utf8_lossy(&link.stderr));
+        let run = Command::new(dir.join("main")).output().unwrap();
+        assert_eq!(String::from_utf8_lossy(&run.stdout), "1 2 3 4 5 -6 seven 8\nprinted 21\nno arguments\n", "{}", emit);
+        assert_eq!(run.status.code(), Some(13), "{}", emit);
+    }
+
+    // Neither the interpreter nor the VM can call into C
+    let cases: [(&[&str], &str); 2] = [
+        (&["run", "main.gv"], "error: runtime error: Can't call C function 'printf' in the interpreter, compile the program and link it instead at position 3:1 in main.gv\n    in main\n"),
+        (&["run", "--vm", "main.gv"], "error: 'main' calls C function 'printf', which can't run on the VM, compile the program and link it instead\n"),
+    ];
+    for (args, error) in cases {
+        let run = galvan(args, &dir);
+        assert_eq!(run.status.code(), Some(1), "{:?}", args);
+        assert_eq!(String::from_utf8_lossy(&run.stderr), error);
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,85 @@
+use std::path::Path;
+
+mod common;
+use common::{galvan, scratch};
+
+// Snapshots of the formatter. Every tests/fmt/<name>.gv gets formatted (a copy of it), what
+// comes out has to match tests/fmt/<name>.expected, and formatting that again can't change
+// anything. GALVAN_BLESS=1 writes the .expected files instead.
+
+#[test]
+fn formatter_snapshots() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fmt");
+    let bless = std::env::var("GALVAN_BLESS").is_ok_and(|bless| bless == "1");
+    let mut inputs: Vec<_> = std::fs::read_dir(&dir).unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "gv")).collect();
+    inputs.sort();
+    assert!(!inputs.is_empty(), "no snapshots in {}", dir.display());
+
+    let scratch = scratch("snapshots");
+    let mut failed = vec![];
+    for input in inputs {
+        let name = input.file_stem().unwrap().to_string_lossy().to_string();
+        let copy = scratch.join(format!("{}.gv", name));
+        std::fs::copy(&input, &copy).unwrap();
+
+        let run = galvan(&["fmt", copy.to_str().unwrap()], &scratch);
+        assert!(run.status.success(), "formatting {} failed:\n{}", input.display(), String::from_utf8_lossy(&run.stderr));
+        let formatted = std::fs::read_to_string(&copy).unwrap();
+
+        let again = galvan(&["fmt", "--check", copy.to_str().unwrap()], &scratch);
+        assert!(again.status.success(), "formatting {} twice changes it:\n{}", input.display(), String::from_utf8_lossy(&again.stdout));
+
+        let expected = dir.join(format!("{}.expected", name));
+        if bless {
+            std::fs::write(&expected, &formatted).unwrap();
+        } else if std::fs::read_to_string(&expected).ok().as_deref() != Some(formatted.as_str()) {
+            eprintln!("--- {} is now:\n{}", expected.display(), formatted);
+            failed.push(name);
+        }
+    }
+    let _ = std::fs::remove_dir_all(&scratch);
+    assert!(failed.is_empty(), "snapshots changed: {} (GALVAN_BLESS=1 updates them)", failed.join(", "));
+}
+
+#[test]
+fn check_changes_nothing() {
+    let dir = scratch("check");
+    let source = "let x=1;\n";
+    std::fs::write(dir.join("main.gv"), source).unwrap();
+
+    let run = galvan(&["fmt", "--check", "main.gv"], &dir);
+    assert_eq!(run.status.code(), Some(1));
+    assert_eq!(String::from_utf8_lossy(&run.stdout), "main.gv isn't formatted (from line 1 on)\n");
+    assert_eq!(std::fs::read_to_string(dir.join("main.gv")).unwrap(), source);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn package_style() {
+    let dir = scratch("package");
+    std::fs::create_dir_all(dir.join("src/drivers")).unwrap();
+    std::fs::write(dir.join("galvan.toml"), "[package]\nname = \"styled\"\nversion = \"0.1.0\"\n\n[fmt]\nwidth = 24\nindent = 2\n").unwrap();
+    std::fs::write(dir.join("src/main.gv"), "import drivers::led;\nif (1 == 1) {call led::set(1, 2, 3);}\n").unwrap();
+    std::fs::write(dir.join("src/drivers/led.gv"), "pub function set(a, b, c) {return a;}\n").unwrap();
+
+    let run = galvan(&["fmt"], &dir);
+    assert!(run.status.success(), "{}", String::from_utf8_lossy(&run.stderr));
+    assert_eq!(std::fs::read_to_string(dir.join("src/main.gv")).unwrap(), "import drivers::led;\nif (1 == 1) {\n  call led::set(\n    1,\n    2,\n    3\n  );\n}\n");
+    assert_eq!(std::fs::read_to_string(dir.join("src/drivers/led.gv")).unwrap(), "pub function set(\n  a,\n  b,\n  c\n) {\n  return a;\n}\n");
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn broken_files_stay_untouched() {
+    let dir = scratch("broken");
+    for (source, error) in [("let x = ;\n", "Expected expression"), ("let x = 1 @;\n", "Unexpected '@'"), ("function f() {\n    let y = 2;\n", "Expected FunctionClosingBracket, not EOF")] {
+        std::fs::write(dir.join("main.gv"), source).unwrap();
+        let run = galvan(&["fmt", "main.gv"], &dir);
+        assert_eq!(run.status.code(), Some(1));
+        assert!(String::from_utf8_lossy(&run.stderr).contains(error), "{}", String::from_utf8_lossy(&run.stderr));
+        assert_eq!(std::fs::read_to_string(dir.join("main.gv")).unwrap(), source);
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,179 @@
+use std::path::Path;
+use std::process::Command;
+
+mod common;
+use common::{galvan, have, scratch, stderr};
+
+// Bare metal programs: what #![no_std] and --freestanding refuse, the linker script --emit=ld
+// writes, -T scripts, #[section] placement and the #[interrupt] wrapper. Nothing here can
+// run on the host, so it's all checked with readelf and the assembly.
+
+const BLINKY: &str = r#"#![no_std]
+
+#[panic_handler]
+function on_panic(message: str) {
+    let spin = 1;
+}
+
+#[interrupt]
+function timer() {
+    let ticks = 1;
+}
+
+#[section(".vectors")]
+function vectors() {
+    return 0;
+}
+
+let x = 1;
+"#;
+
+const SCRIPT: &str = r#"/* Generated by galvan */
+ENTRY(_start)
+
+MEMORY
+{
+    FLASH (rx) : ORIGIN = 0x8000000, LENGTH = 512K
+    RAM (rwx) : ORIGIN = 0x20000000, LENGTH = 128K
+}
+
+SECTIONS
+{
+    .vectors : { KEEP(*(.vectors)) } > FLASH
+    .text : { *(.text .text.*) } > FLASH
+    .rodata : { *(.rodata .rodata.*) } > FLASH
+    .stack (NOLOAD) : { . = ALIGN(16); . = . + 0x4000; __stack_top = .; } > RAM
+}
+"#;
+
+/// Name and address of every section in `elf` that has one
+fn sections(elf: &Path) -> Vec<(String, u64)> {
+    let readelf = Command::new("readelf").arg("-SW").arg(elf).output().unwrap();
+    assert!(readelf.status.success(), "{}", stderr(&readelf));
+    String::from_utf8_lossy(&readelf.stdout).lines().filter_map(|line| {
+        let mut fields = line.split(']').nth(1)?.split_whitespace();
+        let name = fields.next()?.to_string();
+        let address = u64::from_str_radix(fields.nth(1)?, 16).ok()?;
+        (address != 0).then_some((name, address))
+    }).collect()
+}
+
+#[test]
+fn needs_no_os() {
+    let dir = scratch("no_std");
+    let cases = [
+        ("#![no_std]\ncall print(1);\n", &[][..], "error: 'print' needs an operating system, which a freestanding program doesn't have at position 2:1 in main.gv\n"),
+        ("let x = 1;\nlet s = \"a\";\nif (x == 1) {\n    call println(s);\n}\n", &["--freestanding"][..],
+            "error: 'std::prelude::println' needs an operating system (it ends up calling '__sys_write_str'), which a freestanding program doesn't have at position 3:1 in main.gv\n"),
+        ("#![no_std]\nlet x = 1;\ncall panic(\"no\");\n", &[][..],
+            "error: 'std::prelude::panic' can panic, a freestanding program needs a #[panic_handler] function for that at position 3:1 in main.gv\n"),
+        ("#![no_std]\n#[interrupt]\nfunction t() {\n    let x = 1;\n}\ncall t();\n", &[][..],
+            "error: 't' is an interrupt handler, only the hardware gets to call it at position 6:1 in main.gv\n"),
+    ];
+    for (source, flags, error) in cases {
+        std::fs::write(dir.join("main.gv"), source).unwrap();
+        let build = galvan(&[&["build", "main.gv", "--emit=exe", "-o", "main"][..], flags].concat(), &dir);
+        assert_eq!((build.status.code(), stderr(&build)), (Some(1), error.to_string()), "{}", source);
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn linker_script() {
+    let dir = scratch("ld");
+    std::fs::write(dir.join("blinky.gv"), BLINKY).unwrap();
+    let build = galvan(&["build", "blinky.gv", "--emit=ld", "-o", "blinky.ld"], &dir);
+    assert!(build.status.success(), "{}", stderr(&build));
+    assert_eq!(std::fs::read_to_string(dir.join("blinky.ld")).unwrap(), SCRIPT);
+
+    // Code in the first region, the stack in the last
+    let build = galvan(&["build", "blinky.gv", "--emit=ld", "--memory=ROM=0x1000:64K", "--memory=SRAM=0x80000:8K", "-o", "memory.ld"], &dir);
+    assert!(build.status.success(), "{}", stderr(&build));
+    let script = std::fs::read_to_string(dir.join("memory.ld")).unwrap();
+    assert!(script.contains("    ROM (rx) : ORIGIN = 0x1000, LENGTH = 64K\n    SRAM (rwx) : ORIGIN = 0x80000, LENGTH = 8K\n}"), "{}", script);
+    assert!(script.contains(".text : { *(.text .text.*) } > ROM\n"), "{}", script);
+    assert!(script.contains("__stack_top = .; } > SRAM\n"), "{}", script);
+
+    // GNU ld takes the same script and lays it out the same way
+    if !have("ld") || !have("readelf") {
+        eprintln!("no ld or readelf, skipping");
+        let _ = std::fs::remove_dir_all(&dir);
+        return;
+    }
+    let build = galvan(&["build", "blinky.gv", "--emit=obj", "-o", "blinky.o"], &dir);
+    assert!(build.status.success(), "{}", stderr(&build));
+    let link = Command::new("ld").args(["-T", "blinky.ld", "blinky.o", "-o", "gnu"]).current_dir(&dir).output().unwrap();
+    assert!(link.status.success(), "{}", stderr(&link));
+    let build = galvan(&["build", "blinky.gv", "--emit=exe", "-o", "galvan"], &dir);
+    assert!(build.status.success(), "{}", stderr(&build));
+    for exe in ["gnu", "galvan"] {
+        let sections = sections(&dir.join(exe));
+        assert_eq!(sections[..2], [(".vectors".to_string(), 0x8000000), (".text".to_string(), 0x8000010)], "{}", exe);
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn custom_script() {
+    let dir = scratch("custom");
+    std::fs::write(dir.join("blinky.gv"), BLINKY).unwrap();
+    std::fs::write(dir.join("my.ld"), "ENTRY(_start)\nMEMORY { ROM (rx) : ORIGIN = 0x400000, LENGTH = 1M\n RAM (rwx) : ORIGIN = 0x600000, LENGTH = 64K }\n\
+        SECTIONS {\n .text : { KEEP(*(.vectors)) *(.text) } > ROM\n .rodata : { *(.rodata) } > ROM\n\
+        .bss (NOLOAD) : { . = ALIGN(16); . = . + 0x1000; __stack_top = .; } > RAM\n}\n").unwrap();
+    std::fs::write(dir.join("region.ld"), "SECTIONS { .text : { *(.text) } > NOWHERE }\n").unwrap();
+    std::fs::write(dir.join("comma.ld"), "MEMORY { ROM : ORIGIN = 0x400000 LENGTH = 1M }\n").unwrap();
+
+    for (script, error) in [
+        ("region.ld", "error: Section '.text' goes in memory region 'NOWHERE', which isn't in MEMORY\n"),
+        ("comma.ld", "error: Expected ',', not 'LENGTH' on line 1 of the linker script (comma.ld)\n"),
+    ] {
+        let build = galvan(&["build", "blinky.gv", "-T", script, "--emit=exe", "-o", "blinky"], &dir);
+        assert_eq!((build.status.code(), stderr(&build)), (Some(1), error.to_string()));
+    }
+
+    let build = galvan(&["build", "blinky.gv", "-T", "my.ld", "--emit=exe", "-o", "blinky"], &dir);
+    assert!(build.status.success(), "{}", stderr(&build));
+    if !have("readelf") {
+        eprintln!("no readelf, skipping");
+        let _ = std::fs::remove_dir_all(&dir);
+        return;
+    }
+    // The vectors come first in .text, so the entry point is right after them
+    assert_eq!(sections(&dir.join("blinky"))[..2], [(".text".to_string(), 0x400000), (".bss".to_string(), 0x600000)]);
+    let header = Command::new("readelf").arg("-h").arg(dir.join("blinky")).output().unwrap();
+    assert!(String::from_utf8_lossy(&header.stdout).contains("Entry point address:               0x400010\n"));
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn interrupt_wrapper() {
+    let dir = scratch("interrupt");
+    std::fs::write(dir.join("blinky.gv"), BLINKY).unwrap();
+    let build = galvan(&["build", "blinky.gv", "--emit=asm", "--entry=reset", "-o", "blinky.s"], &dir);
+    assert!(build.status.success(), "{}", stderr(&build));
+    let asm = std::fs::read_to_string(dir.join("blinky.s")).unwrap();
+    assert!(asm.starts_with(".globl reset\n.globl timer\n"), "{}", asm);
+    assert!(asm.contains("reset:\n    leaq __stack_top(%rip), %rsp\n    call _gv_main\n.Lreset_hang:\n    jmp .Lreset_hang\n"), "{}", asm);
+
+    // Every register saved around the call, in reverse on the way out, and iretq
+    let registers = ["rax", "rcx", "rdx", "rbx", "rbp", "rsi", "rdi", "r8", "r9", "r10", "r11", "r12", "r13", "r14", "r15"];
+    let mut wrapper = String::from("timer:\n");
+    for register in registers {
+        wrapper += &format!("    pushq %{}\n", register);
+    }
+    wrapper += "    cld\n    call _gv_timer\n";
+    for register in registers.iter().rev() {
+        wrapper += &format!("    popq %{}\n", register);
+    }
+    wrapper += "    iretq\n";
+    assert!(asm.contains(&wrapper), "{}", asm);
+    assert!(asm.contains(".section .vectors,\"ax\",@progbits\n\n_gv_vectors:\n"), "{}", asm);
+
+    // The C backend leaves it to the C compiler
+    let build = galvan(&["build", "blinky.gv", "--emit=c", "-o", "blinky.c"], &dir);
+    assert!(build.status.success(), "{}", stderr(&build));
+    let c = std::fs::read_to_string(dir.join("blinky.c")).unwrap();
+    assert!(c.contains("__attribute__((interrupt"), "{}", c);
+    assert!(c.contains("__attribute__((section(\".vectors\")))"), "{}", c);
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,79 @@
+use std::path::Path;
+
+mod common;
+use common::{galvan, scratch};
+
+// `galvan highlight` and `galvan grammar`. editors/galvan.tmLanguage.json is checked in for
+// editors to pick up, it has to be what `galvan grammar` writes (GALVAN_BLESS=1 rewrites it).
+
+const SOURCE: &str = "#[interrupt]\nfunction tick(count: i64, name: str) {\n    // Comments stay\n    let s = \"a<b \\\"q\\\"\";\n    return count => 10 & io::str;\n}\n";
+
+#[test]
+fn html_page() {
+    let dir = scratch("html");
+    std::fs::write(dir.join("main.gv"), SOURCE).unwrap();
+    let run = galvan(&["highlight", "main.gv", "--html"], &dir);
+    assert!(run.status.success(), "{}", String::from_utf8_lossy(&run.stderr));
+    let page = String::from_utf8_lossy(&run.stdout);
+    assert!(page.starts_with("<!DOCTYPE html>") && page.contains(".gv-keyword {"), "{}", page);
+    for expected in [
+        "<span class=\"gv-attribute\">#[interrupt]</span>",
+        "<span class=\"gv-keyword\">function</span> <span class=\"gv-function\">tick</span>",
+        "count<span class=\"gv-punctuation\">:</span> <span class=\"gv-type\">i64</span>",
+        "<span class=\"gv-comment\">// Comments stay</span>",
+        "<span class=\"gv-string\">&quot;a&lt;b \\&quot;q\\&quot;&quot;</span>",
+        // `=>` is one operator, `&` is something the lexer skips, `io::str` isn't a type
+        "<span class=\"gv-operator\">=&gt;</span> <span class=\"gv-number\">10</span> <span class=\"gv-invalid\">&amp;</span> io<span class=\"gv-punctuation\">::</span>str",
+    ] {
+        assert!(page.contains(expected), "no {} in\n{}", expected, page);
+    }
+
+    // -o writes the page instead
+    let run = galvan(&["highlight", "main.gv", "--html", "-o", "main.html"], &dir);
+    assert!(run.status.success(), "{}", String::from_utf8_lossy(&run.stderr));
+    assert_eq!(run.stdout, b"");
+    assert_eq!(std::fs::read_to_string(dir.join("main.html")).unwrap(), page);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn terminal_colors() {
+    let dir = scratch("ansi");
+    std::fs::write(dir.join("main.gv"), SOURCE).unwrap();
+    let run = galvan(&["highlight", "main.gv"], &dir);
+    assert!(run.status.success(), "{}", String::from_utf8_lossy(&run.stderr));
+    let colored = String::from_utf8_lossy(&run.stdout).to_string();
+    assert!(colored.contains("\x1b[35mfunction\x1b[0m"), "{:?}", colored);
+
+    // Without the escape sequences it's the file again
+    let mut plain = String::new();
+    let mut rest = colored.as_str();
+    while let Some(start) = rest.find('\x1b') {
+        plain.push_str(&rest[..start]);
+        rest = &rest[start + rest[start..].find('m').unwrap() + 1..];
+    }
+    plain.push_str(rest);
+    assert_eq!(plain, SOURCE);
+
+    let run = galvan(&["highlight", "missing.gv"], &dir);
+    assert_eq!(run.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&run.stderr).contains("Can't read 'missing.gv'"));
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn grammar_is_up_to_date() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let run = galvan(&["grammar"], root);
+    assert!(run.status.success(), "{}", String::from_utf8_lossy(&run.stderr));
+    let grammar = String::from_utf8_lossy(&run.stdout);
+    for expected in ["\"scopeName\": \"source.galvan\"", "|extern|export)\\\\b", "(i64|str)", "==|=>|=<|=!|!="] {
+        assert!(grammar.contains(expected), "no {} in\n{}", expected, grammar);
+    }
+
+    let path = root.join("editors/galvan.tmLanguage.json");
+    if std::env::var("GALVAN_BLESS").is_ok_and(|bless| bless == "1") {
+        std::fs::write(&path, grammar.as_bytes()).unwrap();
+    }
+    assert!(std::fs::read_to_string(&path).unwrap() == grammar, "{} is out of date, GALVAN_BLESS=1 updates it", path.display());
+}
@@ -0,0 +1,48 @@
+mod common;
+use common::{galvan, scratch};
+
+// `galvan run` with the tree-walking interpreter: stdout is only the program's, the top
+// level's return value is the exit code, and runtime errors say where they happened.
+
+#[test]
+fn output_and_exit_code() {
+    let dir = scratch("output");
+    std::fs::write(dir.join("main.gv"), "function fib(n) {\n    if (n < 2) {\n        return n;\n    }\n    return fib(n - 1) + fib(n - 2);\n}\n\nlet i = 0;\nwhile (i < 8) {\n    call print(i, fib(i));\n    let i = i + 1;\n}\nlet big = 9223372036854775807;\ncall print(big + 1, \"done\");\nreturn 300;\n").unwrap();
+    let run = galvan(&["run", "main.gv"], &dir);
+    assert_eq!(String::from_utf8_lossy(&run.stdout), "0 0\n1 1\n2 1\n3 2\n4 3\n5 5\n6 8\n7 13\n-9223372036854775808 done\n");
+    assert_eq!(String::from_utf8_lossy(&run.stderr), "");
+    // Only the low 8 bits make it to the shell, like with a compiled program
+    assert_eq!(run.status.code(), Some(300 % 256));
+
+    // --verbose has the compiler's stage prints, on stderr
+    let run = galvan(&["run", "main.gv", "--verbose"], &dir);
+    assert!(String::from_utf8_lossy(&run.stdout).starts_with("0 0\n"));
+    assert!(String::from_utf8_lossy(&run.stderr).contains("- - - INTERPRETER"));
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn runtime_errors() {
+    let dir = scratch("errors");
+    std::fs::write(dir.join("divide.gv"), "function f(x) {\n    let y = 10 / x;\n    return y;\n}\nfunction g(x) {\n    return f(x - 1);\n}\ncall print(\"before\");\ncall g(1);\n").unwrap();
+    let run = galvan(&["run", "divide.gv"], &dir);
+    assert_eq!(run.status.code(), Some(1));
+    assert_eq!(String::from_utf8_lossy(&run.stdout), "before\n");
+    assert_eq!(String::from_utf8_lossy(&run.stderr), "error: runtime error: Division by zero at position 2:5 in divide.gv\n    in f\n    in g\n    in main\n");
+
+    // Runaway recursion is an error too, with the backtrace cut short
+    std::fs::write(dir.join("recurse.gv"), "function down(n) {\n    return down(n + 1);\n}\ncall down(0);\n").unwrap();
+    let run = galvan(&["run", "recurse.gv"], &dir);
+    assert_eq!(run.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&run.stderr);
+    assert!(stderr.starts_with("error: runtime error: Stack overflow calling 'down' at position 2:5 in recurse.gv\n    in down\n"), "{}", stderr);
+    assert!(stderr.lines().count() < 30 && stderr.trim_end().ends_with("more"), "{}", stderr);
+
+    // Compile errors never get to run anything
+    std::fs::write(dir.join("unknown.gv"), "call print(\"never\");\ncall print(nope);\n").unwrap();
+    let run = galvan(&["run", "unknown.gv"], &dir);
+    assert_eq!(run.status.code(), Some(1));
+    assert_eq!(String::from_utf8_lossy(&run.stdout), "");
+    assert_eq!(String::from_utf8_lossy(&run.stderr), "error: Unknown variable 'nope' at position 2:1 in unknown.gv\n");
+    let _ = std::fs::remove_dir_all(&dir);
+}
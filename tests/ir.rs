@@ -0,0 +1,68 @@
+mod common;
+use common::{galvan, scratch};
+
+// The textual IR: what --emit=ir writes has to parse back into the same module, and IR that
+// doesn't make sense is an error with something to go on instead of a crash further down.
+
+const SOURCE: &str = r#"const LIMIT = 5;
+
+function square(x) {
+    return x * x;
+}
+
+function greet(name: str) {
+    call print("hi \"", name, "\"\n");
+    return 0;
+}
+
+let i = 0;
+let total = 0;
+while (i < LIMIT) {
+    if (i == 2) {
+        let total = total + square(i);
+    } else {
+        let total = total - 1;
+    }
+    let i = i + 1;
+}
+call greet("you");
+return total;
+"#;
+
+#[test]
+fn dump_parse_dump() {
+    let dir = scratch("roundtrip");
+    std::fs::write(dir.join("main.gv"), SOURCE).unwrap();
+    let run = galvan(&["build", "main.gv", "--emit=ir", "-o", "first.ir"], &dir);
+    assert!(run.status.success(), "{}", String::from_utf8_lossy(&run.stderr));
+    let first = std::fs::read_to_string(dir.join("first.ir")).unwrap();
+    for expected in ["string s0 = \"hi \\\"\"", "function greet(name: str) -> i64 {", "    local total: i64", "    branch %1, bb2, bb3", "    %6: i64 = call square(%5)"] {
+        assert!(first.contains(expected), "no {} in\n{}", expected, first);
+    }
+
+    let run = galvan(&["build", "first.ir", "--emit=ir", "-o", "second.ir"], &dir);
+    assert!(run.status.success(), "{}", String::from_utf8_lossy(&run.stderr));
+    assert_eq!(std::fs::read_to_string(dir.join("second.ir")).unwrap(), first);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn malformed_ir() {
+    let dir = scratch("malformed");
+    for (body, error) in [
+        ("    %0: i64 = add %1, 1\n    ret %0\n", "IR error in function 'main': bb0 instruction 0: register %1 is used but never assigned"),
+        ("    %0: i64 = copy 1\n    call nope(%0)\n    ret %0\n", "IR error in function 'main': bb0 instruction 1: call to 'nope' which doesn't exist"),
+        ("    %0: f32 = add 1, 1\n    ret %0\n", "Unknown type 'f32'"),
+        ("    %0: i64 = frob 1, 1\n    ret %0\n", "Unknown instruction 'frob' on line 3"),
+        ("    jump bb7\n", "Function 'main' jumps to bb7 which doesn't exist"),
+        ("function main(a: i64, a: i64) -> i64 {\nbb0:\n    ret 0\n}\n", "IR error in function 'main': parameter #1 is called 'a' like one before it"),
+    ] {
+        let function = if body.starts_with("function") {body.to_string()} else {format!("function main() -> i64 {{\nbb0:\n{}}}\n", body)};
+        std::fs::write(dir.join("bad.ir"), function).unwrap();
+        let run = galvan(&["build", "bad.ir", "--emit=ir", "-o", "out.ir"], &dir);
+        assert_eq!(run.status.code(), Some(1), "{}", body);
+        let stderr = String::from_utf8_lossy(&run.stderr);
+        assert_eq!(stderr.trim_end(), format!("error: {}", error));
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,121 @@
+mod common;
+use common::{galvan, scratch};
+
+// Runs `galvan lint` on files and packages written to the temp dir, and checks every line
+// it prints.
+
+const SOURCE: &str = r#"const limit = 3;
+let total = 0;
+let unused = 5;
+
+function helper(a, b, _c) {
+    let tmp = 1;
+    return a;
+    let after = 2;
+}
+
+function count(x) {
+    let total = x;
+    if (total == total) {
+        return 1;
+    }
+    while (1 == 1) {
+        let x = x + 1;
+    }
+    return 0;
+}
+
+function echo(print) {
+    return print;
+}
+
+#[allow(unused_function, naming)]
+function camelCase() {
+    return 0;
+}
+
+function search() {
+    while (1) {
+        if (limit == 2) {
+            return 1;
+        }
+    }
+    if (0) {
+        return 2;
+    } else {
+        return 3;
+    }
+    return 4;
+}
+
+let total = count(total) + search() + echo(1);
+while (0) {}
+"#;
+
+#[test]
+fn every_rule() {
+    let dir = scratch("rules");
+    std::fs::write(dir.join("main.gv"), SOURCE).unwrap();
+    let run = galvan(&["lint", "main.gv"], &dir);
+    assert!(run.status.success(), "{}", String::from_utf8_lossy(&run.stderr));
+    let expected = [
+        "warning: Constant 'limit' should be UPPER_CASE, like 'LIMIT' at position 1:7 in main.gv [naming]",
+        "warning: Variable 'unused' is never read at position 3:5 in main.gv [unused_variable]",
+        "warning: Function 'helper' is never called at position 5:10 in main.gv [unused_function]",
+        "warning: Parameter 'b' is never read at position 5:20 in main.gv [unused_parameter]",
+        "warning: Variable 'tmp' is never read at position 6:9 in main.gv [unused_variable]",
+        "warning: Unreachable code at position 8:5 in main.gv [unreachable_code]",
+        "warning: Variable 'after' is never read at position 8:9 in main.gv [unused_variable]",
+        "warning: Variable 'total' of 'count' isn't the top level's 'total', functions can't see top level variables at position 12:9 in main.gv [shadowing]",
+        "warning: 'total' is compared with itself, that's always true at position 13:5 in main.gv [self_comparison]",
+        "warning: The loop's condition is always true and there's no return in it, it never ends at position 16:5 in main.gv [constant_condition]",
+        "warning: Unreachable code at position 19:5 in main.gv [unreachable_code]",
+        "warning: Parameter 'print' has the same name as a function at position 22:15 in main.gv [shadowing]",
+        "warning: The condition is always false at position 37:5 in main.gv [constant_condition]",
+        "warning: Unreachable code at position 42:5 in main.gv [unreachable_code]",
+        "warning: The loop's condition is always false, it never runs at position 46:1 in main.gv [constant_condition]",
+    ];
+    assert_eq!(String::from_utf8_lossy(&run.stdout).lines().collect::<Vec<_>>(), expected);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn allow_attributes() {
+    let dir = scratch("allow");
+    let source = "#![allow(unused_variable)]\nlet a = 1;\n#[allow(unused_parameter, unused_function)]\nfunction f(x) {\n    return 0;\n}\n";
+    std::fs::write(dir.join("main.gv"), source).unwrap();
+    let run = galvan(&["lint", "main.gv"], &dir);
+    assert!(run.status.success(), "{}", String::from_utf8_lossy(&run.stderr));
+    assert_eq!(String::from_utf8_lossy(&run.stdout), "");
+
+    // It still builds, the attributes are only for the linter
+    let build = galvan(&["build", "main.gv", "--emit=ir", "-o", "main.ir"], &dir);
+    assert!(build.status.success(), "{}", String::from_utf8_lossy(&build.stderr));
+
+    std::fs::write(dir.join("main.gv"), "#[allow(unused_things)]\nfunction f() {\n    return 0;\n}\n").unwrap();
+    let run = galvan(&["lint", "main.gv"], &dir);
+    assert_eq!(run.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&run.stderr).contains("Unknown lint 'unused_things'"), "{}", String::from_utf8_lossy(&run.stderr));
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn package_levels() {
+    let dir = scratch("package");
+    std::fs::create_dir_all(dir.join("src")).unwrap();
+    std::fs::write(dir.join("galvan.toml"), "[package]\nname = \"levels\"\nversion = \"0.1.0\"\n\n[lint]\nunused_variable = \"deny\"\nnaming = \"allow\"\n").unwrap();
+    std::fs::write(dir.join("src/main.gv"), "let unusedValue = 1;\nfunction f(x) {\n    return 0;\n}\nreturn f(2);\n").unwrap();
+
+    let run = galvan(&["lint"], &dir);
+    assert_eq!(run.status.code(), Some(1));
+    assert_eq!(String::from_utf8_lossy(&run.stdout).lines().collect::<Vec<_>>(), [
+        "error: Variable 'unusedValue' is never read at position 1:5 in src/main.gv [unused_variable]",
+        "warning: Parameter 'x' is never read at position 2:12 in src/main.gv [unused_parameter]",
+    ]);
+    assert!(String::from_utf8_lossy(&run.stderr).contains("1 denied lint found something"));
+
+    std::fs::write(dir.join("galvan.toml"), "[package]\nname = \"levels\"\nversion = \"0.1.0\"\n\n[lint]\nunused = \"deny\"\n").unwrap();
+    let run = galvan(&["lint"], &dir);
+    assert!(String::from_utf8_lossy(&run.stderr).contains("Unknown lint 'unused'"), "{}", String::from_utf8_lossy(&run.stderr));
+    let _ = std::fs::remove_dir_all(&dir);
+}
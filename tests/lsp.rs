@@ -0,0 +1,224 @@
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+mod common;
+use common::scratch;
+
+// Drives `galvan lsp` with a scripted session over its stdin and stdout, the way an editor
+// would, and checks what comes back for every request.
+
+const URI: &str = "file:///tmp/galvan-lsp-test/main.gv";
+
+const SOURCE: &str = r#"const LIMIT = 10;
+
+function square(x) {
+    let y = x * x;
+    return y;
+}
+
+function greet(name: str) {
+    call print("hi ", name, "\n");
+    return 0;
+}
+
+let i = 0;
+while (i < LIMIT) {
+    let i = i + square(i);
+}
+call greet("you");
+"#;
+
+fn frame(body: &str) -> String {
+    format!("Content-Length: {}\r\n\r\n{}", body.len(), body)
+}
+
+fn request(id: usize, method: &str, params: &str) -> String {
+    frame(&format!(r#"{{"jsonrpc":"2.0","id":{},"method":"{}","params":{}}}"#, id, method, params))
+}
+
+fn notification(method: &str, params: &str) -> String {
+    frame(&format!(r#"{{"jsonrpc":"2.0","method":"{}","params":{}}}"#, method, params))
+}
+
+/// `{"textDocument":...,"position":...}` plus whatever else the request takes
+fn at(line: usize, character: usize, rest: &str) -> String {
+    at_in(URI, line, character, rest)
+}
+
+fn at_in(uri: &str, line: usize, character: usize, rest: &str) -> String {
+    format!(r#"{{"textDocument":{{"uri":"{}"}},"position":{{"line":{},"character":{}}}{}}}"#, uri, line, character, rest)
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Runs the session, gives back every message the server sent
+fn session(messages: &[String]) -> Vec<String> {
+    let mut server = Command::new(env!("CARGO_BIN_EXE_galvan")).arg("lsp")
+        .stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped())
+        .spawn().unwrap();
+    server.stdin.take().unwrap().write_all(messages.concat().as_bytes()).unwrap();
+    let output = server.wait_with_output().unwrap();
+    assert!(output.status.success(), "galvan lsp failed:\n{}", String::from_utf8_lossy(&output.stderr));
+
+    let mut replies = vec![];
+    let mut rest = String::from_utf8(output.stdout).unwrap();
+    while !rest.is_empty() {
+        let (header, body) = rest.split_once("\r\n\r\n").expect("no header");
+        let length: usize = header.strip_prefix("Content-Length: ").unwrap().parse().unwrap();
+        replies.push(body[..length].to_string());
+        rest = body[length..].to_string();
+    }
+    replies
+}
+
+fn reply(replies: &[String], id: usize) -> &str {
+    let start = format!(r#"{{"jsonrpc":"2.0","id":{},"#, id);
+    replies.iter().find(|reply| reply.starts_with(&start)).unwrap_or_else(|| panic!("no reply to request {}", id))
+}
+
+#[test]
+fn language_server() {
+    let replies = session(&[
+        request(1, "initialize", "{}"),
+        notification("initialized", "{}"),
+        notification("textDocument/didOpen", &format!(r#"{{"textDocument":{{"uri":"{}","languageId":"galvan","version":1,"text":"{}"}}}}"#, URI, escape(SOURCE))),
+        // `square` in the loop
+        request(2, "textDocument/definition", &at(14, 17, "")),
+        request(3, "textDocument/references", &at(2, 10, r#","context":{"includeDeclaration":false}"#)),
+        // `y`, `square` and `print`
+        request(4, "textDocument/hover", &at(3, 9, "")),
+        request(5, "textDocument/hover", &at(2, 10, "")),
+        request(6, "textDocument/hover", &at(8, 10, "")),
+        request(7, "textDocument/documentSymbol", &format!(r#"{{"textDocument":{{"uri":"{}"}}}}"#, URI)),
+        // Inside square
+        request(8, "textDocument/completion", &at(4, 4, "")),
+        request(9, "textDocument/rename", &at(12, 4, r#","newName":"n""#)),
+        request(10, "textDocument/rename", &at(12, 4, r#","newName":"LIMIT""#)),
+        notification("textDocument/didChange", &format!(r#"{{"textDocument":{{"uri":"{}","version":2}},"contentChanges":[{{"text":"let a = 1;\nlet b = a + c;\n"}}]}}"#, URI)),
+        // Missing `;` at the end of the input, the error is right after its last lexeme
+        notification("textDocument/didChange", &format!(r#"{{"textDocument":{{"uri":"{}","version":3}},"contentChanges":[{{"text":"import std::io;\nlet a = 1;\ncall print(a)\n"}}]}}"#, URI)),
+        // A block nobody closes
+        notification("textDocument/didChange", &format!(r#"{{"textDocument":{{"uri":"{}","version":4}},"contentChanges":[{{"text":"function f() {{\n    let y = 2;\n"}}]}}"#, URI)),
+        request(11, "shutdown", "null"),
+        notification("exit", "{}"),
+    ]);
+
+    assert!(reply(&replies, 1).contains(r#""renameProvider":true"#));
+    assert_eq!(replies[1], format!(r#"{{"jsonrpc":"2.0","method":"textDocument/publishDiagnostics","params":{{"uri":"{}","diagnostics":[]}}}}"#, URI));
+    assert!(reply(&replies, 2).contains(r#""range":{"start":{"line":2,"character":9},"end":{"line":2,"character":15}}"#));
+    assert_eq!(reply(&replies, 3), format!(r#"{{"jsonrpc":"2.0","id":3,"result":[{{"uri":"{}","range":{{"start":{{"line":14,"character":16}},"end":{{"line":14,"character":22}}}}}}]}}"#, URI));
+    assert!(reply(&replies, 4).contains("let y: i64"));
+    assert!(reply(&replies, 5).contains("function square(x: i64) -> i64"));
+    assert!(reply(&replies, 6).contains("function print(...) -> void"));
+
+    let symbols = reply(&replies, 7);
+    for symbol in [r#""name":"LIMIT","detail":"const LIMIT: i64","kind":14"#, r#""name":"greet","detail":"function greet(name: str) -> i64","kind":12"#, r#""name":"name","detail":"name: str","kind":13"#] {
+        assert!(symbols.contains(symbol), "{} isn't in {}", symbol, symbols);
+    }
+
+    let completion = reply(&replies, 8);
+    for item in [r#""label":"x""#, r#""label":"square""#, r#""label":"println""#, r#""label":"while","kind":14"#] {
+        assert!(completion.contains(item), "{} isn't in {}", item, completion);
+    }
+    // The top level's `i` isn't visible in square
+    assert!(!completion.contains(r#""label":"i""#));
+
+    // The `let`, the condition, both sides of the assignment and the argument
+    assert_eq!(reply(&replies, 9).matches(r#""newText":"n""#).count(), 5);
+    assert!(reply(&replies, 10).contains(r#""error":{"code":-32803,"message":"'LIMIT' is already defined"}"#));
+
+    let diagnostics: Vec<&String> = replies.iter().filter(|reply| reply.contains("publishDiagnostics")).collect();
+    let unknown = diagnostics[diagnostics.len() - 3];
+    assert!(unknown.contains(r#""range":{"start":{"line":1,"character":0},"end":{"line":1,"character":3}},"severity":1,"source":"galvan","message":"Unknown variable 'c'""#), "{}", unknown);
+    let end = diagnostics[diagnostics.len() - 2];
+    assert!(end.contains(r#""range":{"start":{"line":2,"character":13},"end":{"line":2,"character":13}},"severity":1,"source":"galvan","message":"Expected EndLine, not EOF""#), "{}", end);
+    let unclosed = diagnostics[diagnostics.len() - 1];
+    assert!(unclosed.contains(r#""range":{"start":{"line":1,"character":14},"end":{"line":1,"character":14}},"severity":1,"source":"galvan","message":"Expected FunctionClosingBracket, not EOF""#), "{}", unclosed);
+    assert_eq!(reply(&replies, 11), r#"{"jsonrpc":"2.0","id":11,"result":null}"#);
+}
+
+#[test]
+fn across_files() {
+    let dir = scratch("modules");
+    std::fs::write(dir.join("util.gv"), "pub const BASE = 10;\n\npub function twice(x) {\n    return x * 2 + BASE;\n}\n").unwrap();
+    let util = format!("file://{}", dir.join("util.gv").display());
+    let main = format!("file://{}", dir.join("main.gv").display());
+    let source = "import util;\nlet a = util::twice(util::BASE);\ncall println(\"done\");\n";
+    let replies = session(&[
+        request(1, "initialize", "{}"),
+        notification("textDocument/didOpen", &format!(r#"{{"textDocument":{{"uri":"{}","languageId":"galvan","version":1,"text":"{}"}}}}"#, main, escape(source))),
+        // `twice`, `BASE` and `println`
+        request(2, "textDocument/definition", &at_in(&main, 1, 16, "")),
+        request(3, "textDocument/references", &at_in(&main, 1, 27, r#","context":{"includeDeclaration":true}"#)),
+        request(4, "textDocument/hover", &at_in(&main, 1, 27, "")),
+        request(5, "textDocument/definition", &at_in(&main, 2, 6, "")),
+        request(6, "textDocument/rename", &at_in(&main, 1, 16, r#","newName":"double""#)),
+        request(7, "textDocument/rename", &at_in(&main, 2, 6, r#","newName":"say""#)),
+        request(8, "shutdown", "null"),
+        notification("exit", "{}"),
+    ]);
+
+    let range = |line: usize, start: usize, end: usize| format!(r#""range":{{"start":{{"line":{},"character":{}}},"end":{{"line":{},"character":{}}}}}"#, line, start, line, end);
+    assert!(replies[1].contains(r#""diagnostics":[]"#), "{}", replies[1]);
+    assert_eq!(reply(&replies, 2), format!(r#"{{"jsonrpc":"2.0","id":2,"result":{{"uri":"{}",{}}}}}"#, util, range(2, 13, 18)));
+    // The definition, the use in util.gv and the one in the document
+    let references = reply(&replies, 3);
+    for (uri, line, start) in [(&util, 0, 10), (&util, 3, 19), (&main, 1, 26)] {
+        let reference = format!(r#"{{"uri":"{}",{}}}"#, uri, range(line, start, start + 4));
+        assert!(references.contains(&reference), "{} isn't in {}", reference, references);
+    }
+    assert_eq!(references.matches(r#""uri""#).count(), 3, "{}", references);
+    assert!(reply(&replies, 4).contains("const BASE: i64"));
+
+    // The prelude gets written out for the editor to show
+    let prelude = reply(&replies, 5);
+    let path = prelude.split_once(r#""uri":"file://"#).and_then(|(_, rest)| rest.split_once('"')).unwrap().0;
+    assert!(path.ends_with("/std/prelude.gv"), "{}", prelude);
+    let line = std::fs::read_to_string(path).unwrap().lines().position(|line| line.starts_with("pub function println(")).unwrap();
+    assert!(prelude.contains(&range(line, 13, 20)), "{}", prelude);
+
+    let rename = reply(&replies, 6);
+    for (uri, line, start) in [(&util, 2, 13), (&main, 1, 14)] {
+        let edit = format!(r#""{}":[{{{},"newText":"double"}}]"#, uri, range(line, start, start + 5));
+        assert!(rename.contains(&edit), "{} isn't in {}", edit, rename);
+    }
+    assert!(reply(&replies, 7).contains(r#""message":"'println' comes with the compiler or a C header, it can't be renamed""#));
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn requests_before_initialize_fail() {
+    let replies = session(&[
+        request(1, "textDocument/hover", &at(0, 0, "")),
+        request(2, "initialize", "{}"),
+        request(3, "shutdown", "null"),
+        notification("exit", "{}"),
+    ]);
+    assert!(reply(&replies, 1).contains(r#""code":-32002"#));
+}
+
+#[test]
+fn hostile_messages() {
+    // Nesting deep enough to overflow the stack of a recursive parser is an invalid message,
+    // the session goes on after it
+    let deep = format!(r#"{{"jsonrpc":"2.0","id":2,"method":"shutdown","params":{}{}}}"#, "[".repeat(100000), "]".repeat(100000));
+    let replies = session(&[
+        request(1, "initialize", "{}"),
+        frame(&deep),
+        request(3, "shutdown", "null"),
+        notification("exit", "{}"),
+    ]);
+    assert!(replies.iter().any(|reply| reply.contains(r#""code":-32700,"message":"Invalid JSON: Nested more than"#)), "{:?}", replies);
+    assert_eq!(reply(&replies, 3), r#"{"jsonrpc":"2.0","id":3,"result":null}"#);
+
+    // A Content-Length nothing could send is an error, not an allocation of that size
+    let mut server = Command::new(env!("CARGO_BIN_EXE_galvan")).arg("lsp")
+        .stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped())
+        .spawn().unwrap();
+    server.stdin.take().unwrap().write_all(b"Content-Length: 18446744073709551615\r\n\r\n{}").unwrap();
+    let output = server.wait_with_output().unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Content-Length 18446744073709551615 is over the limit"), "{}", String::from_utf8_lossy(&output.stderr));
+}
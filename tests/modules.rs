@@ -0,0 +1,63 @@
+mod common;
+use common::{galvan, scratch};
+
+// Multi-file programs: errors say which file they're in and talk about `uart::init`, not
+// the name it gets after linking, and two different items never end up with the same name.
+
+/// Runs `galvan run main.gv` in `dir`, gives back the exit code and stderr
+fn run(dir: &std::path::Path, main: &str) -> (Option<i32>, String) {
+    std::fs::write(dir.join("main.gv"), main).unwrap();
+    let run = galvan(&["run", "main.gv"], dir);
+    (run.status.code(), String::from_utf8_lossy(&run.stderr).to_string())
+}
+
+#[test]
+fn errors_name_the_file() {
+    let dir = scratch("errors");
+    std::fs::create_dir_all(dir.join("drivers")).unwrap();
+    std::fs::write(dir.join("drivers/uart.gv"), "pub function init(port) {\n    return port;\n}\n").unwrap();
+    std::fs::write(dir.join("drivers/bad.gv"), "pub function broken() {\n    let x = ;\n}\n").unwrap();
+
+    assert_eq!(run(&dir, "import drivers::uart;\ncall print(uart::init(1));\n").0, Some(0));
+    assert_eq!(run(&dir, "import drivers::bad;\n"), (Some(1), "error: Expected expression, not EndLine at position 2:13 in drivers/bad.gv\n".to_string()));
+    assert_eq!(run(&dir, "call print(1 + 99999999999999999999);\n"), (Some(1), "error: Invalid integer '99999999999999999999' at position 1:16 in main.gv\n".to_string()));
+
+    // Calls into modules are reported with the path they were written with
+    assert_eq!(run(&dir, "import drivers::uart;\ncall print(uart::init(1, 2));\n"),
+        (Some(1), "error: Function 'drivers::uart::init' takes 1 arguments, 2 were given at position 2:1 in main.gv\n".to_string()));
+    assert_eq!(run(&dir, "import drivers::uart;\ncall print(uart::init(\"x\"));\n"),
+        (Some(1), "error: Argument 1 of 'drivers::uart::init' has to be i64, not str at position 2:1 in main.gv\n".to_string()));
+    assert_eq!(run(&dir, "import std::io;\ncall io::write_file(\"f\", 3);\n"),
+        (Some(1), "error: Function 'std::io::write_file' takes 3 arguments, 2 were given at position 2:1 in main.gv\n".to_string()));
+
+    // Constants are evaluated while linking, their errors point at the value
+    assert_eq!(run(&dir, "const NAME = \"x\";\npub const TWICE = NAME * 2;\n"),
+        (Some(1), "error: Constant 'TWICE': Operator Multiplication only works on i64, not str and i64 at position 2:19 in main.gv\n".to_string()));
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn mangled_names_are_unique() {
+    // `a::b__c` and `a::b::c` would both be a__b__c, and `a_::f` and `a::_f` both a___f
+    let dir = scratch("mangle");
+    std::fs::create_dir_all(dir.join("a")).unwrap();
+    std::fs::write(dir.join("a.gv"), "pub function b__c() {\n    return 1;\n}\n").unwrap();
+    std::fs::write(dir.join("a/b.gv"), "pub function c() {\n    return 2;\n}\n").unwrap();
+    std::fs::write(dir.join("a_.gv"), "pub function f() {\n    return 3;\n}\n").unwrap();
+
+    assert_eq!(run(&dir, "import a;\nimport a::b;\ncall print(b::c());\n"),
+        (Some(1), "error: Function 'b__c' can't have '__' in its name, module paths are joined with it at position 1:5 in a.gv\n".to_string()));
+    assert_eq!(run(&dir, "import a::b__c;\n"),
+        (Some(1), "error: Module name 'b__c' can't have '__' in it or end in '_' at position 1:1 in main.gv\n".to_string()));
+    assert_eq!(run(&dir, "import a_;\n"),
+        (Some(1), "error: Module name 'a_' can't have '__' in it or end in '_' at position 1:1 in main.gv\n".to_string()));
+    // The root's functions aren't mangled, so they'd clash with the standard library's
+    assert_eq!(run(&dir, "function std__core__min(a, b) {\n    return a;\n}\ncall print(min(1, 2));\n"),
+        (Some(1), "error: Function 'std__core__min' can't have '__' in its name, module paths are joined with it at position 1:1 in main.gv\n".to_string()));
+
+    // A single _ anywhere is fine
+    std::fs::write(dir.join("a_b.gv"), "pub function _f_() {\n    return 4;\n}\n").unwrap();
+    let (code, stderr) = run(&dir, "import a_b;\nimport a::b;\nreturn a_b::_f_() + b::c();\n");
+    assert_eq!((code, stderr.as_str()), (Some(6), ""));
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,43 @@
+use std::path::Path;
+use std::process::Command;
+
+// Before/after snapshots of the optimizer. Every tests/opt/<name>.ir starts with a
+// `; args: ...` line, gets built with --emit=ir and those arguments, and what comes out has
+// to match tests/opt/<name>.expected. GALVAN_BLESS=1 writes the .expected files instead.
+
+#[test]
+fn optimizer_snapshots() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/opt");
+    let bless = std::env::var("GALVAN_BLESS").is_ok_and(|bless| bless == "1");
+    let mut inputs: Vec<_> = std::fs::read_dir(&dir).unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "ir")).collect();
+    inputs.sort();
+    assert!(!inputs.is_empty(), "no snapshots in {}", dir.display());
+
+    let mut failed = vec![];
+    for input in inputs {
+        let name = input.file_stem().unwrap().to_string_lossy().to_string();
+        let source = std::fs::read_to_string(&input).unwrap();
+        let Some(args) = source.lines().next().and_then(|line| line.strip_prefix("; args:")) else {
+            panic!("{} doesn't start with a '; args:' line", input.display());
+        };
+
+        let output = std::env::temp_dir().join(format!("galvan-opt-{}-{}.ir", std::process::id(), name));
+        let run = Command::new(env!("CARGO_BIN_EXE_galvan"))
+            .arg("build").arg(&input).arg("--emit=ir").args(args.split_whitespace()).arg("-o").arg(&output)
+            .output().unwrap();
+        assert!(run.status.success(), "building {} failed:\n{}", input.display(), String::from_utf8_lossy(&run.stderr));
+        let optimized = std::fs::read_to_string(&output).unwrap();
+        let _ = std::fs::remove_file(&output);
+
+        let expected = dir.join(format!("{}.expected", name));
+        if bless {
+            std::fs::write(&expected, &optimized).unwrap();
+        } else if std::fs::read_to_string(&expected).ok().as_deref() != Some(optimized.as_str()) {
+            eprintln!("--- {} is now:\n{}", expected.display(), optimized);
+            failed.push(name);
+        }
+    }
+    assert!(failed.is_empty(), "snapshots changed: {} (GALVAN_BLESS=1 updates them)", failed.join(", "));
+}
@@ -0,0 +1,126 @@
+use std::path::Path;
+
+mod common;
+use common::{galvan, scratch, stderr};
+
+// Packages: `galvan new`, building with local dependencies, what galvan.lock records and
+// when it changes, and the errors for --locked, cycles and versions that don't fit.
+
+/// `galvan new app` and `galvan new --lib mathlib` in `dir`, with app depending on mathlib
+fn packages(dir: &Path, requirement: &str) {
+    assert!(galvan(&["new", "app"], dir).status.success());
+    assert!(galvan(&["new", "--lib", "mathlib"], dir).status.success());
+    let manifest = std::fs::read_to_string(dir.join("app/galvan.toml")).unwrap()
+        .replace("# drivers = { path = \"../drivers\" }", &format!("mathlib = {{ path = \"../mathlib\", version = \"{}\" }}", requirement));
+    std::fs::write(dir.join("app/galvan.toml"), manifest).unwrap();
+    std::fs::write(dir.join("app/src/main.gv"), "import mathlib;\ncall print(mathlib::add(2, 3));\nreturn 0;\n").unwrap();
+}
+
+/// The lockfile with the checksums left out
+fn lockfile(app: &Path) -> (String, Vec<String>) {
+    let text = std::fs::read_to_string(app.join("galvan.lock")).unwrap();
+    let checksums = text.lines().filter(|line| line.starts_with("checksum = ")).map(str::to_string).collect();
+    (text.lines().filter(|line| !line.starts_with("checksum = ")).collect::<Vec<_>>().join("\n"), checksums)
+}
+
+#[test]
+fn new_package() {
+    let dir = scratch("new");
+    let new = galvan(&["new", "blinky"], &dir);
+    assert_eq!(String::from_utf8_lossy(&new.stdout), "Created application package 'blinky' in blinky\n");
+    assert_eq!(std::fs::read_to_string(dir.join("blinky/galvan.toml")).unwrap(),
+        "[package]\nname = \"blinky\"\nversion = \"0.1.0\"\nentry = \"src/main.gv\"\ntarget = \"x86_64-linux\"\n\n[dependencies]\n# drivers = { path = \"../drivers\" }\n");
+    assert_eq!(std::fs::read_to_string(dir.join("blinky/.gitignore")).unwrap(), "/target\n");
+    let run = galvan(&["run"], &dir.join("blinky"));
+    assert_eq!((String::from_utf8_lossy(&run.stdout).as_ref(), run.status.code()), ("Hello from blinky!\n", Some(0)));
+
+    let new = galvan(&["new", "--lib", "drivers"], &dir);
+    assert_eq!(String::from_utf8_lossy(&new.stdout), "Created library package 'drivers' in drivers\n");
+    assert!(std::fs::read_to_string(dir.join("drivers/src/lib.gv")).unwrap().starts_with("pub function add(a, b) {"));
+
+    let again = galvan(&["new", "blinky"], &dir);
+    assert_eq!((again.status.code(), stderr(&again)), (Some(1), "error: 'blinky' already exists\n".to_string()));
+    for name in ["Blinky", "two-words", "two__words", "trailing_", "std"] {
+        let bad = galvan(&["new", name], &dir);
+        assert_eq!(bad.status.code(), Some(1), "{}", name);
+        assert_eq!(stderr(&bad), format!("error: '{}' isn't a valid package name, use lowercase letters, digits and single _s, not at the end\n", name));
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn lockfile_tracks_dependencies() {
+    let dir = scratch("lock");
+    packages(&dir, "0.1");
+    let app = dir.join("app");
+
+    // No lockfile yet, --locked can't make one
+    let build = galvan(&["build", "--locked"], &app);
+    assert_eq!((build.status.code(), stderr(&build)), (Some(1), "error: galvan.lock doesn't exist, but --locked was given\n".to_string()));
+    let run = galvan(&["run"], &app);
+    assert_eq!((String::from_utf8_lossy(&run.stdout).as_ref(), run.status.code()), ("5\n", Some(0)));
+    let (text, checksums) = lockfile(&app);
+    assert_eq!(text, "# Written by galvan build, don't edit by hand\n\n\
+        [[package]]\nname = \"mathlib\"\nversion = \"0.1.0\"\npath = \"../mathlib\"\ndependencies = []\n\n\
+        [[package]]\nname = \"app\"\nversion = \"0.1.0\"\npath = \".\"\ndependencies = [\"mathlib\"]");
+    // Only the dependency gets one, the root's sources change all the time
+    assert_eq!(checksums.len(), 1);
+    assert!(checksums[0].starts_with("checksum = \"fnv1a:"), "{}", checksums[0]);
+
+    // Up to date, so --locked is happy and the root's changes don't matter
+    std::fs::write(app.join("src/main.gv"), "import mathlib;\nreturn mathlib::add(1, 1);\n").unwrap();
+    let build = galvan(&["build", "--locked"], &app);
+    assert!(build.status.success(), "{}", stderr(&build));
+    assert!(app.join("target/app").is_file());
+
+    // A dependency's source changes, so does its checksum
+    std::fs::write(dir.join("mathlib/src/lib.gv"), "pub function add(a, b) {\n    return a + b + 0;\n}\n").unwrap();
+    let build = galvan(&["build", "--locked"], &app);
+    assert_eq!((build.status.code(), stderr(&build)), (Some(1), "error: galvan.lock is out of date, but --locked was given\n".to_string()));
+    assert_eq!(lockfile(&app).1, checksums);
+    let build = galvan(&["build"], &app);
+    assert!(build.status.success(), "{}", stderr(&build));
+    let (new_text, new_checksums) = lockfile(&app);
+    assert_eq!(new_text, text);
+    assert_ne!(new_checksums, checksums);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn dependency_errors() {
+    let dir = scratch("errors");
+    packages(&dir, "0.2");
+    let app = dir.join("app");
+    let build = galvan(&["build"], &app);
+    assert_eq!((build.status.code(), stderr(&build)), (Some(1), "error: 'app' needs mathlib 0.2, but ../mathlib has version 0.1.0\n".to_string()));
+
+    // Like Cargo, 0.1 takes any 0.1.x and 1.2 anything from 1.2.0 up to 2
+    let toml = |version: &str, requirement: &str| {
+        let manifest = std::fs::read_to_string(dir.join("mathlib/galvan.toml")).unwrap();
+        let manifest: Vec<String> = manifest.lines().map(|line| if line.starts_with("version = ") {format!("version = \"{}\"", version)} else {line.to_string()}).collect();
+        std::fs::write(dir.join("mathlib/galvan.toml"), manifest.join("\n") + "\n").unwrap();
+        let manifest = std::fs::read_to_string(app.join("galvan.toml")).unwrap();
+        let manifest: Vec<String> = manifest.lines().map(|line| match line.starts_with("mathlib = ") {
+            true => format!("mathlib = {{ path = \"../mathlib\", version = \"{}\" }}", requirement),
+            false => line.to_string(),
+        }).collect();
+        std::fs::write(app.join("galvan.toml"), manifest.join("\n") + "\n").unwrap();
+        galvan(&["build"], &app)
+    };
+    assert!(toml("0.1.7", "0.1").status.success());
+    assert!(toml("1.4.0", "1.2").status.success());
+    assert_eq!(stderr(&toml("1.1.9", "1.2")), "error: 'app' needs mathlib 1.2, but ../mathlib has version 1.1.9\n");
+    assert_eq!(stderr(&toml("2.0.0", "1.2")), "error: 'app' needs mathlib 1.2, but ../mathlib has version 2.0.0\n");
+    // A caret means the same
+    assert!(toml("0.1.7", "^0.1").status.success());
+    assert_eq!(stderr(&toml("0.2.0", "^0.1")), "error: 'app' needs mathlib ^0.1, but ../mathlib has version 0.2.0\n");
+    assert_eq!(stderr(&toml("0.1.0", "~0.1")), "error: Invalid version requirement '~0.1'\n");
+
+    // mathlib needing app back
+    toml("0.1.0", "0.1");
+    let manifest = std::fs::read_to_string(dir.join("mathlib/galvan.toml")).unwrap();
+    std::fs::write(dir.join("mathlib/galvan.toml"), manifest.replace("# drivers = { path = \"../drivers\" }", "app = { path = \"../app\" }")).unwrap();
+    let build = galvan(&["build"], &app);
+    assert_eq!((build.status.code(), stderr(&build)), (Some(1), "error: Dependency cycle: app -> mathlib -> app\n".to_string()));
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,153 @@
+use std::path::Path;
+
+mod common;
+use common::{galvan, run, scratch};
+
+// The shared register allocator through `--emit=regalloc` and through the programs it ends up
+// in. A small function pins down the dump, then one with more values alive across a call than
+// any target has callee-saved registers: those have to end up in callee-saved registers or
+// spill slots, never in one the call clobbers, and the program still has to print what the
+// interpreter does on every native target.
+
+const SMALL: &str = "function add(a, b) {\n    return a + b;\n}\n\nreturn add(1, 2);\n";
+
+const SMALL_DUMP: &str = "function add
+    spill slots: 0
+    callee-saved: rbx
+    calls at: none
+bb0:
+    0  %0: i64 = load a
+    1  %1: i64 = load b
+    2  %2: i64 = add %0, %1
+    3  ret %2
+registers:
+    %0: i64 [0, 2] r10
+    %1: i64 [1, 2] r11
+    %2: i64 [2, 3] rbx
+
+function main
+    spill slots: 0
+    callee-saved: none
+    calls at: 0
+bb0:
+    0  %0: i64 = call add(1, 2)
+    1  ret %0
+registers:
+    %0: i64 [0, 1] r10
+
+";
+
+/// Twelve values alive across the call to id, which the optimizer can't see through
+const PRESSURE: &str = r#"function id(x) {
+    if (x < 0) {
+        return id(x + 1);
+    }
+    return x;
+}
+
+function pressure(a) {
+    let v0 = a * 3;
+    let v1 = a * 5;
+    let v2 = a * 7;
+    let v3 = a * 9;
+    let v4 = a * 11;
+    let v5 = a * 13;
+    let v6 = a * 15;
+    let v7 = a * 17;
+    let v8 = a * 19;
+    let v9 = a * 21;
+    let v10 = a * 23;
+    let v11 = a * 25;
+    let c = id(a);
+    let sum = v0 + v1 + v2 + v3 + v4 + v5 + v6 + v7 + v8 + v9 + v10 + v11 + c;
+    return sum;
+}
+
+let i = 0;
+while (i < 3) {
+    call print(pressure(id(i + 5000000000)));
+    let i = i + 1;
+}
+return pressure(id(1)) - 360;
+"#;
+
+const PRESSURE_OUTPUT: &str = "845000000000\n845000000169\n845000000338\n";
+
+/// Every target with the registers that survive a call
+const TARGETS: [(&str, &[&str]); 4] = [
+    ("x86_64", &["rbx", "r12", "r13", "r14", "r15"]),
+    ("riscv32", &["s1", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11"]),
+    ("thumbv7m", &["r5", "r6", "r7", "r8", "r9", "r10", "r11"]),
+    ("thumbv6m", &["r5", "r6", "r7"]),
+];
+
+fn dump(file: &str, target: &str, level: &str, dir: &Path) -> String {
+    let build = galvan(&["build", file, &format!("--target={}", target), "--emit=regalloc", level, "-o", "dump.txt"], dir);
+    assert!(build.status.success(), "{}", String::from_utf8_lossy(&build.stderr));
+    std::fs::read_to_string(dir.join("dump.txt")).unwrap()
+}
+
+#[test]
+fn dump_format() {
+    let dir = scratch("dump");
+    std::fs::write(dir.join("small.gv"), SMALL).unwrap();
+    assert_eq!(dump("small.gv", "x86_64", "-O0", &dir), SMALL_DUMP);
+    // i64s are register pairs on the 32-bit targets
+    let riscv = dump("small.gv", "riscv32", "-O0", &dir);
+    assert!(riscv.contains("    callee-saved: s1, s2, s3\n"), "{}", riscv);
+    assert!(riscv.contains("    %0: i64 [0, 2] t3:t4\n    %1: i64 [1, 2] t5:s1\n    %2: i64 [2, 3] s2:s3\n"), "{}", riscv);
+    let thumb = dump("small.gv", "thumbv7m", "-O0", &dir);
+    assert!(thumb.contains("    %0: i64 [0, 2] r5:r6\n    %1: i64 [1, 2] r7:r8\n    %2: i64 [2, 3] r9:r10\n"), "{}", thumb);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn values_across_calls() {
+    let dir = scratch("pressure");
+    std::fs::write(dir.join("pressure.gv"), PRESSURE).unwrap();
+    for (target, callee_saved) in TARGETS {
+        for level in ["-O0", "-O2"] {
+            let dump = dump("pressure.gv", target, level, &dir);
+            let function = dump.split("\n\n").find(|function| function.starts_with("function pressure\n")).unwrap();
+            let line = |prefix: &str| function.lines().find_map(|line| line.strip_prefix(prefix)).unwrap().to_string();
+            let calls: Vec<usize> = line("    calls at: ").split(", ").filter_map(|call| call.split(' ').next()?.parse().ok()).collect();
+            assert!(!calls.is_empty(), "{} {}\n{}", target, level, function);
+            let mut across = 0;
+            for register in function.lines().skip_while(|line| *line != "registers:").skip(1) {
+                // "    %3: i64 [2, 14] r12" or "... spill 0:spill 1"
+                let Some((range, homes)) = register.split_once("] ") else {continue};
+                let (start, end) = range.split_once('[').unwrap().1.split_once(", ").unwrap();
+                let (start, end): (usize, usize) = (start.parse().unwrap(), end.parse().unwrap());
+                if !calls.iter().any(|call| start < *call && *call < end) {continue}
+                across += 1;
+                for home in homes.split(':') {
+                    assert!(home.starts_with("spill ") || callee_saved.contains(&home), "{} {}: {} lives across a call in {}", target, level, register, home);
+                }
+            }
+            // At -O2 the twelve values are virtual registers, more than fit
+            if level == "-O2" {
+                assert!(across >= 12, "{}\n{}", target, function);
+                assert_ne!(line("    spill slots: "), "0", "{}\n{}", target, function);
+            }
+        }
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn matches_the_interpreter() {
+    let dir = scratch("run");
+    std::fs::write(dir.join("pressure.gv"), PRESSURE).unwrap();
+    let expected = (PRESSURE_OUTPUT.to_string(), Some(65));
+    assert_eq!(run(&["run", "pressure.gv"], &dir), expected);
+    for level in ["-O0", "-O2", "-Os"] {
+        let build = galvan(&["build", "pressure.gv", "--emit=exe", level, "-o", "pressure"], &dir);
+        assert!(build.status.success(), "{}", String::from_utf8_lossy(&build.stderr));
+        let exe = std::process::Command::new(dir.join("pressure")).output().unwrap();
+        assert_eq!((String::from_utf8_lossy(&exe.stdout).to_string(), exe.status.code()), expected, "x86_64 {}", level);
+        for (target, _) in &TARGETS[1..] {
+            assert_eq!(run(&["run", &format!("--target={}", target), level, "pressure.gv"], &dir), expected, "{} {}", target, level);
+        }
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+}
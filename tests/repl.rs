@@ -0,0 +1,52 @@
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+// `galvan repl` with a script on its stdin: definitions stick around between entries, an
+// error is only an error and the session goes on, and the meta-commands print what they say.
+
+/// Feeds `script` to the REPL, gives back stdout without the prompts, and stderr
+fn session(script: &str) -> (String, String) {
+    let mut repl = Command::new(env!("CARGO_BIN_EXE_galvan")).arg("repl")
+        .stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped())
+        .spawn().unwrap();
+    repl.stdin.take().unwrap().write_all(script.as_bytes()).unwrap();
+    let output = repl.wait_with_output().unwrap();
+    assert!(output.status.success(), "galvan repl failed:\n{}", String::from_utf8_lossy(&output.stderr));
+    let stdout = String::from_utf8_lossy(&output.stdout).replace("galvan> ", "").replace("   ...> ", "");
+    (stdout, String::from_utf8_lossy(&output.stderr).to_string())
+}
+
+#[test]
+fn definitions_persist() {
+    let (stdout, stderr) = session("let x = 20;\nfunction double(a) {\n    return a * 2;\n}\ndouble(x) + 2\ncall print(\"x is\", x);\n");
+    assert_eq!(stdout, "Galvan REPL, :help for help\n42\nx is 20\n\n");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn errors_keep_going() {
+    let (stdout, stderr) = session("\nlet x = 5;\n\ncall print(nope);\n10 / 0\nlet y = ;\nx + 1\n:nope\n:quit\nx\n");
+    // Everything after an error still runs, and nothing after :quit does
+    assert_eq!(stdout, "Galvan REPL, :help for help\n6\n");
+    assert_eq!(stderr, "error: Unknown variable 'nope' at position 1:1\n\
+        error: runtime error: Division by zero at position 1:1\n    in main\n\
+        error: Expected expression, not EndLine at position 1:9\n\
+        error: Unknown command ':nope', see :help\n");
+}
+
+#[test]
+fn meta_commands() {
+    let (stdout, stderr) = session("function name() {\n    return \"galvan\";\n}\n:type name()\n:type 1 < 2\n:tokens let y = 1;\n:ast y * 2\n");
+    assert_eq!(stderr, "");
+    let expected = "Galvan REPL, :help for help\nstr\ni64\n\
+        1:1\tKeyword\tlet\n1:5\tIdentifier\ty\n1:7\tEqualSign\t=\n1:9\tInteger\t1\n1:10\tEndLine\t;\n\
+        Operation(\n    Operation {\n        left: Variable(\n            \"y\",\n            (\n                1,\n                1,\n                0,\n            ),\n        ),\n        operator: Multiplication,\n        right: Number(\n            2,\n        ),\n    },\n)\n\n";
+    assert_eq!(stdout, expected);
+}
+
+#[test]
+fn reset_forgets_everything() {
+    let (stdout, stderr) = session("let x = 1;\nfunction f() {\n    return 2;\n}\n:reset\nx\nf()\nlet x = 3;\nx\n");
+    assert_eq!(stdout, "Galvan REPL, :help for help\n3\n\n");
+    assert_eq!(stderr, "error: Unknown variable 'x'\nerror: Unknown function 'f'\n");
+}
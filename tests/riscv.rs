@@ -0,0 +1,86 @@
+use std::process::Command;
+
+mod common;
+use common::{galvan, have, run, scratch};
+
+// The RV32IMC backend on the built-in encoder and simulator, against the interpreter at every
+// optimization level: i64s as register pairs, the MIN / -1 corner, the standard library and a
+// freestanding program that ends up spinning. With llvm-mc around the assembly has to
+// assemble for the same target.
+
+const SOURCE: &str = r#"import std::core;
+import std::math;
+import std::str;
+
+function fib(n) {
+    if (n < 2) {
+        return n;
+    }
+    return fib(n - 1) + fib(n - 2);
+}
+
+let big = 5000000000;
+let min = 0 - 9223372036854775807 - 1;
+let m = 0 - 1;
+call print(big * 3, big + big, big - 7000000000, big / 3, 0 - big);
+call print(min / m, min - 1, min);
+call print(big < 6000000000, big == 5000000000, min < big, big >= 5000000001);
+call print(fib(15), core::max(3, 9), core::abs(0 - 4), math::pow(2, 40), str::concat("con", "cat"));
+return fib(10);
+"#;
+
+const OUTPUT: &str = "15000000000 10000000000 -2000000000 1666666666 -5000000000\n\
+    -9223372036854775808 9223372036854775807 -9223372036854775808\n\
+    1 1 1 0\n610 9 4 1099511627776 concat\n";
+
+const FREESTANDING: &str = r#"#![no_std]
+
+function double(x) {
+    return x + x;
+}
+
+let i = 0;
+let total = 0;
+while (i < 7) {
+    let total = total + double(3);
+    let i = i + 1;
+}
+return total;
+"#;
+
+#[test]
+fn matches_the_interpreter() {
+    let dir = scratch("run");
+    std::fs::write(dir.join("main.gv"), SOURCE).unwrap();
+    std::fs::write(dir.join("bare.gv"), FREESTANDING).unwrap();
+    assert_eq!(run(&["run", "main.gv"], &dir), (OUTPUT.to_string(), Some(55)));
+    assert_eq!(run(&["run", "bare.gv"], &dir), (String::new(), Some(42)));
+    for level in ["-O0", "-O2", "-Os"] {
+        assert_eq!(run(&["run", "--target=riscv32", level, "main.gv"], &dir), (OUTPUT.to_string(), Some(55)), "{}", level);
+        // The simulator stops once _start is spinning, with main's result as the exit code
+        assert_eq!(run(&["run", "--target=riscv32", level, "bare.gv"], &dir), (String::new(), Some(42)), "{}", level);
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn assembles_with_llvm_mc() {
+    if !have("llvm-mc") {
+        eprintln!("no llvm-mc, skipping");
+        return;
+    }
+    let dir = scratch("llvm-mc");
+    std::fs::write(dir.join("main.gv"), SOURCE).unwrap();
+    std::fs::write(dir.join("bare.gv"), FREESTANDING).unwrap();
+    for file in ["main", "bare"] {
+        for level in ["-O0", "-O2", "-Os"] {
+            let asm = format!("{}{}.s", file, level);
+            let build = galvan(&["build", &format!("{}.gv", file), "--target=riscv32", "--emit=asm", level, "-o", &asm], &dir);
+            assert!(build.status.success(), "{}", String::from_utf8_lossy(&build.stderr));
+            let assemble = Command::new("llvm-mc").args(["-triple=riscv32", "-mattr=+m,+c", "-filetype=obj", &asm, "-o", "/dev/null"])
+                .current_dir(&dir).output().unwrap();
+            assert!(assemble.status.success(), "{}: {}", asm, String::from_utf8_lossy(&assemble.stderr));
+        }
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,79 @@
+use std::process::{Command, Output};
+
+mod common;
+use common::{galvan, have, scratch};
+
+// The standard library, run the same everywhere: math, str and mem, files written, appended
+// and read back through io, and a failing assert_eq panicking with exit code 101.
+
+const SOURCE: &str = r#"import std::core;
+import std::io;
+import std::math;
+import std::mem;
+import std::str;
+
+call print(math::pow(3, 4), math::sqrt(1000000), math::sqrt(99), math::gcd(84, 36), math::lcm(4, 6), math::rem(0 - 7, 3));
+call print(min(3, 9), max(3, 9), abs(0 - 5), core::sign(0 - 8), core::clamp(15, 0, 10), core::I64_MIN);
+
+call print(str::len("galvan"), str::compare("abc", "abd"), str::equal("x", "x"), str::byte("A", 0));
+call print(str::from_int(0 - 1234), str::to_int("567") + 1, str::concat("con", "cat"));
+
+let buffer = mem::alloc(16);
+call mem::set(buffer, 120, 3);
+let copy = mem::alloc(16);
+call mem::copy(copy, buffer, 4);
+call print(str::from_buffer(copy), mem::compare(buffer, copy, 4), mem::load(copy + 1));
+
+let file = "std-test.txt";
+let text = mem::alloc(8);
+call str::copy_into(text, "first\n");
+call io::write_file(file, text, 6);
+call io::append_file(file, "second\n");
+let contents = mem::alloc(64);
+let length = io::read_file(file, contents, 63);
+call mem::store(contents + length, 0);
+call io::print("read ");
+call io::print_int(length);
+call io::println(":");
+call io::print(str::from_buffer(contents));
+call io::eprintln("to stderr");
+call assert_eq(math::pow(2, 10), 1024, "pow");
+call assert(str::equal(str::from_int(42), "42"), "from_int");
+call println("all good");
+call assert_eq(1, 2, "one is two");
+call println("never");
+"#;
+
+const OUTPUT: &str = "81 1000 9 12 12 -1\n3 9 5 -1 10 -9223372036854775808\n6 -1 1 65\n-1234 568 concat\nxxx 0 120\nread 13:\nfirst\nsecond\nall good\n";
+
+const ERRORS: &str = "to stderr\npanic: assertion failed: one is two (1 != 2)\n";
+
+fn check(dir: &std::path::Path, output: Output, what: &str) {
+    assert_eq!(String::from_utf8_lossy(&output.stdout), OUTPUT, "{}", what);
+    assert_eq!(String::from_utf8_lossy(&output.stderr), ERRORS, "{}", what);
+    assert_eq!(output.status.code(), Some(101), "{}", what);
+    assert_eq!(std::fs::read_to_string(dir.join("std-test.txt")).unwrap(), "first\nsecond\n", "{}", what);
+    std::fs::remove_file(dir.join("std-test.txt")).unwrap();
+}
+
+#[test]
+fn std_everywhere() {
+    let dir = scratch("std");
+    std::fs::write(dir.join("main.gv"), SOURCE).unwrap();
+    for args in [&["run", "main.gv"][..], &["run", "--vm", "main.gv"], &["run", "--target=riscv32", "main.gv"], &["run", "--target=thumbv7m", "main.gv"]] {
+        check(&dir, galvan(args, &dir), &args.join(" "));
+    }
+    for level in ["-O0", "-O2"] {
+        let build = galvan(&["build", "main.gv", "--emit=exe", level, "-o", "main"], &dir);
+        assert!(build.status.success(), "{}", String::from_utf8_lossy(&build.stderr));
+        check(&dir, Command::new(dir.join("main")).current_dir(&dir).output().unwrap(), level);
+    }
+    if have("cc") {
+        let build = galvan(&["build", "main.gv", "--emit=c", "-o", "main.c"], &dir);
+        assert!(build.status.success(), "{}", String::from_utf8_lossy(&build.stderr));
+        let compile = Command::new("cc").args(["-std=c99", "-Wall", "-Werror", "main.c", "-o", "main-c"]).current_dir(&dir).output().unwrap();
+        assert!(compile.status.success(), "{}", String::from_utf8_lossy(&compile.stderr));
+        check(&dir, Command::new(dir.join("main-c")).current_dir(&dir).output().unwrap(), "c");
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,185 @@
+use std::process::Command;
+
+mod common;
+use common::{galvan, have, run, scratch};
+
+// Both Thumb profiles (ARMv7-M and the ARMv6-M subset) on the built-in encoder and simulator,
+// against the interpreter at every optimization level: i64s as register pairs, the MIN / -1
+// corner, the standard library, a freestanding program that ends up spinning, and one long
+// enough that ARMv6-M needs far branches and literal pools in the middle of the code.
+// Freestanding programs boot from their vector table. With llvm-mc around the assembly has
+// to assemble for the right CPU, and the vector table has to have every handler in its slot.
+
+const SOURCE: &str = r#"import std::core;
+import std::math;
+import std::str;
+
+function fib(n) {
+    if (n < 2) {
+        return n;
+    }
+    return fib(n - 1) + fib(n - 2);
+}
+
+let big = 5000000000;
+let min = 0 - 9223372036854775807 - 1;
+let m = 0 - 1;
+call print(big * 3, big + big, big - 7000000000, big / 3, 0 - big);
+call print(min / m, min - 1, min);
+call print(big < 6000000000, big == 5000000000, min < big, big >= 5000000001);
+call print(big * big, 0 - big * 4000000001, 123456789 * 987654321);
+call print(fib(15), core::max(3, 9), core::abs(0 - 4), math::pow(2, 40), str::concat("con", "cat"));
+return fib(10);
+"#;
+
+const OUTPUT: &str = "15000000000 10000000000 -2000000000 1666666666 -5000000000\n\
+    -9223372036854775808 9223372036854775807 -9223372036854775808\n\
+    1 1 1 0\n6553255926290448384 -1553255931290448384 121932631112635269\n\
+    610 9 4 1099511627776 concat\n";
+
+const FREESTANDING: &str = r#"#![no_std]
+
+function double(x) {
+    return x + x;
+}
+
+let i = 0;
+let total = 0;
+while (i < 7) {
+    let total = total + double(3);
+    let i = i + 1;
+}
+return total;
+"#;
+
+/// Started through `reset` from the vector table, with a systick handler in slot 15
+const BOOT: &str = r#"#![no_std]
+
+#[interrupt]
+function systick() {
+    return 0;
+}
+
+function double(x) {
+    return x + x;
+}
+
+return double(21);
+"#;
+
+const TARGETS: [&str; 2] = ["thumbv7m", "thumbv6m"];
+
+/// A loop over more code than a 16-bit branch reaches, with a string (and so a literal pool
+/// load on ARMv6-M) every few lines
+fn long_source() -> String {
+    let mut source = "let acc = 1;\nlet i = 0;\nwhile (i < 3) {\n".to_string();
+    for line in 0..120 {
+        source.push_str(&format!("    let acc = acc * {} + {};\n", 7919 + line * 104729, line * 1000003));
+        if line % 10 == 0 {source.push_str(&format!("    call print(\"line {}\", acc);\n", line))}
+    }
+    source.push_str("    let i = i + 1;\n}\ncall print(acc);\nreturn 3;\n");
+    source
+}
+
+#[test]
+fn matches_the_interpreter() {
+    let dir = scratch("run");
+    std::fs::write(dir.join("main.gv"), SOURCE).unwrap();
+    std::fs::write(dir.join("bare.gv"), FREESTANDING).unwrap();
+    std::fs::write(dir.join("long.gv"), long_source()).unwrap();
+    assert_eq!(run(&["run", "main.gv"], &dir), (OUTPUT.to_string(), Some(55)));
+    assert_eq!(run(&["run", "bare.gv"], &dir), (String::new(), Some(42)));
+    let long = run(&["run", "long.gv"], &dir);
+    assert_eq!(long.1, Some(3));
+    for target in TARGETS {
+        let target = format!("--target={}", target);
+        for level in ["-O0", "-O2", "-Os"] {
+            assert_eq!(run(&["run", &target, level, "main.gv"], &dir), (OUTPUT.to_string(), Some(55)), "{} {}", target, level);
+            // The simulator stops once _start is spinning, with main's result as the exit code
+            assert_eq!(run(&["run", &target, level, "bare.gv"], &dir), (String::new(), Some(42)), "{} {}", target, level);
+            assert_eq!(run(&["run", &target, level, "long.gv"], &dir), long, "{} {}", target, level);
+        }
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn boots_from_the_vector_table() {
+    let dir = scratch("boot");
+    std::fs::write(dir.join("boot.gv"), BOOT).unwrap();
+    for target in TARGETS {
+        let target = format!("--target={}", target);
+        assert_eq!(run(&["run", &target, "--entry=reset", "boot.gv"], &dir), (String::new(), Some(42)), "{}", target);
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+/// Assembles `asm` in `dir` for the target's CPU into `object`
+fn assemble(target: &str, asm: &str, object: &str, dir: &std::path::Path) {
+    let cpu = if target == "thumbv6m" {"-mcpu=cortex-m0plus"} else {"-mcpu=cortex-m3"};
+    let assemble = Command::new("llvm-mc").args([&format!("-triple={}", target), cpu, "-filetype=obj", asm, "-o", object])
+        .current_dir(dir).output().unwrap();
+    assert!(assemble.status.success(), "{} {}: {}", target, asm, String::from_utf8_lossy(&assemble.stderr));
+}
+
+#[test]
+fn assembles_with_llvm_mc() {
+    if !have("llvm-mc") {
+        eprintln!("no llvm-mc, skipping");
+        return;
+    }
+    let dir = scratch("llvm-mc");
+    std::fs::write(dir.join("main.gv"), SOURCE).unwrap();
+    std::fs::write(dir.join("bare.gv"), FREESTANDING).unwrap();
+    std::fs::write(dir.join("long.gv"), long_source()).unwrap();
+    for target in TARGETS {
+        for file in ["main", "bare", "long"] {
+            for level in ["-O0", "-O2", "-Os"] {
+                let asm = format!("{}-{}{}.s", file, target, level);
+                let build = galvan(&["build", &format!("{}.gv", file), &format!("--target={}", target), "--emit=asm", level, "-o", &asm], &dir);
+                assert!(build.status.success(), "{}", String::from_utf8_lossy(&build.stderr));
+                assemble(target, &asm, "/dev/null", &dir);
+            }
+        }
+    }
+    // The ARMv6-M version of the long one really needed what it has on top
+    let asm = std::fs::read_to_string(dir.join("long-thumbv6m-O0.s")).unwrap();
+    assert!(asm.contains("    .ltorg\n.Lpool_skip"), "no literal pool in the middle of the code");
+    assert!(asm.contains("    bl .L"), "no far branch");
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn vector_table_slots() {
+    if !have("llvm-mc") || !have("readelf") {
+        eprintln!("no llvm-mc or readelf, skipping");
+        return;
+    }
+    let dir = scratch("vectors");
+    std::fs::write(dir.join("boot.gv"), BOOT).unwrap();
+    // ARMv6-M has no mem_manage, bus_fault, usage_fault or debug_monitor, those slots are 0
+    let default_slots: [(&str, &[u32]); 2] = [("thumbv7m", &[2, 3, 4, 5, 6, 11, 12, 14]), ("thumbv6m", &[2, 3, 11, 14])];
+    for (target, defaults) in default_slots {
+        let build = galvan(&["build", "boot.gv", &format!("--target={}", target), "--entry=reset", "-o", "boot.s"], &dir);
+        assert!(build.status.success(), "{}", String::from_utf8_lossy(&build.stderr));
+        assemble(target, "boot.s", "boot.o", &dir);
+        let readelf = Command::new("readelf").args(["-rW", "boot.o"]).current_dir(&dir).output().unwrap();
+        let relocations = String::from_utf8_lossy(&readelf.stdout).to_string();
+        // Offset, symbol value and name of every word in the table
+        let table: Vec<(u32, u32, String)> = relocations.split("\n\n").find(|section| section.contains("'.rel.vector_table'")).unwrap()
+            .lines().skip(2).map(|line| {
+                let fields: Vec<&str> = line.split_whitespace().collect();
+                (u32::from_str_radix(fields[0], 16).unwrap(), u32::from_str_radix(fields[3], 16).unwrap(), fields[4].to_string())
+            }).collect();
+        let mut expected = vec![(0, "__stack_top"), (4, "reset")];
+        expected.extend(defaults.iter().map(|slot| (slot * 4, "__gv_default_handler")));
+        expected.push((15 * 4, "systick"));
+        let slots: Vec<(u32, &str)> = table.iter().map(|(offset, _, name)| (*offset, name.as_str())).collect();
+        assert_eq!(slots, expected, "{}", target);
+        // Handlers are Thumb code, their addresses need the Thumb bit
+        for (_, value, name) in &table {
+            if name != "__stack_top" {assert_eq!(value & 1, 1, "{} {}", target, name)}
+        }
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,104 @@
+mod common;
+use common::{galvan, scratch};
+
+// The bytecode VM: `run --vm`, the --emit=bytecode disassembly and .gvc files written by one
+// galvan and run by another. A broken .gvc has to be an error, never a crash.
+
+const SOURCE: &str = r#"function square(x) {
+    return x * x;
+}
+
+let i = 0;
+while (i < 3) {
+    call print(i, square(i), "s");
+    let i = i + 1;
+}
+let min = 0 - 9223372036854775807 - 1;
+let m = 0 - 1;
+call print(min / m, min - 1);
+return 7;
+"#;
+
+const OUTPUT: &str = "0 0 s\n1 1 s\n2 4 s\n-9223372036854775808 9223372036854775807\n";
+
+#[test]
+fn vm_matches_the_interpreter() {
+    let dir = scratch("vm");
+    std::fs::write(dir.join("main.gv"), SOURCE).unwrap();
+    for args in [&["run", "main.gv"][..], &["run", "--vm", "main.gv"], &["run", "--vm", "-O2", "main.gv"]] {
+        let run = galvan(args, &dir);
+        assert_eq!((String::from_utf8_lossy(&run.stdout).as_ref(), run.status.code()), (OUTPUT, Some(7)), "{:?}", args);
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn disassembly() {
+    let dir = scratch("bytecode");
+    std::fs::write(dir.join("main.gv"), SOURCE).unwrap();
+    let build = galvan(&["build", "main.gv", "--emit=bytecode", "-o", "main.txt"], &dir);
+    assert!(build.status.success(), "{}", String::from_utf8_lossy(&build.stderr));
+    let text = std::fs::read_to_string(dir.join("main.txt")).unwrap();
+    assert!(text.contains("const c2 = \"s\"\n"), "{}", text);
+    // Every slot the code uses and not one more
+    assert!(text.contains("function square: 1 parameters, 3 slots\n    0000  load 0\n"), "{}", text);
+    assert!(text.contains("function main (entry): 0 parameters, "), "{}", text);
+    assert!(text.contains("  call square, 1\n"), "{}", text);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn gvc_round_trip() {
+    let dir = scratch("gvc");
+    std::fs::write(dir.join("main.gv"), SOURCE).unwrap();
+    let build = galvan(&["build", "main.gv", "--emit=gvc", "-o", "main.gvc"], &dir);
+    assert!(build.status.success(), "{}", String::from_utf8_lossy(&build.stderr));
+    // Doesn't need the source anymore
+    std::fs::remove_file(dir.join("main.gv")).unwrap();
+    let run = galvan(&["run", "main.gvc"], &dir);
+    assert_eq!((String::from_utf8_lossy(&run.stdout).as_ref(), run.status.code()), (OUTPUT, Some(7)));
+    assert_eq!(String::from_utf8_lossy(&run.stderr), "");
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn corrupt_gvc_is_refused() {
+    let dir = scratch("corrupt");
+    std::fs::write(dir.join("main.gv"), SOURCE).unwrap();
+    let build = galvan(&["build", "main.gv", "--emit=gvc", "-o", "main.gvc"], &dir);
+    assert!(build.status.success(), "{}", String::from_utf8_lossy(&build.stderr));
+    let bytes = std::fs::read(dir.join("main.gvc")).unwrap();
+    // `square`'s parameter and slot counts come right after its name
+    let name = bytes.windows(6).position(|window| window == b"square").unwrap();
+    let slots = name + 6 + 4;
+
+    let mut cases: Vec<(&str, Vec<u8>)> = vec![];
+    cases.push(("Not a .gvc file", b"GVX\0".to_vec()));
+    cases.push(("Unexpected end of file", bytes[..bytes.len() - 3].to_vec()));
+    let mut trailing = bytes.clone();
+    trailing.push(0);
+    cases.push(("Trailing bytes after the program", trailing));
+    let mut version = bytes.clone();
+    version[4] = 9;
+    cases.push((".gvc version 9 isn't supported, only 2", version));
+    // Found by fuzzing, the VM used to make room for four billion slots and abort
+    let mut huge = bytes.clone();
+    huge[slots..slots + 4].copy_from_slice(&0xFFFF_FFF0u32.to_le_bytes());
+    cases.push(("'square' has more slots than it uses", huge));
+    let mut few = bytes.clone();
+    few[slots..slots + 4].copy_from_slice(&1u32.to_le_bytes());
+    cases.push(("Slot out of range at square:0001", few));
+    let mut parameters = bytes.clone();
+    parameters[slots - 4..slots].copy_from_slice(&5u32.to_le_bytes());
+    cases.push(("'square' has more parameters than slots", parameters));
+
+    for (index, (error, bytes)) in cases.into_iter().enumerate() {
+        let file = format!("broken{}.gvc", index);
+        std::fs::write(dir.join(&file), bytes).unwrap();
+        let run = galvan(&["run", &file], &dir);
+        assert_eq!(run.status.code(), Some(1), "{}", error);
+        assert_eq!(String::from_utf8_lossy(&run.stdout), "", "{}", error);
+        assert_eq!(String::from_utf8_lossy(&run.stderr), format!("error: Invalid .gvc file '{}': {}\n", file, error));
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,115 @@
+use std::path::Path;
+use std::process::Command;
+
+mod common;
+use common::{galvan, have, run, scratch};
+
+// The Wasm backend against the interpreter. With node around the .wasm runs in the minimal
+// host from the readme (taken out of readme.md, so the two can't drift apart), with the
+// sys_* imports the standard library needs added for the second program. Without node the
+// .wat and the .wasm at least have to have the imports and exports the readme promises.
+
+const SOURCE: &str = r#"function fib(n) {
+    if (n < 2) {
+        return n;
+    }
+    return fib(n - 1) + fib(n - 2);
+}
+
+export function add(a, b) {
+    return a + b;
+}
+
+let big = 5000000000;
+let min = 0 - 9223372036854775807 - 1;
+let m = 0 - 1;
+let i = 0;
+let total = 0;
+while (i < 10) {
+    let total = total + add(i, i);
+    let i = i + 1;
+}
+call print(big * 3, big + big, big - 7000000000, big / 3, 0 - big);
+call print(min / m, min - 1, min);
+call print(big < 6000000000, big == 5000000000, min < big, big >= 5000000001);
+call print("fib", fib(20), "total", total);
+return fib(10);
+"#;
+
+const OUTPUT: &str = "15000000000 10000000000 -2000000000 1666666666 -5000000000\n\
+    -9223372036854775808 9223372036854775807 -9223372036854775808\n\
+    1 1 1 0\nfib 6765 total 90\n";
+
+const STD: &str = r#"import std::core;
+import std::io;
+import std::math;
+import std::str;
+
+call io::println("hello from std::io");
+call print(core::max(3, 9), core::abs(0 - 4), math::pow(2, 40), str::concat("con", "cat"));
+return core::min(7, 12);
+"#;
+
+const STD_OUTPUT: &str = "hello from std::io\n9 4 1099511627776 concat\n";
+
+/// What the standard library imports on top of print, for the host
+const SYS_IMPORTS: &str = r#"env.sys_write_str = (fd, address) => {
+    const text = string(address);
+    (fd == 2n ? process.stderr : process.stdout).write(text);
+    return BigInt(text.length);
+};
+env.sys_exit = (code) => process.exit(Number(code));
+"#;
+
+/// The readme's host as a node module that runs the .wasm in argv[2] and exits with what
+/// `_start` returned
+fn host(imports: &str) -> String {
+    let readme = std::fs::read_to_string(Path::new(env!("CARGO_MANIFEST_DIR")).join("readme.md")).unwrap();
+    let start = readme.find("A minimal host:\n```\n").expect("no minimal host in the readme") + "A minimal host:\n```\n".len();
+    let end = start + readme[start..].find("\n```").unwrap();
+    let code = readme[start..end].replace("const { instance }", &format!("{}const {{ instance }}", imports));
+    format!("import {{ readFileSync }} from \"node:fs\";\nconst bytes = readFileSync(process.argv[2]);\n{}\nprocess.exit(Number(exitCode));\n", code)
+}
+
+#[test]
+fn matches_the_interpreter() {
+    let dir = scratch("node");
+    std::fs::write(dir.join("main.gv"), SOURCE).unwrap();
+    std::fs::write(dir.join("std.gv"), STD).unwrap();
+    assert_eq!(run(&["run", "main.gv"], &dir), (OUTPUT.to_string(), Some(55)));
+    assert_eq!(run(&["run", "std.gv"], &dir), (STD_OUTPUT.to_string(), Some(7)));
+    for file in ["main", "std"] {
+        for emit in ["exe", "asm"] {
+            let output = format!("{}.{}", file, if emit == "exe" {"wasm"} else {"wat"});
+            let build = galvan(&["build", &format!("{}.gv", file), "--target=wasm32", &format!("--emit={}", emit), "-o", &output], &dir);
+            assert!(build.status.success(), "{}", String::from_utf8_lossy(&build.stderr));
+        }
+    }
+
+    if !have("node") {
+        eprintln!("no node, only checking the module structure");
+        let wasm = std::fs::read(dir.join("main.wasm")).unwrap();
+        assert_eq!(&wasm[..8], b"\0asm\x01\0\0\0");
+        for name in ["print_i64", "print_str", "_start", "add", "memory"] {
+            assert!(wasm.windows(name.len()).any(|window| window == name.as_bytes()), "{} isn't in the .wasm", name);
+        }
+        let wat = std::fs::read_to_string(dir.join("main.wat")).unwrap();
+        assert!(wat.starts_with("(module\n"), "{}", wat);
+        for expected in ["(import \"env\" \"print_i64\" (func $print_i64 (param i64)))", "(import \"env\" \"print_str\" (func $print_str (param i32)))",
+            "(memory (export \"memory\")", "(export \"_start\") (result i64)", "(export \"add\")"] {
+            assert!(wat.contains(expected), "no {} in\n{}", expected, wat);
+        }
+        let wat = std::fs::read_to_string(dir.join("std.wat")).unwrap();
+        assert!(wat.contains("(import \"env\" \"sys_write_str\""), "{}", wat);
+        let _ = std::fs::remove_dir_all(&dir);
+        return;
+    }
+    std::fs::write(dir.join("host.mjs"), host("")).unwrap();
+    std::fs::write(dir.join("std-host.mjs"), host(SYS_IMPORTS)).unwrap();
+    for (host, wasm, expected) in [("host.mjs", "main.wasm", (OUTPUT, 55)), ("std-host.mjs", "std.wasm", (STD_OUTPUT, 7))] {
+        let node = Command::new("node").args([host, wasm]).current_dir(&dir).output().unwrap();
+        assert_eq!(String::from_utf8_lossy(&node.stderr), "", "{}", wasm);
+        assert_eq!((String::from_utf8_lossy(&node.stdout).to_string(), node.status.code()), (expected.0.to_string(), Some(expected.1)), "{}", wasm);
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,74 @@
+use std::path::Path;
+use std::process::Command;
+
+mod common;
+use common::{galvan, have, scratch};
+
+// The x86-64 assembly backend: --emit=asm in both syntaxes, assembled and linked with the
+// system's binutils, has to print the same as the interpreter. Without `as` and `ld` around
+// there's nothing to check, so the test says so and passes.
+
+const SOURCE: &str = r#"function sum(a, b, c, d, e, f, g, h) {
+    return a + b + c + d + e + f + g + h;
+}
+
+let min = 0 - 9223372036854775807;
+let min = min - 1;
+call print(min, 9223372036854775807, 0, 0 - 42);
+call print(sum(1, 2, 3, 4, 5, 6, 7, 8), 100 / 7, 0 - 100 / 7, "text");
+return 12;
+"#;
+
+const OUTPUT: &str = "-9223372036854775808 9223372036854775807 0 -42\n36 14 -14 text\n";
+
+/// Assembles and links `asm`, runs the program and gives back its output and exit code
+fn assemble_and_run(dir: &Path, asm: &str) -> (String, Option<i32>) {
+    let object = dir.join(format!("{}.o", asm));
+    let exe = dir.join(format!("{}.exe", asm));
+    assert!(Command::new("as").arg(dir.join(asm)).arg("-o").arg(&object).status().unwrap().success(), "as {}", asm);
+    assert!(Command::new("ld").arg(&object).arg("-o").arg(&exe).status().unwrap().success(), "ld {}", asm);
+    let run = Command::new(&exe).output().unwrap();
+    (String::from_utf8_lossy(&run.stdout).to_string(), run.status.code())
+}
+
+#[test]
+fn assembly_runs() {
+    if !have("as") || !have("ld") {
+        eprintln!("no as or ld, skipping");
+        return;
+    }
+    let dir = scratch("asm");
+    std::fs::write(dir.join("main.gv"), SOURCE).unwrap();
+    for (syntax, asm) in [("att", "att.s"), ("intel", "intel.s")] {
+        let run = galvan(&["build", "main.gv", "--emit=asm", &format!("--syntax={}", syntax), "-o", asm], &dir);
+        assert!(run.status.success(), "{}", String::from_utf8_lossy(&run.stderr));
+        assert_eq!(assemble_and_run(&dir, asm), (OUTPUT.to_string(), Some(12)), "{}", syntax);
+    }
+
+    // The interpreter agrees
+    let run = galvan(&["run", "main.gv"], &dir);
+    assert_eq!(String::from_utf8_lossy(&run.stdout), OUTPUT);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn min_divided_by_minus_one() {
+    // idiv traps on it, the interpreter wraps around, and constfold hides it at -O2
+    let dir = scratch("div");
+    std::fs::write(dir.join("main.gv"), "function divide(a, b) {\n    return a / b;\n}\nlet a = 0 - 9223372036854775807 - 1;\nlet m = 0 - 1;\ncall print(a / m, divide(a, m), divide(7, m), divide(0 - 7, 2));\n").unwrap();
+    const OUTPUT: &str = "-9223372036854775808 -9223372036854775808 -7 -3\n";
+    let run = galvan(&["run", "main.gv"], &dir);
+    assert_eq!(String::from_utf8_lossy(&run.stdout), OUTPUT);
+    for level in ["-O0", "-O2"] {
+        let build = galvan(&["build", "main.gv", "--emit=exe", level, "-o", "main"], &dir);
+        assert!(build.status.success(), "{}", String::from_utf8_lossy(&build.stderr));
+        let run = Command::new(dir.join("main")).output().unwrap();
+        assert_eq!((String::from_utf8_lossy(&run.stdout).as_ref(), run.status.code()), (OUTPUT, Some(0)), "{}", level);
+    }
+    if have("as") && have("ld") {
+        let build = galvan(&["build", "main.gv", "--emit=asm", "-o", "main.s"], &dir);
+        assert!(build.status.success(), "{}", String::from_utf8_lossy(&build.stderr));
+        assert_eq!(assemble_and_run(&dir, "main.s"), (OUTPUT.to_string(), Some(0)));
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+}